@@ -0,0 +1,32 @@
+/* Generates the gRPC service for the "grpc" feature (see src/grpc.rs and
+   proto/streamlet.proto). The messages are prost structs written by hand in src/grpc.rs,
+   so building needs no protoc. Without the feature there is nothing to do. */
+
+#[cfg(feature = "grpc")]
+fn main() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type(format!("crate::grpc::{}", output))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("StreamletLog")
+        .package("streamlet.v1")
+        .method(method("submit_entry", "SubmitEntry", "SubmitEntryRequest", "Receipt").build())
+        .method(method("get_block", "GetBlock", "GetBlockRequest", "Block").build())
+        .method(method("get_chain_status", "GetChainStatus", "ChainStatusRequest", "ChainStatus").build())
+        .method(method("get_proof", "GetProof", "GetProofRequest", "ProofUpdate").server_streaming().build())
+        .build();
+    Builder::new().compile(&[service]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+#[cfg(not(feature = "grpc"))]
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,132 @@
+/* Adaptive epoch length (--adaptive-epoch <min>:<max>). Epochs start at the configured
+   length, and every ADAPT_WINDOW epochs the node looks at how many of that window's
+   epochs put a block on the finalized chain. Leaders propose every epoch, so a window
+   where fewer than half did means proposals and votes keep missing the deadline: the
+   length grows by half. A window where nearly all did (9 in 10) means the network keeps
+   up easily: the length shrinks by a tenth. Either way it stays within [min, max].
+   Nodes don't agree on epoch lengths by message; each works them out from its own
+   finalized chain, which is the same everywhere. A window is judged once a block from a
+   later epoch is finalized (its ancestors, the window's finalized blocks, are fixed by
+   then), and the new length starts ADAPT_DELAY epochs after that block's epoch, which
+   leaves the other nodes time to finalize it too. Every node must use the same bounds
+   and initial length. A node that restarts, or adopts a chain, works the lengths out
+   again from its chain. */
+
+use crate::blockchain::{Chain, LocalChain};
+use crate::Sha256Hash;
+use std::time::Duration;
+
+// Epochs per judged window
+pub const ADAPT_WINDOW: u64 = 20;
+// Epochs between the block that closes a window and the new length taking effect
+pub const ADAPT_DELAY: u64 = 10;
+
+#[derive(Debug)]
+pub struct AdaptiveEpoch {
+    min: Duration,
+    max: Duration,
+    initial: Duration,
+    target: Duration,                // length decided by the last judged window
+    changes: Vec<(u64, Duration)>,   // (first epoch, length), in epoch order
+    window: u64,                     // the window being counted: epochs (window * W, (window + 1) * W]
+    finalized_in_window: u64,
+    seen: (u64, Sha256Hash),         // height and hash of the last finalized block looked at
+}
+
+impl AdaptiveEpoch {
+    /* @param initial: the configured epoch length
+    @param min: shortest epoch length
+    @param max: longest epoch length */
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        let initial = initial.clamp(min, max);
+        AdaptiveEpoch { min, max, initial, target: initial, changes: Vec::new(), window: 0, finalized_in_window: 0, seen: (0, LocalChain::new().blocks[0].block.hash) }
+    }
+
+    /* Looks at the finalized blocks added since the last call, judging every window
+    they close. A chain that isn't an extension of the one seen so far (adopted from a
+    snapshot, say) is looked at again from genesis.
+    @param chain: the finalized chain */
+    pub fn catch_up(&mut self, chain: &LocalChain) {
+        let (seen_height, seen_hash) = self.seen;
+        if chain.blocks.get(seen_height as usize).map(|signed| signed.block.hash) != Some(seen_hash) {
+            *self = AdaptiveEpoch::new(self.initial, self.min, self.max);
+        }
+        for signed in chain.blocks.iter().skip(self.seen.0 as usize + 1) {
+            let epoch = signed.block.epoch;
+            while epoch > (self.window + 1) * ADAPT_WINDOW {
+                self.judge_window(epoch);
+            }
+            if epoch > self.window * ADAPT_WINDOW {
+                self.finalized_in_window += 1;
+            }
+        }
+        let (tip, _) = chain.head();
+        self.seen = (tip.height, tip.hash);
+    }
+
+    // Closes the current window, closed by a finalized block from `epoch`
+    fn judge_window(&mut self, epoch: u64) {
+        let next = if self.finalized_in_window * 2 < ADAPT_WINDOW {
+            self.target * 3 / 2
+        } else if self.finalized_in_window * 10 >= ADAPT_WINDOW * 9 {
+            self.target * 9 / 10
+        } else {
+            self.target
+        };
+        let next = next.clamp(self.min, self.max);
+        if next != self.target {
+            self.target = next;
+            self.changes.push((epoch + ADAPT_DELAY, next));
+        }
+        self.window += 1;
+        self.finalized_in_window = 0;
+    }
+
+    /* The length of an epoch, as far as the finalized chain seen so far decides it.
+    @param epoch: the epoch */
+    pub fn length_at(&self, epoch: u64) -> Duration {
+        self.changes
+            .iter()
+            .rev()
+            .find(|(first_epoch, _)| *first_epoch <= epoch)
+            .map_or(self.initial, |(_, length)| *length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+
+    fn chain_with_epochs(epochs: impl Iterator<Item = u64>) -> LocalChain {
+        let mut chain = LocalChain::new();
+        for epoch in epochs {
+            let parent = chain.head().0.clone();
+            chain.append_block(Block::new(epoch, parent.hash, Vec::new(), parent.height + 1, 0), Vec::new());
+        }
+        chain
+    }
+
+    #[test]
+    fn test_epochs_lengthen_when_slow_and_shorten_when_fast() {
+        let second = Duration::from_secs(1);
+        let mut slow = AdaptiveEpoch::new(Duration::from_secs(10), second, Duration::from_secs(20));
+        // Every third epoch finalizes a block: too few
+        slow.catch_up(&chain_with_epochs((1..=45).filter(|epoch| epoch % 3 == 0)));
+        // Window 1 is closed by the block from epoch 21, window 2 by the one from epoch 42
+        assert_eq!(slow.length_at(30), Duration::from_secs(10));
+        assert_eq!(slow.length_at(31), Duration::from_secs(15));
+        assert_eq!(slow.length_at(52), Duration::from_secs(20));
+
+        let mut fast = AdaptiveEpoch::new(Duration::from_secs(10), second, Duration::from_secs(20));
+        let chain = chain_with_epochs(1..=25);
+        fast.catch_up(&chain);
+        assert_eq!(fast.length_at(31), Duration::from_secs(9));
+        // Looking again changes nothing; a node that sees the same chain agrees
+        fast.catch_up(&chain);
+        let mut again = AdaptiveEpoch::new(Duration::from_secs(10), second, Duration::from_secs(20));
+        again.catch_up(&chain_with_epochs(1..=10));
+        again.catch_up(&chain);
+        assert_eq!(again.changes, fast.changes);
+    }
+}
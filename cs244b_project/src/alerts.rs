@@ -0,0 +1,143 @@
+/* Node alerts: conditions an operator should hear about, as typed values.
+   The node logs each of these, and also hands them to every subscriber of its alert
+   stream (StreamletInstance::subscribe_alerts), so a service embedding the node can
+   route them into its own alerting instead of scraping logs:
+   - ConsensusStall: nothing was finalized for STALL_EPOCHS epochs
+   - StorageFailure: a block, the finalized tip or a vote couldn't be written to disk
+   - PeerOverBudget: a peer made us check more signatures than its budget this epoch
+   - TreeHead: another node's signed tree head shows misbehavior (e.g. a split view)
+   - MissedMergeDelay: we didn't finalize an entry within the delay we promised
+   - LatencyBudget: blocks take too long to notarize for the epoch length
+   - VotingAnomaly: a validator's voting pattern stands out
+   - UnsupportedProtocol: a protocol version this node doesn't implement is (or is
+     about to be) in force
+   - PeerBanned: the network shut a peer out for misbehaving (see peer_score)
+   - SelfAudit: what this node serves disagrees with what another validator signed
+     (see self_audit)
+   Critical alerts need someone to act; warnings are worth a look. */
+
+use crate::blockchain::EntryId;
+use crate::latency_watchdog::LatencyWarning;
+use crate::monitor::Alert;
+use crate::self_audit::Divergence;
+use crate::vote_analysis::Anomaly;
+use std::fmt;
+
+// Epochs without a newly finalized block before consensus counts as stalled
+pub const STALL_EPOCHS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeAlert {
+    ConsensusStall { epoch: u64, finalized_height: u64, epochs: u64 },
+    StorageFailure { what: String, error: String },
+    PeerOverBudget { peer: String, epoch: u64 },
+    TreeHead(Alert),
+    MissedMergeDelay { entry_id: EntryId },
+    LatencyBudget(LatencyWarning),
+    VotingAnomaly(Anomaly),
+    UnsupportedProtocol { version: u32, activation_epoch: u64, supported: u32 },
+    PeerBanned { peer: String },
+    SelfAudit(Divergence),
+}
+
+impl NodeAlert {
+    pub fn severity(&self) -> Severity {
+        match self {
+            NodeAlert::ConsensusStall { .. }
+            | NodeAlert::StorageFailure { .. }
+            | NodeAlert::UnsupportedProtocol { .. }
+            | NodeAlert::SelfAudit(_) => {
+                Severity::Critical
+            }
+            NodeAlert::TreeHead(alert) if alert.is_misbehavior() => Severity::Critical,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for NodeAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeAlert::ConsensusStall { epoch, finalized_height, epochs } => write!(
+                f,
+                "Epoch: {}, consensus stalled: nothing finalized for {} epochs (finalized height {})",
+                epoch, epochs, finalized_height
+            ),
+            NodeAlert::StorageFailure { what, error } => write!(f, "Failed to persist {}: {}", what, error),
+            NodeAlert::PeerOverBudget { peer, epoch } => {
+                write!(f, "Epoch: {}, {} is over its verification budget; deferring its messages", epoch, peer)
+            }
+            NodeAlert::TreeHead(alert) => write!(f, "tree head gossip: {}", alert),
+            NodeAlert::MissedMergeDelay { entry_id } => {
+                write!(f, "Missed the merge delay for entry {}; it is still not finalized", entry_id)
+            }
+            NodeAlert::LatencyBudget(warning) => write!(f, "latency budget: {}", warning),
+            NodeAlert::VotingAnomaly(anomaly) => write!(f, "Voting anomaly: {}", anomaly),
+            NodeAlert::UnsupportedProtocol { version, activation_epoch, supported } => write!(
+                f,
+                "Protocol version {} activates at epoch {}, but this node only supports up to {}; upgrade it before then",
+                version, activation_epoch, supported
+            ),
+            NodeAlert::PeerBanned { peer } => write!(f, "Banned peer {} for repeated misbehavior", peer),
+            NodeAlert::SelfAudit(divergence) => write!(f, "Self-audit: {}", divergence),
+        }
+    }
+}
+
+/* Watches finalization for stalls; reports each stall once. */
+#[derive(Debug, Default)]
+pub struct StallWatch {
+    finalized_height: u64,
+    since_epoch: u64, // epoch the finalized height last grew in
+    reported: bool,
+}
+
+impl StallWatch {
+    /* Checks progress at the start of an epoch.
+    @param epoch: the epoch that just started
+    @param finalized_height: height of the finalized chain now */
+    pub fn check(&mut self, epoch: u64, finalized_height: u64) -> Option<NodeAlert> {
+        if finalized_height > self.finalized_height || self.since_epoch == 0 {
+            self.finalized_height = finalized_height;
+            self.since_epoch = epoch;
+            self.reported = false;
+            return None;
+        }
+        let epochs = epoch.saturating_sub(self.since_epoch);
+        if epochs < STALL_EPOCHS || self.reported {
+            return None;
+        }
+        self.reported = true;
+        Some(NodeAlert::ConsensusStall { epoch, finalized_height, epochs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stalls_are_reported_once() {
+        let mut watch = StallWatch::default();
+        assert_eq!(watch.check(1, 0), None);
+        for epoch in 2..1 + STALL_EPOCHS {
+            assert_eq!(watch.check(epoch, 0), None);
+        }
+        let stall = watch.check(1 + STALL_EPOCHS, 0).unwrap();
+        assert_eq!(stall, NodeAlert::ConsensusStall { epoch: 1 + STALL_EPOCHS, finalized_height: 0, epochs: STALL_EPOCHS });
+        assert_eq!(stall.severity(), Severity::Critical);
+        assert_eq!(watch.check(2 + STALL_EPOCHS, 0), None);
+
+        // Progress ends the stall; the next one is reported again
+        assert_eq!(watch.check(30, 1), None);
+        assert!(watch.check(30 + STALL_EPOCHS, 1).is_some());
+        let gap = NodeAlert::TreeHead(Alert::Gap { signer: String::from("h2"), from_size: 3 });
+        assert_eq!(gap.severity(), Severity::Warning);
+    }
+}
@@ -10,11 +10,12 @@ use serde::{Deserialize, Serialize};
 // use crate::messages::*;
 use crate::app::app_interface::{APP_NET_TOPIC, APP_SENDER_ID, APP_NAME};
 use crate::messages::*;
-use crate::network::NetworkStack;
+use crate::network::{NetworkEvent, NetworkStack, EVENT_QUEUE_CAPACITY};
 use crate::utils::crypto::*;
-use crate::blockchain::{LocalChain, SignedBlock};
+use crate::error::StreamletError;
+use crate::blockchain::{EntryId, InclusionPromise, LocalChain, LogEntry, SignedBlock};
 use rand::distributions::Alphanumeric;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io::{Read, Write};
 use tokio::{
@@ -87,14 +88,10 @@ impl OnionRouterBasicData {
             .to_string(),
             onion_key: gen
                 .sample_iter(&Alphanumeric)
-                .take(OnionRouterBasicData::KEY_SIZE)
-                .map(char::from)
-                .collect(),
+                .take(OnionRouterBasicData::KEY_SIZE).collect(),
             full_hash: gen
                 .sample_iter(&Alphanumeric)
-                .take(OnionRouterBasicData::REMAINING_DATA_HASH_SIZE)
-                .map(char::from)
-                .collect(),
+                .take(OnionRouterBasicData::REMAINING_DATA_HASH_SIZE).collect(),
         }
     }
 }
@@ -129,25 +126,29 @@ pub struct Application {
     keypair: Keypair,
     curr_nonce: u32,
     outstanding_requests: HashSet<u32>,
+    // Entries we submitted (by id), and each node's promise to include them
+    submitted: HashMap<EntryId, Vec<u8>>,
+    promises: HashMap<(EntryId, String), InclusionPromise>,
 }
 
 // State: KeyPair for signatures (eventually)
 
 impl Application {
     pub fn new() -> Self {
-        let mut csprng = OsRng {};
-        let keypair = Keypair::generate(&mut csprng);
+        let keypair = Keypair::generate(SignatureScheme::default());
         Self {
-            keypair: keypair,
+            keypair,
             curr_nonce: 0,
             outstanding_requests: HashSet::new(),
+            submitted: HashMap::new(),
+            promises: HashMap::new(),
         }
     }
 
     pub async fn run(&mut self) {
 
-        let (net_sender, mut receiver) = mpsc::unbounded_channel();
-        let mut net_stack = NetworkStack::new(APP_NET_TOPIC, net_sender).await;
+        let (net_sender, mut receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let mut net_stack = NetworkStack::new(APP_NET_TOPIC, net_sender, &[]).await;
 
         // Set up STDIN
         let mut stdin = BufReader::new(stdin()).lines();
@@ -163,8 +164,11 @@ impl Application {
                         let line_data = line.expect("Can't get line").expect("Can't read from stdin");
                         Some(AppEventType::UserInput(line_data))
                     },
-                    network_response = receiver.recv() => {
-                        Some(AppEventType::NetworkInput(network_response.expect("Response doesn't exist.")))
+                    network_event = receiver.recv() => {
+                        match network_event.expect("Response doesn't exist.") {
+                            NetworkEvent::Message(bytes) => Some(AppEventType::NetworkInput(bytes)),
+                            _ => None,
+                        }
                     },
                     _ = net_stack.clear_unhandled_event() => {
                         None
@@ -184,9 +188,9 @@ impl Application {
                             self.outstanding_requests.insert(msg.tag);
                             
                             // Send message to streamlet instances
-                            net_stack.broadcast_message(
-                                serialize(&msg).expect("Can't serialize msg from app!"),
-                            );
+                            if let Err(e) = net_stack.broadcast_message(msg.serialize()) {
+                                error!("Couldn't send to streamlet: {}", e);
+                            }
                         } else if _line.starts_with("request chain") {
                             // Request finalized chain
                             let msg = self.make_latest_chain_request();
@@ -196,51 +200,75 @@ impl Application {
                             self.outstanding_requests.insert(msg.tag);
                             
                             // Send message to streamlet instances
-                            net_stack.broadcast_message(
-                                serialize(&msg).expect("Can't serialize msg from app!"),
-                            );
+                            if let Err(e) = net_stack.broadcast_message(msg.serialize()) {
+                                error!("Couldn't send to streamlet: {}", e);
+                            }
                         } else {
                             // Otherwise: create a new directory
                             let msg = self.make_data();
-                            net_stack.broadcast_message(
-                                serialize(&msg).expect("Can't serialize msg from app!"),
-                            );
+                            if let Err(e) = net_stack.broadcast_message(msg.serialize()) {
+                                error!("Couldn't send to streamlet: {}", e);
+                            }
                         }
 
                     }
                     AppEventType::NetworkInput(input) => {
                         // Received message
-                        let message = Message::deserialize(&input);
+                        let message = match Message::deserialize(&input) {
+                            Some(message) => message,
+                            None => {
+                                debug!("Dropping malformed message");
+                                continue;
+                            }
+                        };
                         info!("Received {:?} message from {}...", &message.kind, &message.sender_name);
 
-                        match &message.kind {
-                            MessageKind::AppBlockResponse => {
+                        match (&message.kind, &message.payload) {
+                            (MessageKind::AppBlockResponse, MessagePayload::SocketAddr(addr)) => {
                                 // For proof-of-concept: only process first response to an outstanding request with matching tag.
                                 if self.outstanding_requests.contains(&message.tag) {
                                     // Process received block
-                                    if let MessagePayload::SocketAddr(addr) = message.payload {
-                                        self.request_block(&addr, &message);
-                                    } else {
-                                        debug!("Unkown payload for MessageKind::AppBlockResponse");
+                                    if let Err(e) = self.request_block(addr, &message) {
+                                        error!("Failed to get block from {}: {}", &message.sender_name, e);
                                     }
                                     // Remove corresponding tag from outstanding requests
                                     self.outstanding_requests.remove(&message.tag);
                                 }
                             }
-                            MessageKind::AppChainResponse => {
+                            (MessageKind::AppChainResponse, MessagePayload::SocketAddr(addr)) => {
                                 // Again, for proof of concept: accept first chain 
                                 if self.outstanding_requests.contains(&message.tag) {
-                                    if let MessagePayload::SocketAddr(addr) = message.payload {
-                                        self.request_chain(&addr, &message);
-                                        // Remove corresponding tag from outstanding requests
-                                        self.outstanding_requests.remove(&message.tag);
-                                    } else {
-                                        debug!("Unknown payload for MessageKind::AppChainResponse");
+                                    if let Err(e) = self.request_chain(addr, &message) {
+                                        error!("Failed to get chain from {}: {}", &message.sender_name, e);
+                                    }
+                                    // Remove corresponding tag from outstanding requests
+                                    self.outstanding_requests.remove(&message.tag);
+                                }
+                            }
+                            (MessageKind::AppReceipt, MessagePayload::Promise(promise)) => {
+                                info!(
+                                    "Entry {} accepted by {}; promised within {} ms",
+                                    promise.entry_id, &message.sender_name, promise.max_merge_delay_ms
+                                );
+                                self.promises.insert((promise.entry_id, message.sender_name.clone()), promise.clone());
+                            }
+                            (MessageKind::AppFinalized, MessagePayload::Inclusion(inclusion)) => {
+                                let id = inclusion.entry_id;
+                                let promise = self.promises.remove(&(id, message.sender_name.clone()));
+                                match (self.submitted.get(&id), promise) {
+                                    (Some(entry), Some(promise)) if promise.is_kept_by(entry, inclusion) => {
+                                        info!("Entry {} finalized by {}; promise kept (tree size {})", id, &message.sender_name, inclusion.sth.tree_size);
+                                    }
+                                    (Some(_), Some(_)) => {
+                                        error!("Entry {} finalized by {}, but its proof doesn't keep the promise", id, &message.sender_name);
+                                    }
+                                    _ => {
+                                        info!("Entry {} finalized by {}", id, &message.sender_name);
                                     }
                                 }
                             }
                             _ => {
-                                debug!("Unknown message format/kind - ignoring");
+                                debug!("Ignoring {:?} message", message.kind);
                             }
                         }
                     }
@@ -252,8 +280,10 @@ impl Application {
     fn make_data(&mut self) -> Message {
         let dir = OnionRouterNetDirectory::new();
         info!("Sending dir to Streamlet: {}", dir);
-        let data =
-            serialize(&dir).expect("Can't serialize a directory!");
+        let entry = LogEntry::new(serialize(&dir).expect("Can't serialize a directory!"));
+        info!("Submitting entry {}", entry.id);
+        let data = entry.serialize();
+        self.submitted.insert(entry.id, data.clone());
         let sig = self.keypair.sign(&data);
         self.curr_nonce += 1;
 
@@ -265,95 +295,94 @@ impl Application {
             "app".to_string(),
         );
         msg.sign_message(sig);
-        return msg;
+        msg
     }
 
     /* Requests the lastest finalized block from streamlet */
     fn make_latest_block_request(&mut self) -> Message {
         self.curr_nonce += 1;
 
-        let msg = Message::new_with_defined_nonce(
+        
+        Message::new_with_defined_nonce(
             MessagePayload::None,
             MessageKind::AppBlockRequest,
             self.curr_nonce,
             APP_SENDER_ID,
             "app".to_string(),
-        );
-        return msg;
+        )
     }
 
     /* Requests the lastest finalized chain from streamlet */
     fn make_latest_chain_request(&mut self) -> Message {
         self.curr_nonce += 1;
 
-        let msg = Message::new_with_defined_nonce(
+        
+        Message::new_with_defined_nonce(
             MessagePayload::None,
             MessageKind::AppChainRequest,
             self.curr_nonce,
             APP_SENDER_ID,
             APP_NAME.to_string(),
-        );
-        return msg;
+        )
     }
 
     /* Request a block, presumed to be either the genesis block or an OnionRouterNetDirectory.
-     */
-    fn request_block(&mut self, addr: &SocketAddr, message: &Message) {
-        let mut stream = TcpStream::connect(addr).expect("Couldn't connect to streamlet instance");
+    Fails if the streamlet instance can't be reached or sends something else. */
+    fn request_block(&mut self, addr: &SocketAddr, message: &Message) -> Result<(), StreamletError> {
+        let mut stream = TcpStream::connect(addr)?;
 
         // Request a block
-        if let Err(e) = stream.write(&String::from("block").into_bytes()) {
-            error!("Failed to request block: {:?}", e);   
-        } else {
-            // Close write stream
-            stream.shutdown(Shutdown::Write).expect("Failed to close write stream");
-
-            // Read the incoming data
-            let mut msg = Vec::new();
-            stream.read_to_end(&mut msg).unwrap();
-            let SignedBlock { block, signatures} = deserialize(&msg).expect("Failed to deserialize block");
-
-            // Close read stream
-            if let Err(_) = stream.shutdown(Shutdown::Read) {
-                // Note: gives error on macOS... https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.shutdown
-                debug!("Error shutting down TCP stream in application (normal on MacOS)");
-            }
+        stream.write_all(&String::from("block").into_bytes())?;
+        // Close write stream
+        stream.shutdown(Shutdown::Write)?;
+
+        // Read the incoming data
+        let mut msg = Vec::new();
+        stream.read_to_end(&mut msg)?;
+        let SignedBlock { block, cert } = deserialize(&msg)?;
+
+        // Close read stream
+        if stream.shutdown(Shutdown::Read).is_err() {
+            // Note: gives error on macOS... https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.shutdown
+            debug!("Error shutting down TCP stream in application (normal on MacOS)");
+        }
 
-            
-            if block.epoch == 0 { // Handle case of block being genesis
-                info!("Recieved genesis block from {} with tag {}", &message.sender_name, message.tag);
-            } else {
-                let directory: OnionRouterNetDirectory =
-                    deserialize(&block.data[..]).expect("Issues unwrapping directory data...");
-                info!("Recieved directory data: {} from {}, with epoch {}, tag: {}, and signatures {:?}", directory, &message.sender_name, block.epoch, message.tag, &signatures);
-            }
+        
+        if block.epoch == 0 { // Handle case of block being genesis
+            info!("Recieved genesis block from {} with tag {}", &message.sender_name, message.tag);
+        } else if block.data.is_empty() { // Leaders propose empty blocks when nothing is pending
+            info!("Recieved empty block from {} with epoch {}, tag {}", &message.sender_name, block.epoch, message.tag);
+        } else {
+            let entry = LogEntry::deserialize(&block.data)
+                .ok_or_else(|| StreamletError::Serialization(String::from("block doesn't hold a log entry")))?;
+            let directory: OnionRouterNetDirectory = deserialize(&entry.data[..])?;
+            info!("Recieved directory data: {} (entry {}) from {}, with epoch {}, tag: {}, and signatures {:?}", directory, entry.id, &message.sender_name, block.epoch, message.tag, &cert.signatures);
         }
+        Ok(())
     }
 
-    fn request_chain(&mut self, addr: &SocketAddr, message: &Message) {
+    fn request_chain(&mut self, addr: &SocketAddr, message: &Message) -> Result<(), StreamletError> {
         // Process received block
     
-        let mut stream = TcpStream::connect(addr).expect("Couldn't connect to streamlet instance");
+        let mut stream = TcpStream::connect(addr)?;
         
         // Request a chain
-        if let Err(e) = stream.write(&String::from("chain").into_bytes()) {
-            error!("Failed to request chain: {:?}", e);   
-        } else {
-            // Close write stream
-            stream.shutdown(Shutdown::Write).expect("Failed to close write stream");
-
-            // Read the incoming data
-            let mut msg = Vec::new();
-            stream.read_to_end(&mut msg).unwrap();
-            let chain: LocalChain = deserialize(&msg).expect("Failed to deserialize blockchain");
-            info!("Recieved chain: {} from {} with tag {}", chain, &message.sender_name, message.tag);
-
-            // Close read stream
-            if let Err(_e) = stream.shutdown(Shutdown::Read) {
-                // Note: gives error on macOS... https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.shutdown
-                debug!("Error shutting down TCP stream in application (normal on MacOS)");
-            }
+        stream.write_all(&String::from("chain").into_bytes())?;
+        // Close write stream
+        stream.shutdown(Shutdown::Write)?;
+
+        // Read the incoming data
+        let mut msg = Vec::new();
+        stream.read_to_end(&mut msg)?;
+        let chain: LocalChain = deserialize(&msg)?;
+        info!("Recieved chain: {} from {} with tag {}", chain, &message.sender_name, message.tag);
+
+        // Close read stream
+        if let Err(_e) = stream.shutdown(Shutdown::Read) {
+            // Note: gives error on macOS... https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.shutdown
+            debug!("Error shutting down TCP stream in application (normal on MacOS)");
         }
+        Ok(())
     }
 }
 
@@ -2,14 +2,15 @@
    along with data meant to be shared between the application and the Streamlet instance. 
    Note: this is currently very bare-bones; it's meant to show what the API is. */
 
+use crate::error::StreamletError;
 use crate::network::NetworkStack;
 use crate::messages::*;
 
 pub struct AppInterface;
 
 pub const APP_SENDER_ID: u32 = 0;
-pub const APP_NET_TOPIC: &'static str = "app";
-pub const APP_NAME: &'static str = "app";
+pub const APP_NET_TOPIC: &str = "app";
+pub const APP_NAME: &str = "app";
 
 
 impl AppInterface {
@@ -21,16 +22,16 @@ impl AppInterface {
         Self
     }
 
-    pub fn send_to_app(&self, net_stack: &mut NetworkStack, msg: Vec<u8>) {
-        net_stack.broadcast_to_topic(APP_NET_TOPIC, msg);
+    pub fn send_to_app(&self, net_stack: &mut NetworkStack, msg: Vec<u8>) -> Result<(), StreamletError> {
+        net_stack.broadcast_to_topic(APP_NET_TOPIC, msg)
     }
 
     /* CUSTOMIZABLE: Do we consider this message to be from the application? */
     pub fn message_is_from_app(&self, message: &Message) -> bool {
-        return message.kind == MessageKind::AppSend &&
+        message.kind == MessageKind::AppSend &&
             message.sender_id == APP_SENDER_ID &&
-            message.sender_name == APP_NAME.to_string() &&
-            self.data_is_valid(message);
+            message.sender_name == APP_NAME &&
+            self.data_is_valid(message)
     }
 
     /* CUSTOMIZABLE: should this data be accepted? Do we consider it to be from the app? 
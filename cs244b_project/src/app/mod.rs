@@ -1,5 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod app;
 pub mod app_interface;
 
 pub use app::*;
-pub use app_interface::*;
@@ -0,0 +1,287 @@
+/* Auditor: lets someone who doesn't run consensus check that an entry is in the log.
+   It takes a node's signed tree head and an audit path for the entry's leaf hash, as
+   served by the HTTP API (see http_api), and verifies both locally: the tree head's
+   signature, and that the path leads from the leaf to the signed root. Nothing the node
+   says is trusted beyond what the signature covers.
+   A client that audits repeatedly pins the latest tree head it verified (SthPin). Every
+   later head must then be consistent with the pinned one: the same root at the same
+   size, or a consistency proof between the two sizes. A node that shows this client a
+   different log than everyone else (a split view) has to stay on that fork forever, and
+   is caught as soon as the client sees an honest head, e.g. one from another node.
+   Clients can also share the heads they see with an auditor, so forks shown to
+   different clients get compared. */
+
+use crate::blockchain::{verify_consistency, verify_leaf_inclusion, AuditPath, ChainId, ConsistencyProof, SignedTreeHead};
+use crate::json_schema::check_version;
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn hex_field(value: &Value, name: &str) -> Result<Vec<u8>, String> {
+    value[name]
+        .as_str()
+        .and_then(|field| hex::decode(field).ok())
+        .ok_or_else(|| format!("'{}' is missing or not hex", name))
+}
+
+fn hash_field(value: &Value, name: &str) -> Result<Sha256Hash, String> {
+    Sha256Hash::try_from(hex_field(value, name)?.as_slice()).map_err(|_| format!("'{}' is not a SHA-256 hash", name))
+}
+
+fn number_field(value: &Value, name: &str) -> Result<u64, String> {
+    value[name].as_u64().ok_or_else(|| format!("'{}' is missing or not a number", name))
+}
+
+/* Reads a get-sth response: the tree head, and the key the node says it signs with.
+@param value: the response body */
+pub fn parse_sth(value: &Value) -> Result<(SignedTreeHead, PublicKey), String> {
+    check_version(value)?;
+    let signature = bincode::deserialize(&hex_field(value, "tree_head_signature")?)
+        .map_err(|_| String::from("'tree_head_signature' is not a signature"))?;
+    let public_key = bincode::deserialize(&hex_field(value, "public_key")?)
+        .map_err(|_| String::from("'public_key' is not a public key"))?;
+    let sth = SignedTreeHead {
+        tree_size: number_field(value, "tree_size")?,
+        root_hash: hash_field(value, "sha256_root_hash")?,
+        timestamp_ms: number_field(value, "timestamp")?,
+        signer: value["signer"].as_str().unwrap_or_default().to_string(),
+        signature,
+    };
+    Ok((sth, public_key))
+}
+
+/* Reads a get-proof-by-hash response.
+@param value: the response body
+@param tree_size: the tree size the proof was requested for */
+pub fn parse_audit_path(value: &Value, tree_size: u64) -> Result<AuditPath, String> {
+    check_version(value)?;
+    let path = value["audit_path"]
+        .as_array()
+        .ok_or_else(|| String::from("'audit_path' is missing"))?
+        .iter()
+        .map(|hash| {
+            hash.as_str()
+                .and_then(|hash| hex::decode(hash).ok())
+                .and_then(|hash| Sha256Hash::try_from(hash.as_slice()).ok())
+                .ok_or_else(|| String::from("'audit_path' holds something that isn't a hex hash"))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(AuditPath { leaf_index: number_field(value, "leaf_index")?, tree_size, path })
+}
+
+/* Reads a get-sth-consistency response.
+@param value: the response body
+@param old_size, new_size: the tree sizes the proof was requested for */
+pub fn parse_consistency(value: &Value, old_size: u64, new_size: u64) -> Result<ConsistencyProof, String> {
+    check_version(value)?;
+    let path = value["consistency"]
+        .as_array()
+        .ok_or_else(|| String::from("'consistency' is missing"))?
+        .iter()
+        .map(|hash| {
+            hash.as_str()
+                .and_then(|hash| hex::decode(hash).ok())
+                .and_then(|hash| Sha256Hash::try_from(hash.as_slice()).ok())
+                .ok_or_else(|| String::from("'consistency' holds something that isn't a hex hash"))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ConsistencyProof { old_size, new_size, path })
+}
+
+/* The latest tree head a client verified, kept between runs. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SthPin {
+    pub chain_id: ChainId,
+    pub public_key: PublicKey,
+    pub sth: SignedTreeHead,
+}
+
+impl SthPin {
+    /* Pins a first tree head; checks its signature.
+    @param sth: the tree head
+    @param public_key: the node's key
+    @param chain_id: the deployment's chain id */
+    pub fn new(sth: SignedTreeHead, public_key: PublicKey, chain_id: ChainId) -> Result<Self, String> {
+        if !sth.verify(&public_key, &chain_id) {
+            return Err(format!("tree head signature from {} doesn't verify", sth.signer));
+        }
+        Ok(Self { chain_id, public_key, sth })
+    }
+
+    /* Reads a pin file (None: there isn't one yet). */
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| format!("{} is not a pinned tree head: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Can't read {}: {}", path.display(), e)),
+        }
+    }
+
+    /* Writes the pin file in one step, so a crash leaves the old pin or the new one. */
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec_pretty(self).expect("Failed serialization.");
+        fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, path)).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    /* The consistency proof needed to accept a tree head of the given size, as
+    (old size, new size); None when the sizes match and the roots can be compared. */
+    pub fn proof_needed(&self, tree_size: u64) -> Option<(u64, u64)> {
+        match tree_size.cmp(&self.sth.tree_size) {
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some((self.sth.tree_size, tree_size)),
+            std::cmp::Ordering::Less => Some((tree_size, self.sth.tree_size)),
+        }
+    }
+
+    /* Checks a tree head against the pin, and pins it if it is newer. An older head
+    (e.g. from a node that is behind) is accepted if the pinned log extends it.
+    Returns whether the pin moved.
+    @param sth: the tree head
+    @param public_key: the key it should verify under (must be the pinned one)
+    @param proof: consistency proof for the sizes from proof_needed */
+    pub fn advance(&mut self, sth: &SignedTreeHead, public_key: &PublicKey, proof: Option<&ConsistencyProof>) -> Result<bool, String> {
+        if *public_key != self.public_key {
+            return Err(format!("{} signs with a different key than the pinned one", sth.signer));
+        }
+        if !sth.verify(public_key, &self.chain_id) {
+            return Err(format!("tree head signature from {} doesn't verify", sth.signer));
+        }
+        let sizes = match self.proof_needed(sth.tree_size) {
+            None if sth.root_hash == self.sth.root_hash => return Ok(false),
+            None => return Err(format!("split view: tree size {} has a different root than the pinned head", sth.tree_size)),
+            Some(sizes) => sizes,
+        };
+        let proof = proof.ok_or_else(|| format!("no consistency proof from tree size {} to {}", sizes.0, sizes.1))?;
+        if (proof.old_size, proof.new_size) != sizes {
+            return Err(format!("consistency proof is from {} to {}, expected {} to {}", proof.old_size, proof.new_size, sizes.0, sizes.1));
+        }
+        let newer = sth.tree_size > self.sth.tree_size;
+        let (old_root, new_root) = if newer { (&self.sth.root_hash, &sth.root_hash) } else { (&sth.root_hash, &self.sth.root_hash) };
+        if !verify_consistency(proof, old_root, new_root) {
+            return Err(format!("tree head of size {} is not consistent with the pinned one of size {}", sth.tree_size, self.sth.tree_size));
+        }
+        if newer {
+            self.sth = sth.clone();
+        }
+        Ok(newer)
+    }
+}
+
+/* Verifies that a leaf is in the log as of a signed tree head.
+@param sth: the node's tree head
+@param public_key: the node's key (pin it rather than taking the node's word for it)
+@param chain_id: the deployment's chain id
+@param leaf: the entry's leaf hash
+@param proof: audit path for the leaf, at the tree head's size */
+pub fn audit(
+    sth: &SignedTreeHead,
+    public_key: &PublicKey,
+    chain_id: &ChainId,
+    leaf: &Sha256Hash,
+    proof: &AuditPath,
+) -> Result<(), String> {
+    if !sth.verify(public_key, chain_id) {
+        return Err(format!("tree head signature from {} doesn't verify", sth.signer));
+    }
+    if proof.tree_size != sth.tree_size {
+        return Err(format!("proof is for tree size {}, tree head has {}", proof.tree_size, sth.tree_size));
+    }
+    if !verify_leaf_inclusion(leaf, proof, &sth.root_hash) {
+        return Err(String::from("audit path doesn't lead to the signed root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{leaf_hash, MerkleTree};
+    use crate::json_schema::{consistency_json, hex_list, inclusion_json, sth_json};
+    use serde_json::json;
+
+    #[test]
+    fn test_audit_from_api_responses() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let mut tree = MerkleTree::new();
+        for entry in [&b"a"[..], b"b", b"c"] {
+            tree.push(entry);
+        }
+        let signed = SignedTreeHead::sign(&tree, String::from("h1"), &keypair, &ChainId::default());
+        let sth_response = json!({
+            "tree_size": signed.tree_size,
+            "timestamp": signed.timestamp_ms,
+            "sha256_root_hash": hex::encode(signed.root_hash),
+            "signer": signed.signer,
+            "tree_head_signature": hex::encode(bincode::serialize(&signed.signature).unwrap()),
+            "public_key": hex::encode(bincode::serialize(&keypair.public()).unwrap()),
+        });
+        let (sth, public_key) = parse_sth(&sth_response).unwrap();
+        assert_eq!(sth, signed);
+        assert_eq!(parse_sth(&sth_json(&signed, &keypair.public())), Ok((sth.clone(), public_key)));
+        let mut newer = sth_json(&signed, &keypair.public());
+        newer["schema_version"] = json!(2);
+        assert!(parse_sth(&newer).is_err());
+
+        let inclusion = tree.prove_inclusion(b"b").unwrap();
+        let proof_response = json!({ "leaf_index": inclusion.leaf_index, "audit_path": hex_list(&inclusion.path) });
+        let proof = parse_audit_path(&proof_response, sth.tree_size).unwrap();
+        assert_eq!(parse_audit_path(&inclusion_json(&inclusion), sth.tree_size), Ok(proof.clone()));
+        assert_eq!(audit(&sth, &public_key, &ChainId::default(), &leaf_hash(b"b"), &proof), Ok(()));
+        assert!(audit(&sth, &public_key, &ChainId::new("test"), &leaf_hash(b"b"), &proof).is_err());
+
+        assert!(audit(&sth, &public_key, &ChainId::default(), &leaf_hash(b"x"), &proof).is_err());
+        let other_key = Keypair::generate(SignatureScheme::default()).public();
+        assert!(audit(&sth, &other_key, &ChainId::default(), &leaf_hash(b"b"), &proof).is_err());
+        assert!(parse_sth(&json!({ "tree_size": 3 })).is_err());
+    }
+
+    #[test]
+    fn test_pinned_heads_only_move_forward_consistently() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let chain_id = ChainId::default();
+        let mut tree = MerkleTree::new();
+        for entry in [&b"a"[..], b"b", b"c"] {
+            tree.push(entry);
+        }
+        let first = SignedTreeHead::sign(&tree, String::from("h1"), &keypair, &chain_id);
+        let mut pin = SthPin::new(first.clone(), keypair.public(), chain_id).unwrap();
+        let mut fork = tree.clone();
+        tree.push(b"d");
+        tree.push(b"e");
+        let second = SignedTreeHead::sign(&tree, String::from("h1"), &keypair, &chain_id);
+
+        // A newer head needs a proof from the pinned size
+        assert_eq!(pin.proof_needed(5), Some((3, 5)));
+        assert!(pin.advance(&second, &keypair.public(), None).is_err());
+        let response = consistency_json(&tree.prove_consistency(3, 5).unwrap());
+        let proof = parse_consistency(&response, 3, 5).unwrap();
+        assert_eq!(pin.advance(&second, &keypair.public(), Some(&proof)), Ok(true));
+        assert_eq!(pin.sth, second);
+        // A lagging node's older head is fine, but doesn't move the pin back
+        assert_eq!(pin.advance(&first, &keypair.public(), Some(&proof)), Ok(false));
+        assert_eq!(pin.sth, second);
+
+        // A forked log is caught at the same size and across sizes
+        fork.push(b"x");
+        fork.push(b"y");
+        let forked = SignedTreeHead::sign(&fork, String::from("h1"), &keypair, &chain_id);
+        assert!(pin.advance(&forked, &keypair.public(), None).unwrap_err().starts_with("split view"));
+        fork.push(b"z");
+        let forked = SignedTreeHead::sign(&fork, String::from("h1"), &keypair, &chain_id);
+        assert!(pin.advance(&forked, &keypair.public(), fork.prove_consistency(5, 6).as_ref()).is_err());
+        let other_key = Keypair::generate(SignatureScheme::default()).public();
+        assert!(pin.advance(&second, &other_key, None).is_err());
+
+        let path = std::env::temp_dir().join(format!("streamlet-pin-test-{}.json", std::process::id()));
+        pin.save(&path).unwrap();
+        assert_eq!(SthPin::load(&path), Ok(Some(pin)));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(SthPin::load(&path), Ok(None));
+    }
+}
@@ -0,0 +1,198 @@
+/* auditor: checks that an entry is in the log, using a node's HTTP API (see http_api),
+   without running consensus. Fetches the node's latest signed tree head and an audit
+   path for the entry, then verifies both locally (see the auditor module).
+
+   Usage:
+     auditor <addr:port> hash <leaf hash hex>        entry given by its Merkle leaf hash
+     auditor <addr:port> entry <hex | @file>         entry given by its bytes, as stored in its block
+   Options:
+     --public-key <hex>   the node's key, as printed by an earlier audit. Without it the
+                          key the node serves is used, which only shows the node is
+                          self-consistent.
+     --deployment <name>  the deployment's name, as given to its nodes (default: none)
+     --pin <file>         remember the latest verified tree head in this file, and require
+                          every later one to be consistent with it (split-view protection).
+                          The first run pins the node's current head and key.
+     --share-sth <addr:port/path>  also POST every tree head seen (get-sth JSON) to an
+                          auditor collecting them, so forks shown to different clients
+                          get compared
+
+   Exit code 0: the entry is in the log; 2: verification failed; 1: anything else. */
+
+use cs244b_project::auditor::{audit, parse_audit_path, parse_consistency, parse_sth, SthPin};
+use cs244b_project::json_schema::sth_json;
+use cs244b_project::{leaf_hash, ChainId, PublicKey, Sha256Hash};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: auditor <addr:port> <hash <hex> | entry <hex | @file>> [--public-key <hex>] [--deployment <name>] [--pin <file>] [--share-sth <addr:port/path>]");
+    exit(1);
+}
+
+// Removes "<flag> <value>" from the arguments
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Some(value)
+        }
+        Some(_) => usage(),
+        None => None,
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    exit(1);
+}
+
+fn read_entry(arg: &str) -> Vec<u8> {
+    if let Some(path) = arg.strip_prefix('@') {
+        std::fs::read(path).unwrap_or_else(|e| fail(format!("Can't read {}: {}", path, e)))
+    } else {
+        hex::decode(arg.trim()).unwrap_or_else(|e| fail(format!("Entry is not valid hex: {}", e)))
+    }
+}
+
+/* Sends an HTTP request and returns the raw response (head, body). */
+fn exchange(addr: &str, request: &str) -> Result<(String, String), String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("Can't connect to {}: {}", addr, e))?;
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(|e| e.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| String::from("Malformed HTTP response"))?;
+    Ok((head.to_string(), body.to_string()))
+}
+
+/* GETs a JSON document from the node's HTTP API. */
+fn get(addr: &str, path: &str) -> Result<Value, String> {
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+    let (head, body) = exchange(addr, &request)?;
+    let body: Value = serde_json::from_str(&body).map_err(|_| format!("{} didn't return JSON", path))?;
+    if !head.starts_with("HTTP/1.1 200") {
+        return Err(format!("{}: {}", path, body["error"].as_str().unwrap_or(head.lines().next().unwrap_or_default())));
+    }
+    Ok(body)
+}
+
+/* POSTs a JSON document to addr:port/path. */
+fn post(endpoint: &str, body: &Value) -> Result<(), String> {
+    let (addr, path) = match endpoint.find('/') {
+        Some(i) => (&endpoint[..i], &endpoint[i..]),
+        None => (endpoint, "/"),
+    };
+    let body = body.to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        addr,
+        body.len(),
+        body
+    );
+    let (head, _) = exchange(addr, &request)?;
+    if !head.starts_with("HTTP/1.1 2") {
+        return Err(head.lines().next().unwrap_or_default().to_string());
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let pinned_key = take_option(&mut args, "--public-key").map(|key| {
+        let bytes = hex::decode(key.trim()).unwrap_or_else(|e| fail(format!("Key is not valid hex: {}", e)));
+        bincode::deserialize::<PublicKey>(&bytes).unwrap_or_else(|_| fail(String::from("Not a public key")))
+    });
+    let chain_id = take_option(&mut args, "--deployment").map(|deployment| ChainId::new(&deployment)).unwrap_or_default();
+    let pin_file = take_option(&mut args, "--pin").map(PathBuf::from);
+    let share_sth = take_option(&mut args, "--share-sth");
+    if args.len() != 3 {
+        usage();
+    }
+    let addr = &args[0];
+    let leaf: Sha256Hash = match args[1].as_str() {
+        "hash" => hex::decode(args[2].trim())
+            .ok()
+            .and_then(|hash| Sha256Hash::try_from(hash.as_slice()).ok())
+            .unwrap_or_else(|| fail(String::from("Leaf hash should be 64 hex digits"))),
+        "entry" => leaf_hash(&read_entry(&args[2])),
+        _ => usage(),
+    };
+
+    let sth_response = get(addr, "/ct/v1/get-sth").unwrap_or_else(|e| fail(e));
+    let (sth, served_key) = parse_sth(&sth_response).unwrap_or_else(|e| fail(format!("Bad tree head: {}", e)));
+    if let Some(endpoint) = &share_sth {
+        if let Err(e) = post(endpoint, &sth_json(&sth, &served_key)) {
+            eprintln!("WARNING: couldn't share the tree head with {}: {}", endpoint, e);
+        }
+    }
+
+    // Check the head against the pinned one before trusting it with anything
+    let pin = pin_file.as_ref().map(|path| SthPin::load(path).unwrap_or_else(|e| fail(e)));
+    let pinned_key = match pin.as_ref().and_then(|pin| pin.as_ref()) {
+        Some(pin) if pin.chain_id != chain_id => fail(String::from("The pin file is for a different deployment")),
+        Some(pin) => Some(pinned_key.unwrap_or(pin.public_key)),
+        None => pinned_key,
+    };
+    let pin = match pin {
+        Some(Some(mut pin)) => {
+            let proof = pin.proof_needed(sth.tree_size).map(|(old_size, new_size)| {
+                let path = format!("/ct/v1/get-sth-consistency?first={}&second={}", old_size, new_size);
+                let response = get(addr, &path)
+                    .unwrap_or_else(|e| fail(format!("Can't get a consistency proof with the pinned tree head: {}", e)));
+                parse_consistency(&response, old_size, new_size).unwrap_or_else(|e| fail(format!("Bad consistency proof: {}", e)))
+            });
+            match pin.advance(&sth, pinned_key.as_ref().unwrap_or(&served_key), proof.as_ref()) {
+                Ok(true) => println!("Pinned tree head moved from size {} to {}", proof.map_or(0, |proof| proof.old_size), sth.tree_size),
+                Ok(false) => {}
+                Err(e) => {
+                    println!("FAILED: {}", e);
+                    exit(2);
+                }
+            }
+            Some(pin)
+        }
+        Some(None) => match SthPin::new(sth.clone(), pinned_key.unwrap_or(served_key), chain_id) {
+            Ok(pin) => Some(pin),
+            Err(e) => {
+                println!("FAILED: {}", e);
+                exit(2);
+            }
+        },
+        None => None,
+    };
+    let proof_path = format!("/ct/v1/get-proof-by-hash?hash={}&tree_size={}", hex::encode(leaf), sth.tree_size);
+    let proof_response = get(addr, &proof_path).unwrap_or_else(|e| fail(e));
+    let proof = parse_audit_path(&proof_response, sth.tree_size).unwrap_or_else(|e| fail(format!("Bad proof: {}", e)));
+
+    if pinned_key.is_none() {
+        println!("WARNING: no --public-key given; checking against the key {} serves", sth.signer);
+    }
+    let public_key = pinned_key.unwrap_or(served_key);
+    match audit(&sth, &public_key, &chain_id, &leaf, &proof) {
+        Ok(()) => {
+            println!(
+                "OK: leaf {} is entry {} of {} in the tree head {} signed at {} ms (root {})",
+                hex::encode(leaf),
+                proof.leaf_index,
+                sth.tree_size,
+                sth.signer,
+                sth.timestamp_ms,
+                hex::encode(sth.root_hash)
+            );
+            println!("{}'s key: {}", sth.signer, hex::encode(bincode::serialize(&public_key).expect("Failed serialization.")));
+            if let (Some(pin), Some(path)) = (pin, &pin_file) {
+                pin.save(path).unwrap_or_else(|e| fail(e));
+            }
+        }
+        Err(e) => {
+            println!("FAILED: {}", e);
+            exit(2);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+/* monitor: watches the tree heads Streamlet nodes publish and reports misbehavior
+   (bad signatures, rollbacks, inconsistent histories, split views; see the monitor
+   module). Runs alongside the nodes on the same network; it doesn't take part in
+   consensus.
+
+   Usage:
+     monitor [--deployment <name>] [--bootstrap <multiaddr,...>]
+   Options:
+     --deployment <name>            the deployment's name, as given to its nodes
+     --bootstrap <multiaddr,...>    peers to dial at startup, beyond the ones mDNS finds
+
+   Each alert is printed on its own line. Evidence of misbehavior is prefixed with
+   "ALERT", unverifiable steps (e.g. heads the monitor missed) with "WARNING". */
+
+use cs244b_project::monitor::{Monitor, STH_TOPIC};
+use cs244b_project::{telemetry, ChainId, Message, MessagePayload, Multiaddr, NetworkEvent, NetworkStack, EVENT_QUEUE_CAPACITY};
+use tokio::select;
+use tokio::sync::mpsc;
+
+fn usage() -> ! {
+    eprintln!("usage: monitor [--deployment <name>] [--bootstrap <multiaddr,...>]");
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    telemetry::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut chain_id = ChainId::default();
+    let mut bootstrap: Vec<Multiaddr> = Vec::new();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, deployment] if flag == "--deployment" => chain_id = ChainId::new(deployment),
+            [flag, peers] if flag == "--bootstrap" => {
+                bootstrap = peers.split(',').map(|peer| peer.trim().parse().unwrap_or_else(|_| usage())).collect()
+            }
+            _ => usage(),
+        }
+    }
+    let (sender, mut receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+    let mut net_stack = NetworkStack::new(STH_TOPIC, sender, &bootstrap).await;
+    let mut monitor = Monitor::new(chain_id);
+    println!("Watching tree heads on topic \"{}\"", STH_TOPIC);
+
+    loop {
+        select! {
+            event = receiver.recv() => {
+                let bytes = match event {
+                    Some(NetworkEvent::Message(bytes)) => bytes,
+                    Some(_) => continue,
+                    None => return,
+                };
+                let update = match Message::deserialize(&bytes) {
+                    Some(Message { payload: MessagePayload::TreeHead(update), .. }) => update,
+                    _ => continue,
+                };
+                for alert in monitor.observe(&update) {
+                    let level = if alert.is_misbehavior() { "ALERT" } else { "WARNING" };
+                    println!("{}: {}", level, alert);
+                }
+                println!(
+                    "{}: tree size {}, root {}",
+                    update.sth.signer,
+                    update.sth.tree_size,
+                    hex::encode(update.sth.root_hash)
+                );
+            },
+            _ = net_stack.clear_unhandled_event() => {},
+        }
+    }
+}
@@ -0,0 +1,99 @@
+/* relay: joins the gossip mesh to forward traffic between nodes, without taking part
+   in consensus or storing the chain (see the relay module). Run relays where they help
+   connectivity, e.g. one per region or NAT; they also answer catch-up requests from
+   the blocks they have seen recently. A relay is also a circuit relay: nodes behind NAT
+   listen through it (their --relay option), and other nodes reach them that way.
+
+   Usage:
+     relay [--retain <blocks>] [--bootstrap <multiaddr,...>] [--listen <multiaddr>]
+   Options:
+     --retain <blocks>              how many recent notarized blocks to keep (default 256)
+     --bootstrap <multiaddr,...>    peers to dial at startup, beyond the ones mDNS finds
+     --listen <multiaddr>           also listen here, so nodes can bootstrap from the relay */
+
+use cs244b_project::relay::{RecentBlocks, DEFAULT_RELAY_RETENTION};
+use cs244b_project::monitor::STH_TOPIC;
+use cs244b_project::{envelope, telemetry, Message, MessageKind, MessagePayload, Multiaddr, NatConfig, NetworkEvent, NetworkStack, StreamletInstance, EVENT_QUEUE_CAPACITY, APP_NET_TOPIC, ROSTER_TOPIC};
+use std::process::exit;
+use tokio::select;
+use tokio::sync::mpsc;
+
+const RELAY_NAME: &str = "relay";
+
+fn usage() -> ! {
+    eprintln!("usage: relay [--retain <blocks>] [--bootstrap <multiaddr,...>] [--listen <multiaddr>]");
+    exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    telemetry::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut retention = DEFAULT_RELAY_RETENTION;
+    let mut bootstrap: Vec<Multiaddr> = Vec::new();
+    let mut listen: Option<Multiaddr> = None;
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, blocks] if flag == "--retain" => retention = blocks.parse().unwrap_or_else(|_| usage()),
+            [flag, peers] if flag == "--bootstrap" => {
+                bootstrap = peers.split(',').map(|peer| peer.trim().parse().unwrap_or_else(|_| usage())).collect()
+            }
+            [flag, addr] if flag == "--listen" => listen = Some(addr.parse().unwrap_or_else(|_| usage())),
+            _ => usage(),
+        }
+    }
+
+    let (sender, mut receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+    let nat = NatConfig { relay_server: true, relays: Vec::new() };
+    let mut net_stack = NetworkStack::with_nat(StreamletInstance::STREAMLET_TOPIC, sender, &bootstrap, &nat).await;
+    if let Some(addr) = &listen {
+        net_stack.listen_on(addr).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
+    }
+    // Subscribing is what makes gossipsub route a topic's traffic through us
+    for topic in [APP_NET_TOPIC, ROSTER_TOPIC, STH_TOPIC] {
+        net_stack.add_topic(topic);
+    }
+    let mut recent = RecentBlocks::new(retention);
+    println!("Relaying; keeping the latest {} notarized blocks", retention);
+
+    loop {
+        select! {
+            event = receiver.recv() => {
+                let bytes = match event {
+                    Some(NetworkEvent::Message(bytes)) => bytes,
+                    Some(NetworkEvent::ListenAddr(addr)) => {
+                        println!("Listening on {}", addr);
+                        continue;
+                    }
+                    Some(_) => continue,
+                    None => return,
+                };
+                // Consensus messages come sealed; the relay reads them without checking the seal
+                let message = match envelope::decode_message(&bytes) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                recent.observe(&message);
+                if let (MessageKind::SyncRequest, MessagePayload::ChainSyncRequest(request)) = (&message.kind, &message.payload) {
+                    if let Some(chain) = recent.chain_for(request) {
+                        println!("Sending {} blocks to {} for catch-up", chain.blocks.len() - 1, message.sender_name);
+                        let response = Message::new_with_defined_tag(
+                            MessagePayload::Chain(chain),
+                            MessageKind::SyncResponse,
+                            message.tag,
+                            0,
+                            RELAY_NAME.to_string(),
+                        );
+                        if let Err(e) = net_stack.respond(message.tag, &message.sender_name, response.serialize()) {
+                            eprintln!("Couldn't send blocks to {}: {}", message.sender_name, e);
+                        }
+                    }
+                }
+            },
+            _ = net_stack.clear_unhandled_event() => {},
+        }
+    }
+}
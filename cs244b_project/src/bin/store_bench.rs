@@ -0,0 +1,232 @@
+/* store-bench: replays synthetic chain workloads against each ChainStore backend.
+
+   Usage:
+     store-bench [--epochs N] [--entry-sizes 64,1024,...] [--fork-rates 0,0.1,...]
+                 [--miss-rates 0,0.2,...] [--dir <scratch dir>]
+
+   Each workload notarizes one block per epoch, with the given entry size. With
+   probability fork-rate a competing sibling block is also notarized, and with
+   probability miss-rate the epoch produces no block at all (which delays finalization).
+   Reported per backend and workload:
+     - write amp: bytes the process wrote (write syscalls) / bytes of blocks stored
+     - space amp: size of the store directory / bytes of blocks stored
+     - p99 / max put: latency of individual writes; spikes are compaction or flush pauses
+     - recovery: time to reopen the store and rebuild the chain, as after a restart */
+
+use cs244b_project::{Block, BlockchainManager, Chain, ChainStore, SignedBlock, SledStore, StoreError};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Backend {
+    name: &'static str,
+    open: fn(&Path) -> Result<Box<dyn ChainStore>, StoreError>,
+}
+
+fn open_sled(path: &Path) -> Result<Box<dyn ChainStore>, StoreError> {
+    // No background flusher: SledStore flushes on every write anyway, and this lets
+    // the directory lock go as soon as the store is dropped (needed for recovery runs)
+    let db = sled::Config::new().path(path).flush_every_ms(None).open()?;
+    Ok(Box::new(SledStore::from_db(db)?))
+}
+
+const BACKENDS: &[Backend] = &[Backend { name: "sled", open: open_sled }];
+
+#[derive(Default)]
+struct Stats {
+    logical_bytes: u64,
+    put_latencies: Vec<Duration>,
+}
+
+/* Wraps a backend to count what is stored and how long each write takes. */
+struct MeasuredStore {
+    inner: Box<dyn ChainStore>,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl ChainStore for MeasuredStore {
+    fn put_block(&mut self, block: &SignedBlock) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.put_block(block);
+        let mut stats = self.stats.lock().expect("Stats lock poisoned");
+        stats.put_latencies.push(start.elapsed());
+        stats.logical_bytes += bincode::serialized_size(block).unwrap_or(0);
+        result
+    }
+    fn remove_block(&mut self, height: u64, hash: &[u8; 32]) -> Result<(), StoreError> {
+        self.inner.remove_block(height, hash)
+    }
+    fn set_finalized_tip(&mut self, hash: &[u8; 32]) -> Result<(), StoreError> {
+        self.inner.set_finalized_tip(hash)
+    }
+    fn blocks(&self) -> Result<Vec<SignedBlock>, StoreError> {
+        self.inner.blocks()
+    }
+    fn finalized_tip(&self) -> Result<Option<[u8; 32]>, StoreError> {
+        self.inner.finalized_tip()
+    }
+}
+
+struct Workload {
+    epochs: u64,
+    entry_size: usize,
+    fork_rate: f64,
+    miss_rate: f64,
+}
+
+struct Report {
+    write_amp: Option<f64>,
+    space_amp: f64,
+    p99_put: Duration,
+    max_put: Duration,
+    recovery: Duration,
+    finalized: usize,
+}
+
+/* Bytes passed to write() by this process so far (Linux only). */
+fn process_bytes_written() -> Option<u64> {
+    let io = fs::read_to_string("/proc/self/io").ok()?;
+    io.lines()
+        .find_map(|line| line.strip_prefix("wchar: "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn run(backend: &Backend, workload: &Workload, path: &Path) -> Result<Report, StoreError> {
+    let _ = fs::remove_dir_all(path);
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let store = MeasuredStore { inner: (backend.open)(path)?, stats: stats.clone() };
+    let mut manager = BlockchainManager::with_store(Box::new(store))?;
+    let mut rng = StdRng::seed_from_u64(workload.epochs ^ workload.entry_size as u64);
+
+    let written_before = process_bytes_written();
+    for epoch in 1..=workload.epochs {
+        if rng.gen_bool(workload.miss_rate) {
+            continue;
+        }
+        let parent = manager.head().0.clone();
+        let data: Vec<u8> = (0..workload.entry_size).map(|_| rng.gen()).collect();
+        let block = Block::new(epoch, parent.hash, data, parent.height + 1, rng.gen());
+        manager.add_notarized_block(block, Vec::new());
+        if rng.gen_bool(workload.fork_rate) {
+            // A competing block at the same height; pruned once the other side finalizes
+            let sibling = Block::new(epoch, parent.hash, vec![0u8; workload.entry_size], parent.height + 1, rng.gen());
+            manager.add_notarized_block(sibling, Vec::new());
+        }
+    }
+    let written = process_bytes_written().zip(written_before).map(|(after, before)| after - before);
+    let finalized = manager.finalized_chain().length();
+    drop(manager);
+
+    let stats = stats.lock().expect("Stats lock poisoned");
+    let logical = stats.logical_bytes.max(1) as f64;
+    let mut latencies = stats.put_latencies.clone();
+    latencies.sort();
+    let p99_put = latencies.get(latencies.len() * 99 / 100).copied().unwrap_or_default();
+    let max_put = latencies.last().copied().unwrap_or_default();
+    let space_amp = dir_size(path) as f64 / logical;
+
+    let start = Instant::now();
+    let recovered = BlockchainManager::with_store((backend.open)(path)?)?;
+    let recovery = start.elapsed();
+    assert_eq!(recovered.finalized_chain().length(), finalized, "recovered a different finalized chain");
+    drop(recovered);
+    let _ = fs::remove_dir_all(path);
+
+    Ok(Report {
+        write_amp: written.map(|bytes| bytes as f64 / logical),
+        space_amp,
+        p99_put,
+        max_put,
+        recovery,
+        finalized,
+    })
+}
+
+fn parse_list<T: std::str::FromStr>(flags: &HashMap<String, String>, name: &str, default: &str) -> Vec<T> {
+    let value = flags.get(name).map(String::as_str).unwrap_or(default);
+    value
+        .split(',')
+        .map(|item| {
+            item.parse().unwrap_or_else(|_| {
+                eprintln!("Invalid value for --{}: {}", name, item);
+                exit(1);
+            })
+        })
+        .collect()
+}
+
+fn main() {
+    let mut flags = HashMap::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(flag) => {
+                flags.insert(flag.to_string(), args.next().unwrap_or_default());
+            }
+            None => {
+                eprintln!("usage: store-bench [--epochs N] [--entry-sizes a,b] [--fork-rates x,y] [--miss-rates x,y] [--dir path]");
+                exit(1);
+            }
+        }
+    }
+    let epochs: u64 = parse_list(&flags, "epochs", "500")[0];
+    let entry_sizes: Vec<usize> = parse_list(&flags, "entry-sizes", "64,1024,16384");
+    let fork_rates: Vec<f64> = parse_list(&flags, "fork-rates", "0,0.1");
+    let miss_rates: Vec<f64> = parse_list(&flags, "miss-rates", "0,0.2");
+    let dir = flags
+        .get("dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("store-bench-{}", std::process::id())));
+
+    println!(
+        "{:<8} {:>8} {:>6} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>9}",
+        "backend", "entry B", "fork", "miss", "write amp", "space amp", "p99 put", "max put", "recovery", "finalized"
+    );
+    for backend in BACKENDS {
+        for &entry_size in &entry_sizes {
+            for &fork_rate in &fork_rates {
+                for &miss_rate in &miss_rates {
+                    let workload = Workload { epochs, entry_size, fork_rate, miss_rate };
+                    let report = run(backend, &workload, &dir.join(backend.name)).unwrap_or_else(|e| {
+                        eprintln!("{} failed: {}", backend.name, e);
+                        exit(1);
+                    });
+                    println!(
+                        "{:<8} {:>8} {:>6.2} {:>6.2} {:>10} {:>10.2} {:>10.2?} {:>10.2?} {:>10.2?} {:>9}",
+                        backend.name,
+                        entry_size,
+                        fork_rate,
+                        miss_rate,
+                        report.write_amp.map(|amp| format!("{:.2}", amp)).unwrap_or_else(|| String::from("n/a")),
+                        report.space_amp,
+                        report.p99_put,
+                        report.max_put,
+                        report.recovery,
+                        report.finalized,
+                    );
+                }
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,100 @@
+/* wire-dump: prints the annotated byte layout of encoded messages and blocks.
+
+   Usage:
+     wire-dump vectors                      print the built-in test vectors
+     wire-dump <frame|chunk|message|envelope|block|signed-block> <hex>
+     wire-dump <frame|chunk|message|envelope|block|signed-block> @<file>   (raw bytes read from file)
+
+   Gossip captured off the network is a frame: a codec flag byte, then the message,
+   compressed or not, or, for frames too large for one gossip message, a chunk of one
+   (its first byte is 0xc0). A frame is annotated, then the message inside it. Consensus
+   messages are sent sealed: decode those as "envelope".
+
+   Input is decoded and re-encoded; if the re-encoding differs from the input, the
+   input was not in canonical form and this is reported (exit code 2); messages from
+   before the wire format version byte decode, but are re-encoded in the current format,
+   so they are reported too. */
+
+use bincode::deserialize;
+use cs244b_project::wire::{dump_block, dump_chunk, dump_envelope, dump_frame, dump_message, dump_signed_block, test_vectors, WireDump};
+use cs244b_project::envelope::ENVELOPE_MAGIC;
+use cs244b_project::{decode_frame, Block, Message, SignedBlock, SignedEnvelope};
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: wire-dump vectors | wire-dump <frame|chunk|message|envelope|block|signed-block> <hex | @file>");
+    exit(1);
+}
+
+fn read_input(arg: &str) -> Vec<u8> {
+    if let Some(path) = arg.strip_prefix('@') {
+        std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Can't read {}: {}", path, e);
+            exit(1);
+        })
+    } else {
+        hex::decode(arg.trim()).unwrap_or_else(|e| {
+            eprintln!("Input is not valid hex: {}", e);
+            exit(1);
+        })
+    }
+}
+
+fn decode(kind: &str, bytes: &[u8]) -> Result<WireDump, String> {
+    match kind {
+        "frame" => dump_frame(bytes).map_err(|e| e.to_string()),
+        "chunk" => dump_chunk(bytes).map_err(|e| e.to_string()),
+        "message" => Message::decode(bytes).map(|m| dump_message(&m)).map_err(|e| e.to_string()),
+        "envelope" => SignedEnvelope::open(bytes)
+            .ok_or_else(|| String::from("not a sealed message"))?
+            .map(|e| dump_envelope(&e))
+            .map_err(|e| e.to_string()),
+        "block" => deserialize::<Block>(bytes).map(|b| dump_block(&b)).map_err(|e| e.to_string()),
+        "signed-block" => deserialize::<SignedBlock>(bytes).map(|b| dump_signed_block(&b)).map_err(|e| e.to_string()),
+        _ => usage(),
+    }
+}
+
+fn dump_or_exit(kind: &str, bytes: &[u8]) -> WireDump {
+    decode(kind, bytes).unwrap_or_else(|e| {
+        eprintln!("Can't decode input as {}: {}", kind, e);
+        exit(1);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() == 2 && args[1] == "vectors" {
+        for (name, dump) in test_vectors() {
+            println!("== {} ==", name);
+            println!("{}", dump);
+            println!("hex: {}\n", hex::encode(dump.bytes()));
+        }
+        return;
+    }
+    if args.len() != 3 {
+        usage();
+    }
+
+    let mut kind = args[1].as_str();
+    let mut bytes = read_input(&args[2]);
+    if kind == "frame" {
+        println!("{}\n", dump_or_exit(kind, &bytes));
+        bytes = decode_frame(&bytes).expect("dump_frame checked the frame");
+        // The message inside, sealed or not
+        kind = if bytes.starts_with(&ENVELOPE_MAGIC) { "envelope" } else { "message" };
+        println!("{} inside the frame:", kind);
+    }
+    let dump = dump_or_exit(kind, &bytes);
+    println!("{}", dump);
+
+    if dump.bytes() != bytes {
+        eprintln!(
+            "WARNING: input is not canonical ({} bytes in, {} bytes re-encoded)",
+            bytes.len(),
+            dump.len()
+        );
+        exit(2);
+    }
+}
@@ -1,12 +1,23 @@
 pub use crate::utils::crypto::*;
+use crate::blockchain::cert::NotarizationCert;
 use crate::Sha256Hash;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+// A notarized block, with the certificate proving it
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SignedBlock {
     pub block: Block,
-    pub signatures: Vec<Signature>,
+    pub cert: NotarizationCert,
+}
+
+impl SignedBlock {
+    /* @param block: the notarized block
+    @param signatures: the votes that notarized it */
+    pub fn new(block: Block, signatures: Vec<Signature>) -> Self {
+        let cert = NotarizationCert::new(&block, signatures);
+        Self { block, cert }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -20,6 +31,9 @@ pub struct Block {
 }
 
 impl Block {
+    /* A block, with its hash: SHA-256 over parent_hash, epoch, data and nonce, in that
+    order, with integers little-endian and data as is, whatever the host's byte order
+    (see messages::wire). */
     pub fn new(
         epoch: u64,
         parent_hash: Sha256Hash,
@@ -32,15 +46,12 @@ impl Block {
 
         // add block fields
         hasher.update(parent_hash.as_slice());
-        hasher.update(epoch.to_ne_bytes().as_slice());
+        hasher.update(epoch.to_le_bytes().as_slice());
         hasher.update(&data);
-        hasher.update(nonce.to_ne_bytes().as_slice());
+        hasher.update(nonce.to_le_bytes().as_slice());
 
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
         Self {
             epoch,
@@ -56,12 +67,9 @@ impl Block {
         let mut hasher = Sha256::new();
         hasher.update(b"hello world");
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
-        return Block::new(0, bytes, data, 0, 0);
+        Block::new(0, bytes, data, 0, 0)
     }
 }
 
@@ -75,10 +83,7 @@ mod tests {
         let mut hasher = Sha256::new();
         hasher.update(b"hello world");
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
         // Create some blocks
         let blk1 = Block::new(0, bytes, String::from("foo").into_bytes(), 0, 0);
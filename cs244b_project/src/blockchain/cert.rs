@@ -0,0 +1,225 @@
+/* Notarization certificates: a block's proof of its own notarization.
+   A certificate names the block (hash and epoch) and holds the votes that notarized it,
+   each a signature over the block as carried in a vote message. Every notarized block
+   carries one (see SignedBlock), so a node handed a chain, e.g. during catch-up or from
+   a store, can check each block was notarized without having seen the votes itself.
+   Under a scheme whose signatures aggregate (bls12-381), the votes are summed into a
+   single aggregate vote instead (see aggregate_votes), with a bit per validator saying
+   whose votes are in it, so a certificate is the same size however many validators
+   there are; checking it still costs a pairing per signer. The bits follow the
+   validators in the order of their names, as the node that made the certificate knew
+   them; a node that knows a different set (a validator joined or rotated its key since)
+   reads them against its own, and the certificate doesn't check out there. */
+
+use crate::blockchain::block::Block;
+use crate::blockchain::chain_id::ChainId;
+use crate::messages::MessagePayload;
+use crate::utils::crypto::*;
+use crate::Sha256Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Most validators an aggregate vote can name (one bit each)
+pub const MAX_AGGREGATE_SIGNERS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotarizationCert {
+    pub block_hash: Sha256Hash,
+    pub epoch: u64,
+    pub signatures: Vec<Signature>, // votes for the block, or their aggregate
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertError {
+    WrongBlock,
+    NoQuorum { signers: usize, needed: usize },
+}
+
+impl fmt::Display for CertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertError::WrongBlock => write!(f, "certificate is for another block"),
+            CertError::NoQuorum { signers, needed } => {
+                write!(f, "certificate has votes from {} known validators, {} needed", signers, needed)
+            }
+        }
+    }
+}
+
+impl NotarizationCert {
+    /* @param block: the notarized block
+    @param signatures: the votes that notarized it */
+    pub fn new(block: &Block, signatures: Vec<Signature>) -> Self {
+        Self { block_hash: block.hash, epoch: block.epoch, signatures }
+    }
+
+    /* What each vote signs: the block, as carried in a vote message, bound to the
+    deployment (see Message::signed_bytes). */
+    pub fn signed_bytes(block: &Block, chain_id: &ChainId) -> Vec<u8> {
+        chain_id.bind(&MessagePayload::Block(block.clone()).serialize())
+    }
+
+    /* Validators with a valid vote in the certificate, each counted once.
+    @param block: the block the certificate is for
+    @param public_keys: validator names and keys
+    @param scheme: the deployment's signature scheme (votes under any other don't count)
+    @param chain_id: the deployment's chain id */
+    pub fn signers(
+        &self,
+        block: &Block,
+        public_keys: &HashMap<String, PublicKey>,
+        scheme: SignatureScheme,
+        chain_id: &ChainId,
+    ) -> HashSet<String> {
+        let bytes = NotarizationCert::signed_bytes(block, chain_id);
+        let mut signers = HashSet::new();
+        let mut matched_keys: Vec<PublicKey> = Vec::new();
+        for signature in self.signatures.iter().filter(|signature| signature.scheme() == scheme) {
+            if let Signature::Bls12381Aggregate { signers: bits, signature } = signature {
+                let validators = validator_order(public_keys, scheme);
+                let named: Vec<usize> = (0..MAX_AGGREGATE_SIGNERS).filter(|&i| bits[i / 8] & (1 << (i % 8)) != 0).collect();
+                if named.iter().any(|&i| i >= validators.len()) {
+                    continue;
+                }
+                let keys: Vec<PublicKey> = named.iter().map(|&i| public_keys[validators[i]]).collect();
+                if verify_aggregate(&keys, &bytes, &Signature::Bls12381(*signature)).is_ok() {
+                    signers.extend(named.iter().map(|&i| validators[i].clone()));
+                }
+                continue;
+            }
+            // A key listed under two names still signs for one validator
+            let signer = public_keys.iter().find(|(name, pk)| {
+                pk.scheme() == scheme && !signers.contains(*name) && !matched_keys.contains(*pk) && pk.verify(&bytes, signature).is_ok()
+            });
+            if let Some((name, pk)) = signer {
+                signers.insert(name.clone());
+                matched_keys.push(*pk);
+            }
+        }
+        signers
+    }
+
+    /* Checks that the certificate is for the block and holds a quorum of votes.
+    @param block: the block the certificate is for
+    @param public_keys: validator names and keys
+    @param scheme: the deployment's signature scheme
+    @param quorum: votes needed to notarize
+    @param chain_id: the deployment's chain id */
+    pub fn verify(
+        &self,
+        block: &Block,
+        public_keys: &HashMap<String, PublicKey>,
+        scheme: SignatureScheme,
+        quorum: usize,
+        chain_id: &ChainId,
+    ) -> Result<(), CertError> {
+        if self.block_hash != block.hash || self.epoch != block.epoch {
+            return Err(CertError::WrongBlock);
+        }
+        let signers = self.signers(block, public_keys, scheme, chain_id).len();
+        if signers < quorum {
+            return Err(CertError::NoQuorum { signers, needed: quorum });
+        }
+        Ok(())
+    }
+}
+
+// Validators under the scheme, in the order an aggregate vote's bits follow
+fn validator_order(public_keys: &HashMap<String, PublicKey>, scheme: SignatureScheme) -> Vec<&String> {
+    let mut validators: Vec<&String> = public_keys.iter().filter(|(_, pk)| pk.scheme() == scheme).map(|(name, _)| name).collect();
+    validators.sort();
+    validators
+}
+
+/* Sums a block's votes into one aggregate vote that names its signers. None if the
+votes don't aggregate: the scheme doesn't support it, a voter isn't among the
+validators, or there are more than MAX_AGGREGATE_SIGNERS of them.
+@param votes: each voter's vote, by name
+@param public_keys: validator names and keys */
+pub fn aggregate_votes(votes: &HashMap<String, Signature>, public_keys: &HashMap<String, PublicKey>) -> Option<Signature> {
+    let validators = validator_order(public_keys, SignatureScheme::Bls12381);
+    if votes.is_empty() || validators.len() > MAX_AGGREGATE_SIGNERS {
+        return None;
+    }
+    let mut bits = [0u8; MAX_AGGREGATE_SIGNERS / 8];
+    for voter in votes.keys() {
+        let i = validators.iter().position(|name| *name == voter)?;
+        bits[i / 8] |= 1 << (i % 8);
+    }
+    let votes: Vec<Signature> = votes.values().copied().collect();
+    match aggregate_signatures(&votes).ok()? {
+        Signature::Bls12381(signature) => Some(Signature::Bls12381Aggregate { signers: bits, signature }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cert_needs_quorum_of_distinct_signers() {
+        let scheme = SignatureScheme::default();
+        let keypairs: Vec<Keypair> = (0..4).map(|_| Keypair::generate(scheme)).collect();
+        let public_keys: HashMap<String, PublicKey> =
+            keypairs.iter().enumerate().map(|(i, keypair)| (format!("h{}", i), keypair.public())).collect();
+        let block = Block::generate_test_block(b"entry".to_vec());
+        let chain_id = ChainId::default();
+        let bytes = NotarizationCert::signed_bytes(&block, &chain_id);
+
+        let votes: Vec<Signature> = keypairs[..3].iter().map(|keypair| keypair.sign(&bytes)).collect();
+        let cert = NotarizationCert::new(&block, votes.clone());
+        assert_eq!(cert.verify(&block, &public_keys, scheme, 3, &chain_id), Ok(()));
+
+        // The same vote twice counts once
+        let doubled = NotarizationCert::new(&block, vec![votes[0], votes[0], votes[1]]);
+        assert_eq!(doubled.verify(&block, &public_keys, scheme, 3, &chain_id), Err(CertError::NoQuorum { signers: 2, needed: 3 }));
+        // Votes from unknown keys don't count
+        let outsider = Keypair::generate(scheme).sign(&bytes);
+        let padded = NotarizationCert::new(&block, vec![votes[0], votes[1], outsider]);
+        assert_eq!(padded.verify(&block, &public_keys, scheme, 3, &chain_id), Err(CertError::NoQuorum { signers: 2, needed: 3 }));
+        // Nor does a certificate for another block
+        let other = Block::generate_test_block(b"other".to_vec());
+        assert_eq!(cert.verify(&other, &public_keys, scheme, 3, &chain_id), Err(CertError::WrongBlock));
+        // Nor do votes from another deployment
+        assert!(cert.verify(&block, &public_keys, scheme, 3, &ChainId::new("test")).is_err());
+        // Only BLS votes aggregate
+        let named: HashMap<String, Signature> = votes.iter().enumerate().map(|(i, vote)| (format!("h{}", i), *vote)).collect();
+        assert_eq!(aggregate_votes(&named, &public_keys), None);
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn test_aggregate_cert_is_constant_size() {
+        let scheme = SignatureScheme::Bls12381;
+        let keypairs: Vec<Keypair> = (0..7).map(|_| Keypair::generate(scheme)).collect();
+        let public_keys: HashMap<String, PublicKey> =
+            keypairs.iter().enumerate().map(|(i, keypair)| (format!("h{}", i), keypair.public())).collect();
+        let block = Block::generate_test_block(b"entry".to_vec());
+        let chain_id = ChainId::default();
+        let bytes = NotarizationCert::signed_bytes(&block, &chain_id);
+        let votes: HashMap<String, Signature> =
+            [1, 2, 4, 6].iter().map(|&i| (format!("h{}", i), keypairs[i].sign(&bytes))).collect();
+
+        let aggregate = aggregate_votes(&votes, &public_keys).unwrap();
+        let cert = NotarizationCert::new(&block, vec![aggregate]);
+        let signers = cert.signers(&block, &public_keys, scheme, &chain_id);
+        assert_eq!(signers, votes.keys().cloned().collect());
+        assert_eq!(cert.verify(&block, &public_keys, scheme, 4, &chain_id), Ok(()));
+        let all: HashMap<String, Signature> =
+            keypairs.iter().enumerate().map(|(i, keypair)| (format!("h{}", i), keypair.sign(&bytes))).collect();
+        let full = NotarizationCert::new(&block, vec![aggregate_votes(&all, &public_keys).unwrap()]);
+        assert_eq!(bincode::serialize(&full).unwrap().len(), bincode::serialize(&cert).unwrap().len());
+
+        // Claiming a signer whose vote isn't in the sum spoils the whole aggregate
+        let mut padded = cert.clone();
+        if let Signature::Bls12381Aggregate { signers, .. } = &mut padded.signatures[0] {
+            signers[0] |= 1;
+        }
+        assert_eq!(padded.verify(&block, &public_keys, scheme, 4, &chain_id), Err(CertError::NoQuorum { signers: 0, needed: 4 }));
+        assert!(cert.verify(&block, &public_keys, scheme, 4, &ChainId::new("test")).is_err());
+        // A single validator's key doesn't verify a sum
+        assert!(keypairs[1].public().verify(&bytes, &cert.signatures[0]).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use crate::blockchain::block::{Block, SignedBlock};
+use crate::blockchain::store::{ChainStore, StoreError};
 use crate::utils::crypto::*;
 use crate::Sha256Hash;
 use serde::{Serialize, Deserialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fmt;
 
 // May not end up needing this trait, I did this in case we wanted to separate the type of chains stored locally
@@ -15,8 +17,9 @@ pub trait Chain {
     fn head(&self) -> (&Block, &Vec<Signature>);
     fn length(&self) -> usize;
     fn copy_up_to_height(&self, height: u64) -> Self;
+    fn load(store: &dyn ChainStore) -> Result<Self, StoreError> where Self: Sized;
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LocalChain {
     pub blocks: Vec<SignedBlock>,
 }
@@ -27,18 +30,12 @@ impl LocalChain {
         let mut hasher = Sha256::new();
         hasher.update("genesis");
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
         // Create genesis block, and wrapper to store signatures (genesis doesn't need any)
         let genesis_block =
             Block::new(0, bytes, String::from("genesis payload").into_bytes(), 0, 0);
-        let genesis_block_wrapper = SignedBlock {
-            block: genesis_block,
-            signatures: Vec::new(),
-        };
+        let genesis_block_wrapper = SignedBlock::new(genesis_block, Vec::new());
 
         self.blocks.push(genesis_block_wrapper);
     }
@@ -48,36 +45,67 @@ impl Chain for LocalChain {
     fn new() -> Self {
         let mut chain = Self { blocks: vec![] };
         chain.genesis();
-        return chain;
+        chain
     }
     fn append_block(&mut self, block: Block, signatures: Vec<Signature>) {
-        let signed_block = SignedBlock { block, signatures };
-        self.blocks.push(signed_block);
+        self.blocks.push(SignedBlock::new(block, signatures));
     }
     fn validate_block(block: &Block, parent_block: &Block) -> bool {
-        return if block.parent_hash != parent_block.hash {
-            true
-        } else {
-            false
-        };
+        // Must link to the parent, sit right above it, come from a later epoch,
+        // and carry the hash of its own contents
+        block.parent_hash == parent_block.hash
+            && block.height == parent_block.height + 1
+            && block.epoch > parent_block.epoch
+            && Block::new(block.epoch, block.parent_hash, block.data.clone(), block.height, block.nonce).hash == block.hash
     }
     fn finalize_block() {}
     fn head(&self) -> (&Block, &Vec<Signature>) {
-        let SignedBlock { block, signatures } =
+        let SignedBlock { block, cert } =
             &self.blocks.last().expect("Blockchain is empty...");
-        (block, signatures)
+        (block, &cert.signatures)
     }
     fn length(&self) -> usize {
-        return self.blocks.len();
+        self.blocks.len()
     }
     fn copy_up_to_height(&self, height: u64) -> LocalChain {
         // +1 because slice end is exclusive
-        // +1 because height does not include genesis block -- it's distance *from* genesis block
-        let copy_idx = usize::try_from(height + 2).expect("could not cast u64 to usize");
+        // (height is the distance from the genesis block, i.e. the block's index in the chain)
+        let copy_idx = usize::try_from(height + 1).expect("could not cast u64 to usize");
         Self {
             blocks: self.blocks[..copy_idx].to_vec(),
         }
     }
+    /* Rebuilds the finalized chain from storage (just genesis if nothing was finalized). */
+    fn load(store: &dyn ChainStore) -> Result<LocalChain, StoreError> {
+        let mut chain = LocalChain::new();
+        let tip = match store.finalized_tip()? {
+            Some(tip) => tip,
+            None => return Ok(chain),
+        };
+        let stored: HashMap<Sha256Hash, SignedBlock> = store
+            .blocks()?
+            .into_iter()
+            .map(|signed_block| (signed_block.block.hash, signed_block))
+            .collect();
+
+        // Walk back from the finalized tip to genesis
+        let genesis_hash = chain.head().0.hash;
+        let mut blocks = Vec::new();
+        let mut current = tip;
+        while current != genesis_hash {
+            let signed_block = stored
+                .get(&current)
+                .ok_or_else(|| StoreError::Corrupt(String::from("finalized chain has a missing block")))?;
+            current = signed_block.block.parent_hash;
+            blocks.push(signed_block.clone());
+            if blocks.len() > stored.len() {
+                return Err(StoreError::Corrupt(String::from("finalized chain loops")));
+            }
+        }
+        blocks.reverse();
+        chain.blocks.extend(blocks);
+        Ok(chain)
+    }
 }
 
 impl fmt::Display for LocalChain {
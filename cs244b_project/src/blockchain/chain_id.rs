@@ -0,0 +1,97 @@
+/* Chain ids: which deployment a signature belongs to.
+   Every signed artifact (votes and the certificates built from them, tree heads,
+   inclusion promises, maintenance windows) signs the chain id along with its contents,
+   so an artifact from one deployment never verifies in another, even where validators
+   reuse their keys (e.g. a test deployment next to production). The id is the hash of
+   the genesis block together with the deployment's name: every deployment starts from
+   the same genesis block, so the name is what tells them apart. A deployment started
+   from a genesis document (see genesis) also hashes in the document. */
+
+use crate::blockchain::chain::{Chain, LocalChain};
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const CHAIN_ID_CONTEXT: &[u8] = b"streamlet chain id v1";
+const GENESIS_CHAIN_ID_CONTEXT: &[u8] = b"streamlet chain id v1 with genesis document";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChainId(pub Sha256Hash);
+
+impl ChainId {
+    /* @param deployment: the deployment's name (the same on every node, tool and client) */
+    pub fn new(deployment: &str) -> Self {
+        let genesis = LocalChain::new().blocks[0].block.hash;
+        let mut hasher = Sha256::new();
+        hasher.update(CHAIN_ID_CONTEXT);
+        hasher.update(genesis);
+        hasher.update(deployment.as_bytes());
+        ChainId(hasher.finalize().into())
+    }
+
+    /* @param deployment: the deployment's name
+    @param document: hash of the deployment's genesis document (see GenesisDocument::hash) */
+    pub fn with_genesis(deployment: &str, document: &Sha256Hash) -> Self {
+        let genesis = LocalChain::new().blocks[0].block.hash;
+        let mut hasher = Sha256::new();
+        hasher.update(GENESIS_CHAIN_ID_CONTEXT);
+        hasher.update(genesis);
+        hasher.update(document);
+        hasher.update(deployment.as_bytes());
+        ChainId(hasher.finalize().into())
+    }
+
+    /* Prefixes a signing preimage with the chain id.
+    @param bytes: what would be signed without it */
+    pub fn bind(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut bound = Vec::with_capacity(self.0.len() + bytes.len());
+        bound.extend_from_slice(&self.0);
+        bound.extend_from_slice(bytes);
+        bound
+    }
+}
+
+/* The chain id of a deployment that wasn't given a name. */
+impl Default for ChainId {
+    fn default() -> Self {
+        ChainId::new("")
+    }
+}
+
+impl fmt::Display for ChainId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for ChainId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .ok()
+            .and_then(|bytes| Sha256Hash::try_from(bytes.as_slice()).ok())
+            .map(ChainId)
+            .ok_or_else(|| format!("not a chain id (64 hex digits): {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_ids_separate_deployments() {
+        let production = ChainId::new("production");
+        assert_eq!(production, ChainId::new("production"));
+        assert_ne!(production, ChainId::new("test"));
+        assert_ne!(production, ChainId::default());
+        assert_eq!(production.to_string().parse::<ChainId>(), Ok(production));
+
+        // The same key's signature doesn't carry over
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let signature = keypair.sign(&ChainId::new("test").bind(b"vote"));
+        assert!(keypair.public().verify(&production.bind(b"vote"), &signature).is_err());
+        assert!(keypair.public().verify(&ChainId::new("test").bind(b"vote"), &signature).is_ok());
+    }
+}
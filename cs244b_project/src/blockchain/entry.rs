@@ -0,0 +1,228 @@
+/* Log entries and their identifiers.
+   Each entry gets a ULID-style id when it is submitted: a 48-bit millisecond timestamp
+   followed by 80 random bits. Ids sort by submission time, so clients have a stable,
+   ordered handle for an entry before its final position in the log is known. */
+
+use bincode::{deserialize, serialize};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::utils::clock;
+
+// Crockford base32 alphabet (no I, L, O, U)
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_LEN: usize = 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct EntryId(pub u128);
+
+/* How entry ids are rendered in logs and responses. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntryIdFormat {
+    #[default]
+    Ulid, // 26 Crockford base32 characters
+    Hex,     // 32 lowercase hex digits
+    Decimal, // the id as a 128-bit unsigned integer
+}
+
+impl FromStr for EntryIdFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ulid" => Ok(EntryIdFormat::Ulid),
+            "hex" => Ok(EntryIdFormat::Hex),
+            "decimal" => Ok(EntryIdFormat::Decimal),
+            _ => Err(format!("unknown entry id format: {}", s)),
+        }
+    }
+}
+
+impl EntryId {
+    /* Generates a new id stamped with the current time. */
+    pub fn generate() -> Self {
+        EntryId::from_parts(clock::unix_time_ms(), rand::thread_rng().gen())
+    }
+
+    /* @param millis: milliseconds since the Unix epoch (only the low 48 bits are kept)
+    @param random: random bits (only the low 80 bits are kept) */
+    pub fn from_parts(millis: u64, random: u128) -> Self {
+        let millis = (millis as u128) & ((1 << 48) - 1);
+        EntryId((millis << 80) | (random & ((1 << 80) - 1)))
+    }
+
+    /* Milliseconds since the Unix epoch at which the id was generated. */
+    pub fn timestamp_ms(&self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+
+    pub fn format(&self, format: EntryIdFormat) -> String {
+        match format {
+            EntryIdFormat::Ulid => {
+                let mut s = vec![0u8; ULID_LEN];
+                let mut value = self.0;
+                for c in s.iter_mut().rev() {
+                    *c = CROCKFORD[(value & 0x1f) as usize];
+                    value >>= 5;
+                }
+                String::from_utf8(s).expect("alphabet is ascii")
+            }
+            EntryIdFormat::Hex => format!("{:032x}", self.0),
+            EntryIdFormat::Decimal => self.0.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for EntryId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(EntryIdFormat::Ulid))
+    }
+}
+
+/* Parses the ULID (Crockford base32) rendering, case-insensitively. */
+impl FromStr for EntryId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // 26 characters hold 130 bits; the first one may only use the low 3
+        if s.len() != ULID_LEN || !s.starts_with(|c: char| ('0'..='7').contains(&c)) {
+            return Err(format!("not a ULID: {}", s));
+        }
+        let mut value: u128 = 0;
+        for c in s.bytes() {
+            let digit = CROCKFORD
+                .iter()
+                .position(|x| *x == c.to_ascii_uppercase())
+                .ok_or_else(|| format!("not a ULID: {}", s))?;
+            value = (value << 5) | digit as u128;
+        }
+        Ok(EntryId(value))
+    }
+}
+
+// Longest content type accepted
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+
+/* MIME-like content type of an entry's data, e.g. "application/json" or
+   "text/plain; charset=utf-8": a type/subtype pair of token characters, optionally
+   followed by parameters. Only the shape is checked; the data isn't. */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentType(String);
+
+impl ContentType {
+    /* The type/subtype pair, lowercased, without parameters. */
+    pub fn essence(&self) -> String {
+        self.0.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+    }
+
+    pub fn is_json(&self) -> bool {
+        let essence = self.essence();
+        essence == "application/json" || essence.ends_with("+json")
+    }
+
+    /* Whether the data is meant to be read as text. */
+    pub fn is_text(&self) -> bool {
+        let essence = self.essence();
+        essence.starts_with("text/") || self.is_json() || essence == "application/xml" || essence.ends_with("+xml")
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let is_token = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c));
+        let (essence, parameters) = s.split_once(';').unwrap_or((s, ""));
+        let valid = s.len() <= MAX_CONTENT_TYPE_LEN
+            && matches!(essence.trim().split_once('/'), Some((kind, subtype)) if is_token(kind) && is_token(subtype))
+            && parameters.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+        if valid {
+            Ok(ContentType(s.to_string()))
+        } else {
+            Err(format!("not a content type: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/* What gets stored in a block's data: the submitted bytes, tagged with their id and,
+   if the submitter gave one, their content type. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub id: EntryId,
+    pub data: Vec<u8>,
+    pub content_type: Option<ContentType>,
+}
+
+// Entries as encoded before they had a content type
+#[derive(Deserialize)]
+struct UntypedLogEntry {
+    id: EntryId,
+    data: Vec<u8>,
+}
+
+impl LogEntry {
+    /* An entry with a fresh id and no content type. */
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { id: EntryId::generate(), data, content_type: None }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        serialize(self).expect("Failed serialization.")
+    }
+    /* Returns None for data that isn't an entry (e.g. empty blocks). */
+    pub fn deserialize(encoded: &[u8]) -> Option<LogEntry> {
+        deserialize(encoded).ok().or_else(|| {
+            let UntypedLogEntry { id, data } = deserialize(encoded).ok()?;
+            Some(LogEntry { id, data, content_type: None })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_id_formats() {
+        let id = EntryId::from_parts(1_469_918_176_385, 0x4e_5d_b1_02_bd_6a_4e_06_9a_3c);
+        assert_eq!(id.timestamp_ms(), 1_469_918_176_385);
+        assert_eq!(id.to_string().len(), 26);
+        assert_eq!(id.to_string().parse::<EntryId>(), Ok(id));
+        assert_eq!(id.to_string().to_lowercase().parse::<EntryId>(), Ok(id));
+        assert_eq!(id.format(EntryIdFormat::Hex), format!("{:032x}", id.0));
+        assert_eq!(id.format(EntryIdFormat::Decimal).parse::<u128>(), Ok(id.0));
+        assert!("8ZZZZZZZZZZZZZZZZZZZZZZZZZ".parse::<EntryId>().is_err());
+    }
+
+    #[test]
+    fn test_content_types() {
+        let json: ContentType = "Application/JSON; charset=utf-8".parse().unwrap();
+        assert_eq!(json.essence(), "application/json");
+        assert!(json.is_json() && json.is_text());
+        assert!("text/plain".parse::<ContentType>().unwrap().is_text());
+        assert!(!"image/png".parse::<ContentType>().unwrap().is_text());
+        for bad in ["", "text", "text/", "/plain", "text/pl ain", "text/plain; x=\u{7}"] {
+            assert!(bad.parse::<ContentType>().is_err(), "{}", bad);
+        }
+
+        // Entries from before content types still decode
+        let entry = LogEntry { id: EntryId::from_parts(1, 2), data: b"{}".to_vec(), content_type: Some(json) };
+        assert_eq!(LogEntry::deserialize(&entry.serialize()), Some(entry.clone()));
+        let untyped = serialize(&(entry.id, entry.data.clone())).unwrap();
+        assert_eq!(LogEntry::deserialize(&untyped), Some(LogEntry { content_type: None, ..entry }));
+    }
+
+    #[test]
+    fn test_entry_ids_sort_by_time() {
+        let earlier = EntryId::from_parts(1000, u128::MAX);
+        let later = EntryId::from_parts(1001, 0);
+        assert!(earlier < later);
+        assert!(earlier.to_string() < later.to_string());
+    }
+}
@@ -0,0 +1,220 @@
+/* Chain export files, for backups and for bootstrapping nodes and auditors offline.
+   An export holds the finalized chain from genesis, each block with its notarization
+   certificate, along with the deployment's chain id and the validators (names and
+   keys) the certificates are checked against. Two formats:
+   - json: {schema_version, chain_id, validators: {name: key}, blocks}, the blocks in
+     the node's JSON shape (see json_schema::block_json). Readable, and what tools
+     outside the crate should parse.
+   - bin: BIN_MAGIC, then the export in bincode. Smaller and quicker to load.
+   An import is checked before anything is loaded (see ChainExport::verify): it must be
+   from this deployment, start at our genesis block, link up by parent hash, and every
+   later block's certificate must hold a quorum of votes from the validators it names.
+   Those validators are taken from the file, so import only exports you trust, such as
+   your own backups. */
+
+use crate::blockchain::*;
+use crate::json_schema;
+use crate::quorum::QuorumRule;
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+// Starts every binary export, so it can't be mistaken for JSON (or anything else)
+pub const BIN_MAGIC: &[u8] = b"STREAMLET-CHAIN-1\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Bin,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "bin" => Ok(ExportFormat::Bin),
+            _ => Err(format!("unknown export format: {} (expected json or bin)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::Json => write!(f, "json"),
+            ExportFormat::Bin => write!(f, "bin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainExport {
+    pub chain_id: ChainId,
+    pub validators: BTreeMap<String, PublicKey>,
+    pub chain: LocalChain, // finalized, from genesis
+}
+
+fn hex_field(value: &Value, name: &str) -> Result<Vec<u8>, String> {
+    value[name]
+        .as_str()
+        .and_then(|field| hex::decode(field).ok())
+        .ok_or_else(|| format!("'{}' is missing or not hex", name))
+}
+
+fn hash_field(value: &Value, name: &str) -> Result<Sha256Hash, String> {
+    Sha256Hash::try_from(hex_field(value, name)?.as_slice()).map_err(|_| format!("'{}' is not a SHA-256 hash", name))
+}
+
+fn number_field(value: &Value, name: &str) -> Result<u64, String> {
+    value[name].as_u64().ok_or_else(|| format!("'{}' is missing or not a number", name))
+}
+
+fn bincode_hex<T: serde::de::DeserializeOwned>(value: &Value, what: &str) -> Result<T, String> {
+    value
+        .as_str()
+        .and_then(|field| hex::decode(field).ok())
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .ok_or_else(|| format!("{} is not valid", what))
+}
+
+// A block as json_schema::block_json writes it
+fn block_from_json(value: &Value) -> Result<SignedBlock, String> {
+    let block = Block {
+        epoch: number_field(value, "epoch")?,
+        hash: hash_field(value, "hash")?,
+        parent_hash: hash_field(value, "parent_hash")?,
+        data: hex_field(value, "data")?,
+        height: number_field(value, "height")?,
+        nonce: number_field(value, "nonce")?,
+    };
+    let signatures = value["signatures"]
+        .as_array()
+        .ok_or_else(|| String::from("'signatures' is missing"))?
+        .iter()
+        .map(|signature| bincode_hex(signature, "a signature"))
+        .collect::<Result<Vec<Signature>, String>>()?;
+    Ok(SignedBlock::new(block, signatures))
+}
+
+impl ChainExport {
+    /* @param format: json or bin */
+    pub fn encode(&self, format: ExportFormat) -> Result<Vec<u8>, String> {
+        match format {
+            ExportFormat::Json => {
+                let validators: serde_json::Map<String, Value> = self
+                    .validators
+                    .iter()
+                    .map(|(name, public_key)| (name.clone(), json!(hex::encode(bincode::serialize(public_key).unwrap_or_default()))))
+                    .collect();
+                let blocks: Vec<Value> = self.chain.blocks.iter().map(|SignedBlock { block, cert }| json_schema::block_json(block, &cert.signatures)).collect();
+                let export = json!({
+                    json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION,
+                    "chain_id": self.chain_id.to_string(),
+                    "validators": validators,
+                    "blocks": blocks,
+                });
+                serde_json::to_vec_pretty(&export).map_err(|e| e.to_string())
+            }
+            ExportFormat::Bin => {
+                let mut bytes = BIN_MAGIC.to_vec();
+                bytes.extend(bincode::serialize(self).map_err(|e| e.to_string())?);
+                Ok(bytes)
+            }
+        }
+    }
+
+    /* Reads an export in either format (told apart by BIN_MAGIC).
+    @param bytes: the file's contents */
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if let Some(body) = bytes.strip_prefix(BIN_MAGIC) {
+            return bincode::deserialize(body).map_err(|e| format!("malformed binary export: {}", e));
+        }
+        let value: Value = serde_json::from_slice(bytes).map_err(|e| format!("neither a binary export nor JSON: {}", e))?;
+        json_schema::check_version(&value)?;
+        let chain_id = value["chain_id"]
+            .as_str()
+            .ok_or_else(|| String::from("'chain_id' is missing"))?
+            .parse()?;
+        let validators = value["validators"]
+            .as_object()
+            .ok_or_else(|| String::from("'validators' is missing"))?
+            .iter()
+            .map(|(name, public_key)| Ok((name.clone(), bincode_hex(public_key, &format!("{}'s key", name))?)))
+            .collect::<Result<BTreeMap<String, PublicKey>, String>>()?;
+        let blocks = value["blocks"]
+            .as_array()
+            .ok_or_else(|| String::from("'blocks' is missing"))?
+            .iter()
+            .map(block_from_json)
+            .collect::<Result<Vec<SignedBlock>, String>>()?;
+        Ok(ChainExport { chain_id, validators, chain: LocalChain { blocks } })
+    }
+
+    /* Checks the export can be trusted as far as its validators are (see the top of
+    this file).
+    @param chain_id: this deployment's chain id
+    @param scheme: the deployment's signature scheme
+    @param quorum_rule: the deployment's quorum rule, applied to the file's validators */
+    pub fn verify(&self, chain_id: &ChainId, scheme: SignatureScheme, quorum_rule: QuorumRule) -> Result<(), String> {
+        if self.chain_id != *chain_id {
+            return Err(format!("export is from another deployment (chain id {})", self.chain_id));
+        }
+        let genesis = &LocalChain::new().blocks[0].block;
+        if self.chain.blocks.first().map(|signed| signed.block.hash) != Some(genesis.hash) {
+            return Err(String::from("export doesn't start at the genesis block"));
+        }
+        if !BlockchainManager::is_chain_valid(&self.chain) {
+            return Err(String::from("export's blocks don't link up"));
+        }
+        if self.validators.is_empty() {
+            return Err(String::from("export names no validators"));
+        }
+        let public_keys: HashMap<String, PublicKey> = self.validators.clone().into_iter().collect();
+        let quorum = quorum_rule.size(public_keys.len());
+        for SignedBlock { block, cert } in self.chain.blocks.iter().skip(1) {
+            cert.verify(block, &public_keys, scheme, quorum, chain_id)
+                .map_err(|e| format!("block at height {}: {}", block.height, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_round_trips_and_verifies() {
+        let chain_id = ChainId::default();
+        let keypairs: Vec<Keypair> = (0..3).map(|_| Keypair::generate(SignatureScheme::Ed25519)).collect();
+        let validators: BTreeMap<String, PublicKey> = keypairs.iter().enumerate().map(|(i, keypair)| (format!("v{}", i), keypair.public())).collect();
+        let mut chain = LocalChain::new();
+        for height in 1..=3 {
+            let parent = chain.head().0.clone();
+            let block = Block::new(height, parent.hash, vec![height as u8], height, 0);
+            let bytes = NotarizationCert::signed_bytes(&block, &chain_id);
+            // Two of three votes: a quorum
+            let votes = keypairs.iter().take(2).map(|keypair| keypair.sign(&bytes)).collect();
+            chain.append_block(block, votes);
+        }
+        let export = ChainExport { chain_id, validators, chain };
+
+        for format in [ExportFormat::Json, ExportFormat::Bin] {
+            let decoded = ChainExport::decode(&export.encode(format).unwrap()).unwrap();
+            assert_eq!(decoded, export);
+            assert!(decoded.verify(&chain_id, SignatureScheme::Ed25519, QuorumRule::TwoThirds).is_ok());
+        }
+        assert!(export.verify(&ChainId::new("other"), SignatureScheme::Ed25519, QuorumRule::TwoThirds).is_err());
+
+        // A block whose votes fall short of a quorum is refused
+        let mut short = export.clone();
+        short.chain.blocks[2].cert.signatures.truncate(1);
+        let refused = short.verify(&chain_id, SignatureScheme::Ed25519, QuorumRule::TwoThirds);
+        assert!(refused.unwrap_err().contains("height 2"));
+    }
+}
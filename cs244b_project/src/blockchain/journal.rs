@@ -0,0 +1,97 @@
+/* Write-ahead journal of this node's votes.
+   A vote is appended (and synced to disk) before it is broadcast, and the journal is
+   read back on startup, so a node that crashes and restarts never signs two different
+   blocks in the same epoch. */
+
+use crate::Sha256Hash;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// One record: epoch (u64, little-endian) followed by the block hash
+const RECORD_LEN: usize = 8 + 32;
+
+pub struct VoteJournal {
+    file: File,
+    votes: HashMap<u64, Sha256Hash>,
+}
+
+impl VoteJournal {
+    /* Opens (or creates) the journal at the given path and loads the recorded votes.
+    A torn final record (crash mid-write) is ignored; it was never broadcast. */
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut votes = HashMap::new();
+        for record in bytes.chunks_exact(RECORD_LEN) {
+            let epoch = u64::from_le_bytes(record[..8].try_into().expect("8-byte slice"));
+            let hash: Sha256Hash = record[8..].try_into().expect("32-byte slice");
+            votes.insert(epoch, hash);
+        }
+        if bytes.len() % RECORD_LEN != 0 {
+            // Drop the partial record so later appends stay aligned
+            file.set_len((bytes.len() - bytes.len() % RECORD_LEN) as u64)?;
+        }
+        Ok(Self { file, votes })
+    }
+
+    /* The block we voted for in the given epoch, if any. */
+    pub fn voted_for(&self, epoch: u64) -> Option<Sha256Hash> {
+        self.votes.get(&epoch).copied()
+    }
+
+    /* Records a vote before it is sent.
+    @param epoch: epoch of the vote
+    @param block_hash: block being voted for
+    Returns Ok(false) if we already voted for a different block in this epoch (the vote
+    must not be sent); Ok(true) if the vote is durably recorded. */
+    pub fn record(&mut self, epoch: u64, block_hash: &Sha256Hash) -> io::Result<bool> {
+        if let Some(previous) = self.votes.get(&epoch) {
+            return Ok(previous == block_hash);
+        }
+        let mut record = epoch.to_le_bytes().to_vec();
+        record.extend_from_slice(block_hash);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.votes.insert(epoch, *block_hash);
+        Ok(true)
+    }
+
+    /* Syncs the journal to disk (each vote already is as it is recorded). */
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_survives_restart() {
+        let path = std::env::temp_dir().join(format!("streamlet-journal-test-{}", std::process::id()));
+        let mut journal = VoteJournal::open(&path).unwrap();
+        assert!(journal.record(3, &[1u8; 32]).unwrap());
+        assert!(journal.record(3, &[1u8; 32]).unwrap());
+        assert!(!journal.record(3, &[2u8; 32]).unwrap());
+        drop(journal);
+
+        // A crash mid-append leaves a torn record behind
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[9u8; 10]).unwrap();
+
+        let mut journal = VoteJournal::open(&path).unwrap();
+        assert_eq!(journal.voted_for(3), Some([1u8; 32]));
+        assert!(!journal.record(3, &[2u8; 32]).unwrap());
+        assert!(journal.record(4, &[2u8; 32]).unwrap());
+        drop(journal);
+        assert_eq!(VoteJournal::open(&path).unwrap().voted_for(4), Some([2u8; 32]));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
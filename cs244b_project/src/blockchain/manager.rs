@@ -1,10 +1,16 @@
 use crate::blockchain::*;
+use crate::json_schema::{self, block_json};
+use crate::Sha256Hash;
 use log::info;
+use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::env;
 use std::io::Write;
 
-// Struct for managing multiple notarized chains and a finalied chain.
+// Struct for managing the tree of notarized blocks and a finalied chain.
+// Streamlet tolerates forks, so notarized blocks are kept as a tree rooted at genesis
+// (keyed by hash, with children indexed by parent hash); proposers and voters work off
+// one of the longest notarized chains in that tree.
 // Provides the abstraction of a single Chain the user can query/manipulate
 // Responsbility for verifying that a block is notarized falls upon code which
 // uses this struct
@@ -12,8 +18,48 @@ pub struct BlockchainManager {
     pub finalized_chain_length: usize,
     pub finalized_chain: LocalChain,
     pub longest_notarized_chain_length: usize, // length = max_height + 1
-    notarized_chains: Vec<LocalChain>, 
+    // Every notarized block (genesis included), keyed by hash
+    notarized_blocks: HashMap<Sha256Hash, SignedBlock>,
+    // Hashes of the notarized children of each notarized block, keyed by parent hash
+    children: HashMap<Sha256Hash, Vec<Sha256Hash>>,
+    // Tips of the longest notarized chains, in the order they were notarized
+    longest_tips: Vec<Sha256Hash>,
     pub last_logged_epoch: u64,
+    // Votes on not-yet-notarized blocks, keyed by block hash
+    pending_votes: HashMap<Sha256Hash, PendingVotes>,
+    // Blocks finalized but not yet handed out by take_newly_finalized
+    newly_finalized: Vec<SignedBlock>,
+    // Where notarized/finalized blocks are persisted (None: memory only)
+    store: Option<Box<dyn ChainStore>>,
+    // Merkle tree over the entries of the finalized chain, in log order
+    merkle_tree: MerkleTree,
+    // Height of the finalized block holding each entry
+    entry_heights: HashMap<EntryId, u64>,
+    // Writes to the store that failed since the last take_storage_errors: (what, error)
+    storage_errors: Vec<(String, StoreError)>,
+    // Validators to aggregate certificates over (None: certificates keep each vote)
+    aggregate_over: Option<HashMap<String, PublicKey>>,
+    // Newest finalized blocks whose bodies a pruned node keeps (None: an archive node, keeps all)
+    keep_bodies: Option<u64>,
+    // Blocks below this height have had their bodies dropped (0: none have)
+    checkpoint: u64,
+    // Entries dropped with them: the first leaves of the Merkle tree
+    pruned_entries: u64,
+}
+
+// Votes collected for a single proposed block, at most one per signer
+struct PendingVotes {
+    block: Block,
+    signatures: HashMap<String, Signature>,
+}
+
+// Pending votes on blocks this many epochs older than the current one are dropped
+const VOTE_RETENTION_EPOCHS: u64 = 10;
+
+impl Default for BlockchainManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockchainManager {
@@ -22,130 +68,572 @@ impl BlockchainManager {
 
     /* Creates a new BlockchainManager instance. */
     pub fn new() -> Self {
+        let genesis = LocalChain::new().blocks.remove(0);
+        let genesis_hash = genesis.block.hash;
         Self {
             finalized_chain_length: 1,
             finalized_chain: LocalChain::new(),
             longest_notarized_chain_length: 1,
-            notarized_chains: Vec::from([LocalChain::new()]),
+            notarized_blocks: HashMap::from([(genesis_hash, genesis)]),
+            children: HashMap::new(),
+            longest_tips: vec![genesis_hash],
             last_logged_epoch: 0,
+            pending_votes: HashMap::new(),
+            newly_finalized: Vec::new(),
+            store: None,
+            merkle_tree: MerkleTree::new(),
+            entry_heights: HashMap::new(),
+            storage_errors: Vec::new(),
+            aggregate_over: None,
+            keep_bodies: None,
+            checkpoint: 0,
+            pruned_entries: 0,
         }
     }
 
-    /* Adds a chain to vector of notarized chains
-    @param chain: notarized chain that was observed */
+    /* Creates a BlockchainManager that persists blocks to the given store, restoring
+    whatever the store already holds (the finalized chain and any notarized forks).
+     @param store: storage backend, e.g. a SledStore */
+    pub fn with_store(store: Box<dyn ChainStore>) -> Result<Self, StoreError> {
+        let mut manager = BlockchainManager::new();
+        let finalized_chain = LocalChain::load(&*store)?;
+        let stored_blocks = store.blocks()?;
+
+        // Replay in height order; blocks that no longer link up are dropped
+        manager.observe_chain(finalized_chain.clone());
+        for SignedBlock { block, cert } in stored_blocks {
+            manager.add_notarized_block(block, cert.signatures);
+        }
+        if manager.finalized_chain_length < finalized_chain.length() {
+            manager.finalized_chain_length = finalized_chain.length();
+            manager.finalized_chain = finalized_chain;
+        }
+        manager.newly_finalized.clear();
+        manager.merkle_tree = MerkleTree::new();
+        manager.entry_heights.clear();
+        // Pruned blocks are headers; their entries are in the tree by leaf hash. Leaves are
+        // stored before headers, in height order, so the last one is below the checkpoint
+        // even if we stopped before recording it
+        let pruned_leaves: HashMap<u64, Sha256Hash> = store.pruned_leaves()?.into_iter().collect();
+        manager.checkpoint = store.checkpoint()?.max(pruned_leaves.keys().max().map_or(0, |height| height + 1));
+        for SignedBlock { block, .. } in manager.finalized_chain.blocks.clone().iter().skip(1) {
+            match pruned_leaves.get(&block.height) {
+                Some(leaf) => {
+                    manager.merkle_tree.push_leaf(*leaf);
+                    manager.pruned_entries += 1;
+                }
+                None => manager.index_finalized_block(block),
+            }
+        }
+        manager.last_logged_epoch = manager.get_latest_finalized_block().0.epoch;
+        info!(
+            "Loaded {} notarized blocks from storage; finalized chain length {}",
+            manager.notarized_blocks.len() - 1,
+            manager.finalized_chain_length
+        );
+        manager.store = Some(store);
+        Ok(manager)
+    }
+
+    /* Records votes on a block. Only the first vote per signer counts.
+     @param block: the block being voted on
+     @param votes: (signer name, signature) pairs, already verified by the caller
+     Returns the number of votes that were new. */
+    pub fn record_votes(&mut self, block: &Block, votes: Vec<(String, Signature)>) -> usize {
+        if self.notarized_blocks.contains_key(&block.hash) {
+            return 0;
+        }
+        let entry = self.pending_votes.entry(block.hash).or_insert_with(|| PendingVotes {
+            block: block.clone(),
+            signatures: HashMap::new(),
+        });
+        let mut new_votes = 0;
+        for (signer, signature) in votes {
+            if let std::collections::hash_map::Entry::Vacant(e) = entry.signatures.entry(signer) {
+                e.insert(signature);
+                new_votes += 1;
+            }
+        }
+        new_votes
+    }
+
+    /* Has certificates of blocks notarized from now on hold one aggregate vote instead
+    of each vote (see aggregate_votes); call again whenever the validators change.
+    @param validators: validator names and keys, under a scheme whose signatures aggregate */
+    pub fn aggregate_certs_over(&mut self, validators: HashMap<String, PublicKey>) {
+        self.aggregate_over = Some(validators);
+    }
+
+    /* Number of distinct signers that have voted for the block. */
+    pub fn vote_count(&self, block_hash: &Sha256Hash) -> usize {
+        self.pending_votes
+            .get(block_hash)
+            .map(|votes| votes.signatures.len())
+            .unwrap_or(0)
+    }
+
+    /* All recorded vote signatures for the block (used when echoing votes). */
+    pub fn votes_for(&self, block_hash: &Sha256Hash) -> Vec<Signature> {
+        self.pending_votes
+            .get(block_hash)
+            .map(|votes| votes.signatures.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_block_notarized(&self, block_hash: &Sha256Hash) -> bool {
+        self.notarized_blocks.contains_key(block_hash)
+    }
+
+    /* Notarizes the block once it has votes from `quorum` distinct signers,
+    adding it (with those signatures) to the block tree under its parent.
+    A block whose parent isn't notarized yet stays pending; it is picked up as
+    soon as the parent gets notarized.
+     @param block_hash: hash of a block with recorded votes
+     @param quorum: number of distinct signers required
+     Returns true if the block was newly notarized. */
+    pub fn try_notarize(&mut self, block_hash: &Sha256Hash, quorum: usize) -> bool {
+        if self.vote_count(block_hash) < quorum {
+            return false;
+        }
+        let parent_hash = self.pending_votes[block_hash].block.parent_hash;
+        if !self.notarized_blocks.contains_key(&parent_hash) {
+            return false;
+        }
+        let PendingVotes { block, signatures } = self
+            .pending_votes
+            .remove(block_hash)
+            .expect("votes were just counted");
+        let signatures = match self.aggregate_over.as_ref().and_then(|validators| aggregate_votes(&signatures, validators)) {
+            Some(aggregate) => vec![aggregate],
+            None => signatures.into_values().collect(),
+        };
+        if !self.add_notarized_block(block, signatures) {
+            return false;
+        }
+        // Children that reached quorum before this block did
+        let waiting: Vec<Sha256Hash> = self
+            .pending_votes
+            .values()
+            .filter(|votes| votes.block.parent_hash == *block_hash)
+            .map(|votes| votes.block.hash)
+            .collect();
+        for child in waiting {
+            self.try_notarize(&child, quorum);
+        }
+        true
+    }
+
+    /* Drops votes on blocks from epochs too old to matter.
+     @param current_epoch: the epoch that just started */
+    pub fn prune_votes(&mut self, current_epoch: u64) {
+        self.pending_votes
+            .retain(|_, votes| votes.block.epoch + VOTE_RETENTION_EPOCHS >= current_epoch);
+    }
+
+    /* Adds the blocks of an observed notarized chain to the block tree.
+    @param chain: notarized chain that was observed; its first block must already be
+    known (e.g. genesis, or a block received during catch-up) and is skipped */
     pub fn observe_chain(&mut self, chain: LocalChain) {
-        self.notarized_chains.push(chain)
+        for SignedBlock { block, cert } in chain.blocks.into_iter().skip(1) {
+            if !self.notarized_blocks.contains_key(&block.hash) {
+                self.add_notarized_block(block, cert.signatures);
+            }
+        }
     }
 
-    /* Validates a chain by checking the hash chain.
+    /* Validates a chain (or a chain segment) by checking the hash chain.
     @param chain: chain to be validated */
-    pub fn is_chain_valid(chain: LocalChain) -> bool {
-        // this will be very trivial chain validation check so we don't have to start making this into notarizing/finalizing/etc...
-        // not totally sure how involved to get with it at the moment
-        for i in 0..chain.length() {
-            if i == 0 {
-                // genesis block can be ignored
-                continue;
-            }
-            let SignedBlock { block: first, .. } =
-                chain.blocks.get(i - 1).expect("expected parent block");
-            let SignedBlock { block: second, .. } = chain.blocks.get(i).expect("expected block");
-            if !<LocalChain as Chain>::validate_block(second, first) {
-                return false;
+    pub fn is_chain_valid(chain: &LocalChain) -> bool {
+        chain.blocks.windows(2).all(|pair| {
+            <LocalChain as Chain>::validate_block(&pair[1].block, &pair[0].block)
+        })
+    }
+
+    /* Segment of the longest notarized chain for a peer that is catching up.
+    @param from_height: height of the first block to include (a block the peer already has)
+    @param max_blocks: cap on the number of blocks after the first one
+    Returns None if we have nothing above from_height. */
+    pub fn notarized_chain_from(&self, from_height: u64, max_blocks: usize) -> Option<LocalChain> {
+        // Pruned blocks can't be checked by the peer
+        if from_height + 1 < self.checkpoint {
+            return None;
+        }
+        let mut chain = self.longest_notarized_chain();
+        let start = usize::try_from(from_height).ok()?;
+        if start + 1 >= chain.length() {
+            return None;
+        }
+        chain.blocks.truncate(start + 1 + max_blocks);
+        chain.blocks.drain(..start);
+        Some(chain)
+    }
+
+    /* Whether the block directly extends the tip of one of the longest notarized chains
+    (the condition for an honest node to vote for it). */
+    pub fn extends_longest_notarized_chain(&self, block: &Block) -> bool {
+        self.longest_tips.contains(&block.parent_hash)
+            && self.notarized_blocks[&block.parent_hash].block.height + 1 == block.height
+    }
+
+    /* One of the longest notarized chains (the first one to reach the current length). */
+    pub fn longest_notarized_chain(&self) -> LocalChain {
+        self.chain_ending_at(&self.longest_tips[0])
+    }
+
+    /* The notarized chain from genesis up to (and including) the given notarized block. */
+    fn chain_ending_at(&self, tip: &Sha256Hash) -> LocalChain {
+        let mut blocks = Vec::new();
+        let mut current = self.notarized_blocks.get(tip);
+        while let Some(signed_block) = current {
+            blocks.push(signed_block.clone());
+            if signed_block.block.height == 0 {
+                break;
             }
+            current = self.notarized_blocks.get(&signed_block.block.parent_hash);
         }
+        blocks.reverse();
+        LocalChain { blocks }
+    }
+
+    /* Adds a notarized block under its (notarized) parent and tries to finalize the
+    chain it ends.
+     @param notarized_block: notarized block to add
+     @param signatures: the votes that notarized it
+     Returns false if the block doesn't fit in the tree (unknown parent, wrong height, or already present). */
+    pub fn add_notarized_block(&mut self, notarized_block: Block, signatures: Vec<Signature>) -> bool {
+        let (epoch, nonce, parent_hash, hash) = (notarized_block.epoch, notarized_block.nonce, notarized_block.parent_hash, notarized_block.hash);
+        if !self.insert_notarized_block(notarized_block, signatures) {
+            return false;
+        }
+        info!("\n\nAdded notarized block with epoch: {}, \nnonce: {}, \nparent hash: {:?}, \nhash: {:?}\n",
+              epoch, nonce, String::from_utf8_lossy(&parent_hash[..]), String::from_utf8_lossy(&hash[..]));
+        self.try_finalize(&hash);
         true
     }
 
-    pub fn index_of_ancestor_chain(&mut self, new_block: Block) -> Option<usize> {
-        let mut chain_idx = 0;
-        for chain in self.notarized_chains.iter_mut() {
-            if chain.length() < self.longest_notarized_chain_length { continue; }
-            let (block, _) = chain.head();
-            if block.hash == new_block.parent_hash { return Some(chain_idx) }
-            chain_idx += 1;
-        }
-        return None;
-    }
-
-    /* Tries to adds block to a notarized chain and tries to finalize the chain
-     @param notarized_block: notarized_block to add */
-    pub fn add_to_chain(&mut self, notarized_block: Block, signatures: Vec<Signature>, chain_index: usize) {
-        let notarized_chain_opt = self.notarized_chains.get_mut(chain_index);         
-        match notarized_chain_opt {
-            Some(notarized_chain) => {
-                // fail-fast checks to protect against public function shenanigans
-                if !(notarized_chain.head().0.hash == notarized_block.parent_hash) { panic!("Block was supposed to be a descendent of the given chain, but was not.") };
-                // if we make stuff more private we should confirm here that the block actually is notarized though this is more of a local issue so 
-                // not crazy important
-
-                notarized_chain.append_block(notarized_block.clone(), signatures);
-                info!("\n\nAdded notarized block with epoch: {}, \nnonce: {}, \nparent hash: {:?}, \nhash: {:?}\nNew chain: {}\n",
-                      notarized_block.epoch, notarized_block.nonce, String::from_utf8_lossy(&notarized_block.parent_hash[..]), String::from_utf8_lossy(&notarized_block.hash[..]), notarized_chain);
-                if notarized_chain.length() > self.longest_notarized_chain_length {
-                    self.longest_notarized_chain_length = notarized_chain.length();
-                    info!(
-                        "New longest notarized chain length: {}",
-                        self.longest_notarized_chain_length
-                    );
-                }
-                self.notarized_chains.sort_by(|a, b| b.length().cmp(&a.length()));
-                self.try_finalize(chain_index);
+    /* Adds a notarized block to the tree (and the store) without trying to finalize
+    anything; see add_notarized_block. */
+    fn insert_notarized_block(&mut self, notarized_block: Block, signatures: Vec<Signature>) -> bool {
+        let parent_height = match self.notarized_blocks.get(&notarized_block.parent_hash) {
+            Some(parent) => parent.block.height,
+            None => return false,
+        };
+        if parent_height + 1 != notarized_block.height
+            || self.notarized_blocks.contains_key(&notarized_block.hash)
+        {
+            return false;
+        }
+        let hash = notarized_block.hash;
+        self.children.entry(notarized_block.parent_hash).or_default().push(hash);
+
+        let length = usize::try_from(notarized_block.height + 1).expect("could not cast u64 to usize");
+        let signed_block = SignedBlock::new(notarized_block, signatures);
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.put_block(&signed_block) {
+                self.storage_errors.push((String::from("notarized block"), e));
             }
-            None => {}
         }
+        self.notarized_blocks.insert(hash, signed_block);
+        if length > self.longest_notarized_chain_length {
+            self.longest_notarized_chain_length = length;
+            self.longest_tips = vec![hash];
+            info!(
+                "New longest notarized chain length: {}",
+                self.longest_notarized_chain_length
+            );
+        } else if length == self.longest_notarized_chain_length {
+            self.longest_tips.push(hash);
+        }
+        true
     }
 
-    /* Tries to finalize the notarized chain given by notarized_chain_idx.
+    /* Tries to finalize the notarized chain ending at the given block.
         If finalization succeeds, updates the finalized chain .
-     @param notarized_chain_idx: index of the notarized chain (in the vec) */
-    fn try_finalize(&mut self, notarized_chain_idx: usize) {
+     @param tip: hash of the newest block of the chain */
+    fn try_finalize(&mut self, tip: &Sha256Hash) {
         // Check if the last 3 consecutive notarized blocks have sequential epochs and if so, commit the first two to finalized log
-        // This may need to be generalized to be an overall loop; can change it then if needed just wasn't sure why it would have to be this way
-        let notarized_chain = &self.notarized_chains[notarized_chain_idx];
+        // Newest block
+        let newest = &self.notarized_blocks[tip].block;
         // Require 3 blocks
-        if notarized_chain.length() < 3 {
+        if newest.height < 2 {
             return;
         }
-        let i = notarized_chain.length();
-        // Newest block 
-        let SignedBlock { block: newest, .. } = notarized_chain
-            .blocks
-            .get(i - 1)
-            .expect("expected recent block");
-        // Second-newest block 
-        let SignedBlock {
-            block: commit_2, ..
-        } = notarized_chain
-            .blocks
-            .get(i - 2)
-            .expect("expected latter notarized block");
+        // Second-newest block
+        let commit_2 = &self.notarized_blocks[&newest.parent_hash].block;
         // Third-newest block
-        let SignedBlock {
-            block: commit_1, ..
-        } = notarized_chain
-            .blocks
-            .get(i - 3)
-            .expect("expected former notarized block");
+        let commit_1 = &self.notarized_blocks[&commit_2.parent_hash].block;
 
-        if newest.epoch == commit_2.epoch + 1 
-            && commit_2.epoch == commit_1.epoch + 1 {
-            self.finalized_chain = notarized_chain.copy_up_to_height(commit_2.height);
+        // Finality only ever moves forward
+        let already_final = commit_2.height < self.finalized_chain_length as u64;
+        if newest.epoch == commit_2.epoch + 1
+            && commit_2.epoch == commit_1.epoch + 1
+            && !already_final {
+            let new_finalized_chain = self.chain_ending_at(&commit_2.hash);
+            for SignedBlock { block, .. } in &new_finalized_chain.blocks[self.finalized_chain_length..] {
+                self.index_finalized_block(block);
+            }
+            // Everything readers look at is updated before blocks are handed out as
+            // newly finalized, so a client told its entry is final can query it right away
+            self.newly_finalized
+                .extend_from_slice(&new_finalized_chain.blocks[self.finalized_chain_length..]);
+            self.finalized_chain = new_finalized_chain;
             self.finalized_chain_length = self.finalized_chain.length();
+            if let Some(store) = self.store.as_mut() {
+                if let Err(e) = store.set_finalized_tip(&self.finalized_chain.head().0.hash) {
+                    self.storage_errors.push((String::from("finalized chain"), e));
+                }
+            }
             info!(
                 "\n\nSuccessfully finalized chain, new finalized chain {}\n",
                 self.finalized_chain
             );
+            self.prune_abandoned_forks();
+            self.take_checkpoint();
+        }
+    }
+
+    /* Drops forks that branch off below the finalized tip; they can never be extended into
+    a finalized chain. */
+    fn prune_abandoned_forks(&mut self) {
+        let mut abandoned = Vec::new();
+        for pair in self.finalized_chain.blocks.windows(2) {
+            let (parent, kept) = (&pair[0].block, &pair[1].block);
+            if let Some(siblings) = self.children.get_mut(&parent.hash) {
+                abandoned.extend(siblings.iter().filter(|hash| **hash != kept.hash).cloned());
+                siblings.retain(|hash| *hash == kept.hash);
+            }
+        }
+        while let Some(hash) = abandoned.pop() {
+            if let Some(SignedBlock { block, .. }) = self.notarized_blocks.remove(&hash) {
+                if let Some(store) = self.store.as_mut() {
+                    if let Err(e) = store.remove_block(block.height, &hash) {
+                        self.storage_errors.push((String::from("removal of an abandoned block"), e));
+                    }
+                }
+            }
+            self.longest_tips.retain(|tip| *tip != hash);
+            if let Some(descendants) = self.children.remove(&hash) {
+                abandoned.extend(descendants);
+            }
+        }
+    }
+
+    /* Adopts a longer finalized chain from a trusted source (a verified export, see
+    export) as if this node had finalized it: its blocks join the block tree, and the
+    finalized chain, entry index and Merkle tree move up to its tip. Its blocks aren't
+    reported as newly finalized.
+     @param chain: finalized chain from genesis; ours must be a prefix of it
+     Returns the number of blocks it finalized, or why it conflicts with ours. */
+    pub fn adopt_finalized_chain(&mut self, chain: LocalChain) -> Result<usize, String> {
+        let conflict = self.finalized_chain.blocks.iter().zip(&chain.blocks).find(|(ours, theirs)| ours.block.hash != theirs.block.hash);
+        if let Some((ours, _)) = conflict {
+            return Err(format!("conflicts with our finalized block at height {}", ours.block.height));
+        }
+        let before = self.finalized_chain_length;
+        if chain.length() <= before {
+            return Ok(0);
+        }
+        // Inserted without trying to finalize each block in turn, which would rebuild the
+        // finalized chain over and over
+        for SignedBlock { block, cert } in chain.blocks.iter().skip(1) {
+            if !self.notarized_blocks.contains_key(&block.hash) {
+                self.insert_notarized_block(block.clone(), cert.signatures.clone());
+            }
+        }
+        for SignedBlock { block, .. } in &chain.blocks[before..] {
+            self.index_finalized_block(block);
+        }
+        self.finalized_chain = self.chain_ending_at(&chain.head().0.hash);
+        self.finalized_chain_length = self.finalized_chain.length();
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.set_finalized_tip(&self.finalized_chain.head().0.hash) {
+                self.storage_errors.push((String::from("finalized chain"), e));
+            }
+        }
+        self.prune_abandoned_forks();
+        self.take_checkpoint();
+        Ok(self.finalized_chain_length - before)
+    }
+
+    /* Our finalized chain as a snapshot for a peer far behind (see snapshot), with the
+    notarized child of its tip that shows the tip is final.
+    Returns None if we have no such child at hand, e.g. right after an import, or if
+    we are pruned. */
+    pub fn snapshot(&self) -> Option<ChainSnapshot> {
+        if self.checkpoint > 0 {
+            return None;
+        }
+        let tip = &self.finalized_chain.head().0;
+        let finality = self
+            .children
+            .get(&tip.hash)?
+            .iter()
+            .map(|hash| &self.notarized_blocks[hash])
+            .find(|child| child.block.epoch == tip.epoch + 1)?;
+        Some(ChainSnapshot {
+            chain: self.finalized_chain.clone(),
+            finality: finality.clone(),
+            tree_size: self.merkle_tree.size(),
+            root_hash: self.merkle_tree.root(),
+        })
+    }
+
+    /* Adopts a verified snapshot (see ChainSnapshot::verify): its chain, as with
+    adopt_finalized_chain, then the block that shows it is final, so we can serve
+    snapshots in turn.
+     @param snapshot: a snapshot that verified
+     Returns the number of blocks it finalized, or why it conflicts with ours. */
+    pub fn adopt_snapshot(&mut self, snapshot: ChainSnapshot) -> Result<usize, String> {
+        let adopted = self.adopt_finalized_chain(snapshot.chain)?;
+        if !self.notarized_blocks.contains_key(&snapshot.finality.block.hash) {
+            self.add_notarized_block(snapshot.finality.block, snapshot.finality.cert.signatures);
+        }
+        Ok(adopted)
+    }
+
+    /* Turns pruning on or off. A pruned node checkpoints its finalized chain every
+    keep blocks, and drops the bodies (data) of the blocks below the latest checkpoint
+    at least keep blocks under the finalized tip, keeping between keep and 2 * keep
+    bodies. Headers and certificates stay, and so does the Merkle tree, so tree heads,
+    inclusion proofs by leaf hash and consistency proofs are served as before. Dropped
+    entries can't be looked up by id or read back, peers can't sync blocks below the
+    checkpoint from us, and we can't serve snapshots; those need an archive node. Bodies
+    once dropped stay dropped if pruning is turned off.
+     @param keep: finalized blocks to keep whole (None: archive node, keep everything) */
+    pub fn set_pruning(&mut self, keep: Option<u64>) {
+        self.keep_bodies = keep.map(|keep| keep.max(1));
+        self.take_checkpoint();
+    }
+
+    /* Finalized blocks a pruned node keeps whole (None: archive node). */
+    pub fn pruning(&self) -> Option<u64> {
+        self.keep_bodies
+    }
+
+    /* Height of the latest checkpoint: blocks below it are headers only (0: nothing pruned). */
+    pub fn checkpoint(&self) -> u64 {
+        self.checkpoint
+    }
+
+    /* Number of entries whose bytes have been dropped: the first leaves of the tree. */
+    pub fn pruned_entries(&self) -> u64 {
+        self.pruned_entries
+    }
+
+    /* Moves the checkpoint up once the finalized chain has grown far enough past it
+    (see set_pruning), dropping the bodies of the blocks it passes. */
+    fn take_checkpoint(&mut self) {
+        let keep = match self.keep_bodies {
+            Some(keep) => keep,
+            None => return,
+        };
+        let finalized_height = self.finalized_chain_length as u64 - 1;
+        let checkpoint = finalized_height.saturating_sub(keep) / keep * keep;
+        if checkpoint <= self.checkpoint {
+            return;
+        }
+        // The genesis block isn't in the log, so it keeps its data
+        for signed in &mut self.finalized_chain.blocks[self.checkpoint.max(1) as usize..checkpoint as usize] {
+            if signed.block.data.is_empty() {
+                continue;
+            }
+            let data = std::mem::take(&mut signed.block.data);
+            if let Some(entry) = LogEntry::deserialize(&data) {
+                self.entry_heights.remove(&entry.id);
+            }
+            if let Some(notarized) = self.notarized_blocks.get_mut(&signed.block.hash) {
+                notarized.block.data.clear();
+            }
+            self.pruned_entries += 1;
+            if let Some(store) = self.store.as_mut() {
+                if let Err(e) = store.put_pruned_block(signed, Some(&leaf_hash(&data))) {
+                    self.storage_errors.push((String::from("pruned block"), e));
+                }
+            }
+        }
+        self.checkpoint = checkpoint;
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.set_checkpoint(checkpoint) {
+                self.storage_errors.push((String::from("checkpoint"), e));
+            }
+        }
+        info!("Checkpoint at height {}; dropped the bodies of the blocks below it", checkpoint);
+    }
+
+    /* The finalized prefix of the chain (starts with the genesis block). */
+    pub fn finalized_chain(&self) -> &LocalChain {
+        &self.finalized_chain
+    }
+
+    /* Adds a newly finalized block's entry to the Merkle tree and the entry index.
+    Empty blocks carry no entry. */
+    fn index_finalized_block(&mut self, block: &Block) {
+        if block.data.is_empty() {
+            return;
+        }
+        self.merkle_tree.push(&block.data);
+        if let Some(entry) = LogEntry::deserialize(&block.data) {
+            self.entry_heights.insert(entry.id, block.height);
+        }
+    }
+
+    /* The finalized block holding the entry with the given id, if it is final yet. */
+    pub fn find_finalized_entry(&self, id: &EntryId) -> Option<&SignedBlock> {
+        let height = usize::try_from(*self.entry_heights.get(id)?).ok()?;
+        self.finalized_chain.blocks.get(height)
+    }
+
+    /* Entries of the finalized log in Merkle leaf order, from leaf start up to
+    (not including) leaf end. Entries a pruned node dropped (see pruned_entries) are
+    left out. */
+    pub fn finalized_entries(&self, start: u64, end: u64) -> Vec<&[u8]> {
+        let first = start.max(self.pruned_entries);
+        self.finalized_chain
+            .blocks
+            .iter()
+            .skip(self.checkpoint.max(1) as usize)
+            .map(|SignedBlock { block, .. }| block.data.as_slice())
+            .filter(|data| !data.is_empty())
+            .skip((first - self.pruned_entries) as usize)
+            .take(end.saturating_sub(first) as usize)
+            .collect()
+    }
+
+    /* Merkle tree over the log's entries (payloads of finalized, non-empty blocks). */
+    pub fn merkle_tree(&self) -> &MerkleTree {
+        &self.merkle_tree
+    }
+
+    /* Returns (and forgets) blocks finalized since the last call, oldest first.
+    Lets callers react to finalization advancing. */
+    pub fn take_newly_finalized(&mut self) -> Vec<SignedBlock> {
+        std::mem::take(&mut self.newly_finalized)
+    }
+
+    /* Returns (and forgets) the store writes that failed since the last call, as
+    (what was being written, error). The chain in memory is unaffected, but it won't
+    survive a restart, so callers should report these. */
+    pub fn take_storage_errors(&mut self) -> Vec<(String, StoreError)> {
+        std::mem::take(&mut self.storage_errors)
+    }
+
+    /* Makes every store write so far durable; a failure is reported like other store
+    errors (see take_storage_errors). */
+    pub fn flush_store(&mut self) {
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.flush() {
+                self.storage_errors.push((String::from("chain store flush"), e));
+            }
         }
     }
 
     pub fn fetch_chain_after_epoch(&mut self, epoch: u64) -> Vec<SignedBlock> {
         let chain = self.finalized_chain.clone().blocks;
-        let chain = chain
+        
+        chain
             .into_iter()
             .filter(|signed_block| (signed_block.block.epoch > epoch) || (self.last_logged_epoch == 0))
-            .collect();
-        chain
+            .collect()
     }
     pub fn fetch_local_finalized_chain(&self) -> LocalChain { self.finalized_chain.clone() }
     pub fn export_local_finalized_chain_to_file(&mut self, local_file_path: String) {
@@ -156,51 +644,54 @@ impl BlockchainManager {
             .create(true)
             .open(local_file_path)
             .unwrap();
-        let unlogged_chain = serde_json::to_string_pretty(&self.fetch_chain_after_epoch(last_epoch)).unwrap();
+        let blocks: Vec<_> = self
+            .fetch_chain_after_epoch(last_epoch)
+            .iter()
+            .map(|SignedBlock { block, cert }| block_json(block, &cert.signatures))
+            .collect();
+        let export = serde_json::json!({ json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION, "blocks": blocks });
+        let unlogged_chain = serde_json::to_string_pretty(&export).unwrap();
         file.write_all(unlogged_chain.as_bytes()).unwrap();
         self.last_logged_epoch = self.get_latest_finalized_block().0.epoch;
     }
     pub fn publish_last_finalized_block(&self) {
         info!("publishing most recent finalized block to public chain");
         // Hard-coding the public path here but if we want to get fancy with it we could have this be determined by the app or have it be specifiable. Just trying to make checking easy
-        let public_path = format!("{}/src/tmp/{}.txt", env::current_dir().expect("invalid current directory").display().to_string(), "pub");
+        let public_path = format!("{}/src/tmp/{}.txt", env::current_dir().expect("invalid current directory").display(), "pub");
         let mut file = OpenOptions::new()
             .append(true)
             .create(true)
             .open(public_path)
             .unwrap();
-        let latest_finalized_block_msg = serde_json::to_string_pretty(&self.get_latest_finalized_block()).unwrap();
+        let (block, signatures) = self.get_latest_finalized_block();
+        let latest_finalized_block_msg = serde_json::to_string_pretty(&block_json(block, signatures)).unwrap();
         file.write_all(latest_finalized_block_msg.as_bytes()).unwrap();
         
     }
 
     /* Returns the most recent notarized block on one of the longest notarized
     chains. */
-    pub fn head(&mut self) -> (&Block, &Vec<Signature>) {
-        // Garbage collect
-        self.cleanup_notarized_chains();
-        return self.notarized_chains[0].head();
+    pub fn head(&self) -> (&Block, &Vec<Signature>) {
+        let SignedBlock { block, cert } = &self.notarized_blocks[&self.longest_tips[0]];
+        (block, &cert.signatures)
     }
     /* Returns a copy of the most recent finalized block. */
     pub fn get_latest_finalized_block(&self) -> (&Block, &Vec<Signature>) {
-        let SignedBlock { block, signatures } = &self
+        let SignedBlock { block, cert } = &self
             .finalized_chain
             .blocks
             .last()
             .expect("finalized chain is empty...");
-        (block, signatures)
-    }
-
-    /* Garbage collect all notarized chains which are no longer a "longest notarized chain" */
-    fn cleanup_notarized_chains(&mut self) {
-        self.notarized_chains
-            .retain(|x| x.length() == self.longest_notarized_chain_length);
+        (block, &cert.signatures)
     }
 
+    /* Prints every fork, i.e. the chain ending at each notarized block without children. */
     pub fn print_notarized_chains(&self) {
         println!("************************ PRINTING NOTARIZED CHAINS **********************");
-        for chain in self.notarized_chains.iter() {
-            println!("{}", chain);
+        for hash in self.notarized_blocks.keys() {
+            if !self.children.contains_key(hash) {
+                println!("{}", self.chain_ending_at(hash));
+            }
         }
         println!("*************************************************************************");
     }
@@ -212,3 +703,211 @@ impl BlockchainManager {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notarize_on_quorum_of_distinct_signers() {
+        let mut manager = BlockchainManager::new();
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let parent_hash = manager.head().0.hash;
+        let block = Block::new(1, parent_hash, b"entry".to_vec(), 1, 0);
+        let sig = keypair.sign(&block.hash);
+
+        // Repeated votes from the same signer don't count twice
+        assert_eq!(manager.record_votes(&block, vec![(String::from("h1"), sig), (String::from("h1"), sig)]), 1);
+        assert!(!manager.try_notarize(&block.hash, 2));
+        assert_eq!(manager.record_votes(&block, vec![(String::from("h2"), sig)]), 1);
+        assert!(manager.try_notarize(&block.hash, 2));
+        assert!(manager.is_block_notarized(&block.hash));
+        assert_eq!(manager.head().0.hash, block.hash);
+        assert_eq!(manager.head().1.len(), 2);
+
+        // Late votes on a notarized block are ignored
+        assert_eq!(manager.record_votes(&block, vec![(String::from("h3"), sig)]), 0);
+    }
+
+    #[test]
+    fn test_finalize_on_three_consecutive_epochs() {
+        let mut manager = BlockchainManager::new();
+        let mut parent_hash = manager.head().0.hash;
+        let mut blocks = Vec::new();
+        // Epochs 2, 3, 4 (genesis is epoch 0, so epoch 2 doesn't chain onto it)
+        for (height, epoch) in [(1, 2), (2, 3), (3, 4)] {
+            let block = Block::new(epoch, parent_hash, vec![height as u8], height, 0);
+            parent_hash = block.hash;
+            assert!(manager.add_notarized_block(block.clone(), Vec::new()));
+            blocks.push(block);
+            if epoch < 4 {
+                assert_eq!(manager.finalized_chain().length(), 1);
+                assert!(manager.take_newly_finalized().is_empty());
+            }
+        }
+        // The first two of the three consecutive blocks are final; the newest is not
+        assert_eq!(manager.finalized_chain().length(), 3);
+        let finalized: Vec<Block> = manager.take_newly_finalized().into_iter().map(|b| b.block).collect();
+        assert_eq!(finalized, blocks[..2].to_vec());
+        assert!(manager.take_newly_finalized().is_empty());
+
+        // Finalized entries are in the Merkle tree, in order
+        assert_eq!(manager.merkle_tree().size(), 2);
+        let proof = manager.merkle_tree().prove_inclusion(&blocks[1].data).unwrap();
+        assert_eq!(proof.leaf_index, 1);
+        assert!(verify_inclusion(&blocks[1].data, &proof, &manager.merkle_tree().root()));
+        assert!(manager.merkle_tree().prove_inclusion(&blocks[2].data).is_none());
+    }
+
+    #[test]
+    fn test_forks_and_longest_notarized_chain() {
+        let mut manager = BlockchainManager::new();
+        let genesis = manager.head().0.clone();
+        let left = Block::new(1, genesis.hash, b"left".to_vec(), 1, 0);
+        let right = Block::new(2, genesis.hash, b"right".to_vec(), 1, 0);
+        assert!(manager.add_notarized_block(left.clone(), Vec::new()));
+        assert!(manager.add_notarized_block(right.clone(), Vec::new()));
+
+        // Both forks are longest, so a block on either one is acceptable
+        assert_eq!(manager.longest_notarized_chain_length, 2);
+        let on_right = Block::new(3, right.hash, b"on right".to_vec(), 2, 0);
+        assert!(manager.extends_longest_notarized_chain(&on_right));
+        assert!(manager.extends_longest_notarized_chain(&Block::new(3, left.hash, b"on left".to_vec(), 2, 0)));
+        // ...but not one skipping ahead of its parent's height
+        assert!(!manager.extends_longest_notarized_chain(&Block::new(3, right.hash, b"bad".to_vec(), 5, 0)));
+
+        assert!(manager.add_notarized_block(on_right.clone(), Vec::new()));
+        assert!(!manager.extends_longest_notarized_chain(&Block::new(4, left.hash, b"stale".to_vec(), 2, 0)));
+        let chain: Vec<Block> = manager.longest_notarized_chain().blocks.into_iter().map(|b| b.block).collect();
+        assert_eq!(chain, vec![genesis, right, on_right.clone()]);
+        assert_eq!(manager.head().0.hash, on_right.hash);
+    }
+
+    #[test]
+    fn test_child_notarized_after_parent() {
+        let mut manager = BlockchainManager::new();
+        let parent = Block::new(1, manager.head().0.hash, b"parent".to_vec(), 1, 0);
+        let child = Block::new(2, parent.hash, b"child".to_vec(), 2, 0);
+        let sig = Keypair::generate(SignatureScheme::default()).sign(&child.hash);
+
+        // The child reaches quorum first and waits for its parent
+        manager.record_votes(&child, vec![(String::from("h1"), sig)]);
+        assert!(!manager.try_notarize(&child.hash, 1));
+        manager.record_votes(&parent, vec![(String::from("h1"), sig)]);
+        assert!(manager.try_notarize(&parent.hash, 1));
+        assert!(manager.is_block_notarized(&child.hash));
+        assert_eq!(manager.head().0.hash, child.hash);
+    }
+
+    #[test]
+    fn test_catch_up_from_peer_segment() {
+        let mut ahead = BlockchainManager::new();
+        let mut parent_hash = ahead.head().0.hash;
+        for (height, epoch) in [(1, 1), (2, 2), (3, 4), (4, 6)] {
+            let block = Block::new(epoch, parent_hash, vec![height as u8], height, 0);
+            parent_hash = block.hash;
+            assert!(ahead.add_notarized_block(block, Vec::new()));
+        }
+
+        let mut behind = BlockchainManager::new();
+        let segment = ahead.notarized_chain_from(0, 2).unwrap();
+        assert_eq!(segment.length(), 3);
+        assert!(BlockchainManager::is_chain_valid(&segment));
+        behind.observe_chain(segment);
+        let segment = ahead.notarized_chain_from(2, 10).unwrap();
+        behind.observe_chain(segment);
+        assert_eq!(behind.head().0.hash, ahead.head().0.hash);
+        assert_eq!(behind.finalized_chain().length(), 2);
+        assert!(ahead.notarized_chain_from(4, 10).is_none());
+
+        // Tampered data no longer matches the block hash
+        let mut tampered = ahead.notarized_chain_from(0, 10).unwrap();
+        tampered.blocks[2].block.data = b"forged".to_vec();
+        assert!(!BlockchainManager::is_chain_valid(&tampered));
+    }
+
+    #[test]
+    fn test_restore_from_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut manager = BlockchainManager::with_store(Box::new(SledStore::from_db(db.clone()).unwrap())).unwrap();
+        let mut parent_hash = manager.head().0.hash;
+        for (height, epoch) in [(1, 1), (2, 2), (3, 4)] {
+            let block = Block::new(epoch, parent_hash, vec![height as u8], height, 0);
+            parent_hash = block.hash;
+            assert!(manager.add_notarized_block(block, Vec::new()));
+        }
+        let finalized = manager.finalized_chain().clone();
+        assert_eq!(finalized.length(), 2);
+        let notarized = manager.longest_notarized_chain();
+        drop(manager);
+
+        // As after a restart
+        let restored = BlockchainManager::with_store(Box::new(SledStore::from_db(db).unwrap())).unwrap();
+        assert_eq!(restored.finalized_chain(), &finalized);
+        assert_eq!(restored.longest_notarized_chain(), notarized);
+        assert_eq!(restored.head().0.hash, parent_hash);
+    }
+
+    #[test]
+    fn test_finalized_entry_is_queryable_immediately() {
+        let mut manager = BlockchainManager::new();
+        let entry = LogEntry::new(b"directory".to_vec());
+        let mut parent_hash = manager.head().0.hash;
+        for (height, epoch) in [(1, 2), (2, 3), (3, 4)] {
+            let data = if height == 1 { entry.serialize() } else { Vec::new() };
+            let block = Block::new(epoch, parent_hash, data, height, 0);
+            parent_hash = block.hash;
+            assert!(manager.find_finalized_entry(&entry.id).is_none());
+            manager.add_notarized_block(block, Vec::new());
+        }
+
+        // Submit -> finalize -> query: once the block is reported final, every query sees it
+        let finalized = manager.take_newly_finalized();
+        assert_eq!(LogEntry::deserialize(&finalized[0].block.data), Some(entry.clone()));
+        assert_eq!(manager.find_finalized_entry(&entry.id), Some(&finalized[0]));
+        assert!(manager.fetch_local_finalized_chain().blocks.contains(&finalized[0]));
+        let proof = manager.merkle_tree().prove_inclusion(&entry.serialize()).unwrap();
+        assert!(verify_inclusion(&entry.serialize(), &proof, &manager.merkle_tree().root()));
+    }
+
+    #[test]
+    fn test_pruned_node_keeps_tree_across_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut pruned = BlockchainManager::with_store(Box::new(SledStore::from_db(db.clone()).unwrap())).unwrap();
+        pruned.set_pruning(Some(2));
+        let mut archive = BlockchainManager::new();
+        let entries: Vec<LogEntry> = (1..=8).map(|height| LogEntry::new(vec![height])).collect();
+        let mut parent_hash = pruned.head().0.hash;
+        for (height, entry) in (1..=8).zip(&entries) {
+            let block = Block::new(height, parent_hash, entry.serialize(), height, 0);
+            parent_hash = block.hash;
+            assert!(archive.add_notarized_block(block.clone(), Vec::new()));
+            assert!(pruned.add_notarized_block(block, Vec::new()));
+        }
+
+        // Finalized up to height 7: the checkpoint is at 4, and blocks 1 to 3 lost their data
+        assert_eq!(pruned.checkpoint(), 4);
+        assert_eq!(pruned.pruned_entries(), 3);
+        assert!(pruned.finalized_chain().blocks[3].block.data.is_empty());
+        assert_eq!(pruned.finalized_chain().blocks[4].block.data, entries[3].serialize());
+        assert!(pruned.find_finalized_entry(&entries[0].id).is_none());
+        assert!(pruned.find_finalized_entry(&entries[3].id).is_some());
+        assert_eq!(pruned.finalized_entries(0, 10), archive.finalized_entries(3, 10));
+        assert!(pruned.notarized_chain_from(0, 10).is_none());
+        assert!(pruned.notarized_chain_from(3, 10).is_some());
+        assert!(pruned.snapshot().is_none());
+
+        // Proofs are served as by an archive node
+        assert_eq!(pruned.merkle_tree().root(), archive.merkle_tree().root());
+        assert_eq!(pruned.merkle_tree().prove_consistency(2, 7), archive.merkle_tree().prove_consistency(2, 7));
+        assert!(pruned.merkle_tree().prove_inclusion(&entries[0].serialize()).is_some());
+        drop(pruned);
+
+        // As after a restart
+        let restored = BlockchainManager::with_store(Box::new(SledStore::from_db(db).unwrap())).unwrap();
+        assert_eq!(restored.checkpoint(), 4);
+        assert_eq!(restored.pruned_entries(), 3);
+        assert_eq!(restored.merkle_tree().root(), archive.merkle_tree().root());
+        assert_eq!(restored.finalized_entries(0, 10), archive.finalized_entries(3, 10));
+    }
+}
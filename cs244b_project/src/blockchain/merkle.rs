@@ -0,0 +1,331 @@
+/* Merkle tree over the log's entries (the payloads of finalized blocks), in the
+   RFC 6962 / RFC 9162 style used by certificate transparency:
+   - leaf hash:  SHA-256(0x00 || entry)
+   - node hash:  SHA-256(0x01 || left || right)
+   - a tree of n leaves splits at the largest power of two smaller than n.
+   verify_inclusion and verify_consistency only need a proof and tree roots, so clients
+   can check proofs without running a node. */
+
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+
+/* Proof that the leaf at leaf_index is in the tree of the first tree_size leaves. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditPath {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub path: Vec<Sha256Hash>, // sibling hashes, from the leaf up
+}
+
+/* Proof that the tree of the first old_size leaves is a prefix of the tree of the
+first new_size leaves, i.e. the log only grew in between. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub path: Vec<Sha256Hash>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    leaves: Vec<Sha256Hash>,
+}
+
+pub fn leaf_hash(entry: &[u8]) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(entry);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Largest power of two strictly smaller than n (n >= 2)
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+fn subtree_root(leaves: &[Sha256Hash]) -> Sha256Hash {
+    match leaves.len() {
+        0 => Sha256::digest(b"").into(),
+        1 => leaves[0],
+        n => {
+            let k = split_point(n);
+            node_hash(&subtree_root(&leaves[..k]), &subtree_root(&leaves[k..]))
+        }
+    }
+}
+
+fn subtree_path(index: usize, leaves: &[Sha256Hash], path: &mut Vec<Sha256Hash>) {
+    let n = leaves.len();
+    if n <= 1 {
+        return;
+    }
+    let k = split_point(n);
+    if index < k {
+        subtree_path(index, &leaves[..k], path);
+        path.push(subtree_root(&leaves[k..]));
+    } else {
+        subtree_path(index - k, &leaves[k..], path);
+        path.push(subtree_root(&leaves[..k]));
+    }
+}
+
+// SUBPROOF from RFC 6962, section 2.1.2; `whole` is true while the old tree's root is
+// a node of the new tree that the verifier already knows (so it is left out)
+fn subtree_consistency(old_size: usize, leaves: &[Sha256Hash], whole: bool, path: &mut Vec<Sha256Hash>) {
+    let n = leaves.len();
+    if old_size == n {
+        if !whole {
+            path.push(subtree_root(leaves));
+        }
+        return;
+    }
+    let k = split_point(n);
+    if old_size <= k {
+        subtree_consistency(old_size, &leaves[..k], whole, path);
+        path.push(subtree_root(&leaves[k..]));
+    } else {
+        subtree_consistency(old_size - k, &leaves[k..], false, path);
+        path.push(subtree_root(&leaves[..k]));
+    }
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self { leaves: Vec::new() }
+    }
+
+    /* Appends a leaf by its hash, for an entry whose bytes are gone (see
+    BlockchainManager::set_pruning). Returns its leaf index. */
+    pub fn push_leaf(&mut self, leaf: Sha256Hash) -> u64 {
+        self.leaves.push(leaf);
+        (self.leaves.len() - 1) as u64
+    }
+
+    /* Appends an entry. Returns its leaf index. */
+    pub fn push(&mut self, entry: &[u8]) -> u64 {
+        self.leaves.push(leaf_hash(entry));
+        (self.leaves.len() - 1) as u64
+    }
+
+    /* Number of entries in the tree. */
+    pub fn size(&self) -> u64 {
+        self.leaves.len() as u64
+    }
+
+    pub fn root(&self) -> Sha256Hash {
+        subtree_root(&self.leaves)
+    }
+
+    /* Root of the tree as it was when it had tree_size entries. */
+    pub fn root_at(&self, tree_size: u64) -> Option<Sha256Hash> {
+        let size = usize::try_from(tree_size).ok()?;
+        self.leaves.get(..size).map(subtree_root)
+    }
+
+    /* Leaf hash at an index. */
+    pub fn leaf(&self, index: u64) -> Option<Sha256Hash> {
+        self.leaves.get(usize::try_from(index).ok()?).copied()
+    }
+
+    /* Leaf index of the (first occurrence of the) entry. */
+    pub fn index_of(&self, entry: &[u8]) -> Option<u64> {
+        self.index_of_leaf(&leaf_hash(entry))
+    }
+
+    /* Leaf index of the (first) leaf with the given leaf hash (see leaf_hash). */
+    pub fn index_of_leaf(&self, hash: &Sha256Hash) -> Option<u64> {
+        self.leaves.iter().position(|leaf| leaf == hash).map(|i| i as u64)
+    }
+
+    /* Inclusion proof for an entry against the current root. */
+    pub fn prove_inclusion(&self, entry: &[u8]) -> Option<AuditPath> {
+        self.prove_inclusion_at(self.index_of(entry)?, self.size())
+    }
+
+    /* Inclusion proof for the leaf at leaf_index against the root at tree_size. */
+    pub fn prove_inclusion_at(&self, leaf_index: u64, tree_size: u64) -> Option<AuditPath> {
+        if leaf_index >= tree_size || tree_size > self.size() {
+            return None;
+        }
+        let mut path = Vec::new();
+        subtree_path(leaf_index as usize, &self.leaves[..tree_size as usize], &mut path);
+        Some(AuditPath { leaf_index, tree_size, path })
+    }
+
+    /* Consistency proof between the roots at two tree sizes (see root_at).
+    @param old_size: size of the older tree (e.g. from an auditor's last tree head)
+    @param new_size: size of the newer tree, at most the current size */
+    pub fn prove_consistency(&self, old_size: u64, new_size: u64) -> Option<ConsistencyProof> {
+        if old_size > new_size || new_size > self.size() {
+            return None;
+        }
+        let mut path = Vec::new();
+        if old_size > 0 && old_size < new_size {
+            subtree_consistency(old_size as usize, &self.leaves[..new_size as usize], true, &mut path);
+        }
+        Some(ConsistencyProof { old_size, new_size, path })
+    }
+}
+
+/* Checks an inclusion proof (RFC 9162, section 2.1.3.2).
+@param entry: the entry's bytes
+@param proof: audit path from the log
+@param root: root hash of the tree of proof.tree_size entries (e.g. from a tree head) */
+pub fn verify_inclusion(entry: &[u8], proof: &AuditPath, root: &Sha256Hash) -> bool {
+    verify_leaf_inclusion(&leaf_hash(entry), proof, root)
+}
+
+/* Same as verify_inclusion, for a client that only has the entry's leaf hash. */
+pub fn verify_leaf_inclusion(leaf: &Sha256Hash, proof: &AuditPath, root: &Sha256Hash) -> bool {
+    if proof.leaf_index >= proof.tree_size {
+        return false;
+    }
+    let mut node = proof.leaf_index;
+    let mut last = proof.tree_size - 1;
+    let mut r = *leaf;
+    for p in &proof.path {
+        if last == 0 {
+            return false;
+        }
+        if node & 1 == 1 || node == last {
+            r = node_hash(p, &r);
+            if node & 1 == 0 {
+                while node & 1 == 0 && node != 0 {
+                    node >>= 1;
+                    last >>= 1;
+                }
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+    last == 0 && r == *root
+}
+
+/* Checks a consistency proof (RFC 9162, section 2.1.4.2).
+@param proof: consistency proof from the log
+@param old_root: root hash of the tree of proof.old_size entries
+@param new_root: root hash of the tree of proof.new_size entries */
+pub fn verify_consistency(proof: &ConsistencyProof, old_root: &Sha256Hash, new_root: &Sha256Hash) -> bool {
+    if proof.old_size > proof.new_size {
+        return false;
+    }
+    // An empty log is a prefix of every log
+    if proof.old_size == 0 {
+        return proof.path.is_empty();
+    }
+    if proof.old_size == proof.new_size {
+        return proof.path.is_empty() && old_root == new_root;
+    }
+    let mut path = proof.path.clone();
+    if proof.old_size.is_power_of_two() {
+        path.insert(0, *old_root);
+    }
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    let mut old_node = proof.old_size - 1;
+    let mut new_node = proof.new_size - 1;
+    while old_node & 1 == 1 {
+        old_node >>= 1;
+        new_node >>= 1;
+    }
+    let mut old_r = *first;
+    let mut new_r = *first;
+    for c in rest {
+        if new_node == 0 {
+            return false;
+        }
+        if old_node & 1 == 1 || old_node == new_node {
+            old_r = node_hash(c, &old_r);
+            new_r = node_hash(c, &new_r);
+            while old_node & 1 == 0 && old_node != 0 {
+                old_node >>= 1;
+                new_node >>= 1;
+            }
+        } else {
+            new_r = node_hash(&new_r, c);
+        }
+        old_node >>= 1;
+        new_node >>= 1;
+    }
+    new_node == 0 && old_r == *old_root && new_r == *new_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inclusion_proofs_for_every_size() {
+        let mut tree = MerkleTree::new();
+        for n in 0..20u8 {
+            tree.push(&[n]);
+            for size in 1..=tree.size() {
+                let root = tree.root_at(size).unwrap();
+                for index in 0..size {
+                    let proof = tree.prove_inclusion_at(index, size).unwrap();
+                    assert!(verify_inclusion(&[index as u8], &proof, &root));
+                    assert!(!verify_inclusion(&[index as u8 + 1], &proof, &root));
+                }
+            }
+        }
+        assert!(tree.prove_inclusion_at(20, 20).is_none());
+    }
+
+    #[test]
+    fn test_consistency_proofs_for_every_size() {
+        let mut tree = MerkleTree::new();
+        for n in 0..20u8 {
+            tree.push(&[n]);
+        }
+        for new_size in 0..=tree.size() {
+            let new_root = tree.root_at(new_size).unwrap();
+            for old_size in 0..=new_size {
+                let old_root = tree.root_at(old_size).unwrap();
+                let proof = tree.prove_consistency(old_size, new_size).unwrap();
+                assert!(verify_consistency(&proof, &old_root, &new_root));
+                // A rewritten history must not verify
+                if old_size > 0 && old_size < new_size {
+                    let forged_root = node_hash(&old_root, &old_root);
+                    assert!(!verify_consistency(&proof, &forged_root, &new_root));
+                    assert!(!verify_consistency(&proof, &old_root, &forged_root));
+                }
+            }
+        }
+        assert!(tree.prove_consistency(5, 4).is_none());
+        assert!(tree.prove_consistency(5, 21).is_none());
+    }
+
+    #[test]
+    fn test_known_root() {
+        // Three-leaf tree: root = H(1 || H(1 || L0 || L1) || L2)
+        let mut tree = MerkleTree::new();
+        for entry in [b"a", b"b", b"c"] {
+            tree.push(entry);
+        }
+        let expected = node_hash(&node_hash(&leaf_hash(b"a"), &leaf_hash(b"b")), &leaf_hash(b"c"));
+        assert_eq!(tree.root(), expected);
+
+        let proof = tree.prove_inclusion(b"c").unwrap();
+        assert_eq!(proof.path, vec![node_hash(&leaf_hash(b"a"), &leaf_hash(b"b"))]);
+        let mut tampered = proof.clone();
+        tampered.tree_size = 4;
+        assert!(!verify_inclusion(b"c", &tampered, &tree.root()));
+    }
+}
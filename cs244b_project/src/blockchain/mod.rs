@@ -1,7 +1,31 @@
 mod block;
+mod cert;
 mod chain;
+mod chain_id;
+mod entry;
+mod export;
+mod journal;
 mod manager;
+mod merkle;
+mod promise;
+mod schema;
+mod sealed;
+mod snapshot;
+mod store;
+mod tree_head;
 
 pub use block::*;
+pub use cert::*;
 pub use chain::*;
+pub use chain_id::*;
+pub use entry::*;
+pub use export::*;
+pub use journal::*;
 pub use manager::*;
+pub use merkle::*;
+pub use promise::*;
+pub use schema::*;
+pub use sealed::*;
+pub use snapshot::*;
+pub use store::*;
+pub use tree_head::*;
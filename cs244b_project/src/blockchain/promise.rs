@@ -0,0 +1,126 @@
+/* Signed inclusion promises, in the spirit of certificate transparency's SCTs.
+   When a node accepts an entry it signs a timestamped promise to include it in the log
+   within a maximum merge delay (MMD). Once the entry is finalized, the node backs the
+   promise with an InclusionProof: an audit path against a signed tree head. A client
+   holding both can show the node kept its word, or, if the deadline passes without a
+   proof, hold up the signed promise as evidence that it didn't. */
+
+use crate::blockchain::chain_id::ChainId;
+use crate::blockchain::entry::EntryId;
+use crate::blockchain::merkle::{leaf_hash, verify_inclusion, AuditPath};
+use crate::blockchain::tree_head::SignedTreeHead;
+use crate::utils::clock::unix_time_ms;
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Domain separator, so a promise signature can't be passed off as anything else
+const PROMISE_CONTEXT: &[u8] = b"streamlet inclusion promise v1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InclusionPromise {
+    pub entry_id: EntryId,
+    pub leaf_hash: Sha256Hash, // Merkle leaf hash of the entry, as it will appear in the log
+    pub timestamp_ms: u64,     // when the entry was accepted (ms since the Unix epoch)
+    pub max_merge_delay_ms: u64,
+    pub signer: String,
+    pub signature: Signature,
+}
+
+/* Backs a promise: the entry's audit path against a tree head that includes it. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub entry_id: EntryId,
+    pub proof: AuditPath,
+    pub sth: SignedTreeHead,
+}
+
+impl InclusionPromise {
+    /* Promises to include an entry.
+    @param entry_id: the entry's id
+    @param entry: the entry's bytes, as they will be stored in its block
+    @param max_merge_delay: how long the entry may take to be finalized
+    @param signer: this node's name
+    @param keypair: this node's signing key (see Signer)
+    @param chain_id: the deployment's chain id */
+    pub fn sign(entry_id: EntryId, entry: &[u8], max_merge_delay: Duration, signer: String, keypair: &dyn Signer, chain_id: &ChainId) -> Self {
+        let leaf_hash = leaf_hash(entry);
+        let timestamp_ms = unix_time_ms();
+        let max_merge_delay_ms = max_merge_delay.as_millis() as u64;
+        let bytes = InclusionPromise::signed_bytes(chain_id, &entry_id, &leaf_hash, timestamp_ms, max_merge_delay_ms);
+        Self {
+            entry_id,
+            leaf_hash,
+            timestamp_ms,
+            max_merge_delay_ms,
+            signer,
+            signature: keypair.sign(&bytes),
+        }
+    }
+
+    /* Checks the signature against the signer's public key, for the given deployment. */
+    pub fn verify(&self, public_key: &dyn Verifier, chain_id: &ChainId) -> bool {
+        let bytes = InclusionPromise::signed_bytes(chain_id, &self.entry_id, &self.leaf_hash, self.timestamp_ms, self.max_merge_delay_ms);
+        public_key.verify(&bytes, &self.signature).is_ok()
+    }
+
+    /* Time (ms since the Unix epoch) by which the entry must be in a signed tree head. */
+    pub fn deadline_ms(&self) -> u64 {
+        self.timestamp_ms.saturating_add(self.max_merge_delay_ms)
+    }
+
+    /* Whether an inclusion proof fulfills this promise: it is for this entry, proves
+    inclusion under its tree head's root, and the tree head is no later than the deadline.
+    Signatures (on the promise and the tree head) are checked separately, with verify.
+    @param entry: the entry's bytes
+    @param inclusion: the proof sent once the entry was finalized */
+    pub fn is_kept_by(&self, entry: &[u8], inclusion: &InclusionProof) -> bool {
+        inclusion.entry_id == self.entry_id
+            && leaf_hash(entry) == self.leaf_hash
+            && inclusion.proof.tree_size == inclusion.sth.tree_size
+            && inclusion.sth.timestamp_ms <= self.deadline_ms()
+            && verify_inclusion(entry, &inclusion.proof, &inclusion.sth.root_hash)
+    }
+
+    fn signed_bytes(chain_id: &ChainId, entry_id: &EntryId, leaf_hash: &Sha256Hash, timestamp_ms: u64, max_merge_delay_ms: u64) -> Vec<u8> {
+        let mut bytes = PROMISE_CONTEXT.to_vec();
+        bytes.extend_from_slice(&chain_id.0);
+        bytes.extend_from_slice(&entry_id.0.to_be_bytes());
+        bytes.extend_from_slice(leaf_hash);
+        bytes.extend_from_slice(&timestamp_ms.to_be_bytes());
+        bytes.extend_from_slice(&max_merge_delay_ms.to_be_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::merkle::MerkleTree;
+
+    #[test]
+    fn test_promise_kept_by_proof() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let id = EntryId::generate();
+        let chain_id = ChainId::default();
+        let promise = InclusionPromise::sign(id, b"entry", Duration::from_secs(60), String::from("h1"), &keypair, &chain_id);
+        assert!(promise.verify(&keypair.public(), &chain_id));
+        assert!(!promise.verify(&keypair.public(), &ChainId::new("test")));
+        let mut forged = promise.clone();
+        forged.max_merge_delay_ms *= 2;
+        assert!(!forged.verify(&keypair.public(), &chain_id));
+
+        let mut tree = MerkleTree::new();
+        tree.push(b"other");
+        tree.push(b"entry");
+        let sth = SignedTreeHead::sign(&tree, String::from("h1"), &keypair, &chain_id);
+        let inclusion = InclusionProof { entry_id: id, proof: tree.prove_inclusion(b"entry").unwrap(), sth };
+        assert!(promise.is_kept_by(b"entry", &inclusion));
+        assert!(!promise.is_kept_by(b"other", &inclusion));
+
+        // A proof that only arrives after the deadline doesn't keep the promise
+        let mut late = inclusion.clone();
+        late.sth.timestamp_ms = promise.deadline_ms() + 1;
+        assert!(!promise.is_kept_by(b"entry", &late));
+    }
+}
@@ -0,0 +1,223 @@
+/* Versioned layout of a node's data directory, and migrations between versions.
+   The directory records the schema version it was written with (the "schema" file).
+   On startup, before anything else opens it, a directory from an older release is
+   upgraded by running the migrations from its version up to SCHEMA_VERSION in order.
+   Everything in the directory is copied to a backup first; if any migration fails,
+   the directory is restored from the backup, so the old release can still read it.
+   A directory from a newer release is refused rather than misread.
+   A change to the encoding of blocks or certificates, or to how the stores lay out
+   their keys, bumps SCHEMA_VERSION and adds a migration to MIGRATIONS. */
+
+use crate::blockchain::store::StoreError;
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Layout this release writes (1: sled "chain" database plus "votes" journal)
+pub const SCHEMA_VERSION: u32 = 1;
+const SCHEMA_FILE: &str = "schema";
+const BACKUP_PREFIX: &str = "backup-schema-";
+
+/* Upgrades a data directory from one schema version to the next. */
+pub struct Migration {
+    pub from: u32, // upgrades from this version to from + 1
+    pub description: &'static str,
+    pub run: fn(&Path) -> Result<(), StoreError>,
+}
+
+// One per version bump, in order
+const MIGRATIONS: &[Migration] = &[];
+
+/* What happened to a data directory on startup. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaStatus {
+    Created,                                          // new directory, stamped with SCHEMA_VERSION
+    Current,                                          // already at SCHEMA_VERSION
+    Migrated { from: u32, to: u32, backup: PathBuf }, // upgraded; the old contents are in backup
+}
+
+fn io_error(e: io::Error) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+/* The schema version a data directory was written with (None: nothing written yet).
+Directories from before versioning hold version 1's layout without saying so. */
+pub fn schema_version(dir: &Path) -> Result<Option<u32>, StoreError> {
+    match fs::read_to_string(dir.join(SCHEMA_FILE)) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| StoreError::Corrupt(format!("schema file holds {:?}, not a version", text.trim()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let has_data = fs::read_dir(dir).map_err(io_error)?.next().is_some();
+            Ok(has_data.then_some(1))
+        }
+        Err(e) => Err(io_error(e)),
+    }
+}
+
+// Replaces the schema file in one step, so a crash leaves the old version or the new one
+fn write_schema_version(dir: &Path, version: u32) -> Result<(), StoreError> {
+    let tmp = dir.join(format!("{}.tmp", SCHEMA_FILE));
+    fs::write(&tmp, format!("{}\n", version)).map_err(io_error)?;
+    fs::rename(&tmp, dir.join(SCHEMA_FILE)).map_err(io_error)
+}
+
+fn is_backup(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().starts_with(BACKUP_PREFIX)
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+// Everything in the directory but earlier backups
+fn contents(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !is_backup(&entry.file_name()) {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+fn backup(dir: &Path, backup: &Path) -> io::Result<()> {
+    fs::create_dir(backup)?;
+    for path in contents(dir)? {
+        copy_recursive(&path, &backup.join(path.file_name().expect("directory entries have names")))?;
+    }
+    Ok(())
+}
+
+fn restore(dir: &Path, backup: &Path) -> io::Result<()> {
+    for path in contents(dir)? {
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    for entry in fs::read_dir(backup)? {
+        let entry = entry?;
+        copy_recursive(&entry.path(), &dir.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/* Brings a data directory up to SCHEMA_VERSION. Must run before its stores are opened.
+@param dir: the node's data directory (created if missing) */
+pub fn upgrade_data_dir(dir: &Path) -> Result<SchemaStatus, StoreError> {
+    upgrade_with(dir, SCHEMA_VERSION, MIGRATIONS)
+}
+
+fn upgrade_with(dir: &Path, target: u32, migrations: &[Migration]) -> Result<SchemaStatus, StoreError> {
+    fs::create_dir_all(dir).map_err(io_error)?;
+    let from = match schema_version(dir)? {
+        None => {
+            write_schema_version(dir, target)?;
+            return Ok(SchemaStatus::Created);
+        }
+        Some(version) if version == target => {
+            // Directories from before versioning get their version written down
+            write_schema_version(dir, target)?;
+            return Ok(SchemaStatus::Current);
+        }
+        Some(version) if version > target => {
+            return Err(StoreError::Corrupt(format!(
+                "data directory has schema version {}, but this release only reads up to {}; use a newer release",
+                version, target
+            )))
+        }
+        Some(version) => version,
+    };
+
+    let backup_dir = dir.join(format!("{}{}", BACKUP_PREFIX, from));
+    if backup_dir.exists() {
+        return Err(StoreError::Backend(format!(
+            "{} already exists (left by an earlier upgrade?); move it away to upgrade again",
+            backup_dir.display()
+        )));
+    }
+    backup(dir, &backup_dir).map_err(|e| StoreError::Backend(format!("couldn't back up the data directory: {}", e)))?;
+    info!("Upgrading data directory from schema {} to {} (backup in {})", from, target, backup_dir.display());
+
+    for version in from..target {
+        let result = match migrations.iter().find(|migration| migration.from == version) {
+            Some(migration) => {
+                info!("Schema {} -> {}: {}", version, version + 1, migration.description);
+                (migration.run)(dir).and_then(|()| write_schema_version(dir, version + 1))
+            }
+            None => Err(StoreError::Corrupt(format!("no migration from schema {}", version))),
+        };
+        if let Err(e) = result {
+            warn!("Upgrade from schema {} failed ({}); restoring the data directory", version, e);
+            return match restore(dir, &backup_dir) {
+                Ok(()) => Err(StoreError::Backend(format!("schema upgrade failed and was rolled back: {}", e))),
+                Err(restore_error) => Err(StoreError::Backend(format!(
+                    "schema upgrade failed ({}) and so did restoring the backup ({}); restore {} by hand",
+                    e,
+                    restore_error,
+                    backup_dir.display()
+                ))),
+            };
+        }
+    }
+    Ok(SchemaStatus::Migrated { from, to: target, backup: backup_dir })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stand-ins for future layout changes: one renames the journal, one always fails
+    fn rename_votes(dir: &Path) -> Result<(), StoreError> {
+        fs::rename(dir.join("votes"), dir.join("vote-journal")).map_err(io_error)
+    }
+
+    fn fail(dir: &Path) -> Result<(), StoreError> {
+        fs::write(dir.join("votes"), b"half-migrated").map_err(io_error)?;
+        Err(StoreError::Corrupt(String::from("bad record")))
+    }
+
+    #[test]
+    fn test_upgrade_backs_up_and_rolls_back() {
+        let dir = std::env::temp_dir().join(format!("streamlet-schema-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(upgrade_with(&dir, 1, &[]).unwrap(), SchemaStatus::Created);
+        fs::write(dir.join("votes"), b"journal").unwrap();
+        assert_eq!(upgrade_with(&dir, 1, &[]).unwrap(), SchemaStatus::Current);
+
+        // A failing migration leaves the directory as it was
+        let failing = [
+            Migration { from: 1, description: "rename the journal", run: rename_votes },
+            Migration { from: 2, description: "always fails", run: fail },
+        ];
+        assert!(upgrade_with(&dir, 3, &failing).is_err());
+        assert_eq!(schema_version(&dir).unwrap(), Some(1));
+        assert_eq!(fs::read(dir.join("votes")).unwrap(), b"journal");
+        assert!(!dir.join("vote-journal").exists());
+
+        fs::remove_dir_all(dir.join(format!("{}1", BACKUP_PREFIX))).unwrap();
+        let status = upgrade_with(&dir, 2, &failing[..1]).unwrap();
+        assert!(matches!(status, SchemaStatus::Migrated { from: 1, to: 2, .. }));
+        assert_eq!(fs::read(dir.join("vote-journal")).unwrap(), b"journal");
+        assert_eq!(fs::read(dir.join(format!("{}1/votes", BACKUP_PREFIX))).unwrap(), b"journal");
+
+        // An older release refuses the newer directory
+        assert!(upgrade_with(&dir, 1, &[]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
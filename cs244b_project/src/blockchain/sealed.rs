@@ -0,0 +1,134 @@
+/* Payload encryption for private logs (--payload-secret <secret>). Every member of a
+   private deployment holds the same secret. A node seals each entry's data and content
+   type (ChaCha20-Poly1305, under a key derived from the secret and the chain id) before
+   queueing it, so proposals, stored blocks, exports and snapshots only ever carry the
+   sealed form. What consensus and the log need stays in the clear: the entry's id, the
+   block headers and hashes, and the Merkle tree, which is built over the sealed
+   entries, so monitors and auditors check the log without the secret. Members open
+   entries when they serve them (get-entry, get-entries); leaf inputs stay sealed.
+   Sealing is deterministic (the nonce is derived from the entry), so validators that
+   seal the same submission get the same bytes and drop it from their mempools once
+   another leader's block holds it. Validator announcements aren't sealed: every node
+   must read them.
+   Sealed data: SEALED_MAGIC, the key id (8 bytes), the nonce (12), then the ciphertext
+   of (data, content type) in bincode, authenticated along with the entry's id. */
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+
+use crate::blockchain::{ChainId, ContentType, LogEntry};
+use crate::utils::crypto::*;
+
+const SEALED_MAGIC: &[u8] = b"streamlet sealed v1\0";
+const KEY_INFO: &[u8] = b"streamlet payload key v1";
+
+pub struct PayloadKey {
+    key_id: [u8; 8],
+    key: Key,
+    nonce_key: [u8; 32], // derives each entry's nonce
+}
+
+impl PayloadKey {
+    /* @param secret: the deployment's payload secret, shared by its members
+    @param chain_id: the deployment's chain id */
+    pub fn derive(secret: &[u8], chain_id: &ChainId) -> Self {
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>::new(Some(&chain_id.0), secret)
+            .expand(KEY_INFO, &mut okm)
+            .expect("64 bytes is a valid HKDF output length");
+        let digest: Sha256Hash = Sha256::digest(&okm[..32]).into();
+        PayloadKey {
+            key_id: digest[..8].try_into().expect("8-byte slice"),
+            key: *Key::from_slice(&okm[..32]),
+            nonce_key: okm[32..].try_into().expect("32-byte slice"),
+        }
+    }
+
+    pub fn is_sealed(entry: &LogEntry) -> bool {
+        entry.data.starts_with(SEALED_MAGIC)
+    }
+
+    /* The entry with its data and content type sealed, and its id kept. */
+    pub fn seal(&self, entry: &LogEntry) -> LogEntry {
+        let plaintext = bincode::serialize(&(&entry.data, &entry.content_type)).expect("Failed serialization.");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.nonce_key).expect("HMAC takes keys of any length");
+        mac.update(&entry.id.0.to_be_bytes());
+        mac.update(&plaintext);
+        let nonce: [u8; 12] = mac.finalize().into_bytes()[..12].try_into().expect("12-byte slice");
+        let ciphertext = ChaCha20Poly1305::new(&self.key)
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: &self.aad(entry) })
+            .expect("ChaCha20-Poly1305 encryption doesn't fail");
+        let mut data = SEALED_MAGIC.to_vec();
+        data.extend_from_slice(&self.key_id);
+        data.extend_from_slice(&nonce);
+        data.extend(ciphertext);
+        LogEntry { id: entry.id, data, content_type: None }
+    }
+
+    /* The entry as it was submitted; None if it isn't sealed, or not under this key, or
+    was tampered with (or moved to another id). */
+    pub fn open(&self, entry: &LogEntry) -> Option<LogEntry> {
+        let sealed = entry.data.strip_prefix(SEALED_MAGIC)?;
+        if sealed.len() < 20 || sealed[..8] != self.key_id {
+            return None;
+        }
+        let plaintext = ChaCha20Poly1305::new(&self.key)
+            .decrypt(Nonce::from_slice(&sealed[8..20]), Payload { msg: &sealed[20..], aad: &self.aad(entry) })
+            .ok()?;
+        let (data, content_type): (Vec<u8>, Option<ContentType>) = bincode::deserialize(&plaintext).ok()?;
+        Some(LogEntry { id: entry.id, data, content_type })
+    }
+
+    fn aad(&self, entry: &LogEntry) -> Vec<u8> {
+        let mut aad = self.key_id.to_vec();
+        aad.extend_from_slice(&entry.id.0.to_be_bytes());
+        aad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::EntryId;
+
+    #[test]
+    fn test_sealed_entries_open_only_for_members() {
+        let chain_id = ChainId::new("private");
+        let key = PayloadKey::derive(b"members only", &chain_id);
+        let entry = LogEntry { content_type: "text/plain".parse().ok(), ..LogEntry::new(b"confidential".to_vec()) };
+        let sealed = key.seal(&entry);
+        assert!(PayloadKey::is_sealed(&sealed) && !PayloadKey::is_sealed(&entry));
+        assert_eq!(sealed.id, entry.id);
+        assert_eq!(sealed.content_type, None);
+        assert!(!sealed.data.windows(12).any(|window| window == b"confidential"));
+        assert_eq!(key.open(&sealed), Some(entry.clone()));
+        // The same entry seals the same way at every member
+        assert_eq!(PayloadKey::derive(b"members only", &chain_id).seal(&entry), sealed);
+
+        assert_eq!(PayloadKey::derive(b"guess", &chain_id).open(&sealed), None);
+        assert_eq!(PayloadKey::derive(b"members only", &ChainId::new("other")).open(&sealed), None);
+        assert_eq!(key.open(&LogEntry { id: EntryId::from_parts(1, 2), ..sealed.clone() }), None);
+        let mut tampered = sealed;
+        *tampered.data.last_mut().unwrap() ^= 1;
+        assert_eq!(key.open(&tampered), None);
+        assert_eq!(key.open(&entry), None);
+
+        // A member node queues entries sealed, and serves them opened
+        let mut node = crate::StreamletInstance::builder().name("m1").deployment("private").build().unwrap();
+        node.set_payload_secret(b"members only");
+        let request = crate::http_api::ApiRequest::AddEntry { data: b"confidential".to_vec(), callback: None, content_type: None, id: None, submission: None };
+        node.answer_api_request(request).unwrap();
+        let queued = node.pending_transactions.pop().unwrap();
+        assert!(PayloadKey::is_sealed(&LogEntry::deserialize(&queued).unwrap()));
+        for (epoch, data) in [(1, queued.clone()), (2, Vec::new())] {
+            let parent = node.blockchain_manager.head().0.clone();
+            let block = crate::blockchain::Block::new(epoch, parent.hash, data, parent.height + 1, 0);
+            node.blockchain_manager.add_notarized_block(block, Vec::new());
+        }
+        let listed = node.answer_api_request(crate::http_api::ApiRequest::GetEntries { start: 0, end: 0 }).unwrap();
+        assert_eq!(listed["entries"][0]["data"], hex::encode(b"confidential"));
+        assert_eq!(listed["entries"][0]["leaf_input"], hex::encode(&queued));
+    }
+}
@@ -0,0 +1,118 @@
+/* Chain snapshots, for nodes far behind the network (see request_chain_sync in lib.rs).
+   Rather than catching up on notarized segments of CHAIN_SYNC_MAX_BLOCKS blocks, one per
+   epoch, a node that is missing many blocks asks peers for the whole finalized chain at
+   once, along with the Merkle state of the log it holds. The snapshot carries its own
+   proof of finality: Streamlet finalizes a block once it, its parent and a child are
+   notarized in consecutive epochs, so the snapshot includes that child (see
+   BlockchainManager::snapshot), and only those three certificates are checked against
+   the validators the node knows. Everything below the tip is bound to it by parent
+   hash. The Merkle state is checked by rebuilding the tree from the chain's entries.
+   A snapshot travels as one message, so it must fit in MAX_MESSAGE_LEN (16 MiB with
+   chunking); peers whose chain is larger answer with a segment instead. */
+
+use crate::blockchain::*;
+use crate::Sha256Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainSnapshot {
+    pub chain: LocalChain,     // finalized, from genesis
+    pub finality: SignedBlock, // a notarized child of the tip, from the epoch after it
+    pub tree_size: u64,        // Merkle tree over the chain's entries
+    pub root_hash: Sha256Hash,
+}
+
+impl ChainSnapshot {
+    /* Checks the snapshot holds a finalized chain of this deployment (see the top of
+    this file).
+    @param public_keys: the validators we know
+    @param scheme: the deployment's signature scheme
+    @param quorum: votes a certificate needs
+    @param chain_id: this deployment's chain id */
+    pub fn verify(
+        &self,
+        public_keys: &HashMap<String, PublicKey>,
+        scheme: SignatureScheme,
+        quorum: usize,
+        chain_id: &ChainId,
+    ) -> Result<(), String> {
+        let genesis = &LocalChain::new().blocks[0].block;
+        if self.chain.blocks.first().map(|signed| signed.block.hash) != Some(genesis.hash) {
+            return Err(String::from("snapshot doesn't start at the genesis block"));
+        }
+        if self.chain.length() < 2 {
+            return Err(String::from("snapshot holds no finalized blocks"));
+        }
+        if !BlockchainManager::is_chain_valid(&self.chain) {
+            return Err(String::from("snapshot's blocks don't link up"));
+        }
+        let tip = &self.chain.blocks[self.chain.length() - 1];
+        let parent = &self.chain.blocks[self.chain.length() - 2];
+        if !<LocalChain as Chain>::validate_block(&self.finality.block, &tip.block)
+            || self.finality.block.epoch != tip.block.epoch + 1
+            || tip.block.epoch != parent.block.epoch + 1
+        {
+            return Err(String::from("snapshot's tip isn't shown to be final"));
+        }
+        // The genesis block has no certificate; it needs none
+        for SignedBlock { block, cert } in [parent, tip, &self.finality].into_iter().filter(|signed| signed.block.height > 0) {
+            cert.verify(block, public_keys, scheme, quorum, chain_id)
+                .map_err(|e| format!("block at height {}: {}", block.height, e))?;
+        }
+        let mut tree = MerkleTree::new();
+        for SignedBlock { block, .. } in self.chain.blocks.iter().skip(1).filter(|signed| !signed.block.data.is_empty()) {
+            tree.push(&block.data);
+        }
+        if tree.size() != self.tree_size || tree.root() != self.root_hash {
+            return Err(String::from("snapshot's Merkle state doesn't match its entries"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_verifies_and_is_adopted() {
+        let chain_id = ChainId::default();
+        let keypairs: Vec<Keypair> = (0..3).map(|_| Keypair::generate(SignatureScheme::Ed25519)).collect();
+        let public_keys: HashMap<String, PublicKey> = keypairs.iter().enumerate().map(|(i, keypair)| (format!("v{}", i), keypair.public())).collect();
+        let mut ahead = BlockchainManager::new();
+        let mut parent_hash = ahead.head().0.hash;
+        for (height, epoch) in [(1, 1), (2, 3), (3, 4), (4, 5)] {
+            let block = Block::new(epoch, parent_hash, vec![height as u8], height, 0);
+            let bytes = NotarizationCert::signed_bytes(&block, &chain_id);
+            let votes = keypairs.iter().take(2).map(|keypair| keypair.sign(&bytes)).collect();
+            parent_hash = block.hash;
+            assert!(ahead.add_notarized_block(block, votes));
+        }
+        let snapshot = ahead.snapshot().unwrap();
+        assert_eq!(snapshot.chain.length(), 4);
+        assert_eq!(snapshot.finality.block.height, 4);
+        assert!(snapshot.verify(&public_keys, SignatureScheme::Ed25519, 2, &chain_id).is_ok());
+
+        let mut behind = BlockchainManager::new();
+        assert_eq!(behind.adopt_snapshot(snapshot.clone()), Ok(3));
+        assert_eq!(behind.finalized_chain(), ahead.finalized_chain());
+        assert_eq!(behind.head().0.hash, ahead.head().0.hash);
+        assert_eq!(behind.merkle_tree().root(), ahead.merkle_tree().root());
+        assert!(behind.take_newly_finalized().is_empty());
+
+        // A tip whose child is from a later epoch isn't shown to be final
+        let mut gap = snapshot.clone();
+        gap.finality.block = Block::new(7, gap.finality.block.parent_hash, vec![4], 4, 0);
+        assert!(gap.verify(&public_keys, SignatureScheme::Ed25519, 2, &chain_id).is_err());
+
+        // Nor is one whose certificates fall short of a quorum
+        let mut short = snapshot.clone();
+        short.finality.cert.signatures.truncate(1);
+        assert!(short.verify(&public_keys, SignatureScheme::Ed25519, 2, &chain_id).is_err());
+
+        let mut forged = snapshot;
+        forged.tree_size += 1;
+        assert!(forged.verify(&public_keys, SignatureScheme::Ed25519, 2, &chain_id).is_err());
+    }
+}
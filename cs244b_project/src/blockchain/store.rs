@@ -0,0 +1,198 @@
+/* Persistent storage for notarized and finalized blocks, so a node's view of the
+   chain survives restarts. Backends implement ChainStore; the sled-backed one is
+   what nodes use when started with a data directory.
+   A pruned node (see BlockchainManager::set_pruning) overwrites finalized blocks below
+   its latest checkpoint with their headers, and keeps the Merkle leaf hash of each
+   entry it drops, so its log's tree can be rebuilt on restart. */
+
+use crate::blockchain::block::SignedBlock;
+use crate::Sha256Hash;
+use bincode::{deserialize, serialize};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Backend(String),
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Backend(e) => write!(f, "storage backend error: {}", e),
+            StoreError::Corrupt(e) => write!(f, "corrupt chain store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sled::Error> for StoreError {
+    fn from(e: sled::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for StoreError {
+    fn from(e: bincode::Error) -> Self {
+        StoreError::Corrupt(e.to_string())
+    }
+}
+
+pub trait ChainStore: Send {
+    /* Persists a notarized block (a no-op if it is already stored). */
+    fn put_block(&mut self, block: &SignedBlock) -> Result<(), StoreError>;
+    /* Forgets a notarized block (e.g. on a fork abandoned by finalization). */
+    fn remove_block(&mut self, height: u64, hash: &Sha256Hash) -> Result<(), StoreError>;
+    /* Records the hash of the newest finalized block. */
+    fn set_finalized_tip(&mut self, hash: &Sha256Hash) -> Result<(), StoreError>;
+    /* All stored notarized blocks, ordered by height. */
+    fn blocks(&self) -> Result<Vec<SignedBlock>, StoreError>;
+    fn finalized_tip(&self) -> Result<Option<Sha256Hash>, StoreError>;
+    /* Makes every write so far durable (e.g. before shutting down). */
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+    /* Replaces a stored finalized block with its header (the block without its data).
+    @param header: the block, data dropped
+    @param leaf: the Merkle leaf hash of the entry it held, if any */
+    fn put_pruned_block(&mut self, _header: &SignedBlock, _leaf: Option<&Sha256Hash>) -> Result<(), StoreError> {
+        Err(StoreError::Backend(String::from("this store can't prune blocks")))
+    }
+    /* Records the latest checkpoint: blocks below this height are headers only. */
+    fn set_checkpoint(&mut self, _height: u64) -> Result<(), StoreError> {
+        Err(StoreError::Backend(String::from("this store can't prune blocks")))
+    }
+    /* The latest checkpoint (0: nothing pruned). */
+    fn checkpoint(&self) -> Result<u64, StoreError> {
+        Ok(0)
+    }
+    /* Leaf hashes of the pruned entries, with the heights of their blocks, ordered by height. */
+    fn pruned_leaves(&self) -> Result<Vec<(u64, Sha256Hash)>, StoreError> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct SledStore {
+    blocks: sled::Tree,
+    meta: sled::Tree,
+    leaves: sled::Tree, // pruned entries' leaf hashes, keyed by big-endian block height
+}
+
+const FINALIZED_TIP_KEY: &[u8] = b"finalized_tip";
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+
+impl SledStore {
+    /* Opens (or creates) a store in the given directory. */
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        SledStore::from_db(sled::open(path)?)
+    }
+
+    /* Uses the chain trees of an already open database. */
+    pub fn from_db(db: sled::Db) -> Result<Self, StoreError> {
+        Ok(Self {
+            blocks: db.open_tree("blocks")?,
+            meta: db.open_tree("meta")?,
+            leaves: db.open_tree("leaves")?,
+        })
+    }
+
+    // Big-endian height first, so iteration order is height order
+    fn block_key(height: u64, hash: &Sha256Hash) -> Vec<u8> {
+        let mut key = height.to_be_bytes().to_vec();
+        key.extend_from_slice(hash);
+        key
+    }
+}
+
+impl ChainStore for SledStore {
+    fn put_block(&mut self, block: &SignedBlock) -> Result<(), StoreError> {
+        let key = SledStore::block_key(block.block.height, &block.block.hash);
+        self.blocks.insert(key, serialize(block)?)?;
+        self.blocks.flush()?;
+        Ok(())
+    }
+
+    fn remove_block(&mut self, height: u64, hash: &Sha256Hash) -> Result<(), StoreError> {
+        self.blocks.remove(SledStore::block_key(height, hash))?;
+        Ok(())
+    }
+
+    fn set_finalized_tip(&mut self, hash: &Sha256Hash) -> Result<(), StoreError> {
+        self.meta.insert(FINALIZED_TIP_KEY, &hash[..])?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    fn blocks(&self) -> Result<Vec<SignedBlock>, StoreError> {
+        self.blocks
+            .iter()
+            .values()
+            .map(|value| Ok(deserialize(&value?)?))
+            .collect()
+    }
+
+    fn finalized_tip(&self) -> Result<Option<Sha256Hash>, StoreError> {
+        match self.meta.get(FINALIZED_TIP_KEY)? {
+            Some(value) => {
+                let hash: Sha256Hash = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| StoreError::Corrupt(String::from("finalized tip is not a hash")))?;
+                Ok(Some(hash))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.blocks.flush()?;
+        self.meta.flush()?;
+        self.leaves.flush()?;
+        Ok(())
+    }
+
+    fn put_pruned_block(&mut self, header: &SignedBlock, leaf: Option<&Sha256Hash>) -> Result<(), StoreError> {
+        // The leaf goes first: a header without it would lose the entry from the tree
+        if let Some(leaf) = leaf {
+            self.leaves.insert(header.block.height.to_be_bytes(), &leaf[..])?;
+        }
+        let key = SledStore::block_key(header.block.height, &header.block.hash);
+        self.blocks.insert(key, serialize(header)?)?;
+        Ok(())
+    }
+
+    fn set_checkpoint(&mut self, height: u64) -> Result<(), StoreError> {
+        self.leaves.flush()?;
+        self.blocks.flush()?;
+        self.meta.insert(CHECKPOINT_KEY, &height.to_be_bytes())?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Result<u64, StoreError> {
+        match self.meta.get(CHECKPOINT_KEY)? {
+            Some(value) => {
+                let height: [u8; 8] = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| StoreError::Corrupt(String::from("checkpoint is not a height")))?;
+                Ok(u64::from_be_bytes(height))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn pruned_leaves(&self) -> Result<Vec<(u64, Sha256Hash)>, StoreError> {
+        self.leaves
+            .iter()
+            .map(|item| {
+                let (key, value) = item?;
+                let height: [u8; 8] = key.as_ref().try_into().map_err(|_| StoreError::Corrupt(String::from("leaf key is not a height")))?;
+                let leaf: Sha256Hash = value.as_ref().try_into().map_err(|_| StoreError::Corrupt(String::from("leaf is not a hash")))?;
+                Ok((u64::from_be_bytes(height), leaf))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,85 @@
+/* Signed Tree Heads: a node's signed commitment to the Merkle tree over the finalized
+   log (size and root), produced each time the finalized chain advances. Monitors and
+   auditors compare STHs across nodes and over time instead of fetching whole chains,
+   and check inclusion proofs against the root. */
+
+use crate::blockchain::chain_id::ChainId;
+use crate::blockchain::merkle::{ConsistencyProof, MerkleTree};
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use crate::utils::clock::unix_time_ms;
+
+// Domain separator, so an STH signature can't be passed off as a vote or vice versa
+const STH_CONTEXT: &[u8] = b"streamlet sth v1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    pub root_hash: Sha256Hash,
+    pub timestamp_ms: u64, // milliseconds since the Unix epoch
+    pub signer: String,    // name of the node that signed it
+    pub signature: Signature,
+}
+
+/* What a node publishes on the STH topic: its new tree head, the key to check it with,
+   and a consistency proof from the previous head it published, so a monitor can check
+   the log only grew in between. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeHeadUpdate {
+    pub sth: SignedTreeHead,
+    pub public_key: PublicKey,
+    pub consistency: ConsistencyProof, // from the previous published size to sth.tree_size
+}
+
+impl SignedTreeHead {
+    /* Signs the current state of a tree.
+    @param tree: Merkle tree over the finalized entries
+    @param signer: this node's name
+    @param keypair: this node's signing key (see Signer)
+    @param chain_id: the deployment's chain id */
+    pub fn sign(tree: &MerkleTree, signer: String, keypair: &dyn Signer, chain_id: &ChainId) -> Self {
+        let timestamp_ms = unix_time_ms();
+        let root_hash = tree.root();
+        let signature = keypair.sign(&SignedTreeHead::signed_bytes(chain_id, tree.size(), &root_hash, timestamp_ms));
+        Self { tree_size: tree.size(), root_hash, timestamp_ms, signer, signature }
+    }
+
+    /* Checks the signature against the signer's public key, for the given deployment. */
+    pub fn verify(&self, public_key: &dyn Verifier, chain_id: &ChainId) -> bool {
+        let bytes = SignedTreeHead::signed_bytes(chain_id, self.tree_size, &self.root_hash, self.timestamp_ms);
+        public_key.verify(&bytes, &self.signature).is_ok()
+    }
+
+    fn signed_bytes(chain_id: &ChainId, tree_size: u64, root_hash: &Sha256Hash, timestamp_ms: u64) -> Vec<u8> {
+        let mut bytes = STH_CONTEXT.to_vec();
+        bytes.extend_from_slice(&chain_id.0);
+        bytes.extend_from_slice(&tree_size.to_be_bytes());
+        bytes.extend_from_slice(&timestamp_ms.to_be_bytes());
+        bytes.extend_from_slice(root_hash);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sth_sign_verify() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let mut tree = MerkleTree::new();
+        tree.push(b"a");
+        tree.push(b"b");
+        let chain_id = ChainId::default();
+        let sth = SignedTreeHead::sign(&tree, String::from("h1"), &keypair, &chain_id);
+        assert_eq!(sth.tree_size, 2);
+        assert_eq!(sth.root_hash, tree.root());
+        assert!(sth.verify(&keypair.public(), &chain_id));
+        assert!(!sth.verify(&keypair.public(), &ChainId::new("test")));
+
+        let mut tampered = sth.clone();
+        tampered.tree_size = 3;
+        assert!(!tampered.verify(&keypair.public(), &chain_id));
+        assert!(!sth.verify(&Keypair::generate(SignatureScheme::default()).public(), &chain_id));
+    }
+}
@@ -0,0 +1,207 @@
+/* Configures a StreamletInstance before it runs, for applications embedding the crate:
+
+       let node = StreamletInstance::builder()
+           .name("validator-1")
+           .validators(4)
+           .epoch_length(Duration::from_secs(5))
+           .key_source(KeySource::Keyfile { path: "node.key".into(), passphrase })
+           .storage_path("data")
+           .build()?;
+
+   Anything not set keeps new()'s default. build() applies the settings in the order the
+   node needs them (the key and deployment before the store, which signs with them), and
+   fails if one can't be applied. Options without a builder method (the HTTP API, NAT
+   traversal, ...) are set on the built node with its set_* methods, before run(). */
+
+use crate::error::StreamletError;
+use crate::genesis::GenesisDocument;
+use crate::quorum::QuorumRule;
+use crate::utils::crypto::{Keypair, SignatureScheme, Signer};
+use crate::{Role, StreamletInstance};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/* Where the node's signing key comes from. */
+pub enum KeySource {
+    Generate, // a fresh keypair, for tests and throwaway nodes
+    Keypair(Keypair),
+    Keyfile { path: PathBuf, passphrase: Vec<u8> }, // encrypted keyfile, created if missing (see keystore)
+    Signer(Box<dyn Signer + Send + Sync>),          // a key held elsewhere (e.g. an HSM)
+}
+
+pub struct StreamletBuilder {
+    name: String,
+    validators: usize,
+    scheme: SignatureScheme,
+    key_source: KeySource,
+    deployment: Option<String>,
+    epoch_length: Option<Duration>,
+    topic: Option<String>,
+    storage_path: Option<PathBuf>,
+    quorum_rule: QuorumRule,
+    role: Role,
+    headless: bool,
+    genesis: Option<GenesisDocument>,
+}
+
+impl Default for StreamletBuilder {
+    fn default() -> Self {
+        StreamletBuilder {
+            name: String::new(),
+            validators: 1,
+            scheme: SignatureScheme::default(),
+            key_source: KeySource::Generate,
+            deployment: None,
+            epoch_length: None,
+            topic: None,
+            storage_path: None,
+            quorum_rule: QuorumRule::default(),
+            role: Role::default(),
+            headless: false,
+            genesis: None,
+        }
+    }
+}
+
+impl StreamletBuilder {
+    /* @param name: identifying "name" of this node */
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /* @param count: number of validators in the deployment, this node included unless
+    it's an observer */
+    pub fn validators(mut self, count: usize) -> Self {
+        self.validators = count.max(1);
+        self
+    }
+
+    /* @param scheme: the deployment's signature scheme (see new_with_scheme) */
+    pub fn scheme(mut self, scheme: SignatureScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn key_source(mut self, source: KeySource) -> Self {
+        self.key_source = source;
+        self
+    }
+
+    /* @param deployment: the deployment's name (see set_deployment) */
+    pub fn deployment(mut self, deployment: &str) -> Self {
+        self.deployment = Some(deployment.to_string());
+        self
+    }
+
+    /* @param epoch_length: duration of one epoch (see set_epoch_length) */
+    pub fn epoch_length(mut self, epoch_length: Duration) -> Self {
+        self.epoch_length = Some(epoch_length);
+        self
+    }
+
+    /* @param topic: gossipsub topic for consensus messages (see set_topic) */
+    pub fn topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_string());
+        self
+    }
+
+    /* @param path: data directory for this node (see open_store) */
+    pub fn storage_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.storage_path = Some(path.into());
+        self
+    }
+
+    pub fn quorum_rule(mut self, rule: QuorumRule) -> Self {
+        self.quorum_rule = rule;
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /* @param headless: whether to run without reading stdin (see set_headless) */
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    /* @param document: the deployment's genesis document (see load_genesis); its
+    scheme, epoch length, validators and chain id replace the ones set here */
+    pub fn genesis(mut self, document: GenesisDocument) -> Self {
+        self.genesis = Some(document);
+        self
+    }
+
+    /* The configured node, ready to run(). Fails if the key doesn't match the scheme,
+    the genesis document doesn't fit the node, or the keyfile or data directory can't
+    be opened. */
+    pub fn build(self) -> Result<StreamletInstance, StreamletError> {
+        let scheme = self.genesis.as_ref().map_or(self.scheme, |genesis| genesis.scheme);
+        let peers = self.validators - usize::from(self.role == Role::Validator);
+        let mut node = StreamletInstance::new_with_scheme(self.name, peers, scheme);
+        match self.key_source {
+            KeySource::Generate => {}
+            KeySource::Keypair(keypair) => node.set_keypair(keypair).map_err(|e| StreamletError::Config(format!("keypair: {}", e)))?,
+            KeySource::Keyfile { path, passphrase } => node
+                .use_keyfile(&path, &passphrase)
+                .map_err(|e| StreamletError::Config(format!("keyfile {}: {}", path.display(), e)))?,
+            KeySource::Signer(signer) => node.set_signer(signer).map_err(|e| StreamletError::Config(format!("signer: {}", e)))?,
+        }
+        if let Some(deployment) = &self.deployment {
+            node.set_deployment(deployment);
+        }
+        if let Some(epoch_length) = self.epoch_length {
+            node.set_epoch_length(epoch_length);
+        }
+        if let Some(topic) = &self.topic {
+            node.set_topic(topic);
+        }
+        node.set_quorum_rule(self.quorum_rule);
+        node.set_role(self.role);
+        node.set_headless(self.headless);
+        if let Some(genesis) = self.genesis {
+            node.load_genesis(genesis)?;
+        }
+        if let Some(path) = &self.storage_path {
+            node.open_store(path).map_err(|e| StreamletError::Config(format!("data directory {}: {}", path.display(), e)))?;
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_applies_settings() {
+        let keypair = Keypair::generate(SignatureScheme::Ed25519);
+        let public_key = keypair.public();
+        let node = StreamletInstance::builder()
+            .name("b1")
+            .validators(4)
+            .key_source(KeySource::Keypair(keypair))
+            .epoch_length(Duration::from_secs(3))
+            .topic("streamlet-test")
+            .quorum_rule(QuorumRule::Majority)
+            .role(Role::Observer)
+            .headless(true)
+            .build()
+            .unwrap();
+        assert_eq!(node.name, "b1");
+        assert_eq!(node.get_public_key(), public_key);
+        assert_eq!(node.epoch_length, Duration::from_secs(3));
+        assert_eq!(node.topic, "streamlet-test");
+        assert_eq!(node.quorum_size(), 3);
+        assert_eq!(node.role, Role::Observer);
+        assert!(node.headless);
+
+        // A key under another scheme is refused
+        let secp = Keypair::generate(SignatureScheme::Secp256k1);
+        let built = StreamletInstance::builder().key_source(KeySource::Keypair(secp)).build();
+        assert!(matches!(built, Err(StreamletError::Config(_))));
+    }
+}
@@ -0,0 +1,160 @@
+/* Proof push to submitters.
+   A submission over the HTTP API may name a callback URL. Once its entry is finalized,
+   the node POSTs the entry's proof bundle there (the entry, its audit path and the signed
+   tree head it is checked against; see json_schema::bundle_json), so the submitter gets
+   a verifiable confirmation without polling. Each delivery carries an HMAC-SHA256 of the body under the node's
+   callback secret (shared with submitters out of band) in the X-Streamlet-Signature
+   header, and is retried with exponential backoff until the receiver answers 2xx.
+   Only plain http:// URLs are supported. */
+
+use hmac::{Hmac, Mac, NewMac};
+use log::{info, warn};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::utils::clock::{sleep, timeout};
+
+// Deliveries tried per entry, and the wait before the first retry (doubled each time)
+const CALLBACK_ATTEMPTS: u32 = 5;
+const CALLBACK_BACKOFF: Duration = Duration::from_secs(1);
+// Longest a single delivery attempt may take
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SIGNATURE_HEADER: &str = "X-Streamlet-Signature";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl std::str::FromStr for CallbackUrl {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("http://").ok_or_else(|| String::from("callback should be an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("bad port in {}", s))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", s));
+        }
+        Ok(Self { host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+impl std::fmt::Display for CallbackUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/* Hex HMAC-SHA256 of a body, as sent in the signature header. */
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// One POST; returns the response's status code
+async fn post(url: &CallbackUrl, body: &[u8], signature: &str) -> Result<u16, String> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await.map_err(|e| e.to_string())?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}: sha256={}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host,
+        body.len(),
+        SIGNATURE_HEADER,
+        signature
+    );
+    stream.write_all(head.as_bytes()).await.map_err(|e| e.to_string())?;
+    stream.write_all(body).await.map_err(|e| e.to_string())?;
+    stream.shutdown().await.map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(|e| e.to_string())?;
+    String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| String::from("malformed HTTP response"))
+}
+
+/* POSTs a body to a callback URL, retrying with exponential backoff until the
+receiver answers 2xx. Returns whether it was delivered.
+@param url: where to deliver
+@param body: the proof bundle, serialized
+@param secret: the node's callback secret, for the signature header */
+pub async fn deliver(url: CallbackUrl, body: Vec<u8>, secret: Vec<u8>) -> bool {
+    deliver_with_backoff(url, body, secret, CALLBACK_BACKOFF).await
+}
+
+// The wait before the first retry is a parameter so tests don't sleep between attempts
+// (the exchange itself is real I/O, so they can't run on paused time)
+async fn deliver_with_backoff(url: CallbackUrl, body: Vec<u8>, secret: Vec<u8>, mut backoff: Duration) -> bool {
+    let signature = sign(&secret, &body);
+    for attempt in 1..=CALLBACK_ATTEMPTS {
+        match timeout(CALLBACK_TIMEOUT, post(&url, &body, &signature)).await {
+            Ok(Ok(status)) if (200..300).contains(&status) => {
+                info!("Delivered proof to {}", url);
+                return true;
+            }
+            Ok(Ok(status)) => warn!("Callback {} answered {} (attempt {})", url, status, attempt),
+            Ok(Err(e)) => warn!("Callback {} failed: {} (attempt {})", url, e, attempt),
+            Err(_) => warn!("Callback {} timed out (attempt {})", url, attempt),
+        }
+        if attempt < CALLBACK_ATTEMPTS {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    warn!("Giving up on callback {}", url);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_callback_urls() {
+        let url: CallbackUrl = "http://example.com:8080/hooks/log".parse().unwrap();
+        assert_eq!(url, CallbackUrl { host: String::from("example.com"), port: 8080, path: String::from("/hooks/log") });
+        assert_eq!("http://example.com".parse::<CallbackUrl>().unwrap().port, 80);
+        assert!("https://example.com/".parse::<CallbackUrl>().is_err());
+        assert!("http://:80/".parse::<CallbackUrl>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed_and_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: CallbackUrl = format!("http://{}/proofs", listener.local_addr().unwrap()).parse().unwrap();
+        let receiver = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            // Fail the first delivery, accept the second
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request).await.unwrap();
+                let reply = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(reply.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+
+        assert!(deliver_with_backoff(url, b"{\"id\":1}".to_vec(), b"secret".to_vec(), Duration::ZERO).await);
+        let requests = receiver.await.unwrap();
+        let (head, body) = requests[1].split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /proofs HTTP/1.1"));
+        assert_eq!(body, "{\"id\":1}");
+        let expected = format!("{}: sha256={}", SIGNATURE_HEADER, sign(b"secret", body.as_bytes()));
+        assert!(head.lines().any(|line| line == expected));
+    }
+}
@@ -0,0 +1,126 @@
+/* Local command channel: a Unix domain socket that accepts the same commands as stdin,
+   one per line, so scripts and tooling can drive a running node without attaching to
+   its terminal. Each command is answered with "ok" once it has been queued; its output
+   goes to the node's log as usual.
+   Authentication is by filesystem permissions: the socket is owner-only (0600), and
+   connections from any user other than the owner (or root) are refused. */
+
+use log::{debug, info, warn};
+use std::io;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[cfg(unix)]
+pub struct ControlSocket {
+    listener: UnixListener,
+    owner: u32, // uid allowed to connect (besides root)
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+    /* Creates the socket, replacing a stale one left by a previous run.
+    @param path: where to create the socket file */
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        let owner = std::fs::metadata(path)?.uid();
+        info!("Accepting commands on {}", path.display());
+        Ok(Self { listener, owner })
+    }
+
+    /* Accepts connections forever, forwarding each command line to the node.
+    @param commands: the node's command queue (shared with stdin) */
+    pub async fn run(self, commands: mpsc::UnboundedSender<String>) {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Control socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            // The socket's mode already keeps other users out; this also covers
+            // connections made before the mode was set
+            match stream.peer_cred() {
+                Ok(cred) if cred.uid() == self.owner || cred.uid() == 0 => {}
+                _ => {
+                    warn!("Refusing control connection from another user");
+                    continue;
+                }
+            }
+            tokio::spawn(serve(stream, commands.clone()));
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve(stream: UnixStream, commands: mpsc::UnboundedSender<String>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        debug!("Control socket command: {}", line);
+        let reply: &[u8] = if commands.send(line).is_ok() { b"ok\n" } else { b"error: node is shutting down\n" };
+        if writer.write_all(reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct ControlSocket;
+
+#[cfg(not(unix))]
+impl ControlSocket {
+    pub fn bind(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets need a Unix platform"))
+    }
+
+    pub async fn run(self, _commands: mpsc::UnboundedSender<String>) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commands_are_forwarded() {
+        let path = std::env::temp_dir().join(format!("streamlet-control-test-{}", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(socket.run(sender));
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"fc\n\nsync\n").await.unwrap();
+        let mut replies = BufReader::new(reader).lines();
+        assert_eq!(replies.next_line().await.unwrap(), Some(String::from("ok")));
+        assert_eq!(replies.next_line().await.unwrap(), Some(String::from("ok")));
+        assert_eq!(receiver.recv().await, Some(String::from("fc")));
+        assert_eq!(receiver.recv().await, Some(String::from("sync")));
+
+        // A restarted node replaces the stale socket
+        assert!(ControlSocket::bind(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+/* Duplicate suppression: each distinct message is handled once.
+   Gossipsub drops repeats of one publication, but the same message still reaches us
+   more than once: on several topics we subscribe to, republished unchanged by a node
+   that received it (an echoed vote that adds no signature), or once over gossip and
+   once as a direct response. Handling a message means checking every signature on
+   it, so repeats cost as much as the first copy and teach nothing. The node remembers
+   the digests of the messages it handled most recently and drops exact repeats before
+   deserializing them.
+   Only identical bytes count as a repeat: an echoed vote carrying more signatures than
+   the last copy is new, and is handled. Messages the node takes up again itself
+   (released from quarantine, or deferred over a peer's budget) aren't checked. */
+
+use crate::utils::crypto::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+// Messages whose digests we remember; a few epochs of traffic for a few dozen validators
+pub const DEFAULT_SEEN_MESSAGES: usize = 8192;
+
+#[derive(Debug)]
+pub struct SeenMessages {
+    capacity: usize,
+    seen: HashMap<[u8; 32], u64>, // digest, and when it was last seen
+    recency: BTreeMap<u64, [u8; 32]>,
+    clock: u64,
+    duplicates: u64,
+}
+
+impl Default for SeenMessages {
+    fn default() -> Self {
+        SeenMessages::new(DEFAULT_SEEN_MESSAGES)
+    }
+}
+
+impl SeenMessages {
+    /* @param capacity: messages to remember (0: remember none, so nothing is dropped) */
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, seen: HashMap::new(), recency: BTreeMap::new(), clock: 0, duplicates: 0 }
+    }
+
+    /* Remembers a message. Returns false if it is a repeat of one remembered already.
+    @param message: the message as received */
+    pub fn first_sighting(&mut self, message: &[u8]) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        let digest: [u8; 32] = Sha256::digest(message).into();
+        self.clock += 1;
+        if let Some(last_seen) = self.seen.get_mut(&digest) {
+            self.recency.remove(last_seen);
+            *last_seen = self.clock;
+            self.recency.insert(self.clock, digest);
+            self.duplicates += 1;
+            return false;
+        }
+        while self.seen.len() >= self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.seen.remove(&oldest),
+                None => break,
+            };
+        }
+        self.seen.insert(digest, self.clock);
+        self.recency.insert(self.clock, digest);
+        true
+    }
+
+    /* Repeats dropped so far. */
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_dropped_until_forgotten() {
+        let mut seen = SeenMessages::new(2);
+        assert!(seen.first_sighting(b"vote"));
+        assert!(!seen.first_sighting(b"vote"));
+        assert!(seen.first_sighting(b"vote with one more signature"));
+        // Seeing "vote" again made it the most recent, so the other one goes first
+        assert!(!seen.first_sighting(b"vote"));
+        assert!(seen.first_sighting(b"proposal"));
+        assert!(!seen.first_sighting(b"vote"));
+        assert!(seen.first_sighting(b"vote with one more signature"));
+        assert_eq!(seen.duplicates(), 3);
+
+        let mut disabled = SeenMessages::new(0);
+        assert!(disabled.first_sighting(b"vote") && disabled.first_sighting(b"vote"));
+    }
+}
@@ -0,0 +1,171 @@
+/* Epoch clock synchronization. Each node starts its epoch clock when its peer discovery
+   ends, so nodes that finished discovery at different times (or joined a running
+   deployment) count epochs from different starts, and clocks drift apart over time.
+   To line them up, validators report where they are in their epoch clock: the epoch
+   and how far into it they are. They send a report when discovery ends, asking for
+   replies, and every EPOCH_SYNC_INTERVAL epochs after that. Reports are sealed in an
+   envelope (see envelope), so only a validator can report, and only for itself.
+   A node turns each report into an offset: how far ahead of its own clock the
+   reporter's is, measured when the report arrives, on the node's own clock. Wall
+   clocks only serve to drop stale reports. Once it holds offsets from enough validators
+   (a quorum, counting itself), the node moves its clock by their median, if that is
+   more than EPOCH_SYNC_TOLERANCE off. The median lies between honest validators'
+   offsets however the others report. Clocks move forward by skipping to the epoch the
+   median is in, or back by at most one epoch, by lengthening the current one: an
+   epoch a node has voted in never starts again. Observers follow validators' reports
+   without counting their own clock.
+   Reports don't account for the time they spend in transit, so nodes stay in line to
+   within about a gossip delay, plus EPOCH_SYNC_TOLERANCE. */
+
+use crate::utils::clock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Epochs between a validator's reports
+pub const EPOCH_SYNC_INTERVAL: u64 = 10;
+// Offsets no larger than this are left alone
+pub const EPOCH_SYNC_TOLERANCE: Duration = Duration::from_millis(500);
+
+/* Where a node is in its epoch clock, as it reports it. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpochPosition {
+    pub epoch: u64,
+    pub elapsed_ms: u64,    // time since the epoch started
+    pub sent_ms: u64,       // when it was sent (clock::unix_time_ms), to drop stale reports
+    pub wants_reply: bool,  // the sender just finished discovery and has no offsets yet
+}
+
+impl EpochPosition {
+    /* Our position, now.
+    @param epoch: the current epoch
+    @param started: when it started
+    @param wants_reply: whether to ask validators for theirs */
+    pub fn now(epoch: u64, started: Instant, wants_reply: bool) -> Self {
+        EpochPosition {
+            epoch,
+            elapsed_ms: clock::now().saturating_duration_since(started).as_millis() as u64,
+            sent_ms: clock::unix_time_ms(),
+            wants_reply,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EpochSync {
+    offsets: HashMap<String, (i64, u64)>, // by validator: ms ahead of our clock, and when we learned it
+}
+
+impl EpochSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Records a validator's report. Returns false if it is too old (or from too far in
+    the future) to go by.
+    @param sender: the validator
+    @param reported: its position
+    @param own: our position, now
+    @param epoch_length: the current epoch length
+    @param now_ms: the current time */
+    pub fn record(&mut self, sender: &str, reported: &EpochPosition, own: &EpochPosition, epoch_length: Duration, now_ms: u64) -> bool {
+        let age_ms = now_ms.abs_diff(reported.sent_ms);
+        if age_ms > epoch_length.as_millis() as u64 {
+            return false;
+        }
+        let epoch_ms = epoch_length.as_millis() as i64;
+        let offset = (reported.epoch as i64 - own.epoch as i64) * epoch_ms + reported.elapsed_ms as i64 - own.elapsed_ms as i64;
+        self.offsets.insert(sender.to_string(), (offset, now_ms));
+        true
+    }
+
+    /* How far to move our clock, in ms (forward if positive), once enough validators
+    reported; None while too few have, or if we are in line already. Forgets the
+    offsets when it returns a move: they are relative to the clock it moves.
+    @param needed: offsets needed, counting ours if we count
+    @param count_own: whether our own clock counts (we are a validator)
+    @param max_age_ms: how long an offset is good for
+    @param now_ms: the current time */
+    pub fn correction(&mut self, needed: usize, count_own: bool, max_age_ms: u64, now_ms: u64) -> Option<i64> {
+        self.offsets.retain(|_, (_, learned_ms)| now_ms.saturating_sub(*learned_ms) <= max_age_ms);
+        let mut offsets: Vec<i64> = self.offsets.values().map(|(offset, _)| *offset).collect();
+        if count_own {
+            offsets.push(0);
+        }
+        if offsets.is_empty() || offsets.len() < needed {
+            return None;
+        }
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        if median.unsigned_abs() <= EPOCH_SYNC_TOLERANCE.as_millis() as u64 {
+            return None;
+        }
+        self.offsets.clear();
+        Some(median)
+    }
+}
+
+/* Moves an epoch clock. Returns the epoch it is in afterwards, and when that started.
+Forward, it skips the epochs the move passes; back, it only lengthens the current epoch,
+by at most its length.
+@param epoch: the current epoch
+@param started: when it started
+@param shift_ms: how far to move (forward if positive)
+@param length_at: the length of each epoch */
+pub fn shift_clock(epoch: u64, started: Instant, shift_ms: i64, length_at: impl Fn(u64) -> Duration) -> (u64, Instant) {
+    let now = clock::now();
+    if shift_ms < 0 {
+        let back = Duration::from_millis(shift_ms.unsigned_abs()).min(length_at(epoch));
+        return (epoch, started + back);
+    }
+    let mut started = started.checked_sub(Duration::from_millis(shift_ms as u64)).unwrap_or(started);
+    let mut epoch = epoch;
+    while started + length_at(epoch) <= now {
+        started += length_at(epoch);
+        epoch += 1;
+    }
+    (epoch, started)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clocks_move_to_the_median_report() {
+        let second = Duration::from_secs(1);
+        let length = Duration::from_secs(10);
+        let position = |epoch, elapsed_ms| EpochPosition { epoch, elapsed_ms, sent_ms: 1_000_000, wants_reply: false };
+        let own = position(5, 2000);
+
+        // One validator is 3.5 epochs ahead, one is 4 s ahead, and one (say, faulty) far behind
+        let mut sync = EpochSync::new();
+        assert!(sync.record("v1", &position(8, 7000), &own, length, 1_000_100));
+        assert!(sync.record("v2", &position(5, 6000), &own, length, 1_000_100));
+        assert_eq!(sync.correction(4, true, 60_000, 1_000_100), None);
+        assert!(sync.record("v3", &position(1, 0), &own, length, 1_000_100));
+        // Offsets: -42 s, 0, 4 s, 35 s; the median is the slower honest validator's
+        assert_eq!(sync.correction(4, true, 60_000, 1_000_100), Some(4000));
+        assert_eq!(sync.correction(1, true, 60_000, 1_000_100), None);
+
+        // Stale reports don't count, and offsets within the tolerance are left alone
+        assert!(!sync.record("v1", &position(8, 7000), &own, length, 1_000_000 + 11_000));
+        assert!(sync.record("v1", &position(5, 2300), &own, length, 1_000_100));
+        assert_eq!(sync.correction(2, true, 60_000, 1_000_100), None);
+        // An observer doesn't count its own clock; old offsets expire
+        assert_eq!(sync.correction(1, false, 60_000, 1_000_100), None);
+        assert!(sync.record("v2", &position(5, 6000), &own, length, 1_000_100));
+        assert_eq!(sync.correction(2, false, 60_000, 1_000_100), Some(4000));
+        assert!(sync.record("v2", &position(5, 6000), &own, length, 1_000_100));
+        assert_eq!(sync.correction(1, false, 60_000, 2_000_000), None);
+
+        // Moving forward skips epochs; moving back only lengthens the current one
+        tokio::time::advance(Duration::from_secs(100)).await;
+        let started = clock::now() - 2 * second;
+        assert_eq!(shift_clock(5, started, 4000, |_| length), (5, started - 4 * second));
+        assert_eq!(shift_clock(5, started, 35_000, |_| length), (8, started - 5 * second));
+        assert_eq!(shift_clock(5, started, -4000, |_| length), (5, started + 4 * second));
+        assert_eq!(shift_clock(5, started, -42_000, |_| length), (5, started + length));
+    }
+}
@@ -0,0 +1,67 @@
+/* Errors a node recovers from, or hands to its caller, instead of panicking.
+   A message the network refuses, one that can't be decoded or signed, or a local
+   resource that fails (stdin, a socket, a directory) costs the node that one operation:
+   it logs the error and carries on. Only errors that leave run() nothing to run on
+   (it can't listen, or the network stack is gone) end it, and are returned from it. */
+
+use crate::messages::WireError;
+use crate::utils::crypto::CryptoError;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamletError {
+    Publish { topic: String, reason: String }, // gossipsub refused it (e.g. no peers on the topic yet)
+    MessageTooLarge(usize),                    // bytes, over the largest message sent (see chunking)
+    Malformed(WireError),
+    Serialization(String),
+    Signing(CryptoError),
+    AlreadySigned, // the message already carries our signature
+    Io(String),
+    ChannelClosed(&'static str), // which channel
+    Config(String),              // a setting that can't be applied (see builder)
+    Import(String),              // a chain export that can't be loaded (see blockchain::export)
+}
+
+impl fmt::Display for StreamletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamletError::Publish { topic, reason } => write!(f, "couldn't publish on {}: {}", topic, reason),
+            StreamletError::MessageTooLarge(len) => write!(f, "message of {} bytes is too large to send", len),
+            StreamletError::Malformed(e) => write!(f, "{}", e),
+            StreamletError::Serialization(e) => write!(f, "serialization failed: {}", e),
+            StreamletError::Signing(e) => write!(f, "couldn't sign: {}", e),
+            StreamletError::AlreadySigned => write!(f, "message is already signed by us"),
+            StreamletError::Io(e) => write!(f, "{}", e),
+            StreamletError::ChannelClosed(channel) => write!(f, "{} channel closed", channel),
+            StreamletError::Config(e) => write!(f, "configuration error: {}", e),
+            StreamletError::Import(e) => write!(f, "can't import chain: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StreamletError {}
+
+impl From<WireError> for StreamletError {
+    fn from(e: WireError) -> Self {
+        StreamletError::Malformed(e)
+    }
+}
+
+impl From<bincode::Error> for StreamletError {
+    fn from(e: bincode::Error) -> Self {
+        StreamletError::Serialization(e.to_string())
+    }
+}
+
+impl From<CryptoError> for StreamletError {
+    fn from(e: CryptoError) -> Self {
+        StreamletError::Signing(e)
+    }
+}
+
+impl From<io::Error> for StreamletError {
+    fn from(e: io::Error) -> Self {
+        StreamletError::Io(e.to_string())
+    }
+}
@@ -0,0 +1,200 @@
+/* Genesis documents: a deployment's founding configuration, written once and handed to
+   every node (--genesis <file>), so nodes can't start out disagreeing on it. A document
+   names the deployment, its signature scheme and epoch length, and each validator with
+   its id and public key:
+
+       {"schema_version": 1, "deployment": "production", "scheme": "ed25519",
+        "epoch_length_ms": 10000,
+        "validators": {"v1": {"id": 0, "public_key": "<hex>"}, ...}}
+
+   Keys are hex of their bincode encoding, as everywhere in json_schema (print a node's
+   with --print-public-key). The document's hash is bound into the chain id, along with
+   the genesis block's (see ChainId::with_genesis), so everything a node signs commits
+   to the whole document: a node loaded with a different one, even one differing only
+   in epoch length, has a different chain id, and its votes, certificates and tree heads
+   don't verify at the others. Nodes also ignore the advertisements of validators the
+   document doesn't list, or that advertise another key, and refuse to start if their
+   own key isn't the one the document gives them. */
+
+use crate::blockchain::ChainId;
+use crate::json_schema;
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+const GENESIS_CONTEXT: &[u8] = b"streamlet genesis v1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenesisValidator {
+    pub id: u32,
+    pub public_key: PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenesisDocument {
+    pub deployment: String, // the deployment's name (see ChainId)
+    pub scheme: SignatureScheme,
+    pub epoch_length_ms: u64,
+    pub validators: BTreeMap<String, GenesisValidator>, // by name
+}
+
+impl GenesisDocument {
+    /* Reads a document in the JSON form above, and checks it (see check).
+    @param bytes: the file's contents */
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_json::from_slice(bytes).map_err(|e| format!("not JSON: {}", e))?;
+        json_schema::check_version(&value)?;
+        let field = |name: &str| value.get(name).ok_or_else(|| format!("'{}' is missing", name));
+        let deployment = field("deployment")?.as_str().ok_or("'deployment' is not a string")?.to_string();
+        let scheme = field("scheme")?
+            .as_str()
+            .ok_or("'scheme' is not a string")?
+            .parse::<SignatureScheme>()
+            .map_err(|e| e.to_string())?;
+        let epoch_length_ms = field("epoch_length_ms")?.as_u64().ok_or("'epoch_length_ms' is not a number")?;
+        let validators = field("validators")?
+            .as_object()
+            .ok_or("'validators' is not an object")?
+            .iter()
+            .map(|(name, validator)| {
+                let id = validator["id"].as_u64().and_then(|id| u32::try_from(id).ok()).ok_or_else(|| format!("{}'s id is missing or not a number", name))?;
+                let public_key = validator["public_key"]
+                    .as_str()
+                    .and_then(|key| hex::decode(key).ok())
+                    .and_then(|bytes| bincode::deserialize(&bytes).ok())
+                    .ok_or_else(|| format!("{}'s public key is missing or not valid", name))?;
+                Ok((name.clone(), GenesisValidator { id, public_key }))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        let document = GenesisDocument { deployment, scheme, epoch_length_ms, validators };
+        document.check()?;
+        Ok(document)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let validators: serde_json::Map<String, Value> = self
+            .validators
+            .iter()
+            .map(|(name, validator)| {
+                let public_key = hex::encode(bincode::serialize(&validator.public_key).unwrap_or_default());
+                (name.clone(), json!({ "id": validator.id, "public_key": public_key }))
+            })
+            .collect();
+        json!({
+            json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION,
+            "deployment": self.deployment,
+            "scheme": self.scheme.to_string(),
+            "epoch_length_ms": self.epoch_length_ms,
+            "validators": validators,
+        })
+    }
+
+    /* Checks the document is one a deployment can run on: at least one validator, ids
+    that tell them apart, keys under the document's scheme, and epochs that take time. */
+    pub fn check(&self) -> Result<(), String> {
+        if self.validators.is_empty() {
+            return Err(String::from("genesis document names no validators"));
+        }
+        if self.epoch_length_ms == 0 {
+            return Err(String::from("epoch length is zero"));
+        }
+        let mut ids = HashSet::new();
+        for (name, validator) in &self.validators {
+            if !ids.insert(validator.id) {
+                return Err(format!("{} has the id of another validator ({})", name, validator.id));
+            }
+            if validator.public_key.scheme() != self.scheme {
+                return Err(format!("{}'s key is {}, but the deployment uses {}", name, validator.public_key.scheme(), self.scheme));
+            }
+        }
+        Ok(())
+    }
+
+    /* Hash of the document, the same on every node that loaded it. */
+    pub fn hash(&self) -> Sha256Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(GENESIS_CONTEXT);
+        hasher.update(bincode::serialize(self).expect("Failed serialization."));
+        hasher.finalize().into()
+    }
+
+    /* The deployment's chain id, which commits to the whole document. */
+    pub fn chain_id(&self) -> ChainId {
+        ChainId::with_genesis(&self.deployment, &self.hash())
+    }
+
+    pub fn epoch_length(&self) -> Duration {
+        Duration::from_millis(self.epoch_length_ms)
+    }
+
+    pub fn public_keys(&self) -> HashMap<String, PublicKey> {
+        self.validators.iter().map(|(name, validator)| (name.clone(), validator.public_key)).collect()
+    }
+
+    /* Why a peer's advertisement doesn't fit the document, if it doesn't.
+    @param name: the advertised name
+    @param public_key: the advertised key */
+    pub fn mismatch(&self, name: &str, public_key: &PublicKey) -> Option<String> {
+        match self.validators.get(name) {
+            None => Some(String::from("not a validator in the genesis document")),
+            Some(validator) if validator.public_key != *public_key => Some(String::from("its key isn't the one in the genesis document")),
+            Some(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genesis_document_round_trips_and_binds_chain_id() {
+        let mut keypairs: Vec<Keypair> = (0..3).map(|_| Keypair::generate(SignatureScheme::Ed25519)).collect();
+        let document = GenesisDocument {
+            deployment: String::from("production"),
+            scheme: SignatureScheme::Ed25519,
+            epoch_length_ms: 5000,
+            validators: keypairs
+                .iter()
+                .enumerate()
+                .map(|(i, keypair)| (format!("v{}", i), GenesisValidator { id: i as u32, public_key: keypair.public() }))
+                .collect(),
+        };
+        let decoded = GenesisDocument::from_json(document.to_json().to_string().as_bytes()).unwrap();
+        assert_eq!(decoded, document);
+        assert_eq!(decoded.chain_id(), document.chain_id());
+        assert_ne!(document.chain_id(), ChainId::new("production"));
+
+        // Any difference in configuration is a different deployment
+        let mut slower = document.clone();
+        slower.epoch_length_ms = 10000;
+        assert_ne!(slower.chain_id(), document.chain_id());
+
+        assert_eq!(document.mismatch("v1", &keypairs[1].public()), None);
+        assert!(document.mismatch("v1", &keypairs[2].public()).is_some());
+        assert!(document.mismatch("v9", &keypairs[1].public()).is_some());
+
+        let mut clash = document.clone();
+        clash.validators.get_mut("v2").unwrap().id = 0;
+        assert!(clash.check().unwrap_err().contains("id"));
+        let mut other_scheme = document.clone();
+        other_scheme.scheme = SignatureScheme::Secp256k1;
+        assert!(GenesisDocument::from_json(other_scheme.to_json().to_string().as_bytes()).is_err());
+
+        // A node built from the document runs the deployment it describes
+        let node = crate::StreamletInstance::builder()
+            .name("v1")
+            .key_source(crate::KeySource::Keypair(keypairs.swap_remove(1)))
+            .genesis(document.clone())
+            .build()
+            .unwrap();
+        assert_eq!(node.chain_id(), document.chain_id());
+        assert_eq!(node.epoch_length, Duration::from_millis(5000));
+        assert_eq!(node.quorum_size(), 2);
+        assert_eq!(node.id, 1);
+        let impostor = crate::StreamletInstance::builder().name("v1").genesis(document).build();
+        assert!(matches!(impostor, Err(crate::StreamletError::Config(_))));
+    }
+}
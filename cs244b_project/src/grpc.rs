@@ -0,0 +1,383 @@
+/* gRPC service for applications writing to the log (needs the "grpc" feature): a
+   programmatic alternative to typing entries at the console. The service is
+   streamlet.v1.StreamletLog, described for clients in proto/streamlet.proto:
+     SubmitEntry(SubmitEntryRequest)    -> Receipt            queue an entry; signed inclusion promise
+     GetBlock(GetBlockRequest)          -> Block              a finalized block, by height
+     GetChainStatus(ChainStatusRequest) -> ChainStatus        epoch, heights, tree size and root
+     GetProof(GetProofRequest)          -> stream ProofUpdate an entry's proof, once it has one
+   GetProof first sends an update with finalized = false if the entry has no proof yet,
+   then its proof once it is finalized under a signed tree head, and ends. It gives up
+   with DEADLINE_EXCEEDED after MAX_PROOF_WAIT (an id this node never saw also ends
+   there). Signatures and keys are the bincode bytes the JSON schema hex-encodes.
+   Like the HTTP API's, calls are answered by the node's event loop (see
+   http_api::ApiCall); its JSON answers are converted here, and its errors map to
+   INVALID_ARGUMENT, PERMISSION_DENIED, NOT_FOUND and UNAVAILABLE. */
+
+use crate::blockchain::EntryId;
+use crate::http_api::{ApiCall, ApiError, ApiRequest};
+use crate::submitters::SignedSubmission;
+use log::{info, warn};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request, Response, Status};
+
+include!(concat!(env!("OUT_DIR"), "/streamlet.v1.StreamletLog.rs"));
+
+pub use streamlet_log_client::StreamletLogClient;
+pub use streamlet_log_server::{StreamletLog, StreamletLogServer};
+
+// How often GetProof asks whether a waiting entry has its proof yet
+const PROOF_POLL: Duration = Duration::from_millis(500);
+// How long GetProof waits for the proof before giving up
+const MAX_PROOF_WAIT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitEntryRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub content_type: String, // empty for none
+    // For nodes that take entries only from listed submitters (see submitters); empty for none
+    #[prost(string, tag = "3")]
+    pub id: String,
+    #[prost(string, tag = "4")]
+    pub submitter: String,
+    #[prost(bytes = "vec", tag = "5")]
+    pub signature: Vec<u8>, // bincode
+}
+
+/* A signed promise to include the entry within the maximum merge delay. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Receipt {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub leaf_hash: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub timestamp_ms: u64,
+    #[prost(uint64, tag = "4")]
+    pub max_merge_delay_ms: u64,
+    #[prost(string, tag = "5")]
+    pub signer: String,
+    #[prost(bytes = "vec", tag = "6")]
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetBlockRequest {
+    #[prost(uint64, tag = "1")]
+    pub height: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Block {
+    #[prost(uint64, tag = "1")]
+    pub height: u64,
+    #[prost(uint64, tag = "2")]
+    pub epoch: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    pub hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub parent_hash: Vec<u8>,
+    #[prost(uint64, tag = "5")]
+    pub nonce: u64,
+    #[prost(bytes = "vec", tag = "6")]
+    pub data: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "7")]
+    pub signatures: Vec<Vec<u8>>, // the notarization certificate's
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChainStatusRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChainStatus {
+    #[prost(uint64, tag = "1")]
+    pub epoch: u64,
+    #[prost(uint64, tag = "2")]
+    pub notarized_height: u64,
+    #[prost(uint64, tag = "3")]
+    pub finalized_height: u64,
+    #[prost(uint64, tag = "4")]
+    pub tree_size: u64,
+    #[prost(bytes = "vec", tag = "5")]
+    pub root_hash: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetProofRequest {
+    #[prost(string, tag = "1")]
+    pub entry_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SignedTreeHead {
+    #[prost(uint64, tag = "1")]
+    pub tree_size: u64,
+    #[prost(uint64, tag = "2")]
+    pub timestamp_ms: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    pub root_hash: Vec<u8>,
+    #[prost(string, tag = "4")]
+    pub signer: String,
+    #[prost(bytes = "vec", tag = "5")]
+    pub signature: Vec<u8>,
+    #[prost(bytes = "vec", tag = "6")]
+    pub public_key: Vec<u8>,
+}
+
+/* An entry's proof bundle; only "finalized" is set while the entry still waits. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProofUpdate {
+    #[prost(bool, tag = "1")]
+    pub finalized: bool,
+    #[prost(uint64, tag = "2")]
+    pub leaf_index: u64,
+    #[prost(uint64, tag = "3")]
+    pub tree_size: u64,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub audit_path: Vec<Vec<u8>>,
+    #[prost(bytes = "vec", tag = "5")]
+    pub leaf_input: Vec<u8>,
+    #[prost(message, optional, tag = "6")]
+    pub sth: Option<SignedTreeHead>,
+}
+
+fn status(e: ApiError) -> Status {
+    match e.status {
+        400 => Status::invalid_argument(e.message),
+        403 => Status::permission_denied(e.message),
+        404 => Status::not_found(e.message),
+        503 => Status::unavailable(e.message),
+        _ => Status::internal(e.message),
+    }
+}
+
+// The event loop's answers follow json_schema, so a missing field is our bug
+fn malformed(name: &str) -> Status {
+    Status::internal(format!("node's answer has no valid '{}'", name))
+}
+
+fn uint(value: &Value, name: &str) -> Result<u64, Status> {
+    value[name].as_u64().ok_or_else(|| malformed(name))
+}
+
+fn text(value: &Value, name: &str) -> Result<String, Status> {
+    value[name].as_str().map(str::to_string).ok_or_else(|| malformed(name))
+}
+
+fn bytes(value: &Value, name: &str) -> Result<Vec<u8>, Status> {
+    value[name].as_str().and_then(|field| hex::decode(field).ok()).ok_or_else(|| malformed(name))
+}
+
+fn byte_list(value: &Value, name: &str) -> Result<Vec<Vec<u8>>, Status> {
+    let list = value[name].as_array().ok_or_else(|| malformed(name))?;
+    list.iter()
+        .map(|item| item.as_str().and_then(|item| hex::decode(item).ok()).ok_or_else(|| malformed(name)))
+        .collect()
+}
+
+fn receipt(json: &Value) -> Result<Receipt, Status> {
+    Ok(Receipt {
+        id: text(json, "id")?,
+        leaf_hash: bytes(json, "leaf_hash")?,
+        timestamp_ms: uint(json, "timestamp")?,
+        max_merge_delay_ms: uint(json, "max_merge_delay")?,
+        signer: text(json, "signer")?,
+        signature: bytes(json, "signature")?,
+    })
+}
+
+fn block(json: &Value) -> Result<Block, Status> {
+    Ok(Block {
+        height: uint(json, "height")?,
+        epoch: uint(json, "epoch")?,
+        hash: bytes(json, "hash")?,
+        parent_hash: bytes(json, "parent_hash")?,
+        nonce: uint(json, "nonce")?,
+        data: bytes(json, "data")?,
+        signatures: byte_list(json, "signatures")?,
+    })
+}
+
+fn chain_status(json: &Value) -> Result<ChainStatus, Status> {
+    Ok(ChainStatus {
+        epoch: uint(json, "epoch")?,
+        notarized_height: uint(json, "notarized_height")?,
+        finalized_height: uint(json, "finalized_height")?,
+        tree_size: uint(json, "tree_size")?,
+        root_hash: bytes(json, "sha256_root_hash")?,
+    })
+}
+
+fn proof(json: &Value) -> Result<ProofUpdate, Status> {
+    let sth = &json["sth"];
+    Ok(ProofUpdate {
+        finalized: true,
+        leaf_index: uint(json, "leaf_index")?,
+        tree_size: uint(json, "tree_size")?,
+        audit_path: byte_list(json, "audit_path")?,
+        leaf_input: bytes(json, "leaf_input")?,
+        sth: Some(SignedTreeHead {
+            tree_size: uint(sth, "tree_size")?,
+            timestamp_ms: uint(sth, "timestamp")?,
+            root_hash: bytes(sth, "sha256_root_hash")?,
+            signer: text(sth, "signer")?,
+            signature: bytes(sth, "tree_head_signature")?,
+            public_key: bytes(sth, "public_key")?,
+        }),
+    })
+}
+
+/* Hands a request to the event loop and waits for its answer. */
+async fn ask(calls: &mpsc::UnboundedSender<ApiCall>, request: ApiRequest) -> Result<Value, Status> {
+    let (reply, answer) = oneshot::channel();
+    calls.send(ApiCall { request, reply }).map_err(|_| Status::unavailable("node is shutting down"))?;
+    answer.await.unwrap_or_else(|_| Err(ApiError::unavailable("node is shutting down"))).map_err(status)
+}
+
+/* Streams an entry's proof to a GetProof caller (see the top of this file).
+@param id: the entry
+@param calls: the node's API request queue
+@param updates: the caller's stream */
+async fn watch_proof(id: EntryId, calls: mpsc::UnboundedSender<ApiCall>, updates: mpsc::Sender<Result<ProofUpdate, Status>>) {
+    let deadline = Instant::now() + MAX_PROOF_WAIT;
+    let mut told_pending = false;
+    loop {
+        let update = match ask(&calls, ApiRequest::GetProofById { id }).await {
+            Ok(bundle) => Some(proof(&bundle)),
+            Err(e) if e.code() == Code::NotFound => None,
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(update) = update {
+            let _ = updates.send(update).await;
+            return;
+        }
+        if !told_pending {
+            told_pending = true;
+            if updates.send(Ok(ProofUpdate::default())).await.is_err() {
+                return; // the caller hung up
+            }
+        }
+        if Instant::now() >= deadline {
+            let _ = updates.send(Err(Status::deadline_exceeded("entry wasn't finalized in time"))).await;
+            return;
+        }
+        tokio::time::sleep(PROOF_POLL).await;
+    }
+}
+
+pub struct LogService {
+    calls: mpsc::UnboundedSender<ApiCall>,
+}
+
+impl LogService {
+    /* @param calls: the node's API request queue */
+    pub fn new(calls: mpsc::UnboundedSender<ApiCall>) -> Self {
+        LogService { calls }
+    }
+}
+
+#[tonic::async_trait]
+impl StreamletLog for LogService {
+    async fn submit_entry(&self, request: Request<SubmitEntryRequest>) -> Result<Response<Receipt>, Status> {
+        let SubmitEntryRequest { data, content_type, id, submitter, signature } = request.into_inner();
+        let content_type = match content_type.as_str() {
+            "" => None,
+            content_type => Some(content_type.parse().map_err(|e: String| Status::invalid_argument(e))?),
+        };
+        let id = match id.as_str() {
+            "" => None,
+            id => Some(id.parse().map_err(|_| Status::invalid_argument("'id' should be an entry id"))?),
+        };
+        let submission = match submitter.as_str() {
+            "" => None,
+            submitter => {
+                let signature = bincode::deserialize(&signature).map_err(|_| Status::invalid_argument("'signature' should be a bincode signature"))?;
+                Some(Box::new(SignedSubmission { submitter: submitter.to_string(), signature }))
+            }
+        };
+        let answer = ask(&self.calls, ApiRequest::AddEntry { data, callback: None, content_type, id, submission }).await?;
+        Ok(Response::new(receipt(&answer)?))
+    }
+
+    async fn get_block(&self, request: Request<GetBlockRequest>) -> Result<Response<Block>, Status> {
+        let answer = ask(&self.calls, ApiRequest::GetBlock { height: request.into_inner().height }).await?;
+        Ok(Response::new(block(&answer)?))
+    }
+
+    async fn get_chain_status(&self, _request: Request<ChainStatusRequest>) -> Result<Response<ChainStatus>, Status> {
+        let answer = ask(&self.calls, ApiRequest::GetChainStatus).await?;
+        Ok(Response::new(chain_status(&answer)?))
+    }
+
+    type GetProofStream = ReceiverStream<Result<ProofUpdate, Status>>;
+
+    async fn get_proof(&self, request: Request<GetProofRequest>) -> Result<Response<Self::GetProofStream>, Status> {
+        let id: EntryId = request
+            .into_inner()
+            .entry_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("'entry_id' should be an entry id"))?;
+        let (updates, stream) = mpsc::channel(2);
+        tokio::spawn(watch_proof(id, self.calls.clone(), updates));
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+/* Serves the service until the node exits.
+@param addr: address to listen on
+@param calls: the node's API request queue */
+pub async fn serve(addr: SocketAddr, calls: mpsc::UnboundedSender<ApiCall>) {
+    info!("gRPC service listening on {}", addr);
+    let server = tonic::transport::Server::builder().add_service(StreamletLogServer::new(LogService::new(calls)));
+    if let Err(e) = server.serve(addr).await {
+        warn!("gRPC service stopped: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_proof_streams_pending_then_proof() {
+        let (calls, mut requests) = mpsc::unbounded_channel::<ApiCall>();
+        // A stand-in event loop, whose entry gets its proof on the third ask
+        tokio::spawn(async move {
+            let mut asked = 0;
+            while let Some(call) = requests.recv().await {
+                asked += 1;
+                let answer = match asked {
+                    1 | 2 => Err(ApiError::not_found("no such finalized entry")),
+                    _ => Ok(json!({
+                        "leaf_input": "abcd", "leaf_index": 3, "tree_size": 4, "audit_path": ["01", "02"],
+                        "sth": { "tree_size": 4, "timestamp": 7, "sha256_root_hash": "ff", "signer": "n1",
+                                 "tree_head_signature": "aa", "public_key": "bb" },
+                    })),
+                };
+                let _ = call.reply.send(answer);
+            }
+        });
+        let service = LogService::new(calls);
+
+        let bad_id = service.get_proof(Request::new(GetProofRequest { entry_id: "not an id".to_string() })).await;
+        assert_eq!(bad_id.err().map(|e| e.code()), Some(Code::InvalidArgument));
+
+        let id = EntryId(42).to_string();
+        let stream = service.get_proof(Request::new(GetProofRequest { entry_id: id })).await.unwrap().into_inner();
+        let updates: Vec<ProofUpdate> = stream.map(|update| update.unwrap()).collect().await;
+        assert_eq!(updates.len(), 2);
+        assert!(!updates[0].finalized);
+        assert!(updates[1].finalized);
+        assert_eq!(updates[1].leaf_index, 3);
+        assert_eq!(updates[1].audit_path, vec![vec![1], vec![2]]);
+        assert_eq!(updates[1].sth.as_ref().map(|sth| sth.root_hash.clone()), Some(vec![0xff]));
+    }
+}
@@ -0,0 +1,283 @@
+/* In-process multi-node harness for consensus scenarios.
+   Runs a validator set as StreamletInstances in one process and plays epochs in lock
+   step: the leader proposes, every online node decides whether to vote (should_vote),
+   and all votes reach every online node, which counts those of the validators it knows. There is no network, clock or app, so a
+   scenario is deterministic apart from block nonces. A node compromised to equivocate
+   proposes two blocks when it leads, and the others hear them in different orders.
+   Nodes can run mixed versions: supported_version stands in for the release a node
+   runs (the current library's PROTOCOL_VERSION, or an older/newer one), and restart
+   takes a node down for an epoch, as replacing its binary would, after which it
+   catches up from a peer like chain sync does. */
+
+use crate::*;
+
+// Votes on one proposal: (voter, signature)
+type Votes = Vec<(String, Signature)>;
+
+pub(crate) struct Cluster {
+    pub nodes: Vec<StreamletInstance>,
+    online: Vec<bool>,
+}
+
+impl Cluster {
+    /* Validators h1..h<size>, all running this library's protocol version. */
+    pub fn new(size: usize) -> Self {
+        let mut nodes: Vec<StreamletInstance> =
+            (1..=size).map(|i| StreamletInstance::new(format!("h{}", i), size - 1)).collect();
+        let keys: Vec<(String, PublicKey)> = nodes.iter().map(|node| (node.name.clone(), node.get_public_key())).collect();
+        for node in nodes.iter_mut() {
+            for (name, key) in &keys {
+                node.add_public_key(name.clone(), key);
+            }
+            node.sorted_peer_names = keys.iter().map(|(name, _)| name.clone()).sorted().collect();
+        }
+        Self { nodes, online: vec![true; size] }
+    }
+
+    /* Takes a node down for the next epoch and brings it back running another release.
+    @param index: the node
+    @param supported_version: newest protocol version the new release implements */
+    pub fn restart(&mut self, index: usize, supported_version: u32) {
+        self.online[index] = false;
+        self.nodes[index].supported_version = supported_version;
+    }
+
+    /* Plays one epoch. Returns whether a block of it was notarized (by any node). */
+    pub fn run_epoch(&mut self, epoch: u64) -> bool {
+        for index in 0..self.nodes.len() {
+            self.nodes[index].maintenance.prune(epoch);
+            self.nodes[index].activate_roster_changes(epoch);
+        }
+        let leader_name = self.nodes[0].get_epoch_leader(epoch);
+        let leader = self.nodes.iter().position(|node| node.name == leader_name).expect("leader is a validator");
+
+        // Each proposal with its votes; an equivocating leader makes two
+        let mut proposals: Vec<(Block, Message, Votes)> = Vec::new();
+        if self.online[leader] && self.nodes[leader].supports_protocol_at(epoch) {
+            let mut proposal = self.nodes[leader].make_proposal(epoch);
+            let sig = self.nodes[leader].sign_message(&mut proposal).expect("fresh proposal");
+            let twin = self.nodes[leader].equivocating_proposal(&proposal);
+            for (message, sig) in std::iter::once((proposal, sig)).chain(twin) {
+                let proposed = match &message.payload {
+                    MessagePayload::Block(block) => block.clone(),
+                    _ => unreachable!("proposals carry a block"),
+                };
+                proposals.push((proposed, message, vec![(leader_name.clone(), sig)]));
+            }
+            // Nodes hear the proposals in different orders, and vote for the first they accept
+            let app_interface = AppInterface;
+            for (index, node) in self.nodes.iter_mut().enumerate() {
+                if index == leader || !self.online[index] {
+                    continue;
+                }
+                let mut vote_this_epoch = None;
+                let count = proposals.len();
+                for offset in 0..count {
+                    let (proposed, message, votes) = &mut proposals[(index + offset) % count];
+                    let mut message = message.clone();
+                    if let Some(sig) = node.should_vote(&mut message, vote_this_epoch, epoch, proposed, &app_interface) {
+                        votes.push((node.name.clone(), sig));
+                        vote_this_epoch.get_or_insert(sig);
+                    }
+                }
+            }
+        }
+
+        let mut notarized = false;
+        for (index, node) in self.nodes.iter_mut().enumerate() {
+            if !self.online[index] {
+                continue;
+            }
+            for (block, _, votes) in &proposals {
+                let counted = votes.iter().filter(|(name, _)| node.public_keys.contains_key(name)).cloned().collect();
+                node.blockchain_manager.record_votes(block, counted);
+                if node.blockchain_manager.try_notarize(&block.hash, node.quorum_size()) {
+                    notarized = true;
+                    node.pending_transactions.remove(&block.data);
+                }
+            }
+            Cluster::apply_finalized(node);
+        }
+
+        // Nodes that were down come back and catch up from a peer
+        for index in 0..self.nodes.len() {
+            if !self.online[index] {
+                self.online[index] = true;
+                self.sync_from_peer(index);
+            }
+        }
+        notarized
+    }
+
+    // What report_finalized does, minus the network
+    fn apply_finalized(node: &mut StreamletInstance) {
+        for signed in node.blockchain_manager.take_newly_finalized() {
+            node.notify_finalized(&signed);
+            let SignedBlock { block, cert } = signed;
+            if let Some(entry) = LogEntry::deserialize(&block.data) {
+                node.apply_announcement(&entry, &block, &cert);
+            }
+            node.pending_transactions.remove(&block.data);
+        }
+    }
+
+    fn sync_from_peer(&mut self, index: usize) {
+        let peer = (index + 1) % self.nodes.len();
+        let from_height = self.nodes[index].blockchain_manager.get_latest_finalized_block().0.height;
+        if let Some(chain) = self.nodes[peer].blockchain_manager.notarized_chain_from(from_height, CHAIN_SYNC_MAX_BLOCKS) {
+            let node = &mut self.nodes[index];
+            if node.is_chain_certified(&chain) {
+                node.blockchain_manager.observe_chain(chain);
+                Cluster::apply_finalized(node);
+            }
+        }
+    }
+
+    /* Every node's operator approves an upgrade, and every node queues its announcement. */
+    pub fn announce_upgrade(&mut self, upgrade: ProtocolUpgrade, epoch: u64) {
+        for node in self.nodes.iter_mut() {
+            node.announce_upgrade(&format!("{} {}", upgrade.version, upgrade.activation_epoch), epoch);
+        }
+    }
+
+    /* Length of the shortest finalized chain among the nodes. */
+    pub fn finalized_length(&self) -> usize {
+        self.nodes.iter().map(|node| node.blockchain_manager.finalized_chain().length()).min().unwrap_or(0)
+    }
+
+    /* Safety: no two nodes finalized different blocks at the same height. */
+    pub fn assert_consistent(&self) {
+        let chains: Vec<Vec<Sha256Hash>> = self
+            .nodes
+            .iter()
+            .map(|node| node.blockchain_manager.finalized_chain().blocks.iter().map(|b| b.block.hash).collect())
+            .collect();
+        for (a, b) in chains.iter().tuple_combinations() {
+            let common = a.len().min(b.len());
+            assert_eq!(a[..common], b[..common], "finalized chains diverge");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEW_VERSION: u32 = PROTOCOL_VERSION + 1;
+
+    // Plays epochs, checking safety after each; returns how many blocks were notarized
+    fn run(cluster: &mut Cluster, epochs: std::ops::Range<u64>) -> usize {
+        let mut notarized = 0;
+        for epoch in epochs {
+            notarized += cluster.run_epoch(epoch) as usize;
+            cluster.assert_consistent();
+        }
+        notarized
+    }
+
+    #[test]
+    fn test_rolling_upgrade_keeps_safety_and_liveness() {
+        let mut cluster = Cluster::new(4);
+        run(&mut cluster, 1..4);
+        let before_rollout = cluster.finalized_length();
+        assert!(before_rollout > 1);
+
+        // Replace one binary at a time; the others keep a quorum meanwhile
+        let mut epoch = 4;
+        for index in 0..4 {
+            cluster.restart(index, NEW_VERSION);
+            run(&mut cluster, epoch..epoch + 4);
+            epoch += 4;
+        }
+        let after_rollout = cluster.finalized_length();
+        assert!(after_rollout > before_rollout);
+
+        // Now the new protocol version can be switched on
+        let upgrade = ProtocolUpgrade { version: NEW_VERSION, activation_epoch: epoch + MIN_UPGRADE_NOTICE + 5 };
+        cluster.announce_upgrade(upgrade, epoch);
+        run(&mut cluster, epoch..upgrade.activation_epoch);
+        assert!(cluster.nodes.iter().all(|node| node.protocol_version(upgrade.activation_epoch) == NEW_VERSION));
+        let at_activation = cluster.finalized_length();
+        assert!(run(&mut cluster, upgrade.activation_epoch..upgrade.activation_epoch + 6) > 0);
+        assert!(cluster.finalized_length() > at_activation);
+    }
+
+    #[test]
+    fn test_retired_validator_stops_counting_everywhere_at_once() {
+        let mut cluster = Cluster::new(4);
+        run(&mut cluster, 1..3);
+        for node in cluster.nodes.iter_mut() {
+            node.announce_roster_change("retire h4");
+        }
+        // Finalized within a few epochs, in force ROSTER_ACTIVATION_DELAY epochs after its block
+        let mut epoch = 3;
+        while cluster.nodes.iter().any(|node| node.roster_history.records().is_empty()) {
+            run(&mut cluster, epoch..epoch + 1);
+            epoch += 1;
+            assert!(epoch < 20, "the retirement wasn't finalized");
+        }
+        let activation = cluster.nodes[0].roster_history.records()[0].activation_epoch();
+        run(&mut cluster, epoch..activation);
+        assert!(cluster.nodes.iter().all(|node| node.quorum_size() == 3 && node.public_keys.contains_key("h4")));
+
+        run(&mut cluster, activation..activation + 1);
+        for node in &cluster.nodes {
+            assert_eq!(node.quorum_size(), 2);
+            assert!(!node.public_keys.contains_key("h4") && !node.sorted_peer_names.contains(&String::from("h4")));
+        }
+        let leaders: Vec<String> = (activation..activation + 40).map(|epoch| cluster.nodes[0].get_epoch_leader(epoch)).collect();
+        assert!(!leaders.contains(&String::from("h4")));
+        let before = cluster.finalized_length();
+        assert!(run(&mut cluster, activation + 1..activation + 8) > 0);
+        assert!(cluster.finalized_length() > before);
+    }
+
+    #[test]
+    fn test_honest_majority_survives_an_equivocating_node() {
+        let mut cluster = Cluster::new(4);
+        cluster.nodes[0].set_compromise(CompromiseType::Equivocate);
+        // run checks after every epoch that no two nodes finalized conflicting blocks
+        assert!(run(&mut cluster, 1..16) > 0);
+        assert!((1..16).any(|epoch| cluster.nodes[1].get_epoch_leader(epoch) == "h1"));
+        assert!(cluster.finalized_length() > 1);
+    }
+
+    #[test]
+    fn test_finalized_subscribers_see_each_block_once_in_order() {
+        let mut cluster = Cluster::new(4);
+        let mut finalized = cluster.nodes[1].subscribe_finalized();
+        run(&mut cluster, 1..8);
+        let mut heights = Vec::new();
+        while let Ok(SignedBlock { block, cert }) = finalized.try_recv() {
+            assert!(!cert.signatures.is_empty());
+            heights.push(block.height);
+        }
+        let expected: Vec<u64> = (1..cluster.nodes[1].blockchain_manager.finalized_chain().length() as u64).collect();
+        assert!(expected.len() > 1);
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    fn test_activation_before_rollout_completes_halts_safely() {
+        let mut cluster = Cluster::new(4);
+        cluster.restart(0, NEW_VERSION);
+        cluster.restart(1, NEW_VERSION);
+        run(&mut cluster, 1..4);
+        let upgrade = ProtocolUpgrade { version: NEW_VERSION, activation_epoch: 4 + MIN_UPGRADE_NOTICE + 5 };
+        cluster.announce_upgrade(upgrade, 4);
+        run(&mut cluster, 4..upgrade.activation_epoch);
+        assert!(cluster.nodes.iter().all(|node| node.protocol_version(upgrade.activation_epoch) == NEW_VERSION));
+
+        // Two old nodes sit out, leaving no quorum: no progress, but no conflicts either
+        let stalled = cluster.finalized_length();
+        assert_eq!(run(&mut cluster, upgrade.activation_epoch..upgrade.activation_epoch + 6), 0);
+        assert_eq!(cluster.finalized_length(), stalled);
+
+        // Upgrading the stragglers restores liveness
+        let epoch = upgrade.activation_epoch + 6;
+        cluster.restart(2, NEW_VERSION);
+        cluster.restart(3, NEW_VERSION);
+        run(&mut cluster, epoch..epoch + 8);
+        assert!(cluster.finalized_length() > stalled);
+    }
+}
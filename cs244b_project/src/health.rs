@@ -0,0 +1,102 @@
+/* Health and readiness, for orchestrators that restart stuck nodes (see the HTTP API's
+   /healthz and /readyz).
+   - Live: the event loop answers (the probe times out otherwise) and starts epochs on
+     time: once peer discovery is done, no more than MAX_LATE_EPOCHS epoch lengths pass
+     without one. A node still discovering peers is live; it waits on its operator.
+   - Ready: peer discovery is done, and the node is synced: it has notarized as far as
+     any block its peers showed it (a finalization or proposal beyond its chain), and
+     isn't waiting to sync after losing its peers.
+   Each check says what is wrong when it fails. */
+
+use std::time::Duration;
+
+// Epoch lengths without a new epoch before a node counts as stuck
+pub const MAX_LATE_EPOCHS: u32 = 3;
+
+#[derive(Debug, Default)]
+pub struct Health {
+    last_epoch_ms: Option<u64>, // when the last epoch started (None: none yet)
+    sync_target: u64,           // highest notarized height peers showed us
+}
+
+impl Health {
+    /* @param now_ms: unix time in ms */
+    pub fn epoch_started(&mut self, now_ms: u64) {
+        self.last_epoch_ms = Some(now_ms);
+    }
+
+    /* A peer showed us a block at this height is notarized. */
+    pub fn peer_at(&mut self, height: u64) {
+        self.sync_target = self.sync_target.max(height);
+    }
+
+    /* Highest notarized height peers showed us. */
+    pub fn sync_target(&self) -> u64 {
+        self.sync_target
+    }
+
+    /* Ok, or why the node is stuck.
+    @param discovered: whether peer discovery is done
+    @param now_ms: unix time in ms
+    @param epoch_length: the deployment's epoch length */
+    pub fn liveness(&self, discovered: bool, now_ms: u64, epoch_length: Duration) -> Result<(), String> {
+        if !discovered {
+            return Ok(());
+        }
+        let limit = (epoch_length * MAX_LATE_EPOCHS).as_millis() as u64;
+        // Discovery just ended; the first epoch may not have started
+        let since = match self.last_epoch_ms {
+            Some(last) => now_ms.saturating_sub(last),
+            None => return Ok(()),
+        };
+        if since > limit {
+            return Err(format!("no epoch started for {} ms", since));
+        }
+        Ok(())
+    }
+
+    /* Ok, or why the node shouldn't take traffic yet.
+    @param discovered: whether peer discovery is done
+    @param height: height of our longest notarized chain
+    @param resync_pending: whether we are waiting to sync after reconnecting */
+    pub fn readiness(&self, discovered: bool, height: u64, resync_pending: bool) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if !discovered {
+            problems.push(String::from("peer discovery isn't done"));
+        }
+        if height < self.sync_target {
+            problems.push(format!("behind the network (height {} of {})", height, self.sync_target));
+        }
+        if resync_pending {
+            problems.push(String::from("syncing after a lost connection"));
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join("; ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_and_readiness() {
+        let epoch = Duration::from_secs(10);
+        let mut health = Health::default();
+        assert!(health.liveness(false, 1_000_000, epoch).is_ok());
+        assert!(health.liveness(true, 1_000_000, epoch).is_ok());
+        health.epoch_started(1_000_000);
+        assert!(health.liveness(true, 1_030_000, epoch).is_ok());
+        assert!(health.liveness(true, 1_030_001, epoch).is_err());
+
+        assert_eq!(health.readiness(false, 0, false), Err(String::from("peer discovery isn't done")));
+        assert_eq!(health.readiness(true, 0, false), Ok(()));
+        health.peer_at(5);
+        health.peer_at(3);
+        assert_eq!(health.readiness(true, 4, false), Err(String::from("behind the network (height 4 of 5)")));
+        assert_eq!(health.readiness(true, 5, false), Ok(()));
+        assert!(health.readiness(true, 5, true).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+/* RFC 6962-style HTTP API for the log (the server needs the "http-api" feature):
+     POST /ct/v1/add-entry               {"data": hex, "callback": url,  -> {"id", signed inclusion promise}
+                                          "content_type": type, "id": id,
+                                          "submitter": name, "signature": hex}
+     GET  /ct/v1/get-sth                                                -> signed tree head
+     GET  /ct/v1/get-proof-by-hash?hash=H&tree_size=N                   -> {"leaf_index", "audit_path"}
+     GET  /ct/v1/get-sth-consistency?first=M&second=N                   -> {"consistency"}
+     GET  /ct/v1/get-entries?start=S&end=E                              -> {"entries"}
+     GET  /ct/v1/get-entry?id=I                                         -> one finalized entry
+     GET  /ct/v1/get-roster-history                                     -> {"changes"}
+     GET  /healthz                                                      -> {"status": "ok"} or 503
+     GET  /readyz                                                       -> {"status": "ready"} or 503
+   Binary fields are hex rather than base64. get-entries returns entries start..=end, as
+   in RFC 6962, capped at MAX_ENTRIES per call. With a callback URL, the entry's proof
+   is also pushed there once it is finalized (see callback). A node that takes entries
+   only from listed submitters (see submitters) wants "id", "submitter" and "signature",
+   the submitter's signature on the entry with that id, and refuses others with a 403.
+   Entries are returned with their content type, if the submitter gave one, and textual
+   ones (any text type, JSON, XML) are also rendered readably alongside the hex. get-entry
+   returns the bare data, with the entry's Content-Type, to clients whose Accept header
+   asks for that type. get-roster-history lists every finalized change to the validator
+   set (see roster), oldest first, with the validators that approved it.
+   /healthz and /readyz are for orchestrators (see health); a 503 says what is wrong.
+   A node whose event loop doesn't answer /healthz within PROBE_TIMEOUT is stuck, and
+   gets a 503 too.
+   Response bodies follow the node's JSON schema (see json_schema). Requests are parsed
+   here and answered by the node's event loop (which owns the chain), the same way
+   TCP block/chain requests are. */
+
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+use crate::blockchain::{ContentType, EntryId};
+use crate::callback::CallbackUrl;
+use crate::submitters::SignedSubmission;
+use crate::Sha256Hash;
+
+// Most entries returned by one get-entries call
+pub const MAX_ENTRIES: u64 = 256;
+// How long /healthz and /readyz wait for the event loop
+pub const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiRequest {
+    AddEntry {
+        data: Vec<u8>,
+        callback: Option<CallbackUrl>,
+        content_type: Option<ContentType>,
+        id: Option<EntryId>,                       // the submitter's, if it signed the entry
+        submission: Option<Box<SignedSubmission>>, // see submitters (boxed: signatures are large)
+    },
+    GetSth,
+    GetProofByHash { hash: Sha256Hash, tree_size: u64 },
+    GetConsistency { first: u64, second: u64 },
+    GetEntries { start: u64, end: u64 },
+    GetEntry { id: EntryId },
+    GetRosterHistory,
+    Health,
+    Ready,
+    // Only over gRPC (see grpc)
+    GetBlock { height: u64 },
+    GetChainStatus,
+    GetProofById { id: EntryId },
+}
+
+impl ApiRequest {
+    /* Whether this is an orchestrator's health or readiness probe. */
+    pub fn is_probe(&self) -> bool {
+        matches!(self, ApiRequest::Health | ApiRequest::Ready)
+    }
+}
+
+/* HTTP status and message for a failed request. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: &str) -> Self {
+        Self { status: 400, message: message.to_string() }
+    }
+
+    pub fn forbidden(message: &str) -> Self {
+        Self { status: 403, message: message.to_string() }
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        Self { status: 404, message: message.to_string() }
+    }
+
+    pub fn unavailable(message: &str) -> Self {
+        Self { status: 503, message: message.to_string() }
+    }
+}
+
+pub type ApiResponse = Result<Value, ApiError>;
+
+/* A request waiting for the event loop, with where to send the answer. */
+pub struct ApiCall {
+    pub request: ApiRequest,
+    pub reply: oneshot::Sender<ApiResponse>,
+}
+
+fn query_params(query: Option<&str>) -> HashMap<&str, &str> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect()
+}
+
+fn number(params: &HashMap<&str, &str>, name: &str) -> Result<u64, ApiError> {
+    params
+        .get(name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| ApiError::bad_request(&format!("missing or invalid '{}'", name)))
+}
+
+/* Parses an HTTP request into an API request.
+@param method: HTTP method
+@param path: request path, without the query string
+@param query: query string, if any
+@param body: request body */
+pub fn parse_request(method: &str, path: &str, query: Option<&str>, body: &[u8]) -> Result<ApiRequest, ApiError> {
+    let params = query_params(query);
+    match (method, path) {
+        ("POST", "/ct/v1/add-entry") => {
+            let body: Value = serde_json::from_slice(body).map_err(|_| ApiError::bad_request("body is not JSON"))?;
+            let data = body["data"]
+                .as_str()
+                .and_then(|data| hex::decode(data).ok())
+                .ok_or_else(|| ApiError::bad_request("'data' should be a hex string"))?;
+            let callback = match body["callback"].as_str() {
+                Some(url) => Some(url.parse().map_err(|e: String| ApiError::bad_request(&e))?),
+                None => None,
+            };
+            let content_type = match body["content_type"].as_str() {
+                Some(content_type) => Some(content_type.parse().map_err(|e: String| ApiError::bad_request(&e))?),
+                None => None,
+            };
+            let id = match body["id"].as_str() {
+                Some(id) => Some(id.parse().map_err(|_| ApiError::bad_request("'id' should be an entry id"))?),
+                None => None,
+            };
+            let submission = match (body["submitter"].as_str(), body["signature"].as_str()) {
+                (Some(submitter), Some(signature)) => {
+                    let signature = hex::decode(signature)
+                        .ok()
+                        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+                        .ok_or_else(|| ApiError::bad_request("'signature' should be a hex signature"))?;
+                    Some(Box::new(SignedSubmission { submitter: submitter.to_string(), signature }))
+                }
+                (None, None) => None,
+                _ => return Err(ApiError::bad_request("'submitter' and 'signature' go together")),
+            };
+            Ok(ApiRequest::AddEntry { data, callback, content_type, id, submission })
+        }
+        ("GET", "/ct/v1/get-sth") => Ok(ApiRequest::GetSth),
+        ("GET", "/ct/v1/get-roster-history") => Ok(ApiRequest::GetRosterHistory),
+        ("GET", "/healthz") => Ok(ApiRequest::Health),
+        ("GET", "/readyz") => Ok(ApiRequest::Ready),
+        ("GET", "/ct/v1/get-proof-by-hash") => {
+            let hash = params
+                .get("hash")
+                .and_then(|hash| hex::decode(hash).ok())
+                .and_then(|hash| Sha256Hash::try_from(hash.as_slice()).ok())
+                .ok_or_else(|| ApiError::bad_request("'hash' should be a hex SHA-256 leaf hash"))?;
+            Ok(ApiRequest::GetProofByHash { hash, tree_size: number(&params, "tree_size")? })
+        }
+        ("GET", "/ct/v1/get-sth-consistency") => Ok(ApiRequest::GetConsistency {
+            first: number(&params, "first")?,
+            second: number(&params, "second")?,
+        }),
+        ("GET", "/ct/v1/get-entries") => {
+            let (start, end) = (number(&params, "start")?, number(&params, "end")?);
+            if start > end {
+                return Err(ApiError::bad_request("'start' is after 'end'"));
+            }
+            Ok(ApiRequest::GetEntries { start, end })
+        }
+        ("GET", "/ct/v1/get-entry") => {
+            let id = params
+                .get("id")
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| ApiError::bad_request("'id' should be an entry id"))?;
+            Ok(ApiRequest::GetEntry { id })
+        }
+        _ => Err(ApiError::not_found("unknown endpoint")),
+    }
+}
+
+/* Whether a client's Accept header asks for a content type by name: the exact type, or
+its type with a wildcard subtype. Headers accepting anything (such as curl's default)
+get JSON.
+@param accept: the request's Accept header
+@param content_type: the entry's content type */
+pub fn accepts(accept: &str, content_type: &ContentType) -> bool {
+    let essence = content_type.essence();
+    let kind = essence.split('/').next().unwrap_or_default();
+    accept.split(',').any(|range| {
+        let range = range.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        range == essence || range == format!("{}/*", kind)
+    })
+}
+
+#[cfg(feature = "http-api")]
+mod server {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use log::{info, warn};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+
+    async fn handle(request: Request<Body>, calls: mpsc::UnboundedSender<ApiCall>) -> Response<Body> {
+        let method = request.method().to_string();
+        let path = request.uri().path().to_string();
+        let query = request.uri().query().map(str::to_string);
+        let accept = request.headers().get(hyper::header::ACCEPT).and_then(|accept| accept.to_str().ok()).map(str::to_string);
+        let result = match hyper::body::to_bytes(request.into_body()).await {
+            Ok(body) => match parse_request(&method, &path, query.as_deref(), &body) {
+                Ok(api_request) => {
+                    let probe = api_request.is_probe();
+                    let (reply, answer) = oneshot::channel();
+                    let _ = calls.send(ApiCall { request: api_request, reply });
+                    let answer = match probe {
+                        true => tokio::time::timeout(PROBE_TIMEOUT, answer)
+                            .await
+                            .unwrap_or_else(|_| Ok(Err(ApiError::unavailable("event loop isn't answering")))),
+                        false => answer.await,
+                    };
+                    answer.unwrap_or_else(|_| Err(ApiError::unavailable("node is shutting down")))
+                }
+                Err(e) => Err(e),
+            },
+            Err(_) => Err(ApiError::bad_request("couldn't read body")),
+        };
+        if let (Some(accept), Ok(entry)) = (&accept, &result) {
+            if let Some(raw) = raw_entry(entry, accept) {
+                return raw;
+            }
+        }
+        let (status, body) = match result {
+            Ok(value) => (StatusCode::OK, value),
+            Err(e) => (
+                StatusCode::from_u16(e.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                json!({ "error": e.message }),
+            ),
+        };
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("valid response")
+    }
+
+    // get-entry's data as is, if the client asked for the entry's content type
+    fn raw_entry(entry: &Value, accept: &str) -> Option<Response<Body>> {
+        let content_type: ContentType = entry.get("content_type")?.as_str()?.parse().ok()?;
+        if entry.get("id").is_none() || !accepts(accept, &content_type) {
+            return None;
+        }
+        let data = hex::decode(entry["data"].as_str()?).ok()?;
+        Response::builder().status(StatusCode::OK).header("content-type", content_type.to_string()).body(Body::from(data)).ok()
+    }
+
+    /* Serves the API until the node exits.
+    @param addr: address to listen on
+    @param calls: the node's API request queue */
+    pub async fn serve(addr: SocketAddr, calls: mpsc::UnboundedSender<ApiCall>) {
+        let make_service = make_service_fn(move |_| {
+            let calls = calls.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| {
+                let calls = calls.clone();
+                async move { Ok::<_, Infallible>(handle(request, calls).await) }
+            })) }
+        });
+        let server = match Server::try_bind(&addr) {
+            Ok(builder) => builder.serve(make_service),
+            Err(e) => {
+                warn!("Couldn't start HTTP API on {}: {}", addr, e);
+                return;
+            }
+        };
+        info!("HTTP API listening on {}", addr);
+        if let Err(e) = server.await {
+            warn!("HTTP API stopped: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "http-api")]
+pub use server::serve;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(parse_request("GET", "/ct/v1/get-sth", None, b""), Ok(ApiRequest::GetSth));
+        assert_eq!(
+            parse_request("POST", "/ct/v1/add-entry", None, br#"{"data": "0a0b"}"#),
+            Ok(ApiRequest::AddEntry { data: vec![0x0a, 0x0b], callback: None, content_type: None, id: None, submission: None })
+        );
+        let typed = parse_request("POST", "/ct/v1/add-entry", None, br#"{"data": "", "content_type": "application/json"}"#);
+        assert!(matches!(typed, Ok(ApiRequest::AddEntry { content_type: Some(t), .. }) if t.is_json()));
+        assert_eq!(
+            parse_request("POST", "/ct/v1/add-entry", None, br#"{"data": "", "content_type": "json"}"#).unwrap_err().status,
+            400
+        );
+        let with_callback = parse_request("POST", "/ct/v1/add-entry", None, br#"{"data": "", "callback": "http://h:81/p"}"#);
+        assert!(matches!(with_callback, Ok(ApiRequest::AddEntry { callback: Some(url), .. }) if url.port == 81));
+        assert_eq!(
+            parse_request("POST", "/ct/v1/add-entry", None, br#"{"data": "", "callback": "ftp://h"}"#).unwrap_err().status,
+            400
+        );
+        let hash = hex::encode([7u8; 32]);
+        assert_eq!(
+            parse_request("GET", "/ct/v1/get-proof-by-hash", Some(&format!("hash={}&tree_size=5", hash)), b""),
+            Ok(ApiRequest::GetProofByHash { hash: [7u8; 32], tree_size: 5 })
+        );
+        assert_eq!(
+            parse_request("GET", "/ct/v1/get-sth-consistency", Some("first=2&second=9"), b""),
+            Ok(ApiRequest::GetConsistency { first: 2, second: 9 })
+        );
+        assert_eq!(parse_request("GET", "/ct/v1/get-entries", Some("start=3&end=1"), b"").unwrap_err().status, 400);
+        assert_eq!(parse_request("GET", "/ct/v1/get-proof-by-hash", Some("hash=zz&tree_size=1"), b"").unwrap_err().status, 400);
+        assert_eq!(parse_request("GET", "/ct/v1/add-entry", None, b"").unwrap_err().status, 404);
+        let id = EntryId::from_parts(5, 6);
+        assert_eq!(parse_request("GET", "/ct/v1/get-entry", Some(&format!("id={}", id)), b""), Ok(ApiRequest::GetEntry { id }));
+        assert_eq!(parse_request("GET", "/ct/v1/get-entry", Some("id=nope"), b"").unwrap_err().status, 400);
+        assert_eq!(parse_request("GET", "/healthz", None, b""), Ok(ApiRequest::Health));
+        assert!(parse_request("GET", "/readyz", None, b"").unwrap().is_probe());
+    }
+
+    #[test]
+    fn test_accept_headers_name_content_types() {
+        let json: ContentType = "application/json".parse().unwrap();
+        assert!(accepts("text/html, application/json;q=0.9", &json));
+        assert!(accepts("application/*", &json));
+        assert!(!accepts("*/*", &json));
+        assert!(!accepts("text/plain", &json));
+    }
+}
@@ -0,0 +1,207 @@
+/* The JSON the node shows the outside world: blocks, entries, signed tree heads,
+   inclusion receipts and proofs, in one shape wherever they appear (HTTP API responses,
+   callback deliveries, exported chain files). Every object carries "schema_version".
+   These are built field by field rather than derived from the internal structs, so
+   those can change without consumers noticing. Adding a field keeps the version;
+   renaming, removing or changing the meaning of one bumps JSON_SCHEMA_VERSION, and
+   consumers (such as the auditor) refuse versions newer than they know (see
+   check_version). Binary fields are hex; signatures and keys are hex of their bincode
+   encoding.
+     entry:       {id, content_type, data, and "text" or "json" for textual types}
+     block:       {height, epoch, hash, parent_hash, nonce, data, entry, signatures}
+     sth:         {tree_size, timestamp, sha256_root_hash, signer, tree_head_signature,
+                   public_key}
+     receipt:     {id, leaf_hash, timestamp, max_merge_delay, signer, signature}
+     inclusion:   {leaf_index, tree_size, audit_path}
+     consistency: {first, second, consistency}
+     bundle:      {id, leaf_input, leaf_index, tree_size, audit_path, sth}
+     roster:      {kind, validator, public_key, weight, id, height, epoch, block_hash,
+                   approvers, signatures}
+     chain export: {chain_id, validators: {name: public_key}, blocks} (see
+                   blockchain::export)
+     genesis:     {deployment, scheme, epoch_length_ms, validators: {name: {id,
+                   public_key}}} (see genesis)
+     submitters:  {submitters: {name: public_key}} (see submitters)
+     static roster: {validators: {name: {public_key, addrs}}} (see static_roster) */
+
+use serde_json::{json, Value};
+
+use crate::blockchain::{AuditPath, Block, ConsistencyProof, ContentType, EntryId, InclusionPromise, InclusionProof, LogEntry};
+use crate::roster::{RosterChange, RosterRecord};
+use crate::{PublicKey, Sha256Hash, Signature, SignedTreeHead};
+
+pub const JSON_SCHEMA_VERSION: u64 = 1;
+pub const VERSION_FIELD: &str = "schema_version";
+
+fn versioned(mut value: Value) -> Value {
+    value[VERSION_FIELD] = json!(JSON_SCHEMA_VERSION);
+    value
+}
+
+fn hex_bincode<T: serde::Serialize>(value: &T) -> String {
+    hex::encode(bincode::serialize(value).expect("Failed serialization."))
+}
+
+/* Checks that a document is in a schema version this release reads. Documents from
+before versioning have no version field, and read as version 1.
+@param value: the document */
+pub fn check_version(value: &Value) -> Result<(), String> {
+    match value.get(VERSION_FIELD) {
+        None => Ok(()),
+        Some(version) => match version.as_u64() {
+            Some(version) if version <= JSON_SCHEMA_VERSION => Ok(()),
+            Some(version) => Err(format!("document uses JSON schema version {}, but this release reads up to {}", version, JSON_SCHEMA_VERSION)),
+            None => Err(format!("'{}' is not a number", VERSION_FIELD)),
+        },
+    }
+}
+
+/* Hex-encodes a list of hashes. */
+pub fn hex_list(hashes: &[Sha256Hash]) -> Value {
+    json!(hashes.iter().map(hex::encode).collect::<Vec<_>>())
+}
+
+/* An entry: its id, content type and data in hex, plus the data as "text", or as
+"json" if it parses, when its content type says it is textual.
+@param entry: the entry */
+pub fn entry_json(entry: &LogEntry) -> Value {
+    let mut value = json!({
+        "id": entry.id.to_string(),
+        "content_type": entry.content_type.as_ref().map(ContentType::to_string),
+        "data": hex::encode(&entry.data),
+    });
+    if let Some(content_type) = entry.content_type.as_ref().filter(|content_type| content_type.is_text()) {
+        match serde_json::from_slice::<Value>(&entry.data) {
+            Ok(parsed) if content_type.is_json() => value["json"] = parsed,
+            _ => value["text"] = json!(String::from_utf8_lossy(&entry.data)),
+        }
+    }
+    versioned(value)
+}
+
+/* A block with the signatures that notarized it; "entry" is the decoded entry, if the
+block holds one.
+@param block: the block
+@param signatures: its notarization signatures */
+pub fn block_json(block: &Block, signatures: &[Signature]) -> Value {
+    versioned(json!({
+        "height": block.height,
+        "epoch": block.epoch,
+        "hash": hex::encode(block.hash),
+        "parent_hash": hex::encode(block.parent_hash),
+        "nonce": block.nonce,
+        "data": hex::encode(&block.data),
+        "entry": LogEntry::deserialize(&block.data).map(|entry| entry_json(&entry)),
+        "signatures": signatures.iter().map(hex_bincode).collect::<Vec<_>>(),
+    }))
+}
+
+/* A signed tree head (RFC 6962 get-sth fields, plus the signer and its key). */
+pub fn sth_json(sth: &SignedTreeHead, public_key: &PublicKey) -> Value {
+    versioned(json!({
+        "tree_size": sth.tree_size,
+        "timestamp": sth.timestamp_ms,
+        "sha256_root_hash": hex::encode(sth.root_hash),
+        "signer": sth.signer,
+        "tree_head_signature": hex_bincode(&sth.signature),
+        // Not in RFC 6962; lets auditors check the signature without joining the network
+        "public_key": hex_bincode(public_key),
+    }))
+}
+
+/* A signed promise to include an entry within the maximum merge delay. */
+pub fn receipt_json(promise: &InclusionPromise) -> Value {
+    versioned(json!({
+        "id": promise.entry_id.to_string(),
+        "leaf_hash": hex::encode(promise.leaf_hash),
+        "timestamp": promise.timestamp_ms,
+        "max_merge_delay": promise.max_merge_delay_ms,
+        "signer": promise.signer,
+        "signature": hex_bincode(&promise.signature),
+    }))
+}
+
+pub fn inclusion_json(proof: &AuditPath) -> Value {
+    versioned(json!({
+        "leaf_index": proof.leaf_index,
+        "tree_size": proof.tree_size,
+        "audit_path": hex_list(&proof.path),
+    }))
+}
+
+pub fn consistency_json(proof: &ConsistencyProof) -> Value {
+    versioned(json!({
+        "first": proof.old_size,
+        "second": proof.new_size,
+        "consistency": hex_list(&proof.path),
+    }))
+}
+
+/* Everything needed to check an entry's inclusion offline.
+@param entry_id: the entry's id
+@param entry: the entry's bytes, as stored in its block (the Merkle leaf input)
+@param inclusion: its audit path and tree head
+@param public_key: key of the node that signed the tree head */
+pub fn bundle_json(entry_id: &EntryId, entry: &[u8], inclusion: &InclusionProof, public_key: &PublicKey) -> Value {
+    versioned(json!({
+        "id": entry_id.to_string(),
+        "leaf_input": hex::encode(entry),
+        "leaf_index": inclusion.proof.leaf_index,
+        "tree_size": inclusion.proof.tree_size,
+        "audit_path": hex_list(&inclusion.proof.path),
+        "sth": sth_json(&inclusion.sth, public_key),
+    }))
+}
+
+/* A finalized roster change and the certificate that approved it. "public_key" is set
+for joins and key rotations, "weight" for joins and weight changes.
+@param record: the change and its block
+@param approvers: validators whose votes are in the certificate (see NotarizationCert::signers) */
+pub fn roster_json(record: &RosterRecord, approvers: &[String]) -> Value {
+    let (public_key, weight) = match &record.change {
+        RosterChange::Join { public_key, weight, .. } => (Some(hex_bincode(public_key)), Some(*weight)),
+        RosterChange::Retire { .. } => (None, None),
+        RosterChange::RotateKey { public_key, .. } => (Some(hex_bincode(public_key)), None),
+        RosterChange::SetWeight { weight, .. } => (None, Some(*weight)),
+    };
+    versioned(json!({
+        "kind": record.change.kind(),
+        "validator": record.change.validator(),
+        "public_key": public_key,
+        "weight": weight,
+        "id": record.entry_id.to_string(),
+        "height": record.block.height,
+        "epoch": record.block.epoch,
+        "block_hash": hex::encode(record.block.hash),
+        "approvers": approvers,
+        "signatures": record.cert.signatures.iter().map(hex_bincode).collect::<Vec<_>>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_documents_are_versioned_and_render_entries() {
+        let mut entry = LogEntry::new(br#"{"name": "h1"}"#.to_vec());
+        assert_eq!(entry_json(&entry)["content_type"], Value::Null);
+        assert!(entry_json(&entry).get("json").is_none());
+        entry.content_type = Some("application/json".parse().unwrap());
+        assert_eq!(entry_json(&entry)["json"]["name"], "h1");
+        entry.content_type = Some("text/plain; charset=utf-8".parse().unwrap());
+        assert_eq!(entry_json(&entry)["text"], r#"{"name": "h1"}"#);
+        assert_eq!(entry_json(&entry)["data"], hex::encode(&entry.data));
+
+        let block = Block::new(3, [1; 32], entry.serialize(), 2, 0);
+        let rendered = block_json(&block, &[]);
+        assert_eq!(rendered[VERSION_FIELD], JSON_SCHEMA_VERSION);
+        assert_eq!(rendered["entry"]["id"], entry.id.to_string());
+        assert_eq!(rendered["hash"], hex::encode(block.hash));
+        assert_eq!(block_json(&Block::new(3, [1; 32], Vec::new(), 2, 0), &[])["entry"], Value::Null);
+
+        assert_eq!(check_version(&rendered), Ok(()));
+        assert_eq!(check_version(&json!({ "tree_size": 3 })), Ok(()));
+        assert!(check_version(&json!({ "schema_version": JSON_SCHEMA_VERSION + 1 })).is_err());
+    }
+}
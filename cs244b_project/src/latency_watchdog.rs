@@ -0,0 +1,234 @@
+/* Latency watchdog: checks that the epoch length leaves enough time to notarize a block.
+   Within an epoch the leader waits a moment, proposes, and every validator votes; the
+   block is notarized once a quorum of votes has reached us. Two things can make that
+   budget infeasible:
+   - at startup, a configuration that can't work: an epoch no longer than the leader's
+     proposal delay, or shorter than the gossip heartbeat (messages that miss the
+     gossipsub mesh are only recovered on heartbeats, so they would arrive epochs late)
+   - at runtime, a network slower than the configuration assumes: the watchdog measures
+     how long after the start of each epoch its block is notarized, and warns when most
+     epochs use up too much of their length
+   Both come with suggested values. Epochs without a proposal (e.g. a crashed leader)
+   say nothing about latency and are not counted. */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+// Epochs whose notarization latency is kept
+pub const LATENCY_WINDOW: usize = 50;
+// Epochs measured before the watchdog judges the network
+const MIN_SAMPLES: usize = 10;
+// Latency (as a share of the epoch length) that most epochs should stay under
+const BUDGET_SHARE: f64 = 0.75;
+// Epochs between repeated warnings
+const WARNING_INTERVAL: u64 = 50;
+
+/* A configuration that can't meet its latency budget. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetError {
+    NoTimeToVote { epoch_length: Duration, proposal_delay: Duration },
+    SlowerGossip { epoch_length: Duration, heartbeat: Duration },
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetError::NoTimeToVote { epoch_length, proposal_delay } => write!(
+                f,
+                "epochs of {:?} end before the leader proposes ({:?} in); use --epoch-length {} or more",
+                epoch_length,
+                proposal_delay,
+                suggested_epoch_secs(*proposal_delay * 2)
+            ),
+            BudgetError::SlowerGossip { epoch_length, heartbeat } => write!(
+                f,
+                "epochs of {:?} are shorter than the gossip heartbeat ({:?}), so messages that miss the mesh arrive epochs late; use --epoch-length {} or more",
+                epoch_length,
+                heartbeat,
+                suggested_epoch_secs(*heartbeat)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BudgetError {}
+
+/* Whole seconds (as --epoch-length takes them) covering a duration. */
+fn suggested_epoch_secs(duration: Duration) -> u64 {
+    (duration.as_millis() as u64).div_ceil(1000).max(1)
+}
+
+/* Checks that a configuration can notarize blocks in time at all.
+@param epoch_length: the configured epoch length
+@param heartbeat: the gossipsub heartbeat interval
+@param proposal_delay: how long into an epoch the leader waits before proposing */
+pub fn check_budget(epoch_length: Duration, heartbeat: Duration, proposal_delay: Duration) -> Result<(), BudgetError> {
+    if epoch_length <= proposal_delay {
+        return Err(BudgetError::NoTimeToVote { epoch_length, proposal_delay });
+    }
+    if epoch_length < heartbeat {
+        return Err(BudgetError::SlowerGossip { epoch_length, heartbeat });
+    }
+    Ok(())
+}
+
+/* Measured latency that doesn't fit the epoch length. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyWarning {
+    pub epoch_length: Duration,
+    pub latency: Duration, // 90th percentile over the recent epochs
+    pub samples: usize,
+    pub suggested_epoch_secs: u64,
+}
+
+impl fmt::Display for LatencyWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "blocks take {:?} to notarize (90th percentile over {} epochs), more than {:.0}% of the {:?} epoch; consider --epoch-length {}",
+            self.latency,
+            self.samples,
+            BUDGET_SHARE * 100.0,
+            self.epoch_length,
+            self.suggested_epoch_secs
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct LatencyWatchdog {
+    epoch_length: Duration,
+    heartbeat: Duration,
+    // The epoch being measured, and when it started (ms, as clock::unix_time_ms)
+    epoch: u64,
+    started_ms: u64,
+    notarized: bool,
+    // Notarization latency of recent epochs; epochs that never notarized count as the full epoch
+    samples: VecDeque<Duration>,
+    last_warning_epoch: Option<u64>,
+}
+
+impl LatencyWatchdog {
+    /* @param epoch_length: the configured epoch length
+    @param heartbeat: the gossipsub heartbeat interval */
+    pub fn new(epoch_length: Duration, heartbeat: Duration) -> Self {
+        Self {
+            epoch_length,
+            heartbeat,
+            epoch: 0,
+            started_ms: 0,
+            notarized: false,
+            samples: VecDeque::new(),
+            last_warning_epoch: None,
+        }
+    }
+
+    /* A new epoch started; closes the measurement of the previous one.
+    @param epoch: the new epoch
+    @param now_ms: the current time
+    @param proposal_seen: whether the previous epoch had a proposal */
+    pub fn epoch_started(&mut self, epoch: u64, now_ms: u64, proposal_seen: bool) {
+        if self.epoch > 0 && proposal_seen && !self.notarized {
+            self.record(self.epoch_length);
+        }
+        self.epoch = epoch;
+        self.started_ms = now_ms;
+        self.notarized = false;
+    }
+
+    /* A block was notarized; measures it if it is the current epoch's first.
+    @param block_epoch: the block's epoch
+    @param now_ms: the current time */
+    pub fn notarized(&mut self, block_epoch: u64, now_ms: u64) {
+        if block_epoch != self.epoch || self.notarized || self.epoch == 0 {
+            return;
+        }
+        self.notarized = true;
+        self.record(Duration::from_millis(now_ms.saturating_sub(self.started_ms)));
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() == LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /* 90th percentile of the recent notarization latencies. */
+    pub fn latency(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        Some(sorted[(sorted.len() * 9 / 10).min(sorted.len() - 1)])
+    }
+
+    /* Returns a warning if recent epochs ran over budget (at most once every
+    WARNING_INTERVAL epochs).
+    @param epoch: the current epoch */
+    pub fn check(&mut self, epoch: u64) -> Option<LatencyWarning> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        if self.last_warning_epoch.is_some_and(|last| epoch < last + WARNING_INTERVAL) {
+            return None;
+        }
+        let latency = self.latency()?;
+        if latency.as_secs_f64() <= self.epoch_length.as_secs_f64() * BUDGET_SHARE {
+            return None;
+        }
+        self.last_warning_epoch = Some(epoch);
+        // Leave the same headroom the budget asks for, and never go under the heartbeat
+        let needed = Duration::from_secs_f64(latency.as_secs_f64() / BUDGET_SHARE).max(self.heartbeat);
+        Some(LatencyWarning {
+            epoch_length: self.epoch_length,
+            latency,
+            samples: self.samples.len(),
+            suggested_epoch_secs: suggested_epoch_secs(needed).max(suggested_epoch_secs(self.epoch_length) + 1),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_checks_and_warnings() {
+        let heartbeat = Duration::from_secs(10);
+        let delay = Duration::from_millis(100);
+        assert_eq!(check_budget(Duration::from_secs(10), heartbeat, delay), Ok(()));
+        let error = check_budget(Duration::from_secs(1), heartbeat, delay).unwrap_err();
+        assert!(matches!(error, BudgetError::SlowerGossip { .. }));
+        assert!(error.to_string().ends_with("--epoch-length 10 or more"));
+        assert!(matches!(
+            check_budget(Duration::from_millis(100), Duration::from_millis(50), delay),
+            Err(BudgetError::NoTimeToVote { .. })
+        ));
+
+        // Blocks notarized 2s into 10s epochs are fine
+        let mut watchdog = LatencyWatchdog::new(Duration::from_secs(10), heartbeat);
+        for epoch in 1..=20 {
+            watchdog.epoch_started(epoch, epoch * 10_000, true);
+            watchdog.notarized(epoch, epoch * 10_000 + 2_000);
+            watchdog.notarized(epoch, epoch * 10_000 + 9_000); // only the first counts
+        }
+        assert_eq!(watchdog.latency(), Some(Duration::from_secs(2)));
+        assert_eq!(watchdog.check(20), None);
+
+        // Epochs that don't notarize at all count as over budget; ones without a proposal don't count
+        for epoch in 21..=40 {
+            watchdog.epoch_started(epoch, epoch * 10_000, epoch != 22);
+            watchdog.notarized(epoch - 1, epoch * 10_000 + 1_000);
+        }
+        watchdog.epoch_started(41, 410_000, true);
+        assert_eq!(watchdog.samples.len(), 39);
+        let warning = watchdog.check(41).unwrap();
+        assert_eq!(warning.latency, Duration::from_secs(10));
+        assert_eq!(warning.suggested_epoch_secs, 14);
+        // Not repeated right away
+        assert_eq!(watchdog.check(42), None);
+    }
+}
@@ -0,0 +1,177 @@
+/* Epoch leader selection.
+   The uniform schedule picks each epoch's leader by hashing the epoch number. In
+   geo-distributed deployments that can hand several consecutive epochs to validators in
+   the same far-away region, so every proposal in a row pays the same long propagation
+   delay. The region-aware schedule instead rotates through the validators in an order
+   that interleaves regions, so consecutive leaders share a region only when one region
+   holds more than half of the validators.
+   Both schedules depend only on the epoch, the roster and the region labels, so every
+   node (and any auditor) computes the same leader; all nodes must use the same labels.
+   That also lets anyone watching the network work out who leads every future epoch and
+   flood that validator just before its turn. The secret schedule hashes the epoch under
+   a key derived from a secret the validators share (the roster channel's), so leaders
+   are as evenly spread as with the uniform schedule, but only validators can tell who
+   leads next. It hides the schedule from outsiders, not from a validator gone rogue,
+   and anyone who learns the secret learns the schedule. */
+
+use crate::utils::crypto::Sha256;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeaderScheduleKind {
+    #[default]
+    Uniform,
+    RegionAware,
+    Secret,
+}
+
+impl FromStr for LeaderScheduleKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uniform" => Ok(LeaderScheduleKind::Uniform),
+            "region-aware" => Ok(LeaderScheduleKind::RegionAware),
+            "secret" => Ok(LeaderScheduleKind::Secret),
+            _ => Err(format!("unknown leader schedule: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeaderSchedule {
+    kind: LeaderScheduleKind,
+    regions: HashMap<String, String>, // validator name -> region label
+    key: Option<[u8; 32]>,            // for the secret schedule
+}
+
+// HKDF info for the secret schedule's key
+const KEY_INFO: &[u8] = b"streamlet leader election v1";
+
+impl LeaderSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_kind(&mut self, kind: LeaderScheduleKind) {
+        self.kind = kind;
+    }
+
+    /* Labels a validator with its region (unlabeled validators share one unnamed region). */
+    pub fn set_region(&mut self, validator: &str, region: &str) {
+        self.regions.insert(validator.to_string(), region.to_string());
+    }
+
+    /* Keys the secret schedule. All validators must use the same secret.
+    @param secret: secret shared by the validators out of band */
+    pub fn set_secret(&mut self, secret: &[u8]) {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        self.key = Some(key);
+    }
+
+    pub fn region_of(&self, validator: &str) -> &str {
+        self.regions.get(validator).map(String::as_str).unwrap_or("")
+    }
+
+    /* Leader of the given epoch.
+    @param epoch: the epoch
+    @param validators: every validator's name, sorted (identical on every node) */
+    pub fn leader<'a>(&self, epoch: u64, validators: &'a [String]) -> &'a String {
+        match self.kind {
+            LeaderScheduleKind::Uniform => {
+                let mut hasher = DefaultHasher::new();
+                hasher.write_u64(epoch);
+                &validators[hasher.finish() as usize % validators.len()]
+            }
+            LeaderScheduleKind::RegionAware => {
+                let rotation = self.rotation(validators);
+                &validators[rotation[(epoch % rotation.len() as u64) as usize]]
+            }
+            LeaderScheduleKind::Secret => {
+                let key = self.key.as_ref().expect("the secret leader schedule needs a secret");
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+                mac.update(&epoch.to_le_bytes());
+                let draw = u64::from_le_bytes(mac.finalize().into_bytes()[..8].try_into().expect("8-byte slice"));
+                &validators[(draw % validators.len() as u64) as usize]
+            }
+        }
+    }
+
+    // One cycle of the region-aware rotation, as indexes into validators. Every validator
+    // appears once. Regions, largest first, fill the even slots and then the odd ones, so
+    // two neighbouring slots (including last and first) only share a region when that
+    // region holds more than half of the validators.
+    fn rotation(&self, validators: &[String]) -> Vec<usize> {
+        let mut members: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, name) in validators.iter().enumerate() {
+            members.entry(self.region_of(name)).or_default().push(i);
+        }
+        let mut regions: Vec<Vec<usize>> = members.into_values().collect();
+        // Stable sort: equal-sized regions stay in name order
+        regions.sort_by_key(|members| std::cmp::Reverse(members.len()));
+
+        let n = validators.len();
+        let slots = (0..n).step_by(2).chain((1..n).step_by(2));
+        let mut rotation = vec![0; n];
+        for (slot, validator) in slots.zip(regions.into_iter().flatten()) {
+            rotation[slot] = validator;
+        }
+        rotation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_aware_schedule_spreads_regions() {
+        let validators: Vec<String> = (1..=6).map(|i| format!("h{}", i)).collect();
+        let mut schedule = LeaderSchedule::new();
+        for (name, region) in [("h1", "us"), ("h2", "us"), ("h3", "us"), ("h4", "eu"), ("h5", "eu"), ("h6", "ap")] {
+            schedule.set_region(name, region);
+        }
+        schedule.set_kind(LeaderScheduleKind::RegionAware);
+
+        let leaders: Vec<&String> = (0..60).map(|epoch| schedule.leader(epoch, &validators)).collect();
+        // Everyone leads once per cycle, and no two consecutive leaders share a region
+        // (including across cycle boundaries)
+        let mut cycle = leaders[..6].to_vec();
+        cycle.sort();
+        assert_eq!(cycle, validators.iter().collect::<Vec<_>>());
+        for pair in leaders.windows(2) {
+            assert_ne!(schedule.region_of(pair[0]), schedule.region_of(pair[1]));
+        }
+
+        // Deterministic across instances
+        let mut other = LeaderSchedule::new();
+        for name in &validators {
+            other.set_region(name, schedule.region_of(name));
+        }
+        other.set_kind(LeaderScheduleKind::RegionAware);
+        assert!((0..60).all(|epoch| other.leader(epoch, &validators) == leaders[epoch as usize]));
+    }
+
+    #[test]
+    fn test_secret_schedule_depends_on_the_secret() {
+        let validators: Vec<String> = (1..=4).map(|i| format!("h{}", i)).collect();
+        let schedule = |secret: &[u8]| {
+            let mut schedule = LeaderSchedule::new();
+            schedule.set_kind(LeaderScheduleKind::Secret);
+            schedule.set_secret(secret);
+            (0..200).map(|epoch| schedule.leader(epoch, &validators).clone()).collect::<Vec<String>>()
+        };
+        let leaders = schedule(b"shared");
+        assert_eq!(schedule(b"shared"), leaders);
+        assert_ne!(schedule(b"guessed"), leaders);
+        // Everyone gets a turn
+        assert!(validators.iter().all(|name| leaders.contains(name)));
+    }
+}
@@ -1,58 +1,270 @@
+mod adaptive_epoch;
+mod alerts;
 mod app;
+pub mod auditor;
 mod blockchain;
+mod builder;
+mod callback;
+mod control_socket;
+mod dedup;
+mod epoch_sync;
+mod error;
+mod genesis;
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)] // every RPC's error is a tonic::Status
+pub mod grpc;
+#[cfg(test)]
+mod harness;
+mod health;
+pub mod http_api;
+pub mod json_schema;
+mod latency_watchdog;
+mod leader_schedule;
+mod maintenance;
+pub mod monitor;
+mod mempool;
 mod messages;
 mod network;
+mod performance;
+mod quarantine;
+mod quorum;
+mod read_cache;
+pub mod relay;
+mod replay;
+mod roster;
+mod self_audit;
+mod shutdown;
+mod signature_cache;
+mod sink;
+mod static_roster;
+mod submitters;
+pub mod telemetry;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod upgrade;
 mod utils;
+mod verify_budget;
+mod verify_pool;
+mod vote_analysis;
 
 use itertools::Itertools;
 use rand::Rng;
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
-use std::hash::Hasher;
-use tokio::sync::{Mutex};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::env;
 use bincode::serialize;
 use std::fs;
 
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
+use tracing::{field, info_span, Span};
+use serde_json::json;
 use std::time::Duration;
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
     select, 
     sync::{mpsc, watch},
-    time::sleep,
 };
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio::net::TcpListener;
 
 pub use app::app_interface::*;
-pub use blockchain::{Block, BlockchainManager, Chain, LocalChain, SignedBlock};
-pub use messages::{Message, MessageKind, MessagePayload};
+use callback::CallbackUrl;
+use control_socket::ControlSocket;
+use http_api::{ApiCall, ApiError, ApiRequest, ApiResponse};
+use monitor::{Alert, Monitor};
+use alerts::StallWatch;
+use health::Health;
+use epoch_sync::EpochSync;
+use verify_pool::{Verified, VerifyJob, VerifyPool};
+pub use epoch_sync::{EpochPosition, EPOCH_SYNC_INTERVAL, EPOCH_SYNC_TOLERANCE};
+pub use error::StreamletError;
+pub use genesis::{GenesisDocument, GenesisValidator};
+pub use shutdown::ShutdownHandle;
+pub use builder::{KeySource, StreamletBuilder};
+pub use quorum::QuorumRule;
+pub use sink::{FinalizationSink, WebhookSink};
+pub use static_roster::{RosterPeer, StaticRoster};
+pub use submitters::{SignedSubmission, SubmitterAcl};
+#[cfg(feature = "kafka")]
+pub use sink::KafkaSink;
+use latency_watchdog::LatencyWatchdog;
+use quarantine::{Missing, Quarantine};
+use read_cache::ReadCache;
+use dedup::SeenMessages;
+use signature_cache::SignatureCache;
+use replay::ReplayGuard;
+use verify_budget::VerificationBudget;
+pub use blockchain::{
+    leaf_hash, verify_consistency, verify_inclusion, verify_leaf_inclusion, AuditPath, ChainExport, ChainId, ChainSnapshot, ConsistencyProof, ExportFormat, InclusionPromise, InclusionProof, TreeHeadUpdate, Block, BlockchainManager, Chain, ChainStore, EntryId, EntryIdFormat, LocalChain, LogEntry,
+    MerkleTree, NotarizationCert, PayloadKey, SchemaStatus, SignedBlock, SignedTreeHead, SledStore, StoreError, VoteJournal, SCHEMA_VERSION,
+};
+pub use adaptive_epoch::{AdaptiveEpoch, ADAPT_DELAY, ADAPT_WINDOW};
+pub use alerts::{NodeAlert, Severity, STALL_EPOCHS};
+pub use latency_watchdog::{BudgetError, LatencyWarning};
+pub use leader_schedule::{LeaderSchedule, LeaderScheduleKind};
+pub use maintenance::{MaintenanceSchedule, MaintenanceWindow, MAX_MAINTENANCE_EPOCHS, MIN_MAINTENANCE_NOTICE};
+pub use mempool::{Mempool, PriorityClass};
+pub use performance::{PerformanceLog, PerformanceReport, ReportPeriod};
+pub use read_cache::{CacheStats, ReadCacheConfig, DEFAULT_DISK_ITEMS, DEFAULT_MEMORY_ITEMS};
+pub use replay::{Replay, DEFAULT_REPLAY_WINDOW};
+pub use messages::{ChainSyncRequest, Message, MessageKind, MessagePayload};
+pub use messages::wire;
+pub use messages::envelope::{self, SignedEnvelope};
+pub use network::codec::{decode_frame, Codec, CodecError};
 pub use network::peer_init;
 pub use network::peer_init::PeerAdvertisement;
-pub use network::NetworkStack;
+pub use network::roster_channel::{RosterChannel, SealedEnvelope, ROSTER_TOPIC};
+pub use network::nat::{NatConfig, NatStatus};
+pub use network::peer_score::{PeerSeverity, BAN_DURATION, BAN_SCORE};
+pub use network::{Multiaddr, NetworkEvent, NetworkStack, PeerId, EVENT_QUEUE_CAPACITY, GOSSIP_HEARTBEAT};
+pub use self_audit::{Divergence, SELF_AUDIT_INTERVAL};
+pub use roster::{RosterChange, RosterHistory, RosterMember, RosterRecord, DEFAULT_WEIGHT, ROSTER_ACTIVATION_DELAY};
+pub use upgrade::{ProtocolUpgrade, UpgradeSchedule, MIN_UPGRADE_NOTICE, PROTOCOL_VERSION};
+pub use utils::clock;
+pub use utils::keystore::{self, KeystoreError};
 pub use utils::crypto::*;
+pub use vote_analysis::{Anomaly, AnomalyKind, VoteAnalyzer};
 
 pub struct StreamletInstance {
     pub id: u32,
     pub name: String,
     expected_peer_count: usize,
     blockchain_manager: BlockchainManager,
-    pending_transactions: VecDeque<Vec<u8>>,
-    keypair: Keypair,
+    pending_transactions: Mempool,
+    // Entries taken over the HTTP API, with their client's signature if they came with
+    // one, until they are passed on to the next leader (see forward_to_leader)
+    leader_forwards: Vec<(LogEntry, Option<SignedSubmission>)>,
+    signer: Arc<dyn Signer + Send + Sync>,
+    signature_scheme: SignatureScheme,
     public_keys: HashMap<String, PublicKey>,
     sorted_peer_names: Vec<String>,
     seen_block_this_epoch: Option<[u8; 32]>,
-    sigs_on_seen_block_this_epoch: Vec<Signature>,
     epoch_of_last_published_block: u64,
     // Solely for demoability
     pub compromise_type: CompromiseType,
     // Number of times as leader for marking when to publish / export to local log
     pub leader_count: u64,
+    // Epochs whose leader didn't propose in time, and the last of them (we don't vote in it)
+    pub empty_epoch_count: u64,
+    empty_epoch: Option<u64>,
+    // Time between epoch ticks
+    epoch_length: Duration,
+    // Tag of our outstanding chain sync request, and the epoch it was sent in
+    chain_sync_tag: Option<u32>,
+    chain_sync_epoch: Option<u64>,
+    // Our finalized height when we last asked for a snapshot; no second one until it moves
+    snapshot_sync_from: Option<u64>,
+    // Peers we have a connection to; after being cut off from all of them, we sync at the next epoch
+    connected_peers: HashSet<PeerId>,
+    sync_after_reconnect: bool,
+    // How entry ids are rendered in logs
+    entry_id_format: EntryIdFormat,
+    // Durable record of our votes (None: no data directory, memory only)
+    vote_journal: Option<VoteJournal>,
+    // Encrypted validators-only topic (None: disabled)
+    roster_channel: Option<RosterChannel>,
+    latest_sth: Option<SignedTreeHead>,
+    vote_analyzer: VoteAnalyzer,
+    control_socket: Option<std::path::PathBuf>,
+    http_api_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    leader_schedule: LeaderSchedule,
+    max_merge_delay: Option<Duration>,
+    // Promises for entries that aren't finalized yet
+    outstanding_promises: HashMap<EntryId, InclusionPromise>,
+    // Tree size of the last head published on the STH topic
+    published_tree_size: u64,
+    // Other nodes' tree heads seen on the STH topic, checked like a monitor would
+    sth_monitor: Monitor,
+    // The deployment's genesis document, if it was started from one
+    genesis: Option<GenesisDocument>,
+    // The fixed validators of a permissioned deployment, if this is one (see static_roster)
+    static_roster: Option<StaticRoster>,
+    // The message being handled, if its signatures were matched on the verification pool
+    verified: Option<Verified>,
+    // Who may submit entries, if not anyone (see submitters)
+    submitters: Option<SubmitterAcl>,
+    // Seals entries for a private log, if this is one (see blockchain::sealed)
+    payload_key: Option<PayloadKey>,
+    // Works out epoch lengths from the finalized chain, if they adapt (see adaptive_epoch); shared with the epoch timer
+    adaptive_epoch: Option<Arc<Mutex<AdaptiveEpoch>>>,
+    // Other validators' epoch clocks, relative to ours (see epoch_sync)
+    epoch_sync: EpochSync,
+    // Protocol versions announced in finalized blocks, and the ones our operator approved
+    upgrades: UpgradeSchedule,
+    // Newest protocol version we implement (PROTOCOL_VERSION; tests lower it to stand in for an older release)
+    supported_version: u32,
+    // Planned downtime of validators, from finalized announcements
+    maintenance: MaintenanceSchedule,
+    // Roster changes from finalized blocks with their certificates, and the ones our operator approved
+    roster_history: RosterHistory,
+    // Where our key is kept, with its passphrase (None: generated for this run only)
+    keyfile: Option<(PathBuf, Vec<u8>)>,
+    // Peer discovery is done: from then on, only roster changes in the log add validators
+    discovered: bool,
+    // The key we rotate to once our rotation is finalized
+    next_key: Option<Keypair>,
+    // Latest epoch in which we saw (or made) the leader's proposal
+    last_proposal_epoch: u64,
+    // Key for signing proof pushes (None: callbacks disabled), and where to push each entry's proof
+    callback_secret: Option<Vec<u8>>,
+    callbacks: HashMap<EntryId, CallbackUrl>,
+    // Compression for our outgoing messages
+    codec: Codec,
+    // Messages waiting for a key or block we don't have yet
+    quarantine: Quarantine,
+    // Which deployment our signatures belong to; signed along with every artifact
+    chain_id: ChainId,
+    // Peers to dial at startup (beyond the ones mDNS finds), and a fixed address to listen on
+    bootstrap_peers: Vec<Multiaddr>,
+    listen_addr: Option<Multiaddr>,
+    // Relays to get through NAT with, and whether to be one
+    nat_config: NatConfig,
+    // How long blocks take to notarize, against the epoch length
+    latency_watchdog: LatencyWatchdog,
+    // Our participation, for performance reports; and where to write them periodically
+    performance: PerformanceLog,
+    report_schedule: Option<(ReportPeriod, std::path::PathBuf)>,
+    last_report_ms: u64,
+    // Signatures each peer may make us check per epoch; consensus messages over it wait until we're idle
+    verify_budget: VerificationBudget,
+    // Digests of recently handled network messages, so repeats are dropped unread
+    seen_messages: SeenMessages,
+    // Signatures found valid recently, so copies of a vote gossiped again aren't checked again (shared with the verification pool)
+    signature_cache: Arc<Mutex<SignatureCache>>,
+    // Nonces of recent consensus messages, and how old a message's block may be (see replay)
+    replay_guard: ReplayGuard,
+    // The epoch as of the last epoch tick, for sealing messages (see envelope)
+    current_epoch: u64,
+    // Where alerts go besides the log (see subscribe_alerts), and what tells us consensus stalled
+    alert_subscribers: Vec<mpsc::UnboundedSender<NodeAlert>>,
+    stall_watch: StallWatch,
+    // Whether we start epochs on time and have caught up (see health)
+    health: Health,
+    // Entries and proofs served over the HTTP API, kept apart from the chain store
+    read_cache: ReadCache,
+    // Consensus messages held back by a node compromised to delay them, sent next epoch
+    delayed_messages: Vec<Vec<u8>>,
+    // Span of the current epoch, which everything handled in it is logged in (see telemetry)
+    epoch_span: Span,
+    // Makes run() return (see shutdown)
+    shutdown: ShutdownHandle,
+    // Gossipsub topic for consensus messages
+    topic: String,
+    // Votes needed to notarize a block (see quorum)
+    quorum_rule: QuorumRule,
+    role: Role,
+    // Don't read commands from stdin (see set_headless)
+    headless: bool,
+    // Where finalized blocks go (see subscribe_finalized)
+    finalized_subscribers: Vec<mpsc::UnboundedSender<SignedBlock>>,
+    finalization_sinks: Vec<Box<dyn FinalizationSink>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CompromiseType {
     WrongParentHash, // Implemented behavior
     NoPropose, // Implemented behavior
@@ -61,61 +273,226 @@ pub enum CompromiseType {
     EarlyEpoch, // Implemented behavior
     LateEpoch, // Implemented behavior
     NoCompromise,
+    Equivocate, // Proposes two blocks when leader, and votes for every proposal
+    VoteInvalid, // Votes for any proposal, valid or not
+    DelayMessages, // Sends its proposals and votes an epoch late
+}
+
+impl std::str::FromStr for CompromiseType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrong-parent-hash" => Ok(CompromiseType::WrongParentHash),
+            "no-propose" => Ok(CompromiseType::NoPropose),
+            "no-vote" => Ok(CompromiseType::NoVote),
+            "non-leader-propose" => Ok(CompromiseType::NonLeaderPropose),
+            "early-epoch" => Ok(CompromiseType::EarlyEpoch),
+            "late-epoch" => Ok(CompromiseType::LateEpoch),
+            "none" => Ok(CompromiseType::NoCompromise),
+            "equivocate" => Ok(CompromiseType::Equivocate),
+            "vote-invalid" => Ok(CompromiseType::VoteInvalid),
+            "delay-messages" => Ok(CompromiseType::DelayMessages),
+            _ => Err(format!("unknown byzantine behavior: {}", s)),
+        }
+    }
+}
+
+/* What a node does in the protocol. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    #[default]
+    Validator, // proposes when leader, and votes
+    Observer,  // follows the chain without proposing or voting
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "validator" => Ok(Role::Validator),
+            "observer" => Ok(Role::Observer),
+            _ => Err(format!("unknown role: {}", s)),
+        }
+    }
 }
 
 enum EventType {
     UserInput(String),
     NetworkInput(Vec<u8>),
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
     EpochStart,
+    ProposalTimeout,
     TCPRequestBlock,
     TCPRequestChain,
+    Shutdown,
 }
 
-// Toggle based on number of nodes. 
+// Default epoch length; see set_epoch_length.
 // Should be higher for more nodes s.t. time for finalization. 
 const EPOCH_LENGTH_S: u64 = 10;
 const EPOCH_DELAY_MS: u64 = 100;
+// An epoch whose leader hasn't proposed by 1/PROPOSAL_TIMEOUT_DIVISOR of the way in is empty
+const PROPOSAL_TIMEOUT_DIVISOR: u32 = 2;
 const PUBLISH_RATE: u64 =  10;
+// Most notarized blocks sent in one chain sync response (keeps it under the gossip size limit)
+const CHAIN_SYNC_MAX_BLOCKS: usize = 64;
+// Peers a chain sync request is sent to directly
+const CHAIN_SYNC_PEERS: usize = 3;
+// Blocks a node must be behind by before it asks for a snapshot rather than segments
+const SNAPSHOT_SYNC_GAP: u64 = 4 * CHAIN_SYNC_MAX_BLOCKS as u64;
+// How often (in epochs) voting patterns are checked for anomalies
+const VOTE_ANALYSIS_INTERVAL: u64 = 50;
+// Mempool submitter name for entries added over the HTTP API
+const HTTP_API_SUBMITTER: &str = "http";
+// Default maximum merge delay, in epochs (see set_max_merge_delay)
+const MERGE_DELAY_EPOCHS: u32 = 12;
+// Mempool submitter name for upgrade announcements
+const UPGRADE_SUBMITTER: &str = "upgrade";
+// How often (in epochs) we re-broadcast our latest tree head, even if it hasn't changed
+const STH_GOSSIP_INTERVAL: u64 = 5;
+// Mempool submitter name for our own maintenance announcements
+const MAINTENANCE_SUBMITTER: &str = "maintenance";
+// Mempool submitter name for roster changes
+const ROSTER_SUBMITTER: &str = "roster";
+// How often (in epochs) we walk the DHT again for nodes that joined since
+const DHT_REFRESH_INTERVAL: u64 = 30;
+// How long the node must be idle before it checks a message that went over its sender's budget
+const DEFERRED_IDLE_MS: u64 = 10;
+// How often a headless node advertises itself until peer discovery is done
+const HEADLESS_ADVERTISE_INTERVAL: Duration = Duration::from_secs(2);
+// Epochs between a validator's advertisements once discovery is over
+const READVERTISE_INTERVAL: u64 = 10;
 
 // ==========================
 // === Core Streamlet API ===
 // ==========================
 
 impl StreamletInstance {
-    const STREAMLET_TOPIC: &'static str = "streamlet";
+    // Topic validators gossip consensus messages on
+    pub const STREAMLET_TOPIC: &'static str = "streamlet";
+
+    /* Configures a node step by step (see builder); the initializers below take the
+    few settings every node needs. */
+    pub fn builder() -> StreamletBuilder {
+        StreamletBuilder::default()
+    }
 
     /* Initializer:
     @param my_name: identifying "name" of this node
     @param expected_peer_count: expected number of StreamletInstances running */
     pub fn new(name: String, expected_peer_count: usize) -> Self {
+        StreamletInstance::new_with_scheme(name, expected_peer_count, SignatureScheme::default())
+    }
+
+    /* Initializer for deployments that use a non-default signature scheme.
+    All nodes of a deployment must agree on the scheme; keys and signatures
+    from any other scheme are rejected.
+    @param signature_scheme: the deployment's signature scheme */
+    pub fn new_with_scheme(name: String, expected_peer_count: usize, signature_scheme: SignatureScheme) -> Self {
         // Setup public/private key pair and id
-        let mut csprng = OsRng {};
-        let keypair: Keypair = Keypair::generate(&mut csprng);
-        let pk: PublicKey = keypair.public.clone();
+        let keypair = Keypair::generate(signature_scheme);
+        let pk: PublicKey = keypair.public();
 
         // Build the streamlet instance
         Self {
             id: 0,
-            expected_peer_count: expected_peer_count,
+            expected_peer_count,
             name: name.clone(),
             blockchain_manager: BlockchainManager::new(),
-            pending_transactions: VecDeque::new(),
-            keypair: keypair,
+            pending_transactions: Mempool::new(),
+            leader_forwards: Vec::new(),
+            signer: Arc::new(keypair),
+            signature_scheme,
             public_keys: HashMap::from([(name.clone(), pk)]),
             sorted_peer_names: Vec::new(),
             seen_block_this_epoch: None,
-            sigs_on_seen_block_this_epoch: vec![],
             epoch_of_last_published_block: 0,
             compromise_type: CompromiseType::NoCompromise,
             leader_count: 0,
+            empty_epoch_count: 0,
+            empty_epoch: None,
+            epoch_length: Duration::from_secs(EPOCH_LENGTH_S),
+            chain_sync_tag: None,
+            chain_sync_epoch: None,
+            snapshot_sync_from: None,
+            connected_peers: HashSet::new(),
+            sync_after_reconnect: false,
+            entry_id_format: EntryIdFormat::default(),
+            vote_journal: None,
+            roster_channel: None,
+            latest_sth: None,
+            vote_analyzer: VoteAnalyzer::new(),
+            control_socket: None,
+            http_api_addr: None,
+            grpc_addr: None,
+            websocket_addr: None,
+            leader_schedule: LeaderSchedule::new(),
+            max_merge_delay: None,
+            outstanding_promises: HashMap::new(),
+            published_tree_size: 0,
+            sth_monitor: Monitor::new(ChainId::default()),
+            genesis: None,
+            static_roster: None,
+            verified: None,
+            submitters: None,
+            payload_key: None,
+            adaptive_epoch: None,
+            epoch_sync: EpochSync::new(),
+            upgrades: UpgradeSchedule::new(),
+            supported_version: PROTOCOL_VERSION,
+            maintenance: MaintenanceSchedule::new(),
+            roster_history: RosterHistory::new(),
+            keyfile: None,
+            discovered: false,
+            next_key: None,
+            last_proposal_epoch: 0,
+            callback_secret: None,
+            callbacks: HashMap::new(),
+            codec: Codec::default(),
+            quarantine: Quarantine::default(),
+            chain_id: ChainId::default(),
+            bootstrap_peers: Vec::new(),
+            listen_addr: None,
+            nat_config: NatConfig::default(),
+            latency_watchdog: LatencyWatchdog::new(Duration::from_secs(EPOCH_LENGTH_S), GOSSIP_HEARTBEAT),
+            performance: PerformanceLog::new(),
+            report_schedule: None,
+            last_report_ms: 0,
+            verify_budget: VerificationBudget::default(),
+            seen_messages: SeenMessages::default(),
+            signature_cache: Arc::new(Mutex::new(SignatureCache::default())),
+            replay_guard: ReplayGuard::default(),
+            current_epoch: 0,
+            alert_subscribers: Vec::new(),
+            stall_watch: StallWatch::default(),
+            health: Health::default(),
+            read_cache: ReadCache::default(),
+            delayed_messages: Vec::new(),
+            epoch_span: Span::none(),
+            shutdown: ShutdownHandle::default(),
+            topic: String::from(StreamletInstance::STREAMLET_TOPIC),
+            quorum_rule: QuorumRule::default(),
+            role: Role::default(),
+            headless: false,
+            finalized_subscribers: Vec::new(),
+            finalization_sinks: Vec::new(),
         }
     }
 
     /* Main straemlet event loop.
     1. Intializes networking stack + input channels (e.g. stdin)
     2. Performs peer discovery
-    3. Runs the main event loop */
-    pub async fn run(&mut self) {
+    3. Runs the main event loop
+    Returns once shut down, or with an error if the node can't listen or loses its
+    network stack; anything else that fails is logged and costs only what failed (see error). */
+    pub async fn run(&mut self) -> Result<(), StreamletError> {
+        if let Err(e) = self.check_latency_budget() {
+            warn!("Configuration error: {}", e);
+        }
+        self.last_report_ms = clock::unix_time_ms();
+        tokio::spawn(self.shutdown_handle().on_signal());
+        let shutdown = self.shutdown_handle();
 
         // Share the epoch data here
         let current_epoch_handle = Arc::new(Mutex::new(1));
@@ -124,19 +501,73 @@ impl StreamletInstance {
         // Initialize
         // (1) message queue for the network to send us data
         // (2) message queue for us to receive data from the network
-        let (net_sender, mut receiver) = mpsc::unbounded_channel();
+        let (net_sender, mut receiver) = mpsc::channel(network::EVENT_QUEUE_CAPACITY);
+        // (3) a lane of its own for consensus messages, read first (see network::event_queue)
+        let (priority_sender, mut priority_receiver) = mpsc::channel(network::EVENT_QUEUE_CAPACITY);
 
-        // Initialize the network stack
-        let mut net_stack =
-            network::NetworkStack::new(StreamletInstance::STREAMLET_TOPIC, net_sender).await;
+        // Initialize the network stack, dialing the roster's validators along with the bootstrap peers
+        let mut dial = self.bootstrap_peers.clone();
+        dial.extend(self.static_roster.iter().flat_map(StaticRoster::addrs));
+        let mut net_stack = network::NetworkStack::with_nat(&self.topic, net_sender, &dial, &self.nat_config).await;
+        net_stack.set_codec(self.codec);
+        net_stack.set_priority_lane(priority_sender);
+        if let Some(addr) = &self.listen_addr {
+            net_stack.listen_on(addr).map_err(StreamletError::Io)?;
+        }
 
         // Set up stdin
         let mut stdin = BufReader::new(stdin()).lines();
+        let mut stdin_open = !self.headless;
+        // With no one to type "init", a headless node advertises itself until discovery is done
+        let mut advertise = clock::interval(HEADLESS_ADVERTISE_INTERVAL);
+        // Whether discovery was started ("init", or headless), so advertisements repeat until it ends
+        let mut advertising = false;
+        if self.headless && self.control_socket.is_none() {
+            warn!("Running headless without a control socket; commands can't be given");
+        }
+
+        // Commands from the control socket are handled just like stdin
+        let (command_sender, mut command_recv) = mpsc::unbounded_channel();
+        if let Some(path) = &self.control_socket {
+            let socket = ControlSocket::bind(path)?;
+            tokio::spawn(socket.run(command_sender.clone()));
+        }
+
+        // Requests from the HTTP API and gRPC service (if enabled) are answered by this loop, which owns the chain
+        let (api_sender, mut api_recv) = mpsc::unbounded_channel::<ApiCall>();
+        #[cfg(feature = "grpc")]
+        if let Some(addr) = self.grpc_addr {
+            tokio::spawn(grpc::serve(addr, api_sender.clone()));
+        }
+        #[cfg(not(feature = "grpc"))]
+        if let Some(addr) = self.grpc_addr {
+            warn!("Not serving gRPC on {}; built without the grpc feature", addr);
+        }
+        for finalization_sink in std::mem::take(&mut self.finalization_sinks) {
+            tokio::spawn(sink::run(finalization_sink, self.subscribe_finalized()));
+        }
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = self.websocket_addr {
+            tokio::spawn(websocket::serve(addr, self.subscribe_finalized()));
+        }
+        #[cfg(not(feature = "websocket"))]
+        if let Some(addr) = self.websocket_addr {
+            warn!("Not streaming over WebSocket on {}; built without the websocket feature", addr);
+        }
+        #[cfg(feature = "http-api")]
+        if let Some(addr) = self.http_api_addr {
+            tokio::spawn(http_api::serve(addr, api_sender.clone()));
+        }
+        #[cfg(not(feature = "http-api"))]
+        if let Some(addr) = self.http_api_addr {
+            warn!("Not serving the HTTP API on {}; built without the http-api feature", addr);
+            drop(api_sender);
+        }
         
         // Set up TCP for processing application requests
-        let addr = "127.0.0.1:0".parse::<SocketAddr>().expect("Couldn't get socket addr");
-        let listener = TcpListener::bind(&addr).await.expect("Couldn't create TCP listener");
-        let local_addr = listener.local_addr().expect("Couldn't get local addr");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = TcpListener::bind(&addr).await?;
+        let local_addr = listener.local_addr()?;
         info!("Listening for inbound TCP connection at {}", local_addr);
 
         // Accept create channels for streamlet instance to send / receive messages from the TCP thread
@@ -149,7 +580,10 @@ impl StreamletInstance {
         });
 
         // Set up what we need to initialize the peer discovery protocol
-        let mut peers = peer_init::Peers::new(self.name.clone(), self.keypair.public, self.expected_peer_count);
+        let mut peers = peer_init::Peers::new(self.name.clone(), self.signer.clone(), self.chain_id, self.expected_peer_count);
+        if self.role == Role::Observer {
+            peers.set_observer();
+        }
         net_stack.open_init_channel();
 
         // Setup epoch timer channel
@@ -157,41 +591,151 @@ impl StreamletInstance {
         let (epoch_trigger, mut epoch_recv) = watch::channel("epoch_trigger");
 
         let current_epoch_handle_timer = current_epoch_handle.clone();
+        let epoch_length = self.epoch_length;
+        let adaptive_epoch_timer = self.adaptive_epoch.clone();
+        // When the current epoch started, and moves of the epoch clock (see epoch_sync)
+        let epoch_started_handle = Arc::new(Mutex::new(clock::now()));
+        let epoch_started_handle_timer = epoch_started_handle.clone();
+        let (clock_shift_trigger, mut clock_shift_recv) = watch::channel(0i64);
         // Epoch timer thread
         let vote_this_epoch_handle_timer = vote_this_epoch_handle.clone();
         tokio::spawn(async move {
             // Wait until signaled that peer discovery is done
             let _ = timer_recv.changed().await.is_ok();
+            let length_at = |epoch| match &adaptive_epoch_timer {
+                Some(adaptive) => adaptive.lock().expect("Adaptive epoch lock poisoned").length_at(epoch),
+                None => epoch_length,
+            };
 
             // Epoch timer loop
             loop {
-                sleep(Duration::from_secs(EPOCH_LENGTH_S)).await;
-                let mut current_epoch = current_epoch_handle_timer.lock().await;
-                *current_epoch = *current_epoch + 1;
-                drop(current_epoch);
+                let epoch = *current_epoch_handle_timer.lock().expect("Epoch lock poisoned");
+                let started = *epoch_started_handle_timer.lock().expect("Epoch lock poisoned");
+                tokio::select! {
+                    _ = clock::sleep_until(started + length_at(epoch)) => {
+                        *epoch_started_handle_timer.lock().expect("Epoch lock poisoned") = started + length_at(epoch);
+                        *current_epoch_handle_timer.lock().expect("Epoch lock poisoned") += 1;
+                    }
+                    shifted = clock_shift_recv.changed() => {
+                        if shifted.is_err() {
+                            break;
+                        }
+                        let shift_ms = *clock_shift_recv.borrow();
+                        let (new_epoch, new_started) = epoch_sync::shift_clock(epoch, started, shift_ms, length_at);
+                        *epoch_started_handle_timer.lock().expect("Epoch lock poisoned") = new_started;
+                        // Still in the same epoch; only its end moved
+                        if new_epoch == epoch {
+                            continue;
+                        }
+                        *current_epoch_handle_timer.lock().expect("Epoch lock poisoned") = new_epoch;
+                    }
+                }
                 // Reset along with epoch counter
-                let mut vote_this_epoch = vote_this_epoch_handle_timer.lock().await;
-                *vote_this_epoch = None;
-                drop(vote_this_epoch);
-                epoch_trigger.send("tick!").expect("Timer reciever closed?");
+                *vote_this_epoch_handle_timer.lock().expect("Epoch lock poisoned") = None;
+                // The event loop is gone
+                if epoch_trigger.send("tick!").is_err() {
+                    break;
+                }
             }
         });
+        // Where we are in the epoch clock, for epoch sync reports
+        let epoch_position = |wants_reply| {
+            let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+            let started = *epoch_started_handle.lock().expect("Epoch lock poisoned");
+            EpochPosition::now(epoch, started, wants_reply)
+        };
 
         let app_interface = AppInterface::new(&mut net_stack);
+        if self.roster_channel.is_some() {
+            net_stack.add_topic(ROSTER_TOPIC);
+        }
+        net_stack.add_topic(monitor::STH_TOPIC);
 
         // Main event loop!
+        let mut outcome = Ok(());
+        // When the current epoch's leader must have proposed by (see proposal_timed_out)
+        let mut proposal_deadline: Option<tokio::time::Instant> = None;
+        // Checks consensus messages' signatures off the event loop
+        let mut verify_pool = VerifyPool::new();
         loop {
-            let evt = {
+            self.verified = None;
+            for (what, e) in self.blockchain_manager.take_storage_errors() {
+                self.raise(NodeAlert::StorageFailure { what, error: e.to_string() });
+            }
+            // Messages released from quarantine go before new input
+            let replay = self.quarantine.next_ready().map(|message| EventType::NetworkInput(message.serialize()));
+            // Replayed and deferred messages were charged to their sender when they first arrived
+            let mut charged = replay.is_some();
+            let evt = if replay.is_some() { replay } else {
                 select! {
                     // User input
-                    line = stdin.next_line() => {
-                        let line_data = line.expect("Can't get line").expect("Can't read from stdin");
-                        Some(EventType::UserInput(line_data))
+                    line = stdin.next_line(), if stdin_open => {
+                        match line {
+                            Ok(Some(line_data)) => Some(EventType::UserInput(line_data)),
+                            Err(e) => {
+                                warn!("Can't read stdin ({}); no longer reading commands from it", e);
+                                stdin_open = false;
+                                None
+                            }
+                            Ok(None) => {
+                                // Detached from the terminal; keep running on the network (and control socket)
+                                info!("stdin closed; no longer reading commands from it");
+                                stdin_open = false;
+                                None
+                            }
+                        }
+                    },
+
+                    // Same commands, from the control socket
+                    Some(line) = command_recv.recv() => {
+                        Some(EventType::UserInput(line))
+                    },
+
+                    Some(call) = api_recv.recv() => {
+                        let _ = call.reply.send(self.answer_api_request(call.request));
+                        let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                        self.forward_to_leader(&mut net_stack, epoch);
+                        None
                     },
 
-                    // When the network receives *any* message, it forwards the data to us thru this channel
-                    network_response = receiver.recv() => {
-                        Some(EventType::NetworkInput(network_response.expect("Response doesn't exist.")))
+                    // When the network receives *any* message, it forwards the data to us thru this channel,
+                    // along with peers coming and going; consensus messages come first
+                    network_event = network::recv_prioritized(&mut priority_receiver, &mut receiver) => {
+                        match network_event {
+                            // The network stack is gone: nothing left to run on
+                            None => {
+                                outcome = Err(StreamletError::ChannelClosed("network"));
+                                Some(EventType::Shutdown)
+                            }
+                            Some(NetworkEvent::Message(bytes)) => Some(EventType::NetworkInput(bytes)),
+                            Some(NetworkEvent::PeerConnected(peer)) => Some(EventType::PeerConnected(peer)),
+                            Some(NetworkEvent::PeerDisconnected(peer)) => Some(EventType::PeerDisconnected(peer)),
+                            Some(NetworkEvent::ListenAddr(addr)) => {
+                                info!("Listening on {}", addr);
+                                None
+                            }
+                            Some(NetworkEvent::Nat(status)) => {
+                                match status {
+                                    NatStatus::Private => info!("Peers see this node behind NAT"),
+                                    NatStatus::Public => info!("This node is reachable from the internet"),
+                                    NatStatus::Unknown => {}
+                                }
+                                None
+                            }
+                            Some(NetworkEvent::PeerBanned(peer)) => {
+                                self.raise(NodeAlert::PeerBanned { peer: peer.to_string() });
+                                None
+                            }
+                        }
+                    },
+
+                    // Keep advertising until discovery ends, in case the first one went unheard
+                    _ = advertise.tick(), if (self.headless || advertising) && !self.discovered => {
+                        Some(EventType::UserInput(String::from("init")))
+                    },
+
+                    _ = shutdown.requested() => {
+                        Some(EventType::Shutdown)
                     },
 
                     // One way to model the timer tick
@@ -199,10 +743,29 @@ impl StreamletInstance {
                         Some(EventType::EpochStart)
                     }
 
+                    // The leader had until now to propose
+                    _ = clock::sleep_until(proposal_deadline.unwrap_or_else(clock::now)), if proposal_deadline.is_some() => {
+                        proposal_deadline = None;
+                        Some(EventType::ProposalTimeout)
+                    }
+
+                    // A consensus message whose signatures were checked on the verification pool
+                    Some(done) = verify_pool.next_verified() => {
+                        let bytes = done.bytes.clone();
+                        self.verified = Some(done);
+                        Some(EventType::NetworkInput(bytes))
+                    },
+
                     // Needs to be polled in order to make progress.
                     _ = net_stack.clear_unhandled_event() => {
                         None
                     },
+
+                    // Nothing else to do: check a message that went over its sender's budget
+                    _ = clock::sleep(Duration::from_millis(DEFERRED_IDLE_MS)), if self.verify_budget.has_deferred() => {
+                        charged = true;
+                        self.verify_budget.next_deferred().map(|message| EventType::NetworkInput(message.serialize()))
+                    },
                     
                     // One way to model getting a TCP request
                     _ = tcp_connect_recv.changed() => {
@@ -219,10 +782,14 @@ impl StreamletInstance {
             };
 
             if let Some(event) = evt {
+                let mut in_epoch = self.epoch_span.clone().entered();
                 match event {
                     EventType::UserInput(line) => {
                         if line.starts_with("init") {
-                            fs::create_dir_all(format!("{}/src/tmp", env::current_dir().expect("invalid current directory").display().to_string())).unwrap();
+                            advertising = true;
+                            if let Err(e) = env::current_dir().and_then(|dir| fs::create_dir_all(dir.join("src/tmp"))) {
+                                warn!("Can't create src/tmp for chain exports: {}", e);
+                            }
                             peers.advertise_self(&mut net_stack);
                         }
                         if line.starts_with("end init") || line.starts_with("e i") || line.starts_with("end discovery") || line.starts_with("e d") {
@@ -231,6 +798,52 @@ impl StreamletInstance {
                             self.blockchain_manager.print_notarized_chains();
                         } else if line.starts_with("finalized chain") || line.starts_with("fc") {
                             self.blockchain_manager.print_finalized_chains();
+                        } else if let Some(text) = line.strip_prefix("announce ") {
+                            self.send_roster_notice(&mut net_stack, text);
+                        } else if let Some(text) = line.strip_prefix("submit ") {
+                            self.submit_entry(&mut net_stack, text.as_bytes().to_vec());
+                        } else if line.starts_with("anomalies") {
+                            self.log_vote_anomalies();
+                        } else if let Some(args) = line.strip_prefix("upgrade ") {
+                            let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                            self.announce_upgrade(args, epoch);
+                        } else if let Some(args) = line.strip_prefix("maintenance ") {
+                            let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                            self.announce_maintenance(args, epoch);
+                        } else if line.starts_with("rotate-key") {
+                            self.rotate_key();
+                        } else if let Some(args) = line.strip_prefix("roster") {
+                            self.announce_roster_change(args.trim());
+                        } else if let Some(addr) = line.strip_prefix("dial ") {
+                            match addr.trim().parse::<Multiaddr>() {
+                                Ok(addr) => net_stack.dial(&addr),
+                                Err(e) => warn!("Not a multiaddr ({}): {}", e, addr),
+                            }
+                        } else if let Some(period) = line.strip_prefix("report") {
+                            match period.trim() {
+                                "" => self.log_performance_report(ReportPeriod::Daily),
+                                period => match period.parse() {
+                                    Ok(period) => self.log_performance_report(period),
+                                    Err(e) => warn!("{}", e),
+                                },
+                            }
+                        } else if line.starts_with("sync") {
+                            let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                            self.request_chain_sync(&mut net_stack, epoch);
+                        } else if line.starts_with("topics") {
+                            let mut topics = net_stack.topics();
+                            topics.sort();
+                            println!("Subscribed topics: {}", topics.join(", "));
+                        } else if let Some(args) = line.strip_prefix("export-chain") {
+                            self.export_chain_command(args);
+                        } else if line.starts_with("cache") {
+                            let stats = self.read_cache.stats();
+                            println!(
+                                "Read cache: {} memory hits, {} disk hits, {} misses, {} rejected from disk",
+                                stats.memory_hits, stats.disk_hits, stats.misses, stats.rejected
+                            );
+                            println!("Duplicate messages dropped: {}", self.seen_messages.duplicates());
+                            println!("Signature checks skipped: {}", self.signature_cache.lock().expect("Signature cache lock poisoned").hits());
                         }
 
                         /*
@@ -261,6 +874,18 @@ impl StreamletInstance {
                             info!("Compromised node will propose each epoch even if not the leader.");
                             self.compromise_type = CompromiseType::NonLeaderPropose
                         }
+                        else if line.starts_with("compromise equivocate") || line.starts_with("eq") {
+                            info!("Compromised node will propose two blocks when leader, and vote for every proposal.");
+                            self.compromise_type = CompromiseType::Equivocate
+                        }
+                        else if line.starts_with("compromise vote-invalid") || line.starts_with("vi") {
+                            info!("Compromised node will vote for any proposal, valid or not.");
+                            self.compromise_type = CompromiseType::VoteInvalid
+                        }
+                        else if line.starts_with("compromise delay-messages") || line.starts_with("dm") {
+                            info!("Compromised node will send its proposals and votes an epoch late.");
+                            self.compromise_type = CompromiseType::DelayMessages
+                        }
                         else if line.starts_with("no-compromise") || line.starts_with("c") {
                             info!("Compromised node is no longer compromised.");
                             self.compromise_type = CompromiseType::NoCompromise
@@ -271,43 +896,123 @@ impl StreamletInstance {
                     EventType::TCPRequestChain => {
                         let finalized_chain = self.blockchain_manager.fetch_local_finalized_chain();
                         debug!("Sending chain {} to TCP thread", finalized_chain);
-                        tcp_data_sender.send(serialize(&finalized_chain).expect("Failed to serialize chain")).expect("Failed to send chain...");
+                        match serialize(&finalized_chain) {
+                            Ok(bytes) => {
+                                if tcp_data_sender.send(bytes).is_err() {
+                                    warn!("TCP thread is gone; can't send the chain");
+                                }
+                            }
+                            Err(e) => warn!("Can't serialize chain for the TCP thread: {}", e),
+                        }
                     }
                     EventType::TCPRequestBlock => {
                         let (latest_finalized_block, signatures) = self.get_latest_finalized_block();
-                        let signed_block = SignedBlock {
-                            block: latest_finalized_block,
-                            signatures: signatures,
-                        };
+                        let signed_block = SignedBlock::new(latest_finalized_block, signatures);
                         debug!("Sending block {:?} to TCP thread", signed_block);
-                        tcp_data_sender.send(serialize(&signed_block).expect("Failed to serialize block")).expect("Failed to send block..");
+                        match serialize(&signed_block) {
+                            Ok(bytes) => {
+                                if tcp_data_sender.send(bytes).is_err() {
+                                    warn!("TCP thread is gone; can't send the block");
+                                }
+                            }
+                            Err(e) => warn!("Can't serialize block for the TCP thread: {}", e),
+                        }
+                    }
+                    EventType::ProposalTimeout => {
+                        let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                        self.proposal_timed_out(epoch);
                     }
                     EventType::EpochStart => {
-                        // Note: it's okay if these slightly trail the epoch timer; 
-                        // they won't be checked or added to unless "this epoch's" 
+                        // Note: it's okay if this slightly trails the epoch timer; 
+                        // it won't be checked unless "this epoch's" 
                         // sigature -- reset by the timer task -- is populated.
                         self.seen_block_this_epoch = None;
-                        self.sigs_on_seen_block_this_epoch = vec![];
 
                         // Want to hold locks for as little time as possible s.t. timer doesn't get out of sync
-                        let current_epoch_ref = current_epoch_handle.lock().await;
-                        let epoch = *current_epoch_ref;
-                        drop(current_epoch_ref);
+                        let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                        drop(in_epoch);
+                        self.epoch_span = info_span!(parent: None, "epoch", node = %self.name, epoch, leader = field::Empty);
+                        in_epoch = self.epoch_span.clone().entered();
+                        self.blockchain_manager.prune_votes(epoch);
+                        self.check_overdue_promises();
+                        self.maintenance.prune(epoch);
+                        self.quarantine.prune(epoch);
+                        self.verify_budget.epoch_started();
+                        self.replay_guard.epoch_started(epoch);
+                        self.current_epoch = epoch;
+                        self.adapt_epoch_length(epoch);
+                        if self.discovered {
+                            let started = *epoch_started_handle.lock().expect("Epoch lock poisoned");
+                            proposal_deadline = Some(started + self.epoch_length / PROPOSAL_TIMEOUT_DIVISOR);
+                        }
+                        self.activate_roster_changes(epoch);
+                        self.forward_to_leader(&mut net_stack, epoch);
+                        for bytes in std::mem::take(&mut self.delayed_messages) {
+                            log_unsent("delayed message", net_stack.broadcast_message(bytes));
+                        }
+                        if epoch.is_multiple_of(EPOCH_SYNC_INTERVAL) {
+                            self.report_epoch_position(&mut net_stack, epoch_position(false));
+                        }
+                        // For validators that start later, and missed our first advertisements
+                        if epoch.is_multiple_of(READVERTISE_INTERVAL) && self.role == Role::Validator {
+                            peers.advertise_self(&mut net_stack);
+                        }
+                        if epoch.is_multiple_of(STH_GOSSIP_INTERVAL) {
+                            self.publish_tree_head(&mut net_stack);
+                        }
+                        if epoch.is_multiple_of(DHT_REFRESH_INTERVAL) {
+                            net_stack.refresh_peers();
+                        }
+                        // Blocks may have been notarized while we were cut off
+                        if self.sync_after_reconnect {
+                            self.sync_after_reconnect = false;
+                            self.request_chain_sync(&mut net_stack, epoch);
+                        }
+                        self.health.epoch_started(clock::unix_time_ms());
+                        self.latency_watchdog.epoch_started(epoch, clock::unix_time_ms(), self.last_proposal_epoch + 1 == epoch);
+                        self.performance.epoch_started(epoch, clock::unix_time_ms());
+                        self.write_scheduled_report();
+                        if let Some(warning) = self.latency_watchdog.check(epoch) {
+                            self.raise(NodeAlert::LatencyBudget(warning));
+                        }
+                        if epoch.is_multiple_of(SELF_AUDIT_INTERVAL) {
+                            self.self_audit();
+                        }
+                        let finalized_height = self.blockchain_manager.get_latest_finalized_block().0.height;
+                        if let Some(stall) = self.stall_watch.check(epoch, finalized_height) {
+                            self.raise(stall);
+                        }
+                        if epoch.is_multiple_of(VOTE_ANALYSIS_INTERVAL) {
+                            self.log_vote_anomalies();
+                        }
+                        if let Some(version) = self.upgrades.activating_at(epoch) {
+                            info!("Epoch: {}, protocol version {} is now active", epoch, version);
+                        }
+                        if !self.supports_protocol_at(epoch) {
+                            warn!(
+                                "Epoch: {}, protocol version {} is active but this node only supports up to {}; not participating until it is upgraded",
+                                epoch, self.protocol_version(epoch), self.supported_version
+                            );
+                            continue;
+                        }
 
                         let leader = self.get_epoch_leader(epoch);
+                        self.epoch_span.record("leader", leader.as_str());
                         
                         info!("Epoch: {} starting with leader {}...", epoch, leader);
 
                         // If I am the current leader, propose a block
-                        if leader == &self.name 
-                        || self.compromise_type == CompromiseType::NonLeaderPropose
-                        && !(self.compromise_type == CompromiseType::NoPropose) {
+                        if (leader == self.name
+                            || self.compromise_type == CompromiseType::NonLeaderPropose)
+                            && self.compromise_type != CompromiseType::NoPropose
+                            && self.role == Role::Validator {
                             info!("I'm the leader");
 
                             self.leader_count += 1;
+                            self.performance.led(epoch);
                             // Ensures that publication happens frequently enough to not miss out if there is node failure.
                             // But not too often that it becomes too taxing to the system.
-                            if (epoch % PUBLISH_RATE == 0) || (self.leader_count % PUBLISH_RATE == 0) {
+                            if epoch.is_multiple_of(PUBLISH_RATE) || self.leader_count.is_multiple_of(PUBLISH_RATE) {
                                 // Initial implementation of "regularly checkpoint to a public log"
                                 // For now: 
                                 // - Avoid duplicate publishing the best we can without reading back the last pushed epoch.
@@ -323,87 +1028,235 @@ impl StreamletInstance {
                                     info!("{} is publishing latest finalized block to public chain at epoch {}", self.name, epoch);
                                     self.blockchain_manager.publish_last_finalized_block();
                                 }
-                                self.blockchain_manager.export_local_finalized_chain_to_file(format!("{}/src/tmp/{}.txt", env::current_dir().expect("invalid current directory").display().to_string(), self.name));
+                                self.blockchain_manager.export_local_finalized_chain_to_file(format!("{}/src/tmp/{}.txt", env::current_dir().expect("invalid current directory").display(), self.name));
                             }
                             
-                            if let Some(data) = self.pending_transactions.pop_front() {
-                                sleep(Duration::from_millis(EPOCH_DELAY_MS)).await;
-                                // Create message contents
-                                let height = u64::try_from(
-                                    self.blockchain_manager.longest_notarized_chain_length,
-                                )
-                                .unwrap();
-                                let (parent, _) = self.blockchain_manager.head();
-                                let mut parent_hash = parent.hash.clone();
-                                let proposed_block = Block::new(
-                                    {
-                                        if self.compromise_type == CompromiseType::EarlyEpoch { 0 }
-                                        else if self.compromise_type == CompromiseType::LateEpoch { epoch + 50 } 
-                                        else { epoch }
-                                    },
-                                    { 
-                                        if self.compromise_type == CompromiseType::WrongParentHash { parent_hash.sort() } 
-                                        parent_hash
-                                    },
-                                    data,
-                                    height,
-                                    rand::thread_rng().gen(),
-                                );
-
-                                // Construct message
-                                let mut message = Message::new(
-                                    MessagePayload::Block(proposed_block),
-                                    MessageKind::Propose,
-                                    self.id,
-                                    self.name.clone(),
-                                );
+                            // Propose every epoch, even with nothing pending: an empty block still
+                            // extends the longest notarized chain and lets earlier blocks finalize.
+                            // Not in the span while other tasks may run
+                            let epoch_span = in_epoch.exit();
+                            clock::sleep(Duration::from_millis(EPOCH_DELAY_MS)).await;
+                            in_epoch = epoch_span.entered();
+                            // After a restart we may have voted this epoch already
+                            if self.vote_journal.as_ref().and_then(|journal| journal.voted_for(epoch)).is_some() {
+                                warn!("Epoch: {}, not proposing; already voted this epoch", epoch);
+                                continue;
+                            }
+                            let mut message = self.make_proposal(epoch);
+                            let proposed_hash = match &message.payload {
+                                MessagePayload::Block(block) => block.hash,
+                                _ => unreachable!("proposals carry a block"),
+                            };
 
-                                // Sign and send mesasage
-                                if let Some(sig) = self.sign_message(&mut message) {
-                                    info!("Epoch: {}, (Propose) SENDING proposal, broadcasting message {}...", epoch, message.nonce);
-                                    net_stack.broadcast_message(message.serialize());
-                                    let mut vote_this_epoch_ref = vote_this_epoch_handle.lock().await;
-                                    *vote_this_epoch_ref = Some(sig);
-                                    drop(vote_this_epoch_ref);
-                                } else {
-                                    debug!("something weird happened...")
+                            // Sign and send mesasage
+                            if !self.journal_vote(epoch, &proposed_hash) {
+                                warn!("Epoch: {}, not proposing; vote journal refused the proposal", epoch);
+                            } else {
+                                match self.sign_message(&mut message) {
+                                    Ok(sig) => {
+                                        info!("Epoch: {}, (Propose) SENDING proposal, broadcasting message {}...", epoch, message.nonce);
+                                        self.performance.voted(epoch);
+                                        self.last_proposal_epoch = epoch;
+                                        // Our proposal doubles as our vote
+                                        if let MessagePayload::Block(block) = &message.payload {
+                                            self.seen_block_this_epoch = Some(block.hash);
+                                            self.blockchain_manager.record_votes(block, vec![(self.name.clone(), sig)]);
+                                        }
+                                        let twin = self.equivocating_proposal(&message);
+                                        self.broadcast_sealed(&mut net_stack, message);
+                                        if let Some((twin, _)) = twin {
+                                            warn!("Epoch: {}, (Byzantine) proposing a second, conflicting block", epoch);
+                                            self.broadcast_sealed(&mut net_stack, twin);
+                                        }
+                                        *vote_this_epoch_handle.lock().expect("Epoch lock poisoned") = Some(sig);
+                                    }
+                                    Err(e) => warn!("Epoch: {}, not proposing: {}", epoch, e),
                                 }
                             }
                         }
                     }
+                    EventType::PeerConnected(peer) => {
+                        debug!("Connected to peer {}", peer);
+                        let was_isolated = self.connected_peers.is_empty();
+                        self.connected_peers.insert(peer);
+                        // Not right away: the peer hasn't told us its topics yet
+                        if was_isolated && *current_epoch_handle.lock().expect("Epoch lock poisoned") > 0 {
+                            info!("Reconnected to the network; syncing the chain at the next epoch");
+                            self.sync_after_reconnect = true;
+                        }
+                    }
+                    EventType::PeerDisconnected(peer) => {
+                        debug!("Disconnected from peer {}", peer);
+                        self.connected_peers.remove(&peer);
+                        if self.connected_peers.is_empty() {
+                            warn!("Lost the connection to every peer");
+                        }
+                    }
                     EventType::NetworkInput(bytes) => {
-                        // Received message
-                        let message = Message::deserialize(&bytes);
+                        // Back from the verification pool, having passed everything before it (see verify_pool)
+                        let resumed = self.verified.as_ref().is_some_and(|done| done.bytes == bytes);
+                        // Repeats of a message we already handled (see dedup)
+                        if !charged && !resumed && !self.seen_messages.first_sighting(&bytes) {
+                            continue;
+                        }
+                        let decoded = match &self.verified {
+                            Some(done) if resumed => Ok((done.message.clone(), done.sealed_by.clone())),
+                            _ => self.open_input(&bytes),
+                        };
+                        let (message, sealed_by) = match decoded {
+                            Ok(decoded) => decoded,
+                            Err(severity) => {
+                                if let (Some(severity), Some(source)) = (severity, net_stack.source_of(&bytes)) {
+                                    net_stack.report_peer(&source, severity);
+                                }
+                                continue;
+                            }
+                        };
                         
                         // Lock mutexes short-term. 
                         // Locking for too long causes epoch timers to get out of sync. 
-                        let current_epoch_ref = current_epoch_handle.lock().await;
-                        let epoch = *current_epoch_ref;
-                        drop(current_epoch_ref);
-                        let vote_this_epoch_ref = vote_this_epoch_handle.lock().await;
-                        let vote_this_epoch = *vote_this_epoch_ref;
-                        drop(vote_this_epoch_ref);
+                        let epoch = *current_epoch_handle.lock().expect("Epoch lock poisoned");
+                        let vote_this_epoch = *vote_this_epoch_handle.lock().expect("Epoch lock poisoned");
 
+                        let message_span = info_span!(
+                            "message",
+                            kind = ?message.kind,
+                            from = %message.sender_name,
+                            id = %message.correlation_id(),
+                            block = field::Empty
+                        );
+                        if let MessagePayload::Block(block) = &message.payload {
+                            message_span.record("block", telemetry::short_hash(&block.hash).as_str());
+                        }
+                        let _in_message = message_span.entered();
                         debug!("Epoch: {}, Received {:?} message...", epoch, &message.kind);
+
+                        let is_consensus = message.kind.is_consensus();
+                        let sealed = sealed_by.is_some();
+                        if is_consensus && !charged && !sealed {
+                            debug!("Epoch: {}, dropping unsealed {:?} message claiming to be from {}", epoch, message.kind, message.sender_name);
+                            continue;
+                        }
+                        // Captured messages played back (see replay), by whoever sealed them
+                        if !charged && !resumed {
+                            if let Some(sender) = &sealed_by {
+                                if let Err(replay) = self.replay_guard.check(sender, &message, epoch) {
+                                    debug!("Epoch: {}, dropping replayed {:?} message from {}: {}", epoch, message.kind, sender, replay);
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // Gossip from nodes off a permissioned deployment's roster (see static_roster)
+                        if let Some(reason) = self.static_roster.as_ref().and_then(|roster| roster.refuses(&message, sealed_by.as_deref())) {
+                            if !charged {
+                                debug!("Epoch: {}, dropping {:?} message from {}: {}", epoch, message.kind, message.sender_name, reason);
+                                if let Some(source) = net_stack.source_of(&bytes) {
+                                    net_stack.report_peer(&source, PeerSeverity::Minor);
+                                }
+                                continue;
+                            }
+                        }
+                        // Bound the signature checks a peer can make us do this epoch; charged to whoever sealed
+                        // the message, since the sender_name is unauthenticated and an echo keeps the original's
+                        let budget_holder = sealed_by.clone().filter(|sender| *sender != self.name);
+                        if is_consensus && !charged && !resumed && budget_holder.as_ref().is_some_and(|sender| !self.verify_budget.admit(sender, &message)) {
+                            let peer = budget_holder.unwrap_or_default();
+                            debug!("Epoch: {}, {} is over its verification budget; deferring its {:?}", epoch, peer, message.kind);
+                            if self.verify_budget.defer(&peer, message) {
+                                self.raise(NodeAlert::PeerOverBudget { peer, epoch });
+                                if let Some(source) = net_stack.source_of(&bytes) {
+                                    net_stack.report_peer(&source, PeerSeverity::Minor);
+                                }
+                            }
+                            continue;
+                        }
+                        // Match its signatures to validators on the verification pool, off the event loop
+                        // (if the pool is busy, they are matched here)
+                        if is_consensus && !charged && !resumed && verify_pool.has_room() {
+                            verify_pool.submit(VerifyJob {
+                                bytes,
+                                message,
+                                sealed_by,
+                                public_keys: self.public_keys.clone(),
+                                scheme: self.signature_scheme,
+                                chain_id: self.chain_id,
+                                cache: self.signature_cache.clone(),
+                            });
+                            continue;
+                        }
                     
                         // Message processing logic
-                        match &message.kind {
+                        match (&message.kind, &message.payload) {
                             // Data from application
-                            MessageKind::AppSend => {
-                                match &message.payload {
-                                    MessagePayload::AppData(data) => {
-                                        info!("Epoch: {}, received message from app; adding to pending transactions", epoch);
-                                        if app_interface.message_is_from_app(&message) && app_interface.data_is_valid(&message) {
-                                            self.pending_transactions.push_back(data.clone());
-                                        }
+                            (MessageKind::AppSend, MessagePayload::AppData(data)) => {
+                                match LogEntry::deserialize(data) {
+                                    Some(entry) if is_announcement(&entry.data) => {
+                                        warn!("Epoch: {}, dropping validator announcement submitted by app {}", epoch, message.sender_name);
                                     }
-                                    _ => {
-                                        debug!("Unkown payload for MessageKind::AppSend");
+                                    Some(entry) if self.submitters.is_some() => {
+                                        warn!("Epoch: {}, dropping entry {} from app {}: the app can't sign submissions", epoch, entry.id.format(self.entry_id_format), message.sender_name);
                                     }
-                                };
+                                    Some(entry) if app_interface.message_is_from_app(&message) && app_interface.data_is_valid(&message) => {
+                                        let entry = match self.seal_entry(entry) {
+                                            Ok(entry) => entry,
+                                            Err(e) => {
+                                                warn!("Epoch: {}, dropping entry from app {}: {}", epoch, message.sender_name, e);
+                                                continue;
+                                            }
+                                        };
+                                        info!("Epoch: {}, received entry {} from app; adding to pending transactions", epoch, entry.id.format(self.entry_id_format));
+                                        let bytes = entry.serialize();
+                                        self.pending_transactions.push(&message.sender_name, bytes.clone());
+                                        // Let the submitter know the entry was accepted, with a promise to include it
+                                        let promise = self.promise_inclusion(entry.id, &bytes);
+                                        let receipt = Message::new_with_defined_tag(
+                                            MessagePayload::Promise(promise),
+                                            MessageKind::AppReceipt,
+                                            message.tag,
+                                            self.id,
+                                            self.name.clone(),
+                                        );
+                                        log_unsent("receipt to app", app_interface.send_to_app(&mut net_stack, receipt.serialize()));
+                                    }
+                                    Some(_) => {}
+                                    None => {
+                                        debug!("Epoch: {}, dropping app data that isn't a log entry", epoch);
+                                    }
+                                }
+                            },
+                            // Entry submitted to every validator by a client (or another validator)
+                            (MessageKind::Submit, MessagePayload::Submit(entry)) => {
+                                // The submitter's signature, if it signed (see submitters)
+                                let submission = message.signatures.first().map(|signature| SignedSubmission { submitter: message.sender_name.clone(), signature: *signature });
+                                if self.role == Role::Observer {
+                                    // Validators take it; we'd never propose it
+                                } else if is_announcement(&entry.data) {
+                                    warn!("Epoch: {}, dropping validator announcement submitted by {}", epoch, message.sender_name);
+                                } else {
+                                    match self.admit_entry(entry, submission.as_ref()).and_then(|()| self.seal_entry(entry.clone())) {
+                                        Err(e) => {
+                                            warn!("Epoch: {}, dropping entry {} from {}: {}", epoch, entry.id.format(self.entry_id_format), message.sender_name, e);
+                                        }
+                                        Ok(entry) => {
+                                            info!("Epoch: {}, received entry {} from {}; adding to pending transactions", epoch, entry.id.format(self.entry_id_format), message.sender_name);
+                                            let bytes = entry.serialize();
+                                            let promise = self.promise_inclusion(entry.id, &bytes);
+                                            self.pending_transactions.push(&message.sender_name, bytes);
+                                            let receipt = Message::new_with_defined_tag(
+                                                MessagePayload::Promise(promise),
+                                                MessageKind::AppReceipt,
+                                                message.tag,
+                                                self.id,
+                                                self.name.clone(),
+                                            );
+                                            log_unsent("receipt", net_stack.broadcast_message(receipt.serialize()));
+                                        }
+                                    }
+                                }
                             },
                             // Fulfill application request for data (ask the app to create a TCP connection for transport)
-                            MessageKind::AppBlockRequest => {
+                            (MessageKind::AppBlockRequest, _) => {
                                 // Construct message
                                 let new_message = Message::new_with_defined_tag(
                                     MessagePayload::SocketAddr(local_addr),
@@ -415,10 +1268,10 @@ impl StreamletInstance {
 
                                 // TOOD: just send to the application instead of bcast?
                                 info!("Epoch: {}, responding to AppBlockRequest with {:?}", epoch, &new_message);
-                                app_interface.send_to_app(&mut net_stack, new_message.serialize());
+                                log_unsent("app block response", app_interface.send_to_app(&mut net_stack, new_message.serialize()));
                             },
                             // Fulfill application request for chain (ask the app to create a TCP connection for transport)
-                            MessageKind::AppChainRequest => {
+                            (MessageKind::AppChainRequest, _) => {
                                 // Construct message
                                 let new_message = Message::new_with_defined_tag(
                                     MessagePayload::SocketAddr(local_addr),
@@ -429,241 +1282,2079 @@ impl StreamletInstance {
                                 );
 
                                 info!("Epoch: {}, responding to AppChainRequest with {:?}", epoch, &new_message);
-                                net_stack.broadcast_to_topic("app", new_message.serialize());
+                                log_unsent("app chain response", net_stack.broadcast_to_topic("app", new_message.serialize()));
                             },
                             // Message only for application (we just ignore)
-                            MessageKind::AppBlockResponse => { /* Do nothing */ },
-                            MessageKind::AppChainResponse => { /* Do nothing */ },
-                            // Peer advertisement logic
-                            MessageKind::PeerInit => {
-                                if let MessagePayload::PeerAdvertisement(ad) = &message.payload {
-                                    self.add_public_key(ad.node_name.clone(), &ad.public_key);
-                                    let status = peers.recv_advertisement(&ad, &mut net_stack);
-
-                                    // Initialize vector of peers (for leader election)
-                                    self.sorted_peer_names =
-                                        self.public_keys.keys().cloned().sorted().collect();
-                                    
-                                    // Sometimes, "default" keys (empty string) end up in the map, 
-                                    // generally because of how it's initialized. Remove these here. 
-                                    self.sorted_peer_names.retain(|x| *x != String::new());
-
-                                    // If we complete the peer discovery protocol, start timer
-                                    // so that they start at roughly the same time on all nodes...
-                                    match status {
-                                        peer_init::InitStatus::DoneStartTimer => {
-                                            let _ = timer_trigger.send("start!").is_ok();
-                                        }
-                                        _ => { /* Do nothing */ }
+                            (MessageKind::AppBlockResponse, _) => { /* Do nothing */ },
+                            (MessageKind::AppChainResponse, _) => { /* Do nothing */ },
+                            (MessageKind::AppReceipt, _) => { /* Do nothing */ },
+                            (MessageKind::AppFinalized, _) => { /* Do nothing */ },
+                            // Another node's tree head: it must agree with our log
+                            (MessageKind::TreeHead, MessagePayload::TreeHead(update)) => {
+                                for alert in self.receive_tree_head(update) {
+                                    if alert.is_misbehavior() {
+                                        self.raise(NodeAlert::TreeHead(alert));
+                                    } else {
+                                        debug!("Epoch: {}, tree head gossip: {}", epoch, alert);
                                     }
-                                } else {
-                                    debug!("Unkown payload for MessageKind::PeerInit");
                                 }
                             },
-                            // Implicit echo logic
-                            MessageKind::Vote => {
-                                if let MessagePayload::Block(block) = &message.payload {
-                                    // Don't "accept" this vote unless you've already gotten a proposal 
-                                    // in the same epoch, and this is a Vote on the same block. 
-                                    if vote_this_epoch.is_some() && self.seen_block_this_epoch == Some(block.hash) {
-                                        // First get all valid, not-yet-received signatures on the block: 
-                                        let mut valid_new_signatures = Vec::new();
-                                        self.get_valid_new_signatures(&message, &mut valid_new_signatures);
-                                        // If there are new signatures, echo!
-                                        if valid_new_signatures.len() > 0 {
-                                            // Record them as seen
-                                            self.sigs_on_seen_block_this_epoch.append(&mut valid_new_signatures);
-                                            self.sigs_on_seen_block_this_epoch.dedup();
-                                            // Clone of message that we can modify
-                                            let mut new_message = message.clone();
-                                            // Add all signatures to echo (reduces needed echoing before notarization)
-                                            new_message.signatures = self.sigs_on_seen_block_this_epoch.clone();
-                                            if self.compromise_type != CompromiseType::NoVote {
-                                                info!("Epoch {}: VOTED and signed message {}; broadcasting", epoch, message.nonce);
-                                                net_stack.broadcast_message(new_message.serialize());
-                                            }
-                                        }
-                                    }
+                            // Peer advertisement logic
+                            (MessageKind::PeerInit, MessagePayload::PeerAdvertisement(ad)) => {
+                                // Only the key's holder can advertise it (see peer_init)
+                                if !ad.verify(&message.signatures, &self.chain_id) {
+                                    warn!("Ignoring an advertisement for {} that its key didn't sign", ad.node_name);
+                                    continue;
+                                }
+                                // Anyone could end discovery otherwise
+                                if ad.is_end_init() && !self.public_keys.values().any(|key| *key == ad.public_key) {
+                                    warn!("Ignoring the end of discovery from a key that isn't a validator's");
+                                    continue;
+                                }
+                                let known = self.public_keys.contains_key(&ad.node_name);
+                                let mismatch = self
+                                    .genesis
+                                    .as_ref()
+                                    .and_then(|genesis| genesis.mismatch(&ad.node_name, &ad.public_key))
+                                    .or_else(|| self.static_roster.as_ref().and_then(|roster| roster.mismatch(&ad.node_name, &ad.public_key)));
+                                if let Some(reason) = mismatch {
+                                    // Not counted towards discovery either: we don't talk to it
+                                    warn!("Ignoring {}'s advertisement: {}", ad.node_name, reason);
+                                    continue;
+                                }
+                                if !self.accept_advertised_key(&ad.node_name, &ad.public_key) {
+                                    debug!("{} isn't a validator; a roster join makes it one", ad.node_name);
+                                } else if self.discovered && !known {
+                                    info!("Epoch: {}, {} joined late; added its key", epoch, ad.node_name);
+                                }
+                                let status = peers.recv_advertisement(ad, &mut net_stack);
 
-                                    if self.is_notarized(&block, &message) {
-                                        let index = self.blockchain_manager.index_of_ancestor_chain(block.clone());
-                                        if index.is_some() {
-                                            info!("Epoch {}: Adding notarized message {} to chain {}", epoch, message.nonce, index.unwrap());
-                                            self.blockchain_manager.add_to_chain( 
-                                                block.clone(), 
-                                                message.clone().get_signatures(), 
-                                                index.unwrap()
-                                            );
-                                        }
+                                // Initialize vector of peers (for leader election)
+                                self.sorted_peer_names =
+                                    self.public_keys.keys().cloned().sorted().collect();
+                                
+                                // Sometimes, "default" keys (empty string) end up in the map, 
+                                // generally because of how it's initialized. Remove these here. 
+                                self.sorted_peer_names.retain(|x| *x != String::new());
+                                self.rekey_roster_channel();
+
+                                // If we complete the peer discovery protocol, start timer
+                                // so that they start at roughly the same time on all nodes...
+                                match status {
+                                    peer_init::InitStatus::DoneStartTimer => {
+                                        *epoch_started_handle.lock().expect("Epoch lock poisoned") = clock::now();
+                                        let _ = timer_trigger.send("start!").is_ok();
+                                        self.discovered = true;
+                                        // Line our epoch clock up with those already running
+                                        self.report_epoch_position(&mut net_stack, epoch_position(true));
+                                        self.announce_own_join();
+                                        // In case we are joining a deployment that is already running
+                                        self.request_chain_sync(&mut net_stack, 0);
                                     }
-                                    
-                                } else {
-                                    debug!("Unkown payload for MessageKind::Vote");
+                                    _ => { /* Do nothing */ }
+                                }
+                            },
+                            // An observer is joining: tell it who we are, but don't count it
+                            (MessageKind::ObserverInit, MessagePayload::PeerAdvertisement(ad)) => {
+                                if self.role == Role::Validator && ad.verify(&message.signatures, &self.chain_id) {
+                                    debug!("Epoch: {}, {} is observing", epoch, ad.node_name);
+                                    peers.answer_observer(ad, &mut net_stack);
+                                }
+                            },
+                            // A validator reports where it is in its epoch clock
+                            (MessageKind::EpochSync, MessagePayload::EpochSync(position)) => {
+                                // Only validators report, and only for themselves
+                                if sealed_by.as_ref() != Some(&message.sender_name)
+                                    || message.sender_name == self.name
+                                    || !self.public_keys.contains_key(&message.sender_name)
+                                    || !self.discovered
+                                {
+                                    continue;
+                                }
+                                let now_ms = clock::unix_time_ms();
+                                if !self.epoch_sync.record(&message.sender_name, position, &epoch_position(false), self.epoch_length, now_ms) {
+                                    debug!("Epoch: {}, dropping stale epoch sync report from {}", epoch, message.sender_name);
+                                    continue;
+                                }
+                                if position.wants_reply {
+                                    self.report_epoch_position(&mut net_stack, epoch_position(false));
+                                }
+                                let max_age_ms = EPOCH_SYNC_INTERVAL * self.epoch_length.as_millis() as u64;
+                                let is_validator = self.role == Role::Validator;
+                                if let Some(shift_ms) = self.epoch_sync.correction(self.quorum_size(), is_validator, max_age_ms, now_ms) {
+                                    info!("Epoch: {}, moving the epoch clock {} ms to line up with the validators", epoch, shift_ms);
+                                    let _ = clock_shift_trigger.send(shift_ms).is_ok();
+                                }
+                            },
+                            // Vote collection and implicit echo logic
+                            (MessageKind::Vote, MessagePayload::Block(block)) => {
+                                // Count every valid signature on the vote, once per signer
+                                let votes = self.identify_signers(&message);
+                                self.report_invalid_signatures(&mut net_stack, &bytes, &message, votes.len(), epoch);
+                                self.analyze_votes(block, &votes, epoch);
+                                let new_votes = self.blockchain_manager.record_votes(block, votes);
+
+                                // Only echo if we've voted for this block in this epoch and the
+                                // vote taught us something new. Echo all known signatures
+                                // (reduces needed echoing before notarization).
+                                if new_votes > 0
+                                    && vote_this_epoch.is_some()
+                                    && self.seen_block_this_epoch == Some(block.hash)
+                                    && self.compromise_type != CompromiseType::NoVote
+                                {
+                                    let mut new_message = message.clone();
+                                    new_message.signatures = self.blockchain_manager.votes_for(&block.hash);
+                                    info!("Epoch {}: VOTED and signed message {}; broadcasting", epoch, message.nonce);
+                                    self.broadcast_sealed(&mut net_stack, new_message);
                                 }
+
+                                if self.blockchain_manager.try_notarize(&block.hash, self.quorum_size()) {
+                                    info!("Epoch {}: block from message {} is NOTARIZED, added to chain", epoch, message.nonce);
+                                    self.block_notarized(block);
+                                    self.announce_notarized(block, &mut net_stack);
+                                    self.release_quarantined_blocks();
+                                    self.report_finalized(&app_interface, &mut net_stack);
+                                }
+                                self.quarantine_if_transient(&message, epoch);
+                            },
+                            // A peer notarized a block: its quorum of votes is in the message
+                            (MessageKind::Notarize, MessagePayload::Block(block)) => {
+                                let votes = self.identify_signers(&message);
+                                self.report_invalid_signatures(&mut net_stack, &bytes, &message, votes.len(), epoch);
+                                self.analyze_votes(block, &votes, epoch);
+                                self.blockchain_manager.record_votes(block, votes);
+                                if self.blockchain_manager.try_notarize(&block.hash, self.quorum_size()) {
+                                    info!("Epoch {}: block is NOTARIZED by {}'s certificate, added to chain", epoch, message.sender_name);
+                                    self.block_notarized(block);
+                                    self.release_quarantined_blocks();
+                                    self.report_finalized(&app_interface, &mut net_stack);
+                                }
+                                self.quarantine_if_transient(&message, epoch);
+                            },
+                            // A peer finalized a block; if it is beyond our chain, we fell behind
+                            (MessageKind::Finalize, MessagePayload::Block(block)) => {
+                                // The finalizer's certificate, which may be an aggregate vote
+                                let cert = NotarizationCert::new(block, message.signatures.clone());
+                                let signers = cert.signers(block, &self.public_keys, self.signature_scheme, &self.chain_id);
+                                if message.sender_name != self.name
+                                    && signers.len() >= self.quorum_size()
+                                    && block.height > self.blockchain_manager.head().0.height
+                                {
+                                    self.health.peer_at(block.height);
+                                    self.request_chain_sync(&mut net_stack, epoch);
+                                }
+                                self.quarantine_if_transient(&message, epoch);
                             },
                             // Follower proposal handling logic
-                            MessageKind::Propose => {
+                            (MessageKind::Propose, MessagePayload::Block(block)) => {
                                 // If we haven't voted  yet this epoch and
                                 // we receive a message from the leader, sign and vote
-                                if let MessagePayload::Block(block) = &message.payload {
-                                    // Clone of message that we can modify
-                                    let mut new_message = message.clone();
-                                    let signature = self.should_vote(&mut new_message, vote_this_epoch, epoch, &block, &app_interface);
-                                    if let Some(sig) = signature {
-                                        if self.compromise_type != CompromiseType::NoVote {
-                                            // Sign and broadcast
-                                            info!("Epoch: {}, (Propose) received PROPOSE, signing and broadcasting message {}...",epoch, message.nonce);
-                                            new_message.kind = MessageKind::Vote;
-                                            self.seen_block_this_epoch = Some(block.hash);
-                                            // Add our signature and the leader's signature
-                                            self.sigs_on_seen_block_this_epoch.append(&mut new_message.clone().get_signatures());
-                                            
-                                            net_stack.broadcast_message(new_message.serialize());
-                                            // If an epoch has passed since we locked the mutex, then we may miss an epoch of voting.
-                                            // This is assumed to be rare, and nodes will recover in the next epoch. 
-                                            // Update - we just voted!
-                                            let mut vote_this_epoch_ref = vote_this_epoch_handle.lock().await;
-                                            *vote_this_epoch_ref = Some(sig);
-                                            drop(vote_this_epoch_ref);
-                                        }
-                                        // Add the received (+ signed by us) message to the chain if its notarized
-                                        if self.is_notarized(&block, &message) {
-                                            info!("Epoch: {}, (Propose) received PROPOSE, message {} is NOTARIZED, adding to chain...",epoch, message.nonce);
-                                            self.blockchain_manager.index_of_ancestor_chain(block.clone()).map(|idx| 
-                                                self.blockchain_manager
-                                                    .add_to_chain(block.clone(), message.clone().get_signatures(), idx)
-                                            );
-                                        }
+                                // A proposal above our longest notarized chain means we fell behind
+                                if block.height > self.blockchain_manager.head().0.height + 1 {
+                                    self.health.peer_at(block.height - 1);
+                                    self.request_chain_sync(&mut net_stack, epoch);
+                                }
+                                if block.epoch == epoch && self.check_from_leader(epoch, &message) {
+                                    self.last_proposal_epoch = epoch;
+                                }
+                                // Clone of message that we can modify
+                                let mut new_message = message.clone();
+                                let signature = if self.empty_epoch == Some(epoch) && block.epoch == epoch {
+                                    debug!("Epoch: {}, not voting for a proposal that came after the epoch was found empty", epoch);
+                                    None
+                                } else {
+                                    self.should_vote(&mut new_message, vote_this_epoch, epoch, block, &app_interface)
+                                };
+                                if let Some(sig) = signature {
+                                    self.seen_block_this_epoch = Some(block.hash);
+                                    // The leader's signature counts as its vote
+                                    let leader_votes = self.identify_signers(&message);
+                                    self.analyze_votes(block, &leader_votes, epoch);
+                                    self.blockchain_manager.record_votes(block, leader_votes);
+
+                                    // An equivocating node votes again this epoch, which the journal would refuse
+                                    if self.compromise_type != CompromiseType::NoVote
+                                        && (self.compromise_type == CompromiseType::Equivocate || self.journal_vote(epoch, &block.hash))
+                                    {
+                                        // Sign and broadcast
+                                        info!("Epoch: {}, (Propose) received PROPOSE, signing and broadcasting message {}...",epoch, message.nonce);
+                                        new_message.kind = MessageKind::Vote;
+                                        self.performance.voted(epoch);
+                                        self.blockchain_manager.record_votes(block, vec![(self.name.clone(), sig)]);
 
-                                        self.pending_transactions.retain(|x| *x != block.data);
+                                        self.broadcast_sealed(&mut net_stack, new_message);
+                                        // If an epoch has passed since we locked the mutex, then we may miss an epoch of voting.
+                                        // This is assumed to be rare, and nodes will recover in the next epoch. 
+                                        // Update - we just voted!
+                                        *vote_this_epoch_handle.lock().expect("Epoch lock poisoned") = Some(sig);
+                                    }
+                                    // Votes may have arrived before the proposal did
+                                    if self.blockchain_manager.try_notarize(&block.hash, self.quorum_size()) {
+                                        info!("Epoch: {}, (Propose) received PROPOSE, message {} is NOTARIZED, added to chain",epoch, message.nonce);
+                                        self.block_notarized(block);
+                                        self.announce_notarized(block, &mut net_stack);
+                                        self.release_quarantined_blocks();
+                                        self.report_finalized(&app_interface, &mut net_stack);
                                     }
+
+                                    self.pending_transactions.remove(&block.data);
                                 } else {
-                                    debug!("Unkown payload for MessageKind::Propose");
+                                    self.quarantine_if_transient(&message, epoch);
+                                }
+                            },
+                            // Peer catching up: send it the notarized blocks it is missing
+                            (MessageKind::SyncRequest, MessagePayload::ChainSyncRequest(request)) => {
+                                if message.sender_name != self.name {
+                                    self.send_chain_segment(&mut net_stack, &message, request, epoch);
+                                }
+                            },
+                            // Peer far behind: send it our finalized chain, or a segment if we can't
+                            (MessageKind::SnapshotRequest, MessagePayload::ChainSyncRequest(request)) => {
+                                if message.sender_name != self.name {
+                                    self.send_snapshot(&mut net_stack, &message, request, epoch);
+                                }
+                            },
+                            (MessageKind::SyncResponse, MessagePayload::Chain(chain)) => {
+                                if self.chain_sync_tag == Some(message.tag) {
+                                    if self.is_chain_certified(chain) {
+                                        info!("Epoch: {}, catching up with {} notarized blocks from {}", epoch, chain.length() - 1, message.sender_name);
+                                        self.blockchain_manager.observe_chain(chain.clone());
+                                        self.release_quarantined_blocks();
+                                        self.report_finalized(&app_interface, &mut net_stack);
+                                    } else {
+                                        warn!("Rejecting invalid chain sync response from {}", message.sender_name);
+                                    }
+                                }
+                            },
+                            (MessageKind::SnapshotResponse, MessagePayload::Snapshot(snapshot)) => {
+                                if self.chain_sync_tag == Some(message.tag) {
+                                    let adopted = snapshot
+                                        .verify(&self.public_keys, self.signature_scheme, self.quorum_size(), &self.chain_id)
+                                        .and_then(|()| self.blockchain_manager.adopt_snapshot(snapshot.clone()));
+                                    match adopted {
+                                        // Other peers asked answer with the same chain
+                                        Ok(0) => {}
+                                        Ok(blocks) => {
+                                            info!("Epoch: {}, caught up with a snapshot of {} finalized blocks from {}", epoch, blocks, message.sender_name);
+                                            self.release_quarantined_blocks();
+                                            self.report_finalized(&app_interface, &mut net_stack);
+                                        }
+                                        Err(e) => warn!("Rejecting snapshot from {}: {}", message.sender_name, e),
+                                    }
                                 }
                             },
+                            (MessageKind::RosterSealed, MessagePayload::Sealed(envelope)) => {
+                                self.receive_roster_notice(envelope);
+                            },
                             _ => {
-                                debug!("Unknown message format/kind - ignoring");
+                                debug!("Ignoring {:?} message", message.kind);
                             },
                         };
                     }
+                    EventType::Shutdown => break,
                 }
             }
         }
-    }
 
-    /* Returns a copy of the instance's public key */
-    pub fn get_public_key(&self) -> PublicKey {
-        return self.keypair.public;
+        // Nothing more is proposed or voted; what we have goes to disk before we leave
+        info!("Shutting down");
+        self.blockchain_manager.flush_store();
+        if let Some(Err(e)) = self.vote_journal.as_mut().map(VoteJournal::flush) {
+            self.raise(NodeAlert::StorageFailure { what: String::from("vote journal flush"), error: e.to_string() });
+        }
+        for (what, e) in self.blockchain_manager.take_storage_errors() {
+            self.raise(NodeAlert::StorageFailure { what, error: e.to_string() });
+        }
+        net_stack.close().await;
+        info!("Shut down");
+        outcome
     }
 
-    /* Returns a copy of the most recently finalized block and its signatures */
-    pub fn get_latest_finalized_block(&self) -> (Block, Vec<Signature>) {
-        let (block, signatures) = self.blockchain_manager.get_latest_finalized_block();
-        (block.clone(), signatures.clone())
+    /* A handle that makes run() return, from any task (see shutdown). run() also shuts
+    down on SIGINT and SIGTERM. */
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
-}
 
-// =========================
-// === Streamlet Helpers ===
-// =========================
+    /* Sets the time between epochs. Must be called before run(), and should
+    match across all nodes. Should be higher for more nodes s.t. time for finalization.
+    @param epoch_length: duration of one epoch */
+    pub fn set_epoch_length(&mut self, epoch_length: Duration) {
+        self.epoch_length = epoch_length;
+        self.latency_watchdog = LatencyWatchdog::new(epoch_length, GOSSIP_HEARTBEAT);
+    }
 
-impl StreamletInstance {
-    /* Signs an arbitrary slice of bytes
-    @param bytes: arbitrary bytes to sign
-    Note: should get rid of this? mainly for testing */
-    fn sign(&self, bytes: &[u8]) -> Signature {
-        return self.keypair.sign(bytes);
+    /* Lets the epoch length adapt to the network, between the given bounds (see
+    adaptive_epoch): longer while few epochs finalize a block, shorter while nearly all
+    do. The length set with set_epoch_length is where it starts. Must be called after it,
+    and before run(), with the same bounds on every node.
+    @param min: shortest epoch length
+    @param max: longest epoch length */
+    pub fn set_adaptive_epoch(&mut self, min: Duration, max: Duration) -> Result<(), StreamletError> {
+        if min > max {
+            return Err(StreamletError::Config(format!("adaptive epoch: {:?} is longer than {:?}", min, max)));
+        }
+        latency_watchdog::check_budget(min, GOSSIP_HEARTBEAT, Duration::from_millis(EPOCH_DELAY_MS))
+            .map_err(|e| StreamletError::Config(format!("adaptive epoch: {}", e)))?;
+        let adaptive = AdaptiveEpoch::new(self.epoch_length, min, max);
+        self.set_epoch_length(adaptive.length_at(0));
+        self.adaptive_epoch = Some(Arc::new(Mutex::new(adaptive)));
+        Ok(())
     }
 
-    /* Signs a message's payload and adds the signature to the message
-    after verifying it has not already signed it (currently inefficient)
-    Returns true if we successfully sign, false if it's already been signed
-    by us
-     @param message: the message instance with a payload to be signed */
-    fn sign_message(&self, message: &mut Message) -> Option<Signature> {
-        // Create signature
-        let signature: Signature = self.sign(message.serialize_payload().as_slice());
-        // Make sure we haven't signed already
-        for s in message.clone().get_signatures() {
-            if signature == s { return None; }
+    /* Catches the adaptive epoch length up with the finalized chain, and takes on the
+    length it gives the epoch that just started.
+    @param epoch: the epoch that just started */
+    fn adapt_epoch_length(&mut self, epoch: u64) {
+        let epoch_length = match &self.adaptive_epoch {
+            Some(adaptive) => {
+                let mut adaptive = adaptive.lock().expect("Adaptive epoch lock poisoned");
+                adaptive.catch_up(self.blockchain_manager.finalized_chain());
+                adaptive.length_at(epoch)
+            }
+            None => return,
+        };
+        if epoch_length != self.epoch_length {
+            info!("Epoch {}: epoch length adapted from {:?} to {:?}", epoch, self.epoch_length, epoch_length);
+            self.set_epoch_length(epoch_length);
         }
+    }
 
-        message.sign_message(signature.clone());
-        return Some(signature);
+    /* Checks that the epoch length leaves time to notarize blocks at all, given the
+    leader's proposal delay and the gossip heartbeat (see latency_watchdog). */
+    pub fn check_latency_budget(&self) -> Result<(), BudgetError> {
+        latency_watchdog::check_budget(self.epoch_length, GOSSIP_HEARTBEAT, Duration::from_millis(EPOCH_DELAY_MS))
     }
 
-    /* Populates a vector with signatures that are valid for the message
-       and have not yet been encountered in this epoch. 
-        @param message: the message instance with signatures
-        @param ret: the vector to populate
-     */
-    fn get_valid_new_signatures(&self, message: &Message, ret: &mut Vec<Signature>) {
-        for signature in &message.signatures {
-            // Ignore seen-before signatures
-            if self.sigs_on_seen_block_this_epoch.contains(signature) { continue; }
-            // Add valid signatures
-            for pk in self.public_keys.values() {
-                if self.verify_signature(message, signature, pk) {
-                    ret.push(signature.clone());
+    /* Persists blocks and votes under the given directory, restoring any state
+    already there (e.g. after a restart). A directory written by an older release is
+    upgraded to the current schema first (see schema). Must be called before run().
+    @param path: data directory for this node */
+    pub fn open_store<P: AsRef<Path>>(&mut self, path: P) -> Result<(), StoreError> {
+        let dir = path.as_ref();
+        match blockchain::upgrade_data_dir(dir)? {
+            SchemaStatus::Migrated { from, to, backup } => {
+                info!("Upgraded {} from schema {} to {}; the old contents are in {}", dir.display(), from, to, backup.display())
+            }
+            SchemaStatus::Created | SchemaStatus::Current => {}
+        }
+        let store = SledStore::open(dir.join("chain"))?;
+        let pruning = self.blockchain_manager.pruning();
+        self.blockchain_manager = BlockchainManager::with_store(Box::new(store))?;
+        self.blockchain_manager.set_pruning(pruning);
+        self.validators_changed();
+        let journal = VoteJournal::open(dir.join("votes")).map_err(|e| StoreError::Backend(e.to_string()))?;
+        self.vote_journal = Some(journal);
+        if self.blockchain_manager.finalized_chain().length() > 1 {
+            self.sign_tree_head();
+        }
+        let announcements: Vec<(LogEntry, SignedBlock)> = self
+            .blockchain_manager
+            .finalized_chain()
+            .blocks
+            .iter()
+            .filter_map(|signed| Some((LogEntry::deserialize(&signed.block.data)?, signed.clone())))
+            .filter(|(entry, _)| is_announcement(&entry.data))
+            .collect();
+        for (entry, SignedBlock { block, cert }) in announcements {
+            self.apply_announcement(&entry, &block, &cert);
+        }
+        // Changes from before the restart are in force already. The validators' number
+        // comes from the command line; discovery finds the ones this leaves out.
+        for change in self.roster_history.take_activated(u64::MAX) {
+            match change {
+                RosterChange::Join { validator, public_key, .. } => self.add_public_key(validator, &public_key),
+                RosterChange::Retire { validator } => {
+                    self.public_keys.remove(&validator);
                 }
+                _ => {}
             }
         }
+        Ok(())
     }
 
-    /* Verifies a (message, signature) pair against a public key.
-    @param message: the message instance with a (signature, payload) pair to be validated
-    @param signature: signature of the message to be validated
-    @param pk: public key to verify against the signature */
-    fn verify_signature(&self, message: &Message, signature: &Signature, pk: &PublicKey) -> bool {
-        let result = pk.verify(message.serialize_payload().as_slice(), signature);
-        if let Err(_) = result {
-            return false;
-        } else {
-            return true;
-        }
+    /* Enables the encrypted validators-only coordination topic. All validators must
+    use the same secret. Must be called before run().
+    @param secret: secret shared by the operators out of band */
+    pub fn enable_roster_channel(&mut self, secret: Vec<u8>) {
+        self.roster_channel = Some(RosterChannel::new(secret));
     }
 
-    /* Determines if a given block is notarized. 
-        NOTE: assumes that all signatures locally cached are valid for the block 
-        with a hash matching the given `block.hash`. 
-        @param block: block received in a vote/proposal */
-    pub fn is_notarized(&self, block: &Block, message: &Message) -> bool {
-        // Partially synchronous model: >= 2N/3 valid signatures for notarization 
-        // Note: expected peer count = excluding self; add one to get N
-        let threshold = (2.0 * (self.expected_peer_count + 1) as f64 / 3.0).ceil() as usize;
-        // Option 1: this matches the proposal we've seen, and we have previously 
-        // observed sufficient signatures in this epoch. 
-        let ret = self.seen_block_this_epoch == Some(block.hash) && 
-            self.sigs_on_seen_block_this_epoch.len() >= threshold;
-        // Option 2: this message -- by itself -- has the threshold of signatures required. 
-        // This occurs if we're doing catch-up from a previous epoch or we missed the proposal.
-        return ret || (self.verify_message(&message) >= threshold);
+    /* Runs without reading stdin, for nodes under systemd or in containers with no
+    terminal: commands come from the control socket (see set_control_socket) instead,
+    and the node advertises itself to start peer discovery on its own, as if "init" had
+    been typed. Must be called before run().
+    @param headless: whether to run headless */
+    pub fn set_headless(&mut self, headless: bool) {
+        self.headless = headless;
     }
 
-    /* Returns the validity of a proposal. */
-    fn should_vote(&mut self, message: &mut Message, vote_this_epoch: Option<Signature>, epoch: u64, block: &Block, app_interface: &AppInterface) -> Option<Signature> {
-        
-        // Basic checks:
-        if !self.check_from_leader(epoch, &message) || // From the leader? 
-            // Correct epoch? 
-            block.epoch != epoch  || 
-            // Descends from ancestor? 
-            self.blockchain_manager.index_of_ancestor_chain(block.clone()).is_none() ||
-            // Is the data valid? 
-            !app_interface.data_is_valid(&message)
-        {
-            return None;
-        }
+    /* Accepts stdin commands on a Unix domain socket too (see control_socket).
+    Must be called before run().
+    @param path: where to create the socket */
+    pub fn set_control_socket<P: Into<std::path::PathBuf>>(&mut self, path: P) {
+        self.control_socket = Some(path.into());
+    }
 
-        let sig = self.sign_message(message);
+    /* Serves the RFC 6962-style HTTP API (see http_api) on the given address.
+    Needs the http-api feature. Must be called before run().
+    @param addr: address to listen on */
+    pub fn set_http_api(&mut self, addr: SocketAddr) {
+        self.http_api_addr = Some(addr);
+    }
+
+    /* Serves the gRPC service (see grpc) on the given address.
+    Needs the grpc feature. Must be called before run().
+    @param addr: address to listen on */
+    pub fn set_grpc(&mut self, addr: SocketAddr) {
+        self.grpc_addr = Some(addr);
+    }
+
+    /* Streams each finalized block to WebSocket clients on the given address (see
+    websocket). Needs the websocket feature. Must be called before run().
+    @param addr: address to listen on */
+    pub fn set_websocket(&mut self, addr: SocketAddr) {
+        self.websocket_addr = Some(addr);
+    }
+
+    /* Chooses how epoch leaders are picked (see leader_schedule).
+    Every node must use the same schedule and region labels.
+    @param kind: uniform (by epoch hash), region-aware rotation, or secret (see set_leader_secret) */
+    pub fn set_leader_schedule(&mut self, kind: LeaderScheduleKind) {
+        self.leader_schedule.set_kind(kind);
+    }
+
+    /* Keys the secret leader schedule, so only validators can tell who leads next.
+    All validators must use the same secret. Must be called before run().
+    @param secret: secret shared by the operators out of band */
+    pub fn set_leader_secret(&mut self, secret: &[u8]) {
+        self.leader_schedule.set_secret(secret);
+    }
+
+    /* Labels a validator with its region, for the region-aware leader schedule.
+    @param validator: the validator's name
+    @param region: any label (e.g. "us-east"); validators with equal labels share a region */
+    pub fn set_validator_region(&mut self, validator: &str, region: &str) {
+        self.leader_schedule.set_region(validator, region);
+    }
+
+    /* Sets the maximum merge delay promised to submitters: how long an accepted entry
+    may take to be finalized (default: 12 epochs).
+    @param delay: the maximum merge delay */
+    pub fn set_max_merge_delay(&mut self, delay: Duration) {
+        self.max_merge_delay = Some(delay);
+    }
+
+    /* Agrees to a protocol upgrade, so this node votes for a block announcing it
+    (see upgrade). Does not queue the announcement; the "upgrade" command does both.
+    @param upgrade: the version and its activation epoch */
+    pub fn approve_upgrade(&mut self, upgrade: ProtocolUpgrade) {
+        self.upgrades.approve(upgrade);
+    }
+
+    /* Protocol version in force at an epoch, per the upgrades finalized so far. */
+    pub fn protocol_version(&self, epoch: u64) -> u32 {
+        self.upgrades.version_at(epoch)
+    }
+
+    /* Enables pushing proofs to submitters' callback URLs (see callback). Each push is
+    signed with an HMAC under this secret, which submitters need to check it.
+    @param secret: secret shared with submitters out of band */
+    pub fn set_callback_secret(&mut self, secret: Vec<u8>) {
+        self.callback_secret = Some(secret);
+    }
+
+    /* Replaces the node's freshly generated keypair with a persistent one (see keystore),
+    so its signatures stay checkable across restarts. Must be called before run().
+    @param keypair: the node's keypair, under the deployment's signature scheme */
+    pub fn set_keypair(&mut self, keypair: Keypair) -> Result<(), CryptoError> {
+        self.set_signer(Box::new(keypair))
+    }
+
+    /* Loads the node's keypair from a keyfile, creating it if it doesn't exist (see
+    keystore), and keeps the file up to date through key rotations. Must be called
+    before run().
+    @param path: the keyfile
+    @param passphrase: what the key is (to be) encrypted under */
+    pub fn use_keyfile(&mut self, path: &Path, passphrase: &[u8]) -> Result<(), KeystoreError> {
+        let keypair = keystore::load_or_create(path, passphrase, self.signature_scheme)?;
+        self.set_keypair(keypair).expect("load_or_create checks the scheme");
+        self.keyfile = Some((path.to_path_buf(), passphrase.to_vec()));
+        Ok(())
+    }
+
+    /* Has the node sign with a key it doesn't hold itself (e.g. one kept by an HSM),
+    instead of its freshly generated keypair. Must be called before run().
+    @param signer: the node's signing key, under the deployment's signature scheme */
+    pub fn set_signer(&mut self, signer: Box<dyn Signer + Send + Sync>) -> Result<(), CryptoError> {
+        if signer.scheme() != self.signature_scheme {
+            return Err(CryptoError::SchemeMismatch);
+        }
+        if self.role == Role::Validator {
+            self.public_keys.insert(self.name.clone(), signer.public());
+        }
+        self.signer = signer.into();
+        self.validators_changed();
+        Ok(())
+    }
+
+    /* Configures the node from the deployment's genesis document (see genesis): its
+    chain id, epoch length and validators with their keys and ids. These override the
+    settings made before. Fails if the document uses another signature scheme than the
+    node, gives the node another key, or leaves a validator node out. Must be called
+    after the node's key and role are set, and before open_store and run().
+    @param document: the deployment's genesis document */
+    pub fn load_genesis(&mut self, document: GenesisDocument) -> Result<(), StreamletError> {
+        document.check().map_err(StreamletError::Config)?;
+        if document.scheme != self.signature_scheme {
+            return Err(StreamletError::Config(format!("the genesis document uses {}, this node {}", document.scheme, self.signature_scheme)));
+        }
+        match document.validators.get(&self.name) {
+            Some(_) if self.role == Role::Observer => {
+                return Err(StreamletError::Config(format!("the genesis document lists {} as a validator, but it's an observer", self.name)));
+            }
+            Some(validator) if validator.public_key != self.signer.public() => {
+                return Err(StreamletError::Config(format!("the genesis document gives {} another key than this node's", self.name)));
+            }
+            Some(validator) => self.id = validator.id,
+            None if self.role == Role::Validator => {
+                return Err(StreamletError::Config(format!("the genesis document doesn't list {} as a validator", self.name)));
+            }
+            None => {}
+        }
+        self.chain_id = document.chain_id();
+        self.sth_monitor = Monitor::new(self.chain_id);
+        self.set_epoch_length(document.epoch_length());
+        self.expected_peer_count = document.validators.len() - usize::from(document.validators.contains_key(&self.name));
+        for (name, public_key) in document.public_keys() {
+            self.add_public_key(name, &public_key);
+        }
+        info!(
+            "Loaded genesis document {} ({} validators); chain id {}",
+            hex::encode(document.hash()),
+            document.validators.len(),
+            self.chain_id
+        );
+        self.genesis = Some(document);
+        Ok(())
+    }
+
+    /* Fixes the deployment's validators to those on a roster (see static_roster): takes
+    on their keys, dials their addresses, and drops gossip from nodes that aren't on it.
+    Fails if the roster leaves this validator out or gives it another key, if the node
+    is an observer (a roster only admits validators), or if the roster disagrees with
+    the genesis document. Must be called after the node's key, role and genesis document
+    are set, and before run().
+    @param roster: the deployment's validators */
+    pub fn set_static_roster(&mut self, roster: StaticRoster) -> Result<(), StreamletError> {
+        if self.role == Role::Observer {
+            return Err(StreamletError::Config(String::from("a static roster only admits validators; observers can't join")));
+        }
+        if let Some(reason) = roster.mismatch(&self.name, &self.signer.public()) {
+            return Err(StreamletError::Config(format!("{}: {}", self.name, reason)));
+        }
+        if let Some(genesis) = &self.genesis {
+            let public_keys = roster.public_keys();
+            if genesis.validators.len() != roster.len() || public_keys.iter().any(|(name, key)| genesis.mismatch(name, key).is_some()) {
+                return Err(StreamletError::Config(String::from("the static roster doesn't list the genesis document's validators")));
+            }
+        }
+        for (name, public_key) in roster.public_keys() {
+            if self.public_keys.get(&name).is_some_and(|known| *known != public_key) {
+                return Err(StreamletError::Config(format!("{} already has another key than the static roster's", name)));
+            }
+            self.add_public_key(name, &public_key);
+        }
+        self.expected_peer_count = roster.len() - 1;
+        info!("Loaded a static roster of {} validators; dropping gossip from nodes not on it", roster.len());
+        self.static_roster = Some(roster);
+        Ok(())
+    }
+
+    /* Takes entries only from the submitters on a list, and validators (see
+    submitters). Must be called before run().
+    @param acl: the submitters' names and keys */
+    pub fn set_submitters(&mut self, acl: SubmitterAcl) {
+        info!("Taking entries from {} listed submitter(s) and the validators only", acl.len());
+        self.submitters = Some(acl);
+    }
+
+    /* Checks an entry may be queued: without a submitter list any may; with one, it
+    must be signed by a listed submitter or validator, and have an id we haven't seen.
+    @param entry: the entry
+    @param submission: who signed it, if anyone did */
+    fn admit_entry(&self, entry: &LogEntry, submission: Option<&SignedSubmission>) -> Result<(), String> {
+        let acl = match &self.submitters {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        if self.outstanding_promises.contains_key(&entry.id) || self.blockchain_manager.find_finalized_entry(&entry.id).is_some() {
+            return Err(String::from("an entry with that id was already submitted"));
+        }
+        acl.check(entry, submission, &self.public_keys, &self.chain_id)
+    }
+
+    /* Makes this a private log: entries are sealed under a key derived from the secret
+    before they are queued, and opened when served (see blockchain::sealed). Every
+    member needs the same secret. Must be called after the deployment is set (the key
+    is bound to its chain id) and before run().
+    @param secret: the deployment's payload secret */
+    pub fn set_payload_secret(&mut self, secret: &[u8]) {
+        self.payload_key = Some(PayloadKey::derive(secret, &self.chain_id));
+    }
+
+    /* Seals an entry for a private log, unless it already is. One sealed under another
+    key is refused: members couldn't read it.
+    @param entry: the entry, as submitted */
+    fn seal_entry(&self, entry: LogEntry) -> Result<LogEntry, String> {
+        match &self.payload_key {
+            None => Ok(entry),
+            Some(key) if PayloadKey::is_sealed(&entry) => match key.open(&entry) {
+                Some(_) => Ok(entry),
+                None => Err(String::from("entry is sealed under another key")),
+            },
+            Some(key) => Ok(key.seal(&entry)),
+        }
+    }
+
+    /* An entry as members read it: opened, if it is sealed and we hold the key. */
+    fn open_entry(&self, entry: LogEntry) -> LogEntry {
+        match &self.payload_key {
+            Some(key) => key.open(&entry).unwrap_or(entry),
+            None => entry,
+        }
+    }
+
+    /* Names the deployment this node belongs to (see chain_id). Its chain id is signed
+    along with every vote, tree head, promise and maintenance window, so nothing signed
+    for another deployment verifies here. Every node and tool of a deployment needs the
+    same name. Must be called before run().
+    @param deployment: the deployment's name */
+    pub fn set_deployment(&mut self, deployment: &str) {
+        self.chain_id = ChainId::new(deployment);
+        self.sth_monitor = Monitor::new(self.chain_id);
+    }
+
+    /* Adds peers to dial at startup, for deployments where mDNS can't find them (across
+    subnets, in the cloud). Must be called before run().
+    @param peers: the peers' addresses, e.g. /ip4/10.0.1.5/tcp/4001 */
+    pub fn set_bootstrap_peers(&mut self, peers: Vec<Multiaddr>) {
+        self.bootstrap_peers = peers;
+    }
+
+    /* Listens on a fixed address (besides a random port), so other nodes can use this
+    one as a bootstrap peer. Must be called before run().
+    @param addr: e.g. /ip4/0.0.0.0/tcp/4001 */
+    pub fn set_listen_addr(&mut self, addr: Multiaddr) {
+        self.listen_addr = Some(addr);
+    }
+
+    /* Sets up NAT traversal (see network::nat): relays to listen through if this node
+    turns out to be behind NAT, and whether to keep relayed connections open for other
+    nodes. Must be called before run().
+    @param config: the relays (each ending in /p2p/<peer id>), and whether to serve as one */
+    pub fn set_nat_traversal(&mut self, config: NatConfig) -> Result<(), String> {
+        for relay in &config.relays {
+            network::nat::circuit_addr(relay)?;
+        }
+        self.nat_config = config;
+        Ok(())
+    }
+
+    /* The chain id this node signs with. */
+    pub fn chain_id(&self) -> ChainId {
+        self.chain_id
+    }
+
+    /* Sets the compression codec for this node's messages (see codec). All nodes of a
+    deployment should use the same one; every node can read all supported codecs.
+    @param codec: none, lz4, or zstd (only in builds with the "zstd" feature) */
+    pub fn set_codec(&mut self, codec: Codec) -> Result<(), CodecError> {
+        if !codec.is_supported() {
+            return Err(CodecError::Unsupported(codec));
+        }
+        self.codec = codec;
+        Ok(())
+    }
+
+    /* Sets how many signatures each peer may make this node check per epoch (see
+    verify_budget); consensus messages beyond that are checked only when the node is idle.
+    @param per_peer: signatures per peer per epoch (0: unlimited) */
+    pub fn set_verification_budget(&mut self, per_peer: usize) {
+        self.verify_budget = VerificationBudget::new(per_peer);
+    }
+
+    /* Makes this a pruned node, which drops the bodies of finalized blocks below its
+    latest checkpoint to bound its disk use (see BlockchainManager::set_pruning). Tree
+    heads and proofs are still served; old entries' bytes, and the blocks new nodes
+    sync from, have to come from archive nodes (the default), so keep some around.
+    @param keep_blocks: finalized blocks to keep whole (at least 1) */
+    pub fn set_pruning(&mut self, keep_blocks: u64) {
+        self.blockchain_manager.set_pruning(Some(keep_blocks));
+    }
+
+    /* Sets how many epochs old a consensus message's block may be, and how long nonces
+    are remembered (see replay). Should cover the longest delay an honest message may see.
+    @param epochs: the window (0: no replay checks) */
+    pub fn set_replay_window(&mut self, epochs: u64) {
+        self.replay_guard = ReplayGuard::new(epochs);
+    }
+
+    /* Makes the node misbehave on purpose, so test clusters can include faulty nodes
+    (the same as typing "compromise ..." at the console).
+    @param compromise: the misbehavior, or NoCompromise to be honest again */
+    pub fn set_compromise(&mut self, compromise: CompromiseType) {
+        self.compromise_type = compromise;
+    }
+
+    /* Sets the gossipsub topic consensus messages go over, so deployments sharing a
+    network don't hear each other. Same on all nodes. Must be called before run().
+    @param topic: the topic's name (default STREAMLET_TOPIC) */
+    pub fn set_topic(&mut self, topic: &str) {
+        self.topic = topic.to_string();
+    }
+
+    /* Sets how many votes notarize a block (see quorum). Same on all nodes. Must be
+    called before run().
+    @param rule: the quorum rule */
+    pub fn set_quorum_rule(&mut self, rule: QuorumRule) {
+        if rule == QuorumRule::Majority {
+            warn!("Notarizing blocks with a majority of votes: safe against crashes, but not against Byzantine validators");
+        }
+        self.quorum_rule = rule;
+    }
+
+    /* Makes the node a validator, or an observer that never proposes or votes. An
+    observer still follows the consensus topic, checks every certificate and keeps the
+    finalized chain, for auditors and API servers. It isn't a validator anywhere: its
+    key isn't among the validators', it advertises itself as an observer (see
+    peer_init), so validators neither count it in quorums nor elect it leader, and its
+    expected peer count is every validator's. Must be called before run().
+    @param role: the node's role */
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+        if role == Role::Observer && self.public_keys.remove(&self.name).is_some() {
+            self.validators_changed();
+        }
+    }
+
+    /* Sizes the cache of entries and proofs served over the HTTP API (see read_cache),
+    and gives it a directory of its own to spill to. Must be called before run().
+    @param config: items kept in memory and on disk, and the directory (None: memory only) */
+    pub fn set_read_cache(&mut self, config: ReadCacheConfig) -> Result<(), StoreError> {
+        self.read_cache = ReadCache::open(config)?;
+        Ok(())
+    }
+
+    /* How HTTP API reads were served so far. */
+    pub fn read_cache_stats(&self) -> CacheStats {
+        self.read_cache.stats()
+    }
+
+    /* Sets how entry ids are rendered in this node's logs.
+    @param format: ULID (Crockford base32), hex or decimal */
+    pub fn set_entry_id_format(&mut self, format: EntryIdFormat) {
+        self.entry_id_format = format;
+    }
+
+    /* Sets the mempool priority class for a submitter (by sender name).
+    Higher classes get more entries proposed per round-robin turn. */
+    pub fn set_submitter_priority(&mut self, submitter: &str, class: PriorityClass) {
+        self.pending_transactions.set_priority(submitter, class);
+    }
+
+    /* Returns a copy of the instance's public key */
+    pub fn get_public_key(&self) -> PublicKey {
+        self.signer.public()
+    }
+
+    /* Inclusion proof for a finalized entry against the current Merkle root
+    (see merkle_root); check it with verify_inclusion.
+    @param entry: the entry's bytes, as stored in its block */
+    pub fn prove_inclusion(&self, entry: &[u8]) -> Option<AuditPath> {
+        self.blockchain_manager.merkle_tree().prove_inclusion(entry)
+    }
+
+    /* A finalized entry's block and its inclusion proof against the current Merkle root.
+    Reflects an entry as soon as its AppFinalized notice has been sent.
+    @param id: the entry's id (from the submitter's receipt) */
+    pub fn lookup_entry(&self, id: &EntryId) -> Option<(SignedBlock, AuditPath)> {
+        let block = self.blockchain_manager.find_finalized_entry(id)?;
+        let proof = self.prove_inclusion(&block.block.data)?;
+        Some((block.clone(), proof))
+    }
+
+    /* This node's signed commitment to the finalized log as of the last time the
+    finalized chain advanced (None before the first finalization). */
+    pub fn latest_sth(&self) -> Option<&SignedTreeHead> {
+        self.latest_sth.as_ref()
+    }
+
+    /* Validators whose voting looks statistically suspicious (see vote_analysis),
+    most suspicious first. */
+    pub fn vote_anomalies(&self) -> Vec<Anomaly> {
+        self.vote_analyzer.anomalies()
+    }
+
+    /* Proof that the finalized log of old_size entries is a prefix of the one of
+    new_size entries; check it with verify_consistency against two tree heads.
+    @param old_size: tree size of the older tree head
+    @param new_size: tree size of the newer tree head (at most the current size) */
+    pub fn prove_consistency(&self, old_size: u64, new_size: u64) -> Option<ConsistencyProof> {
+        self.blockchain_manager.merkle_tree().prove_consistency(old_size, new_size)
+    }
+
+    /* Root of the Merkle tree over all finalized entries, and the number of entries. */
+    pub fn merkle_root(&self) -> (Sha256Hash, u64) {
+        let tree = self.blockchain_manager.merkle_tree();
+        (tree.root(), tree.size())
+    }
+
+    /* Returns a copy of the most recently finalized block and its signatures */
+    pub fn get_latest_finalized_block(&self) -> (Block, Vec<Signature>) {
+        let (block, signatures) = self.blockchain_manager.get_latest_finalized_block();
+        (block.clone(), signatures.clone())
+    }
+}
+
+// =========================
+// === Streamlet Helpers ===
+// =========================
+
+impl StreamletInstance {
+    /* Signs an arbitrary slice of bytes
+    @param bytes: arbitrary bytes to sign
+    Note: should get rid of this? mainly for testing */
+    fn sign(&self, bytes: &[u8]) -> Signature {
+        self.signer.sign(bytes)
+    }
+
+    /* Logs each block finalized since the last call and tells the app which entries
+    were finalized. The manager has already indexed these blocks, so an app that
+    queries this node after the notice sees its entry. */
+    fn report_finalized(&mut self, app_interface: &AppInterface, net_stack: &mut NetworkStack) {
+        let newly_finalized = self.blockchain_manager.take_newly_finalized();
+        if let Some(SignedBlock { block, cert }) = newly_finalized.last() {
+            self.sign_tree_head();
+            self.publish_tree_head(net_stack);
+            self.vote_analyzer.record_finalized(self.blockchain_manager.finalized_chain());
+            // Lets peers that missed it notice they fell behind
+            let mut message = Message::new(MessagePayload::Block(block.clone()), MessageKind::Finalize, self.id, self.name.clone());
+            message.signatures = cert.signatures.clone();
+            self.broadcast_sealed(net_stack, message);
+        }
+        for signed in newly_finalized {
+            self.notify_finalized(&signed);
+            let SignedBlock { block, cert } = signed;
+            let entry = LogEntry::deserialize(&block.data);
+            info!(
+                "FINALIZED block at height {} (epoch {}, {} signatures, entry {})",
+                block.height,
+                block.epoch,
+                cert.signatures.len(),
+                entry.as_ref().map(|e| e.id.format(self.entry_id_format)).unwrap_or_else(|| String::from("none"))
+            );
+            if let Some(entry) = entry {
+                if self.apply_announcement(&entry, &block, &cert) {
+                    self.pending_transactions.remove(&block.data);
+                    continue;
+                }
+                if self.outstanding_promises.remove(&entry.id).is_some() {
+                    self.performance.promise_settled(true, clock::unix_time_ms());
+                }
+                let inclusion = match self.inclusion_proof(entry.id, &block.data) {
+                    Some(inclusion) => inclusion,
+                    None => continue,
+                };
+                if let (Some(url), Some(secret)) = (self.callbacks.remove(&entry.id), &self.callback_secret) {
+                    let bundle = json_schema::bundle_json(&entry.id, &block.data, &inclusion, &self.signer.public());
+                    tokio::spawn(callback::deliver(url, bundle.to_string().into_bytes(), secret.clone()));
+                }
+                let notice = Message::new(
+                    MessagePayload::Inclusion(inclusion),
+                    MessageKind::AppFinalized,
+                    self.id,
+                    self.name.clone(),
+                );
+                log_unsent("notice to app", app_interface.send_to_app(net_stack, notice.serialize()));
+            }
+        }
+    }
+
+    /* Queues an entry typed at this node and submits it to the other validators, so
+    whichever of us leads next can propose it.
+    @param data: the entry's bytes */
+    fn submit_entry(&mut self, net_stack: &mut NetworkStack, data: Vec<u8>) {
+        let entry = LogEntry { content_type: "text/plain; charset=utf-8".parse().ok(), ..LogEntry::new(data) };
+        let entry = match self.seal_entry(entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Can't submit the entry: {}", e);
+                return;
+            }
+        };
+        self.promise_inclusion(entry.id, &entry.serialize());
+        self.pending_transactions.push(&self.name.clone(), entry.serialize());
+        info!("Submitted entry {}", entry.id.format(self.entry_id_format));
+        let message = self.submission(entry, None);
+        log_unsent("entry", net_stack.broadcast_message(message.serialize()));
+    }
+
+    /* A Submit message for an entry we took, for validators that only take entries from
+    known submitters: under its client's name and signature, or else signed by us
+    (validators may always submit).
+    @param entry: the entry, sealed if this is a private log
+    @param submission: the client's signature on the entry, if it came with one */
+    fn submission(&self, entry: LogEntry, submission: Option<SignedSubmission>) -> Message {
+        let submission = submission.unwrap_or_else(|| SignedSubmission::sign(&entry, &self.name, &*self.signer, &self.chain_id));
+        let mut message = Message::new(MessagePayload::Submit(entry), MessageKind::Submit, self.id, submission.submitter);
+        message.sign_message(submission.signature);
+        message
+    }
+
+    /* Passes entries taken over the HTTP API on to the leader of the next epoch, directly
+    (see network::direct), so they are proposed then rather than at our own next turn.
+    The leader of the current epoch has proposed already. An entry leaves the queue once
+    it is sent; one that can't be sent is tried again with the next epoch's leader. The
+    entries stay in our own mempool too: if the leader misses them, we propose them when
+    we lead, and the mempool drops them once they are finalized either way.
+    @param net_stack: the network
+    @param epoch: the current epoch */
+    fn forward_to_leader(&mut self, net_stack: &mut NetworkStack, epoch: u64) {
+        let (leader, messages) = match self.leader_forwarding(epoch) {
+            Some(forwarding) => forwarding,
+            None => return,
+        };
+        let peer = match net_stack.peer_of(&leader) {
+            Some(peer) => peer,
+            None => {
+                debug!("Epoch: {}, can't reach {}, the next leader; keeping {} entry(ies) to try again", epoch, leader, messages.len());
+                return;
+            }
+        };
+        let mut unsent = Vec::new();
+        for (forward, message) in std::mem::take(&mut self.leader_forwards).into_iter().zip(messages) {
+            debug!("Epoch: {}, forwarding an entry to {}, the next leader", epoch, leader);
+            if let Err(e) = net_stack.send_to_peer(&peer, message.serialize()) {
+                warn!("Couldn't forward an entry to {}: {}; keeping it to try again", leader, e);
+                unsent.push(forward);
+            }
+        }
+        self.leader_forwards = unsent;
+    }
+
+    /* The entries waiting to be forwarded, in queue order, with the leader to forward
+    them to (None: there are none, or we lead next, and propose them ourselves). Entries
+    finalized meanwhile leave the queue.
+    @param epoch: the current epoch */
+    fn leader_forwarding(&mut self, epoch: u64) -> Option<(String, Vec<Message>)> {
+        let blockchain_manager = &self.blockchain_manager;
+        self.leader_forwards.retain(|(entry, _)| blockchain_manager.find_finalized_entry(&entry.id).is_none());
+        if self.leader_forwards.is_empty() || self.sorted_peer_names.is_empty() {
+            return None;
+        }
+        let leader = self.get_epoch_leader(epoch + 1);
+        if leader == self.name {
+            self.leader_forwards.clear();
+            return None;
+        }
+        let messages = self.leader_forwards.iter().map(|(entry, submission)| self.submission(entry.clone(), submission.clone())).collect();
+        Some((leader, messages))
+    }
+
+    /* Handles the "upgrade <version> <activation epoch>" command: approves the upgrade
+    and queues its announcement, to be proposed when we lead. Operators run the same
+    command on every node; the announcement finalizes once a quorum approved it.
+    @param args: the command's arguments
+    @param epoch: the current epoch */
+    fn announce_upgrade(&mut self, args: &str, epoch: u64) {
+        let parsed: Vec<u64> = args.split_whitespace().filter_map(|arg| arg.parse().ok()).collect();
+        let upgrade = match parsed[..] {
+            [version, activation_epoch] if version <= u32::MAX as u64 => {
+                ProtocolUpgrade { version: version as u32, activation_epoch }
+            }
+            _ => {
+                warn!("Usage: upgrade <version> <activation epoch>");
+                return;
+            }
+        };
+        if let Err(e) = self.upgrades.check(&upgrade, epoch) {
+            warn!("Not announcing upgrade: {}", e);
+            return;
+        }
+        if upgrade.version > self.supported_version {
+            warn!("This node doesn't support protocol version {} yet; upgrade it before epoch {}", upgrade.version, upgrade.activation_epoch);
+        }
+        self.upgrades.approve(upgrade);
+        self.pending_transactions.push(UPGRADE_SUBMITTER, upgrade.to_entry().serialize());
+        info!("Approved protocol version {} from epoch {}; queued its announcement", upgrade.version, upgrade.activation_epoch);
+    }
+
+    /* Schedules an upgrade announced in a finalized block.
+    @param upgrade: the announced upgrade
+    @param announced_epoch: epoch of the block */
+    fn schedule_upgrade(&mut self, upgrade: &ProtocolUpgrade, announced_epoch: u64) {
+        match self.upgrades.schedule(upgrade, announced_epoch) {
+            Ok(()) if upgrade.version > self.supported_version => self.raise(NodeAlert::UnsupportedProtocol {
+                version: upgrade.version,
+                activation_epoch: upgrade.activation_epoch,
+                supported: self.supported_version,
+            }),
+            Ok(()) => info!("Protocol version {} will activate at epoch {}", upgrade.version, upgrade.activation_epoch),
+            // Honest validators don't vote for these, so a quorum didn't either
+            Err(e) => warn!("Ignoring finalized upgrade announcement: {}", e),
+        }
+    }
+
+    /* Whether this node implements the protocol version in force at an epoch. */
+    fn supports_protocol_at(&self, epoch: u64) -> bool {
+        self.protocol_version(epoch) <= self.supported_version
+    }
+
+    /* Handles the "maintenance <start epoch> <end epoch>" command: signs a window for
+    our own downtime and queues its announcement, to be proposed when we lead.
+    @param args: the command's arguments
+    @param epoch: the current epoch */
+    fn announce_maintenance(&mut self, args: &str, epoch: u64) {
+        let parsed: Vec<u64> = args.split_whitespace().filter_map(|arg| arg.parse().ok()).collect();
+        let window = match parsed[..] {
+            [start_epoch, end_epoch] => MaintenanceWindow::sign(self.name.clone(), start_epoch, end_epoch, &*self.signer, &self.chain_id),
+            _ => {
+                warn!("Usage: maintenance <start epoch> <end epoch>");
+                return;
+            }
+        };
+        if let Err(e) = window.check(epoch) {
+            warn!("Not announcing maintenance: {}", e);
+            return;
+        }
+        self.pending_transactions.push(MAINTENANCE_SUBMITTER, window.to_entry().serialize());
+        info!("Queued maintenance window for epochs {}..{}", window.start_epoch, window.end_epoch);
+    }
+
+    /* Handles the "roster" command: with no arguments, prints every finalized roster
+    change; otherwise approves the change (see RosterChange::parse) and queues it.
+    @param args: the command's arguments */
+    fn announce_roster_change(&mut self, args: &str) {
+        if args.is_empty() {
+            for record in self.roster_history.records() {
+                println!(
+                    "Height {} (epoch {}): {}, approved by {}",
+                    record.block.height,
+                    record.block.epoch,
+                    record.change,
+                    self.roster_approvers(record).join(", ")
+                );
+            }
+            return;
+        }
+        let change = match RosterChange::parse(args) {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        if self.roster_history.contains(&change) {
+            warn!("Not announcing roster change: it is already in the log");
+            return;
+        }
+        self.pending_transactions.push(ROSTER_SUBMITTER, change.to_entry().serialize());
+        info!("Approved roster change ({}); queued its announcement", change);
+        self.roster_history.approve(change);
+    }
+
+    /* Applies the joins and retirements that take effect this epoch (see roster): a
+    joining validator's votes count from now on and it takes turns leading, a retiring
+    one's don't and it doesn't, and the quorum follows the new number of validators.
+    @param epoch: the epoch that just started */
+    fn activate_roster_changes(&mut self, epoch: u64) {
+        let mut changed = false;
+        for change in self.roster_history.take_activated(epoch) {
+            match change {
+                RosterChange::Join { validator, public_key, .. } if !self.public_keys.contains_key(&validator) => {
+                    if public_key.scheme() != self.signature_scheme {
+                        warn!("Epoch: {}, can't add {}: its key is {}, the deployment uses {}", epoch, validator, public_key.scheme(), self.signature_scheme);
+                        continue;
+                    }
+                    self.public_keys.insert(validator.clone(), public_key);
+                    self.quarantine.key_added();
+                    info!("Epoch: {}, {} joined the validators", epoch, validator);
+                    changed = true;
+                }
+                RosterChange::Retire { validator } if self.public_keys.remove(&validator).is_some() => {
+                    if validator == self.name {
+                        warn!("Epoch: {}, we retired from the validators; our votes no longer count", epoch);
+                    } else {
+                        info!("Epoch: {}, {} retired from the validators", epoch, validator);
+                    }
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        if changed {
+            self.sorted_peer_names = self.public_keys.keys().filter(|name| !name.is_empty()).cloned().sorted().collect();
+            self.expected_peer_count = self.sorted_peer_names.len().saturating_sub(usize::from(self.role == Role::Validator));
+            self.rekey_roster_channel();
+            self.validators_changed();
+            info!("Epoch: {}, {} validators now, quorum {}", epoch, self.sorted_peer_names.len(), self.quorum_size());
+        }
+    }
+
+    /* Handles the "rotate-key" command: generates a new keypair and queues our rotation
+    to it, signed with both keys (see roster). We keep signing with the current key until
+    the rotation is finalized; with a keyfile, the new key waits beside it meanwhile. */
+    fn rotate_key(&mut self) {
+        if self.next_key.is_some() {
+            warn!("Not rotating our key: the last rotation isn't finalized yet");
+            return;
+        }
+        let next_key = Keypair::generate(self.signature_scheme);
+        if let Some((path, passphrase)) = &self.keyfile {
+            if let Err(e) = keystore::stage_next(path, &next_key, passphrase) {
+                error!("Not rotating our key: can't save the new one: {}", e);
+                return;
+            }
+        }
+        let change = RosterChange::rotate_key(self.name.clone(), &*self.signer, &next_key, &self.chain_id);
+        self.pending_transactions.push(ROSTER_SUBMITTER, change.to_entry().serialize());
+        info!("Queued the rotation of our key to {}", hex::encode(bincode::serialize(&next_key.public()).expect("Failed serialization.")));
+        self.next_key = Some(next_key);
+    }
+
+    /* Our key rotation is finalized: signs with the new key from now on.
+    @param public_key: the key the log rotated us to */
+    fn finish_key_rotation(&mut self, public_key: &PublicKey) {
+        if self.signer.public() == *public_key {
+            return;
+        }
+        // After a restart, the new key is where rotate_key left it
+        let staged = match (self.next_key.take(), &self.keyfile) {
+            (Some(next_key), _) => Some(next_key),
+            (None, Some((path, passphrase))) => keystore::load_next(path, passphrase).ok().flatten(),
+            (None, None) => None,
+        };
+        match staged {
+            Some(next_key) if next_key.public() == *public_key => {
+                if let Some((path, _)) = &self.keyfile {
+                    if let Err(e) = keystore::promote_next(path) {
+                        error!("Can't replace our keyfile with the rotated key: {}", e);
+                    }
+                }
+                self.signer = Arc::new(next_key);
+                info!("Our key rotation is finalized; signing with the new key");
+            }
+            staged => {
+                self.next_key = staged;
+                error!("The log rotated our key to one we don't hold; other validators won't accept our signatures");
+            }
+        }
+    }
+
+    /* Queues our own Join once peer discovery is done, unless the log already has us
+    (with this key, or one we rotated to since), so the starting roster is recorded (see roster). */
+    fn announce_own_join(&mut self) {
+        if self.role == Role::Observer {
+            return;
+        }
+        let join = RosterChange::Join { validator: self.name.clone(), public_key: self.signer.public(), weight: DEFAULT_WEIGHT };
+        if !self.roster_history.roster_at(u64::MAX).contains_key(&self.name) && !self.roster_history.contains(&join) {
+            self.pending_transactions.push(ROSTER_SUBMITTER, join.to_entry().serialize());
+        }
+    }
+
+    /* Agrees to a roster change, so this node votes for a block announcing it. Does not
+    queue the announcement; the "roster" command does both.
+    @param change: the change */
+    pub fn approve_roster_change(&mut self, change: RosterChange) {
+        self.roster_history.approve(change);
+    }
+
+    /* Every finalized roster change, oldest first, with the certificate that approved it. */
+    pub fn roster_history(&self) -> &[RosterRecord] {
+        self.roster_history.records()
+    }
+
+    /* Validators whose votes are in a roster change's certificate, by name. */
+    fn roster_approvers(&self, record: &RosterRecord) -> Vec<String> {
+        let signers = record.cert.signers(&record.block, &self.public_keys, self.signature_scheme, &self.chain_id);
+        signers.into_iter().sorted().collect()
+    }
+
+    /* Acts on a finalized validator announcement (upgrade, maintenance window or roster
+    change). Returns false if the entry isn't one.
+    @param entry: the finalized entry
+    @param block: its block
+    @param cert: the block's certificate */
+    fn apply_announcement(&mut self, entry: &LogEntry, block: &Block, cert: &NotarizationCert) -> bool {
+        if let Some(upgrade) = ProtocolUpgrade::from_entry(entry) {
+            self.schedule_upgrade(&upgrade, block.epoch);
+        } else if let Some(window) = MaintenanceWindow::from_entry(entry) {
+            // Signatures were checked by the voters that finalized it (see announcement_is_acceptable)
+            info!("{} is in maintenance during epochs {}..{}", window.validator, window.start_epoch, window.end_epoch);
+            self.maintenance.schedule(window);
+        } else if let Some(change) = RosterChange::from_entry(entry) {
+            if self.roster_history.record(change.clone(), block, cert) {
+                info!("Roster change at height {}: {}", block.height, change);
+                // Voters checked the rotation against the validator's key (see announcement_is_acceptable)
+                if let RosterChange::RotateKey { validator, public_key, .. } = &change {
+                    if let Some(key) = self.public_keys.get_mut(validator) {
+                        *key = *public_key;
+                        self.validators_changed();
+                    }
+                    if *validator == self.name {
+                        self.finish_key_rotation(public_key);
+                    }
+                    self.rekey_roster_channel();
+                }
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /* Whether we may vote for a block's validator announcement, if it carries one:
+    upgrades need our operator's approval, maintenance windows the validator's own
+    signature, and either must be valid in the block's epoch. Roster changes need our
+    operator's approval, except a validator joining with the key discovery gave us, and
+    a key rotation signed by the validator's current key. */
+    fn announcement_is_acceptable(&self, block: &Block) -> bool {
+        let entry = match LogEntry::deserialize(&block.data) {
+            Some(entry) if is_announcement(&entry.data) => entry,
+            _ => return true,
+        };
+        if let Some(upgrade) = ProtocolUpgrade::from_entry(&entry) {
+            self.upgrades.is_approved(&upgrade) && self.upgrades.check(&upgrade, block.epoch).is_ok()
+        } else if let Some(window) = MaintenanceWindow::from_entry(&entry) {
+            self.public_keys.get(&window.validator).is_some_and(|pk| window.verify(pk, &self.chain_id))
+                && window.check(block.epoch).is_ok()
+        } else if let Some(change) = RosterChange::from_entry(&entry) {
+            let self_authorized = match &change {
+                RosterChange::Join { validator, public_key, weight } => {
+                    *weight == DEFAULT_WEIGHT && self.public_keys.get(validator) == Some(public_key)
+                }
+                RosterChange::RotateKey { validator, .. } => {
+                    self.public_keys.get(validator).is_some_and(|pk| change.verify_rotation(pk, &self.chain_id))
+                }
+                _ => false,
+            };
+            (self_authorized || self.roster_history.is_approved(&change)) && !self.roster_history.contains(&change)
+        } else {
+            false
+        }
+    }
+
+    /* The leader's time to propose ran out. If it hasn't, the epoch is empty: we record
+    it and won't vote for a proposal that arrives later, and the next leader extends
+    the longest notarized chain as it would after any epoch (see make_proposal). Leaders
+    in maintenance are never scheduled, so planned downtime doesn't trigger this.
+    @param epoch: the current epoch */
+    fn proposal_timed_out(&mut self, epoch: u64) {
+        if self.sorted_peer_names.is_empty() || self.last_proposal_epoch >= epoch || self.seen_block_this_epoch.is_some() {
+            return;
+        }
+        self.empty_epoch = Some(epoch);
+        self.empty_epoch_count += 1;
+        warn!(
+            "Epoch: {}, no proposal from {} within {:?}; the epoch is empty",
+            epoch,
+            self.get_epoch_leader(epoch),
+            self.epoch_length / PROPOSAL_TIMEOUT_DIVISOR
+        );
+    }
+
+    /* Signs a promise to include an entry within the maximum merge delay, and
+    remembers it until the entry is finalized.
+    @param entry_id: the entry's id
+    @param entry: the entry's bytes, as they will be stored in its block */
+    fn promise_inclusion(&mut self, entry_id: EntryId, entry: &[u8]) -> InclusionPromise {
+        let max_merge_delay = self.max_merge_delay.unwrap_or(self.epoch_length * MERGE_DELAY_EPOCHS);
+        let promise = InclusionPromise::sign(entry_id, entry, max_merge_delay, self.name.clone(), &*self.signer, &self.chain_id);
+        self.outstanding_promises.insert(entry_id, promise.clone());
+        self.performance.promise_made(clock::unix_time_ms());
+        promise
+    }
+
+    /* Proof that a finalized entry is in the latest signed tree head. */
+    fn inclusion_proof(&mut self, entry_id: EntryId, entry: &[u8]) -> Option<InclusionProof> {
+        let sth = self.latest_sth.clone()?;
+        let proof = self.read_cache.inclusion_proof(self.blockchain_manager.merkle_tree(), &leaf_hash(entry), sth.tree_size)?;
+        Some(InclusionProof { entry_id, proof, sth })
+    }
+
+    /* Warns about (and forgets) promises whose merge delay ran out before the entry
+    was finalized. */
+    fn check_overdue_promises(&mut self) {
+        let now = clock::unix_time_ms();
+        let mut missed = Vec::new();
+        self.outstanding_promises.retain(|id, promise| {
+            let overdue = promise.deadline_ms() < now;
+            if overdue {
+                missed.push(*id);
+            }
+            !overdue
+        });
+        for entry_id in missed {
+            self.raise(NodeAlert::MissedMergeDelay { entry_id });
+            self.performance.promise_settled(false, now);
+        }
+    }
+
+    /* Alerts the operator: logs the alert and hands it to every subscriber (see
+    subscribe_alerts). */
+    fn raise(&mut self, alert: NodeAlert) {
+        match alert.severity() {
+            Severity::Critical => error!("{}", alert),
+            Severity::Warning => warn!("{}", alert),
+        }
+        self.alert_subscribers.retain(|subscriber| subscriber.send(alert.clone()).is_ok());
+    }
+
+    /* A stream of this node's alerts (consensus stalls, storage failures, misbehaving
+    peers, split views...; see alerts), for services embedding the node to route into
+    their own alerting. Every subscriber gets every alert raised after it subscribed;
+    dropping the receiver unsubscribes. */
+    pub fn subscribe_alerts(&mut self) -> mpsc::UnboundedReceiver<NodeAlert> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.alert_subscribers.push(sender);
+        receiver
+    }
+
+    /* A stream of the blocks this node finalizes, each with its notarization
+    certificate, for services embedding the node to build state machines on. Blocks
+    come once each, in height order, from the first finalized after subscribing
+    (earlier ones are in the finalized chain, e.g. through the HTTP API); empty blocks
+    and validator announcements included. Dropping the receiver unsubscribes. */
+    pub fn subscribe_finalized(&mut self) -> mpsc::UnboundedReceiver<SignedBlock> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.finalized_subscribers.push(sender);
+        receiver
+    }
+
+    /* Writes this node's finalized chain, with its certificates and the validators
+    that signed them, to a file (see blockchain::export).
+    @param format: json or bin
+    @param path: file to write (replaced if it exists)
+    Returns the number of blocks written, genesis included; fails on a pruned node,
+    which no longer has the whole chain. */
+    pub fn export_chain<P: AsRef<Path>>(&self, format: ExportFormat, path: P) -> Result<usize, StreamletError> {
+        let checkpoint = self.blockchain_manager.checkpoint();
+        if checkpoint > 0 {
+            return Err(StreamletError::Config(format!("blocks below height {} are pruned here; export from an archive node", checkpoint)));
+        }
+        let export = ChainExport {
+            chain_id: self.chain_id,
+            validators: self.public_keys.clone().into_iter().collect(),
+            chain: self.blockchain_manager.finalized_chain().clone(),
+        };
+        fs::write(path, export.encode(format).map_err(StreamletError::Serialization)?)?;
+        Ok(export.chain.length())
+    }
+
+    /* Loads a chain written by export_chain as this node's finalized chain, once it
+    checks out (see blockchain::export). Must be called before run(), and after
+    open_store for the blocks to be persisted. Loads nothing if the file can't be read,
+    doesn't check out, or conflicts with the chain the node already has.
+    @param path: the export
+    Returns the number of blocks it added. */
+    pub fn import_chain<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, StreamletError> {
+        let export = ChainExport::decode(&fs::read(path)?).map_err(StreamletError::Import)?;
+        export.verify(&self.chain_id, self.signature_scheme, self.quorum_rule).map_err(StreamletError::Import)?;
+        let added = self.blockchain_manager.adopt_finalized_chain(export.chain).map_err(StreamletError::Import)?;
+        info!("Imported {} finalized blocks; finalized chain length {}", added, self.blockchain_manager.finalized_chain().length());
+        Ok(added)
+    }
+
+    /* Handles "export-chain [--format json|bin] --out <file>" from stdin. */
+    fn export_chain_command(&self, args: &str) {
+        const USAGE: &str = "Usage: export-chain [--format json|bin] --out <file>";
+        let mut format = ExportFormat::default();
+        let mut out = None;
+        let mut args = args.split_whitespace();
+        while let Some(flag) = args.next() {
+            match (flag, args.next()) {
+                ("--format", Some(value)) => match value.parse() {
+                    Ok(value) => format = value,
+                    Err(e) => {
+                        warn!("{}", e);
+                        return;
+                    }
+                },
+                ("--out", Some(path)) => out = Some(path),
+                _ => {
+                    warn!("{}", USAGE);
+                    return;
+                }
+            }
+        }
+        match out {
+            Some(path) => match self.export_chain(format, path) {
+                Ok(blocks) => info!("Exported {} finalized blocks to {} ({})", blocks, path, format),
+                Err(e) => warn!("Couldn't export the chain to {}: {}", path, e),
+            },
+            None => warn!("{}", USAGE),
+        }
+    }
+
+    /* Mirrors the blocks this node finalizes into another system (see sink). Must be
+    called before run(), which starts the sinks.
+    @param sink: e.g. a WebhookSink, or an embedder's own */
+    pub fn add_finalization_sink(&mut self, sink: Box<dyn FinalizationSink>) {
+        self.finalization_sinks.push(sink);
+    }
+
+    fn notify_finalized(&mut self, signed: &SignedBlock) {
+        self.finalized_subscribers.retain(|subscriber| subscriber.send(signed.clone()).is_ok());
+    }
+
+    /* Our signed performance report over the period ending now (see performance).
+    @param period: day or week */
+    pub fn performance_report(&self, period: ReportPeriod) -> PerformanceReport {
+        self.performance
+            .report(self.name.clone(), period, clock::unix_time_ms(), self.epoch_length)
+            .sign(&*self.signer, &self.chain_id)
+    }
+
+    /* Handles the "report [daily|weekly]" command: logs our signed performance report. */
+    fn log_performance_report(&self, period: ReportPeriod) {
+        let report = self.performance_report(period);
+        info!("Performance report: {}", report.to_json(&self.signer.public(), &self.chain_id));
+    }
+
+    /* Writes a performance report to the report directory once per period (see
+    set_report_schedule). */
+    fn write_scheduled_report(&mut self) {
+        let (period, dir) = match &self.report_schedule {
+            Some(schedule) => schedule.clone(),
+            None => return,
+        };
+        let now = clock::unix_time_ms();
+        if now < self.last_report_ms + period.duration().as_millis() as u64 {
+            return;
+        }
+        self.last_report_ms = now;
+        let report = self.performance_report(period);
+        let path = dir.join(format!("report-{}-{}-{}.json", self.name, period, report.end_ms));
+        let json = report.to_json(&self.signer.public(), &self.chain_id);
+        match fs::write(&path, json.to_string()) {
+            Ok(()) => info!("Wrote {} performance report to {}", period, path.display()),
+            Err(e) => warn!("Couldn't write performance report to {}: {}", path.display(), e),
+        }
+    }
+
+    /* Writes a signed performance report to a directory at the end of every period,
+    for publishing. Must be called before run().
+    @param period: day or week
+    @param dir: where reports go (one file per report) */
+    pub fn set_report_schedule<P: Into<std::path::PathBuf>>(&mut self, period: ReportPeriod, dir: P) {
+        self.report_schedule = Some((period, dir.into()));
+    }
+
+    /* Feeds verified votes to the vote analyzer.
+    @param block: the block voted for
+    @param votes: verified (signer, signature) pairs
+    @param epoch: the current epoch */
+    fn analyze_votes(&mut self, block: &Block, votes: &[(String, Signature)], epoch: u64) {
+        if self.sorted_peer_names.is_empty() {
+            return;
+        }
+        let proposer = self.get_epoch_leader(block.epoch);
+        for (voter, _) in votes {
+            // Catch-up votes after planned downtime aren't anomalies
+            if self.maintenance.in_maintenance(voter, block.epoch) {
+                continue;
+            }
+            self.vote_analyzer.record_vote(voter, block, &proposer, epoch);
+        }
+    }
+
+    /* Reports suspicious voting patterns to the operator. */
+    fn log_vote_anomalies(&mut self) {
+        let anomalies = self.vote_analyzer.anomalies();
+        if anomalies.is_empty() {
+            info!("No voting anomalies detected");
+        }
+        for anomaly in anomalies {
+            self.raise(NodeAlert::VotingAnomaly(anomaly));
+        }
+    }
+
+    /* Checks the tree head and proofs this node serves against the latest tree heads the
+    other validators signed (see self_audit), and alerts on whatever disagrees. */
+    fn self_audit(&mut self) {
+        let peer_heads: Vec<SignedTreeHead> = self
+            .sth_monitor
+            .heads()
+            .values()
+            .filter(|head| head.signer != self.name && self.public_keys.contains_key(&head.signer))
+            .sorted_by(|a, b| a.signer.cmp(&b.signer))
+            .cloned()
+            .collect();
+        let public_key = self.signer.public();
+        let chain_id = self.chain_id;
+        let mut rng = rand::thread_rng();
+        let divergences = self_audit::audit_served(
+            &mut |request| self.answer_api_request(request),
+            &public_key,
+            &chain_id,
+            &peer_heads,
+            &mut |entries| rng.gen_range(0, entries),
+        );
+        for divergence in divergences {
+            self.raise(NodeAlert::SelfAudit(divergence));
+        }
+    }
+
+    /* Answers an HTTP API request from the node's current state. */
+    fn answer_api_request(&mut self, request: ApiRequest) -> ApiResponse {
+        match request {
+            ApiRequest::AddEntry { data, callback, content_type, id, submission } => {
+                if is_announcement(&data) {
+                    return Err(ApiError::bad_request("validator announcements can't be submitted"));
+                }
+                if self.role == Role::Observer {
+                    return Err(ApiError::bad_request("observers don't take entries; submit to a validator"));
+                }
+                if callback.is_some() && self.callback_secret.is_none() {
+                    return Err(ApiError::bad_request("this node doesn't do callbacks (no --callback-secret)"));
+                }
+                // Queued here, and passed on to the next leader (see forward_to_leader)
+                let mut entry = LogEntry { content_type, ..LogEntry::new(data) };
+                if let Some(id) = id {
+                    entry.id = id;
+                }
+                self.admit_entry(&entry, submission.as_deref()).map_err(|e| ApiError::forbidden(&e))?;
+                // The client signed the entry as it sent it, so one we seal goes on under our name
+                let sealed_here = self.payload_key.is_some() && !PayloadKey::is_sealed(&entry);
+                let entry = self.seal_entry(entry).map_err(|e| ApiError::bad_request(&e))?;
+                let bytes = entry.serialize();
+                let promise = self.promise_inclusion(entry.id, &bytes);
+                let submitter = submission.as_deref().map_or(HTTP_API_SUBMITTER, |submission| submission.submitter.as_str());
+                self.pending_transactions.push(submitter, bytes);
+                if let Some(url) = callback {
+                    self.callbacks.insert(entry.id, url);
+                }
+                self.leader_forwards.push((entry.clone(), submission.filter(|_| !sealed_here).map(|submission| *submission)));
+                info!("Received entry {} over HTTP; adding to pending transactions", entry.id.format(self.entry_id_format));
+                Ok(json_schema::receipt_json(&promise))
+            }
+            ApiRequest::GetSth => {
+                let sth = self.latest_sth.as_ref().ok_or_else(|| ApiError::not_found("no tree head yet"))?;
+                Ok(json_schema::sth_json(sth, &self.signer.public()))
+            }
+            ApiRequest::GetProofByHash { hash, tree_size } => {
+                let proof = self
+                    .read_cache
+                    .inclusion_proof(self.blockchain_manager.merkle_tree(), &hash, tree_size)
+                    .ok_or_else(|| ApiError::not_found("no such leaf in a tree of that size"))?;
+                Ok(json_schema::inclusion_json(&proof))
+            }
+            ApiRequest::GetConsistency { first, second } => {
+                let proof = self
+                    .read_cache
+                    .consistency_proof(self.blockchain_manager.merkle_tree(), first, second)
+                    .ok_or_else(|| ApiError::bad_request("tree sizes out of range"))?;
+                Ok(json_schema::consistency_json(&proof))
+            }
+            ApiRequest::GetEntries { start, end } => {
+                if start >= self.blockchain_manager.merkle_tree().size() {
+                    return Err(ApiError::bad_request("'start' is beyond the tree size"));
+                }
+                let pruned = self.blockchain_manager.pruned_entries();
+                if start < pruned {
+                    return Err(ApiError::not_found(&format!("entries below {} are pruned on this node; ask an archive node", pruned)));
+                }
+                let end = end.min(start + http_api::MAX_ENTRIES - 1);
+                let entries: Vec<_> = self
+                    .blockchain_manager
+                    .finalized_entries(start, end + 1)
+                    .into_iter()
+                    .map(|data| {
+                        let mut rendered = match LogEntry::deserialize(data) {
+                            Some(entry) => json_schema::entry_json(&self.open_entry(entry)),
+                            None => json!({ "id": null }),
+                        };
+                        rendered["leaf_input"] = json!(hex::encode(data));
+                        rendered
+                    })
+                    .collect();
+                Ok(json!({ json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION, "entries": entries }))
+            }
+            ApiRequest::GetRosterHistory => {
+                let changes: Vec<_> = self
+                    .roster_history
+                    .records()
+                    .iter()
+                    .map(|record| json_schema::roster_json(record, &self.roster_approvers(record)))
+                    .collect();
+                Ok(json!({ json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION, "changes": changes }))
+            }
+            ApiRequest::Health => {
+                self.health
+                    .liveness(self.discovered, clock::unix_time_ms(), self.epoch_length)
+                    .map_err(|reason| ApiError::unavailable(&reason))?;
+                Ok(json!({ json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION, "status": "ok", "epoch": self.current_epoch }))
+            }
+            ApiRequest::Ready => {
+                let height = self.blockchain_manager.head().0.height;
+                self.health
+                    .readiness(self.discovered, height, self.sync_after_reconnect)
+                    .map_err(|reason| ApiError::unavailable(&reason))?;
+                Ok(json!({ json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION, "status": "ready", "height": height }))
+            }
+            ApiRequest::GetEntry { id } => {
+                let chain = &self.blockchain_manager;
+                let entry = self
+                    .read_cache
+                    .entry(chain.merkle_tree(), id, || chain.find_finalized_entry(&id).map(|signed| signed.block.data.clone()))
+                    .and_then(|data| LogEntry::deserialize(&data))
+                    .ok_or_else(|| ApiError::not_found("no such finalized entry"))?;
+                Ok(json_schema::entry_json(&self.open_entry(entry)))
+            }
+            ApiRequest::GetBlock { height } => {
+                let SignedBlock { block, cert } = self
+                    .blockchain_manager
+                    .finalized_chain()
+                    .blocks
+                    .iter()
+                    .find(|signed| signed.block.height == height)
+                    .ok_or_else(|| ApiError::not_found("no finalized block at that height"))?;
+                Ok(json_schema::block_json(block, &cert.signatures))
+            }
+            ApiRequest::GetChainStatus => {
+                let tree = self.blockchain_manager.merkle_tree();
+                Ok(json!({
+                    json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION,
+                    "epoch": self.current_epoch,
+                    "notarized_height": self.blockchain_manager.head().0.height,
+                    "finalized_height": self.blockchain_manager.get_latest_finalized_block().0.height,
+                    "tree_size": tree.size(),
+                    "sha256_root_hash": hex::encode(tree.root()),
+                }))
+            }
+            ApiRequest::GetProofById { id } => {
+                let data = self
+                    .blockchain_manager
+                    .find_finalized_entry(&id)
+                    .map(|signed| signed.block.data.clone())
+                    .ok_or_else(|| ApiError::not_found("no such finalized entry"))?;
+                // Finalized, but not yet under a signed tree head
+                let inclusion = self.inclusion_proof(id, &data).ok_or_else(|| ApiError::not_found("entry isn't under a tree head yet"))?;
+                Ok(json_schema::bundle_json(&id, &data, &inclusion, &self.signer.public()))
+            }
+        }
+    }
+
+    /* Checks another node's tree head from the STH topic: the monitor checks (signature,
+    rollback, consistency with its earlier heads, split views between nodes), the signer's
+    key against the one it advertised, and its root against our own log at that size.
+    Returns any alerts.
+    @param update: the tree head update */
+    fn receive_tree_head(&mut self, update: &TreeHeadUpdate) -> Vec<Alert> {
+        let signer = update.sth.signer.clone();
+        if signer == self.name {
+            return Vec::new();
+        }
+        if self.public_keys.get(&signer).is_some_and(|pk| *pk != update.public_key) {
+            return vec![Alert::KeyChanged { signer }];
+        }
+        let mut alerts = self.sth_monitor.observe(update);
+        if alerts.iter().any(|alert| matches!(alert, Alert::BadSignature { .. } | Alert::KeyChanged { .. })) {
+            return alerts;
+        }
+        // Heads no bigger than our log must match it; bigger ones we check once we catch up
+        let ours = self.blockchain_manager.merkle_tree().root_at(update.sth.tree_size);
+        if ours.is_some_and(|root| root != update.sth.root_hash) {
+            alerts.push(Alert::SplitView { tree_size: update.sth.tree_size, signers: (self.name.clone(), signer) });
+        }
+        alerts
+    }
+
+    /* Publishes the latest tree head on the STH topic, with a consistency proof from
+    the previously published one. Also sent periodically when unchanged, so nodes and
+    monitors that missed it can compare. */
+    fn publish_tree_head(&mut self, net_stack: &mut NetworkStack) {
+        let sth = match &self.latest_sth {
+            Some(sth) => sth.clone(),
+            None => return,
+        };
+        let consistency = match self
+            .blockchain_manager
+            .merkle_tree()
+            .prove_consistency(self.published_tree_size, sth.tree_size)
+        {
+            Some(proof) => proof,
+            None => return,
+        };
+        self.published_tree_size = sth.tree_size;
+        let update = TreeHeadUpdate { sth, public_key: self.signer.public(), consistency };
+        let message = Message::new(MessagePayload::TreeHead(update), MessageKind::TreeHead, self.id, self.name.clone());
+        log_unsent("tree head", net_stack.broadcast_to_topic(monitor::STH_TOPIC, message.serialize()));
+    }
+
+    /* Signs a tree head over the current finalized log, and computes the proofs
+    monitors will ask for against it (see read_cache). */
+    fn sign_tree_head(&mut self) {
+        let tree = self.blockchain_manager.merkle_tree();
+        let sth = SignedTreeHead::sign(tree, self.name.clone(), &*self.signer, &self.chain_id);
+        info!("Signed tree head: size {}, root {}", sth.tree_size, hex::encode(sth.root_hash));
+        let previous_size = self.latest_sth.as_ref().map(|previous| previous.tree_size);
+        self.read_cache.precompute(tree, sth.tree_size, previous_size);
+        self.latest_sth = Some(sth);
+    }
+
+    /* Durably records that we are about to vote for (or propose) a block.
+    Returns false if the vote must not be sent: we already voted for a different
+    block in this epoch (possibly before a restart), or the journal can't be written.
+    @param epoch: the current epoch
+    @param block_hash: hash of the block we want to vote for */
+    fn journal_vote(&mut self, epoch: u64, block_hash: &Sha256Hash) -> bool {
+        let journal = match self.vote_journal.as_mut() {
+            Some(journal) => journal,
+            None => return true,
+        };
+        match journal.record(epoch, block_hash) {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                self.raise(NodeAlert::StorageFailure { what: format!("vote journal (epoch {}; not voting)", epoch), error: e.to_string() });
+                false
+            }
+        }
+    }
+
+    /* Re-derives the roster channel key after the roster changed. */
+    fn rekey_roster_channel(&mut self) {
+        if let Some(channel) = self.roster_channel.as_mut() {
+            let roster: Vec<(String, PublicKey)> = self
+                .public_keys
+                .iter()
+                .filter(|(name, _)| !name.is_empty())
+                .map(|(name, pk)| (name.clone(), *pk))
+                .collect();
+            channel.rekey(&roster);
+        }
+    }
+
+    /* Sends a signed announcement to the other validators over the roster channel.
+    @param text: the announcement (e.g. a planned maintenance window) */
+    fn send_roster_notice(&self, net_stack: &mut NetworkStack, text: &str) {
+        let channel = match &self.roster_channel {
+            Some(channel) => channel,
+            None => {
+                warn!("Roster channel is disabled (start with --roster-secret)");
+                return;
+            }
+        };
+        let mut notice = Message::new(
+            MessagePayload::String(text.to_string()),
+            MessageKind::RosterNotice,
+            self.id,
+            self.name.clone(),
+        );
+        notice.sign_message(self.sign(&notice.signed_bytes(&self.chain_id)));
+        match channel.seal(&notice.serialize()) {
+            Some(envelope) => {
+                let message = Message::new(MessagePayload::Sealed(envelope), MessageKind::RosterSealed, self.id, self.name.clone());
+                log_unsent("roster notice", net_stack.broadcast_to_topic(ROSTER_TOPIC, message.serialize()));
+            }
+            None => warn!("Roster channel has no key yet (peer discovery not done)"),
+        }
+    }
+
+    /* Decrypts and logs an announcement from another validator, if it was sealed for
+    our roster and signed by its sender. */
+    fn receive_roster_notice(&self, envelope: &SealedEnvelope) {
+        let plaintext = match self.roster_channel.as_ref().and_then(|channel| channel.open(envelope)) {
+            Some(plaintext) => plaintext,
+            None => {
+                debug!("Ignoring roster message we can't open");
+                return;
+            }
+        };
+        let notice: Message = match bincode::deserialize(&plaintext) {
+            Ok(notice) => notice,
+            Err(_) => return,
+        };
+        let signed_by_sender = match (self.public_keys.get(&notice.sender_name), notice.signatures.first()) {
+            (Some(pk), Some(signature)) => self.verify_signature(&notice, signature, pk),
+            _ => false,
+        };
+        match (&notice.kind, &notice.payload) {
+            (MessageKind::RosterNotice, MessagePayload::String(text)) if signed_by_sender => {
+                info!("Roster notice from {}: {}", notice.sender_name, text);
+            }
+            _ => warn!("Dropping unsigned or malformed roster message claiming to be from {}", notice.sender_name),
+        }
+    }
+
+    /* Broadcasts a block we just notarized, with the votes that did it, so peers
+    still collecting votes can notarize it at once.
+    @param block: the notarized block */
+    fn announce_notarized(&mut self, block: &Block, net_stack: &mut NetworkStack) {
+        let mut message = Message::new(MessagePayload::Block(block.clone()), MessageKind::Notarize, self.id, self.name.clone());
+        message.signatures = self.blockchain_manager.votes_for(&block.hash);
+        self.broadcast_sealed(net_stack, message);
+    }
+
+    /* Asks peers for the notarized blocks we are missing (at most once per epoch).
+    Far behind the network (SNAPSHOT_SYNC_GAP blocks or more), asks for a snapshot of
+    the finalized chain instead, unless the last one got us nowhere.
+    Responses are validated before use; see is_chain_certified and ChainSnapshot::verify.
+    @param epoch: the current epoch */
+    fn request_chain_sync(&mut self, net_stack: &mut NetworkStack, epoch: u64) {
+        if self.chain_sync_epoch == Some(epoch) {
+            return;
+        }
+        let request = ChainSyncRequest {
+            from_height: self.blockchain_manager.get_latest_finalized_block().0.height,
+            known_height: self.blockchain_manager.head().0.height,
+        };
+        let snapshot = self.health.sync_target().saturating_sub(request.from_height) >= SNAPSHOT_SYNC_GAP
+            && self.snapshot_sync_from != Some(request.from_height);
+        let kind = match snapshot {
+            true => {
+                self.snapshot_sync_from = Some(request.from_height);
+                MessageKind::SnapshotRequest
+            }
+            false => MessageKind::SyncRequest,
+        };
+        let message = Message::new(
+            MessagePayload::ChainSyncRequest(request),
+            kind,
+            self.id,
+            self.name.clone(),
+        );
+        info!("Epoch: {}, requesting {} from peers", epoch, if snapshot { "a chain snapshot" } else { "chain sync" });
+        self.chain_sync_tag = Some(message.tag);
+        self.chain_sync_epoch = Some(epoch);
+        // A permissioned deployment only answers its validators, so show we are one
+        let bytes = match self.static_roster {
+            Some(_) => self.seal(message),
+            None => message.serialize(),
+        };
+        // Ask a few peers directly; only flood the request if we don't know any yet
+        match net_stack.send_direct_request(bytes.clone(), CHAIN_SYNC_PEERS) {
+            Ok(0) => log_unsent("chain sync request", net_stack.broadcast_message(bytes)),
+            Ok(_) => {}
+            Err(e) => log_unsent("chain sync request", Err(e)),
+        }
+    }
+
+    /* Answers a peer catching up with the notarized blocks it is missing, if we have any.
+    @param message: the peer's request
+    @param request: what it has
+    @param epoch: the current epoch */
+    fn send_chain_segment(&mut self, net_stack: &mut NetworkStack, message: &Message, request: &ChainSyncRequest, epoch: u64) {
+        if self.blockchain_manager.head().0.height <= request.known_height {
+            return;
+        }
+        if let Some(chain) = self.blockchain_manager.notarized_chain_from(request.from_height, CHAIN_SYNC_MAX_BLOCKS) {
+            let response = Message::new_with_defined_tag(
+                MessagePayload::Chain(chain),
+                MessageKind::SyncResponse,
+                message.tag,
+                self.id,
+                self.name.clone(),
+            );
+            info!("Epoch: {}, sending notarized chain to {} for catch-up", epoch, message.sender_name);
+            log_unsent("catch-up chain", net_stack.respond(message.tag, &message.sender_name, response.serialize()));
+        }
+    }
+
+    /* Answers a peer far behind with a snapshot of our finalized chain (see snapshot).
+    Falls back to a segment, as for a SyncRequest, if our finalized chain is no longer
+    than the peer's, we can't show our tip is final, or the snapshot is too large to send.
+    @param message: the peer's request
+    @param request: what it has
+    @param epoch: the current epoch */
+    fn send_snapshot(&mut self, net_stack: &mut NetworkStack, message: &Message, request: &ChainSyncRequest, epoch: u64) {
+        let snapshot = match self.blockchain_manager.snapshot() {
+            Some(snapshot) if snapshot.chain.head().0.height > request.from_height => snapshot,
+            _ => return self.send_chain_segment(net_stack, message, request, epoch),
+        };
+        let blocks = snapshot.chain.length() - 1;
+        let response = Message::new_with_defined_tag(
+            MessagePayload::Snapshot(snapshot),
+            MessageKind::SnapshotResponse,
+            message.tag,
+            self.id,
+            self.name.clone(),
+        );
+        match net_stack.respond(message.tag, &message.sender_name, response.serialize()) {
+            Ok(()) => info!("Epoch: {}, sending a snapshot of {} finalized blocks to {}", epoch, blocks, message.sender_name),
+            Err(StreamletError::MessageTooLarge(len)) => {
+                debug!("Snapshot of {} bytes is too large to send; sending {} a segment", len, message.sender_name);
+                self.send_chain_segment(net_stack, message, request, epoch);
+            }
+            Err(e) => log_unsent("snapshot", Err(e)),
+        }
+    }
+
+    /* Validates a chain segment received during catch-up: it must start at a block
+    we already have notarized, link up by parent hash, and every later block must
+    carry a notarization certificate with votes from a quorum of known signers.
+    @param chain: the segment to validate */
+    fn is_chain_certified(&self, chain: &LocalChain) -> bool {
+        let anchor = match chain.blocks.first() {
+            Some(signed_block) => &signed_block.block,
+            None => return false,
+        };
+        if !self.blockchain_manager.is_block_notarized(&anchor.hash) || !BlockchainManager::is_chain_valid(chain) {
+            return false;
+        }
+        let quorum = self.quorum_size();
+        chain.blocks.iter().skip(1).all(|SignedBlock { block, cert }| {
+            match cert.verify(block, &self.public_keys, self.signature_scheme, quorum, &self.chain_id) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Block at height {} isn't certified: {}", block.height, e);
+                    false
+                }
+            }
+        })
+    }
+
+    /* Builds and signs this epoch's proposal: a block extending the longest
+    notarized chain, carrying the oldest pending transaction (or no data).
+    @param epoch: the current epoch */
+    fn make_proposal(&mut self, epoch: u64) -> Message {
+        let data = self.pending_transactions.pop().unwrap_or_default();
+        let height = u64::try_from(self.blockchain_manager.longest_notarized_chain_length).unwrap();
+        let mut parent_hash = self.blockchain_manager.head().0.hash;
+        let proposed_block = Block::new(
+            {
+                if self.compromise_type == CompromiseType::EarlyEpoch { 0 }
+                else if self.compromise_type == CompromiseType::LateEpoch { epoch + 50 }
+                else { epoch }
+            },
+            {
+                if self.compromise_type == CompromiseType::WrongParentHash { parent_hash.sort() }
+                parent_hash
+            },
+            data,
+            height,
+            rand::thread_rng().gen(),
+        );
+
+        Message::new(
+            MessagePayload::Block(proposed_block),
+            MessageKind::Propose,
+            self.id,
+            self.name.clone(),
+        )
+    }
+
+    /* Signs a message's payload and adds the signature to the message
+    after verifying it has not already signed it (currently inefficient)
+    Returns our signature, or AlreadySigned if it's already been signed
+    by us, or Signing if our signer failed
+     @param message: the message instance with a payload to be signed */
+    fn sign_message(&self, message: &mut Message) -> Result<Signature, StreamletError> {
+        // Create signature
+        let signature: Signature = self.signer.try_sign(&message.signed_bytes(&self.chain_id))?;
+        // Make sure we haven't signed already
+        for s in message.clone().get_signatures() {
+            if signature == s { return Err(StreamletError::AlreadySigned); }
+        }
+
+        message.sign_message(signature);
+        Ok(signature)
+    }
+
+    /* Matches each valid signature on the message to the known signer whose
+    key verifies it. Each key counts once, however many of the signatures it
+    verifies (a repeated signature, or a second one from the same validator).
+        @param message: the message instance with signatures
+    Returns (signer name, signature) pairs, one per distinct signer. */
+    fn identify_signers(&self, message: &Message) -> Vec<(String, Signature)> {
+        // Matched on the verification pool already, against the keys we still go by
+        if let Some(done) = &self.verified {
+            if done.message == *message && done.public_keys == self.public_keys {
+                return done.signers.clone();
+            }
+        }
+        verify_pool::identify_signers(message, &self.public_keys, self.signature_scheme, &self.chain_id, &self.signature_cache)
+    }
+
+    /* Verifies a (message, signature) pair against a public key.
+    @param message: the message instance with a (signature, payload) pair to be validated
+    @param signature: signature of the message to be validated
+    @param pk: public key to verify against the signature */
+    fn verify_signature(&self, message: &Message, signature: &Signature, pk: &PublicKey) -> bool {
+        verify_pool::verify_signature(message, signature, pk, self.signature_scheme, &self.chain_id, &self.signature_cache)
+    }
+
+    /* Number of distinct signatures needed to notarize a block.
+    Partially synchronous model: >= 2N/3 valid signatures for notarization.
+    Note: expected peer count = excluding self; add one to get N (roster changes keep
+    it current; see activate_roster_changes) */
+    fn quorum_size(&self) -> usize {
+        self.quorum_rule.size(self.validator_count())
+    }
+
+    /* Validators in the deployment: our peers, and us unless we're an observer. */
+    fn validator_count(&self) -> usize {
+        self.expected_peer_count + usize::from(self.role == Role::Validator)
+    }
+
+    /* Determines if a given block is notarized. 
+        @param block: block received in a vote/proposal
+        @param message: the vote/proposal carrying it */
+    pub fn is_notarized(&self, block: &Block, message: &Message) -> bool {
+        let threshold = self.quorum_size();
+        // Option 1: we have recorded votes from enough distinct signers for this block.
+        let ret = self.blockchain_manager.is_block_notarized(&block.hash)
+            || self.blockchain_manager.vote_count(&block.hash) >= threshold;
+        // Option 2: this message -- by itself -- has the threshold of signatures required. 
+        // This occurs if we're doing catch-up from a previous epoch or we missed the proposal.
+        ret || (self.verify_message(message) >= threshold)
+    }
+
+    /* Returns the validity of a proposal. */
+    fn should_vote(&mut self, message: &mut Message, vote_this_epoch: Option<Signature>, epoch: u64, block: &Block, app_interface: &AppInterface) -> Option<Signature> {
+        if self.role == Role::Observer {
+            return None;
+        }
+        // A compromised node may vote for anything, or for more than one block per epoch
+        match self.compromise_type {
+            CompromiseType::VoteInvalid => return self.sign_message(message).ok(),
+            CompromiseType::Equivocate if block.epoch == epoch && self.check_from_leader(epoch, message) => {
+                return self.sign_message(message).ok();
+            }
+            _ => {}
+        }
+
+        // Basic checks:
+        if !self.check_from_leader(epoch, message) || // From the leader? 
+            // Correct epoch? 
+            block.epoch != epoch  || 
+            // Descends from ancestor? 
+            !self.blockchain_manager.extends_longest_notarized_chain(block) ||
+            // Is the data valid? 
+            !app_interface.data_is_valid(message) ||
+            // Do we run this epoch's protocol, and agree to any upgrade it announces?
+            !self.supports_protocol_at(epoch) ||
+            !self.announcement_is_acceptable(block)
+        {
+            return None;
+        }
 
-        if let Some(signature_value) = sig {
-            // Vote if and only if: 
-            // - We haven't voted before in this epoch
-            // - Or, this is a re-broadcast of our vote in this epoch
-            if vote_this_epoch.is_none() || vote_this_epoch.unwrap() == signature_value {
-                return Some(signature_value);
+        let signature_value = match self.sign_message(message) {
+            Ok(signature) => signature,
+            Err(StreamletError::AlreadySigned) => return None,
+            Err(e) => {
+                warn!("Epoch: {}, not voting: {}", epoch, e);
+                return None;
             }
+        };
+
+        // Vote if and only if: 
+        // - We haven't voted before in this epoch
+        // - Or, this is a re-broadcast of our vote in this epoch
+        if vote_this_epoch.is_none() || vote_this_epoch.unwrap() == signature_value {
+            return Some(signature_value);
         }
 
         None 
@@ -676,64 +3367,315 @@ impl StreamletInstance {
         let leader = self.get_epoch_leader(epoch);
 
         // Make sure we have leader's public key
-        if !self.public_keys.contains_key(leader) {
+        if !self.public_keys.contains_key(&leader) {
             warn!("Missing leader public key...");
             return false;
         }
-        let leader_pk = self.public_keys[leader];
+        let leader_pk = self.public_keys[&leader];
 
         // Check leader's signature
         let signatures = message.clone().get_signatures();
-        if signatures.len() >= 1 {
-            return self.verify_signature(message, &signatures[0], &leader_pk);
+        if !signatures.is_empty() {
+            self.verify_signature(message, &signatures[0], &leader_pk)
         } else {
-            return false;
+            false
         }
     }
 
-    /* Determines epoch leader using deterministic hash function. */
-    fn get_epoch_leader(&self, epoch: u64) -> &String {
-        let mut hasher = DefaultHasher::new();
-        hasher.write_u64(epoch);
-        let result = hasher.finish() as usize;
-        let leader_index = result % (self.expected_peer_count + 1); // +1 for self
-        return &self.sorted_peer_names[leader_index];
+    /* Determines epoch leader (see leader_schedule), skipping validators in maintenance. */
+    fn get_epoch_leader(&self, epoch: u64) -> String {
+        let available = self.maintenance.available(epoch, &self.sorted_peer_names);
+        self.leader_schedule.leader(epoch, &available).clone()
     }
 
     /* Add public key to local data structure. */
     pub fn add_public_key(&mut self, instance_name: String, pk: &PublicKey) {
-        self.public_keys.insert(instance_name, pk.clone());
+        if pk.scheme() != self.signature_scheme {
+            warn!("Ignoring {} key for {}; deployment uses {}", pk.scheme(), instance_name, self.signature_scheme);
+            return;
+        }
+        // One key is one signer: under a second name it would count its votes twice
+        if let Some((other, _)) = self.public_keys.iter().find(|(name, key)| **key == *pk && **name != instance_name) {
+            warn!("Ignoring key for {}; it is {}'s", instance_name, other);
+            return;
+        }
+        if self.public_keys.insert(instance_name, *pk) != Some(*pk) {
+            self.quarantine.key_added();
+            self.validators_changed();
+        }
     }
 
-    /* Used for testing. 
-    Compare message signatures against all known public keys, returning 
-    the number of valid signatures
-    @param message: the message instance with signatures to be validated */
-    fn verify_message(&self, message: &Message) -> usize {
-        let mut num_valid_signatures = 0;
-        let signatures = message.clone().get_signatures(); // Check all signatures
-
-        // Check all sigatures on the message; prevent double-signing
-        let mut seen = std::collections::HashSet::new();
-        for signature in signatures.iter() {
-            // Check against all known pk's
-            for name in self.public_keys.keys() {
-                let pk = self.public_keys[name];
-                if !seen.contains(name) && self.verify_signature(message, signature, &pk) {
-                    num_valid_signatures += 1;
-                    seen.insert(name);
-                    break;
+    /* Takes on the key a peer advertised, if the peer is (or may become) a validator:
+    during discovery anyone may, and after it, validators we know (say, restarting) and
+    validators that start late, while the deployment has seats we haven't heard from.
+    Anyone else needs a roster join. Returns whether the key was taken on.
+    @param name: the advertised name
+    @param pk: the advertised key */
+    fn accept_advertised_key(&mut self, name: &str, pk: &PublicKey) -> bool {
+        if self.roster_history.is_retired(name) {
+            return false;
+        }
+        let heard_from = self.public_keys.keys().filter(|known| !known.is_empty()).count();
+        if self.discovered && !self.public_keys.contains_key(name) && heard_from >= self.validator_count() {
+            return false;
+        }
+        self.add_public_key(name.to_string(), pk);
+        self.public_keys.get(name) == Some(pk)
+    }
+
+    /* Keeps certificates aggregated over the current validators, under a scheme whose
+    signatures aggregate (see aggregate_votes). */
+    fn validators_changed(&mut self) {
+        if self.signature_scheme.aggregates() {
+            self.blockchain_manager.aggregate_certs_over(self.public_keys.clone());
+        }
+    }
+
+    /* What a message that failed verification is waiting for, if it may have failed
+    only because we haven't caught up yet (see quarantine): signatures we can't match
+    to a key while some validators' keys are still unknown, or a current proposal
+    extending a block we haven't seen notarized.
+    @param message: the message
+    @param epoch: the current epoch */
+    fn missing_for(&self, message: &Message, epoch: u64) -> Option<Missing> {
+        let known_validators = self.public_keys.keys().filter(|name| !name.is_empty()).count();
+        if known_validators < self.validator_count()
+            && self.identify_signers(message).len() < message.signatures.len()
+        {
+            return Some(Missing::Key);
+        }
+        match (&message.kind, &message.payload) {
+            (MessageKind::Propose, MessagePayload::Block(block))
+                if block.epoch == epoch && !self.blockchain_manager.is_block_notarized(&block.parent_hash) =>
+            {
+                Some(Missing::Block(block.parent_hash))
+            }
+            _ => None,
+        }
+    }
+
+    /* Tells the other nodes where we are in our epoch clock (see epoch_sync). Only
+    validators report.
+    @param position: our position */
+    fn report_epoch_position(&mut self, net_stack: &mut NetworkStack, position: EpochPosition) {
+        if self.role != Role::Validator {
+            return;
+        }
+        let message = Message::new(MessagePayload::EpochSync(position), MessageKind::EpochSync, self.id, self.name.clone());
+        self.broadcast_sealed(net_stack, message);
+    }
+
+    /* Wraps a consensus message in an envelope signed by this node (see envelope).
+    @param message: the message to send */
+    fn seal(&self, message: Message) -> Vec<u8> {
+        SignedEnvelope::seal(message, &self.name, self.current_epoch, &*self.signer, &self.chain_id).serialize()
+    }
+
+    /* Seals and broadcasts a consensus message. A node compromised to delay messages
+    holds it until the next epoch starts instead. */
+    fn broadcast_sealed(&mut self, net_stack: &mut NetworkStack, message: Message) {
+        let bytes = self.seal(message);
+        if self.compromise_type == CompromiseType::DelayMessages {
+            self.delayed_messages.push(bytes);
+        } else {
+            log_unsent("message", net_stack.broadcast_message(bytes));
+        }
+    }
+
+    /* A node compromised to equivocate proposes a second block in its epoch: the first
+    one with another nonce, so it hashes differently, signed like it. None for any
+    other node.
+    @param proposal: our signed proposal */
+    fn equivocating_proposal(&self, proposal: &Message) -> Option<(Message, Signature)> {
+        let block = match (&self.compromise_type, &proposal.payload) {
+            (CompromiseType::Equivocate, MessagePayload::Block(block)) => block,
+            _ => return None,
+        };
+        let twin = Block::new(block.epoch, block.parent_hash, block.data.clone(), block.height, block.nonce.wrapping_add(1));
+        let mut message = Message::new(MessagePayload::Block(twin), MessageKind::Propose, self.id, self.name.clone());
+        let signature = self.sign_message(&mut message).ok()?;
+        Some((message, signature))
+    }
+
+    /* Checks a received envelope: its signature, and that its key is the one its sender
+    advertised (if we know the sender yet). Consensus messages must be sealed by a
+    validator we know (from discovery, the genesis document or a roster): anyone can
+    make up a name and a key to seal with. Returns why not otherwise, with how badly
+    the peer that sent it misbehaved.
+    @param envelope: the envelope */
+    fn check_envelope(&self, envelope: &SignedEnvelope) -> Result<(), (String, PeerSeverity)> {
+        if envelope.public_key.scheme() != self.signature_scheme || !envelope.verify(&self.chain_id) {
+            return Err((String::from("invalid envelope signature"), PeerSeverity::Major));
+        }
+        match self.public_keys.get(&envelope.sender) {
+            Some(known) if *known != envelope.public_key => {
+                return Err((String::from("envelope key isn't the one the sender advertised"), PeerSeverity::Major));
+            }
+            None if envelope.message.kind.is_consensus() => {
+                return Err((format!("{:?} message sealed by {}, who isn't a known validator", envelope.message.kind, envelope.sender), PeerSeverity::Major));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /* Decodes a message from the network; consensus messages come sealed by whoever sent
+    them (see envelope). Returns the message and who sealed it, or else how badly the
+    peer that sent it misbehaved (None: not at all, it comes from a newer release during
+    a rolling upgrade; see messages).
+    @param bytes: the message as received */
+    fn open_input(&self, bytes: &[u8]) -> Result<(Message, Option<String>), Option<PeerSeverity>> {
+        let decoded = match SignedEnvelope::open(bytes) {
+            Some(Ok(envelope)) => match self.check_envelope(&envelope) {
+                Ok(()) => Ok((envelope.message, Some(envelope.sender))),
+                Err((e, severity)) => {
+                    debug!("Dropping message sealed by {}: {}", envelope.sender, e);
+                    return Err(Some(severity));
                 }
+            },
+            Some(Err(e)) => Err(e),
+            None => Message::decode(bytes).map(|message| (message, None)),
+        };
+        decoded.map_err(|e| {
+            if e.is_from_newer_release() {
+                debug!("Skipping message we can't read yet: {}", e);
+                None
+            } else {
+                debug!("Dropping malformed message: {}", e);
+                Some(PeerSeverity::Minor)
+            }
+        })
+    }
+
+    /* Reports the peer a message came from to the network (see peer_score) if some of
+    its signatures match no validator's key. Signatures we can't match only because some
+    validators' keys are still unknown don't count.
+    @param net_stack: the network
+    @param bytes: the message as received
+    @param message: the message
+    @param valid_signatures: how many of its signatures match a key
+    @param epoch: the current epoch */
+    fn report_invalid_signatures(&self, net_stack: &mut NetworkStack, bytes: &[u8], message: &Message, valid_signatures: usize, epoch: u64) {
+        if valid_signatures == message.signatures.len() || self.missing_for(message, epoch) == Some(Missing::Key) {
+            return;
+        }
+        if let Some(peer) = net_stack.source_of(bytes) {
+            warn!("Epoch: {}, {:?} message from {} carries invalid signatures; reporting peer {}", epoch, message.kind, message.sender_name, peer);
+            net_stack.report_peer(&peer, PeerSeverity::Major);
+        }
+    }
+
+    /* Parks a message in quarantine if it is waiting for a key or block (see missing_for),
+    to be processed again once that arrives.
+    @param message: the message, already handled as far as it could be
+    @param epoch: the current epoch */
+    fn quarantine_if_transient(&mut self, message: &Message, epoch: u64) {
+        if let Some(missing) = self.missing_for(message, epoch) {
+            if self.quarantine.park(message.clone(), missing, epoch) {
+                debug!("Epoch: {}, parking {:?} message from {} until {:?} arrives", epoch, message.kind, message.sender_name, missing);
             }
         }
+    }
+
+    /* Records a newly notarized block's latency, and whether it was our proposal. */
+    fn block_notarized(&mut self, block: &Block) {
+        self.latency_watchdog.notarized(block.epoch, clock::unix_time_ms());
+        if !self.sorted_peer_names.is_empty() && self.get_epoch_leader(block.epoch) == self.name {
+            self.performance.proposal_notarized(block.epoch);
+        }
+    }
+
+    /* Releases quarantined messages whose missing block is now notarized. */
+    fn release_quarantined_blocks(&mut self) {
+        let manager = &self.blockchain_manager;
+        self.quarantine.blocks_arrived(|hash| manager.is_block_notarized(hash));
+    }
+
+    /* Number of distinct known signers with a valid signature on the message (see
+    identify_signers).
+    @param message: the message instance with signatures to be validated */
+    fn verify_message(&self, message: &Message) -> usize {
+        let num_valid_signatures = self.identify_signers(message).len();
         debug!(
             "Attempted validation on message {}, found {} valid signatures",
             message.nonce, num_valid_signatures
         );
-        return num_valid_signatures;
+        num_valid_signatures
+    }
+}
+
+/* Whether a log entry's data is reserved for validator announcements (upgrades and
+maintenance windows), which apps may not submit. */
+fn is_announcement(data: &[u8]) -> bool {
+    ProtocolUpgrade::is_tagged(data) || MaintenanceWindow::is_tagged(data) || RosterChange::is_tagged(data)
+}
+
+/* A message the network refuses costs only that message: the protocol tolerates lost
+messages, so we log it and carry on. */
+fn log_unsent(what: &str, result: Result<(), StreamletError>) {
+    if let Err(e) = result {
+        warn!("Couldn't send {}: {}", what, e);
+    }
+}
+
+async fn run_tcp_server(listener: TcpListener, 
+                mut tcp_data_receiver: mpsc::UnboundedReceiver<Vec<u8>>, 
+                tcp_connect_trigger: tokio::sync::watch::Sender<&str>) 
+{
+    loop {
+        // A connection that fails costs only that request
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        // Determine Request Type
+        let mut msg_bytes = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut msg_bytes).await {
+            warn!("Did not receive TCP request: {}", e);
+            continue;
+        }
+        
+        // Ask streamlet for data
+        let request = match msg_bytes.as_slice() {
+            b"chain" => "chain",
+            b"block" => "block",
+            _ => {
+                info!("Unknown TCP request type");
+                continue;
+            }
+        };
+        debug!("(TCP Thread) asking streamlet for {}", request);
+        // Streamlet is gone
+        if tcp_connect_trigger.send(request).is_err() {
+            return;
+        }
+        let data: Vec<u8> = match tcp_data_receiver.recv().await {
+            Some(data) => data,
+            None => return,
+        };
+        
+        // Send through TCP stream
+        let sent = async {
+            stream.write_all(&data).await?;
+            stream.flush().await?;
+            stream.shutdown().await
+        };
+        if let Err(e) = sent.await {
+            warn!("Failed writing data to TCP stream: {}", e);
+        }
     }
 }
 
+// ***** APPLICATION *****
+pub async fn run_app() {
+    let mut app = app::Application::new();
+    app.run().await;
+}
+
 // ============================
 // === Streamlet Unit Tests ===
 // ============================
@@ -762,10 +3704,7 @@ mod tests {
         let mut hasher = Sha256::new();
         hasher.update(b"hello world");
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
         // Create a test block
         let blk = Block::new(0, bytes, String::from("test").into_bytes(), 0, 0);
@@ -780,11 +3719,14 @@ mod tests {
         );
 
         // Signing message
-        streamlet1.sign_message(&mut message);
+        streamlet1.sign_message(&mut message).unwrap();
         assert!(message.signature_count() == 1);
-        streamlet2.sign_message(&mut message);
+        streamlet2.sign_message(&mut message).unwrap();
         assert!(message.signature_count() == 2);
-        streamlet3.sign_message(&mut message);
+        streamlet3.sign_message(&mut message).unwrap();
+        assert!(message.signature_count() == 3);
+        // Signing twice is an error, not a second signature
+        assert_eq!(streamlet3.sign_message(&mut message), Err(StreamletError::AlreadySigned));
         assert!(message.signature_count() == 3);
 
         // Adding public keys to streamlet1
@@ -797,47 +3739,395 @@ mod tests {
         // Verify message with all signatures
         let good_result = streamlet1.verify_message(&message);
         assert!(good_result == 3);
+
+        // A repeated signature, or a key known under a second name, is still one signer
+        let repeated = message.signatures[2];
+        message.signatures.push(repeated);
+        assert_eq!(streamlet1.verify_message(&message), 3);
+        streamlet1.public_keys.insert(String::from("alias3"), streamlet3.get_public_key());
+        assert_eq!(streamlet1.verify_message(&message), 3);
+        assert_eq!(streamlet1.identify_signers(&message).len(), 3);
+        streamlet1.public_keys.remove("alias3");
+        streamlet1.add_public_key(String::from("alias3"), &streamlet3.get_public_key());
+        assert!(!streamlet1.public_keys.contains_key("alias3"));
+
+        // Votes signed for another deployment don't count
+        streamlet1.set_deployment("test");
+        assert_eq!(streamlet1.verify_message(&message), 0);
     }
-}
 
-async fn run_tcp_server(listener: TcpListener, 
-                mut tcp_data_receiver: mpsc::UnboundedReceiver<Vec<u8>>, 
-                tcp_connect_trigger: tokio::sync::watch::Sender<&str>) 
-{
-    loop {
-        let (mut stream, _) = listener.accept().await.expect("Failed to accept TCP connection");
+    #[test]
+    fn test_quarantine_waits_for_keys_and_parents() {
+        let mut streamlet = StreamletInstance::new(String::from("h1"), 2);
+        let peer = StreamletInstance::new(String::from("h2"), 2);
+        let genesis = streamlet.blockchain_manager.head().0.clone();
+        let parent = Block::new(1, genesis.hash, b"parent".to_vec(), 1, 0);
+        let child = Block::new(2, parent.hash, b"child".to_vec(), 2, 0);
 
-        // Determine Request Type
-        let mut msg_bytes = Vec::new();
-        stream.read_to_end(&mut msg_bytes).await.expect("Did not recieive data");
-        let msg = String::from_utf8(msg_bytes.clone()).expect("Unable to decode msg bytes");
-        
-        // Ask streamlet for data
-        match msg.as_str() {
-            "chain" => {
-                debug!("(TCP Thread) asking streamlet for chain");
-                tcp_connect_trigger.send("chain").expect("TCP connect trigger closed?");
+        // A vote from a validator whose key hasn't arrived yet waits for it
+        let mut vote = Message::new(MessagePayload::Block(parent.clone()), MessageKind::Vote, 0, String::from("h2"));
+        peer.sign_message(&mut vote).unwrap();
+        assert_eq!(streamlet.missing_for(&vote, 1), Some(Missing::Key));
+        streamlet.quarantine_if_transient(&vote, 1);
+        assert!(streamlet.quarantine.next_ready().is_none());
+        streamlet.add_public_key(String::from("h2"), &peer.get_public_key());
+        assert_eq!(streamlet.quarantine.next_ready(), Some(vote.clone()));
+        assert_eq!(streamlet.missing_for(&vote, 1), None);
+
+        // A current proposal on an unknown parent waits for the parent's notarization
+        let mut proposal = Message::new(MessagePayload::Block(child), MessageKind::Propose, 0, String::from("h2"));
+        peer.sign_message(&mut proposal).unwrap();
+        assert_eq!(streamlet.missing_for(&proposal, 1), None);
+        assert_eq!(streamlet.missing_for(&proposal, 2), Some(Missing::Block(parent.hash)));
+        streamlet.quarantine_if_transient(&proposal, 2);
+        streamlet.release_quarantined_blocks();
+        assert!(streamlet.quarantine.next_ready().is_none());
+        streamlet.blockchain_manager.add_notarized_block(parent, Vec::new());
+        streamlet.release_quarantined_blocks();
+        assert_eq!(streamlet.quarantine.next_ready(), Some(proposal));
+    }
+
+    #[test]
+    fn test_tree_head_gossip_flags_split_view() {
+        let mut h1 = StreamletInstance::new(String::from("h1"), 1);
+        let h2 = StreamletInstance::new(String::from("h2"), 1);
+        h1.add_public_key(String::from("h2"), &h2.get_public_key());
+        // Epochs 1..3 are consecutive, so "a" and "b" finalize
+        for (epoch, data) in [(1, &b"a"[..]), (2, b"b"), (3, b"c")] {
+            let parent = h1.blockchain_manager.head().0.clone();
+            let block = Block::new(epoch, parent.hash, data.to_vec(), parent.height + 1, 0);
+            h1.blockchain_manager.add_notarized_block(block, Vec::new());
+        }
+
+        let head = |entries: &[&[u8]], keypair: &dyn Signer| {
+            let mut tree = MerkleTree::new();
+            for entry in entries {
+                tree.push(entry);
             }
-            "block" => { 
-                debug!("(TCP Thread) asking streamlet for block");
-                tcp_connect_trigger.send("block").expect("TCP connect trigger closed?"); 
+            TreeHeadUpdate {
+                sth: SignedTreeHead::sign(&tree, String::from("h2"), keypair, &ChainId::default()),
+                public_key: keypair.public(),
+                consistency: tree.prove_consistency(0, tree.size()).unwrap(),
             }
-            _ => { 
-                info!("Unknown TCP request type"); 
+        };
+        // Agrees with our log, or is ahead of it
+        assert!(h1.receive_tree_head(&head(&[b"a"], &*h2.signer)).is_empty());
+        assert!(h1.receive_tree_head(&head(&[b"a", b"b", b"c"], &*h2.signer)).is_empty());
+
+        let alerts = h1.receive_tree_head(&head(&[b"a", b"x"], &*h2.signer));
+        assert!(alerts.contains(&Alert::SplitView { tree_size: 2, signers: (String::from("h1"), String::from("h2")) }));
+        let impostor = Keypair::generate(SignatureScheme::default());
+        assert_eq!(
+            h1.receive_tree_head(&head(&[b"a"], &impostor)),
+            vec![Alert::KeyChanged { signer: String::from("h2") }]
+        );
+    }
+
+    #[test]
+    fn test_http_api_answers() {
+        let mut streamlet = StreamletInstance::new(String::from("Test"), 1);
+        assert_eq!(streamlet.answer_api_request(ApiRequest::GetSth).unwrap_err().status, 404);
+
+        // Finalize two entries (epochs 1..4 are consecutive, so all but the tip finalize)
+        let mut entries: Vec<LogEntry> = (0..3u8).map(|i| LogEntry::new(vec![i])).collect();
+        entries[1] = LogEntry { content_type: "text/plain".parse().ok(), ..LogEntry::new(b"hello".to_vec()) };
+        for (i, entry) in entries.iter().enumerate() {
+            let parent = streamlet.blockchain_manager.head().0.clone();
+            let block = Block::new(i as u64 + 1, parent.hash, entry.serialize(), parent.height + 1, 0);
+            streamlet.blockchain_manager.add_notarized_block(block, Vec::new());
+        }
+        streamlet.sign_tree_head();
+
+        let sth = streamlet.answer_api_request(ApiRequest::GetSth).unwrap();
+        assert_eq!(sth["tree_size"], 2);
+        let root: Sha256Hash = hex::decode(sth["sha256_root_hash"].as_str().unwrap()).unwrap().try_into().unwrap();
+
+        let leaf = entries[1].serialize();
+        let proof = streamlet
+            .answer_api_request(ApiRequest::GetProofByHash { hash: blockchain::leaf_hash(&leaf), tree_size: 2 })
+            .unwrap();
+        // Computed when the tree head was signed (see read_cache::precompute)
+        assert_eq!((streamlet.read_cache_stats().memory_hits, streamlet.read_cache_stats().misses), (1, 0));
+        let path = proof["audit_path"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hash| hex::decode(hash.as_str().unwrap()).unwrap().try_into().unwrap())
+            .collect();
+        let audit_path = AuditPath { leaf_index: proof["leaf_index"].as_u64().unwrap(), tree_size: 2, path };
+        assert!(verify_inclusion(&leaf, &audit_path, &root));
+
+        let listed = streamlet.answer_api_request(ApiRequest::GetEntries { start: 0, end: 10 }).unwrap();
+        assert_eq!(listed["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(listed["entries"][1]["id"], entries[1].id.to_string());
+        assert_eq!(listed["entries"][1]["text"], "hello");
+        assert_eq!(listed["entries"][1]["leaf_input"], hex::encode(entries[1].serialize()));
+        let fetched = streamlet.answer_api_request(ApiRequest::GetEntry { id: entries[1].id }).unwrap();
+        assert_eq!(fetched["content_type"], "text/plain");
+        assert_eq!(streamlet.answer_api_request(ApiRequest::GetEntry { id: entries[2].id }).unwrap_err().status, 404);
+        assert!(streamlet.answer_api_request(ApiRequest::GetConsistency { first: 1, second: 2 }).is_ok());
+        assert!(streamlet.answer_api_request(ApiRequest::GetConsistency { first: 1, second: 3 }).is_err());
+
+        // The gRPC service's queries
+        let status = streamlet.answer_api_request(ApiRequest::GetChainStatus).unwrap();
+        assert_eq!((status["notarized_height"].as_u64(), status["finalized_height"].as_u64(), status["tree_size"].as_u64()), (Some(3), Some(2), Some(2)));
+        assert_eq!(streamlet.answer_api_request(ApiRequest::GetBlock { height: 2 }).unwrap()["data"], hex::encode(&leaf));
+        assert_eq!(streamlet.answer_api_request(ApiRequest::GetBlock { height: 3 }).unwrap_err().status, 404);
+        let bundle = streamlet.answer_api_request(ApiRequest::GetProofById { id: entries[1].id }).unwrap();
+        assert_eq!(bundle["audit_path"], proof["audit_path"]);
+        assert_eq!(streamlet.answer_api_request(ApiRequest::GetProofById { id: entries[2].id }).unwrap_err().status, 404);
+
+        let added = streamlet.answer_api_request(ApiRequest::AddEntry { data: b"new".to_vec(), callback: None, content_type: None, id: None, submission: None }).unwrap();
+        let queued = LogEntry::deserialize(&streamlet.pending_transactions.pop().unwrap()).unwrap();
+        assert_eq!(queued.data, b"new".to_vec());
+        assert_eq!(added["id"], queued.id.to_string());
+    }
+
+    #[test]
+    fn test_exported_chain_imports_into_a_fresh_node() {
+        let mut source = StreamletInstance::new(String::from("x1"), 0);
+        for epoch in 1..=4 {
+            let parent = source.blockchain_manager.head().0.clone();
+            let block = Block::new(epoch, parent.hash, vec![epoch as u8], parent.height + 1, 0);
+            let vote = source.signer.sign(&NotarizationCert::signed_bytes(&block, &source.chain_id));
+            source.blockchain_manager.add_notarized_block(block, vec![vote]);
+        }
+        assert_eq!(source.blockchain_manager.finalized_chain().length(), 4);
+        let path = std::env::temp_dir().join(format!("streamlet-export-test-{}", std::process::id()));
+        assert_eq!(source.export_chain(ExportFormat::Bin, &path).unwrap(), 4);
+
+        let mut fresh = StreamletInstance::new(String::from("x2"), 0);
+        assert_eq!(fresh.import_chain(&path).unwrap(), 3);
+        assert_eq!(fresh.blockchain_manager.finalized_chain(), source.blockchain_manager.finalized_chain());
+        assert_eq!(fresh.blockchain_manager.merkle_tree().root(), source.blockchain_manager.merkle_tree().root());
+        assert!(fresh.blockchain_manager.take_newly_finalized().is_empty());
+        assert_eq!(fresh.import_chain(&path).unwrap(), 0);
+
+        // Another deployment's export doesn't load
+        let mut elsewhere = StreamletInstance::new(String::from("x3"), 0);
+        elsewhere.set_deployment("elsewhere");
+        assert!(matches!(elsewhere.import_chain(&path), Err(StreamletError::Import(_))));
+        assert_eq!(elsewhere.blockchain_manager.finalized_chain().length(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_alerts_reach_subscribers() {
+        let mut streamlet = StreamletInstance::new(String::from("h1"), 3);
+        let mut alerts = streamlet.subscribe_alerts();
+        let dropped = streamlet.subscribe_alerts();
+        drop(dropped);
+        let upgrade = ProtocolUpgrade { version: PROTOCOL_VERSION + 1, activation_epoch: 100 };
+        streamlet.schedule_upgrade(&upgrade, 1);
+        let alert = alerts.try_recv().unwrap();
+        assert!(matches!(alert, NodeAlert::UnsupportedProtocol { activation_epoch: 100, .. }));
+        assert_eq!(alert.severity(), Severity::Critical);
+        assert_eq!(streamlet.alert_subscribers.len(), 1);
+        assert!(alerts.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_observer_is_not_a_validator() {
+        let mut observer = StreamletInstance::builder().name("o1").validators(4).role(Role::Observer).build().unwrap();
+        // Four validators, none of them us: three votes make a quorum, as at the validators
+        assert_eq!(observer.quorum_size(), 3);
+        assert!(!observer.public_keys.contains_key("o1"));
+        observer.announce_own_join();
+        assert!(observer.pending_transactions.pop().is_none());
+        let refused = observer.answer_api_request(ApiRequest::AddEntry { data: b"new".to_vec(), callback: None, content_type: None, id: None, submission: None });
+        assert_eq!(refused.unwrap_err().status, 400);
+
+        let validator = StreamletInstance::builder().name("v1").validators(4).build().unwrap();
+        assert_eq!(validator.quorum_size(), 3);
+        assert!(validator.public_keys.contains_key("v1"));
+    }
+
+    #[test]
+    fn test_epoch_without_timely_proposal_is_empty() {
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        node.sorted_peer_names = vec![String::from("v1"), String::from("v2"), String::from("v3")];
+        // The leader proposed: not empty
+        node.last_proposal_epoch = 4;
+        node.proposal_timed_out(4);
+        assert_eq!((node.empty_epoch, node.empty_epoch_count), (None, 0));
+
+        node.proposal_timed_out(5);
+        assert_eq!((node.empty_epoch, node.empty_epoch_count), (Some(5), 1));
+        // The next leader builds on the longest notarized chain, as after any epoch
+        let proposal = node.make_proposal(6);
+        match proposal.payload {
+            MessagePayload::Block(block) => {
+                assert_eq!(block.parent_hash, node.blockchain_manager.head().0.hash);
+                assert_eq!(block.height, node.blockchain_manager.head().0.height + 1);
             }
+            _ => panic!("proposals carry a block"),
         }
-        let data: Vec<u8> = tcp_data_receiver.recv().await.expect("Expected to receive data from streamlet...");
-        
-        // Send through TCP stream
-        stream.write_all(&data).await.expect("Failed writing data to TCP stream...");
-        stream.flush().await.expect("Failed to flush stream...");
-        stream.shutdown().await.expect("Failed to close stream...");
     }
-}
 
-// ***** APPLICATION *****
-pub async fn run_app() {
-    let mut app = app::Application::new();
-    app.run().await;
-}
+    #[test]
+    fn test_late_validators_take_open_seats() {
+        // Three validators; discovery ended having heard from only one of our two peers
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        let peer = |name: &str| StreamletInstance::new(String::from(name), 2).get_public_key();
+        let v2 = peer("v2");
+        assert!(node.accept_advertised_key("v2", &v2));
+        node.discovered = true;
+
+        // A validator that starts late takes the open seat; a restarting one keeps its own
+        assert!(node.accept_advertised_key("v3", &peer("v3")));
+        assert!(node.accept_advertised_key("v2", &v2));
+        assert_eq!(node.quorum_size(), 2);
+        // Once every seat is taken, newcomers need a roster join
+        assert!(!node.accept_advertised_key("v4", &peer("v4")));
+        assert!(!node.public_keys.contains_key("v4"));
+    }
+
+    #[test]
+    fn test_http_entries_are_forwarded_to_the_next_leader() {
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        node.sorted_peer_names = vec![String::from("v1"), String::from("v2"), String::from("v3")];
+        let add = |node: &mut StreamletInstance, entry: LogEntry, submission: Option<SignedSubmission>| {
+            let request = ApiRequest::AddEntry { data: entry.data, callback: None, content_type: None, id: Some(entry.id), submission: submission.map(Box::new) };
+            node.answer_api_request(request).unwrap();
+        };
+        let submitted = |message: &Message| match &message.payload {
+            MessagePayload::Submit(entry) => entry.clone(),
+            _ => panic!("forwarded entries are submissions"),
+        };
+        let follower_epoch = (0..).find(|epoch| node.get_epoch_leader(epoch + 1) != "v1").unwrap();
+        let leader_epoch = (0..).find(|epoch| node.get_epoch_leader(epoch + 1) == "v1").unwrap();
+
+        add(&mut node, LogEntry::new(b"first".to_vec()), None);
+        let client = Keypair::generate(node.signature_scheme);
+        let entry = LogEntry::new(b"second".to_vec());
+        let signature = SignedSubmission::sign(&entry, "client", &client, &node.chain_id);
+        add(&mut node, entry, Some(signature.clone()));
+        let (leader, messages) = node.leader_forwarding(follower_epoch).unwrap();
+        assert_eq!(leader, node.get_epoch_leader(follower_epoch + 1));
+        assert_eq!(messages.len(), 2);
+        // Without a client's signature, signed by us, so a leader that only takes entries
+        // from known submitters takes it
+        assert_eq!(submitted(&messages[0]).data, b"first");
+        assert_eq!(messages[0].sender_name, "v1");
+        let signed = SignedSubmission::signed_bytes(&submitted(&messages[0]), &node.chain_id);
+        assert!(node.get_public_key().verify(&signed, &messages[0].signatures[0]).is_ok());
+        // With one, under the client's name and signature, so the leader queues it as theirs
+        assert_eq!(submitted(&messages[1]).data, b"second");
+        assert_eq!(messages[1].sender_name, "client");
+        assert_eq!(messages[1].signatures, vec![signature.signature]);
+        // Kept until sent (see forward_to_leader), to try again at the next epoch
+        assert_eq!(node.leader_forwarding(follower_epoch).unwrap().1.len(), 2);
+
+        // Leading next, we propose them ourselves; either way, they stay in our mempool
+        add(&mut node, LogEntry::new(b"third".to_vec()), None);
+        assert!(node.leader_forwarding(leader_epoch).is_none());
+        assert!(node.leader_forwards.is_empty());
+        assert_eq!(node.pending_transactions.len(), 3);
+    }
+
+    #[test]
+    fn test_entries_sealed_here_are_forwarded_under_our_name() {
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        node.sorted_peer_names = vec![String::from("v1"), String::from("v2"), String::from("v3")];
+        node.set_payload_secret(b"members only");
+        let follower_epoch = (0..).find(|epoch| node.get_epoch_leader(epoch + 1) != "v1").unwrap();
+        let client = Keypair::generate(node.signature_scheme);
+        let entry = LogEntry::new(b"private".to_vec());
+        let signature = SignedSubmission::sign(&entry, "client", &client, &node.chain_id);
+        let request = ApiRequest::AddEntry { data: entry.data, callback: None, content_type: None, id: Some(entry.id), submission: Some(Box::new(signature)) };
+        node.answer_api_request(request).unwrap();
+
+        // The client signed the entry before we sealed it, so its signature doesn't cover it
+        let (_, messages) = node.leader_forwarding(follower_epoch).unwrap();
+        let sealed = match &messages[0].payload {
+            MessagePayload::Submit(entry) => entry.clone(),
+            _ => panic!("forwarded entries are submissions"),
+        };
+        assert!(PayloadKey::is_sealed(&sealed));
+        assert_eq!(messages[0].sender_name, "v1");
+        let signed = SignedSubmission::signed_bytes(&sealed, &node.chain_id);
+        assert!(node.get_public_key().verify(&signed, &messages[0].signatures[0]).is_ok());
+    }
+
+    #[test]
+    fn test_envelopes_from_unknown_senders_are_refused() {
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        let validator = StreamletInstance::new(String::from("v2"), 2);
+        node.add_public_key(String::from("v2"), &validator.get_public_key());
+        let block = Block::new(1, [0; 32], b"entry".to_vec(), 1, 0);
+        let vote = Message::new(MessagePayload::Block(block), MessageKind::Vote, 0, String::from("v2"));
+
+        let sealed = SignedEnvelope::seal(vote.clone(), "v2", 1, &*validator.signer, &node.chain_id);
+        assert_eq!(node.check_envelope(&sealed), Ok(()));
+        // A made-up identity, however well it signs, isn't a validator
+        let stranger = Keypair::generate(node.signature_scheme);
+        let forged = SignedEnvelope::seal(vote, "v9", 1, &stranger, &node.chain_id);
+        assert!(matches!(node.check_envelope(&forged), Err((_, PeerSeverity::Major))));
+        // Nor is a known name under someone else's key
+        let stolen = SignedEnvelope::seal(forged.message.clone(), "v2", 1, &stranger, &node.chain_id);
+        assert!(matches!(node.check_envelope(&stolen), Err((_, PeerSeverity::Major))));
+    }
+
+    #[test]
+    fn test_malformed_messages_count_against_their_sender() {
+        let node = StreamletInstance::new(String::from("v1"), 2);
+        let block = Block::new(1, [0; 32], b"entry".to_vec(), 1, 0);
+        let vote = Message::new(MessagePayload::Block(block), MessageKind::Vote, 0, String::from("v1"));
+        let encoded = vote.serialize();
+        let sealed = SignedEnvelope::seal(vote.clone(), "v1", 1, &*node.signer, &node.chain_id).serialize();
+        assert_eq!(node.open_input(&encoded), Ok((vote.clone(), None)));
+        assert_eq!(node.open_input(&sealed), Ok((vote, Some(String::from("v1")))));
+
+        // Garbage, a message cut short, or a sealed one whose message doesn't decode
+        let mut scores = network::peer_score::PeerScores::default();
+        let (sender, now) = (PeerId::random(), std::time::Instant::now());
+        for malformed in [&b"junk"[..], &encoded[..encoded.len() - 1], &sealed[..sealed.len() - 1]] {
+            let severity = node.open_input(malformed).unwrap_err();
+            assert_eq!(severity, Some(PeerSeverity::Minor));
+            scores.report(sender, severity.unwrap(), now);
+        }
+        assert!(scores.score(&sender, now) > 0);
+        // ... but one from a newer release isn't held against its sender
+        let mut newer = encoded.clone();
+        newer[1] += 1;
+        assert_eq!(node.open_input(&newer), Err(None));
+    }
 
+    #[test]
+    fn test_self_audit_alerts_on_divergence() {
+        let mut node = StreamletInstance::new(String::from("v1"), 2);
+        let peer = StreamletInstance::new(String::from("v2"), 2);
+        node.add_public_key(String::from("v2"), &peer.get_public_key());
+        let mut alerts = node.subscribe_alerts();
+        // Epochs 1..3 are consecutive, so "a" and "b" finalize
+        for (epoch, data) in [(1, &b"a"[..]), (2, b"b"), (3, b"c")] {
+            let parent = node.blockchain_manager.head().0.clone();
+            let block = Block::new(epoch, parent.hash, data.to_vec(), parent.height + 1, 0);
+            node.blockchain_manager.add_notarized_block(block, Vec::new());
+        }
+        node.sign_tree_head();
+
+        let head = |entries: &[&[u8]]| {
+            let mut tree = MerkleTree::new();
+            for entry in entries {
+                tree.push(entry);
+            }
+            TreeHeadUpdate {
+                sth: SignedTreeHead::sign(&tree, String::from("v2"), &*peer.signer, &ChainId::default()),
+                public_key: peer.get_public_key(),
+                consistency: tree.prove_consistency(0, tree.size()).unwrap(),
+            }
+        };
+        node.receive_tree_head(&head(&[b"a"]));
+        node.self_audit();
+        assert!(alerts.try_recv().is_err());
+
+        // The peer signed another log than the one we serve
+        node.sth_monitor = Monitor::new(node.chain_id);
+        node.receive_tree_head(&head(&[b"a", b"x"]));
+        node.self_audit();
+        let alert = alerts.try_recv().unwrap();
+        assert_eq!(alert, NodeAlert::SelfAudit(Divergence::Root { peer: String::from("v2"), tree_size: 2 }));
+        assert_eq!(alert.severity(), Severity::Critical);
+    }
+}
@@ -1,15 +1,18 @@
-use tokio;
-
-use cs244b_project::StreamletInstance;
+use cs244b_project::{
+    keystore, telemetry, Codec, CompromiseType, EntryIdFormat, GenesisDocument, KeySource, LeaderScheduleKind, Multiaddr, NatConfig, PriorityClass, QuorumRule, ReadCacheConfig, ReportPeriod, Role, SignatureScheme,
+    StaticRoster, StreamletInstance, SubmitterAcl, WebhookSink, DEFAULT_DISK_ITEMS, DEFAULT_MEMORY_ITEMS,
+};
+use std::collections::HashMap;
+use std::time::Duration;
 
 const DEFAULT_NUM_HOSTS: usize = 2;
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    telemetry::init();
 
     /* Parse optional CL args: */
-    let args: Vec<String> = std::env::args().collect();
+    let (args, flags) = split_flags(std::env::args().collect());
 
     /* - For application (net directory service): app */
     if args.len() == 2 && args[1].starts_with("app") {
@@ -19,16 +22,14 @@ async fn main() {
     }
 
     /* - For streamlet: <expected peers> <name of this host> */
-    let expected_peer_count = {
+    let num_hosts = {
         if args.len() >= 2 {
-            let num_hosts = args[1]
+            args[1]
                 .clone()
                 .parse::<usize>()
-                .expect("(Optional) first argument should be host count.");
-            // Number of peers = num_hosts - this node
-            num_hosts - 1
+                .expect("(Optional) first argument should be host count.")
         } else {
-            DEFAULT_NUM_HOSTS - 1
+            DEFAULT_NUM_HOSTS
         }
     };
     let name = {
@@ -39,8 +40,325 @@ async fn main() {
         }
     };
 
-    let mut streamlet = StreamletInstance::new(name, expected_peer_count);
+    /* - Optional flags:
+         --scheme <ed25519|secp256k1|bls12-381>: deployment-wide signature scheme (bls12-381 needs the bls feature)
+         --epoch-length <seconds>: time between epochs (same on all nodes)
+         --adaptive-epoch <min>:<max>: let the epoch length adapt between these bounds, in seconds (same on all nodes)
+         --priority <submitter=low|normal|high,...>: mempool priority classes
+         --id-format <ulid|hex|decimal>: how entry ids are printed
+         --data-dir <path>: persist the chain there (and reload it on restart)
+         --roster-secret <secret>: enable the encrypted validators-only topic
+         --control-socket <path>: also accept stdin commands on this Unix socket
+         --headless <on|off>: don't read stdin; take commands from the control socket and start peer discovery unprompted
+         --http-api <addr:port>: serve the HTTP API (needs the http-api feature)
+         --grpc <addr:port>: serve the gRPC service (needs the grpc feature)
+         --websocket <addr:port>: stream finalized blocks to WebSocket clients (needs the websocket feature)
+         --webhook-sink <url>: POST each finalized block to this http:// URL, signed with --callback-secret
+         --kafka-sink <brokers>/<topic>: produce each finalized block to a Kafka topic (needs the kafka feature)
+         --regions <name=region,...>: region labels for validators (same on all nodes)
+         --leader-schedule <uniform|region-aware|secret>: how epoch leaders are picked (secret needs --roster-secret)
+         --max-merge-delay <seconds>: how long accepted entries may take to finalize
+         --callback-secret <secret>: push proofs to submitters' callback URLs, signed with this
+         --codec <none|lz4|zstd>: compression for this node's large messages (same on all nodes; zstd needs the zstd feature)
+         --keyfile <path>: load this node's keypair from an encrypted keyfile (created if missing)
+         --key-passphrase <passphrase>: the keyfile's passphrase (or set STREAMLET_KEY_PASSPHRASE)
+         --deployment <name>: the deployment's name, bound into every signature (same on all nodes and tools)
+         --genesis <file>: the deployment's genesis document (same on all nodes); sets the deployment, scheme, epoch length and validators
+         --submitters <file>: take entries only if signed by a submitter listed in this JSON file, or a validator
+         --static-roster <file>: the deployment's fixed validators (names, keys, optional addresses) in this JSON file; gossip from nodes not on it is dropped
+         --payload-secret <secret>: make this a private log: seal entries under a key derived from this secret (same on all nodes)
+         --quorum <two-thirds|majority>: votes that notarize a block (default two-thirds; majority only survives crashes, not Byzantine validators; same on all nodes)
+         --role <validator|observer>: an observer follows and serves the chain without proposing or voting (its host count is the number of validators)
+         --print-public-key <on|off>: print this node's public key (e.g. from --keyfile), for the genesis document, and exit
+         --bootstrap <multiaddr,...>: peers to dial at startup, for networks mDNS doesn't cover
+         --listen <multiaddr>: also listen here (a fixed port other nodes can bootstrap from)
+         --relay <multiaddr,...>: relays (ending in /p2p/<peer id>) to listen through if this node is behind NAT
+         --relay-server <on|off>: keep relayed connections open, to serve as a relay for nodes behind NAT
+         --report-dir <path>: write a signed performance report there every period
+         --report-period <daily|weekly>: how often (default daily)
+         --verify-budget <signatures>: signatures each peer may make us check per epoch (0: unlimited)
+         --replay-window <epochs>: drop consensus messages for blocks older than this (default 20; 0: no replay checks)
+         --read-cache <path>: spill the HTTP API's cache of entries and proofs to this directory
+         --read-cache-memory <items>, --read-cache-disk <items>: how many items it keeps in each tier
+         --import <file>: load a chain written by the export-chain command before starting (checked first)
+         --pruned <blocks>: drop the bodies of finalized blocks below the latest checkpoint, keeping at least this many whole (default: archive node, keep all)
+         --byzantine <behavior>: misbehave on purpose, for testing (equivocate, no-vote, vote-invalid, delay-messages, ...) */
+    let scheme = flags
+        .get("scheme")
+        .map(|s| s.parse::<SignatureScheme>().unwrap_or_else(|e| panic!("--scheme should be ed25519, secp256k1 or bls12-381: {}", e)))
+        .unwrap_or_default();
+
+    let mut builder = StreamletInstance::builder().name(&name).validators(num_hosts).scheme(scheme);
+    if let Some(path) = flags.get("keyfile") {
+        let passphrase = flags
+            .get("key-passphrase")
+            .cloned()
+            .or_else(|| std::env::var(keystore::PASSPHRASE_ENV).ok())
+            .expect("--keyfile needs --key-passphrase or STREAMLET_KEY_PASSPHRASE");
+        builder = builder.key_source(KeySource::Keyfile { path: path.into(), passphrase: passphrase.into_bytes() });
+    }
+    if let Some(deployment) = flags.get("deployment") {
+        builder = builder.deployment(deployment);
+    }
+    if let Some(rule) = flags.get("quorum") {
+        let rule = rule.parse::<QuorumRule>().expect("--quorum should be two-thirds or majority");
+        builder = builder.quorum_rule(rule);
+    }
+    if let Some(role) = flags.get("role") {
+        let role = role.parse::<Role>().expect("--role should be validator or observer");
+        builder = builder.role(role);
+    }
+    if let Some(path) = flags.get("genesis") {
+        let document = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| GenesisDocument::from_json(&bytes))
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            });
+        builder = builder.genesis(document);
+    }
+    if let Some(secs) = flags.get("epoch-length") {
+        let secs = secs.parse::<u64>().expect("--epoch-length should be a number of seconds");
+        builder = builder.epoch_length(Duration::from_secs(secs));
+    }
+    if let Some(path) = flags.get("data-dir") {
+        builder = builder.storage_path(path);
+    }
+    match flags.get("headless").map(String::as_str) {
+        Some("on") => builder = builder.headless(true),
+        None | Some("off") => {}
+        Some(_) => panic!("--headless should be on or off"),
+    }
+    let mut streamlet = builder.build().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if flags.get("print-public-key").map(String::as_str) == Some("on") {
+        println!("{}", hex::encode(bincode::serialize(&streamlet.get_public_key()).expect("Failed serialization.")));
+        return;
+    }
+    if let Some(path) = flags.get("import") {
+        if let Err(e) = streamlet.import_chain(path) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(bounds) = flags.get("adaptive-epoch") {
+        let (min, max) = bounds
+            .split_once(':')
+            .and_then(|(min, max)| Some((min.parse::<u64>().ok()?, max.parse::<u64>().ok()?)))
+            .expect("--adaptive-epoch should be <min>:<max>, in seconds");
+        if let Err(e) = streamlet.set_adaptive_epoch(Duration::from_secs(min), Duration::from_secs(max)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(secret) = flags.get("payload-secret") {
+        streamlet.set_payload_secret(secret.as_bytes());
+    }
+    if let Some(path) = flags.get("submitters") {
+        let acl = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| SubmitterAcl::from_json(&bytes))
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            });
+        streamlet.set_submitters(acl);
+    }
+    if let Some(path) = flags.get("static-roster") {
+        let result = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| StaticRoster::from_json(&bytes))
+            .and_then(|roster| streamlet.set_static_roster(roster).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(priorities) = flags.get("priority") {
+        for assignment in priorities.split(',') {
+            let (submitter, class) = assignment
+                .split_once('=')
+                .expect("--priority entries should look like submitter=class");
+            let class = class.parse::<PriorityClass>().expect("priority should be low, normal or high");
+            streamlet.set_submitter_priority(submitter, class);
+        }
+    }
+
+    if let Some(regions) = flags.get("regions") {
+        for assignment in regions.split(',') {
+            let (validator, region) = assignment
+                .split_once('=')
+                .expect("--regions entries should look like name=region");
+            streamlet.set_validator_region(validator, region);
+        }
+    }
+    if let Some(kind) = flags.get("leader-schedule") {
+        let kind = kind.parse::<LeaderScheduleKind>().expect("--leader-schedule should be uniform, region-aware or secret");
+        if kind == LeaderScheduleKind::Secret {
+            let secret = flags.get("roster-secret").expect("--leader-schedule secret needs --roster-secret");
+            streamlet.set_leader_secret(secret.as_bytes());
+        }
+        streamlet.set_leader_schedule(kind);
+    }
+
+    if let Some(secs) = flags.get("max-merge-delay") {
+        let secs = secs.parse::<u64>().expect("--max-merge-delay should be a number of seconds");
+        streamlet.set_max_merge_delay(Duration::from_secs(secs));
+    }
+
+    if let Some(budget) = flags.get("verify-budget") {
+        let budget = budget.parse::<usize>().expect("--verify-budget should be a number of signatures");
+        streamlet.set_verification_budget(budget);
+    }
+
+    if let Some(keep) = flags.get("pruned") {
+        let keep = keep.parse::<u64>().expect("--pruned should be a number of blocks");
+        streamlet.set_pruning(keep);
+    }
+
+    if let Some(window) = flags.get("replay-window") {
+        let window = window.parse::<u64>().expect("--replay-window should be a number of epochs");
+        streamlet.set_replay_window(window);
+    }
+
+    if let Some(behavior) = flags.get("byzantine") {
+        let behavior = behavior.parse::<CompromiseType>().unwrap_or_else(|e| panic!("--byzantine: {}", e));
+        streamlet.set_compromise(behavior);
+    }
+
+    if let Some(format) = flags.get("id-format") {
+        let format = format.parse::<EntryIdFormat>().expect("--id-format should be ulid, hex or decimal");
+        streamlet.set_entry_id_format(format);
+    }
+
+    if let Some(secret) = flags.get("roster-secret") {
+        streamlet.enable_roster_channel(secret.clone().into_bytes());
+    }
+
+    if let Some(path) = flags.get("control-socket") {
+        streamlet.set_control_socket(path);
+    }
+
+    if let Some(codec) = flags.get("codec") {
+        let codec = codec.parse::<Codec>().expect("--codec should be none, lz4 or zstd");
+        streamlet.set_codec(codec).expect("--codec zstd needs a build with the \"zstd\" feature");
+    }
+
+    if let Some(secret) = flags.get("callback-secret") {
+        streamlet.set_callback_secret(secret.clone().into_bytes());
+    }
+
+    if let Some(peers) = flags.get("bootstrap") {
+        let peers = peers
+            .split(',')
+            .map(|peer| peer.trim().parse::<Multiaddr>().expect("--bootstrap should be multiaddrs like /ip4/10.0.1.5/tcp/4001"))
+            .collect();
+        streamlet.set_bootstrap_peers(peers);
+    }
+
+    if let Some(addr) = flags.get("listen") {
+        streamlet.set_listen_addr(addr.parse().expect("--listen should be a multiaddr like /ip4/0.0.0.0/tcp/4001"));
+    }
+
+    if flags.contains_key("relay") || flags.contains_key("relay-server") {
+        let relays = flags
+            .get("relay")
+            .map(|relays| {
+                relays
+                    .split(',')
+                    .map(|relay| relay.trim().parse::<Multiaddr>().expect("--relay should be multiaddrs like /ip4/203.0.113.5/tcp/4001/p2p/<peer id>"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let relay_server = match flags.get("relay-server").map(String::as_str) {
+            None | Some("off") => false,
+            Some("on") => true,
+            Some(_) => panic!("--relay-server should be on or off"),
+        };
+        streamlet.set_nat_traversal(NatConfig { relay_server, relays }).unwrap_or_else(|e| panic!("--relay: {}", e));
+    }
+
+    if let Some(dir) = flags.get("report-dir") {
+        let period = flags
+            .get("report-period")
+            .map(|period| period.parse::<ReportPeriod>().expect("--report-period should be daily or weekly"))
+            .unwrap_or(ReportPeriod::Daily);
+        streamlet.set_report_schedule(period, dir);
+    }
+
+    if flags.contains_key("read-cache") || flags.contains_key("read-cache-memory") {
+        let config = ReadCacheConfig {
+            memory_items: flags
+                .get("read-cache-memory")
+                .map(|items| items.parse().expect("--read-cache-memory should be a number of items"))
+                .unwrap_or(DEFAULT_MEMORY_ITEMS),
+            disk_items: flags
+                .get("read-cache-disk")
+                .map(|items| items.parse().expect("--read-cache-disk should be a number of items"))
+                .unwrap_or(DEFAULT_DISK_ITEMS),
+            dir: flags.get("read-cache").map(std::path::PathBuf::from),
+        };
+        streamlet.set_read_cache(config).expect("Failed to open the read cache");
+    }
+
+    if let Some(addr) = flags.get("http-api") {
+        streamlet.set_http_api(addr.parse().expect("--http-api should be an address like 127.0.0.1:8080"));
+    }
+
+    if let Some(addr) = flags.get("grpc") {
+        streamlet.set_grpc(addr.parse().expect("--grpc should be an address like 127.0.0.1:50051"));
+    }
+
+    if let Some(addr) = flags.get("websocket") {
+        streamlet.set_websocket(addr.parse().expect("--websocket should be an address like 127.0.0.1:8081"));
+    }
+
+    if let Some(url) = flags.get("webhook-sink") {
+        let secret = flags.get("callback-secret").expect("--webhook-sink needs --callback-secret to sign deliveries");
+        let url = url.parse().unwrap_or_else(|e| panic!("--webhook-sink: {}", e));
+        streamlet.add_finalization_sink(Box::new(WebhookSink::new(url, secret.as_bytes())));
+    }
+
+    if let Some(target) = flags.get("kafka-sink") {
+        let (brokers, topic) = target.rsplit_once('/').expect("--kafka-sink should be <brokers>/<topic>, like localhost:9092/streamlet");
+        #[cfg(feature = "kafka")]
+        {
+            let sink = cs244b_project::KafkaSink::new(brokers, topic).unwrap_or_else(|e| panic!("--kafka-sink: {}", e));
+            streamlet.add_finalization_sink(Box::new(sink));
+        }
+        #[cfg(not(feature = "kafka"))]
+        panic!("--kafka-sink {}/{} needs a build with the \"kafka\" feature", brokers, topic);
+    }
+
+    if let Err(e) = streamlet.check_latency_budget() {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(1);
+    }
 
     // Probably want to setup the id, num instances, exchange keys, etc.
-    streamlet.run().await; // Runs libp2p event loop
+    // Runs libp2p event loop, until SIGINT/SIGTERM (or it can't run)
+    if let Err(e) = streamlet.run().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    // Don't wait for the blocking read of stdin to finish
+    std::process::exit(0);
+}
+
+/* Separates "--flag value" pairs from positional arguments. */
+fn split_flags(args: Vec<String>) -> (Vec<String>, HashMap<String, String>) {
+    let mut positional = Vec::new();
+    let mut flags = HashMap::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if let Some(flag) = arg.strip_prefix("--") {
+            flags.insert(flag.to_string(), iter.next().unwrap_or_default());
+        } else {
+            positional.push(arg);
+        }
+    }
+    (positional, flags)
 }
@@ -0,0 +1,167 @@
+/* Planned validator downtime.
+   A validator about to be rebooted announces a maintenance window: a range of epochs,
+   signed with its own key (so nobody else can take it out of the rotation), carried in
+   a log entry like an upgrade announcement (see upgrade). Once the block is finalized,
+   every node leaves the validator out of the leader schedule for those epochs, so its
+   downtime doesn't leave epochs without a proposal, and doesn't raise missed-proposal
+   warnings or vote anomalies for it.
+   As with upgrades, a window must start at least MIN_MAINTENANCE_NOTICE epochs after the
+   block announcing it, so every node knows about it (and agrees on the leaders) before
+   it begins. */
+
+use crate::blockchain::{ChainId, LogEntry};
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+
+// Fewest epochs between the announcing block and the start of a window
+pub const MIN_MAINTENANCE_NOTICE: u64 = 10;
+// Longest window, in epochs
+pub const MAX_MAINTENANCE_EPOCHS: u64 = 360;
+// Prefix of a log entry's data that marks it as a maintenance announcement
+const MAINTENANCE_TAG: &[u8] = b"streamlet maintenance window v1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub validator: String,
+    pub start_epoch: u64,
+    pub end_epoch: u64, // exclusive
+    pub signature: Signature, // by the validator
+}
+
+impl MaintenanceWindow {
+    /* Announces our own downtime.
+    @param validator: this node's name
+    @param start_epoch: first epoch we'll be away
+    @param end_epoch: first epoch we're back
+    @param keypair: this node's signing key (see Signer)
+    @param chain_id: the deployment's chain id */
+    pub fn sign(validator: String, start_epoch: u64, end_epoch: u64, keypair: &dyn Signer, chain_id: &ChainId) -> Self {
+        let signature = keypair.sign(&MaintenanceWindow::signed_bytes(chain_id, &validator, start_epoch, end_epoch));
+        Self { validator, start_epoch, end_epoch, signature }
+    }
+
+    /* Checks the signature against the validator's public key, for the given deployment. */
+    pub fn verify(&self, public_key: &dyn Verifier, chain_id: &ChainId) -> bool {
+        let bytes = MaintenanceWindow::signed_bytes(chain_id, &self.validator, self.start_epoch, self.end_epoch);
+        public_key.verify(&bytes, &self.signature).is_ok()
+    }
+
+    /* Checks that the window may be announced in a block of the given epoch: it starts
+    after the notice period and isn't empty or too long.
+    @param announced_epoch: epoch of the block carrying the announcement */
+    pub fn check(&self, announced_epoch: u64) -> Result<(), String> {
+        if self.start_epoch < announced_epoch.saturating_add(MIN_MAINTENANCE_NOTICE) {
+            return Err(format!(
+                "window starts at epoch {}, less than {} epochs after epoch {}",
+                self.start_epoch, MIN_MAINTENANCE_NOTICE, announced_epoch
+            ));
+        }
+        if self.end_epoch <= self.start_epoch || self.end_epoch - self.start_epoch > MAX_MAINTENANCE_EPOCHS {
+            return Err(format!("window should last between 1 and {} epochs", MAX_MAINTENANCE_EPOCHS));
+        }
+        Ok(())
+    }
+
+    pub fn covers(&self, epoch: u64) -> bool {
+        (self.start_epoch..self.end_epoch).contains(&epoch)
+    }
+
+    /* The announcement as a log entry. */
+    pub fn to_entry(&self) -> LogEntry {
+        let mut data = MAINTENANCE_TAG.to_vec();
+        data.extend_from_slice(&bincode::serialize(self).expect("Failed serialization."));
+        LogEntry::new(data)
+    }
+
+    /* The window announced by a log entry (None for ordinary entries). */
+    pub fn from_entry(entry: &LogEntry) -> Option<Self> {
+        entry.data.strip_prefix(MAINTENANCE_TAG).and_then(|bytes| bincode::deserialize(bytes).ok())
+    }
+
+    /* Whether submitted data claims to be an announcement (only validators may make those). */
+    pub fn is_tagged(data: &[u8]) -> bool {
+        data.starts_with(MAINTENANCE_TAG)
+    }
+
+    fn signed_bytes(chain_id: &ChainId, validator: &str, start_epoch: u64, end_epoch: u64) -> Vec<u8> {
+        let mut bytes = MAINTENANCE_TAG.to_vec();
+        bytes.extend_from_slice(&chain_id.0);
+        bytes.extend_from_slice(&start_epoch.to_be_bytes());
+        bytes.extend_from_slice(&end_epoch.to_be_bytes());
+        bytes.extend_from_slice(validator.as_bytes());
+        bytes
+    }
+}
+
+/* Maintenance windows from finalized announcements. */
+#[derive(Debug, Default)]
+pub struct MaintenanceSchedule {
+    windows: Vec<MaintenanceWindow>,
+}
+
+impl MaintenanceSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn schedule(&mut self, window: MaintenanceWindow) {
+        self.windows.push(window);
+    }
+
+    pub fn in_maintenance(&self, validator: &str, epoch: u64) -> bool {
+        self.windows.iter().any(|window| window.validator == validator && window.covers(epoch))
+    }
+
+    /* Validators eligible to lead an epoch: everyone not in maintenance (or everyone,
+    should all of them be away at once).
+    @param epoch: the epoch
+    @param validators: every validator's name, sorted */
+    pub fn available(&self, epoch: u64, validators: &[String]) -> Vec<String> {
+        let available: Vec<String> = validators
+            .iter()
+            .filter(|validator| !self.in_maintenance(validator, epoch))
+            .cloned()
+            .collect();
+        if available.is_empty() {
+            validators.to_vec()
+        } else {
+            available
+        }
+    }
+
+    /* Forgets windows that ended before an epoch. */
+    pub fn prune(&mut self, epoch: u64) {
+        self.windows.retain(|window| window.end_epoch > epoch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_excludes_validator() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let chain_id = ChainId::default();
+        let window = MaintenanceWindow::sign(String::from("h2"), 30, 40, &keypair, &chain_id);
+        assert!(window.verify(&keypair.public(), &chain_id));
+        assert!(!window.verify(&keypair.public(), &ChainId::new("test")));
+        let mut forged = window.clone();
+        forged.validator = String::from("h1");
+        assert!(!forged.verify(&keypair.public(), &chain_id));
+
+        assert_eq!(MaintenanceWindow::from_entry(&window.to_entry()), Some(window.clone()));
+        assert!(window.check(20).is_ok());
+        assert!(window.check(25).is_err());
+        assert!(MaintenanceWindow::sign(String::from("h2"), 30, 30, &keypair, &chain_id).check(0).is_err());
+
+        let validators: Vec<String> = ["h1", "h2", "h3"].iter().map(|name| name.to_string()).collect();
+        let mut schedule = MaintenanceSchedule::new();
+        schedule.schedule(window);
+        assert_eq!(schedule.available(29, &validators), validators);
+        assert_eq!(schedule.available(30, &validators), vec![String::from("h1"), String::from("h3")]);
+        assert!(!schedule.in_maintenance("h2", 40));
+        schedule.prune(40);
+        assert!(!schedule.in_maintenance("h2", 35));
+    }
+}
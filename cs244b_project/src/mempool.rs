@@ -0,0 +1,154 @@
+/* Pending entries waiting to be proposed, queued per submitter.
+   Entries are handed out in weighted round-robin order across submitters, so a
+   single high-volume client can't monopolize every block. Within one submitter's
+   queue, entries keep their arrival order. */
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PriorityClass {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl PriorityClass {
+    /* Entries taken from a submitter per turn in the rotation. */
+    fn weight(&self) -> usize {
+        match self {
+            PriorityClass::Low => 1,
+            PriorityClass::Normal => 2,
+            PriorityClass::High => 4,
+        }
+    }
+}
+
+impl FromStr for PriorityClass {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "low" => Ok(PriorityClass::Low),
+            "normal" => Ok(PriorityClass::Normal),
+            "high" => Ok(PriorityClass::High),
+            _ => Err(format!("unknown priority class: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Mempool {
+    queues: HashMap<String, VecDeque<Vec<u8>>>,
+    // Submitters with pending entries, in rotation order; the front is being served
+    rotation: VecDeque<String>,
+    // Entries already taken from the front submitter during its current turn
+    served_this_turn: usize,
+    priorities: HashMap<String, PriorityClass>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Sets the priority class for a submitter (unlisted submitters are Normal). */
+    pub fn set_priority(&mut self, submitter: &str, class: PriorityClass) {
+        self.priorities.insert(submitter.to_string(), class);
+    }
+
+    /* Queues an entry from a submitter. */
+    pub fn push(&mut self, submitter: &str, entry: Vec<u8>) {
+        let queue = self.queues.entry(submitter.to_string()).or_default();
+        if queue.is_empty() {
+            self.rotation.push_back(submitter.to_string());
+        }
+        queue.push_back(entry);
+    }
+
+    /* Takes the next entry to propose, or None if nothing is pending. */
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let submitter = self.rotation.front()?.clone();
+        let queue = self.queues.get_mut(&submitter).expect("rotation out of sync with queues");
+        let entry = queue.pop_front();
+        self.served_this_turn += 1;
+
+        let weight = self.priorities.get(&submitter).copied().unwrap_or_default().weight();
+        if queue.is_empty() {
+            self.queues.remove(&submitter);
+            self.rotation.pop_front();
+            self.served_this_turn = 0;
+        } else if self.served_this_turn >= weight {
+            self.rotation.rotate_left(1);
+            self.served_this_turn = 0;
+        }
+        entry
+    }
+
+    /* Drops an entry (from whichever submitter) once it has been proposed by someone else. */
+    pub fn remove(&mut self, entry: &[u8]) {
+        for queue in self.queues.values_mut() {
+            queue.retain(|x| x != entry);
+        }
+        let emptied: Vec<String> = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| queue.is_empty())
+            .map(|(submitter, _)| submitter.clone())
+            .collect();
+        for submitter in emptied {
+            self.queues.remove(&submitter);
+            if self.rotation.front() == Some(&submitter) {
+                self.served_this_turn = 0;
+            }
+            self.rotation.retain(|x| *x != submitter);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.values().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_across_submitters() {
+        let mut mempool = Mempool::new();
+        for i in 0..5u8 {
+            mempool.push("busy", vec![i]);
+        }
+        mempool.push("quiet", vec![100]);
+        mempool.set_priority("busy", PriorityClass::Low);
+
+        // The quiet submitter gets a turn right after the busy one's single-entry turn
+        assert_eq!(mempool.pop(), Some(vec![0]));
+        assert_eq!(mempool.pop(), Some(vec![100]));
+        assert_eq!(mempool.pop(), Some(vec![1]));
+        assert_eq!(mempool.len(), 3);
+
+        mempool.remove(&[2]);
+        assert_eq!(mempool.pop(), Some(vec![3]));
+        assert_eq!(mempool.pop(), Some(vec![4]));
+        assert_eq!(mempool.pop(), None);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_priority_weights() {
+        let mut mempool = Mempool::new();
+        mempool.set_priority("a", PriorityClass::High);
+        for i in 0..6u8 {
+            mempool.push("a", vec![i]);
+            mempool.push("b", vec![10 + i]);
+        }
+        let order: Vec<u8> = (0..7).map(|_| mempool.pop().unwrap()[0]).collect();
+        assert_eq!(order, vec![0, 1, 2, 3, 10, 11, 4]);
+    }
+}
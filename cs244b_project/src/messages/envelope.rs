@@ -0,0 +1,142 @@
+/* Signed envelopes: authenticated senders for consensus messages.
+   The signatures inside a message are votes on its payload; they say nothing about who
+   sent the message, and its sender_name is whatever the sender typed. A validator
+   therefore wraps each consensus message it sends (proposal, vote, notarization,
+   finalization) in an envelope carrying its own name and public key and a signature,
+   under that key, over the message's payload, kind, nonce, tag and sender name, the
+   envelope's sender, and the epoch it was sent in. Receivers check the signature, and
+   that the key is the one they know the sender by, before handling the message; a
+   consensus message that isn't sealed, or is sealed by a node that isn't a known
+   validator, is dropped. The epoch is signed so logs and
+   audits can rely on it, but isn't compared with the receiver's own: each node counts
+   epochs from when its peer discovery ended, so a node that joined late counts from a
+   different start until epoch sync lines it up (see epoch_sync), and even then nodes
+   may differ by an epoch around its boundary (replay checks go by the epoch of the
+   block instead; see replay). Epoch sync reports (MessageKind::EpochSync) are sealed
+   too, and only count if the envelope's sender is the one reporting.
+   The envelope's sender is whoever sent this copy. It differs from the message's
+   sender_name when a validator echoes another's message (an echoed vote keeps the
+   proposal's sender_name); the sender_name says whose message it was first.
+   On the wire a sealed message is ENVELOPE_MAGIC, the bincode encoding of the sender,
+   public key, epoch and signature, then the message in its own wire format (see
+   messages). A plain message begins with WIRE_MAGIC (or, from older releases, its
+   payload's variant index, a small integer), so it never starts with ENVELOPE_MAGIC,
+   and both can share a topic. */
+
+use bincode::{deserialize_from, serialize};
+
+use crate::blockchain::ChainId;
+use crate::messages::{Message, MessageKind, WireError};
+use crate::utils::crypto::*;
+
+// Prefix of a sealed message on the wire
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"SLE1";
+// Domain separation for envelope signatures (they can't pass for votes, or anything else)
+pub const ENVELOPE_CONTEXT: &[u8] = b"streamlet envelope v1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedEnvelope {
+    pub sender: String,        // name of the node that sent this copy
+    pub public_key: PublicKey, // its key
+    pub epoch: u64,            // its epoch when it sent it
+    pub signature: Signature,
+    pub message: Message,
+}
+
+impl SignedEnvelope {
+    fn signed_bytes(message: &Message, sender: &str, epoch: u64, chain_id: &ChainId) -> Vec<u8> {
+        let fields = (ENVELOPE_CONTEXT, &message.payload, &message.kind, message.nonce, message.tag, &message.sender_name, sender, epoch);
+        chain_id.bind(&serialize(&fields).expect("Failed serialization."))
+    }
+
+    /* Wraps a message for sending.
+    @param message: the message
+    @param sender: our name
+    @param epoch: our current epoch
+    @param keypair: our key
+    @param chain_id: the deployment's chain id */
+    pub fn seal(message: Message, sender: &str, epoch: u64, keypair: &dyn Signer, chain_id: &ChainId) -> Self {
+        let signature = keypair.sign(&SignedEnvelope::signed_bytes(&message, sender, epoch, chain_id));
+        Self { sender: sender.to_string(), public_key: keypair.public(), epoch, signature, message }
+    }
+
+    /* Whether the envelope's signature is valid under the key it names. Says nothing
+    about whether that key is really the sender's; check it against the known key. */
+    pub fn verify(&self, chain_id: &ChainId) -> bool {
+        let bytes = SignedEnvelope::signed_bytes(&self.message, &self.sender, self.epoch, chain_id);
+        self.public_key.verify(&bytes, &self.signature).is_ok()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.extend(serialize(&(&self.sender, &self.public_key, self.epoch, &self.signature)).expect("Failed serialization."));
+        bytes.extend(self.message.serialize());
+        bytes
+    }
+
+    /* Decodes a sealed message: None if the bytes aren't one (e.g. a plain message), an
+    error if they are but can't be decoded. */
+    pub fn open(bytes: &[u8]) -> Option<Result<Self, WireError>> {
+        let mut rest = bytes.strip_prefix(&ENVELOPE_MAGIC[..])?;
+        let (sender, public_key, epoch, signature) = match deserialize_from(&mut rest) {
+            Ok(header) => header,
+            Err(_) => return Some(Err(WireError::Malformed)),
+        };
+        Some(Message::decode(rest).map(|message| Self { sender, public_key, epoch, signature, message }))
+    }
+}
+
+/* The kind of a message, sealed or not, read without decoding it (see Message::peek_kind);
+for sorting traffic at the network boundary, before anything is checked. */
+pub fn peek_kind(bytes: &[u8]) -> Option<MessageKind> {
+    match bytes.strip_prefix(&ENVELOPE_MAGIC[..]) {
+        Some(mut rest) => {
+            let _: (String, PublicKey, u64, Signature) = deserialize_from(&mut rest).ok()?;
+            Message::peek_kind(rest)
+        }
+        None => Message::peek_kind(bytes),
+    }
+}
+
+/* Decodes a message whether or not it is sealed, without checking the envelope; for
+tools that only read traffic (e.g. a relay keeping recent blocks). */
+pub fn decode_message(bytes: &[u8]) -> Option<Message> {
+    match SignedEnvelope::open(bytes) {
+        Some(envelope) => envelope.ok().map(|envelope| envelope.message),
+        None => Message::deserialize(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::messages::MessagePayload;
+
+    #[test]
+    fn test_envelope_binds_sender_and_message() {
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let chain_id = ChainId::default();
+        let block = MessagePayload::Block(Block::new(4, [0; 32], b"entry".to_vec(), 1, 0));
+        let message = Message::new(block, MessageKind::Vote, 1, String::from("h1"));
+        let envelope = SignedEnvelope::seal(message.clone(), "h2", 4, &keypair, &chain_id);
+        assert!(envelope.verify(&chain_id));
+        assert!(!envelope.verify(&ChainId::new("other deployment")));
+
+        let bytes = envelope.serialize();
+        assert_eq!(SignedEnvelope::open(&bytes), Some(Ok(envelope.clone())));
+        assert_eq!(decode_message(&bytes), Some(message.clone()));
+        assert_eq!(SignedEnvelope::open(&message.serialize()), None);
+        assert_eq!(decode_message(&message.serialize()), Some(message.clone()));
+        assert_eq!(peek_kind(&bytes), Some(MessageKind::Vote));
+        assert_eq!(peek_kind(&message.serialize()), Some(MessageKind::Vote));
+        assert_eq!(peek_kind(b"junk"), None);
+
+        let mut renamed = envelope.clone();
+        renamed.sender = String::from("h3");
+        assert!(!renamed.verify(&chain_id));
+        let mut retyped = envelope;
+        retyped.message.kind = MessageKind::Notarize;
+        assert!(!retyped.verify(&chain_id));
+    }
+}
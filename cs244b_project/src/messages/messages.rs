@@ -1,14 +1,70 @@
 use bincode::{deserialize, serialize};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
 use std::vec::Vec;
 
-use crate::blockchain::Block;
+use crate::blockchain::{Block, ChainId, ChainSnapshot, EntryId, InclusionPromise, InclusionProof, LocalChain, LogEntry, TreeHeadUpdate};
+use crate::epoch_sync::EpochPosition;
+use crate::error::StreamletError;
 use crate::network::peer_init::PeerAdvertisement;
+use crate::network::roster_channel::SealedEnvelope;
 use crate::utils::crypto::*;
 
+/* Wire format (version 1). A message is sent as:
+     u8   WIRE_MAGIC
+     u8   format version (WIRE_VERSION)
+     u32  kind (MessageKind's variant index)
+     u32  header length, then the header: nonce, tag, sender_id, sender_name, signatures
+     u32  payload length, then the payload (MessagePayload, as signers sign it)
+   Integers are little-endian, and sections are bincode-encoded (see wire for the layout
+   of each field). The format evolves without breaking older releases:
+   - New fields go at the end of the header or payload section, or after the payload
+     as a section of their own; a release that doesn't know them skips them. Fields
+     that must be signed can't be added this way (a release re-encoding the payload
+     without them would check signatures over the wrong bytes); they need a new version.
+   - New kinds and payloads are appended to their enums. A release that doesn't know
+     one reports UnknownKind or UnknownPayload, and the message is skipped without
+     holding it against its sender.
+   - An incompatible change bumps WIRE_VERSION; older releases skip such messages.
+   Messages from releases before the version byte (plain bincode of the struct, which
+   starts with the payload's variant index, never WIRE_MAGIC) are still decoded. */
+pub const WIRE_MAGIC: u8 = 0xfe;
+pub const WIRE_VERSION: u8 = 1;
+// Payload variants this release knows (MessagePayload::EpochSync is the last)
+const KNOWN_PAYLOADS: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    Malformed,
+    UnknownVersion(u8),
+    UnknownKind(u32),
+    UnknownPayload(u32),
+    Mismatch(String), // the payload doesn't fit the kind
+}
+
+impl WireError {
+    /* Whether the message may be fine, but from a newer release than ours. */
+    pub fn is_from_newer_release(&self) -> bool {
+        matches!(self, WireError::UnknownVersion(_) | WireError::UnknownKind(_) | WireError::UnknownPayload(_))
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Malformed => write!(f, "malformed message"),
+            WireError::UnknownVersion(version) => write!(f, "unknown wire format version {}", version),
+            WireError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            WireError::UnknownPayload(payload) => write!(f, "unknown payload variant {}", payload),
+            WireError::Mismatch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedMessage")]
 pub struct Message {
     pub payload: MessagePayload,
     pub kind: MessageKind,
@@ -55,15 +111,64 @@ impl Message {
     }
     // Used to sign the message payload (block)
     pub fn serialize_payload(&self) -> Vec<u8> {
-        return self.payload.serialize();
+        self.payload.serialize()
     }
+    /* What signers of the message sign: the payload, bound to the deployment's chain id. */
+    pub fn signed_bytes(&self, chain_id: &ChainId) -> Vec<u8> {
+        chain_id.bind(&self.serialize_payload())
+    }
+    /* Encodes the message in the current wire format (see WIRE_VERSION). */
     pub fn serialize(&self) -> Vec<u8> {
-        let encoded: Vec<u8> = serialize(self).expect("Failed serialization.");
-        return encoded;
+        let header = serialize(&(self.nonce, self.tag, self.sender_id, &self.sender_name, &self.signatures)).expect("Failed serialization.");
+        let payload = self.payload.serialize();
+        let mut encoded = vec![WIRE_MAGIC, WIRE_VERSION];
+        encoded.extend(serialize(&self.kind).expect("Failed serialization."));
+        for section in [header, payload] {
+            encoded.extend((section.len() as u32).to_le_bytes());
+            encoded.extend(section);
+        }
+        encoded
     }
-    pub fn deserialize(encoded: &Vec<u8>) -> Message {
-        let decoded: Message = deserialize(&encoded[..]).expect("Failed deserialization.");
-        return decoded;
+    /* Decodes a message; None if it is malformed, from a newer release we can't read,
+    or its payload doesn't fit its kind. */
+    pub fn deserialize(encoded: &[u8]) -> Option<Message> {
+        Message::decode(encoded).ok()
+    }
+    /* Decodes a message, saying why it can't be if so.
+    @param encoded: the message in any wire format version we know */
+    pub fn decode(encoded: &[u8]) -> Result<Message, WireError> {
+        match encoded {
+            [WIRE_MAGIC, WIRE_VERSION, rest @ ..] => Message::decode_v1(rest),
+            [WIRE_MAGIC, version, ..] => Err(WireError::UnknownVersion(*version)),
+            // From before the version byte
+            _ => deserialize(encoded).map_err(|_| WireError::Malformed),
+        }
+    }
+    /* The kind of an encoded message, read from its header without decoding the rest;
+    None if the message isn't in the current wire format, or its kind is unknown.
+    @param encoded: the message */
+    pub fn peek_kind(encoded: &[u8]) -> Option<MessageKind> {
+        match encoded {
+            [WIRE_MAGIC, WIRE_VERSION, rest @ ..] => deserialize(rest.get(..4)?).ok(),
+            _ => None,
+        }
+    }
+    fn decode_v1(mut rest: &[u8]) -> Result<Message, WireError> {
+        let kind_index = take_u32(&mut rest)?;
+        let kind: MessageKind = deserialize(&kind_index.to_le_bytes()).map_err(|_| WireError::UnknownKind(kind_index))?;
+        // Fields after the ones we know, in either section, are from newer releases
+        let header = take_section(&mut rest)?;
+        let (nonce, tag, sender_id, sender_name, signatures) = deserialize(header).map_err(|_| WireError::Malformed)?;
+        let mut payload = take_section(&mut rest)?;
+        let payload = match deserialize(payload) {
+            Ok(payload) => payload,
+            Err(_) => match take_u32(&mut payload) {
+                Ok(variant) if variant >= KNOWN_PAYLOADS => return Err(WireError::UnknownPayload(variant)),
+                _ => return Err(WireError::Malformed),
+            },
+        };
+        let unchecked = UncheckedMessage { payload, kind, nonce, tag, sender_id, sender_name, signatures };
+        Message::try_from(unchecked).map_err(WireError::Mismatch)
     }
     // Access functions for message signatures to avoid storing entire Siganture vector copies
     pub fn get_signatures(self) -> Vec<Signature> { self.signatures } 
@@ -71,6 +176,60 @@ impl Message {
         self.signatures.push(signature)
     }
     pub fn signature_count(&self) -> usize { self.signatures.len() }
+    /* Names the message in logs, the same on every node (see telemetry). Votes and echoes
+    keep their proposal's sender and nonce, so they share its id. */
+    pub fn correlation_id(&self) -> String {
+        format!("{}-{:08x}", self.sender_name, self.nonce)
+    }
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, WireError> {
+    if bytes.len() < 4 {
+        return Err(WireError::Malformed);
+    }
+    let (value, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Ok(u32::from_le_bytes(value.try_into().expect("4 bytes")))
+}
+
+fn take_section<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], WireError> {
+    let len = take_u32(bytes)? as usize;
+    if bytes.len() < len {
+        return Err(WireError::Malformed);
+    }
+    let (section, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(section)
+}
+
+// A message as decoded, before its payload is checked against its kind
+#[derive(Deserialize)]
+struct UncheckedMessage {
+    payload: MessagePayload,
+    kind: MessageKind,
+    nonce: u32,
+    tag: u32,
+    sender_id: u32,
+    sender_name: String,
+    signatures: Vec<Signature>,
+}
+
+impl TryFrom<UncheckedMessage> for Message {
+    type Error = String;
+    fn try_from(m: UncheckedMessage) -> Result<Self, Self::Error> {
+        if !m.kind.accepts(&m.payload) {
+            return Err(format!("{:?} message can't carry a {} payload", m.kind, m.payload.name()));
+        }
+        Ok(Message {
+            payload: m.payload,
+            kind: m.kind,
+            nonce: m.nonce,
+            tag: m.tag,
+            sender_id: m.sender_id,
+            sender_name: m.sender_name,
+            signatures: m.signatures,
+        })
+    }
 }
 
 // Wrapper for different kinds of messages
@@ -82,25 +241,71 @@ pub enum MessagePayload {
     AppData(Vec<u8>),
     SocketAddr(SocketAddr),
     None,
+    ChainSyncRequest(ChainSyncRequest),
+    Chain(LocalChain),
+    EntryId(EntryId),
+    Sealed(SealedEnvelope),
+    Promise(InclusionPromise),
+    Inclusion(InclusionProof),
+    TreeHead(TreeHeadUpdate),
+    Submit(LogEntry),
+    Snapshot(ChainSnapshot),
+    EpochSync(EpochPosition),
+}
+
+// Sent by a node that fell behind: asks peers for the notarized blocks it is missing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainSyncRequest {
+    pub from_height: u64,  // height of the requester's latest finalized block (peers send blocks from here on)
+    pub known_height: u64, // height of the requester's longest notarized chain
 }
 
 // Useful for serializing the payload (block) so we can sign it
 impl MessagePayload {
     pub fn serialize(&self) -> Vec<u8> {
         let encoded: Vec<u8> = serialize(self).unwrap();
-        return encoded;
+        encoded
+    }
+    pub fn deserialize(encoded: &[u8]) -> Result<MessagePayload, StreamletError> {
+        Ok(deserialize(encoded)?)
     }
-    pub fn deserialize(encoded: &Vec<u8>) -> MessagePayload {
-        let decoded: MessagePayload = deserialize(&encoded[..]).unwrap();
-        return decoded;
+
+    /* The variant's name, for error messages. */
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessagePayload::Block(_) => "Block",
+            MessagePayload::String(_) => "String",
+            MessagePayload::PeerAdvertisement(_) => "PeerAdvertisement",
+            MessagePayload::AppData(_) => "AppData",
+            MessagePayload::SocketAddr(_) => "SocketAddr",
+            MessagePayload::None => "None",
+            MessagePayload::ChainSyncRequest(_) => "ChainSyncRequest",
+            MessagePayload::Chain(_) => "Chain",
+            MessagePayload::EntryId(_) => "EntryId",
+            MessagePayload::Sealed(_) => "Sealed",
+            MessagePayload::Promise(_) => "Promise",
+            MessagePayload::Inclusion(_) => "Inclusion",
+            MessagePayload::TreeHead(_) => "TreeHead",
+            MessagePayload::Submit(_) => "Submit",
+            MessagePayload::Snapshot(_) => "Snapshot",
+            MessagePayload::EpochSync(_) => "EpochSync",
+        }
     }
 }
 
+/* What a message is for. Each kind carries one kind of payload (see accepts); messages
+   whose payload doesn't match their kind are rejected when they are decoded, so handlers
+   can rely on it. */
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessageKind {
-    Vote,
-    Propose,
-    Test,
+    // Consensus (payload: a block)
+    Propose,  // signatures: the leader's
+    Vote,     // signatures: votes for the block, echoed as they are collected
+    Notarize, // the block just got notarized; signatures: the quorum of votes
+    Finalize, // the block is the newest one finalized; signatures: its notarization
+    // Catch-up for nodes that start late or fall behind
+    SyncRequest,
+    SyncResponse,
     PeerInit,
     // Application-Streamlet config
     AppRequest,
@@ -109,6 +314,56 @@ pub enum MessageKind {
     AppBlockResponse,
     AppChainRequest,
     AppChainResponse,
+    AppReceipt, // Acknowledges an accepted entry (payload: a signed inclusion promise)
+    // Validators-only coordination (see network::roster_channel)
+    RosterSealed, // payload: a sealed RosterNotice message
+    RosterNotice, // payload: the announcement text
+    AppFinalized, // An entry was finalized and is now queryable (payload: its inclusion proof)
+    // Published on the STH topic for monitors (see monitor)
+    TreeHead,
+    // A client submits an entry to every validator's mempool (payload: the entry)
+    Submit,
+    // Catch-up for nodes far behind: the whole finalized chain at once (see snapshot)
+    SnapshotRequest,
+    SnapshotResponse,
+    // An observer's peer advertisement: validators answer it but don't count it (see peer_init)
+    ObserverInit,
+    // A validator reports where it is in its epoch clock (see epoch_sync)
+    EpochSync,
+}
+
+impl MessageKind {
+    /* Whether messages of this kind drive consensus (and must come sealed; see envelope). */
+    pub fn is_consensus(&self) -> bool {
+        matches!(self, MessageKind::Propose | MessageKind::Vote | MessageKind::Notarize | MessageKind::Finalize)
+    }
+
+    /* Whether a message of this kind may carry the payload. */
+    pub fn accepts(&self, payload: &MessagePayload) -> bool {
+        use MessagePayload as P;
+        match self {
+            MessageKind::Propose | MessageKind::Vote | MessageKind::Notarize | MessageKind::Finalize => {
+                matches!(payload, P::Block(_))
+            }
+            MessageKind::SyncRequest => matches!(payload, P::ChainSyncRequest(_)),
+            MessageKind::SyncResponse => matches!(payload, P::Chain(_)),
+            MessageKind::PeerInit | MessageKind::ObserverInit => matches!(payload, P::PeerAdvertisement(_)),
+            MessageKind::AppRequest | MessageKind::AppBlockRequest | MessageKind::AppChainRequest => {
+                matches!(payload, P::None)
+            }
+            MessageKind::AppSend => matches!(payload, P::AppData(_)),
+            MessageKind::AppBlockResponse | MessageKind::AppChainResponse => matches!(payload, P::SocketAddr(_)),
+            MessageKind::AppReceipt => matches!(payload, P::Promise(_)),
+            MessageKind::RosterSealed => matches!(payload, P::Sealed(_)),
+            MessageKind::RosterNotice => matches!(payload, P::String(_)),
+            MessageKind::AppFinalized => matches!(payload, P::Inclusion(_)),
+            MessageKind::TreeHead => matches!(payload, P::TreeHead(_)),
+            MessageKind::Submit => matches!(payload, P::Submit(_)),
+            MessageKind::SnapshotRequest => matches!(payload, P::ChainSyncRequest(_)),
+            MessageKind::SnapshotResponse => matches!(payload, P::Snapshot(_)),
+            MessageKind::EpochSync => matches!(payload, P::EpochSync(_)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -121,10 +376,7 @@ mod tests {
         let mut hasher = Sha256::new();
         hasher.update(b"hello world");
         let result = hasher.finalize();
-        let bytes: Sha256Hash = result
-            .as_slice()
-            .try_into()
-            .expect("slice with incorrect length");
+        let bytes: Sha256Hash = result.into();
 
         // Create a test block
         let blk = Block::new(0, bytes, String::from("test").into_bytes(), 0, 0);
@@ -141,6 +393,54 @@ mod tests {
         let serialized_message = message.serialize();
         let deserialized_message = Message::deserialize(&serialized_message);
 
-        assert_eq!(message, deserialized_message);
+        assert_eq!(deserialized_message.as_ref().map(Message::correlation_id), Some(message.correlation_id()));
+        assert_eq!(Some(message), deserialized_message);
+
+        let entry = LogEntry::new(b"entry".to_vec());
+        let submission = Message::new(MessagePayload::Submit(entry), MessageKind::Submit, 0, String::from("client"));
+        assert_eq!(Message::deserialize(&submission.serialize()), Some(submission));
+
+        // A payload that doesn't fit the kind is rejected, as is garbage
+        let mismatched = Message::new(MessagePayload::String(String::from("hi")), MessageKind::Vote, 0, String::from("test"));
+        assert!(!MessageKind::Vote.accepts(&mismatched.payload));
+        assert_eq!(Message::deserialize(&mismatched.serialize()), None);
+        assert_eq!(Message::deserialize(b"junk"), None);
+    }
+
+    #[test]
+    fn test_wire_format_evolution() {
+        let block = Block::new(2, [0; 32], b"entry".to_vec(), 1, 0);
+        let message = Message::new(MessagePayload::Block(block), MessageKind::Propose, 1, String::from("h1"));
+        let encoded = message.serialize();
+        assert_eq!(encoded[..2], [WIRE_MAGIC, WIRE_VERSION]);
+
+        // Messages from before the version byte
+        assert_eq!(Message::decode(&serialize(&message).unwrap()), Ok(message.clone()));
+        // Fields a newer release appended are skipped
+        let mut appended = encoded.clone();
+        appended.extend(b"newer section");
+        assert_eq!(Message::decode(&appended), Ok(message.clone()));
+
+        // Kinds, payloads and versions we don't know yet
+        let mut newer_kind = encoded.clone();
+        newer_kind[2..6].copy_from_slice(&999u32.to_le_bytes());
+        assert_eq!(Message::decode(&newer_kind), Err(WireError::UnknownKind(999)));
+        let position = EpochPosition { epoch: 3, elapsed_ms: 250, sent_ms: 1_000, wants_reply: false };
+        let epoch_sync = Message::new(MessagePayload::EpochSync(position.clone()), MessageKind::EpochSync, 1, String::from("h1")).serialize();
+        let epoch_sync_at = epoch_sync.len() - MessagePayload::EpochSync(position).serialize().len();
+        assert_eq!(epoch_sync[epoch_sync_at..epoch_sync_at + 4], (KNOWN_PAYLOADS - 1).to_le_bytes());
+        let payload_at = encoded.len() - message.payload.serialize().len();
+        let mut newer_payload = encoded.clone();
+        newer_payload[payload_at..payload_at + 4].copy_from_slice(&KNOWN_PAYLOADS.to_le_bytes());
+        assert_eq!(Message::decode(&newer_payload), Err(WireError::UnknownPayload(KNOWN_PAYLOADS)));
+        // ... but a known payload that doesn't decode is just malformed
+        let mut corrupted = epoch_sync.clone();
+        *corrupted.last_mut().unwrap() = 2; // wants_reply, a bool
+        assert_eq!(Message::decode(&corrupted), Err(WireError::Malformed));
+        let mut newer_version = encoded.clone();
+        newer_version[1] = WIRE_VERSION + 1;
+        assert!(Message::decode(&newer_version).unwrap_err().is_from_newer_release());
+
+        assert_eq!(Message::decode(&encoded[..encoded.len() - 1]), Err(WireError::Malformed));
     }
 }
@@ -1,3 +1,6 @@
+#[allow(clippy::module_inception)]
 mod messages;
+pub mod envelope;
+pub mod wire;
 
 pub use messages::*;
@@ -0,0 +1,469 @@
+/* Annotated view of the on-the-wire (bincode) encoding of messages and blocks.
+   Meant for people writing verifiers or alternate implementations in other languages:
+   every field is encoded on its own, in declaration order, so the concatenation of the
+   annotated fields is byte-for-byte what `serialize()` produces. A message starts with
+   a magic byte and its wire format version, and its header and payload are sections
+   prefixed with their length (u32), so newer releases can add to them (see messages).
+   On the network, a message (sealed or not) is framed (see network::codec): one flag
+   byte naming its compression codec (0 = none, 1 = zstd, 2 = lz4), then either the
+   message as it is (none), or its length (u32) and the compressed bytes. Messages under
+   COMPRESS_MIN_LEN bytes, or that wouldn't shrink, are sent as none. dump_frame
+   annotates a frame. A frame over MAX_CHUNK_LEN bytes is published as chunks instead
+   (see network::chunking): the flag byte CHUNK_FLAG (0xc0), a transfer id (u64), the
+   chunk's index and the number of chunks (u32 each), then its part of the frame.
+   Receivers join the parts in index order to get the frame back. dump_chunk annotates
+   a chunk.
+
+   Consensus messages are sent sealed (see envelope): ENVELOPE_MAGIC ("SLE1"), the
+   sender's name, public key, epoch and signature, then the message. The signature is
+   over the chain id (32 raw bytes) followed by the encoding of (ENVELOPE_CONTEXT,
+   payload, kind, nonce, tag, sender_name, sender, epoch); dump_envelope_signed_input
+   lays it out field by field.
+
+   bincode (1.x, default options) layout reminders:
+   - integers are fixed-width little-endian
+   - Vec<T> / String / byte strings: u64 length prefix, then the elements
+   - fixed-size arrays ([u8; 32]): raw bytes, no length prefix
+   - signatures / public keys: u32 scheme index (0 = ed25519, 1 = secp256k1), then raw bytes
+   - enums: u32 variant index, then the variant's contents
+
+   A block's hash isn't computed over its encoding. It is SHA-256 over:
+   - parent_hash (32 raw bytes)
+   - epoch (u64, little-endian)
+   - data (raw bytes, no length prefix)
+   - nonce (u64, little-endian)
+   Height isn't covered. */
+
+use bincode::{deserialize, serialize};
+use serde::Serialize;
+use std::fmt;
+
+use crate::blockchain::{Block, ChainId, NotarizationCert, SignedBlock};
+use crate::messages::envelope::{SignedEnvelope, ENVELOPE_CONTEXT, ENVELOPE_MAGIC};
+use crate::messages::{Message, MessageKind, MessagePayload, WIRE_MAGIC, WIRE_VERSION};
+use crate::network::chunking::{chunk, is_chunk, ChunkError, CHUNK_FLAG};
+use crate::network::codec::{Codec, CodecError};
+use crate::utils::crypto::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireField {
+    pub offset: usize,
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WireDump {
+    pub fields: Vec<WireField>,
+}
+
+impl WireDump {
+    pub fn new() -> Self {
+        Self { fields: Vec::new() }
+    }
+
+    /* Appends the encoding of a single field.
+    @param name: annotation for the field (dotted path, e.g. "payload.block.epoch")
+    @param value: the field's value, encoded exactly as it would be inside its parent */
+    pub fn push<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) {
+        let bytes = serialize(value).expect("Failed serialization.");
+        self.fields.push(WireField {
+            offset: self.len(),
+            name: name.to_string(),
+            bytes,
+        });
+    }
+
+    /* Appends bytes as they are, for fields that aren't bincode (e.g. compressed data). */
+    pub fn push_raw(&mut self, name: &str, bytes: &[u8]) {
+        self.fields.push(WireField {
+            offset: self.len(),
+            name: name.to_string(),
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    /* Appends the fields of another dump, encoded right after this one's.
+    @param prefix: prepended to each of its field names */
+    pub fn append(&mut self, prefix: &str, other: WireDump) {
+        let start = self.len();
+        self.fields.extend(other.fields.into_iter().map(|field| WireField {
+            offset: start + field.offset,
+            name: format!("{}{}", prefix, field.name),
+            bytes: field.bytes,
+        }));
+    }
+
+    /* Total encoded length, in bytes. */
+    pub fn len(&self) -> usize {
+        self.fields
+            .last()
+            .map(|f| f.offset + f.bytes.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /* The full encoding (concatenation of all annotated fields). */
+    pub fn bytes(&self) -> Vec<u8> {
+        self.fields.iter().flat_map(|f| f.bytes.clone()).collect()
+    }
+}
+
+impl fmt::Display for WireDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>8} {:>6}  {:<36} bytes", "offset", "len", "field")?;
+        for field in &self.fields {
+            writeln!(
+                f,
+                "{:>8} {:>6}  {:<36} {}",
+                field.offset,
+                field.bytes.len(),
+                field.name,
+                hex::encode(&field.bytes)
+            )?;
+        }
+        write!(f, "total: {} bytes", self.len())
+    }
+}
+
+/* Annotated encoding of a block, with each field name prefixed by `prefix`. */
+fn push_block(dump: &mut WireDump, prefix: &str, block: &Block) {
+    dump.push(&format!("{}epoch (u64)", prefix), &block.epoch);
+    dump.push(&format!("{}hash ([u8; 32])", prefix), &block.hash);
+    dump.push(&format!("{}parent_hash ([u8; 32])", prefix), &block.parent_hash);
+    dump.push(&format!("{}data (len u64 + bytes)", prefix), &block.data);
+    dump.push(&format!("{}height (u64)", prefix), &block.height);
+    dump.push(&format!("{}nonce (u64)", prefix), &block.nonce);
+}
+
+fn push_signatures(dump: &mut WireDump, prefix: &str, signatures: &[Signature]) {
+    dump.push(&format!("{}signatures.len (u64)", prefix), &(signatures.len() as u64));
+    for (i, signature) in signatures.iter().enumerate() {
+        dump.push(&format!("{}signatures[{}] (u32 scheme + signature bytes)", prefix, i), signature);
+    }
+}
+
+fn push_cert(dump: &mut WireDump, prefix: &str, cert: &NotarizationCert) {
+    dump.push(&format!("{}block_hash ([u8; 32])", prefix), &cert.block_hash);
+    dump.push(&format!("{}epoch (u64)", prefix), &cert.epoch);
+    push_signatures(dump, prefix, &cert.signatures);
+}
+
+pub fn dump_block(block: &Block) -> WireDump {
+    let mut dump = WireDump::new();
+    push_block(&mut dump, "", block);
+    dump
+}
+
+pub fn dump_signed_block(signed_block: &SignedBlock) -> WireDump {
+    let mut dump = WireDump::new();
+    push_block(&mut dump, "block.", &signed_block.block);
+    push_cert(&mut dump, "cert.", &signed_block.cert);
+    dump
+}
+
+pub fn dump_message(message: &Message) -> WireDump {
+    let mut dump = WireDump::new();
+    dump.push("magic (u8)", &WIRE_MAGIC);
+    dump.push("version (u8)", &WIRE_VERSION);
+    dump.push(&format!("kind (u32) = {:?}", message.kind), &message.kind);
+    let header = (message.nonce, message.tag, message.sender_id, &message.sender_name, &message.signatures);
+    dump.push("header.len (u32)", &(serialize(&header).expect("Failed serialization.").len() as u32));
+    dump.push("header.nonce (u32)", &message.nonce);
+    dump.push("header.tag (u32)", &message.tag);
+    dump.push("header.sender_id (u32)", &message.sender_id);
+    dump.push("header.sender_name (len u64 + utf8)", &message.sender_name);
+    push_signatures(&mut dump, "header.", &message.signatures);
+    dump.push("payload.len (u32)", &(message.payload.serialize().len() as u32));
+    match &message.payload {
+        MessagePayload::Block(block) => {
+            dump.push("payload.variant (u32) = Block", &0u32);
+            push_block(&mut dump, "payload.block.", block);
+        }
+        MessagePayload::String(s) => {
+            dump.push("payload.variant (u32) = String", &1u32);
+            dump.push("payload.string (len u64 + utf8)", s);
+        }
+        MessagePayload::PeerAdvertisement(ad) => {
+            dump.push("payload.variant (u32) = PeerAdvertisement", &2u32);
+            dump.push("payload.peer_advertisement", ad);
+        }
+        MessagePayload::AppData(data) => {
+            dump.push("payload.variant (u32) = AppData", &3u32);
+            dump.push("payload.app_data (len u64 + bytes)", data);
+        }
+        MessagePayload::SocketAddr(addr) => {
+            dump.push("payload.variant (u32) = SocketAddr", &4u32);
+            dump.push("payload.socket_addr", addr);
+        }
+        MessagePayload::None => {
+            dump.push("payload.variant (u32) = None", &5u32);
+        }
+        MessagePayload::ChainSyncRequest(request) => {
+            dump.push("payload.variant (u32) = ChainSyncRequest", &6u32);
+            dump.push("payload.request.from_height (u64)", &request.from_height);
+            dump.push("payload.request.known_height (u64)", &request.known_height);
+        }
+        MessagePayload::Chain(chain) => {
+            dump.push("payload.variant (u32) = Chain", &7u32);
+            dump.push("payload.chain.blocks.len (u64)", &(chain.blocks.len() as u64));
+            for (i, signed_block) in chain.blocks.iter().enumerate() {
+                push_block(&mut dump, &format!("payload.chain.blocks[{}].block.", i), &signed_block.block);
+                push_cert(&mut dump, &format!("payload.chain.blocks[{}].cert.", i), &signed_block.cert);
+            }
+        }
+        MessagePayload::EntryId(id) => {
+            dump.push("payload.variant (u32) = EntryId", &8u32);
+            dump.push("payload.entry_id (u128)", id);
+        }
+        MessagePayload::Sealed(envelope) => {
+            dump.push("payload.variant (u32) = Sealed", &9u32);
+            dump.push("payload.sealed.key_id ([u8; 8])", &envelope.key_id);
+            dump.push("payload.sealed.nonce ([u8; 12])", &envelope.nonce);
+            dump.push("payload.sealed.ciphertext (len u64 + bytes)", &envelope.ciphertext);
+        }
+        MessagePayload::Promise(promise) => {
+            dump.push("payload.variant (u32) = Promise", &10u32);
+            dump.push("payload.promise", promise);
+        }
+        MessagePayload::Inclusion(inclusion) => {
+            dump.push("payload.variant (u32) = Inclusion", &11u32);
+            dump.push("payload.inclusion", inclusion);
+        }
+        MessagePayload::TreeHead(update) => {
+            dump.push("payload.variant (u32) = TreeHead", &12u32);
+            dump.push("payload.tree_head", update);
+        }
+        MessagePayload::Submit(entry) => {
+            dump.push("payload.variant (u32) = Submit", &13u32);
+            dump.push("payload.entry", entry);
+        }
+        MessagePayload::Snapshot(snapshot) => {
+            dump.push("payload.variant (u32) = Snapshot", &14u32);
+            dump.push("payload.snapshot.chain.blocks.len (u64)", &(snapshot.chain.blocks.len() as u64));
+            for (i, signed_block) in snapshot.chain.blocks.iter().enumerate() {
+                push_block(&mut dump, &format!("payload.snapshot.chain.blocks[{}].block.", i), &signed_block.block);
+                push_cert(&mut dump, &format!("payload.snapshot.chain.blocks[{}].cert.", i), &signed_block.cert);
+            }
+            push_block(&mut dump, "payload.snapshot.finality.block.", &snapshot.finality.block);
+            push_cert(&mut dump, "payload.snapshot.finality.cert.", &snapshot.finality.cert);
+            dump.push("payload.snapshot.tree_size (u64)", &snapshot.tree_size);
+            dump.push("payload.snapshot.root_hash ([u8; 32])", &snapshot.root_hash);
+        }
+        MessagePayload::EpochSync(position) => {
+            dump.push("payload.variant (u32) = EpochSync", &15u32);
+            dump.push("payload.position.epoch (u64)", &position.epoch);
+            dump.push("payload.position.elapsed_ms (u64)", &position.elapsed_ms);
+            dump.push("payload.position.sent_ms (u64)", &position.sent_ms);
+            dump.push("payload.position.wants_reply (bool)", &position.wants_reply);
+        }
+    }
+    dump
+}
+
+pub fn dump_envelope(envelope: &SignedEnvelope) -> WireDump {
+    let mut dump = WireDump::new();
+    dump.push("magic ([u8; 4]) = SLE1", &ENVELOPE_MAGIC);
+    dump.push("sender (len u64 + utf8)", &envelope.sender);
+    dump.push("public_key (u32 scheme + key bytes)", &envelope.public_key);
+    dump.push("epoch (u64)", &envelope.epoch);
+    dump.push("signature (u32 scheme + signature bytes)", &envelope.signature);
+    dump.append("message.", dump_message(&envelope.message));
+    dump
+}
+
+/* What an envelope's signature is over: rebuild these bytes from the envelope and check
+the signature on them under its public key.
+@param chain_id: the deployment's chain id */
+pub fn dump_envelope_signed_input(envelope: &SignedEnvelope, chain_id: &ChainId) -> WireDump {
+    let message = &envelope.message;
+    let mut dump = WireDump::new();
+    dump.push("chain_id ([u8; 32])", chain_id);
+    dump.push("context (len u64 + bytes) = \"streamlet envelope v1\"", ENVELOPE_CONTEXT);
+    dump.push("message.payload (u32 variant + contents)", &message.payload);
+    dump.push(&format!("message.kind (u32) = {:?}", message.kind), &message.kind);
+    dump.push("message.nonce (u32)", &message.nonce);
+    dump.push("message.tag (u32)", &message.tag);
+    dump.push("message.sender_name (len u64 + utf8)", &message.sender_name);
+    dump.push("sender (len u64 + utf8)", &envelope.sender);
+    dump.push("epoch (u64)", &envelope.epoch);
+    dump
+}
+
+/* Annotated framing of a message as sent on the network (see network::codec). The
+message itself isn't taken apart: decode_frame it and dump that.
+@param frame: the frame, as received */
+pub fn dump_frame(frame: &[u8]) -> Result<WireDump, CodecError> {
+    let (id, body) = frame.split_first().ok_or(CodecError::Empty)?;
+    let codec = Codec::from_id(*id)?;
+    let mut dump = WireDump::new();
+    dump.push(&format!("codec (u8) = {}", codec), id);
+    if codec == Codec::None {
+        dump.push_raw("message (as it is)", body);
+        return Ok(dump);
+    }
+    if body.len() < 4 {
+        return Err(CodecError::Corrupt);
+    }
+    dump.push_raw("message.len (u32)", &body[..4]);
+    dump.push_raw(&format!("message ({} compressed)", codec), &body[4..]);
+    Ok(dump)
+}
+
+/* Annotated chunk of a frame too large for one gossip message (see network::chunking).
+@param chunk: the chunk, as received */
+pub fn dump_chunk(chunk: &[u8]) -> Result<WireDump, ChunkError> {
+    if !is_chunk(chunk) {
+        return Err(ChunkError::Malformed);
+    }
+    let (_, transfer, index, count): (u8, u64, u32, u32) = deserialize(chunk).map_err(|_| ChunkError::Malformed)?;
+    let mut dump = WireDump::new();
+    dump.push("flag (u8) = chunk", &CHUNK_FLAG);
+    dump.push("transfer (u64)", &transfer);
+    dump.push("index (u32)", &index);
+    dump.push("count (u32)", &count);
+    dump.push_raw("part of the frame", &chunk[dump.len()..]);
+    Ok(dump)
+}
+
+/* Ed25519 keypair derived from a constant seed. */
+fn vector_keypair() -> Keypair {
+    let secret = Ed25519SecretKey::from_bytes(&[7u8; 32]).expect("constant secret key");
+    let public = (&secret).into();
+    Keypair::from(Ed25519Keypair { secret, public })
+}
+
+/* Fixed inputs whose encodings other implementations can check themselves against.
+   Keys are derived from constant seeds, so signatures are reproducible (ed25519 is deterministic). */
+pub fn test_vectors() -> Vec<(&'static str, WireDump)> {
+    let keypair = vector_keypair();
+
+    let block = Block::new(3, [1u8; 32], b"entry".to_vec(), 1, 42);
+    let signature = keypair.sign(&MessagePayload::Block(block.clone()).serialize());
+
+    let signed_block = SignedBlock::new(block.clone(), vec![signature]);
+    let message = Message {
+        payload: MessagePayload::Block(block.clone()),
+        kind: MessageKind::Vote,
+        nonce: 1,
+        tag: 2,
+        sender_id: 3,
+        sender_name: String::from("h1"),
+        signatures: vec![signature],
+    };
+    let envelope = SignedEnvelope::seal(message.clone(), "h2", 3, &keypair, &ChainId::default());
+    let large = Block::new(3, [1u8; 32], b"entry ".repeat(200), 1, 42);
+    let proposal = Message { payload: MessagePayload::Block(large), kind: MessageKind::Propose, ..message.clone() };
+    let frame = |message: Vec<u8>| dump_frame(&Codec::Lz4.encode_frame(&message).expect("lz4 is always supported")).expect("just framed");
+
+    vec![
+        ("block", dump_block(&block)),
+        ("signed_block", dump_signed_block(&signed_block)),
+        ("vote_message", dump_message(&message)),
+        ("vote_envelope", dump_envelope(&envelope)),
+        ("vote_envelope_signed_input (default chain id)", dump_envelope_signed_input(&envelope, &ChainId::default())),
+        ("vote_envelope_frame (too small to compress)", frame(envelope.serialize())),
+        ("large_proposal_frame (lz4)", frame(proposal.serialize())),
+        ("chunk (second of two)", dump_chunk(&chunk(7, 1, 2, b"end of a frame")).expect("just made")),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_matches_wire_encoding() {
+        let keypair = vector_keypair();
+        let block = Block::generate_test_block(b"test".to_vec());
+        let mut message = Message::new(
+            MessagePayload::Block(block.clone()),
+            MessageKind::Propose,
+            1,
+            String::from("test"),
+        );
+        message.sign_message(keypair.sign(&message.signed_bytes(&ChainId::default())));
+
+        assert_eq!(dump_block(&block).bytes(), serialize(&block).unwrap());
+        assert_eq!(dump_message(&message).bytes(), message.serialize());
+
+        let other = Message::new(MessagePayload::String(String::from("hi")), MessageKind::RosterNotice, 1, String::from("test"));
+        assert_eq!(dump_message(&other).bytes(), other.serialize());
+    }
+
+    #[test]
+    fn test_envelope_signed_input_verifies() {
+        let keypair = vector_keypair();
+        let chain_id = ChainId::new("test");
+        let message = Message::new(MessagePayload::String(String::from("hi")), MessageKind::Vote, 1, String::from("h1"));
+        let envelope = SignedEnvelope::seal(message, "h2", 4, &keypair, &chain_id);
+        assert_eq!(dump_envelope(&envelope).bytes(), envelope.serialize());
+
+        // Rebuilt as documented, the input checks out under the envelope's key
+        let input = dump_envelope_signed_input(&envelope, &chain_id).bytes();
+        assert!(envelope.public_key.verify(&input, &envelope.signature).is_ok());
+        let elsewhere = dump_envelope_signed_input(&envelope, &ChainId::default()).bytes();
+        assert!(envelope.public_key.verify(&elsewhere, &envelope.signature).is_err());
+    }
+
+    #[test]
+    fn test_frames_are_annotated() {
+        let vectors = test_vectors();
+        let (_, small) = &vectors[5];
+        assert_eq!(small.fields[0].bytes, [Codec::None.id()]);
+        assert_eq!(small.fields[1].bytes, vectors[3].1.bytes());
+        let (_, large) = &vectors[6];
+        assert_eq!(large.fields[0].bytes, [Codec::Lz4.id()]);
+        let message = crate::network::codec::decode_frame(&large.bytes()).unwrap();
+        assert_eq!(large.fields[1].bytes, (message.len() as u32).to_le_bytes());
+        assert!(Message::decode(&message).is_ok());
+
+        assert_eq!(dump_frame(b""), Err(CodecError::Empty));
+        assert_eq!(dump_frame(b"\x07x"), Err(CodecError::UnknownCodec(7)));
+        assert_eq!(dump_frame(b"\x02xy"), Err(CodecError::Corrupt));
+    }
+
+    #[test]
+    fn test_chunks_are_annotated() {
+        use crate::network::chunking::{split, MAX_CHUNK_LEN};
+        let frame: Vec<u8> = (0..2 * MAX_CHUNK_LEN + 10).map(|i| i as u8).collect();
+        let mut joined: Vec<u8> = Vec::new();
+        for (index, chunk) in split(frame.clone()).unwrap().iter().enumerate() {
+            let dump = dump_chunk(chunk).unwrap();
+            assert_eq!(dump.bytes(), *chunk);
+            assert_eq!(dump.fields[2].bytes, (index as u32).to_le_bytes());
+            assert_eq!(dump.fields[3].bytes, 3u32.to_le_bytes());
+            joined.extend_from_slice(&dump.fields[4].bytes);
+        }
+        assert_eq!(joined, frame);
+        assert_eq!(dump_chunk(&frame[..20]), Err(ChunkError::Malformed));
+        assert_eq!(dump_chunk(&[CHUNK_FLAG, 1, 2]), Err(ChunkError::Malformed));
+    }
+
+    #[test]
+    fn test_vectors_are_stable() {
+        let vectors = test_vectors();
+        let (_, block) = &vectors[0];
+        assert_eq!(block.len(), 8 + 32 + 32 + (8 + 5) + 8 + 8);
+        assert_eq!(
+            hex::encode(block.bytes()),
+            "0300000000000000\
+             28887efef7b9799c6b72b62cd7915d3d79641470cfe78eb372d95c95f578df94\
+             0101010101010101010101010101010101010101010101010101010101010101\
+             0500000000000000656e747279\
+             0100000000000000\
+             2a00000000000000"
+        );
+
+        // The hash, over the layout documented above
+        let mut input = [1u8; 32].to_vec();
+        input.extend(hex::decode("0300000000000000").unwrap());
+        input.extend(b"entry");
+        input.extend(hex::decode("2a00000000000000").unwrap());
+        let hash: Sha256Hash = Sha256::digest(&input).into();
+        assert_eq!(hex::encode(hash), "28887efef7b9799c6b72b62cd7915d3d79641470cfe78eb372d95c95f578df94");
+    }
+}
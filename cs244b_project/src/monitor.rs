@@ -0,0 +1,220 @@
+/* Monitor: the auditing half of the transparency log.
+   Watches the tree heads nodes publish on the STH topic and raises alerts when one
+   misbehaves:
+   - BadSignature: the head's signature doesn't check out
+   - KeyChanged: a node signs with a different key than before (keys are pinned on first
+     sight, so start the monitor before anything suspicious can happen)
+   - Rollback: a node's tree shrank, or its timestamp went backwards
+   - Inconsistent: a node's new head isn't an extension of an earlier one
+   - SplitView: two heads of the same size have different roots, i.e. someone is showing
+     different logs to different parties (all nodes finalize the same log, so their heads
+     must agree)
+   - Gap: a consistency proof starts from a size the monitor has no root for, so the step
+     couldn't be checked (a warning; the monitor may simply have missed some heads) */
+
+use crate::blockchain::{verify_consistency, ChainId, SignedTreeHead, TreeHeadUpdate};
+use crate::utils::crypto::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+pub const STH_TOPIC: &str = "sth";
+
+// Roots remembered per tree size, for split-view checks and proof starting points
+const MAX_KNOWN_ROOTS: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    BadSignature { signer: String },
+    KeyChanged { signer: String },
+    Rollback { signer: String, previous_size: u64, new_size: u64 },
+    Inconsistent { signer: String, old_size: u64, new_size: u64 },
+    SplitView { tree_size: u64, signers: (String, String) },
+    Gap { signer: String, from_size: u64 },
+}
+
+impl Alert {
+    /* Whether the alert is evidence of misbehavior (as opposed to a gap in what we saw). */
+    pub fn is_misbehavior(&self) -> bool {
+        !matches!(self, Alert::Gap { .. })
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Alert::BadSignature { signer } => write!(f, "{} published a tree head with a bad signature", signer),
+            Alert::KeyChanged { signer } => write!(f, "{} signed with a different key than before", signer),
+            Alert::Rollback { signer, previous_size, new_size } => {
+                write!(f, "{} rolled back from tree size {} to {}", signer, previous_size, new_size)
+            }
+            Alert::Inconsistent { signer, old_size, new_size } => {
+                write!(f, "{}'s tree of size {} does not extend the one of size {}", signer, new_size, old_size)
+            }
+            Alert::SplitView { tree_size, signers } => write!(
+                f,
+                "split view: {} and {} have different roots at tree size {}",
+                signers.0, signers.1, tree_size
+            ),
+            Alert::Gap { signer, from_size } => {
+                write!(f, "can't check {}'s new head: no known root at tree size {}", signer, from_size)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    chain_id: ChainId,
+    keys: HashMap<String, PublicKey>,
+    heads: HashMap<String, SignedTreeHead>, // latest accepted head per node
+    roots: BTreeMap<u64, (Sha256Hash, String)>, // tree size -> (root, who first published it)
+}
+
+impl Monitor {
+    /* @param chain_id: the chain id of the deployment being watched */
+    pub fn new(chain_id: ChainId) -> Self {
+        Self { chain_id, ..Self::default() }
+    }
+
+    /* Latest accepted tree head of every node seen so far. */
+    pub fn heads(&self) -> &HashMap<String, SignedTreeHead> {
+        &self.heads
+    }
+
+    /* Checks a published tree head; returns any alerts. Heads that fail a check are
+    not accepted as the node's latest.
+    @param update: a tree head update from the STH topic */
+    pub fn observe(&mut self, update: &TreeHeadUpdate) -> Vec<Alert> {
+        let sth = &update.sth;
+        let signer = sth.signer.clone();
+        match self.keys.get(&signer) {
+            Some(key) if *key != update.public_key => return vec![Alert::KeyChanged { signer }],
+            Some(_) => {}
+            None => {
+                self.keys.insert(signer.clone(), update.public_key);
+            }
+        }
+        if !sth.verify(&update.public_key, &self.chain_id) {
+            return vec![Alert::BadSignature { signer }];
+        }
+
+        let mut alerts = Vec::new();
+        if let Some(previous) = self.heads.get(&signer) {
+            if sth.tree_size < previous.tree_size || sth.timestamp_ms < previous.timestamp_ms {
+                return vec![Alert::Rollback { signer, previous_size: previous.tree_size, new_size: sth.tree_size }];
+            }
+        }
+
+        // The new head must extend what we already know at the proof's starting size
+        let proof = &update.consistency;
+        let old_root = self.roots.get(&proof.old_size).map(|(root, _)| *root);
+        let consistent = proof.new_size == sth.tree_size
+            && match old_root {
+                Some(old_root) => verify_consistency(proof, &old_root, &sth.root_hash),
+                // Every tree extends the empty one
+                None if proof.old_size == 0 => proof.path.is_empty(),
+                None => {
+                    alerts.push(Alert::Gap { signer: signer.clone(), from_size: proof.old_size });
+                    true
+                }
+            };
+        if !consistent {
+            return vec![Alert::Inconsistent { signer, old_size: proof.old_size, new_size: sth.tree_size }];
+        }
+
+        match self.roots.get(&sth.tree_size) {
+            Some((root, first_signer)) if *root != sth.root_hash => {
+                alerts.push(Alert::SplitView { tree_size: sth.tree_size, signers: (first_signer.clone(), signer) });
+                return alerts;
+            }
+            Some(_) => {}
+            None => {
+                self.roots.insert(sth.tree_size, (sth.root_hash, signer.clone()));
+                while self.roots.len() > MAX_KNOWN_ROOTS {
+                    let oldest = *self.roots.keys().next().expect("non-empty");
+                    self.roots.remove(&oldest);
+                }
+            }
+        }
+        self.heads.insert(signer, sth.clone());
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::MerkleTree;
+
+    // Publishes tree heads the way a node does: each with a proof from its previous one
+    struct Node {
+        name: String,
+        keypair: Keypair,
+        published: u64,
+    }
+
+    impl Node {
+        fn new(name: &str) -> Self {
+            Self { name: name.to_string(), keypair: Keypair::generate(SignatureScheme::default()), published: 0 }
+        }
+
+        fn publish(&mut self, tree: &MerkleTree) -> TreeHeadUpdate {
+            let update = TreeHeadUpdate {
+                sth: SignedTreeHead::sign(tree, self.name.clone(), &self.keypair, &ChainId::default()),
+                public_key: self.keypair.public(),
+                consistency: tree.prove_consistency(self.published.min(tree.size()), tree.size()).unwrap(),
+            };
+            self.published = tree.size();
+            update
+        }
+    }
+
+    fn tree_of(entries: &[&[u8]]) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for entry in entries {
+            tree.push(entry);
+        }
+        tree
+    }
+
+    #[test]
+    fn test_honest_nodes_raise_no_alerts() {
+        let mut monitor = Monitor::new(ChainId::default());
+        let (mut h1, mut h2) = (Node::new("h1"), Node::new("h2"));
+        assert!(monitor.observe(&h1.publish(&tree_of(&[b"a"]))).is_empty());
+        assert!(monitor.observe(&h2.publish(&tree_of(&[b"a", b"b"]))).is_empty());
+        assert!(monitor.observe(&h1.publish(&tree_of(&[b"a", b"b", b"c"]))).is_empty());
+        assert_eq!(monitor.heads()["h1"].tree_size, 3);
+    }
+
+    #[test]
+    fn test_detects_misbehavior() {
+        let mut monitor = Monitor::new(ChainId::default());
+        let mut h1 = Node::new("h1");
+        let mut h2 = Node::new("h2");
+        monitor.observe(&h1.publish(&tree_of(&[b"a", b"b"])));
+
+        // h2 shows a different log of the same size
+        let alerts = monitor.observe(&h2.publish(&tree_of(&[b"a", b"x"])));
+        assert!(matches!(&alerts[..], [Alert::SplitView { tree_size: 2, .. }]));
+
+        // h1 rewrites its history
+        let alerts = monitor.observe(&h1.publish(&tree_of(&[b"a", b"y", b"c"])));
+        assert!(matches!(&alerts[..], [Alert::Inconsistent { old_size: 2, new_size: 3, .. }]));
+
+        // h1 rolls back
+        let alerts = monitor.observe(&h1.publish(&tree_of(&[b"a"])));
+        assert!(matches!(&alerts[..], [Alert::Rollback { previous_size: 2, new_size: 1, .. }]));
+
+        // Someone else signs as h1
+        let mut impostor = Node::new("h1");
+        impostor.published = 2;
+        let alerts = monitor.observe(&impostor.publish(&tree_of(&[b"a", b"b", b"c"])));
+        assert_eq!(alerts, vec![Alert::KeyChanged { signer: String::from("h1") }]);
+
+        let mut tampered = h1.publish(&tree_of(&[b"a", b"b", b"c", b"d"]));
+        tampered.sth.tree_size = 5;
+        assert_eq!(monitor.observe(&tampered), vec![Alert::BadSignature { signer: String::from("h1") }]);
+        assert!(alerts.iter().all(Alert::is_misbehavior));
+    }
+}
@@ -0,0 +1,231 @@
+/* Chunked transfer for gossip messages larger than gossipsub carries.
+   Gossipsub refuses to publish (and peers refuse to read) anything over
+   MAX_TRANSMIT_SIZE. A framed message bigger than MAX_CHUNK_LEN (a block with large
+   entries, a chain snapshot flooded over gossip) is split into chunks, published one by
+   one, and put back together by each receiver before it is decoded. Direct requests and
+   responses don't need this: each goes over a stream of its own (see direct).
+   A chunk is:
+     u8   CHUNK_FLAG (not a codec id, so a chunk can't pass for a whole frame)
+     u64  transfer id (random, picked by the sender)
+     u32  index of this chunk
+     u32  number of chunks in the transfer
+     ...  the chunk's bytes
+   Receivers keep partial transfers by sender, so chunks can arrive in any order and
+   interleaved with other traffic. A transfer that isn't complete within
+   TRANSFER_TIMEOUT is dropped. No message may be over MAX_MESSAGE_LEN, a peer can have
+   at most MAX_TRANSFERS_PER_PEER transfers in progress, and all of them together at most
+   MAX_PENDING_BYTES; the oldest go first. */
+
+use libp2p::PeerId;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::codec::MAX_FRAME_LEN;
+
+// Largest gossipsub message, envelope included (gossipsub's own default)
+pub const MAX_TRANSMIT_SIZE: usize = 65536;
+// Largest frame sent whole; room is left for the chunk header and gossipsub's envelope
+pub const MAX_CHUNK_LEN: usize = MAX_TRANSMIT_SIZE - 1024;
+// Largest message sent or reassembled (a frame of the largest decompressed size)
+pub const MAX_MESSAGE_LEN: usize = MAX_FRAME_LEN + 1;
+pub const CHUNK_FLAG: u8 = 0xc0;
+const HEADER_LEN: usize = 1 + 8 + 4 + 4;
+const MAX_CHUNKS: usize = MAX_MESSAGE_LEN.div_ceil(MAX_CHUNK_LEN);
+
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_TRANSFERS_PER_PEER: usize = 4;
+const MAX_PENDING_BYTES: usize = 4 * MAX_MESSAGE_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    Malformed,
+    TooLarge(usize), // bytes
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::Malformed => write!(f, "malformed chunk"),
+            ChunkError::TooLarge(len) => write!(f, "message of {} bytes is over the {} byte limit", len, MAX_MESSAGE_LEN),
+        }
+    }
+}
+
+/* Whether a gossip message is a chunk rather than a whole frame. */
+pub fn is_chunk(message: &[u8]) -> bool {
+    message.first() == Some(&CHUNK_FLAG)
+}
+
+/* Splits a frame into the messages to publish: the frame itself if it fits in one, else
+its chunks.
+@param frame: the framed message (see codec) */
+pub fn split(frame: Vec<u8>) -> Result<Vec<Vec<u8>>, ChunkError> {
+    if frame.len() > MAX_MESSAGE_LEN {
+        return Err(ChunkError::TooLarge(frame.len()));
+    }
+    if frame.len() <= MAX_CHUNK_LEN {
+        return Ok(vec![frame]);
+    }
+    let transfer: u64 = rand::thread_rng().gen();
+    let count = frame.len().div_ceil(MAX_CHUNK_LEN);
+    let chunks = frame.chunks(MAX_CHUNK_LEN).enumerate().map(|(index, bytes)| chunk(transfer, index, count, bytes)).collect();
+    Ok(chunks)
+}
+
+/* One chunk of a transfer, laid out as above.
+@param transfer: the transfer id
+@param index, count: the chunk's index, and the number of chunks
+@param bytes: its part of the frame */
+pub fn chunk(transfer: u64, index: usize, count: usize, bytes: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(HEADER_LEN + bytes.len());
+    chunk.push(CHUNK_FLAG);
+    chunk.extend(transfer.to_le_bytes());
+    chunk.extend((index as u32).to_le_bytes());
+    chunk.extend((count as u32).to_le_bytes());
+    chunk.extend_from_slice(bytes);
+    chunk
+}
+
+#[derive(Debug)]
+struct Transfer {
+    started: Instant,
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    bytes: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    transfers: HashMap<(PeerId, u64), Transfer>,
+    pending_bytes: usize,
+}
+
+impl Reassembler {
+    /* Takes a chunk in. Returns the whole frame once its last chunk is in, None until
+    then, or why the chunk is bad.
+    @param from: the peer answerable for the chunk (its author)
+    @param chunk: the chunk, as received
+    @param now: the current time */
+    pub fn add(&mut self, from: PeerId, chunk: &[u8], now: Instant) -> Result<Option<Vec<u8>>, ChunkError> {
+        if chunk.len() < HEADER_LEN || !is_chunk(chunk) {
+            return Err(ChunkError::Malformed);
+        }
+        let transfer = u64::from_le_bytes(chunk[1..9].try_into().expect("8 bytes"));
+        let index = u32::from_le_bytes(chunk[9..13].try_into().expect("4 bytes")) as usize;
+        let count = u32::from_le_bytes(chunk[13..17].try_into().expect("4 bytes")) as usize;
+        let bytes = &chunk[HEADER_LEN..];
+        if !(2..=MAX_CHUNKS).contains(&count) || index >= count || bytes.is_empty() || bytes.len() > MAX_CHUNK_LEN {
+            return Err(ChunkError::Malformed);
+        }
+        self.expire(now);
+
+        let key = (from, transfer);
+        if !self.transfers.contains_key(&key) {
+            self.make_room(from);
+            let transfer = Transfer { started: now, chunks: vec![None; count], missing: count, bytes: 0 };
+            self.transfers.insert(key, transfer);
+        }
+        let transfer = self.transfers.get_mut(&key).expect("inserted above");
+        if transfer.chunks.len() != count {
+            return Err(ChunkError::Malformed);
+        }
+        if transfer.chunks[index].is_some() {
+            return Ok(None);
+        }
+        if transfer.bytes + bytes.len() > MAX_MESSAGE_LEN {
+            let len = transfer.bytes + bytes.len();
+            self.drop_transfer(&key);
+            return Err(ChunkError::TooLarge(len));
+        }
+        transfer.chunks[index] = Some(bytes.to_vec());
+        transfer.missing -= 1;
+        transfer.bytes += bytes.len();
+        self.pending_bytes += bytes.len();
+        if transfer.missing > 0 {
+            self.evict_over_budget();
+            return Ok(None);
+        }
+        let transfer = self.drop_transfer(&key).expect("still pending");
+        Ok(Some(transfer.chunks.into_iter().flatten().flatten().collect()))
+    }
+
+    fn drop_transfer(&mut self, key: &(PeerId, u64)) -> Option<Transfer> {
+        let transfer = self.transfers.remove(key)?;
+        self.pending_bytes -= transfer.bytes;
+        Some(transfer)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<(PeerId, u64)> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| now.duration_since(transfer.started) > TRANSFER_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.drop_transfer(&key);
+        }
+    }
+
+    // A new transfer from this peer is starting: drops its oldest if it has too many
+    fn make_room(&mut self, from: PeerId) {
+        let ours = self.transfers.iter().filter(|((peer, _), _)| *peer == from);
+        if ours.clone().count() >= MAX_TRANSFERS_PER_PEER {
+            let oldest = ours.min_by_key(|(_, transfer)| transfer.started).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.drop_transfer(&oldest);
+            }
+        }
+    }
+
+    fn evict_over_budget(&mut self) {
+        while self.pending_bytes > MAX_PENDING_BYTES {
+            let oldest = self.transfers.iter().min_by_key(|(_, transfer)| transfer.started).map(|(key, _)| *key);
+            match oldest {
+                Some(oldest) => self.drop_transfer(&oldest),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_reassemble_in_any_order() {
+        let frame: Vec<u8> = (0..3 * MAX_CHUNK_LEN + 10).map(|i| i as u8).collect();
+        assert_eq!(split(vec![0, 1, 2]).unwrap(), vec![vec![0, 1, 2]]);
+        let mut chunks = split(frame.clone()).unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| is_chunk(chunk) && chunk.len() <= MAX_CHUNK_LEN + HEADER_LEN));
+
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        chunks.reverse();
+        let last = chunks.pop().unwrap();
+        for chunk in &chunks {
+            assert_eq!(reassembler.add(alice, chunk, now), Ok(None));
+            // Another peer's copy is a transfer of its own
+            assert_eq!(reassembler.add(bob, chunk, now), Ok(None));
+        }
+        assert_eq!(reassembler.add(alice, &chunks[0], now), Ok(None));
+        assert_eq!(reassembler.add(alice, &last, now), Ok(Some(frame.clone())));
+        assert_eq!(reassembler.transfers.len(), 1);
+
+        // Stale transfers are dropped
+        let later = now + TRANSFER_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(reassembler.add(alice, &split(frame.clone()).unwrap()[0], later), Ok(None));
+        assert_eq!(reassembler.transfers.len(), 1);
+
+        let mut bad = last.clone();
+        bad[9..13].copy_from_slice(&9u32.to_le_bytes());
+        assert_eq!(reassembler.add(alice, &bad, later), Err(ChunkError::Malformed));
+        assert_eq!(reassembler.add(alice, &last[..HEADER_LEN], later), Err(ChunkError::Malformed));
+        assert_eq!(split(vec![0; MAX_MESSAGE_LEN + 1]), Err(ChunkError::TooLarge(MAX_MESSAGE_LEN + 1)));
+    }
+}
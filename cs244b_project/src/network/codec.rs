@@ -0,0 +1,347 @@
+/* Payload compression for gossip messages.
+   Every message on the wire is framed as one flag byte naming the codec, then the body
+   encoded with it. The sending codec is a deployment-wide choice (like the signature
+   scheme): "none" spends no CPU, "lz4" trades a little CPU for less bandwidth, and
+   "zstd" more CPU for less still. A receiver decodes whatever codec the flag byte names,
+   so nodes, apps and monitors interoperate whichever codec each one sends with.
+   Only messages of COMPRESS_MIN_LEN bytes or more are compressed: chain-sync responses,
+   blocks with large entries, and the like. Votes and other small messages are sent as
+   "none", as are messages that wouldn't shrink.
+   Registered codecs:
+     0 none   the bytes as they are
+     1 zstd   a zstd frame, prefixed with the decompressed length (u32, little-endian).
+              Only builds with the "zstd" feature can send or receive it (decoding one
+              otherwise reports CodecError::Unsupported)
+     2 lz4    LZ4 block format, prefixed with the decompressed length (u32, little-endian)
+   Flag byte 0xc0 is taken by chunking (see chunking), which splits frames too large for
+   one gossip message rather than encoding them. */
+
+use std::fmt;
+use std::str::FromStr;
+
+// Largest decompressed frame we accept (guards against decompression bombs)
+pub const MAX_FRAME_LEN: usize = 16 << 20;
+// Smallest message worth compressing; smaller ones are sent as they are
+pub const COMPRESS_MIN_LEN: usize = 1024;
+// zstd's default level: most of its ratio at a fraction of the top levels' CPU
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    Empty,
+    UnknownCodec(u8),
+    Unsupported(Codec),
+    Corrupt,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Empty => write!(f, "empty frame"),
+            CodecError::UnknownCodec(id) => write!(f, "unknown codec id {}", id),
+            CodecError::Unsupported(codec) => write!(f, "codec {} is not supported by this build", codec),
+            CodecError::Corrupt => write!(f, "corrupt compressed data"),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(format!("unknown codec: {}", s)),
+        }
+    }
+}
+
+impl Codec {
+    /* The codec's flag byte. */
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CodecError> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Lz4),
+            _ => Err(CodecError::UnknownCodec(id)),
+        }
+    }
+
+    /* Whether this build can encode and decode the codec. */
+    pub fn is_supported(&self) -> bool {
+        !matches!(self, Codec::Zstd) || cfg!(feature = "zstd")
+    }
+
+    /* Frames bytes for the wire: the flag byte, then the encoded body. Falls back to
+    "none" if the message is too small to bother, or the codec doesn't make it smaller. */
+    pub fn encode_frame(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if !self.is_supported() {
+            return Err(CodecError::Unsupported(*self));
+        }
+        let compressed = match self {
+            _ if bytes.len() < COMPRESS_MIN_LEN => None,
+            Codec::None => None,
+            Codec::Zstd => Some(zstd_compress(bytes)?),
+            Codec::Lz4 => Some(lz4_compress(bytes)),
+        };
+        let body = compressed.map(|compressed| {
+            let mut body = (bytes.len() as u32).to_le_bytes().to_vec();
+            body.extend(compressed);
+            body
+        });
+        let body = body.filter(|body| body.len() < bytes.len());
+        let (codec, body) = match &body {
+            Some(body) => (*self, body.as_slice()),
+            None => (Codec::None, bytes),
+        };
+        let mut frame = Vec::with_capacity(body.len() + 1);
+        frame.push(codec.id());
+        frame.extend_from_slice(body);
+        Ok(frame)
+    }
+}
+
+/* Reverses encode_frame, whichever codec the frame was sent with. */
+pub fn decode_frame(frame: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let (id, body) = frame.split_first().ok_or(CodecError::Empty)?;
+    let codec = Codec::from_id(*id)?;
+    if codec == Codec::None {
+        return Ok(body.to_vec());
+    }
+    if !codec.is_supported() {
+        return Err(CodecError::Unsupported(codec));
+    }
+    if body.len() < 4 {
+        return Err(CodecError::Corrupt);
+    }
+    let len = u32::from_le_bytes(body[..4].try_into().expect("4 bytes")) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(CodecError::Corrupt);
+    }
+    match codec {
+        Codec::Zstd => zstd_decompress(&body[4..], len),
+        _ => lz4_decompress(&body[4..], len),
+    }
+}
+
+// ---- zstd (only with the "zstd" feature; is_supported is checked before these) ----
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(input: &[u8]) -> Result<Vec<u8>, CodecError> {
+    zstd::bulk::compress(input, ZSTD_LEVEL).map_err(|_| CodecError::Corrupt)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, CodecError> {
+    // Fails if the frame decompresses to more than len bytes
+    let out = zstd::bulk::decompress(input, len).map_err(|_| CodecError::Corrupt)?;
+    if out.len() != len {
+        return Err(CodecError::Corrupt);
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unsupported(Codec::Zstd))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &[u8], _: usize) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unsupported(Codec::Zstd))
+}
+
+// ---- LZ4 block format (https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md) ----
+
+const MIN_MATCH: usize = 4;
+// The last 5 bytes are always literals, and the last match starts 12 bytes before the end
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_LOG: u32 = 12;
+
+fn write_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+// One sequence: literals, then (unless it is the last one) a match (offset, length)
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_code = found.map(|(_, len)| len - MIN_MATCH).unwrap_or(0);
+    out.push(((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_code >= 15 {
+            write_length(out, match_code - 15);
+        }
+    }
+}
+
+// Greedy compressor with a single-entry hash table of 4-byte sequences
+fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_LOG]; // position + 1 (0: empty)
+    let mut anchor = 0;
+    let mut i = 0;
+    while input.len() >= MF_LIMIT && i <= input.len() - MF_LIMIT {
+        let sequence = u32::from_le_bytes(input[i..i + 4].try_into().expect("4 bytes"));
+        let slot = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+        let candidate = table[slot];
+        table[slot] = i + 1;
+        if candidate > 0 {
+            let start = candidate - 1;
+            if i - start <= u16::MAX as usize && input[start..start + MIN_MATCH] == input[i..i + MIN_MATCH] {
+                let max_len = input.len() - LAST_LITERALS - i;
+                let mut len = MIN_MATCH;
+                while len < max_len && input[start + len] == input[i + len] {
+                    len += 1;
+                }
+                write_sequence(&mut out, &input[anchor..i], Some((i - start, len)));
+                i += len;
+                anchor = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], i: &mut usize) -> Result<usize, CodecError> {
+    let mut n = 0usize;
+    loop {
+        let byte = *input.get(*i).ok_or(CodecError::Corrupt)?;
+        *i += 1;
+        n = n.checked_add(byte as usize).ok_or(CodecError::Corrupt)?;
+        if byte != 255 {
+            return Ok(n);
+        }
+    }
+}
+
+fn lz4_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, CodecError> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    loop {
+        let token = *input.get(i).ok_or(CodecError::Corrupt)?;
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(input, &mut i)?;
+        }
+        let end = i.checked_add(literals).filter(|end| *end <= input.len()).ok_or(CodecError::Corrupt)?;
+        if out.len() + literals > len {
+            return Err(CodecError::Corrupt);
+        }
+        out.extend_from_slice(&input[i..end]);
+        i = end;
+        if i == input.len() {
+            break;
+        }
+
+        let offset = input.get(i..i + 2).ok_or(CodecError::Corrupt)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        i += 2;
+        let mut match_len = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            match_len += read_length(input, &mut i)?;
+        }
+        if offset == 0 || offset > out.len() || out.len() + match_len > len {
+            return Err(CodecError::Corrupt);
+        }
+        // Byte by byte: the match may overlap what it is copying
+        let start = out.len() - offset;
+        for k in 0..match_len {
+            out.push(out[start + k]);
+        }
+    }
+    if out.len() != len {
+        return Err(CodecError::Corrupt);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let repetitive: Vec<u8> = b"streamlet vote for block ".iter().cycle().take(5000).cloned().collect();
+        let varied: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(7919) >> 3) as u8).collect();
+        let codecs: Vec<Codec> = [Codec::None, Codec::Lz4, Codec::Zstd].into_iter().filter(Codec::is_supported).collect();
+        for bytes in [repetitive.clone(), varied, b"tiny".to_vec(), Vec::new()] {
+            for codec in &codecs {
+                assert_eq!(decode_frame(&codec.encode_frame(&bytes).unwrap()).unwrap(), bytes);
+            }
+        }
+        for codec in &codecs[1..] {
+            let frame = codec.encode_frame(&repetitive).unwrap();
+            assert_eq!(frame[0], codec.id());
+            assert!(frame.len() < repetitive.len() / 10);
+            // Not worth compressing: sent as is
+            assert_eq!(codec.encode_frame(b"tiny").unwrap(), b"\x00tiny".to_vec());
+            let small = &repetitive[..COMPRESS_MIN_LEN - 1];
+            assert_eq!(codec.encode_frame(small).unwrap()[0], Codec::None.id());
+        }
+
+        if !cfg!(feature = "zstd") {
+            assert_eq!(Codec::Zstd.encode_frame(b"x"), Err(CodecError::Unsupported(Codec::Zstd)));
+            assert_eq!(decode_frame(b"\x01x"), Err(CodecError::Unsupported(Codec::Zstd)));
+        }
+        // A frame claiming fewer bytes than it decompresses to is rejected
+        let mut frame = Codec::Lz4.encode_frame(&repetitive).unwrap();
+        frame[1..5].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(decode_frame(&frame), Err(CodecError::Corrupt));
+        assert_eq!(decode_frame(b"\x07x"), Err(CodecError::UnknownCodec(7)));
+        assert_eq!(decode_frame(b""), Err(CodecError::Empty));
+    }
+
+    #[test]
+    fn test_lz4_block_format() {
+        // Literals "abc", a 16-byte match at offset 3, then 5 final literals
+        let block = b"\x3cabc\x03\x00\x50abcab";
+        let expected = b"abcabcabcabcabcabcaabcab".to_vec();
+        assert_eq!(lz4_decompress(block, expected.len()).unwrap(), expected);
+        // Offsets before the start, truncated input and wrong lengths are rejected
+        assert_eq!(lz4_decompress(b"\x3cabc\x09\x00\x50abcab", 24), Err(CodecError::Corrupt));
+        assert_eq!(lz4_decompress(b"\x3cabc\x03", 24), Err(CodecError::Corrupt));
+        assert_eq!(lz4_decompress(block, 23), Err(CodecError::Corrupt));
+    }
+}
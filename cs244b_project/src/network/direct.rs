@@ -0,0 +1,115 @@
+/* Point-to-point requests between peers (libp2p request-response), for traffic only one
+   peer needs: a node catching up asks a few peers for the blocks it is missing, and the
+   answer (up to megabytes of blocks) goes back to it alone instead of being flooded
+   over gossipsub to the whole mesh.
+   The same channel carries one-way messages to a single peer (unicast), which are
+   acknowledged with an empty response.
+   Requests and responses are framed messages (see codec), each sent with a varint length
+   prefix on its own substream; a request starts with a byte saying which kind it is. */
+
+use async_trait::async_trait;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::RequestResponseCodec;
+use std::io;
+
+// Largest request or response we read (a chain sync response is at most 64 blocks)
+pub const MAX_DIRECT_MESSAGE: usize = 16 * 1024 * 1024;
+
+const ASK: u8 = 0;
+const TELL: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectRequest {
+    Ask(Vec<u8>),  // the peer answers with a message
+    Tell(Vec<u8>), // one-way; the peer only acknowledges it
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectProtocol;
+
+impl ProtocolName for DirectProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/streamlet/direct/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectCodec;
+
+#[async_trait]
+impl RequestResponseCodec for DirectCodec {
+    type Protocol = DirectProtocol;
+    type Request = DirectRequest;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &DirectProtocol, io: &mut T) -> io::Result<DirectRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut kind = [0];
+        io.read_exact(&mut kind).await?;
+        let message = read_length_prefixed(io, MAX_DIRECT_MESSAGE).await?;
+        match kind[0] {
+            ASK => Ok(DirectRequest::Ask(message)),
+            TELL => Ok(DirectRequest::Tell(message)),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown direct request kind {}", other))),
+        }
+    }
+
+    async fn read_response<T>(&mut self, _: &DirectProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, MAX_DIRECT_MESSAGE).await
+    }
+
+    async fn write_request<T>(&mut self, _: &DirectProtocol, io: &mut T, request: DirectRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let (kind, message) = match request {
+            DirectRequest::Ask(message) => (ASK, message),
+            DirectRequest::Tell(message) => (TELL, message),
+        };
+        io.write_all(&[kind]).await?;
+        write_length_prefixed(io, message).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &DirectProtocol, io: &mut T, response: Vec<u8>) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, response).await?;
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+
+    #[test]
+    fn test_codec_round_trip() {
+        let mut codec = DirectCodec;
+        let mut wire = Cursor::new(Vec::new());
+        block_on(codec.write_request(&DirectProtocol, &mut wire, DirectRequest::Ask(b"sync please".to_vec()))).unwrap();
+        let mut wire = Cursor::new(wire.into_inner());
+        assert_eq!(block_on(codec.read_request(&DirectProtocol, &mut wire)).unwrap(), DirectRequest::Ask(b"sync please".to_vec()));
+        let mut wire = Cursor::new(Vec::new());
+        block_on(codec.write_request(&DirectProtocol, &mut wire, DirectRequest::Tell(b"hi".to_vec()))).unwrap();
+        let mut bytes = wire.into_inner();
+        assert_eq!(block_on(codec.read_request(&DirectProtocol, &mut Cursor::new(bytes.clone()))).unwrap(), DirectRequest::Tell(b"hi".to_vec()));
+        bytes[0] = 7;
+        assert!(block_on(codec.read_request(&DirectProtocol, &mut Cursor::new(bytes))).is_err());
+
+        // Oversized frames are refused rather than buffered
+        let mut wire = Cursor::new(Vec::new());
+        block_on(codec.write_response(&DirectProtocol, &mut wire, vec![0; MAX_DIRECT_MESSAGE + 1])).unwrap();
+        let mut wire = Cursor::new(wire.into_inner());
+        assert!(block_on(codec.read_response(&DirectProtocol, &mut wire)).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+/* The channel from the network to the application. It is bounded, so a flood of gossip
+   can't pile up in memory while the application is busy (say, checking signatures).
+   With the channel full, messages are dropped: gossipsub and catch-up recover what
+   matters. Peer and listening events are few and can't be recovered, so they wait
+   instead, and go before anything else once there is room. The network also stops
+   taking in more while the channel is full (see NetworkStack::clear_unhandled_event),
+   so most of the backlog stays with the senders' connections rather than here.
+   Consensus messages can take a lane of their own (see set_priority_lane), so a
+   proposal or vote doesn't wait behind a queue of chain segments and chat; the
+   application reads that lane first (see recv_prioritized). Messages are sorted by
+   the kind in their header (see envelope::peek_kind), before anything is checked, so
+   a peer can send anything down the lane; the application still checks it, and peer
+   scores and verification budgets bound what that costs. A lane that is full spills
+   over into the main channel. */
+
+use log::{error, warn};
+use std::collections::VecDeque;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use super::NetworkEvent;
+use crate::messages::envelope::peek_kind;
+
+// Events the application's channel holds before messages are dropped
+pub const EVENT_QUEUE_CAPACITY: usize = 1024;
+// Dropped messages between warnings
+const DROP_WARNING_INTERVAL: u64 = 100;
+
+pub struct EventQueue {
+    sender: mpsc::Sender<NetworkEvent>,
+    priority: Option<mpsc::Sender<NetworkEvent>>, // consensus messages' lane, if they have one
+    held: VecDeque<NetworkEvent>, // events other than messages that found the channel full, oldest first
+    dropped: u64,                 // messages dropped because the channel was full
+}
+
+impl EventQueue {
+    /* @param sender: the application's channel */
+    pub fn new(sender: mpsc::Sender<NetworkEvent>) -> Self {
+        EventQueue { sender, priority: None, held: VecDeque::new(), dropped: 0 }
+    }
+
+    /* Sends consensus messages down a lane of their own from now on.
+    @param priority: the lane */
+    pub fn set_priority_lane(&mut self, priority: mpsc::Sender<NetworkEvent>) {
+        self.priority = Some(priority);
+    }
+
+    /* Passes an event on, or drops or holds it if the channel is full (see above).
+    @param event: the event */
+    pub fn push(&mut self, event: NetworkEvent) {
+        let event = match (&self.priority, event) {
+            (Some(priority), NetworkEvent::Message(bytes)) if peek_kind(&bytes).is_some_and(|kind| kind.is_consensus()) => {
+                match priority.try_send(NetworkEvent::Message(bytes)) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(event)) => event,
+                    Err(TrySendError::Closed(event)) => {
+                        self.priority = None;
+                        event
+                    }
+                }
+            }
+            (_, event) => event,
+        };
+        self.flush();
+        if !self.held.is_empty() {
+            match event {
+                NetworkEvent::Message(_) => self.count_dropped(),
+                event => self.held.push_back(event),
+            }
+            return;
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(NetworkEvent::Message(_))) => self.count_dropped(),
+            Err(TrySendError::Full(event)) => self.held.push_back(event),
+            Err(TrySendError::Closed(_)) => error!("Error communicating with main application: channel closed"),
+        }
+    }
+
+    /* Sends the held events the channel has room for. */
+    pub fn flush(&mut self) {
+        while let Some(event) = self.held.pop_front() {
+            match self.sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(event)) => {
+                    self.held.push_front(event);
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    self.held.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    /* Waits until the channel has room (or is closed), then sends what was held. */
+    pub async fn wait_for_room(&mut self) {
+        if self.sender.reserve().await.is_ok() {
+            self.flush();
+        }
+    }
+
+    /* Messages dropped so far because the channel was full. */
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /* Counts a message dropped by whoever owns the queue, e.g. because another channel
+    it routes to is full. */
+    pub fn count_dropped(&mut self) {
+        self.dropped += 1;
+        if self.dropped % DROP_WARNING_INTERVAL == 1 {
+            warn!("Application is behind; dropped {} incoming message(s) so far", self.dropped);
+        }
+    }
+}
+
+/* The next event from the network, taking consensus messages first (see
+set_priority_lane); None once the main channel closes.
+@param priority: the consensus messages' lane
+@param events: the main channel */
+pub async fn recv_prioritized(priority: &mut mpsc::Receiver<NetworkEvent>, events: &mut mpsc::Receiver<NetworkEvent>) -> Option<NetworkEvent> {
+    tokio::select! {
+        biased;
+        Some(event) = priority.recv() => Some(event),
+        event = events.recv() => event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Block, Chain, ChainId, LocalChain};
+    use crate::messages::envelope::SignedEnvelope;
+    use crate::messages::{Message, MessageKind, MessagePayload};
+    use crate::network::nat::NatStatus;
+    use crate::utils::crypto::{Keypair, SignatureScheme};
+
+    #[tokio::test]
+    async fn test_full_queue_drops_messages_and_holds_peer_events() {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let mut queue = EventQueue::new(sender);
+        queue.push(NetworkEvent::Message(b"one".to_vec()));
+        queue.push(NetworkEvent::Message(b"two".to_vec()));
+        queue.push(NetworkEvent::Message(b"three".to_vec()));
+        queue.push(NetworkEvent::Nat(NatStatus::Private));
+        // Once something is held, messages don't jump ahead of it
+        queue.push(NetworkEvent::Message(b"four".to_vec()));
+        assert_eq!(queue.dropped(), 2);
+
+        assert!(matches!(receiver.recv().await, Some(NetworkEvent::Message(bytes)) if bytes == b"one"));
+        assert!(matches!(receiver.recv().await, Some(NetworkEvent::Message(bytes)) if bytes == b"two"));
+        queue.wait_for_room().await;
+        queue.push(NetworkEvent::Message(b"five".to_vec()));
+        assert!(matches!(receiver.recv().await, Some(NetworkEvent::Nat(NatStatus::Private))));
+        assert!(matches!(receiver.recv().await, Some(NetworkEvent::Message(bytes)) if bytes == b"five"));
+        assert!(receiver.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_consensus_messages_overtake_bulk_traffic() {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let (priority_sender, mut priority) = mpsc::channel(1);
+        let mut queue = EventQueue::new(sender);
+        queue.set_priority_lane(priority_sender);
+
+        let segment = Message::new(MessagePayload::Chain(LocalChain::new()), MessageKind::SyncResponse, 0, String::from("v2"));
+        let block = Block::new(3, [0; 32], Vec::new(), 1, 0);
+        let vote = Message::new(MessagePayload::Block(block), MessageKind::Vote, 0, String::from("v1"));
+        let sealed = SignedEnvelope::seal(vote, "v1", 3, &Keypair::generate(SignatureScheme::Ed25519), &ChainId::default()).serialize();
+        for _ in 0..3 {
+            queue.push(NetworkEvent::Message(segment.serialize()));
+        }
+        queue.push(NetworkEvent::Message(sealed.clone()));
+        // The lane is full: the next vote spills over behind the segments
+        queue.push(NetworkEvent::Message(sealed.clone()));
+
+        let first = recv_prioritized(&mut priority, &mut receiver).await;
+        assert!(matches!(first, Some(NetworkEvent::Message(bytes)) if bytes == sealed));
+        let mut kinds = Vec::new();
+        while let Ok(NetworkEvent::Message(bytes)) = receiver.try_recv() {
+            kinds.push(peek_kind(&bytes).unwrap());
+        }
+        assert_eq!(kinds, vec![MessageKind::SyncResponse, MessageKind::SyncResponse, MessageKind::SyncResponse, MessageKind::Vote]);
+    }
+}
@@ -1,4 +1,13 @@
+#[allow(clippy::module_inception)]
 mod network;
+pub mod chunking;
+pub mod codec;
+pub mod direct;
+pub mod event_queue;
+pub mod nat;
 pub mod peer_init;
+pub mod peer_score;
+pub mod roster_channel;
 
+pub use event_queue::{recv_prioritized, EVENT_QUEUE_CAPACITY};
 pub use network::*;
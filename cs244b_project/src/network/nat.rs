@@ -0,0 +1,147 @@
+/* NAT traversal: how a node behind a home router still takes part.
+   A node behind NAT can dial out but can't be dialed, so two such nodes never connect
+   to each other, and mDNS only helps on the same LAN. The fix is a circuit relay
+   (libp2p relay, v1): a publicly reachable node that forwards connections. A node
+   behind NAT connects to a relay and listens through it (<relay address>/p2p-circuit);
+   that address is advertised like any other (identify, the DHT), so other nodes dial
+   it and the relay joins the two connections.
+   Whether to listen through relays is decided from what peers see: identify tells us
+   the address each peer observed our connection coming from. If peers on the
+   internet see us at an address that isn't one of ours, we are behind NAT. (libp2p's
+   AutoNAT, which asks peers to dial back, needs a newer libp2p than this build; this
+   check is cheaper but can't tell a 1:1 NAT with open ports, as on some cloud hosts,
+   from a home router, so such a node also listens through a relay it may not need.)
+   Observations from LAN peers are ignored: they see our LAN address either way. */
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+// Peers that must agree before we decide we are (or aren't) reachable
+pub const NAT_CONFIRMATIONS: usize = 2;
+// Most peers whose observations we keep
+const MAX_OBSERVERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatStatus {
+    Unknown,
+    Public,  // peers on the internet see us at one of our own addresses
+    Private, // they see a router's address: we are behind NAT
+}
+
+/* How to get through NAT. Relays must name their peer id (/p2p/<id>). */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NatConfig {
+    pub relay_server: bool,     // keep relayed connections open for other nodes
+    pub relays: Vec<Multiaddr>, // listen through these if we turn out to be behind NAT
+}
+
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+// Whether an address can be seen from the internet (not loopback, LAN or link-local)
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/* Decides whether we are behind NAT from the addresses peers observe us at. */
+#[derive(Debug)]
+pub struct ReachabilityWatch {
+    own_ips: HashSet<IpAddr>,
+    verdicts: HashMap<PeerId, bool>, // whether the peer saw us at one of our own addresses
+    status: NatStatus,
+}
+
+impl Default for ReachabilityWatch {
+    fn default() -> Self {
+        Self { own_ips: HashSet::new(), verdicts: HashMap::new(), status: NatStatus::Unknown }
+    }
+}
+
+impl ReachabilityWatch {
+    /* We listen on an address (one per interface for a wildcard listen address). */
+    pub fn listening(&mut self, addr: &Multiaddr) {
+        if let Some(ip) = ip_of(addr) {
+            self.own_ips.insert(ip);
+        }
+    }
+
+    /* A peer saw our connection come from an address. Returns the new status if this
+    changed it.
+    @param peer: the peer
+    @param observed: the address it saw */
+    pub fn observed(&mut self, peer: PeerId, observed: &Multiaddr) -> Option<NatStatus> {
+        let ip = ip_of(observed).filter(is_global)?;
+        if self.verdicts.len() >= MAX_OBSERVERS && !self.verdicts.contains_key(&peer) {
+            return None;
+        }
+        self.verdicts.insert(peer, self.own_ips.contains(&ip));
+        let reachable = self.verdicts.values().filter(|reachable| **reachable).count();
+        let status = if reachable >= NAT_CONFIRMATIONS {
+            NatStatus::Public
+        } else if self.verdicts.len() - reachable >= NAT_CONFIRMATIONS {
+            NatStatus::Private
+        } else {
+            NatStatus::Unknown
+        };
+        if status == self.status || status == NatStatus::Unknown {
+            return None;
+        }
+        self.status = status;
+        Some(status)
+    }
+
+    pub fn status(&self) -> NatStatus {
+        self.status
+    }
+}
+
+/* The address to listen on through a relay.
+@param relay: the relay's address, ending in /p2p/<its peer id> */
+pub fn circuit_addr(relay: &Multiaddr) -> Result<Multiaddr, String> {
+    match relay.iter().last() {
+        Some(Protocol::P2p(_)) => Ok(relay.clone().with(Protocol::P2pCircuit)),
+        _ => Err(format!("relay address {} should end in /p2p/<peer id>", relay)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_follows_what_internet_peers_see() {
+        let mut watch = ReachabilityWatch::default();
+        watch.listening(&"/ip4/192.168.1.20/tcp/4001".parse().unwrap());
+        let router: Multiaddr = "/ip4/203.0.113.7/tcp/51234".parse().unwrap();
+        let lan: Multiaddr = "/ip4/192.168.1.20/tcp/51234".parse().unwrap();
+        // LAN peers say nothing either way; one internet peer isn't enough
+        assert_eq!(watch.observed(PeerId::random(), &lan), None);
+        assert_eq!(watch.observed(PeerId::random(), &router), None);
+        assert_eq!(watch.observed(PeerId::random(), &router), Some(NatStatus::Private));
+        assert_eq!(watch.observed(PeerId::random(), &router), None);
+        assert_eq!(watch.status(), NatStatus::Private);
+
+        let mut public = ReachabilityWatch::default();
+        public.listening(&"/ip4/203.0.113.9/tcp/4001".parse().unwrap());
+        let own: Multiaddr = "/ip4/203.0.113.9/tcp/60000".parse().unwrap();
+        public.observed(PeerId::random(), &own);
+        assert_eq!(public.observed(PeerId::random(), &own), Some(NatStatus::Public));
+
+        let relay: Multiaddr = format!("/ip4/203.0.113.9/tcp/4001/p2p/{}", PeerId::random()).parse().unwrap();
+        assert!(circuit_addr(&relay).unwrap().to_string().ends_with("/p2p-circuit"));
+        assert!(circuit_addr(&"/ip4/203.0.113.9/tcp/4001".parse().unwrap()).is_err());
+    }
+}
@@ -3,18 +3,40 @@ use libp2p::{
     futures::StreamExt,
     gossipsub,
     gossipsub::{
-        GossipsubEvent, IdentTopic as Topic, MessageAuthenticity,
+        GossipsubEvent, IdentTopic as Topic, MessageAuthenticity, TopicHash,
     },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity,
+    kad::{record::store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
     mdns::{Mdns, MdnsEvent},
+    multiaddr::Protocol,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent, RequestResponseMessage,
+        ResponseChannel,
+    },
     mplex, noise,
-    swarm::{NetworkBehaviourEventProcess, Swarm, SwarmBuilder},
+    relay::{self, Relay, RelayConfig},
+    swarm::{NetworkBehaviourEventProcess, Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    NetworkBehaviour, PeerId, Transport,
+    NetworkBehaviour, Transport,
 };
-use log::{error, info};
-use std::time::Duration;
+pub use libp2p::{Multiaddr, PeerId};
+use log::{debug, error, info};
+use super::chunking::{self, ChunkError, Reassembler, MAX_TRANSMIT_SIZE};
+use super::codec::{decode_frame, Codec};
+use super::direct::{DirectCodec, DirectProtocol, DirectRequest};
+use super::event_queue::EventQueue;
+use super::nat::{circuit_addr, NatConfig, NatStatus, ReachabilityWatch};
+use super::peer_score::{PeerScores, PeerSeverity};
+use crate::error::StreamletError;
+use crate::messages::envelope::decode_message;
+use crate::utils::crypto::{Digest, Sha256};
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, VecDeque};
+use std::iter;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tracing::{debug_span, field, Span};
 
 // Set this to be the max. amount of time we're likely to be running one instance. 
  // Generally, due to a (likely) bug in libp2p's mDNS implementation, we can't 
@@ -22,6 +44,31 @@ use tokio::sync::mpsc;
  // timeout to be very large. 
  static IDLE_MINS : u64 = 20;
 
+// Gossipsub heartbeat: mesh upkeep, and gossip to peers outside the mesh
+pub const GOSSIP_HEARTBEAT: Duration = Duration::from_secs(10);
+// Our own protocols, so the DHT doesn't mix with other libp2p networks (e.g. IPFS)
+const KAD_PROTOCOL: &[u8] = b"/streamlet/kad/1.0.0";
+const IDENTIFY_PROTOCOL: &str = "/streamlet/id/1.0.0";
+// Inbound direct requests waiting for the application's answer; the oldest is dropped (unanswered) beyond this
+const MAX_PENDING_RESPONSES: usize = 64;
+// Nodes whose peer id we remember (from the messages they send), for unicast replies
+const MAX_KNOWN_NODES: usize = 1024;
+// Recent messages whose sender we remember, so the application can report a bad one
+const MAX_TRACKED_SOURCES: usize = 1024;
+// Longest a closing node waits for its connections to close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/* What the network tells the application. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+    Message(Vec<u8>),         // a message for us: gossip on a topic we subscribe to, or directly from a peer
+    PeerConnected(PeerId),    // our first connection to a peer opened
+    PeerDisconnected(PeerId), // our last connection to a peer closed
+    ListenAddr(Multiaddr),    // we now listen on this address (e.g. the random port picked at startup)
+    Nat(NatStatus),           // we found out whether we are behind NAT (see nat)
+    PeerBanned(PeerId),       // a peer misbehaved too often and is shut out for a while (see peer_score)
+}
+
 pub struct NetworkStack {
     // Access to network functionality
     swarm: Swarm<AppBehaviour>,
@@ -32,6 +79,11 @@ pub struct NetworkStack {
     // unsubscribe from.
     init_topic: Topic,
     init_open: bool,
+    // Compression for outgoing messages (incoming ones name their own codec)
+    codec: Codec,
+    // Relays to listen through once we find we're behind NAT, and whether we do already
+    relays: Vec<Multiaddr>,
+    relayed: bool,
 }
 
 #[derive(NetworkBehaviour)]
@@ -42,24 +94,194 @@ struct AppBehaviour {
     gossipsub: gossipsub::Gossipsub,
     // A way of discovering peers that are running our protocol.
     mdns: Mdns,
+    // Peer discovery beyond the LAN: a DHT seeded from the bootstrap peers. Identify tells
+    // us the addresses peers listen on, so they can be put in the DHT's routing table.
+    kademlia: Kademlia<MemoryStore>,
+    identify: Identify,
+    // Point-to-point requests and responses (see direct)
+    direct: RequestResponse<DirectCodec>,
+    // Circuit relay: dialing and listening through relays, and relaying for others (see nat)
+    relay: Relay,
+    // Whether we have started a DHT bootstrap (a walk for the peers closest to us)
+    #[behaviour(ignore)]
+    dht_bootstrapped: bool,
+    // Where to answer inbound direct requests, by the tag of the request message
+    #[behaviour(ignore)]
+    pending_responses: VecDeque<(u32, ResponseChannel<Vec<u8>>)>,
+    // Peer id of each node, by the name in its messages
+    #[behaviour(ignore)]
+    node_peers: HashMap<String, PeerId>,
+    // Whether we're behind NAT, from the addresses peers see us at; and news of a change
+    #[behaviour(ignore)]
+    reachability: ReachabilityWatch,
+    #[behaviour(ignore)]
+    nat_change: Option<NatStatus>,
+    // Misbehavior reported against each peer, and peers banned since the swarm last heard
+    #[behaviour(ignore)]
+    scores: PeerScores,
+    #[behaviour(ignore)]
+    newly_banned: Vec<PeerId>,
+    // Digest and sender of recent messages, oldest first
+    #[behaviour(ignore)]
+    sources: VecDeque<([u8; 32], PeerId)>,
+    // Gossip messages too large for one gossipsub message, partly received (see chunking)
+    #[behaviour(ignore)]
+    reassembler: Reassembler,
 
     // How to send arbitrary network events to the application (core logic)
     #[behaviour(ignore)]
-    app_sender: mpsc::UnboundedSender<Vec<u8>>,
+    app_events: EventQueue,
+    // Topics whose messages go to a channel of their own instead of app_events
+    #[behaviour(ignore)]
+    topic_routes: HashMap<TopicHash, mpsc::Sender<NetworkEvent>>,
+}
+
+/* Adds the message's correlation id to a span of its trip over the network (see
+telemetry); the message is only decoded for it when the span is enabled. */
+fn with_correlation_id(span: Span, message: &[u8]) -> Span {
+    if !span.is_disabled() {
+        if let Some(message) = decode_message(message) {
+            span.record("id", message.correlation_id().as_str());
+        }
+    }
+    span
+}
+
+impl AppBehaviour {
+    /* Hands a framed message to the application, on its topic's channel if it has one;
+    returns the decoded bytes.
+    @param frame: the message as received
+    @param topic: the topic it was published on (None: it came directly from a peer)
+    @param from: the peer answerable for it (the author of signed gossip) */
+    fn deliver(&mut self, frame: &[u8], topic: Option<&TopicHash>, from: PeerId) -> Option<Vec<u8>> {
+        let assembled = match chunking::is_chunk(frame) {
+            true => match self.reassembler.add(from, frame, Instant::now()) {
+                Ok(Some(assembled)) => Some(assembled),
+                Ok(None) => return None,
+                Err(e) => {
+                    error!("Dropping chunk from {}: {}", from, e);
+                    self.penalize(from, PeerSeverity::Minor);
+                    return None;
+                }
+            },
+            false => None,
+        };
+        let frame = assembled.as_deref().unwrap_or(frame);
+        let data = match decode_frame(frame) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Dropping message we can't decode from {}: {}", from, e);
+                self.penalize(from, PeerSeverity::Minor);
+                return None;
+            }
+        };
+        if self.sources.len() == MAX_TRACKED_SOURCES {
+            self.sources.pop_front();
+        }
+        self.sources.push_back((Sha256::digest(&data).into(), from));
+        let topic_name = topic.map(TopicHash::as_str).unwrap_or("direct");
+        let span = with_correlation_id(debug_span!("receive", topic = topic_name, from = %from, bytes = data.len(), id = field::Empty), &data);
+        let _receiving = span.enter();
+        debug!("Received message");
+        if let Some(route) = topic.and_then(|topic| self.topic_routes.get(topic)) {
+            match route.try_send(NetworkEvent::Message(data.clone())) {
+                Ok(()) => return Some(data),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.app_events.count_dropped();
+                    return Some(data);
+                }
+                // Whoever took the topic's messages is gone; the application gets them again
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    let topic = topic.expect("routes are by topic");
+                    error!("Channel for topic {} closed; routing its messages to the application", topic);
+                    self.topic_routes.remove(topic);
+                }
+            }
+        }
+        self.notify(NetworkEvent::Message(data.clone()));
+        Some(data)
+    }
+
+    /* Adds a report to a peer's score; a peer that gets banned is shut out the next
+    time the swarm is polled (see NetworkStack::apply_bans). */
+    fn penalize(&mut self, peer: PeerId, severity: PeerSeverity) {
+        if self.scores.report(peer, severity, Instant::now()) {
+            self.newly_banned.push(peer);
+        }
+    }
+
+    fn notify(&mut self, event: NetworkEvent) {
+        self.app_events.push(event);
+    }
+
+    /* Remembers which peer a message came from, so replies can go to it alone; returns
+    the message's tag. Gossip is signed with its author's libp2p key, so the source is the
+    peer that wrote the message, not whoever relayed it. Names aren't checked here (the
+    application checks signatures), so a forged name can only misdirect a reply. */
+    fn learn_sender(&mut self, data: &[u8], source: Option<PeerId>) -> Option<u32> {
+        let message = decode_message(data)?;
+        if let Some(peer) = source {
+            if self.node_peers.len() < MAX_KNOWN_NODES || self.node_peers.contains_key(&message.sender_name) {
+                self.node_peers.insert(message.sender_name, peer);
+            }
+        }
+        Some(message.tag)
+    }
 }
 
 impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
     fn inject_event(&mut self, event: GossipsubEvent) {
         if let GossipsubEvent::Message {
             message,
-            propagation_source: _,
+            propagation_source,
             message_id: _,
         } = event
         {
-            let res = self.app_sender.send(message.data);
-            if let Err(e) = res {
-                error!("Error communicating with main application {}", e);
+            // Gossip already in flight when its sender was banned
+            if self.scores.is_banned(&propagation_source, Instant::now()) {
+                return;
+            }
+            // Gossip is signed by its author, so the author answers for it, not whoever relayed it
+            let author = message.source.unwrap_or(propagation_source);
+            if let Some(data) = self.deliver(&message.data, Some(&message.topic), author) {
+                self.learn_sender(&data, message.source);
+            }
+        }
+    }
+}
+
+// Direct requests reach the application like gossip; it answers with respond()
+impl NetworkBehaviourEventProcess<RequestResponseEvent<DirectRequest, Vec<u8>>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<DirectRequest, Vec<u8>>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                let (frame, wants_response) = match request {
+                    DirectRequest::Ask(frame) => (frame, true),
+                    DirectRequest::Tell(frame) => (frame, false),
+                };
+                let tag = match self.deliver(&frame, None, peer).and_then(|data| self.learn_sender(&data, Some(peer))) {
+                    Some(tag) => tag,
+                    None => return,
+                };
+                if !wants_response {
+                    let _ = self.direct.send_response(channel, Vec::new());
+                    return;
+                }
+                self.pending_responses.retain(|(_, channel)| channel.is_open());
+                if self.pending_responses.len() == MAX_PENDING_RESPONSES {
+                    self.pending_responses.pop_front();
+                }
+                self.pending_responses.push_back((tag, channel));
             }
+            // Empty responses only acknowledge a unicast message
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { response, .. } } => {
+                if !response.is_empty() {
+                    self.deliver(&response, None, peer);
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => debug!("Direct request to {} failed: {:?}", peer, error),
+            RequestResponseEvent::InboundFailure { peer, error, .. } => debug!("Direct request from {} failed: {:?}", peer, error),
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
@@ -79,8 +301,65 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     }
 }
 
+// DHT (peer discovery across networks): every peer in the routing table joins the gossip
+impl NetworkBehaviourEventProcess<KademliaEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated { peer, is_new_peer: true, .. } = event {
+            debug!("DHT found peer {}", peer);
+            self.gossipsub.add_explicit_peer(&peer);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            // Only peers running our DHT protocol go in the routing table
+            if !info.protocols.iter().any(|protocol| protocol.as_bytes() == KAD_PROTOCOL) {
+                return;
+            }
+            if let Some(status) = self.reachability.observed(peer_id, &info.observed_addr) {
+                self.nat_change = Some(status);
+            }
+            for addr in info.listen_addrs {
+                self.kademlia.add_address(&peer_id, addr);
+            }
+            // The first peer we learn about is enough to walk the DHT from
+            if !self.dht_bootstrapped {
+                self.dht_bootstrapped = self.kademlia.bootstrap().is_ok();
+            }
+        }
+    }
+}
+
+// The relay behaviour reports nothing
+impl NetworkBehaviourEventProcess<()> for AppBehaviour {
+    fn inject_event(&mut self, _: ()) {}
+}
+
 impl NetworkStack {
-    pub async fn new(topic_name: &str, app_sender: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+    /* Joins the network: listens on a random local port, finds peers on the LAN with mDNS,
+    and dials the given bootstrap peers (e.g. nodes on other subnets, where mDNS doesn't
+    reach). From the bootstrap peers it walks a Kademlia DHT to find every other node,
+    wherever it is; mDNS still covers the LAN without any bootstrap peers.
+    @param topic_name: the topic to subscribe to
+    @param app_sender: where received messages and peer events go (a channel of
+        EVENT_QUEUE_CAPACITY, say; see event_queue)
+    @param bootstrap: addresses to dial, e.g. /ip4/10.0.1.5/tcp/4001 (optionally ending in /p2p/<peer id>) */
+    pub async fn new(topic_name: &str, app_sender: mpsc::Sender<NetworkEvent>, bootstrap: &[Multiaddr]) -> Self {
+        NetworkStack::with_nat(topic_name, app_sender, bootstrap, &NatConfig::default()).await
+    }
+
+    /* Joins the network as new does, set up to get through NAT (see nat): relays are
+    dialed like bootstrap peers, and listened through if we turn out to be behind NAT.
+    @param nat: whether to serve as a relay, and which relays to use */
+    pub async fn with_nat(
+        topic_name: &str,
+        app_sender: mpsc::Sender<NetworkEvent>,
+        bootstrap: &[Multiaddr],
+        nat: &NatConfig,
+    ) -> Self {
+        let bootstrap: Vec<Multiaddr> = bootstrap.iter().chain(&nat.relays).cloned().collect();
         // Key and identification
         let keys = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(keys.public());
@@ -88,17 +367,53 @@ impl NetworkStack {
         // Topic to listen on
         let topic = Topic::new(topic_name);
 
-        let transport = NetworkStack::create_transport(&keys).await;
+        let mut relay_config = RelayConfig::default();
+        if nat.relay_server || !nat.relays.is_empty() {
+            // Relayed connections carry consensus traffic; don't let them idle out
+            relay_config.connection_idle_timeout = Duration::from_secs(60 * IDLE_MINS);
+        }
+        let (transport, relay) = NetworkStack::create_transport(&keys, relay_config).await;
         let gossipsub = NetworkStack::init_gossipsub(&topic, &keys);
         let mdns = Mdns::new(Default::default())
             .await
             .expect("Can't set up peer discovery protocol");
+        let mut kad_config = KademliaConfig::default();
+        kad_config.set_protocol_name(KAD_PROTOCOL);
+        let mut kademlia = Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kad_config);
+        // Bootstrap addresses that name their peer (/p2p/<id>) go straight into the routing table
+        for addr in &bootstrap {
+            if let Some(Protocol::P2p(hash)) = addr.iter().last() {
+                if let Ok(peer) = PeerId::from_multihash(hash) {
+                    kademlia.add_address(&peer, addr.clone());
+                }
+            }
+        }
+        let identify = Identify::new(IdentifyConfig::new(IDENTIFY_PROTOCOL.to_string(), keys.public()));
+        let direct = RequestResponse::new(
+            DirectCodec,
+            iter::once((DirectProtocol, ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
 
         // **** create the swarm ****
         let behaviour = AppBehaviour {
-            gossipsub: gossipsub,
-            mdns: mdns,
-            app_sender: app_sender,
+            gossipsub,
+            mdns,
+            kademlia,
+            identify,
+            direct,
+            relay,
+            dht_bootstrapped: false,
+            pending_responses: VecDeque::new(),
+            node_peers: HashMap::new(),
+            reachability: ReachabilityWatch::default(),
+            nat_change: None,
+            scores: PeerScores::default(),
+            newly_banned: Vec::new(),
+            sources: VecDeque::new(),
+            reassembler: Reassembler::default(),
+            app_events: EventQueue::new(app_sender),
+            topic_routes: HashMap::new(),
         };
         let mut swarm = SwarmBuilder::new(transport, behaviour, peer_id)
             .executor(Box::new(|fut| {
@@ -112,30 +427,275 @@ impl NetworkStack {
 
         let init_topic = Topic::new("init");
 
-        Self {
-            swarm: swarm,
-            topic: topic,
-            init_topic: init_topic,
+        let mut stack = Self {
+            swarm,
+            topic,
+            init_topic,
             init_open: false,
+            codec: Codec::default(),
+            relays: nat.relays.clone(),
+            relayed: false,
+        };
+        for addr in &bootstrap {
+            stack.dial(addr);
         }
+        stack
     }
 
-    pub fn broadcast_message(&mut self, message: Vec<u8>) {
-        let res = self
-            .swarm
-            .behaviour_mut()
+    /* Also listens on a fixed address, so other nodes can bootstrap from this one.
+    @param addr: e.g. /ip4/0.0.0.0/tcp/4001 */
+    pub fn listen_on(&mut self, addr: &Multiaddr) -> Result<(), String> {
+        self.swarm.listen_on(addr.clone()).map(|_| ()).map_err(|e| format!("Can't listen on {}: {:?}", addr, e))
+    }
+
+    /* Walks the DHT again for peers close to us, to find nodes that joined since the
+    last walk. Does nothing until the DHT knows at least one peer. */
+    pub fn refresh_peers(&mut self) {
+        let behaviour = self.swarm.behaviour_mut();
+        if let Ok(query) = behaviour.kademlia.bootstrap() {
+            debug!("Refreshing DHT peers (query {:?})", query);
+            behaviour.dht_bootstrapped = true;
+        }
+    }
+
+    /* Dials a peer by address. The connection is made in the background; failures are
+    only logged, so a peer that isn't up yet has to be dialed again.
+    @param addr: the peer's address */
+    pub fn dial(&mut self, addr: &Multiaddr) {
+        match self.swarm.dial_addr(addr.clone()) {
+            Ok(()) => info!("Dialing {}", addr),
+            Err(e) => error!("Can't dial {}: {:?}", addr, e),
+        }
+    }
+
+    /* Sets the compression codec for outgoing messages (see codec). */
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    fn frame(&self, message: Vec<u8>) -> Result<Vec<u8>, StreamletError> {
+        self.codec.encode_frame(&message).map_err(|e| StreamletError::Serialization(e.to_string()))
+    }
+
+    /* Publishes a message on a topic, in chunks if it is too large for one gossipsub
+    message (see chunking). Fails with MessageTooLarge, before publishing anything, if
+    it is over MAX_MESSAGE_LEN even framed. */
+    fn publish(&mut self, topic: Topic, message: Vec<u8>) -> Result<(), StreamletError> {
+        let span = with_correlation_id(debug_span!("publish", topic = %topic.hash(), bytes = message.len(), id = field::Empty), &message);
+        let _publishing = span.enter();
+        let chunks = match chunking::split(self.frame(message)?) {
+            Ok(chunks) => chunks,
+            Err(ChunkError::TooLarge(len)) => return Err(StreamletError::MessageTooLarge(len)),
+            Err(e) => return Err(StreamletError::Publish { topic: topic.to_string(), reason: e.to_string() }),
+        };
+        debug!("Publishing in {} chunk(s)", chunks.len());
+        for chunk in chunks {
+            let published = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), chunk);
+            published.map_err(|e| StreamletError::Publish { topic: topic.to_string(), reason: format!("{:?}", e) })?;
+        }
+        Ok(())
+    }
+
+    /* Publishes a message on our topic. Fails if it is too large, or gossipsub refuses it
+    (e.g. no peers yet); nothing else is affected. */
+    pub fn broadcast_message(&mut self, message: Vec<u8>) -> Result<(), StreamletError> {
+        self.publish(self.topic.clone(), message)
+    }
+
+    /* Sends a message directly to a few random peers on our topic rather than to everyone;
+    their answers arrive like any other message. Returns how many peers it went to (0:
+    no peers yet, so nothing was sent).
+    @param message: the serialized message
+    @param max_peers: how many peers to ask */
+    pub fn send_direct_request(&mut self, message: Vec<u8>, max_peers: usize) -> Result<usize, StreamletError> {
+        let message = self.frame(message)?;
+        let topic = self.topic.hash();
+        let behaviour = self.swarm.behaviour_mut();
+        let peers: Vec<PeerId> = behaviour
             .gossipsub
-            .publish(self.topic.clone(), message);
-        if let Err(e) = res {
-            panic!("Failed to send message over GossipSub protocol: {:?}", e);
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .map(|(peer, _)| *peer)
+            .choose_multiple(&mut rand::thread_rng(), max_peers);
+        for peer in &peers {
+            behaviour.direct.send_request(peer, DirectRequest::Ask(message.clone()));
+        }
+        Ok(peers.len())
+    }
+
+    /* Sends a message to one peer only. Delivery happens in the background; failures
+    to deliver are only logged.
+    @param peer: the peer, e.g. from peer_of
+    @param message: the serialized message */
+    pub fn send_to_peer(&mut self, peer: &PeerId, message: Vec<u8>) -> Result<(), StreamletError> {
+        let span = with_correlation_id(debug_span!("publish", peer = %peer, bytes = message.len(), id = field::Empty), &message);
+        let _publishing = span.enter();
+        debug!("Sending directly");
+        let message = self.frame(message)?;
+        self.swarm.behaviour_mut().direct.send_request(peer, DirectRequest::Tell(message));
+        Ok(())
+    }
+
+    /* The peer a node's messages last came from (None: we haven't heard from it).
+    @param node_name: the name the node signs its messages with */
+    pub fn peer_of(&self, node_name: &str) -> Option<PeerId> {
+        self.swarm.behaviour().node_peers.get(node_name).copied()
+    }
+
+    /* Answers a message: over the requester's own channel if it came as a direct request,
+    else to the requester alone if we know its peer, else over gossip.
+    @param request_tag: tag of the message being answered
+    @param requester: name of the node that sent it
+    @param response: the serialized answer */
+    pub fn respond(&mut self, request_tag: u32, requester: &str, response: Vec<u8>) -> Result<(), StreamletError> {
+        let behaviour = self.swarm.behaviour_mut();
+        let channel = match behaviour.pending_responses.iter().position(|(tag, _)| *tag == request_tag) {
+            Some(index) => behaviour.pending_responses.remove(index).map(|(_, channel)| channel),
+            None => None,
+        };
+        match channel {
+            Some(channel) => {
+                let response = self.frame(response)?;
+                if self.swarm.behaviour_mut().direct.send_response(channel, response).is_err() {
+                    debug!("Requester of message {} went away before the response", request_tag);
+                }
+                Ok(())
+            }
+            None => match self.peer_of(requester) {
+                Some(peer) => self.send_to_peer(&peer, response),
+                None => self.broadcast_message(response),
+            },
         }
     }
 
-    // Polling happens via stream
+    /* Disconnects from every peer, so they see us leave at once rather than when the
+    connections time out, waiting up to CLOSE_TIMEOUT for the connections to close. */
+    pub async fn close(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.behaviour().gossipsub.all_peers().map(|(peer, _)| *peer).collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        let swarm = &mut self.swarm;
+        let closed = async {
+            while swarm.network_info().num_peers() > 0 {
+                swarm.select_next_some().await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            debug!("Some connections were still open after {:?}", CLOSE_TIMEOUT);
+        }
+    }
+
+    /* Drives the network; must be polled for anything to happen. Messages arrive on the
+    application's channel as they come in; connection and listening changes this passes on. */
     pub async fn clear_unhandled_event(&mut self) {
-        self.swarm.select_next_some().await;
+        // Backpressure: take nothing more from the network until the application has room for it
+        self.swarm.behaviour_mut().app_events.wait_for_room().await;
+        let event = self.swarm.select_next_some().await;
+        if let Some(status) = self.swarm.behaviour_mut().nat_change.take() {
+            self.nat_changed(status);
+        }
+        self.apply_bans();
+        let event = match event {
+            SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } if num_established.get() == 1 => {
+                NetworkEvent::PeerConnected(peer_id)
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => NetworkEvent::PeerDisconnected(peer_id),
+            SwarmEvent::NewListenAddr { address, .. } => {
+                self.swarm.behaviour_mut().reachability.listening(&address);
+                NetworkEvent::ListenAddr(address)
+            }
+            _ => return,
+        };
+        self.swarm.behaviour_mut().notify(event);
+    }
+
+    // Behind NAT, nobody can dial us: listen through the relays instead
+    fn nat_changed(&mut self, status: NatStatus) {
+        if status == NatStatus::Private && !self.relayed {
+            if self.relays.is_empty() {
+                info!("We are behind NAT and have no relays to listen through (see --relay); only outgoing connections will work");
+            }
+            for relay in self.relays.clone() {
+                match circuit_addr(&relay).and_then(|addr| self.listen_on(&addr)) {
+                    Ok(()) => info!("Behind NAT; listening through relay {}", relay),
+                    Err(e) => error!("{}", e),
+                }
+            }
+            self.relayed = true;
+        }
+        self.swarm.behaviour_mut().notify(NetworkEvent::Nat(status));
+    }
+
+    /* Reports a peer's misbehavior (see peer_score); a peer that misbehaves too often
+    is disconnected and refused for a while.
+    @param peer: the peer, e.g. from peer_of
+    @param severity: how badly it misbehaved */
+    pub fn report_peer(&mut self, peer: &PeerId, severity: PeerSeverity) {
+        debug!("Peer {} reported for {:?} misbehavior", peer, severity);
+        self.swarm.behaviour_mut().penalize(*peer, severity);
+        self.apply_bans();
+    }
+
+    /* The peer a recent message came from, to report it if the message turns out to be
+    bad (None: too long ago, or not from the network). Unlike peer_of, this can't be
+    pointed at someone else by a message forging a node's name.
+    @param message: the message, as delivered to the application */
+    pub fn source_of(&self, message: &[u8]) -> Option<PeerId> {
+        let digest: [u8; 32] = Sha256::digest(message).into();
+        self.swarm.behaviour().sources.iter().rev().find(|(seen, _)| *seen == digest).map(|(_, peer)| *peer)
+    }
+
+    /* Sends consensus messages to the application down a lane of their own, ahead of
+    everything else (see event_queue).
+    @param priority: the lane; read it with recv_prioritized */
+    pub fn set_priority_lane(&mut self, priority: mpsc::Sender<NetworkEvent>) {
+        self.swarm.behaviour_mut().app_events.set_priority_lane(priority);
+    }
+
+    /* Messages dropped so far because the application (or a routed topic's task) fell
+    behind (see event_queue). */
+    pub fn dropped_messages(&self) -> u64 {
+        self.swarm.behaviour().app_events.dropped()
+    }
+
+    /* A peer's misbehavior score now (a ban at BAN_SCORE; see peer_score). */
+    pub fn peer_score(&self, peer: &PeerId) -> u32 {
+        self.swarm.behaviour().scores.score(peer, Instant::now())
+    }
+
+    /* Peers banned now. */
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.swarm.behaviour().scores.banned(Instant::now())
+    }
+
+    // Shuts out peers banned since the last call, and lets back in those whose ban ended
+    fn apply_bans(&mut self) {
+        let behaviour = self.swarm.behaviour_mut();
+        let banned: Vec<PeerId> = behaviour.newly_banned.drain(..).collect();
+        let expired = behaviour.scores.expire_bans(Instant::now());
+        for peer in banned {
+            info!("Banning peer {} for misbehavior", peer);
+            self.swarm.ban_peer_id(peer);
+            let behaviour = self.swarm.behaviour_mut();
+            behaviour.gossipsub.blacklist_peer(&peer);
+            behaviour.notify(NetworkEvent::PeerBanned(peer));
+        }
+        for peer in expired {
+            info!("Ban on peer {} ended", peer);
+            self.swarm.unban_peer_id(peer);
+            self.swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+        }
     }
 
+    /* Whether we are behind NAT, as far as we know yet. */
+    pub fn nat_status(&self) -> NatStatus {
+        self.swarm.behaviour().reachability.status()
+    }
+
+    /* Subscribes to another topic; its messages go to the application like the main
+    topic's (see add_routed_topic to keep them apart).
+    @param topic: the topic's name */
     pub fn add_topic(&mut self, topic: &str) {
         self.swarm
             .behaviour_mut()
@@ -144,18 +704,38 @@ impl NetworkStack {
             .expect("Can't open new topic channel");
     }
 
-    pub fn broadcast_to_topic(&mut self, topic: &str, message: Vec<u8>) {
-        let res = self
-            .swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(Topic::new(topic), message);
+    /* Subscribes to a topic whose messages go to a channel of their own, e.g. for a task
+    that handles one kind of traffic (tree heads, peer discovery) apart from consensus.
+    If that channel closes, the topic's messages go to the application again; while it
+    is full, they are dropped.
+    @param topic: the topic's name
+    @param sender: where the topic's messages go (as NetworkEvent::Message) */
+    pub fn add_routed_topic(&mut self, topic: &str, sender: mpsc::Sender<NetworkEvent>) {
+        self.add_topic(topic);
+        self.swarm.behaviour_mut().topic_routes.insert(Topic::new(topic).hash(), sender);
+    }
 
-        if let Err(e) = res {
-            panic!("Failed to broadcast to topic {} with error {:?}.", topic, e);
+    /* Stops receiving a topic's messages (we can still publish on it).
+    @param topic: the topic's name */
+    pub fn remove_topic(&mut self, topic: &str) {
+        let topic = Topic::new(topic);
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.topic_routes.remove(&topic.hash());
+        if let Err(e) = behaviour.gossipsub.unsubscribe(&topic) {
+            error!("Can't unsubscribe from {}: {:?}", topic, e);
         }
     }
 
+    /* Names of the topics we are subscribed to. */
+    pub fn topics(&self) -> Vec<String> {
+        self.swarm.behaviour().gossipsub.topics().map(|topic| topic.as_str().to_string()).collect()
+    }
+
+    /* Publishes a message on another topic; fails like broadcast_message. */
+    pub fn broadcast_to_topic(&mut self, topic: &str, message: Vec<u8>) -> Result<(), StreamletError> {
+        self.publish(Topic::new(topic), message)
+    }
+
     // Methods for handling an optional "init" channel.
     // Optional, additional channel that can be opened and closed
     // Can be used for, e.g., a short-term bootstrapping period.
@@ -188,13 +768,7 @@ impl NetworkStack {
         if !self.init_open {
             return;
         }
-        let res = self
-            .swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(self.init_topic.clone(), message);
-
-        if let Err(_e) = res {
+        if let Err(_e) = self.publish(self.init_topic.clone(), message) {
             info!("Not enough peers to initialize yet.");
         }
     }
@@ -203,28 +777,29 @@ impl NetworkStack {
 
     async fn create_transport(
         keys: &identity::Keypair,
-    ) -> transport::Boxed<(PeerId, muxing::StreamMuxerBox)> {
+        relay_config: RelayConfig,
+    ) -> (transport::Boxed<(PeerId, muxing::StreamMuxerBox)>, Relay) {
         // Needed for configuring encryption on the transport layer
         let auth_keys = noise::Keypair::<noise::X25519Spec>::new()
-            .into_authentic(&keys)
+            .into_authentic(keys)
             .expect("Can't create auth keys for p2p channel");
 
-        // Create encrypted transport layer
-        let transport = TokioTcpConfig::new()
-            .nodelay(true)
+        // TCP, plus connections through relays; both encrypted the same way
+        let (tcp, relay) = relay::new_transport_and_behaviour(relay_config, TokioTcpConfig::new().nodelay(true));
+        let transport = tcp
             .upgrade(upgrade::Version::V1)
             .authenticate(noise::NoiseConfig::xx(auth_keys).into_authenticated())
             .multiplex(mplex::MplexConfig::new())
             .boxed();
-
-        transport
+        (transport, relay)
     }
 
     fn init_gossipsub(topic: &Topic, keys: &identity::Keypair) -> gossipsub::Gossipsub {
 
         // Set up the gossipsub configuration
         let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
-            .heartbeat_interval(Duration::from_secs(10))
+            .heartbeat_interval(GOSSIP_HEARTBEAT)
+            .max_transmit_size(MAX_TRANSMIT_SIZE)
             .idle_timeout(Duration::from_secs(60 * IDLE_MINS))
             .build()
             .expect("Can't set up GossipSub configuration");
@@ -235,7 +810,7 @@ impl NetworkStack {
 
         // Set up the gossipsub configuration
         gossipsub
-            .subscribe(&topic)
+            .subscribe(topic)
             .expect("Can't subscribe to topic!");
 
         gossipsub
@@ -1,20 +1,37 @@
-use ed25519_dalek::PublicKey;
-use log::info;
+/* Peer discovery: nodes advertise their name and public key on the init channel until
+   each has heard from the expected number of peers. Advertisements are signed with the
+   advertised key, over the advertisement and the deployment's chain id (the signature
+   travels in the message's signatures), and receivers drop any that don't verify (see
+   PeerAdvertisement::verify) before taking on the key. That proves whoever advertised
+   holds the key, not that the name is theirs: a deployment that must pin names to keys
+   hands its nodes the roster (a genesis document or a static roster; see genesis and
+   static_roster), and nodes ignore advertisements that don't match it. */
+
+use log::{info, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::time::SystemTime;
 use std::{collections::HashMap};
 
 use super::NetworkStack;
+use crate::blockchain::ChainId;
 use crate::messages::{Message, MessageKind, MessagePayload};
+use crate::utils::clock;
+use crate::utils::crypto::{PublicKey, Signature, Signer};
+
+// Domain separator, so an advertisement signature can't pass for anything else
+const ADVERTISEMENT_CONTEXT: &[u8] = b"streamlet advertisement v1";
 
-#[derive(Debug)]
 pub struct Peers {
     pub node_name: String,
     pub node_id: u32,
     pub public_key: PublicKey,
     pub peer_list: HashMap<String, PublicKey>,
     num_expected: usize,
+    observer: bool,
+    signer: Arc<dyn Signer + Send + Sync>, // signs our advertisements
+    chain_id: ChainId,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -27,20 +44,44 @@ pub struct PeerAdvertisement {
     known_peers: Vec<String>,
 }
 
+impl PeerAdvertisement {
+    /* What an advertisement's signature covers: the advertisement, for one deployment. */
+    fn signed_bytes(&self, chain_id: &ChainId) -> Vec<u8> {
+        let mut bytes = ADVERTISEMENT_CONTEXT.to_vec();
+        bytes.extend(bincode::serialize(self).expect("Failed serialization."));
+        chain_id.bind(&bytes)
+    }
+
+    /* Whether one of the signatures is the advertised key's, on this advertisement.
+    @param signatures: the signatures on the message that carried it
+    @param chain_id: this deployment's chain id */
+    pub fn verify(&self, signatures: &[Signature], chain_id: &ChainId) -> bool {
+        let bytes = self.signed_bytes(chain_id);
+        signatures.iter().any(|signature| self.public_key.verify(&bytes, signature).is_ok())
+    }
+
+    /* Whether this is the end of discovery (see Peers::send_end_init), not a peer. */
+    pub fn is_end_init(&self) -> bool {
+        self.end_init
+    }
+}
+
 pub enum InitStatus {
     InProgress,
     Done,
     DoneStartTimer,
+    LateJoin, // a peer we hadn't heard from advertised after our discovery ended
 }
 
 impl Peers {
     /* Initializer:
         @param my_name: identifying "name" of this node
             (if empty string, name will be generated from random 32-bit number)
-        @param public_key: public key belonging to the owning StreamletInstance
+        @param signer: key belonging to the owning StreamletInstance, which signs our advertisements
+        @param chain_id: the deployment's chain id
     */
-    pub fn new(mut my_name: String, public_key: PublicKey, num_peers: usize) -> Self {
-        if my_name == "" {
+    pub fn new(mut my_name: String, signer: Arc<dyn Signer + Send + Sync>, chain_id: ChainId, num_peers: usize) -> Self {
+        if my_name.is_empty() {
             let rand: u32 = rand::thread_rng().gen();
             my_name = format!("{}", rand).to_string();
         }
@@ -48,12 +89,21 @@ impl Peers {
         Self {
             node_name: my_name,
             node_id: 0,
-            public_key: public_key,
+            public_key: signer.public(),
             peer_list: HashMap::new(),
             num_expected: num_peers,
+            observer: false,
+            signer,
+            chain_id,
         }
     }
 
+    /* Joins as an observer: our advertisements say so (MessageKind::ObserverInit), so
+    validators answer them without counting us, and we don't answer theirs. */
+    pub fn set_observer(&mut self) {
+        self.observer = true;
+    }
+
     /* Set the peer id with the result of the peer init process.
     @param new_node_id: node id chosen based off of peer init process */
     pub fn set_node_id(&mut self, new_node_id: u32) {
@@ -64,6 +114,8 @@ impl Peers {
     Inserts the peer into the hashmap if not already present.
     Protocol will accept first public key received for a peer.
     Closes the initialization channel if all peers have been received.
+    After that, peers that start late (or restart) still get an answer, so they learn
+    our key (see recv_late_advertisement).
     @param ad: PeerAdvertisement received from the network
     @param net_stack: network stack containing an initialization channel to send on. */
     pub fn recv_advertisement(
@@ -75,14 +127,18 @@ impl Peers {
             self.end_init(net_stack);
             return InitStatus::Done;
         }
-        if self.is_done() || self.peer_list.contains_key(&ad.node_name) {
+        if self.is_done() {
+            return self.recv_late_advertisement(ad, net_stack);
+        }
+        if self.peer_list.contains_key(&ad.node_name) {
             return InitStatus::Done;
         }
         info!("{} adding peer: {}", self.node_name, ad.node_name);
         self.peer_list.insert(ad.node_name.clone(), ad.public_key);
 
-        if !ad.known_peers.contains(&self.node_name) {
-            self.advertise_self(net_stack);
+        // Only the advertiser is missing us (anyone else who is advertises too), so answer it alone
+        if !self.observer && !ad.known_peers.contains(&self.node_name) {
+            self.answer(ad, net_stack);
         }
 
         if self.is_done() && net_stack.init_channel_open() {
@@ -94,7 +150,45 @@ impl Peers {
             return InitStatus::DoneStartTimer;
         }
 
-        return InitStatus::InProgress;
+        InitStatus::InProgress
+    }
+
+    /* An advertisement that arrived after our discovery ended: from a peer that started
+    late, restarted, or missed our advertisement. Whether it is a validator is up to
+    the caller; here it is only answered (if it doesn't know us yet) and remembered.
+    @param ad: PeerAdvertisement received from the network
+    @param net_stack: network stack to answer on */
+    fn recv_late_advertisement(&mut self, ad: &PeerAdvertisement, net_stack: &mut NetworkStack) -> InitStatus {
+        if !self.observer && !ad.known_peers.contains(&self.node_name) {
+            self.answer(ad, net_stack);
+        }
+        if self.peer_list.contains_key(&ad.node_name) {
+            return InitStatus::Done;
+        }
+        info!("{} adding late peer: {}", self.node_name, ad.node_name);
+        self.peer_list.insert(ad.node_name.clone(), ad.public_key);
+        InitStatus::LateJoin
+    }
+
+    /* Tells an observer who we are, in reply to its advertisement. It isn't added to
+    our peers: observers don't count towards discovery.
+    @param ad: the observer's advertisement
+    @param net_stack: network stack to answer on */
+    pub fn answer_observer(&mut self, ad: &PeerAdvertisement, net_stack: &mut NetworkStack) {
+        self.answer(ad, net_stack);
+    }
+
+    // Sends our advertisement to the advertiser alone, if we can reach it directly
+    fn answer(&mut self, ad: &PeerAdvertisement, net_stack: &mut NetworkStack) {
+        match net_stack.peer_of(&ad.node_name) {
+            Some(peer) => {
+                let message = self.advertisement();
+                if let Err(e) = net_stack.send_to_peer(&peer, message.serialize()) {
+                    warn!("Couldn't answer {}'s advertisement: {}", ad.node_name, e);
+                }
+            }
+            None => self.advertise_self(net_stack),
+        }
     }
 
     /* If all expected advertisements have been received. */
@@ -107,17 +201,11 @@ impl Peers {
             end_init: true,
             node_name: String::new(),
             node_id: self.node_id,
-            timestamp: SystemTime::now(),
+            timestamp: clock::system_time(),
             public_key: self.public_key,
             known_peers: Vec::new(),
         };
-        let message = Message::new(
-            MessagePayload::PeerAdvertisement(my_ad),
-            MessageKind::PeerInit,
-            self.node_id,
-            self.node_name.clone(),
-        );
-
+        let message = self.signed(my_ad, MessageKind::PeerInit);
         net_stack.send_init_channel(message.serialize());
         self.end_init(net_stack);
     }
@@ -144,28 +232,60 @@ impl Peers {
     #[allow(dead_code)]
     pub fn permanently_delete_peer(&mut self, name: String) {
         // If value was in the map, expected count should go down
-        if let Some(_) = self.peer_list.remove(&name) {
+        if self.peer_list.remove(&name).is_some() {
             self.num_expected -= 1;
         }
     }
 
     pub fn advertise_self(&mut self, net_stack: &mut NetworkStack) {
+        net_stack.send_init_channel(self.advertisement().serialize());
+    }
+
+    fn advertisement(&self) -> Message {
         let my_ad = PeerAdvertisement {
             end_init: false,
             node_name: self.node_name.clone(),
             node_id: self.node_id,
-            timestamp: SystemTime::now(),
+            timestamp: clock::system_time(),
             public_key: self.public_key,
             known_peers: Vec::from_iter(self.peer_list.keys().cloned()),
         };
 
-        let message = Message::new(
-            MessagePayload::PeerAdvertisement(my_ad),
-            MessageKind::PeerInit,
-            self.node_id,
-            self.node_name.clone(),
-        );
+        let kind = if self.observer { MessageKind::ObserverInit } else { MessageKind::PeerInit };
+        self.signed(my_ad, kind)
+    }
 
-        net_stack.send_init_channel(message.serialize());
+    // Wraps an advertisement in a message, signed with our key
+    fn signed(&self, ad: PeerAdvertisement, kind: MessageKind) -> Message {
+        let signature = self.signer.sign(&ad.signed_bytes(&self.chain_id));
+        let mut message = Message::new(MessagePayload::PeerAdvertisement(ad), kind, self.node_id, self.node_name.clone());
+        message.sign_message(signature);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::crypto::{Keypair, SignatureScheme};
+
+    #[test]
+    fn test_advertisements_are_signed_by_their_key() {
+        let chain_id = ChainId::default();
+        let peers = Peers::new(String::from("v1"), Arc::new(Keypair::generate(SignatureScheme::Ed25519)), chain_id, 2);
+        let message = peers.advertisement();
+        let ad = match &message.payload {
+            MessagePayload::PeerAdvertisement(ad) => ad.clone(),
+            _ => panic!("advertisements carry a PeerAdvertisement"),
+        };
+        assert!(ad.verify(&message.signatures, &chain_id));
+        assert!(!ad.verify(&[], &chain_id));
+        assert!(!ad.verify(&message.signatures, &ChainId::new("other")));
+
+        // Someone else's key, or name, under our signature doesn't verify
+        let forged = PeerAdvertisement { public_key: Keypair::generate(SignatureScheme::Ed25519).public(), ..ad.clone() };
+        assert!(!forged.verify(&message.signatures, &chain_id));
+        let renamed = PeerAdvertisement { node_name: String::from("v2"), ..ad };
+        assert!(!renamed.verify(&message.signatures, &chain_id));
     }
 }
@@ -0,0 +1,143 @@
+/* Peer scoring: keeps misbehaving peers out.
+   The application knows when a peer misbehaves (a message with signatures no validator
+   made, a flood of messages) and the network doesn't, so the application reports it
+   (NetworkStack::report_peer); the network reports what it sees itself, frames it can't
+   decode. Each report adds its severity's penalty to the peer's score, and the score
+   drains by one point every DECAY_INTERVAL, so honest peers that trip up now and then
+   (a message garbled in transit, a burst while catching up) never add up to a ban.
+   A peer whose score reaches BAN_SCORE is banned for BAN_DURATION: its connections are
+   closed, new ones refused, and its gossip ignored. Once the ban ends it starts over
+   with a clean score.
+   Peers are known by libp2p peer id, which a node generates at startup, so a banned
+   node that restarts comes back under a new id; banning buys time, it doesn't exclude
+   a validator (the signatures it sends are checked either way). */
+
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Score at which a peer is banned
+pub const BAN_SCORE: u32 = 100;
+// How long a ban lasts
+pub const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+// A peer's score drops by one point per interval
+const DECAY_INTERVAL: Duration = Duration::from_secs(10);
+// Most peers with a score we keep; reports about others are dropped until some decay to zero
+const MAX_SCORED_PEERS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PeerSeverity {
+    Minor, // e.g. an undecodable message or going over a budget: may be an accident
+    Major, // e.g. signatures that no validator made: not an accident
+    Fatal, // proof of malice: ban at once
+}
+
+impl PeerSeverity {
+    fn penalty(self) -> u32 {
+        match self {
+            PeerSeverity::Minor => 5,
+            PeerSeverity::Major => 25,
+            PeerSeverity::Fatal => BAN_SCORE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    points: u32,
+    updated: Instant, // when the points were last decayed
+}
+
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    scores: HashMap<PeerId, Score>,
+    bans: HashMap<PeerId, Instant>, // when each ban ends
+}
+
+impl PeerScores {
+    /* The peer's score now, after decay. */
+    pub fn score(&self, peer: &PeerId, now: Instant) -> u32 {
+        self.scores.get(peer).map(|score| decayed(score, now)).unwrap_or(0)
+    }
+
+    /* Adds a report to the peer's score. Returns true if this got the peer banned.
+    @param peer: the peer that misbehaved
+    @param severity: how badly
+    @param now: the current time */
+    pub fn report(&mut self, peer: PeerId, severity: PeerSeverity, now: Instant) -> bool {
+        if self.is_banned(&peer, now) {
+            return false;
+        }
+        if self.scores.len() >= MAX_SCORED_PEERS && !self.scores.contains_key(&peer) {
+            self.scores.retain(|_, score| decayed(score, now) > 0);
+            if self.scores.len() >= MAX_SCORED_PEERS {
+                return false;
+            }
+        }
+        let score = self.scores.entry(peer).or_insert(Score { points: 0, updated: now });
+        score.points = decayed(score, now).saturating_add(severity.penalty());
+        score.updated = now;
+        if score.points < BAN_SCORE {
+            return false;
+        }
+        self.scores.remove(&peer);
+        self.bans.insert(peer, now + BAN_DURATION);
+        true
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, now: Instant) -> bool {
+        self.bans.get(peer).is_some_and(|until| *until > now)
+    }
+
+    /* Forgets bans that have ended, and returns those peers (to let back in). */
+    pub fn expire_bans(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self.bans.iter().filter(|(_, until)| **until <= now).map(|(peer, _)| *peer).collect();
+        for peer in &expired {
+            self.bans.remove(peer);
+        }
+        expired
+    }
+
+    /* Peers banned now. */
+    pub fn banned(&self, now: Instant) -> Vec<PeerId> {
+        self.bans.iter().filter(|(_, until)| **until > now).map(|(peer, _)| *peer).collect()
+    }
+}
+
+fn decayed(score: &Score, now: Instant) -> u32 {
+    let intervals = now.saturating_duration_since(score.updated).as_secs() / DECAY_INTERVAL.as_secs();
+    score.points.saturating_sub(intervals.min(u32::MAX as u64) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores_decay_and_bans_expire() {
+        let mut scores = PeerScores::default();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        // Three invalid signatures in a row are forgiven after a while...
+        for _ in 0..3 {
+            assert!(!scores.report(peer, PeerSeverity::Major, start));
+        }
+        assert_eq!(scores.score(&peer, start), 75);
+        let later = start + DECAY_INTERVAL * 50;
+        assert_eq!(scores.score(&peer, later), 25);
+        assert!(!scores.report(peer, PeerSeverity::Major, later));
+        assert!(!scores.report(peer, PeerSeverity::Major, later));
+        // ...but not a steady stream of them
+        assert!(scores.report(peer, PeerSeverity::Major, later));
+        assert!(scores.is_banned(&peer, later));
+        assert!(!scores.report(peer, PeerSeverity::Fatal, later));
+        assert_eq!(scores.banned(later), vec![peer]);
+
+        assert!(scores.report(other, PeerSeverity::Fatal, start));
+        assert!(!scores.is_banned(&PeerId::random(), start));
+        assert_eq!(scores.expire_bans(start + BAN_DURATION), vec![other]);
+        assert_eq!(scores.expire_bans(later + BAN_DURATION), vec![peer]);
+        assert!(!scores.is_banned(&peer, later + BAN_DURATION));
+        assert_eq!(scores.score(&peer, later + BAN_DURATION), 0);
+    }
+}
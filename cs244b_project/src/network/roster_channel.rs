@@ -0,0 +1,111 @@
+/* Optional validators-only coordination channel (e.g. planned maintenance announcements).
+   Messages on it are encrypted under a key derived from the roster (every node's name
+   and public key) and a secret shared out of band by the operators, so observers on the
+   public topics can't read them. The key changes whenever the roster does. */
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::crypto::*;
+
+pub const ROSTER_TOPIC: &str = "roster";
+const KEY_INFO: &[u8] = b"streamlet roster channel v1";
+
+// Ciphertext plus what a receiver needs to decrypt it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SealedEnvelope {
+    pub key_id: [u8; 8], // prefix of the roster digest the key was derived from
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+pub struct RosterChannel {
+    secret: Vec<u8>,
+    // (key id, key) for the current roster; None until the roster is known
+    key: Option<([u8; 8], Key)>,
+}
+
+impl RosterChannel {
+    /* @param secret: operator-provided secret shared by all validators */
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self { secret, key: None }
+    }
+
+    /* Derives the key for a (new) roster.
+    @param roster: every validator's (name, public key), in any order */
+    pub fn rekey(&mut self, roster: &[(String, PublicKey)]) {
+        let mut members: Vec<&(String, PublicKey)> = roster.iter().collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut hasher = Sha256::new();
+        for (name, pk) in members {
+            hasher.update(bincode::serialize(name).expect("Failed serialization."));
+            hasher.update(bincode::serialize(pk).expect("Failed serialization."));
+        }
+        let digest: Sha256Hash = hasher.finalize().into();
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&digest), &self.secret)
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        let key_id: [u8; 8] = digest[..8].try_into().expect("8-byte slice");
+        self.key = Some((key_id, *Key::from_slice(&key)));
+    }
+
+    /* Encrypts a message for the current roster (None if the roster isn't known yet). */
+    pub fn seal(&self, plaintext: &[u8]) -> Option<SealedEnvelope> {
+        let (key_id, key) = self.key.as_ref()?;
+        let nonce: [u8; 12] = rand::thread_rng().gen();
+        let ciphertext = ChaCha20Poly1305::new(key)
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: key_id })
+            .ok()?;
+        Some(SealedEnvelope { key_id: *key_id, nonce, ciphertext })
+    }
+
+    /* Decrypts a message. Fails if it was sealed for a different roster or secret,
+    or was tampered with. */
+    pub fn open(&self, envelope: &SealedEnvelope) -> Option<Vec<u8>> {
+        let (key_id, key) = self.key.as_ref()?;
+        if *key_id != envelope.key_id {
+            return None;
+        }
+        ChaCha20Poly1305::new(key)
+            .decrypt(
+                Nonce::from_slice(&envelope.nonce),
+                Payload { msg: &envelope.ciphertext, aad: key_id },
+            )
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_per_roster() {
+        let roster: Vec<(String, PublicKey)> = ["h1", "h2"]
+            .iter()
+            .map(|name| (name.to_string(), Keypair::generate(SignatureScheme::default()).public()))
+            .collect();
+        let mut alice = RosterChannel::new(b"secret".to_vec());
+        let mut bob = RosterChannel::new(b"secret".to_vec());
+        let mut outsider = RosterChannel::new(b"guess".to_vec());
+        assert!(alice.seal(b"x").is_none());
+        alice.rekey(&roster);
+        bob.rekey(&roster.iter().rev().cloned().collect::<Vec<_>>());
+        outsider.rekey(&roster);
+
+        let envelope = alice.seal(b"maintenance at epoch 100").unwrap();
+        assert_eq!(bob.open(&envelope), Some(b"maintenance at epoch 100".to_vec()));
+        assert_eq!(outsider.open(&envelope), None);
+
+        // After a reconfiguration, old envelopes no longer open
+        let mut new_roster = roster.clone();
+        new_roster.push((String::from("h3"), Keypair::generate(SignatureScheme::default()).public()));
+        bob.rekey(&new_roster);
+        assert_eq!(bob.open(&envelope), None);
+    }
+}
@@ -0,0 +1,300 @@
+/* Validator performance reports: an accountability record of this node's participation.
+   The node keeps a running record of each epoch it was up for (whether it led, whether
+   its proposal was notarized, whether it voted) and of the inclusion promises it made
+   (kept within the maximum merge delay, or missed). A report sums that record over the
+   last day or week and is signed with the node's key under the deployment's chain id,
+   so it can be published to the log's community and checked by anyone who knows the
+   key. Uptime is the share of the period's epochs the node was running for; a node
+   that started part-way through a period reports that honestly. */
+
+use crate::blockchain::ChainId;
+use crate::json_schema;
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const REPORT_CONTEXT: &[u8] = b"streamlet performance report v1";
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReportPeriod {
+    Daily,
+    Weekly,
+}
+
+impl ReportPeriod {
+    pub fn duration(&self) -> Duration {
+        match self {
+            ReportPeriod::Daily => Duration::from_millis(DAY_MS),
+            ReportPeriod::Weekly => Duration::from_millis(7 * DAY_MS),
+        }
+    }
+}
+
+impl fmt::Display for ReportPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReportPeriod::Daily => write!(f, "daily"),
+            ReportPeriod::Weekly => write!(f, "weekly"),
+        }
+    }
+}
+
+impl FromStr for ReportPeriod {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "daily" | "day" => Ok(ReportPeriod::Daily),
+            "weekly" | "week" => Ok(ReportPeriod::Weekly),
+            other => Err(format!("unknown report period: {} (expected daily or weekly)", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct EpochRecord {
+    epoch: u64,
+    start_ms: u64,
+    led: bool,
+    proposal_notarized: bool,
+    voted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PromiseOutcome {
+    Made,
+    Kept,
+    Missed,
+}
+
+/* Running record of this node's participation, kept for the longest report period. */
+#[derive(Debug, Default)]
+pub struct PerformanceLog {
+    epochs: VecDeque<EpochRecord>,
+    promises: VecDeque<(u64, PromiseOutcome)>, // (time in ms, what happened)
+}
+
+impl PerformanceLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* An epoch started while we were up.
+    @param epoch: the epoch
+    @param now_ms: the current time */
+    pub fn epoch_started(&mut self, epoch: u64, now_ms: u64) {
+        self.epochs.push_back(EpochRecord { epoch, start_ms: now_ms, ..EpochRecord::default() });
+        let horizon = now_ms.saturating_sub(ReportPeriod::Weekly.duration().as_millis() as u64);
+        while self.epochs.front().is_some_and(|record| record.start_ms < horizon) {
+            self.epochs.pop_front();
+        }
+        while self.promises.front().is_some_and(|(at, _)| *at < horizon) {
+            self.promises.pop_front();
+        }
+    }
+
+    fn record_for(&mut self, epoch: u64) -> Option<&mut EpochRecord> {
+        self.epochs.iter_mut().rev().find(|record| record.epoch == epoch)
+    }
+
+    /* We were the leader of an epoch. */
+    pub fn led(&mut self, epoch: u64) {
+        if let Some(record) = self.record_for(epoch) {
+            record.led = true;
+        }
+    }
+
+    /* A block we proposed was notarized.
+    @param epoch: the block's epoch */
+    pub fn proposal_notarized(&mut self, epoch: u64) {
+        if let Some(record) = self.record_for(epoch) {
+            record.proposal_notarized = true;
+        }
+    }
+
+    /* We voted in an epoch (a leader's proposal counts as its vote). */
+    pub fn voted(&mut self, epoch: u64) {
+        if let Some(record) = self.record_for(epoch) {
+            record.voted = true;
+        }
+    }
+
+    /* We promised to include an entry within the maximum merge delay. */
+    pub fn promise_made(&mut self, now_ms: u64) {
+        self.promises.push_back((now_ms, PromiseOutcome::Made));
+    }
+
+    /* A promised entry was finalized in time (kept), or its deadline passed (missed). */
+    pub fn promise_settled(&mut self, kept: bool, now_ms: u64) {
+        let outcome = if kept { PromiseOutcome::Kept } else { PromiseOutcome::Missed };
+        self.promises.push_back((now_ms, outcome));
+    }
+
+    /* Sums the record over the period ending now (unsigned).
+    @param validator: this node's name
+    @param period: day or week
+    @param now_ms: the current time
+    @param epoch_length: the deployment's epoch length */
+    pub fn report(&self, validator: String, period: ReportPeriod, now_ms: u64, epoch_length: Duration) -> PerformanceReport {
+        let start_ms = now_ms.saturating_sub(period.duration().as_millis() as u64);
+        let epochs: Vec<&EpochRecord> = self.epochs.iter().filter(|record| record.start_ms >= start_ms).collect();
+        let promises = self.promises.iter().filter(|(at, _)| *at >= start_ms);
+        let count = |outcome: PromiseOutcome| promises.clone().filter(|(_, o)| *o == outcome).count() as u64;
+        PerformanceReport {
+            validator,
+            period,
+            start_ms,
+            end_ms: now_ms,
+            first_epoch: epochs.first().map(|record| record.epoch),
+            last_epoch: epochs.last().map(|record| record.epoch),
+            epochs_expected: period.duration().as_millis() as u64 / (epoch_length.as_millis() as u64).max(1),
+            epochs_up: epochs.len() as u64,
+            epochs_led: epochs.iter().filter(|record| record.led).count() as u64,
+            proposals_notarized: epochs.iter().filter(|record| record.proposal_notarized).count() as u64,
+            votes_cast: epochs.iter().filter(|record| record.voted).count() as u64,
+            promises_made: count(PromiseOutcome::Made),
+            promises_kept: count(PromiseOutcome::Kept),
+            promises_missed: count(PromiseOutcome::Missed),
+            signature: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceReport {
+    pub validator: String,
+    pub period: ReportPeriod,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub first_epoch: Option<u64>,
+    pub last_epoch: Option<u64>,
+    pub epochs_expected: u64, // epochs in the period, at the deployment's epoch length
+    pub epochs_up: u64,       // epochs this node was running for
+    pub epochs_led: u64,
+    pub proposals_notarized: u64,
+    pub votes_cast: u64,
+    pub promises_made: u64,
+    pub promises_kept: u64,   // finalized within the maximum merge delay
+    pub promises_missed: u64, // merge delay passed first
+    pub signature: Option<Signature>, // by the validator
+}
+
+impl PerformanceReport {
+    /* Share of the period's epochs the node was up for, in percent. */
+    pub fn uptime_percent(&self) -> f64 {
+        if self.epochs_expected == 0 {
+            return 0.0;
+        }
+        (self.epochs_up as f64 / self.epochs_expected as f64 * 100.0).min(100.0)
+    }
+
+    /* Share of settled promises that were kept, in percent (None: nothing settled). */
+    pub fn merge_delay_compliance_percent(&self) -> Option<f64> {
+        let settled = self.promises_kept + self.promises_missed;
+        (settled > 0).then(|| self.promises_kept as f64 / settled as f64 * 100.0)
+    }
+
+    // Everything but the signature, under the chain id
+    fn signed_bytes(&self, chain_id: &ChainId) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = None;
+        let mut bytes = REPORT_CONTEXT.to_vec();
+        bytes.extend_from_slice(&chain_id.0);
+        bytes.extend(bincode::serialize(&unsigned).expect("Failed serialization."));
+        bytes
+    }
+
+    /* Signs the report with the validator's key.
+    @param keypair: this node's signing key (see Signer)
+    @param chain_id: the deployment's chain id */
+    pub fn sign(mut self, keypair: &dyn Signer, chain_id: &ChainId) -> Self {
+        self.signature = Some(keypair.sign(&self.signed_bytes(chain_id)));
+        self
+    }
+
+    pub fn verify(&self, public_key: &dyn Verifier, chain_id: &ChainId) -> bool {
+        match &self.signature {
+            Some(signature) => public_key.verify(&self.signed_bytes(chain_id), signature).is_ok(),
+            None => false,
+        }
+    }
+
+    /* The report for publishing: the figures, the signed report (hex), and the key it
+    verifies under.
+    @param public_key: the validator's key */
+    pub fn to_json(&self, public_key: &PublicKey, chain_id: &ChainId) -> Value {
+        json!({
+            json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION,
+            "validator": self.validator,
+            "period": self.period.to_string(),
+            "chain_id": chain_id.to_string(),
+            "start_ms": self.start_ms,
+            "end_ms": self.end_ms,
+            "first_epoch": self.first_epoch,
+            "last_epoch": self.last_epoch,
+            "epochs_led": self.epochs_led,
+            "proposals_notarized": self.proposals_notarized,
+            "votes_cast": self.votes_cast,
+            "epochs_up": self.epochs_up,
+            "epochs_expected": self.epochs_expected,
+            "uptime_percent": self.uptime_percent(),
+            "promises_made": self.promises_made,
+            "promises_kept": self.promises_kept,
+            "promises_missed": self.promises_missed,
+            "merge_delay_compliance_percent": self.merge_delay_compliance_percent(),
+            "signed_report": hex::encode(bincode::serialize(self).expect("Failed serialization.")),
+            "public_key": hex::encode(bincode::serialize(public_key).expect("Failed serialization.")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_sums_the_period_and_verifies() {
+        let epoch_length = Duration::from_secs(3600);
+        let mut log = PerformanceLog::new();
+        // Up for the last 12 hours of a day; led every 4th epoch
+        let day_start = 10 * DAY_MS;
+        for epoch in 12..24 {
+            let now = day_start + epoch * 3_600_000;
+            log.epoch_started(epoch, now);
+            log.voted(epoch);
+            if epoch % 4 == 0 {
+                log.led(epoch);
+                if epoch != 20 {
+                    log.proposal_notarized(epoch);
+                }
+            }
+            log.promise_made(now);
+            log.promise_settled(epoch != 23, now + 1);
+        }
+        let end = day_start + DAY_MS;
+        let report = log.report(String::from("h1"), ReportPeriod::Daily, end, epoch_length);
+        assert_eq!((report.first_epoch, report.last_epoch), (Some(12), Some(23)));
+        assert_eq!((report.epochs_up, report.epochs_expected), (12, 24));
+        assert_eq!(report.uptime_percent(), 50.0);
+        assert_eq!((report.epochs_led, report.proposals_notarized, report.votes_cast), (3, 2, 12));
+        assert_eq!((report.promises_made, report.promises_kept, report.promises_missed), (12, 11, 1));
+
+        // Only the period counts
+        let later = log.report(String::from("h1"), ReportPeriod::Daily, end + DAY_MS * 3 / 4, epoch_length);
+        assert_eq!(later.epochs_up, 6);
+
+        let keypair = Keypair::generate(SignatureScheme::default());
+        let chain_id = ChainId::default();
+        let signed = report.sign(&keypair, &chain_id);
+        assert!(signed.verify(&keypair.public(), &chain_id));
+        assert!(!signed.verify(&keypair.public(), &ChainId::new("test")));
+        let mut inflated = signed.clone();
+        inflated.votes_cast += 1;
+        assert!(!inflated.verify(&keypair.public(), &chain_id));
+        assert_eq!("weekly".parse::<ReportPeriod>(), Ok(ReportPeriod::Weekly));
+    }
+}
@@ -0,0 +1,140 @@
+/* Quarantine for messages that can't be checked yet.
+   Some messages fail verification only because this node is missing something, not
+   because they are invalid: a vote signed by a validator whose key hasn't reached us
+   yet (e.g. during peer discovery), or a proposal extending a block we haven't seen
+   notarized (its Notarize message is still on the way). Rather than dropping these,
+   the node parks them here and replays them once the missing piece arrives: a new
+   key (add_public_key) or a newly notarized block. The queue is bounded, and messages
+   that wait too long are dropped, so junk can't pile up. */
+
+use crate::messages::Message;
+use crate::Sha256Hash;
+use std::collections::VecDeque;
+
+// Most messages parked at once; the oldest is dropped to make room
+pub const QUARANTINE_CAPACITY: usize = 256;
+// Epochs a message may stay parked
+pub const QUARANTINE_EPOCHS: u64 = 3;
+
+/* What a parked message is waiting for. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Missing {
+    Key,               // a signer's public key
+    Block(Sha256Hash), // a notarized block (the parent of the one in the message)
+}
+
+#[derive(Debug)]
+struct Parked {
+    message: Message,
+    missing: Missing,
+    epoch: u64,
+}
+
+#[derive(Debug)]
+pub struct Quarantine {
+    capacity: usize,
+    parked: VecDeque<Parked>,
+    // Released messages, waiting to be processed again
+    ready: VecDeque<Message>,
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Quarantine::new(QUARANTINE_CAPACITY)
+    }
+}
+
+impl Quarantine {
+    /* @param capacity: most messages parked at once */
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), parked: VecDeque::new(), ready: VecDeque::new() }
+    }
+
+    /* Parks a message until what it is missing arrives. Returns false if the same
+    message is already parked.
+    @param message: the message
+    @param missing: what it is waiting for
+    @param epoch: the current epoch */
+    pub fn park(&mut self, message: Message, missing: Missing, epoch: u64) -> bool {
+        if self.parked.iter().any(|parked| parked.message == message) {
+            return false;
+        }
+        if self.parked.len() == self.capacity {
+            self.parked.pop_front();
+        }
+        self.parked.push_back(Parked { message, missing, epoch });
+        true
+    }
+
+    /* A public key was added: releases every message waiting for one. */
+    pub fn key_added(&mut self) {
+        self.release(|missing| *missing == Missing::Key);
+    }
+
+    /* Releases the messages waiting for blocks that are now notarized.
+    @param is_notarized: whether a block is notarized */
+    pub fn blocks_arrived<F: Fn(&Sha256Hash) -> bool>(&mut self, is_notarized: F) {
+        self.release(|missing| matches!(missing, Missing::Block(hash) if is_notarized(hash)));
+    }
+
+    fn release<F: Fn(&Missing) -> bool>(&mut self, matches: F) {
+        let (released, kept): (VecDeque<Parked>, VecDeque<Parked>) =
+            self.parked.drain(..).partition(|parked| matches(&parked.missing));
+        self.parked = kept;
+        self.ready.extend(released.into_iter().map(|parked| parked.message));
+    }
+
+    /* The next released message to process again, if any. */
+    pub fn next_ready(&mut self) -> Option<Message> {
+        self.ready.pop_front()
+    }
+
+    /* Drops messages that have waited too long.
+    @param current_epoch: the epoch that just started */
+    pub fn prune(&mut self, current_epoch: u64) {
+        self.parked.retain(|parked| parked.epoch + QUARANTINE_EPOCHS >= current_epoch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::messages::{MessageKind, MessagePayload};
+
+    fn message(nonce: u32) -> Message {
+        let block = MessagePayload::Block(Block::new(1, [0; 32], b"entry".to_vec(), 1, 0));
+        Message::new_with_defined_nonce(block, MessageKind::Vote, nonce, 1, String::from("h1"))
+    }
+
+    #[test]
+    fn test_parked_messages_are_released_by_what_they_miss() {
+        let mut quarantine = Quarantine::new(3);
+        let first = message(1);
+        assert!(quarantine.park(first.clone(), Missing::Key, 1));
+        assert!(!quarantine.park(first, Missing::Key, 1));
+        quarantine.park(message(2), Missing::Block([2; 32]), 1);
+        quarantine.park(message(3), Missing::Block([3; 32]), 2);
+
+        quarantine.blocks_arrived(|hash| *hash == [3; 32]);
+        assert_eq!(quarantine.next_ready().map(|m| m.nonce), Some(3));
+        assert_eq!(quarantine.next_ready(), None);
+        quarantine.key_added();
+        assert_eq!(quarantine.next_ready().map(|m| m.nonce), Some(1));
+        assert_eq!(quarantine.parked.len(), 1);
+
+        // Bounded: the oldest message makes room
+        quarantine.park(message(4), Missing::Key, 2);
+        quarantine.park(message(5), Missing::Key, 5);
+        assert!(quarantine.park(message(6), Missing::Key, 5));
+        assert_eq!(quarantine.parked.len(), 3);
+        quarantine.blocks_arrived(|_| true);
+        assert_eq!(quarantine.next_ready(), None);
+
+        // Messages parked too long ago are dropped
+        quarantine.prune(2 + QUARANTINE_EPOCHS + 1);
+        quarantine.key_added();
+        let released: Vec<u32> = std::iter::from_fn(|| quarantine.next_ready()).map(|m| m.nonce).collect();
+        assert_eq!(released, vec![5, 6]);
+    }
+}
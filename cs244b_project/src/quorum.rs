@@ -0,0 +1,62 @@
+/* How many validators' votes notarize a block (see StreamletInstance::set_quorum_rule).
+   - TwoThirds (the default): ⌈2n/3⌉ of the n validators, this node included. Safe as
+     long as fewer than a third of them are Byzantine.
+   - Majority: more than half. For deployments that only have to survive crashes: blocks
+     notarize with fewer votes, but a single misbehaving validator can break safety.
+   All nodes of a deployment must use the same rule (--quorum <two-thirds|majority>). */
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuorumRule {
+    #[default]
+    TwoThirds,
+    Majority,
+}
+
+impl QuorumRule {
+    /* Votes needed to notarize a block.
+    @param validators: number of validators, this node included */
+    pub fn size(&self, validators: usize) -> usize {
+        match self {
+            QuorumRule::TwoThirds => (2 * validators).div_ceil(3),
+            QuorumRule::Majority => validators / 2 + 1,
+        }
+    }
+}
+
+impl FromStr for QuorumRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "two-thirds" => Ok(QuorumRule::TwoThirds),
+            "majority" => Ok(QuorumRule::Majority),
+            _ => Err(format!("unknown quorum rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for QuorumRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuorumRule::TwoThirds => write!(f, "two-thirds"),
+            QuorumRule::Majority => write!(f, "majority"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quorum_sizes() {
+        let sizes: Vec<(usize, usize)> = (1..=7).map(|n| (QuorumRule::TwoThirds.size(n), QuorumRule::Majority.size(n))).collect();
+        assert_eq!(sizes, vec![(1, 1), (2, 2), (2, 2), (3, 3), (4, 3), (4, 4), (5, 4)]);
+        for rule in [QuorumRule::TwoThirds, QuorumRule::Majority] {
+            assert_eq!(rule.to_string().parse::<QuorumRule>(), Ok(rule));
+        }
+        assert!("half".parse::<QuorumRule>().is_err());
+    }
+}
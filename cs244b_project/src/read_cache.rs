@@ -0,0 +1,371 @@
+/* Read cache for nodes serving heavy read traffic over the HTTP API (observers, API
+   front ends): finalized entries, inclusion proofs and consistency proofs, in two tiers.
+   - memory: the most recently used items (LRU), up to memory_items
+   - disk: up to disk_items, in a sled database of its own, apart from the chain store,
+     so serving reads never contends with the validator writing blocks and votes
+   Everything cached is immutable once finalized (an entry, or a proof for a fixed tree
+   size), so nothing is ever invalidated. What is on disk isn't trusted, though: it may
+   be left over from another deployment, from before the node lost its chain, or
+   tampered with. Each item read from disk is checked against the node's own Merkle
+   tree (the leaf, and the proof against the root at its tree size) before it is
+   served; an item that fails is dropped and recomputed. Disk eviction goes by key
+   order rather than recency, which is good enough for a second tier.
+   Monitors poll for fresh proofs as soon as a tree head is out, so the node computes
+   those ahead of time whenever it signs one (see precompute): inclusion proofs for the
+   newest entries against it, and its consistency with the previous tree head. */
+
+use crate::blockchain::{
+    leaf_hash, verify_consistency, verify_leaf_inclusion, AuditPath, ConsistencyProof, EntryId, LogEntry, MerkleTree, StoreError,
+};
+use crate::Sha256Hash;
+use bincode::{deserialize, serialize};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+// Items kept in memory, and on disk, unless configured otherwise
+pub const DEFAULT_MEMORY_ITEMS: usize = 4096;
+pub const DEFAULT_DISK_ITEMS: usize = 1 << 20;
+// Tree roots remembered for checking items read from disk
+const ROOT_MEMO: usize = 64;
+// Newest entries whose inclusion proofs are computed at each tree head (see precompute)
+pub const PRECOMPUTED_PROOFS: u64 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadCacheConfig {
+    pub memory_items: usize,
+    pub disk_items: usize,
+    pub dir: Option<PathBuf>, // None: memory only
+}
+
+impl Default for ReadCacheConfig {
+    fn default() -> Self {
+        Self { memory_items: DEFAULT_MEMORY_ITEMS, disk_items: DEFAULT_DISK_ITEMS, dir: None }
+    }
+}
+
+/* How reads were served since the node started. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub rejected: u64, // read from disk but failed the check
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum CacheKey {
+    Entry(EntryId),
+    Inclusion { leaf: Sha256Hash, tree_size: u64 },
+    Consistency { first: u64, second: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Cached {
+    Entry { leaf_index: u64, data: Vec<u8> },
+    Inclusion(AuditPath),
+    Consistency(ConsistencyProof),
+}
+
+pub struct ReadCache {
+    config: ReadCacheConfig,
+    memory: HashMap<CacheKey, (Cached, u64)>, // item, and when it was last used
+    recency: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    disk: Option<sled::Tree>,
+    disk_len: usize,
+    roots: HashMap<u64, Sha256Hash>,
+    stats: CacheStats,
+}
+
+impl Default for ReadCache {
+    fn default() -> Self {
+        ReadCache::memory_only(ReadCacheConfig::default().memory_items)
+    }
+}
+
+impl ReadCache {
+    fn memory_only(memory_items: usize) -> Self {
+        Self {
+            config: ReadCacheConfig { memory_items, disk_items: 0, dir: None },
+            memory: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            disk: None,
+            disk_len: 0,
+            roots: HashMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /* Opens the cache, with its disk tier (created if missing) if the config names a
+    directory. */
+    pub fn open(config: ReadCacheConfig) -> Result<Self, StoreError> {
+        let mut cache = ReadCache::memory_only(config.memory_items);
+        if let Some(dir) = &config.dir {
+            let tree = sled::open(dir)?.open_tree("reads")?;
+            cache.disk_len = tree.len();
+            cache.disk = Some(tree);
+        }
+        cache.config = config;
+        Ok(cache)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /* Inclusion proof for a leaf against the root at tree_size (see MerkleTree).
+    @param tree: the node's Merkle tree
+    @param leaf: the leaf hash
+    @param tree_size: size of the tree to prove against */
+    pub fn inclusion_proof(&mut self, tree: &MerkleTree, leaf: &Sha256Hash, tree_size: u64) -> Option<AuditPath> {
+        let key = CacheKey::Inclusion { leaf: *leaf, tree_size };
+        let found = self.get_or_compute(key, tree, || {
+            let index = tree.index_of_leaf(leaf)?;
+            tree.prove_inclusion_at(index, tree_size).map(Cached::Inclusion)
+        });
+        match found? {
+            Cached::Inclusion(proof) => Some(proof),
+            _ => None,
+        }
+    }
+
+    /* Consistency proof between the roots at two tree sizes. */
+    pub fn consistency_proof(&mut self, tree: &MerkleTree, first: u64, second: u64) -> Option<ConsistencyProof> {
+        let found = self.get_or_compute(CacheKey::Consistency { first, second }, tree, || {
+            tree.prove_consistency(first, second).map(Cached::Consistency)
+        });
+        match found? {
+            Cached::Consistency(proof) => Some(proof),
+            _ => None,
+        }
+    }
+
+    /* A finalized entry's bytes.
+    @param tree: the node's Merkle tree
+    @param id: the entry's id
+    @param finalized: looks the entry up in the finalized chain, on a miss */
+    pub fn entry<F: FnOnce() -> Option<Vec<u8>>>(&mut self, tree: &MerkleTree, id: EntryId, finalized: F) -> Option<Vec<u8>> {
+        let found = self.get_or_compute(CacheKey::Entry(id), tree, || {
+            let data = finalized()?;
+            Some(Cached::Entry { leaf_index: tree.index_of(&data)?, data })
+        });
+        match found? {
+            Cached::Entry { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    /* Computes proofs monitors ask for right after a tree head, before they ask: inclusion
+    proofs for the newest PRECOMPUTED_PROOFS entries against the new tree size, and
+    consistency with the previous tree head. These don't count as misses.
+    @param tree: the node's Merkle tree
+    @param tree_size: the new tree head's size
+    @param previous_size: the previous tree head's size, if there was one */
+    pub fn precompute(&mut self, tree: &MerkleTree, tree_size: u64, previous_size: Option<u64>) {
+        for index in tree_size.saturating_sub(PRECOMPUTED_PROOFS)..tree_size {
+            if let (Some(leaf), Some(proof)) = (tree.leaf(index), tree.prove_inclusion_at(index, tree_size)) {
+                self.store(CacheKey::Inclusion { leaf, tree_size }, Cached::Inclusion(proof));
+            }
+        }
+        if let Some(first) = previous_size.filter(|first| *first < tree_size) {
+            if let Some(proof) = tree.prove_consistency(first, tree_size) {
+                self.store(CacheKey::Consistency { first, second: tree_size }, Cached::Consistency(proof));
+            }
+        }
+    }
+
+    fn get_or_compute<F: FnOnce() -> Option<Cached>>(&mut self, key: CacheKey, tree: &MerkleTree, compute: F) -> Option<Cached> {
+        if let Some((item, used)) = self.memory.get_mut(&key) {
+            self.recency.remove(used);
+            self.clock += 1;
+            *used = self.clock;
+            self.recency.insert(self.clock, key);
+            self.stats.memory_hits += 1;
+            return Some(item.clone());
+        }
+        if let Some(item) = self.read_disk(&key, tree) {
+            self.stats.disk_hits += 1;
+            self.remember(key, item.clone());
+            return Some(item);
+        }
+        self.stats.misses += 1;
+        let item = compute()?;
+        self.write_disk(&key, &item);
+        self.remember(key, item.clone());
+        Some(item)
+    }
+
+    // Caches an item computed ahead of a request, unless it is in memory already
+    fn store(&mut self, key: CacheKey, item: Cached) {
+        if self.memory.contains_key(&key) {
+            return;
+        }
+        self.write_disk(&key, &item);
+        self.remember(key, item);
+    }
+
+    fn remember(&mut self, key: CacheKey, item: Cached) {
+        if self.config.memory_items == 0 {
+            return;
+        }
+        while self.memory.len() >= self.config.memory_items {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.memory.remove(&oldest),
+                None => break,
+            };
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.memory.insert(key, (item, self.clock));
+    }
+
+    fn read_disk(&mut self, key: &CacheKey, tree: &MerkleTree) -> Option<Cached> {
+        let disk = self.disk.clone()?;
+        let disk_key = serialize(key).expect("Failed serialization.");
+        let value = match disk.get(&disk_key) {
+            Ok(value) => value?,
+            Err(e) => {
+                warn!("Read cache: couldn't read from disk: {}", e);
+                return None;
+            }
+        };
+        match deserialize::<Cached>(&value) {
+            Ok(item) if self.check(key, &item, tree) => Some(item),
+            _ => {
+                self.stats.rejected += 1;
+                if let Ok(Some(_)) = disk.remove(&disk_key) {
+                    self.disk_len = self.disk_len.saturating_sub(1);
+                }
+                None
+            }
+        }
+    }
+
+    fn write_disk(&mut self, key: &CacheKey, item: &Cached) {
+        let disk = match &self.disk {
+            Some(disk) if self.config.disk_items > 0 => disk,
+            _ => return,
+        };
+        if self.disk_len >= self.config.disk_items {
+            if let Ok(Some(_)) = disk.pop_min() {
+                self.disk_len -= 1;
+            }
+        }
+        match disk.insert(serialize(key).expect("Failed serialization."), serialize(item).expect("Failed serialization.")) {
+            Ok(None) => self.disk_len += 1,
+            Ok(Some(_)) => {}
+            Err(e) => warn!("Read cache: couldn't write to disk: {}", e),
+        }
+    }
+
+    // Root of the node's tree at a size, computed once per size
+    fn root_at(&mut self, tree: &MerkleTree, tree_size: u64) -> Option<Sha256Hash> {
+        if let Some(root) = self.roots.get(&tree_size) {
+            return Some(*root);
+        }
+        let root = tree.root_at(tree_size)?;
+        if self.roots.len() >= ROOT_MEMO {
+            self.roots.clear();
+        }
+        self.roots.insert(tree_size, root);
+        Some(root)
+    }
+
+    // Whether an item read from disk agrees with the node's own tree
+    fn check(&mut self, key: &CacheKey, item: &Cached, tree: &MerkleTree) -> bool {
+        match (key, item) {
+            (CacheKey::Entry(id), Cached::Entry { leaf_index, data }) => {
+                tree.leaf(*leaf_index) == Some(leaf_hash(data)) && LogEntry::deserialize(data).is_some_and(|entry| entry.id == *id)
+            }
+            (CacheKey::Inclusion { leaf, tree_size }, Cached::Inclusion(proof)) => {
+                proof.tree_size == *tree_size
+                    && tree.leaf(proof.leaf_index) == Some(*leaf)
+                    && self.root_at(tree, *tree_size).is_some_and(|root| verify_leaf_inclusion(leaf, proof, &root))
+            }
+            (CacheKey::Consistency { first, second }, Cached::Consistency(proof)) => {
+                if (proof.old_size, proof.new_size) != (*first, *second) {
+                    return false;
+                }
+                match (self.root_at(tree, *first), self.root_at(tree, *second)) {
+                    (Some(old_root), Some(new_root)) => verify_consistency(proof, &old_root, &new_root),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_items_are_checked_before_serving() {
+        let dir = std::env::temp_dir().join(format!("streamlet-read-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let entries: Vec<LogEntry> = (0..5u8).map(|i| LogEntry::new(vec![i])).collect();
+        let mut tree = MerkleTree::new();
+        for entry in &entries {
+            tree.push(&entry.serialize());
+        }
+        let leaf = leaf_hash(&entries[2].serialize());
+        let config = ReadCacheConfig { memory_items: 1, disk_items: 8, dir: Some(dir.clone()) };
+        let mut cache = ReadCache::open(config.clone()).unwrap();
+        let proof = cache.inclusion_proof(&tree, &leaf, 5).unwrap();
+        assert_eq!(cache.inclusion_proof(&tree, &leaf, 5), Some(proof.clone()));
+        assert!(cache.consistency_proof(&tree, 2, 5).is_some());
+        let data = entries[1].serialize();
+        assert_eq!(cache.entry(&tree, entries[1].id, || Some(data.clone())), Some(data.clone()));
+        assert_eq!(cache.stats(), CacheStats { memory_hits: 1, disk_hits: 0, misses: 3, rejected: 0 });
+        drop(cache);
+
+        // A restarted node serves them from disk
+        let mut cache = ReadCache::open(config.clone()).unwrap();
+        assert_eq!(cache.inclusion_proof(&tree, &leaf, 5), Some(proof));
+        assert_eq!(cache.entry(&tree, entries[1].id, || None), Some(data));
+        assert_eq!(cache.stats().disk_hits, 2);
+        drop(cache);
+
+        // ... but not against a different log
+        let mut other = MerkleTree::new();
+        for entry in entries.iter().rev() {
+            other.push(&entry.serialize());
+        }
+        let mut cache = ReadCache::open(config).unwrap();
+        assert!(cache.consistency_proof(&other, 2, 5).is_some());
+        assert_eq!(cache.entry(&other, entries[1].id, || None), None);
+        assert_eq!(cache.stats(), CacheStats { memory_hits: 0, disk_hits: 0, misses: 2, rejected: 2 });
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_fresh_proofs_are_precomputed_at_each_tree_head() {
+        let mut tree = MerkleTree::new();
+        for i in 0..20u8 {
+            tree.push(&LogEntry::new(vec![i]).serialize());
+        }
+        let leaf = |index: u64| tree.leaf(index).unwrap();
+        let mut cache = ReadCache::default();
+        cache.precompute(&tree, 20, Some(12));
+        // The newest PRECOMPUTED_PROOFS entries, and consistency with the previous head
+        for index in 20 - PRECOMPUTED_PROOFS..20 {
+            assert_eq!(cache.inclusion_proof(&tree, &leaf(index), 20), tree.prove_inclusion_at(index, 20));
+        }
+        assert_eq!(cache.consistency_proof(&tree, 12, 20), tree.prove_consistency(12, 20));
+        assert_eq!(cache.stats(), CacheStats { memory_hits: PRECOMPUTED_PROOFS + 1, disk_hits: 0, misses: 0, rejected: 0 });
+        // Older entries are computed when asked for
+        assert!(cache.inclusion_proof(&tree, &leaf(3), 20).is_some());
+        assert_eq!(cache.stats().misses, 1);
+
+        // The first tree head has nothing to be consistent with
+        let mut cache = ReadCache::default();
+        cache.precompute(&tree, 20, None);
+        assert!(cache.inclusion_proof(&tree, &leaf(19), 20).is_some());
+        assert!(cache.consistency_proof(&tree, 12, 20).is_some());
+        assert_eq!(cache.stats(), CacheStats { memory_hits: 1, disk_hits: 0, misses: 1, rejected: 0 });
+    }
+}
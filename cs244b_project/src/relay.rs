@@ -0,0 +1,125 @@
+/* Relay: a gossip-only node for scaling the mesh.
+   A relay subscribes to every topic nodes publish on, so gossipsub routes traffic
+   through it (e.g. to bridge validators that can't reach each other directly across
+   NATs or regions), but it takes no part in consensus and keeps no full chain. It
+   remembers only the most recent notarized blocks it sees, with their certificates,
+   and uses them to answer catch-up requests, taking that load off the validators.
+   The relay doesn't check certificates (it doesn't know the validators' keys); nodes
+   catching up check every block they receive anyway (see is_chain_certified). */
+
+use crate::blockchain::{Block, LocalChain, SignedBlock};
+use crate::messages::{ChainSyncRequest, Message, MessageKind, MessagePayload};
+use crate::Sha256Hash;
+use std::collections::{BTreeSet, HashMap};
+
+// Blocks a relay remembers by default
+pub const DEFAULT_RELAY_RETENTION: usize = 256;
+// Cap on the blocks sent in one catch-up response (as for validators)
+const RELAY_SYNC_MAX_BLOCKS: usize = 64;
+
+/* The most recent notarized blocks seen on the network, up to a fixed number. */
+#[derive(Debug)]
+pub struct RecentBlocks {
+    capacity: usize,
+    blocks: HashMap<Sha256Hash, SignedBlock>,
+    by_height: BTreeSet<(u64, Sha256Hash)>,
+}
+
+impl RecentBlocks {
+    /* @param capacity: how many blocks to keep; the lowest ones are forgotten first */
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), blocks: HashMap::new(), by_height: BTreeSet::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /* Remembers the notarized blocks a message carries, if any. */
+    pub fn observe(&mut self, message: &Message) {
+        match (&message.kind, &message.payload) {
+            (MessageKind::Notarize, MessagePayload::Block(block)) | (MessageKind::Finalize, MessagePayload::Block(block)) => {
+                self.insert(SignedBlock::new(block.clone(), message.signatures.clone()));
+            }
+            (MessageKind::SyncResponse, MessagePayload::Chain(chain)) => {
+                for signed_block in &chain.blocks {
+                    self.insert(signed_block.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn insert(&mut self, signed_block: SignedBlock) {
+        let Block { height, hash, .. } = signed_block.block;
+        // A later copy may carry more votes
+        let better = match self.blocks.get(&hash) {
+            Some(known) => signed_block.cert.signatures.len() > known.cert.signatures.len(),
+            None => true,
+        };
+        if better {
+            self.blocks.insert(hash, signed_block);
+            self.by_height.insert((height, hash));
+        }
+        while self.blocks.len() > self.capacity {
+            let lowest = *self.by_height.iter().next().expect("blocks are indexed");
+            self.by_height.remove(&lowest);
+            self.blocks.remove(&lowest.1);
+        }
+    }
+
+    /* A catch-up segment, as a validator would send: from the block at the requested
+    height up the highest chain we know, if we still have all of it.
+    @param request: the peer's catch-up request */
+    pub fn chain_for(&self, request: &ChainSyncRequest) -> Option<LocalChain> {
+        let &(top, tip) = self.by_height.iter().next_back()?;
+        if top <= request.known_height || top <= request.from_height {
+            return None;
+        }
+        // Walk down from the tip to the requested height
+        let mut blocks = vec![self.blocks[&tip].clone()];
+        while blocks.last()?.block.height > request.from_height {
+            let parent = &blocks.last()?.block.parent_hash;
+            blocks.push(self.blocks.get(parent)?.clone());
+        }
+        blocks.reverse();
+        blocks.truncate(RELAY_SYNC_MAX_BLOCKS + 1);
+        Some(LocalChain { blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Chain, LocalChain};
+
+    #[test]
+    fn test_relay_keeps_recent_blocks_and_serves_catch_up() {
+        let genesis = LocalChain::new().blocks[0].block.clone();
+        let mut chain = vec![genesis];
+        for epoch in 1..=5 {
+            let parent = chain.last().unwrap();
+            chain.push(Block::new(epoch, parent.hash, vec![epoch as u8], parent.height + 1, 0));
+        }
+        let mut recent = RecentBlocks::new(4);
+        for block in &chain[1..] {
+            let message = Message::new(MessagePayload::Block(block.clone()), MessageKind::Notarize, 1, String::from("h1"));
+            recent.observe(&message);
+        }
+        // Votes aren't notarized blocks
+        let vote = Message::new(MessagePayload::Block(chain[5].clone()), MessageKind::Vote, 1, String::from("h1"));
+        recent.observe(&vote);
+        assert_eq!(recent.len(), 4);
+
+        // Heights 2..5 are kept: a peer at height 2 can catch up, one at height 1 can't
+        let served = recent.chain_for(&ChainSyncRequest { from_height: 2, known_height: 3 }).unwrap();
+        let heights: Vec<u64> = served.blocks.iter().map(|b| b.block.height).collect();
+        assert_eq!(heights, vec![2, 3, 4, 5]);
+        assert!(recent.chain_for(&ChainSyncRequest { from_height: 1, known_height: 1 }).is_none());
+        assert!(recent.chain_for(&ChainSyncRequest { from_height: 2, known_height: 5 }).is_none());
+    }
+}
@@ -0,0 +1,184 @@
+/* Replay protection for consensus messages.
+   A vote is a signature on a block, and a block names its epoch, so a vote captured
+   today still verifies next month. Two checks keep captured messages from being
+   played back:
+   - Epoch bound: a vote, proposal, notarization or finalization whose block is more
+     than the window's epochs older than the current epoch is dropped before its
+     signatures are checked. The epoch is inside what the signatures sign, so a replayer
+     can't freshen it. Honest messages are a few epochs old at most; a node that fell
+     further behind catches up by chain sync, which this doesn't touch.
+   - Nonce window: every message carries a random nonce, and the node remembers the
+     nonces it saw from each sender during the window, with the signatures each message
+     carried. A message reusing one of them is a replay unless it adds a signature (an
+     echoed vote keeps the proposal's nonce, but carries more votes). The sender is the
+     validator that sealed the message (see envelope), not the sender_name in it,
+     which nobody vouches for: a peer making up a name per message would otherwise
+     get a fresh set of nonces each time. Nonces are remembered for at most
+     MAX_SENDERS senders; the one heard from least recently is forgotten first.
+   The nonce isn't signed, so a replayer can change it; the nonce window catches whole
+   messages played back as captured, and the epoch bound is what stops altered ones
+   outside the window. Inside it, a replayed vote is only counted once anyway. */
+
+use crate::messages::{Message, MessageKind, MessagePayload};
+use crate::utils::crypto::Signature;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+// Epochs a consensus message stays acceptable, and its nonce remembered
+pub const DEFAULT_REPLAY_WINDOW: u64 = 20;
+// Most nonces remembered per sender; the oldest is forgotten to make room
+const MAX_NONCES_PER_SENDER: usize = 1024;
+// Most senders whose nonces are remembered; a few dozen validators with room to spare
+const MAX_SENDERS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replay {
+    Stale { block_epoch: u64 },               // its block is older than the window
+    SeenNonce { sender: String, nonce: u32 }, // the same message (or a subset of it) was seen already
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Replay::Stale { block_epoch } => write!(f, "its block is from epoch {}, outside the replay window", block_epoch),
+            Replay::SeenNonce { sender, nonce } => write!(f, "nonce {} from {} was already used", nonce, sender),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SeenNonce {
+    kind: MessageKind,
+    nonce: u32,
+    epoch: u64, // when we first saw it
+    signatures: Vec<Signature>,
+}
+
+#[derive(Debug)]
+pub struct ReplayGuard {
+    window: u64, // 0: no checks
+    seen: HashMap<String, VecDeque<SeenNonce>>, // by sender, oldest first
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard::new(DEFAULT_REPLAY_WINDOW)
+    }
+}
+
+impl ReplayGuard {
+    /* @param window: epochs a consensus message stays acceptable (0: no checks) */
+    pub fn new(window: u64) -> Self {
+        Self { window, seen: HashMap::new() }
+    }
+
+    /* Checks a consensus message, and remembers its nonce if it passes. Other
+    messages always pass.
+    @param sender: the validator that sealed the message (see envelope)
+    @param message: the message, as received
+    @param epoch: the current epoch */
+    pub fn check(&mut self, sender: &str, message: &Message, epoch: u64) -> Result<(), Replay> {
+        let block = match (&message.kind, &message.payload) {
+            (MessageKind::Vote | MessageKind::Notarize | MessageKind::Finalize | MessageKind::Propose, MessagePayload::Block(block)) => block,
+            _ => return Ok(()),
+        };
+        if self.window == 0 {
+            return Ok(());
+        }
+        if block.epoch + self.window < epoch {
+            return Err(Replay::Stale { block_epoch: block.epoch });
+        }
+        if !self.seen.contains_key(sender) && self.seen.len() >= MAX_SENDERS {
+            self.forget_quietest_sender();
+        }
+        let seen = self.seen.entry(sender.to_string()).or_default();
+        match seen.iter_mut().find(|seen| seen.nonce == message.nonce && seen.kind == message.kind) {
+            Some(earlier) => {
+                let new: Vec<Signature> =
+                    message.signatures.iter().filter(|signature| !earlier.signatures.contains(signature)).copied().collect();
+                if new.is_empty() {
+                    return Err(Replay::SeenNonce { sender: sender.to_string(), nonce: message.nonce });
+                }
+                earlier.signatures.extend(new);
+            }
+            None => {
+                if seen.len() == MAX_NONCES_PER_SENDER {
+                    seen.pop_front();
+                }
+                seen.push_back(SeenNonce { kind: message.kind.clone(), nonce: message.nonce, epoch, signatures: message.signatures.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    // Forgets the sender whose latest nonce is the oldest, to make room for another
+    fn forget_quietest_sender(&mut self) {
+        let quietest = self.seen.iter().min_by_key(|(_, seen)| seen.back().map_or(0, |latest| latest.epoch)).map(|(sender, _)| sender.clone());
+        if let Some(sender) = quietest {
+            self.seen.remove(&sender);
+        }
+    }
+
+    /* A new epoch started: forgets nonces that left the window.
+    @param epoch: the epoch that just started */
+    pub fn epoch_started(&mut self, epoch: u64) {
+        let window = self.window;
+        for seen in self.seen.values_mut() {
+            seen.retain(|seen| seen.epoch + window >= epoch);
+        }
+        self.seen.retain(|_, seen| !seen.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::utils::crypto::*;
+
+    fn vote(block_epoch: u64, nonce: u32, signers: &[&Keypair]) -> Message {
+        let block = MessagePayload::Block(Block::new(block_epoch, [0; 32], b"entry".to_vec(), 1, 0));
+        let mut message = Message::new_with_defined_nonce(block, MessageKind::Vote, nonce, 1, String::from("h1"));
+        for keypair in signers {
+            message.sign_message(keypair.sign(b"block"));
+        }
+        message
+    }
+
+    #[test]
+    fn test_old_and_repeated_messages_are_replays() {
+        let (a, b) = (Keypair::generate(SignatureScheme::default()), Keypair::generate(SignatureScheme::default()));
+        let mut guard = ReplayGuard::new(5);
+        assert_eq!(guard.check("h1", &vote(10, 7, &[&a]), 10), Ok(()));
+        assert_eq!(guard.check("h1", &vote(10, 7, &[&a]), 10), Err(Replay::SeenNonce { sender: String::from("h1"), nonce: 7 }));
+        // An echo with another vote is news; the same echo again isn't
+        assert_eq!(guard.check("h1", &vote(10, 7, &[&a, &b]), 11), Ok(()));
+        assert!(guard.check("h1", &vote(10, 7, &[&b]), 11).is_err());
+        assert_eq!(guard.check("h1", &vote(10, 8, &[&a]), 15), Ok(()));
+        assert_eq!(guard.check("h1", &vote(10, 9, &[&a]), 16), Err(Replay::Stale { block_epoch: 10 }));
+
+        // Forgotten once out of the window (by then the epoch bound stops it anyway)
+        guard.epoch_started(16);
+        assert_eq!(guard.check("h1", &vote(12, 7, &[&a]), 16), Ok(()));
+        assert_eq!(ReplayGuard::new(0).check("h1", &vote(0, 7, &[&a]), 100), Ok(()));
+    }
+
+    #[test]
+    fn test_nonces_are_kept_for_a_bounded_number_of_senders() {
+        let a = Keypair::generate(SignatureScheme::default());
+        let mut guard = ReplayGuard::new(5);
+        assert_eq!(guard.check("h1", &vote(10, 7, &[&a]), 10), Ok(()));
+        // Sealed by h2, a message naming h1 is h2's: it neither replays nor uses up h1's nonces
+        assert_eq!(guard.check("h2", &vote(10, 7, &[&a]), 10), Ok(()));
+        assert_eq!(guard.check("h2", &vote(10, 7, &[&a]), 10), Err(Replay::SeenNonce { sender: String::from("h2"), nonce: 7 }));
+
+        // However many senders there are, only MAX_SENDERS are remembered; the quietest go first
+        assert_eq!(guard.check("h1", &vote(11, 8, &[&a]), 11), Ok(()));
+        for sender in 0..MAX_SENDERS * 4 {
+            assert_eq!(guard.check(&format!("s{}", sender), &vote(11, 7, &[&a]), 11), Ok(()));
+            assert!(guard.seen.len() <= MAX_SENDERS);
+        }
+        assert_eq!(guard.seen.len(), MAX_SENDERS);
+        assert!(!guard.seen.contains_key("h2"));
+    }
+}
@@ -0,0 +1,343 @@
+/* Validator-set transparency: every change to who runs the log is itself in the log.
+   A roster change (a validator joining with its key and weight, retiring, rotating its
+   key, or changing weight) is announced in a log entry like an upgrade (see upgrade),
+   and joins the history once its block is finalized. The block's notarization certificate is
+   the change's approval: the votes of the validators that accepted it. Replaying the
+   finalized changes in order therefore gives the validator set at any height, and
+   anyone with the log can audit who controlled it and when, and who agreed to each
+   change (RosterHistory).
+   Validators vote for a change only if their operator approved it, with one exception:
+   a validator's Join with the key (and default weight) the other validators already
+   know it by from peer discovery. Each validator announces its own Join once peer
+   discovery is done, so the starting roster gets into the log without operators having
+   to approve what discovery already established.
+   Key rotations need no approval either: a validator rotates its own key, signing the
+   rotation with its current key (the authority for it) and its new one (proof that it
+   holds it). Validators vote for a rotation signed by the key they know the validator by,
+   and once it is finalized they check that validator's signatures against the new key.
+   Joins and retirements change who the validators are, ROSTER_ACTIVATION_DELAY epochs
+   after the block that carried them: every node finalizes the change well before then,
+   so all of them switch at the same epoch. From that epoch on, a joining validator's
+   votes count and it takes turns leading, a retiring one's don't and it doesn't, and the
+   quorum is two thirds of the new set. Peer discovery gives the starting validators;
+   after it, only the log adds or removes any. Every validator has one vote whatever its
+   weight, for now. */
+
+use crate::blockchain::{Block, ChainId, EntryId, LogEntry, NotarizationCert};
+use crate::utils::crypto::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Weight of a validator that joined without naming one
+pub const DEFAULT_WEIGHT: u64 = 1;
+// Prefix of a log entry's data that marks it as a roster change
+const ROSTER_TAG: &[u8] = b"streamlet roster change v1";
+// Domain separation for key rotation signatures
+const ROTATION_CONTEXT: &[u8] = b"streamlet key rotation v1";
+// Epochs between a join or retirement's block and the epoch it takes effect in
+pub const ROSTER_ACTIVATION_DELAY: u64 = 10;
+
+// Rotations carry two signatures; roster changes are too rare for their size to matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RosterChange {
+    Join { validator: String, public_key: PublicKey, weight: u64 },
+    Retire { validator: String },
+    // signature: by the validator's current key; proof: by the new one
+    RotateKey { validator: String, public_key: PublicKey, signature: Signature, proof: Signature },
+    SetWeight { validator: String, weight: u64 },
+}
+
+fn parse_key(hex_key: &str) -> Result<PublicKey, String> {
+    hex::decode(hex_key)
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .ok_or_else(|| format!("{} is not a hex public key (as printed by get-sth)", hex_key))
+}
+
+impl RosterChange {
+    pub fn validator(&self) -> &str {
+        match self {
+            RosterChange::Join { validator, .. }
+            | RosterChange::Retire { validator }
+            | RosterChange::RotateKey { validator, .. }
+            | RosterChange::SetWeight { validator, .. } => validator,
+        }
+    }
+
+    /* What kind of change this is, as named in commands and JSON. */
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RosterChange::Join { .. } => "join",
+            RosterChange::Retire { .. } => "retire",
+            RosterChange::RotateKey { .. } => "rotate",
+            RosterChange::SetWeight { .. } => "weight",
+        }
+    }
+
+    /* Parses a change from the "roster" command's arguments:
+    join <name> <public key hex> [weight], retire <name>, or weight <name> <weight>.
+    (A key rotation needs the validator's keys; see rotate_key.) */
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let weight = |word: &str| word.parse::<u64>().map_err(|_| format!("{} is not a weight", word));
+        match words[..] {
+            ["join", validator, key] => {
+                Ok(RosterChange::Join { validator: validator.to_string(), public_key: parse_key(key)?, weight: DEFAULT_WEIGHT })
+            }
+            ["join", validator, key, w] => {
+                Ok(RosterChange::Join { validator: validator.to_string(), public_key: parse_key(key)?, weight: weight(w)? })
+            }
+            ["retire", validator] => Ok(RosterChange::Retire { validator: validator.to_string() }),
+            ["weight", validator, w] => Ok(RosterChange::SetWeight { validator: validator.to_string(), weight: weight(w)? }),
+            _ => Err(String::from(
+                "usage: roster join <name> <key> [weight] | retire <name> | weight <name> <weight>",
+            )),
+        }
+    }
+
+    /* A validator's rotation to a new key, signed with both keys.
+    @param validator: the validator's name
+    @param current: its current key
+    @param new: the key it rotates to
+    @param chain_id: the deployment's chain id */
+    pub fn rotate_key(validator: String, current: &dyn Signer, new: &dyn Signer, chain_id: &ChainId) -> Self {
+        let public_key = new.public();
+        let bytes = rotation_bytes(chain_id, &validator, &public_key);
+        RosterChange::RotateKey { signature: current.sign(&bytes), proof: new.sign(&bytes), validator, public_key }
+    }
+
+    /* Whether a key rotation is signed by the validator's current key and by the new key.
+    Other changes carry no signatures, and pass.
+    @param current: the key we know the validator by
+    @param chain_id: the deployment's chain id */
+    pub fn verify_rotation(&self, current: &dyn Verifier, chain_id: &ChainId) -> bool {
+        match self {
+            RosterChange::RotateKey { validator, public_key, signature, proof } => {
+                let bytes = rotation_bytes(chain_id, validator, public_key);
+                public_key.scheme() == current.scheme()
+                    && current.verify(&bytes, signature).is_ok()
+                    && public_key.verify(&bytes, proof).is_ok()
+            }
+            _ => true,
+        }
+    }
+
+    /* The change as a log entry. The id is derived from the change, so every node that
+    queues the same change queues the same bytes. */
+    pub fn to_entry(&self) -> LogEntry {
+        let mut data = ROSTER_TAG.to_vec();
+        data.extend_from_slice(&bincode::serialize(self).expect("Failed serialization."));
+        let digest = Sha256::digest(&data);
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&digest[..16]);
+        LogEntry { id: EntryId::from_parts(0, u128::from_be_bytes(id)), data, content_type: None }
+    }
+
+    /* The change announced by a log entry (None for ordinary entries). */
+    pub fn from_entry(entry: &LogEntry) -> Option<Self> {
+        entry.data.strip_prefix(ROSTER_TAG).and_then(|bytes| bincode::deserialize(bytes).ok())
+    }
+
+    /* Whether submitted data claims to be a roster change (only validators may make those). */
+    pub fn is_tagged(data: &[u8]) -> bool {
+        data.starts_with(ROSTER_TAG)
+    }
+}
+
+fn rotation_bytes(chain_id: &ChainId, validator: &str, public_key: &PublicKey) -> Vec<u8> {
+    let mut bytes = ROTATION_CONTEXT.to_vec();
+    bytes.extend(bincode::serialize(&(validator, public_key)).expect("Failed serialization."));
+    chain_id.bind(&bytes)
+}
+
+impl fmt::Display for RosterChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RosterChange::Join { validator, weight, .. } => write!(f, "{} joins with weight {}", validator, weight),
+            RosterChange::Retire { validator } => write!(f, "{} retires", validator),
+            RosterChange::RotateKey { validator, .. } => write!(f, "{} rotates its key", validator),
+            RosterChange::SetWeight { validator, weight } => write!(f, "{} now has weight {}", validator, weight),
+        }
+    }
+}
+
+/* A finalized roster change, with the block that carried it and that block's
+certificate (the votes that approved it). */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RosterRecord {
+    pub change: RosterChange,
+    pub entry_id: EntryId,
+    pub block: Block,
+    pub cert: NotarizationCert,
+}
+
+impl RosterRecord {
+    /* The first epoch the change is in force (see ROSTER_ACTIVATION_DELAY). */
+    pub fn activation_epoch(&self) -> u64 {
+        self.block.epoch.saturating_add(ROSTER_ACTIVATION_DELAY)
+    }
+}
+
+/* A validator as of some height. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RosterMember {
+    pub public_key: PublicKey,
+    pub weight: u64,
+}
+
+/* Every finalized roster change, oldest first, and the changes our operator approved. */
+#[derive(Debug, Default)]
+pub struct RosterHistory {
+    records: Vec<RosterRecord>,
+    approved: Vec<RosterChange>,
+    activated: usize, // records handed out by take_activated
+}
+
+impl RosterHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Agrees to a change, so we vote for a block announcing it. */
+    pub fn approve(&mut self, change: RosterChange) {
+        if !self.approved.contains(&change) {
+            self.approved.push(change);
+        }
+    }
+
+    pub fn is_approved(&self, change: &RosterChange) -> bool {
+        self.approved.contains(change)
+    }
+
+    /* Records a finalized change. Returns false for one already recorded (the same
+    entry finalized again, in another block).
+    @param change: the change
+    @param block: its finalized block
+    @param cert: the block's certificate */
+    pub fn record(&mut self, change: RosterChange, block: &Block, cert: &NotarizationCert) -> bool {
+        let entry_id = change.to_entry().id;
+        if self.records.iter().any(|record| record.entry_id == entry_id) {
+            return false;
+        }
+        self.approved.retain(|approved| *approved != change);
+        self.records.push(RosterRecord { change, entry_id, block: block.clone(), cert: cert.clone() });
+        true
+    }
+
+    pub fn records(&self) -> &[RosterRecord] {
+        &self.records
+    }
+
+    pub fn contains(&self, change: &RosterChange) -> bool {
+        let entry_id = change.to_entry().id;
+        self.records.iter().any(|record| record.entry_id == entry_id)
+    }
+
+    /* The changes that have taken effect by an epoch and weren't handed out yet, oldest
+    first (see ROSTER_ACTIVATION_DELAY).
+    @param epoch: the current epoch (u64::MAX: every change recorded) */
+    pub fn take_activated(&mut self, epoch: u64) -> Vec<RosterChange> {
+        let activated: Vec<RosterChange> = self.records[self.activated..]
+            .iter()
+            .take_while(|record| record.activation_epoch() <= epoch)
+            .map(|record| record.change.clone())
+            .collect();
+        self.activated += activated.len();
+        activated
+    }
+
+    /* Whether the log retired a validator (and it hasn't joined again since). */
+    pub fn is_retired(&self, validator: &str) -> bool {
+        let last = self.records.iter().rev().find(|record| {
+            record.change.validator() == validator && matches!(record.change, RosterChange::Join { .. } | RosterChange::Retire { .. })
+        });
+        matches!(last, Some(RosterRecord { change: RosterChange::Retire { .. }, .. }))
+    }
+
+    /* The validator set the log's changes give at a height (all of them: u64::MAX).
+    Changes to validators that never joined, or already retired, change nothing. */
+    pub fn roster_at(&self, height: u64) -> BTreeMap<String, RosterMember> {
+        let mut roster = BTreeMap::new();
+        for record in self.records.iter().take_while(|record| record.block.height <= height) {
+            match &record.change {
+                RosterChange::Join { validator, public_key, weight } => {
+                    roster.insert(validator.clone(), RosterMember { public_key: *public_key, weight: *weight });
+                }
+                RosterChange::Retire { validator } => {
+                    roster.remove(validator);
+                }
+                RosterChange::RotateKey { validator, public_key, .. } => {
+                    if let Some(member) = roster.get_mut(validator) {
+                        member.public_key = *public_key;
+                    }
+                }
+                RosterChange::SetWeight { validator, weight } => {
+                    if let Some(member) = roster.get_mut(validator) {
+                        member.weight = *weight;
+                    }
+                }
+            }
+        }
+        roster
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_replays_to_the_roster_at_each_height() {
+        let (old_keypair, new_keypair) = (Keypair::generate(SignatureScheme::default()), Keypair::generate(SignatureScheme::default()));
+        let (old_key, new_key) = (old_keypair.public(), new_keypair.public());
+        let key_hex = hex::encode(bincode::serialize(&old_key).unwrap());
+        let join = RosterChange::parse(&format!("join h1 {}", key_hex)).unwrap();
+        assert_eq!(join, RosterChange::Join { validator: String::from("h1"), public_key: old_key, weight: DEFAULT_WEIGHT });
+        assert!(RosterChange::parse("join h1 zz").is_err());
+        assert_eq!(RosterChange::from_entry(&join.to_entry()), Some(join.clone()));
+        assert_eq!(join.to_entry(), join.to_entry());
+        assert!(RosterChange::is_tagged(&join.to_entry().data));
+
+        // A rotation needs both keys' signatures, for this deployment
+        let chain_id = ChainId::default();
+        let rotation = RosterChange::rotate_key(String::from("h1"), &old_keypair, &new_keypair, &chain_id);
+        assert!(rotation.verify_rotation(&old_key, &chain_id));
+        assert!(!rotation.verify_rotation(&new_key, &chain_id));
+        assert!(!rotation.verify_rotation(&old_key, &ChainId::new("other deployment")));
+        // Rotating to a key the validator doesn't hold
+        let mut unproven = rotation.clone();
+        if let RosterChange::RotateKey { proof, .. } = &mut unproven {
+            *proof = old_keypair.sign(b"anything");
+        }
+        assert!(!unproven.verify_rotation(&old_key, &chain_id));
+
+        let mut history = RosterHistory::new();
+        let changes = [
+            join.clone(),
+            RosterChange::parse(&format!("join h2 {} 2", key_hex)).unwrap(),
+            rotation,
+            RosterChange::parse("weight h2 3").unwrap(),
+            RosterChange::parse("retire h1").unwrap(),
+        ];
+        for (height, change) in changes.iter().enumerate() {
+            let block = Block::new(height as u64, [0; 32], change.to_entry().serialize(), height as u64 + 1, 0);
+            let cert = NotarizationCert::new(&block, Vec::new());
+            assert!(history.record(change.clone(), &block, &cert));
+            assert!(!history.record(change.clone(), &block, &cert));
+        }
+        assert_eq!(history.roster_at(1)["h1"].public_key, old_key);
+        assert_eq!(history.roster_at(3)["h1"].public_key, new_key);
+        assert_eq!(history.roster_at(4)["h2"].weight, 3);
+        assert_eq!(history.roster_at(u64::MAX).keys().collect::<Vec<_>>(), vec!["h2"]);
+        assert!(history.contains(&join));
+        assert!(history.is_retired("h1") && !history.is_retired("h2"));
+
+        // Changes take effect in order, ROSTER_ACTIVATION_DELAY epochs after their blocks
+        assert!(history.take_activated(ROSTER_ACTIVATION_DELAY - 1).is_empty());
+        assert_eq!(history.take_activated(ROSTER_ACTIVATION_DELAY + 1), changes[..2].to_vec());
+        assert_eq!(history.take_activated(u64::MAX), changes[2..].to_vec());
+        assert!(history.take_activated(u64::MAX).is_empty());
+    }
+}
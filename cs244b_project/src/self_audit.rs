@@ -0,0 +1,187 @@
+/* Self-audit: the node checks what it serves against what its peers sign. A node's
+   clients only see its API, so storage that rotted, a read cache that went bad (see
+   read_cache) or a tampered serving layer would show them a log other than the one the
+   node agreed on with its peers, and nothing in consensus would notice. Every
+   SELF_AUDIT_INTERVAL epochs the node asks its own API handler, as a client would, for
+   its tree head, and checks it against the latest tree head of each validator it heard
+   on the STH topic (see monitor):
+   - at the same size, the roots must match
+   - at a smaller size, the consistency proof the node serves must lead from the
+     validator's root to its own
+   - an inclusion proof the node serves, for a random entry under the validator's head,
+     must lead from the entry it serves to the validator's root
+   Heads bigger than the node's are skipped until it catches up. Each disagreement is a
+   Divergence naming the validator (NodeAlert::SelfAudit). The fault may be the other
+   node's; if it is the same whichever validator is named, it is this node's. */
+
+use crate::auditor::{parse_audit_path, parse_consistency, parse_sth};
+use crate::blockchain::{leaf_hash, verify_consistency, verify_leaf_inclusion, ChainId, SignedTreeHead};
+use crate::http_api::{ApiRequest, ApiResponse};
+use crate::utils::crypto::*;
+use std::fmt;
+
+// Epochs between self-audits
+pub const SELF_AUDIT_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    TreeHead { reason: String },                                  // the served tree head doesn't hold up on its own
+    Root { peer: String, tree_size: u64 },                        // the peer signed another root at the size we serve
+    Consistency { peer: String, from_size: u64, to_size: u64 },   // our served head doesn't extend the peer's
+    Inclusion { peer: String, tree_size: u64, leaf_index: u64 },  // a served proof doesn't lead to the peer's root
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::TreeHead { reason } => write!(f, "the tree head we serve is bad: {}", reason),
+            Divergence::Root { peer, tree_size } => write!(f, "we serve a different root than {} signed at tree size {}", peer, tree_size),
+            Divergence::Consistency { peer, from_size, to_size } => {
+                write!(f, "we serve no valid proof that our tree of size {} extends {}'s of size {}", to_size, peer, from_size)
+            }
+            Divergence::Inclusion { peer, tree_size, leaf_index } => write!(
+                f,
+                "the proof we serve for entry {} doesn't lead to {}'s root at tree size {}",
+                leaf_index, peer, tree_size
+            ),
+        }
+    }
+}
+
+/* Checks the tree head and proofs the node serves against its peers' tree heads (see
+above). Returns what disagrees; nothing if the node has no tree head yet.
+@param api: answers API requests the way the node serves them
+@param public_key: our key
+@param chain_id: the deployment's chain id
+@param peer_heads: the latest tree head of each other validator
+@param pick: picks which entry to prove, given how many there are */
+pub fn audit_served(
+    api: &mut dyn FnMut(ApiRequest) -> ApiResponse,
+    public_key: &PublicKey,
+    chain_id: &ChainId,
+    peer_heads: &[SignedTreeHead],
+    pick: &mut dyn FnMut(u64) -> u64,
+) -> Vec<Divergence> {
+    let served = match api(ApiRequest::GetSth) {
+        Ok(served) => served,
+        Err(e) if e.status == 404 => return Vec::new(),
+        Err(e) => return vec![Divergence::TreeHead { reason: e.message }],
+    };
+    let sth = match parse_sth(&served) {
+        Ok((sth, key)) if key == *public_key && sth.verify(public_key, chain_id) => sth,
+        Ok(_) => return vec![Divergence::TreeHead { reason: String::from("it isn't signed with our key") }],
+        Err(reason) => return vec![Divergence::TreeHead { reason }],
+    };
+
+    let mut divergences = Vec::new();
+    for head in peer_heads.iter().filter(|head| head.tree_size <= sth.tree_size) {
+        let peer = head.signer.clone();
+        if head.tree_size == sth.tree_size && head.root_hash != sth.root_hash {
+            divergences.push(Divergence::Root { peer, tree_size: sth.tree_size });
+            continue;
+        }
+        let consistent = api(ApiRequest::GetConsistency { first: head.tree_size, second: sth.tree_size })
+            .ok()
+            .and_then(|served| parse_consistency(&served, head.tree_size, sth.tree_size).ok())
+            .is_some_and(|proof| verify_consistency(&proof, &head.root_hash, &sth.root_hash));
+        if !consistent {
+            divergences.push(Divergence::Consistency { peer, from_size: head.tree_size, to_size: sth.tree_size });
+            continue;
+        }
+        if head.tree_size == 0 {
+            continue;
+        }
+        let leaf_index = pick(head.tree_size);
+        let entry = api(ApiRequest::GetEntries { start: leaf_index, end: leaf_index })
+            .ok()
+            .and_then(|served| served["entries"][0]["leaf_input"].as_str().and_then(|entry| hex::decode(entry).ok()));
+        // Pruned here: nothing to prove
+        let leaf = match entry {
+            Some(entry) => leaf_hash(&entry),
+            None => continue,
+        };
+        let included = api(ApiRequest::GetProofByHash { hash: leaf, tree_size: head.tree_size })
+            .ok()
+            .and_then(|served| parse_audit_path(&served, head.tree_size).ok())
+            .is_some_and(|proof| verify_leaf_inclusion(&leaf, &proof, &head.root_hash));
+        if !included {
+            divergences.push(Divergence::Inclusion { peer, tree_size: head.tree_size, leaf_index });
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::MerkleTree;
+    use crate::http_api::ApiError;
+    use crate::json_schema::{consistency_json, inclusion_json, sth_json};
+    use serde_json::json;
+
+    // Serves a tree the way the node's API handler does
+    fn serve(tree: &MerkleTree, sth: &SignedTreeHead, key: &PublicKey, request: ApiRequest) -> ApiResponse {
+        let missing = || ApiError::not_found("no such thing");
+        match request {
+            ApiRequest::GetSth => Ok(sth_json(sth, key)),
+            ApiRequest::GetConsistency { first, second } => tree.prove_consistency(first, second).map(|proof| consistency_json(&proof)).ok_or_else(missing),
+            ApiRequest::GetProofByHash { hash, tree_size } => {
+                let index = tree.index_of_leaf(&hash).ok_or_else(missing)?;
+                tree.prove_inclusion_at(index, tree_size).map(|proof| inclusion_json(&proof)).ok_or_else(missing)
+            }
+            ApiRequest::GetEntries { start, .. } => Ok(json!({ "entries": [{ "leaf_input": hex::encode([start as u8]) }] })),
+            _ => Err(missing()),
+        }
+    }
+
+    #[test]
+    fn test_served_proofs_are_checked_against_peer_heads() {
+        let chain_id = ChainId::default();
+        let ours = Keypair::generate(SignatureScheme::default());
+        let peer = Keypair::generate(SignatureScheme::default());
+        let mut tree = MerkleTree::new();
+        let mut peer_heads = Vec::new();
+        for i in 0..6u8 {
+            tree.push(&[i]);
+            if i == 3 || i == 5 {
+                peer_heads.push(SignedTreeHead::sign(&tree, format!("v{}", i), &peer, &chain_id));
+            }
+        }
+        let sth = SignedTreeHead::sign(&tree, String::from("v1"), &ours, &chain_id);
+        let mut pick = |size: u64| size - 1;
+        let mut honest = |request| serve(&tree, &sth, &ours.public(), request);
+        assert_eq!(audit_served(&mut honest, &ours.public(), &chain_id, &peer_heads, &mut pick), Vec::new());
+
+        // A serving layer whose entries went bad
+        let mut bad_entries = |request| match request {
+            ApiRequest::GetEntries { .. } => Ok(json!({ "entries": [{ "leaf_input": hex::encode([9u8]) }] })),
+            request => serve(&tree, &sth, &ours.public(), request),
+        };
+        assert_eq!(
+            audit_served(&mut bad_entries, &ours.public(), &chain_id, &peer_heads, &mut pick),
+            vec![
+                Divergence::Inclusion { peer: String::from("v3"), tree_size: 4, leaf_index: 3 },
+                Divergence::Inclusion { peer: String::from("v5"), tree_size: 6, leaf_index: 5 },
+            ]
+        );
+
+        // A different log than the validators signed
+        let mut fork = MerkleTree::new();
+        for i in 10..16u8 {
+            fork.push(&[i]);
+        }
+        let forked = SignedTreeHead::sign(&fork, String::from("v1"), &ours, &chain_id);
+        let mut diverged = |request| serve(&fork, &forked, &ours.public(), request);
+        assert_eq!(
+            audit_served(&mut diverged, &ours.public(), &chain_id, &peer_heads, &mut pick),
+            vec![
+                Divergence::Consistency { peer: String::from("v3"), from_size: 4, to_size: 6 },
+                Divergence::Root { peer: String::from("v5"), tree_size: 6 },
+            ]
+        );
+
+        // A tree head someone else signed
+        let mut impostor = |request| serve(&tree, &sth, &peer.public(), request);
+        assert!(matches!(audit_served(&mut impostor, &ours.public(), &chain_id, &peer_heads, &mut pick)[..], [Divergence::TreeHead { .. }]));
+    }
+}
@@ -0,0 +1,72 @@
+/* Graceful shutdown.
+   StreamletInstance::run() returns once shutdown is requested: through a
+   ShutdownHandle (see StreamletInstance::shutdown_handle), which a service embedding
+   the node can call from any task, or by SIGINT/SIGTERM. The node then proposes and
+   votes no more, flushes its chain store and vote journal to disk, and disconnects from
+   its peers before returning. A second signal exits at once, for a node too stuck to
+   shut down. */
+
+use log::{info, warn};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+// Exit status for a second signal (128 + SIGINT, as shells report it)
+const FORCED_EXIT_STATUS: i32 = 130;
+
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    /* Asks the node to shut down; run() returns once it has. Calling it before run()
+    makes run() return right away. */
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+
+    /* Completes once shutdown is requested. Only one task may wait on it. */
+    pub(crate) async fn requested(&self) {
+        self.notify.notified().await
+    }
+
+    /* Requests shutdown on SIGINT or SIGTERM, and exits the process on the next one. */
+    pub(crate) async fn on_signal(self) {
+        if wait_for_signal().await.is_err() {
+            warn!("Can't listen for signals; shut down through the ShutdownHandle instead");
+            return;
+        }
+        info!("Signal received; shutting down (again to exit at once)");
+        self.shutdown();
+        if wait_for_signal().await.is_ok() {
+            std::process::exit(FORCED_EXIT_STATUS);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_before_waiting_is_not_lost() {
+        let handle = ShutdownHandle::default();
+        handle.clone().shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(1), handle.requested()).await.expect("shutdown was requested");
+    }
+}
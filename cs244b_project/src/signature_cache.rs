@@ -0,0 +1,119 @@
+/* Signature cache: each valid signature is checked once. The same vote reaches a node
+   many times over, inside the echoes of every validator that collected it (each echo
+   carries every vote its sender knows of, so is new to dedup while mostly repeating
+   signatures already checked), and again in notarizations and finalizations. With 20
+   or more validators, most of the signatures a node is asked to check are ones it has
+   checked before. The cache remembers the most recently used valid (signed bytes,
+   signature, key) triples, by digest, and a signature found in it isn't checked
+   again. Only valid signatures are remembered, so garbage signatures can't push out
+   good ones (the verification budget bounds what those cost; see verify_budget).
+   The cache is shared by the event loop and the verification pool (see verify_pool). */
+
+use crate::utils::crypto::*;
+use std::collections::{BTreeMap, HashMap};
+
+// Valid signatures remembered; the votes of a few epochs for a few dozen validators
+pub const DEFAULT_SIGNATURE_CACHE: usize = 16384;
+
+#[derive(Debug)]
+pub struct SignatureCache {
+    capacity: usize,
+    valid: HashMap<[u8; 32], u64>, // digest of the triple, and when it was last used
+    recency: BTreeMap<u64, [u8; 32]>,
+    clock: u64,
+    hits: u64,
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        SignatureCache::new(DEFAULT_SIGNATURE_CACHE)
+    }
+}
+
+impl SignatureCache {
+    /* @param capacity: signatures to remember (0: none; every signature is checked) */
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, valid: HashMap::new(), recency: BTreeMap::new(), clock: 0, hits: 0 }
+    }
+
+    /* What a signature is remembered by.
+    @param signed: digest of the bytes it signs
+    @param signature: the signature
+    @param public_key: the key it verifies under */
+    pub fn key(signed: &Sha256Hash, signature: &Signature, public_key: &PublicKey) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(signed);
+        hasher.update(bincode::serialize(&(signature, public_key)).expect("Failed serialization."));
+        hasher.finalize().into()
+    }
+
+    /* Whether the signature was found valid before (it is then the most recently used).
+    @param key: the signature's key (see key) */
+    pub fn contains(&mut self, key: &[u8; 32]) -> bool {
+        self.clock += 1;
+        match self.valid.get_mut(key) {
+            Some(last_used) => {
+                self.recency.remove(last_used);
+                *last_used = self.clock;
+                self.recency.insert(self.clock, *key);
+                self.hits += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* Remembers a valid signature, forgetting the least recently used beyond capacity.
+    @param key: the signature's key (see key) */
+    pub fn insert(&mut self, key: [u8; 32]) {
+        if self.capacity == 0 || self.contains(&key) {
+            return;
+        }
+        while self.valid.len() >= self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.valid.remove(&oldest),
+                None => break,
+            };
+        }
+        self.clock += 1;
+        self.valid.insert(key, self.clock);
+        self.recency.insert(self.clock, key);
+    }
+
+    /* Signature checks skipped so far. */
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_signatures_are_checked_once_until_forgotten() {
+        let signer = Keypair::generate(SignatureScheme::Ed25519);
+        let other = Keypair::generate(SignatureScheme::Ed25519).public();
+        let signed: Sha256Hash = Sha256::digest(b"vote").into();
+        let signature = signer.sign(b"vote");
+        let key = SignatureCache::key(&signed, &signature, &signer.public());
+        // The same signature under another key, or over other bytes, is another triple
+        assert_ne!(key, SignatureCache::key(&signed, &signature, &other));
+        assert_ne!(key, SignatureCache::key(&Sha256::digest(b"other vote").into(), &signature, &signer.public()));
+
+        let mut cache = SignatureCache::new(2);
+        assert!(!cache.contains(&key));
+        cache.insert(key);
+        assert!(cache.contains(&key));
+        cache.insert([1; 32]);
+        // Using the signature made it the most recent, so [1; 32] goes first
+        assert!(cache.contains(&key));
+        cache.insert([2; 32]);
+        assert!(cache.contains(&key) && !cache.contains(&[1; 32]) && cache.contains(&[2; 32]));
+        assert_eq!(cache.hits(), 4);
+
+        let mut disabled = SignatureCache::new(0);
+        disabled.insert(key);
+        assert!(!disabled.contains(&key));
+    }
+}
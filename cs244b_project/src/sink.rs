@@ -0,0 +1,162 @@
+/* Sinks that mirror the finalized chain into other systems (see
+   StreamletInstance::add_finalization_sink), so operators can feed existing pipelines
+   without forking the crate. Each sink runs in its own task and is handed every block
+   the node finalizes after run() starts, once each and in height order; empty blocks
+   and validator announcements included. A slow sink only delays itself: blocks queue
+   for it while consensus goes on. A block a sink fails to take is logged and skipped,
+   so sinks retry on their own if they must not lose blocks.
+   Built in:
+   - WebhookSink: POSTs each block's JSON (see json_schema::block_json) to an http://
+     URL, signed and retried like a proof callback (see callback).
+   - KafkaSink (the "kafka" feature): produces each block's JSON to a Kafka topic, keyed
+     by height. */
+
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::blockchain::SignedBlock;
+use crate::callback::{self, CallbackUrl};
+use crate::json_schema;
+
+#[async_trait]
+pub trait FinalizationSink: Send {
+    /* Where the sink sends blocks, for logs. */
+    fn name(&self) -> String;
+
+    /* Hands over one finalized block; returns once the sink has taken it.
+    @param block: the block and its notarization certificate */
+    async fn deliver(&mut self, block: &SignedBlock) -> Result<(), String>;
+}
+
+/* Feeds a sink the node's finalized blocks until the node stops.
+@param sink: the sink
+@param finalized: the node's finalized blocks (see StreamletInstance::subscribe_finalized) */
+pub async fn run(mut sink: Box<dyn FinalizationSink>, mut finalized: mpsc::UnboundedReceiver<SignedBlock>) {
+    while let Some(signed) = finalized.recv().await {
+        if let Err(e) = sink.deliver(&signed).await {
+            warn!("Sink {} didn't take block {}: {}", sink.name(), signed.block.height, e);
+        }
+    }
+}
+
+fn block_body(signed: &SignedBlock) -> Vec<u8> {
+    json_schema::block_json(&signed.block, &signed.cert.signatures).to_string().into_bytes()
+}
+
+/* POSTs each block to a URL, with an HMAC of the body in the X-Streamlet-Signature
+header (see callback). */
+pub struct WebhookSink {
+    url: CallbackUrl,
+    secret: Vec<u8>,
+}
+
+impl WebhookSink {
+    /* @param url: where to POST blocks
+    @param secret: key for the signature header, shared with the receiver */
+    pub fn new(url: CallbackUrl, secret: &[u8]) -> Self {
+        WebhookSink { url, secret: secret.to_vec() }
+    }
+}
+
+#[async_trait]
+impl FinalizationSink for WebhookSink {
+    fn name(&self) -> String {
+        self.url.to_string()
+    }
+
+    async fn deliver(&mut self, block: &SignedBlock) -> Result<(), String> {
+        match callback::deliver(self.url.clone(), block_body(block), self.secret.clone()).await {
+            true => Ok(()),
+            false => Err(String::from("gave up after retrying")),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaSink;
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::*;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use std::time::Duration;
+
+    // How long a block may wait in the producer's queue for the brokers
+    const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /* Produces each block to a Kafka topic, keyed by its height. */
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl KafkaSink {
+        /* @param brokers: bootstrap servers, comma-separated ("host:port,...")
+        @param topic: topic to produce to */
+        pub fn new(brokers: &str, topic: &str) -> Result<Self, String> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("enable.idempotence", "true")
+                .create()
+                .map_err(|e| e.to_string())?;
+            Ok(KafkaSink { producer, topic: topic.to_string() })
+        }
+    }
+
+    #[async_trait]
+    impl FinalizationSink for KafkaSink {
+        fn name(&self) -> String {
+            format!("kafka:{}", self.topic)
+        }
+
+        async fn deliver(&mut self, block: &SignedBlock) -> Result<(), String> {
+            let key = block.block.height.to_string();
+            let body = block_body(block);
+            let record = FutureRecord::to(&self.topic).key(&key).payload(&body);
+            self.producer.send(record, QUEUE_TIMEOUT).await.map(|_| ()).map_err(|(e, _)| e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+
+    struct Recorder {
+        heights: mpsc::UnboundedSender<u64>,
+    }
+
+    #[async_trait]
+    impl FinalizationSink for Recorder {
+        fn name(&self) -> String {
+            String::from("recorder")
+        }
+
+        async fn deliver(&mut self, block: &SignedBlock) -> Result<(), String> {
+            if block.block.height == 2 {
+                return Err(String::from("refused"));
+            }
+            let _ = self.heights.send(block.block.height);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sink_gets_blocks_in_order_past_failures() {
+        let (heights, mut delivered) = mpsc::unbounded_channel();
+        let (finalized, blocks) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run(Box::new(Recorder { heights }), blocks));
+        for height in 1..=3 {
+            let block = Block::new(height, [0; 32], Vec::new(), height, 0);
+            finalized.send(SignedBlock::new(block, Vec::new())).unwrap();
+        }
+        drop(finalized);
+        task.await.unwrap();
+        assert_eq!(delivered.recv().await, Some(1));
+        assert_eq!(delivered.recv().await, Some(3));
+        assert_eq!(delivered.recv().await, None);
+    }
+}
@@ -0,0 +1,196 @@
+/* Static validator rosters, for permissioned deployments (--static-roster <file>). A node
+   started without one admits whoever advertises during discovery. With one, the
+   deployment's validators are fixed up front, with their keys and, optionally, where to
+   reach them:
+
+       {"schema_version": 1,
+        "validators": {"v1": {"public_key": "<hex>", "addrs": ["/ip4/10.0.1.5/tcp/4001"]}, ...}}
+
+   Keys are hex of their bincode encoding, as everywhere in json_schema. The node takes
+   the keys on, dials the addresses at startup (as it does --bootstrap peers), ignores
+   advertisements that don't match the roster, and drops gossip from nodes that aren't
+   on it (see StaticRoster::refuses), reporting the peer it came from (see peer_score).
+   Libp2p peer ids change every time a node starts, so nodes are told apart by their
+   keys, not their connections: a message only counts as a validator's if the validator
+   sealed it (see envelope). Clients still submit, subject to --submitters. Observers
+   aren't on the roster, so they can't catch up from a permissioned deployment. A
+   validator that joins later (see roster) must be added to every node's file. */
+
+use crate::json_schema;
+use crate::messages::{Message, MessageKind, MessagePayload};
+use crate::network::Multiaddr;
+use crate::utils::crypto::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RosterPeer {
+    pub public_key: PublicKey,
+    pub addrs: Vec<Multiaddr>, // where to dial it; may be empty (found over mDNS then)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticRoster {
+    validators: BTreeMap<String, RosterPeer>, // by name
+}
+
+impl StaticRoster {
+    pub fn new(validators: BTreeMap<String, RosterPeer>) -> Self {
+        StaticRoster { validators }
+    }
+
+    /* Reads a roster in the JSON form above.
+    @param bytes: the file's contents */
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_json::from_slice(bytes).map_err(|e| format!("not JSON: {}", e))?;
+        json_schema::check_version(&value)?;
+        let validators = value["validators"]
+            .as_object()
+            .ok_or("'validators' is missing or not an object")?
+            .iter()
+            .map(|(name, validator)| {
+                let public_key = validator["public_key"]
+                    .as_str()
+                    .and_then(|key| hex::decode(key).ok())
+                    .and_then(|bytes| bincode::deserialize(&bytes).ok())
+                    .ok_or_else(|| format!("{}'s public key is missing or not valid", name))?;
+                let addrs = match &validator["addrs"] {
+                    Value::Null => Vec::new(),
+                    Value::Array(addrs) => addrs
+                        .iter()
+                        .map(|addr| addr.as_str().and_then(|addr| addr.parse().ok()).ok_or_else(|| format!("{} has an address that isn't a multiaddr", name)))
+                        .collect::<Result<Vec<_>, String>>()?,
+                    _ => return Err(format!("{}'s 'addrs' is not a list", name)),
+                };
+                Ok((name.clone(), RosterPeer { public_key, addrs }))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        if validators.is_empty() {
+            return Err(String::from("the roster names no validators"));
+        }
+        Ok(StaticRoster { validators })
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RosterPeer> {
+        self.validators.get(name)
+    }
+
+    pub fn public_keys(&self) -> HashMap<String, PublicKey> {
+        self.validators.iter().map(|(name, validator)| (name.clone(), validator.public_key)).collect()
+    }
+
+    /* Every listed address, to dial at startup. */
+    pub fn addrs(&self) -> Vec<Multiaddr> {
+        self.validators.values().flat_map(|validator| validator.addrs.iter().cloned()).collect()
+    }
+
+    /* Why a peer's advertisement doesn't fit the roster, if it doesn't.
+    @param name: the advertised name
+    @param public_key: the advertised key */
+    pub fn mismatch(&self, name: &str, public_key: &PublicKey) -> Option<String> {
+        match self.validators.get(name) {
+            None => Some(String::from("not on the static roster")),
+            Some(validator) if validator.public_key != *public_key => Some(String::from("its key isn't the one on the static roster")),
+            Some(_) => None,
+        }
+    }
+
+    /* Why the roster keeps a received message out, if it does. A sealed message must be
+    sealed by a listed validator (the envelope's key was checked against the validator's
+    already). Unsealed, only messages the roster can't or needn't vouch for get in: those
+    from clients and apps, and those that carry their own proof: advertisements (checked
+    against the roster when handled), chain segments and snapshots (by their
+    certificates), roster notices (by the roster secret), and tree heads signed with a
+    listed key.
+    @param message: the message
+    @param sealed_by: the envelope's sender, if it came sealed */
+    pub fn refuses(&self, message: &Message, sealed_by: Option<&str>) -> Option<String> {
+        if let Some(sender) = sealed_by {
+            if !self.validators.contains_key(sender) {
+                return Some(format!("sealed by {}, who isn't on the static roster", sender));
+            }
+            return None;
+        }
+        match (&message.kind, &message.payload) {
+            (
+                MessageKind::Submit
+                | MessageKind::AppRequest
+                | MessageKind::AppSend
+                | MessageKind::AppBlockRequest
+                | MessageKind::AppBlockResponse
+                | MessageKind::AppChainRequest
+                | MessageKind::AppChainResponse
+                | MessageKind::AppReceipt
+                | MessageKind::AppFinalized
+                | MessageKind::PeerInit
+                | MessageKind::SyncResponse
+                | MessageKind::SnapshotResponse
+                | MessageKind::RosterSealed,
+                _,
+            ) => None,
+            (MessageKind::TreeHead, MessagePayload::TreeHead(update)) => {
+                if self.validators.values().any(|validator| validator.public_key == update.public_key) {
+                    None
+                } else {
+                    Some(String::from("tree head signed with a key that isn't on the static roster"))
+                }
+            }
+            (kind, _) => Some(format!("unsealed {:?} message; only validators on the static roster send those", kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::LogEntry;
+    use crate::messages::ChainSyncRequest;
+
+    #[test]
+    fn test_static_roster_keeps_out_unlisted_nodes() {
+        let key = Keypair::generate(SignatureScheme::Ed25519);
+        let other = Keypair::generate(SignatureScheme::Ed25519).public();
+        let hex_key = hex::encode(bincode::serialize(&key.public()).unwrap());
+        let json = format!(
+            r#"{{"schema_version": 1, "validators": {{"v1": {{"public_key": "{}", "addrs": ["/ip4/10.0.1.5/tcp/4001"]}}, "v2": {{"public_key": "{}"}}}}}}"#,
+            hex_key,
+            hex::encode(bincode::serialize(&other).unwrap())
+        );
+        let roster = StaticRoster::from_json(json.as_bytes()).unwrap();
+        assert_eq!(roster.len(), 2);
+        assert_eq!(roster.addrs(), vec!["/ip4/10.0.1.5/tcp/4001".parse::<Multiaddr>().unwrap()]);
+        assert_eq!(roster.mismatch("v1", &key.public()), None);
+        assert!(roster.mismatch("v1", &other).is_some());
+        assert!(roster.mismatch("v3", &key.public()).is_some());
+        let bad_addr = json.replace("/ip4/10.0.1.5/tcp/4001", "10.0.1.5:4001");
+        assert!(StaticRoster::from_json(bad_addr.as_bytes()).is_err());
+
+        // Requests for the chain must come sealed by a listed validator; client submissions needn't
+        let request = ChainSyncRequest { from_height: 0, known_height: 0 };
+        let sync = Message::new(MessagePayload::ChainSyncRequest(request), MessageKind::SyncRequest, 0, String::from("v1"));
+        assert!(roster.refuses(&sync, None).is_some());
+        assert_eq!(roster.refuses(&sync, Some("v1")), None);
+        assert!(roster.refuses(&sync, Some("outsider")).is_some());
+        let submit = Message::new(MessagePayload::Submit(LogEntry::new(b"entry".to_vec())), MessageKind::Submit, 0, String::from("alice"));
+        assert_eq!(roster.refuses(&submit, None), None);
+
+        // A node takes the roster's keys on, and only starts with its own
+        let mut node = crate::StreamletInstance::builder().name("v1").build().unwrap();
+        let listed = StaticRoster::new(BTreeMap::from([
+            (String::from("v1"), RosterPeer { public_key: node.get_public_key(), addrs: Vec::new() }),
+            (String::from("v2"), RosterPeer { public_key: other, addrs: Vec::new() }),
+        ]));
+        assert!(node.set_static_roster(roster).is_err());
+        node.set_static_roster(listed).unwrap();
+        assert_eq!(node.expected_peer_count, 1);
+        assert_eq!(node.public_keys.get("v2"), Some(&other));
+    }
+}
@@ -0,0 +1,166 @@
+/* Submitter access control, for permissioned logs (--submitters <file>). A node started
+   without it takes entries from anyone. With it, the node queues an entry only if a
+   submitter on the allow-list signed it. The list is a JSON file:
+
+       {"schema_version": 1, "submitters": {"alice": "<hex key>", ...}}
+
+   Keys are hex of their bincode encoding, as everywhere in json_schema, under any
+   scheme. A submitter signs the entry as it will be stored, id included, under the
+   deployment's chain id (see SignedSubmission), and sends the signature along:
+   - over gossip (MessageKind::Submit), as the message's signature, sent under its name;
+   - over HTTP, as "submitter" and "signature" next to the entry's "id";
+   - over gRPC, in SubmitEntryRequest's id, submitter and signature fields.
+   Validators may always submit, under their own keys. Since the id is signed, a signed
+   entry can't be queued twice: ids the node already promised or finalized are refused
+   (see StreamletInstance::admit_entry). The app tool can't sign, so a node with a list
+   refuses its entries. */
+
+use crate::blockchain::{ChainId, LogEntry};
+use crate::json_schema;
+use crate::utils::crypto::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+// Domain separator, so a submission signature can't be passed off as anything else
+const SUBMISSION_CONTEXT: &[u8] = b"streamlet submission v1";
+
+/* A submitter's signature on an entry. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedSubmission {
+    pub submitter: String,
+    pub signature: Signature,
+}
+
+impl SignedSubmission {
+    /* Signs an entry for submission.
+    @param entry: the entry, with the id it will be stored under
+    @param submitter: the submitter's name on the allow-list
+    @param signer: the submitter's key
+    @param chain_id: the deployment's chain id */
+    pub fn sign(entry: &LogEntry, submitter: &str, signer: &dyn Signer, chain_id: &ChainId) -> Self {
+        SignedSubmission { submitter: submitter.to_string(), signature: signer.sign(&SignedSubmission::signed_bytes(entry, chain_id)) }
+    }
+
+    /* What a submitter signs: the entry's bytes, for one deployment. */
+    pub fn signed_bytes(entry: &LogEntry, chain_id: &ChainId) -> Vec<u8> {
+        let mut bytes = SUBMISSION_CONTEXT.to_vec();
+        bytes.extend_from_slice(&chain_id.0);
+        bytes.extend(entry.serialize());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubmitterAcl {
+    submitters: BTreeMap<String, PublicKey>, // by name
+}
+
+impl SubmitterAcl {
+    pub fn new(submitters: BTreeMap<String, PublicKey>) -> Self {
+        SubmitterAcl { submitters }
+    }
+
+    /* Reads a list in the JSON form above.
+    @param bytes: the file's contents */
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_json::from_slice(bytes).map_err(|e| format!("not JSON: {}", e))?;
+        json_schema::check_version(&value)?;
+        let submitters = value["submitters"]
+            .as_object()
+            .ok_or("'submitters' is missing or not an object")?
+            .iter()
+            .map(|(name, key)| {
+                let public_key = key
+                    .as_str()
+                    .and_then(|key| hex::decode(key).ok())
+                    .and_then(|bytes| bincode::deserialize(&bytes).ok())
+                    .ok_or_else(|| format!("{}'s key is not valid", name))?;
+                Ok((name.clone(), public_key))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        Ok(SubmitterAcl { submitters })
+    }
+
+    pub fn len(&self) -> usize {
+        self.submitters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.submitters.is_empty()
+    }
+
+    /* Checks an entry was signed by a submitter on the list, or by a validator.
+    @param entry: the entry
+    @param submission: who signed it, if anyone did
+    @param validators: the validators' keys, by name
+    @param chain_id: the deployment's chain id */
+    pub fn check(
+        &self,
+        entry: &LogEntry,
+        submission: Option<&SignedSubmission>,
+        validators: &HashMap<String, PublicKey>,
+        chain_id: &ChainId,
+    ) -> Result<(), String> {
+        let submission = submission.ok_or("entry isn't signed by a submitter")?;
+        let public_key = self
+            .submitters
+            .get(&submission.submitter)
+            .or_else(|| validators.get(&submission.submitter))
+            .ok_or_else(|| format!("{} may not submit entries", submission.submitter))?;
+        public_key
+            .verify(&SignedSubmission::signed_bytes(entry, chain_id), &submission.signature)
+            .map_err(|_| format!("{}'s signature on the entry doesn't verify", submission.submitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_only_listed_submitters_get_entries_in() {
+        let chain_id = ChainId::default();
+        let alice = Keypair::generate(SignatureScheme::Secp256k1);
+        let mallory = Keypair::generate(SignatureScheme::Ed25519);
+        let validator = Keypair::generate(SignatureScheme::Ed25519);
+        let file = json!({
+            json_schema::VERSION_FIELD: json_schema::JSON_SCHEMA_VERSION,
+            "submitters": { "alice": hex::encode(bincode::serialize(&alice.public()).unwrap()) },
+        });
+        let acl = SubmitterAcl::from_json(file.to_string().as_bytes()).unwrap();
+        assert_eq!(acl.len(), 1);
+        let validators = HashMap::from([(String::from("v1"), validator.public())]);
+
+        let entry = LogEntry::new(b"statement".to_vec());
+        let signed = SignedSubmission::sign(&entry, "alice", &alice, &chain_id);
+        assert!(acl.check(&entry, Some(&signed), &validators, &chain_id).is_ok());
+        let by_validator = SignedSubmission::sign(&entry, "v1", &validator, &chain_id);
+        assert!(acl.check(&entry, Some(&by_validator), &validators, &chain_id).is_ok());
+
+        assert!(acl.check(&entry, None, &validators, &chain_id).is_err());
+        let unknown = SignedSubmission::sign(&entry, "mallory", &mallory, &chain_id);
+        assert!(acl.check(&entry, Some(&unknown), &validators, &chain_id).is_err());
+        let impostor = SignedSubmission::sign(&entry, "alice", &mallory, &chain_id);
+        assert!(acl.check(&entry, Some(&impostor), &validators, &chain_id).is_err());
+
+        // The signature covers the whole entry, and the deployment
+        let other = LogEntry { data: b"forged".to_vec(), ..entry.clone() };
+        assert!(acl.check(&other, Some(&signed), &validators, &chain_id).is_err());
+        assert!(acl.check(&entry, Some(&signed), &validators, &ChainId::new("other")).is_err());
+
+        // A node with the list refuses unsigned entries over HTTP, and signed ones twice
+        let mut node = crate::StreamletInstance::new(String::from("v1"), 0);
+        node.set_submitters(acl);
+        let add = |submission: Option<Box<SignedSubmission>>| crate::http_api::ApiRequest::AddEntry {
+            data: entry.data.clone(),
+            callback: None,
+            content_type: None,
+            id: Some(entry.id),
+            submission,
+        };
+        assert_eq!(node.answer_api_request(add(None)).unwrap_err().status, 403);
+        assert!(node.answer_api_request(add(Some(Box::new(signed.clone())))).is_ok());
+        assert_eq!(node.answer_api_request(add(Some(Box::new(signed)))).unwrap_err().status, 403);
+    }
+}
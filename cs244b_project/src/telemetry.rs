@@ -0,0 +1,35 @@
+/* Tracing: log lines in the context of what the node was doing.
+   Every binary logs through a tracing subscriber, filtered by RUST_LOG as before (e.g.
+   RUST_LOG=info, or RUST_LOG=cs244b_project=debug). Log lines from the log crate are
+   turned into tracing events, so they show the spans they were logged in:
+   - epoch (info): the node, the epoch and its leader, for everything handled in it
+   - message (info): each consensus or client message handled, with its kind, sender,
+     block and correlation id
+   - publish / receive (debug): each message sent or received over the network, with
+     its topic, peer, size and correlation id
+   A message's correlation id (see Message::correlation_id) is the same on every node,
+   and a proposal's votes and echoes share it, so grepping the logs of all nodes for it
+   follows a block from proposal to notarization across the cluster. Set
+   STREAMLET_SPAN_TIMING=1 to also log when each span closes, with the time spent in it. */
+
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+// Set (to anything but 0) to log span timings
+pub const SPAN_TIMING_ENV: &str = "STREAMLET_SPAN_TIMING";
+
+/* Installs the subscriber; call once, at the start of main. Logs errors only unless
+RUST_LOG says otherwise. */
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let span_events = match std::env::var(SPAN_TIMING_ENV) {
+        Ok(value) if value != "0" => FmtSpan::CLOSE,
+        _ => FmtSpan::NONE,
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).with_span_events(span_events).init();
+}
+
+/* First bytes of a hash in hex, enough to tell blocks apart in logs. */
+pub fn short_hash(hash: &[u8]) -> String {
+    hex::encode(&hash[..hash.len().min(4)])
+}
@@ -0,0 +1,145 @@
+/* Coordinated protocol upgrades.
+   A new protocol version is announced in a reconfiguration block: a block whose log
+   entry carries a ProtocolUpgrade (the version and the epoch it activates at). Once
+   that block is finalized, every node knows the same activation epoch, and runs the
+   old rules before it and the new rules from it on; version-specific rules branch on
+   the version in force for an epoch (see version_at).
+   Validators only vote for an announcement their operator approved, so an upgrade
+   needs a quorum of operators to agree on it, and only for one announced at least
+   MIN_UPGRADE_NOTICE epochs ahead, so every node finalizes it well before it applies.
+   A node that doesn't support a version stops proposing and voting once it activates,
+   instead of running old rules alongside upgraded nodes. */
+
+use crate::blockchain::{EntryId, LogEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+// Newest protocol version this build implements
+pub const PROTOCOL_VERSION: u32 = 1;
+// Fewest epochs between an announcement's block and its activation epoch
+pub const MIN_UPGRADE_NOTICE: u64 = 10;
+// Prefix of a log entry's data that marks it as an upgrade announcement
+const UPGRADE_TAG: &[u8] = b"streamlet protocol upgrade v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProtocolUpgrade {
+    pub version: u32,
+    pub activation_epoch: u64,
+}
+
+impl ProtocolUpgrade {
+    /* The announcement as a log entry. The id is derived from the upgrade, so every
+    node that queues the same upgrade queues the same bytes. */
+    pub fn to_entry(&self) -> LogEntry {
+        let mut data = UPGRADE_TAG.to_vec();
+        data.extend_from_slice(&bincode::serialize(self).expect("Failed serialization."));
+        let id = EntryId::from_parts(0, ((self.version as u128) << 64) | self.activation_epoch as u128);
+        LogEntry { id, data, content_type: None }
+    }
+
+    /* The upgrade announced by a log entry (None for ordinary entries). */
+    pub fn from_entry(entry: &LogEntry) -> Option<Self> {
+        entry.data.strip_prefix(UPGRADE_TAG).and_then(|bytes| bincode::deserialize(bytes).ok())
+    }
+
+    /* Whether submitted data claims to be an announcement (only validators may make those). */
+    pub fn is_tagged(data: &[u8]) -> bool {
+        data.starts_with(UPGRADE_TAG)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UpgradeSchedule {
+    activations: BTreeMap<u64, u32>, // activation epoch -> version, from finalized announcements
+    approved: HashSet<ProtocolUpgrade>,
+}
+
+impl UpgradeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Protocol version in force at an epoch (1 until the first upgrade activates). */
+    pub fn version_at(&self, epoch: u64) -> u32 {
+        self.activations.range(..=epoch).next_back().map(|(_, version)| *version).unwrap_or(1)
+    }
+
+    /* Newest version scheduled so far, active or not. */
+    pub fn latest_version(&self) -> u32 {
+        self.activations.values().next_back().copied().unwrap_or(1)
+    }
+
+    /* Version activating at exactly this epoch, if any. */
+    pub fn activating_at(&self, epoch: u64) -> Option<u32> {
+        self.activations.get(&epoch).copied()
+    }
+
+    /* Checks that an upgrade may be announced in a block of the given epoch: it must
+    be newer than anything scheduled, and activate after the notice period.
+    @param upgrade: the announced upgrade
+    @param announced_epoch: epoch of the block carrying the announcement */
+    pub fn check(&self, upgrade: &ProtocolUpgrade, announced_epoch: u64) -> Result<(), String> {
+        if upgrade.version <= self.latest_version() {
+            return Err(format!("version {} is not newer than version {}", upgrade.version, self.latest_version()));
+        }
+        if upgrade.activation_epoch < announced_epoch.saturating_add(MIN_UPGRADE_NOTICE) {
+            return Err(format!(
+                "activation epoch {} is less than {} epochs after epoch {}",
+                upgrade.activation_epoch, MIN_UPGRADE_NOTICE, announced_epoch
+            ));
+        }
+        if let Some((&last_activation, _)) = self.activations.iter().next_back() {
+            if upgrade.activation_epoch <= last_activation {
+                return Err(format!("activation epoch {} is not after epoch {}", upgrade.activation_epoch, last_activation));
+            }
+        }
+        Ok(())
+    }
+
+    /* Schedules an upgrade from a finalized announcement (see check).
+    @param upgrade: the announced upgrade
+    @param announced_epoch: epoch of the finalized block carrying the announcement */
+    pub fn schedule(&mut self, upgrade: &ProtocolUpgrade, announced_epoch: u64) -> Result<(), String> {
+        self.check(upgrade, announced_epoch)?;
+        self.activations.insert(upgrade.activation_epoch, upgrade.version);
+        Ok(())
+    }
+
+    /* Records that this node's operator agreed to an upgrade, so we vote for its announcement. */
+    pub fn approve(&mut self, upgrade: ProtocolUpgrade) {
+        self.approved.insert(upgrade);
+    }
+
+    pub fn is_approved(&self, upgrade: &ProtocolUpgrade) -> bool {
+        self.approved.contains(upgrade)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_activates_at_agreed_epoch() {
+        let upgrade = ProtocolUpgrade { version: 2, activation_epoch: 40 };
+        let entry = upgrade.to_entry();
+        assert_eq!(ProtocolUpgrade::from_entry(&entry), Some(upgrade));
+        assert_eq!(entry, upgrade.to_entry());
+        assert_eq!(ProtocolUpgrade::from_entry(&LogEntry::new(b"hello".to_vec())), None);
+
+        let mut schedule = UpgradeSchedule::new();
+        // Not enough notice
+        assert!(schedule.schedule(&upgrade, 35).is_err());
+        schedule.schedule(&upgrade, 20).unwrap();
+        assert_eq!(schedule.version_at(39), 1);
+        assert_eq!(schedule.version_at(40), 2);
+        assert_eq!(schedule.activating_at(40), Some(2));
+
+        // Later upgrades must be newer and activate later
+        assert!(schedule.check(&upgrade, 20).is_err());
+        assert!(schedule.check(&ProtocolUpgrade { version: 3, activation_epoch: 40 }, 20).is_err());
+        schedule.schedule(&ProtocolUpgrade { version: 3, activation_epoch: 60 }, 45).unwrap();
+        assert_eq!(schedule.version_at(59), 2);
+        assert_eq!(schedule.version_at(1000), 3);
+    }
+}
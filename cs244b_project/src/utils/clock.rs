@@ -0,0 +1,82 @@
+/* The node's clock. Every timer (the epoch clock, the proposal delay, callback retries)
+   and every timestamp (entry ids, tree heads, inclusion promises and their merge-delay
+   checks, peer advertisements) goes through here rather than std::time or a direct
+   tokio sleep, and everything here runs on tokio's clock. So a runtime with paused time
+   (tokio::time::pause, or #[tokio::test(start_paused = true)]) controls all of it: tests
+   and simulations advance time instead of waiting for it.
+   Wall-clock time is read once, at first use, and advances with tokio's clock after that.
+   (Gossipsub's heartbeat runs on libp2p's own timer and isn't covered.) */
+
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{error::Elapsed, Instant};
+
+// Milliseconds since the Unix epoch at first use, and the tokio instant it was read at
+static ORIGIN: Lazy<(u64, std::time::Instant)> = Lazy::new(|| {
+    let unix_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    (unix_ms, Instant::now().into_std())
+});
+
+/* Milliseconds since the Unix epoch, as used in signed timestamps. */
+pub fn unix_time_ms() -> u64 {
+    let (origin_ms, origin) = *ORIGIN;
+    let now = Instant::now().into_std();
+    // A paused runtime's clock may stand before the origin (it stops when it is paused)
+    if now >= origin {
+        origin_ms + (now - origin).as_millis() as u64
+    } else {
+        origin_ms.saturating_sub((origin - now).as_millis() as u64)
+    }
+}
+
+/* The current time, as a SystemTime. */
+pub fn system_time() -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(unix_time_ms())
+}
+
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+pub async fn sleep_until(deadline: Instant) {
+    tokio::time::sleep_until(deadline).await
+}
+
+/* The current instant on tokio's clock, for measuring time within the node. */
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/* Ticks every period, the first time at once. */
+pub fn interval(period: Duration) -> tokio::time::Interval {
+    tokio::time::interval(period)
+}
+
+/* Runs a future, giving up after a duration. */
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    tokio::time::timeout(duration, future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_time_drives_the_clock() {
+        let start = unix_time_ms();
+        // Sleeps complete as soon as the runtime is idle, without real waiting
+        let real = std::time::Instant::now();
+        sleep(Duration::from_secs(3600)).await;
+        assert!(real.elapsed() < Duration::from_secs(60));
+        assert_eq!(unix_time_ms() - start, 3_600_000);
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(unix_time_ms() - start, 3_601_500);
+        assert!(timeout(Duration::from_secs(5), std::future::pending::<()>()).await.is_err());
+        assert_eq!(unix_time_ms() - start, 3_606_500);
+    }
+}
@@ -0,0 +1,443 @@
+/* Signature scheme abstraction.
+   A deployment picks exactly one scheme (ed25519, secp256k1, or bls12-381 with the
+   "bls" feature) up front; keys and signatures carry their scheme so that every
+   verification path can reject material produced under another one.
+   BLS12-381 signatures by several keys over the same bytes add up to one signature of
+   the same size (see aggregate_signatures), which is what lets a notarization
+   certificate stay constant-size (see cert). Each BLS signature signs the signer's
+   public key along with the bytes (the "message augmentation" scheme), so an aggregate
+   can't be forged with a key made up from the other signers' keys. Builds without the
+   feature still decode BLS keys and signatures, but can't sign or check them.
+   The protocol signs through the Signer trait and checks signatures through Verifier,
+   so a deployment can sign with a key it doesn't hold in memory (e.g. one kept by an
+   HSM, or by existing secp256k1 PKI tooling), as long as it signs under the deployment's
+   scheme. Keypair and PublicKey are the in-memory implementations. */
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::fmt;
+use std::str::FromStr;
+
+pub use rand::rngs::OsRng;
+pub use sha2::{Digest, Sha256};
+
+use ed25519_dalek::{Signer as _, Verifier as _};
+
+pub type Sha256Hash = [u8; 32];
+
+// Raw ed25519 types, for callers that need to build keys from fixed seeds (e.g., test vectors).
+pub use ed25519_dalek::{Keypair as Ed25519Keypair, SecretKey as Ed25519SecretKey};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+pub enum SignatureScheme {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    Bls12381,
+}
+
+impl FromStr for SignatureScheme {
+    type Err = CryptoError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "secp256k1" => Ok(SignatureScheme::Secp256k1),
+            "bls12-381" | "bls12381" if cfg!(feature = "bls") => Ok(SignatureScheme::Bls12381),
+            "bls12-381" | "bls12381" => Err(CryptoError::UnsupportedScheme(SignatureScheme::Bls12381)),
+            _ => Err(CryptoError::UnknownScheme(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for SignatureScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureScheme::Ed25519 => write!(f, "ed25519"),
+            SignatureScheme::Secp256k1 => write!(f, "secp256k1"),
+            SignatureScheme::Bls12381 => write!(f, "bls12-381"),
+        }
+    }
+}
+
+impl SignatureScheme {
+    /* Whether signatures under this scheme aggregate (see aggregate_signatures). */
+    pub fn aggregates(&self) -> bool {
+        *self == SignatureScheme::Bls12381
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    UnknownScheme(String),
+    SchemeMismatch,
+    InvalidKey,
+    InvalidSignature,
+    UnsupportedScheme(SignatureScheme), // this build can't sign or verify under it
+    SigningFailed(String),              // the signer couldn't produce a signature, and why
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::UnknownScheme(s) => write!(f, "unknown signature scheme: {}", s),
+            CryptoError::SchemeMismatch => write!(f, "key and signature use different schemes"),
+            CryptoError::InvalidKey => write!(f, "malformed public key"),
+            CryptoError::InvalidSignature => write!(f, "signature verification failed"),
+            CryptoError::UnsupportedScheme(scheme) => write!(f, "{} isn't supported by this build", scheme),
+            CryptoError::SigningFailed(e) => write!(f, "signing failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+pub enum Keypair {
+    Ed25519(ed25519_dalek::Keypair),
+    Secp256k1(libsecp256k1::SecretKey),
+    #[cfg(feature = "bls")]
+    Bls12381(blst::min_pk::SecretKey),
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::PublicKey),
+    // SEC1 compressed point
+    Secp256k1(#[serde_as(as = "[_; 33]")] [u8; 33]),
+    // Compressed G1 point
+    Bls12381(#[serde_as(as = "[_; 48]")] [u8; 48]),
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Signature {
+    Ed25519(ed25519_dalek::Signature),
+    // Compact (r || s), low-s normalized, over SHA-256 of the message
+    Secp256k1(#[serde_as(as = "[_; 64]")] [u8; 64]),
+    // Compressed G2 point, over the signer's public key followed by the message
+    Bls12381(#[serde_as(as = "[_; 96]")] [u8; 96]),
+    // Several validators' BLS votes summed into one (see cert): a bit per validator,
+    // in the order of their names, says whose votes are in the sum
+    Bls12381Aggregate {
+        #[serde_as(as = "[_; 32]")]
+        signers: [u8; 32],
+        #[serde_as(as = "[_; 96]")]
+        signature: [u8; 96],
+    },
+}
+
+pub trait Signer {
+    /* The scheme the signatures are under. */
+    fn scheme(&self) -> SignatureScheme;
+    /* The key the signatures verify under. */
+    fn public(&self) -> PublicKey;
+    fn sign(&self, bytes: &[u8]) -> Signature;
+    /* Like sign, for signers that can fail (a remote key that is unreachable, say). */
+    fn try_sign(&self, bytes: &[u8]) -> Result<Signature, CryptoError> {
+        Ok(self.sign(bytes))
+    }
+}
+
+pub trait Verifier {
+    fn scheme(&self) -> SignatureScheme;
+    /* Verifies a signature over `bytes`. Fails if it was produced under a different scheme. */
+    fn verify(&self, bytes: &[u8], signature: &Signature) -> Result<(), CryptoError>;
+}
+
+impl Signer for Keypair {
+    fn scheme(&self) -> SignatureScheme {
+        Keypair::scheme(self)
+    }
+    fn public(&self) -> PublicKey {
+        Keypair::public(self)
+    }
+    fn sign(&self, bytes: &[u8]) -> Signature {
+        Keypair::sign(self, bytes)
+    }
+}
+
+impl Verifier for PublicKey {
+    fn scheme(&self) -> SignatureScheme {
+        PublicKey::scheme(self)
+    }
+    fn verify(&self, bytes: &[u8], signature: &Signature) -> Result<(), CryptoError> {
+        PublicKey::verify(self, bytes, signature)
+    }
+}
+
+impl Keypair {
+    /* Generates a fresh keypair for the given scheme. */
+    pub fn generate(scheme: SignatureScheme) -> Self {
+        let mut csprng = OsRng {};
+        match scheme {
+            SignatureScheme::Ed25519 => Keypair::Ed25519(ed25519_dalek::Keypair::generate(&mut csprng)),
+            SignatureScheme::Secp256k1 => Keypair::Secp256k1(libsecp256k1::SecretKey::random(&mut csprng)),
+            #[cfg(feature = "bls")]
+            SignatureScheme::Bls12381 => {
+                let mut ikm = [0u8; 32];
+                rand::RngCore::fill_bytes(&mut csprng, &mut ikm);
+                Keypair::Bls12381(blst::min_pk::SecretKey::key_gen(&ikm, &[]).expect("32 bytes of key material"))
+            }
+            #[cfg(not(feature = "bls"))]
+            SignatureScheme::Bls12381 => panic!("{}", CryptoError::UnsupportedScheme(scheme)),
+        }
+    }
+
+    /* Rebuilds a keypair from its 32-byte secret key (see secret_bytes). */
+    pub fn from_secret_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Result<Self, CryptoError> {
+        match scheme {
+            SignatureScheme::Ed25519 => {
+                let secret = ed25519_dalek::SecretKey::from_bytes(bytes).map_err(|_| CryptoError::InvalidKey)?;
+                let public = ed25519_dalek::PublicKey::from(&secret);
+                Ok(Keypair::Ed25519(ed25519_dalek::Keypair { secret, public }))
+            }
+            SignatureScheme::Secp256k1 => {
+                let secret = libsecp256k1::SecretKey::parse_slice(bytes).map_err(|_| CryptoError::InvalidKey)?;
+                Ok(Keypair::Secp256k1(secret))
+            }
+            #[cfg(feature = "bls")]
+            SignatureScheme::Bls12381 => {
+                let secret = blst::min_pk::SecretKey::from_bytes(bytes).map_err(|_| CryptoError::InvalidKey)?;
+                Ok(Keypair::Bls12381(secret))
+            }
+            #[cfg(not(feature = "bls"))]
+            SignatureScheme::Bls12381 => Err(CryptoError::UnsupportedScheme(scheme)),
+        }
+    }
+
+    /* The secret key's bytes, for storing the keypair (see keystore). */
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        match self {
+            Keypair::Ed25519(kp) => kp.secret.to_bytes(),
+            Keypair::Secp256k1(sk) => sk.serialize(),
+            #[cfg(feature = "bls")]
+            Keypair::Bls12381(sk) => sk.to_bytes(),
+        }
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Keypair::Ed25519(_) => SignatureScheme::Ed25519,
+            Keypair::Secp256k1(_) => SignatureScheme::Secp256k1,
+            #[cfg(feature = "bls")]
+            Keypair::Bls12381(_) => SignatureScheme::Bls12381,
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        match self {
+            Keypair::Ed25519(kp) => PublicKey::Ed25519(kp.public),
+            Keypair::Secp256k1(sk) => {
+                PublicKey::Secp256k1(libsecp256k1::PublicKey::from_secret_key(sk).serialize_compressed())
+            }
+            #[cfg(feature = "bls")]
+            Keypair::Bls12381(sk) => PublicKey::Bls12381(sk.sk_to_pk().compress()),
+        }
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> Signature {
+        match self {
+            Keypair::Ed25519(kp) => Signature::Ed25519(kp.sign(bytes)),
+            Keypair::Secp256k1(sk) => {
+                let (sig, _) = libsecp256k1::sign(&secp256k1_digest(bytes), sk);
+                Signature::Secp256k1(sig.serialize())
+            }
+            #[cfg(feature = "bls")]
+            Keypair::Bls12381(sk) => Signature::Bls12381(sk.sign(bytes, BLS_DST, &sk.sk_to_pk().compress()).compress()),
+        }
+    }
+}
+
+impl From<ed25519_dalek::Keypair> for Keypair {
+    fn from(keypair: ed25519_dalek::Keypair) -> Self {
+        Keypair::Ed25519(keypair)
+    }
+}
+
+impl PublicKey {
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            PublicKey::Ed25519(_) => SignatureScheme::Ed25519,
+            PublicKey::Secp256k1(_) => SignatureScheme::Secp256k1,
+            PublicKey::Bls12381(_) => SignatureScheme::Bls12381,
+        }
+    }
+
+    /* Verifies a signature over `bytes`. Fails if the signature was produced under a different scheme. */
+    pub fn verify(&self, bytes: &[u8], signature: &Signature) -> Result<(), CryptoError> {
+        match (self, signature) {
+            (PublicKey::Ed25519(pk), Signature::Ed25519(sig)) => {
+                pk.verify(bytes, sig).map_err(|_| CryptoError::InvalidSignature)
+            }
+            (PublicKey::Secp256k1(pk), Signature::Secp256k1(sig)) => {
+                let pk = libsecp256k1::PublicKey::parse_compressed(pk).map_err(|_| CryptoError::InvalidKey)?;
+                let sig = libsecp256k1::Signature::parse_standard(sig).map_err(|_| CryptoError::InvalidSignature)?;
+                // Reject the malleated (high-s) twin so each (key, message) has a single valid signature
+                if sig.s.is_high() || !libsecp256k1::verify(&secp256k1_digest(bytes), &sig, &pk) {
+                    return Err(CryptoError::InvalidSignature);
+                }
+                Ok(())
+            }
+            (PublicKey::Bls12381(pk), Signature::Bls12381(sig)) => bls::verify(&[pk], bytes, sig),
+            // One key's signature, not a sum of several
+            (PublicKey::Bls12381(_), Signature::Bls12381Aggregate { .. }) => Err(CryptoError::InvalidSignature),
+            _ => Err(CryptoError::SchemeMismatch),
+        }
+    }
+}
+
+impl Signature {
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Signature::Ed25519(_) => SignatureScheme::Ed25519,
+            Signature::Secp256k1(_) => SignatureScheme::Secp256k1,
+            Signature::Bls12381(_) | Signature::Bls12381Aggregate { .. } => SignatureScheme::Bls12381,
+        }
+    }
+}
+
+/* Sums signatures by several keys over the same bytes into one signature, which
+verify_aggregate checks against all of those keys at once (BLS12-381 only).
+@param signatures: the signatures to sum, each a plain BLS12-381 signature */
+pub fn aggregate_signatures(signatures: &[Signature]) -> Result<Signature, CryptoError> {
+    let points: Vec<&[u8; 96]> = signatures
+        .iter()
+        .map(|signature| match signature {
+            Signature::Bls12381(sig) => Ok(sig),
+            _ => Err(CryptoError::SchemeMismatch),
+        })
+        .collect::<Result<_, _>>()?;
+    bls::aggregate(&points).map(Signature::Bls12381)
+}
+
+/* Checks a sum of signatures over `bytes` (see aggregate_signatures): valid only if
+every one of the keys signed them.
+@param public_keys: the keys whose signatures were summed
+@param aggregate: the sum */
+pub fn verify_aggregate(public_keys: &[PublicKey], bytes: &[u8], aggregate: &Signature) -> Result<(), CryptoError> {
+    let keys: Vec<&[u8; 48]> = public_keys
+        .iter()
+        .map(|pk| match pk {
+            PublicKey::Bls12381(pk) => Ok(pk),
+            _ => Err(CryptoError::SchemeMismatch),
+        })
+        .collect::<Result<_, _>>()?;
+    match aggregate {
+        Signature::Bls12381(sig) if !keys.is_empty() => bls::verify(&keys, bytes, sig),
+        Signature::Bls12381(_) => Err(CryptoError::InvalidSignature),
+        _ => Err(CryptoError::SchemeMismatch),
+    }
+}
+
+// Domain separation tag of the BLS signatures (ciphersuite with message augmentation)
+#[cfg(feature = "bls")]
+const BLS_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_AUG_";
+
+#[cfg(feature = "bls")]
+mod bls {
+    use super::{CryptoError, BLS_DST};
+    use blst::min_pk::{AggregateSignature, PublicKey, Signature};
+    use blst::BLST_ERROR;
+
+    // Checks that each key signed `bytes`, prefixed with the key (see Keypair::sign)
+    pub fn verify(keys: &[&[u8; 48]], bytes: &[u8], signature: &[u8; 96]) -> Result<(), CryptoError> {
+        let signature = Signature::uncompress(signature).map_err(|_| CryptoError::InvalidSignature)?;
+        let points: Vec<PublicKey> =
+            keys.iter().map(|key| PublicKey::uncompress(*key)).collect::<Result<_, _>>().map_err(|_| CryptoError::InvalidKey)?;
+        let messages: Vec<Vec<u8>> = keys.iter().map(|key| [&key[..], bytes].concat()).collect();
+        let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_slice()).collect();
+        let points: Vec<&PublicKey> = points.iter().collect();
+        match signature.aggregate_verify(true, &messages, BLS_DST, &points, true) {
+            BLST_ERROR::BLST_SUCCESS => Ok(()),
+            _ => Err(CryptoError::InvalidSignature),
+        }
+    }
+
+    pub fn aggregate(signatures: &[&[u8; 96]]) -> Result<[u8; 96], CryptoError> {
+        let points: Vec<Signature> = signatures
+            .iter()
+            .map(|signature| Signature::uncompress(*signature))
+            .collect::<Result<_, _>>()
+            .map_err(|_| CryptoError::InvalidSignature)?;
+        let points: Vec<&Signature> = points.iter().collect();
+        let sum = AggregateSignature::aggregate(&points, true).map_err(|_| CryptoError::InvalidSignature)?;
+        Ok(sum.to_signature().compress())
+    }
+}
+
+#[cfg(not(feature = "bls"))]
+mod bls {
+    use super::{CryptoError, SignatureScheme};
+
+    pub fn verify(_keys: &[&[u8; 48]], _bytes: &[u8], _signature: &[u8; 96]) -> Result<(), CryptoError> {
+        Err(CryptoError::UnsupportedScheme(SignatureScheme::Bls12381))
+    }
+
+    pub fn aggregate(_signatures: &[&[u8; 96]]) -> Result<[u8; 96], CryptoError> {
+        Err(CryptoError::UnsupportedScheme(SignatureScheme::Bls12381))
+    }
+}
+
+fn secp256k1_digest(bytes: &[u8]) -> libsecp256k1::Message {
+    let digest: Sha256Hash = Sha256::digest(bytes).into();
+    libsecp256k1::Message::parse(&digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify_per_scheme() {
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::Secp256k1] {
+            let keypair = Keypair::generate(scheme);
+            let signature = keypair.sign(b"entry");
+            assert_eq!(signature.scheme(), scheme);
+            assert!(keypair.public().verify(b"entry", &signature).is_ok());
+            assert!(keypair.public().verify(b"other", &signature).is_err());
+            let restored = Keypair::from_secret_bytes(scheme, &keypair.secret_bytes()).unwrap();
+            assert_eq!(restored.public(), keypair.public());
+        }
+    }
+
+    #[test]
+    fn test_cross_scheme_rejected() {
+        let ed = Keypair::generate(SignatureScheme::Ed25519);
+        let secp = Keypair::generate(SignatureScheme::Secp256k1);
+        assert_eq!(
+            ed.public().verify(b"entry", &secp.sign(b"entry")),
+            Err(CryptoError::SchemeMismatch)
+        );
+        assert_eq!("Secp256k1".parse::<SignatureScheme>(), Ok(SignatureScheme::Secp256k1));
+    }
+
+    // A key held elsewhere (an HSM, say): here, one that counts what it signs
+    struct ExternalSigner(Keypair, std::cell::Cell<usize>);
+
+    impl Signer for ExternalSigner {
+        fn scheme(&self) -> SignatureScheme {
+            self.0.scheme()
+        }
+        fn public(&self) -> PublicKey {
+            self.0.public()
+        }
+        fn sign(&self, bytes: &[u8]) -> Signature {
+            self.1.set(self.1.get() + 1);
+            self.0.sign(bytes)
+        }
+    }
+
+    #[test]
+    fn test_signers_and_verifiers_are_pluggable() {
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::Secp256k1] {
+            let external = ExternalSigner(Keypair::generate(scheme), std::cell::Cell::new(0));
+            let signers: [&dyn Signer; 2] = [&external, &external.0];
+            for signer in signers {
+                let verifier: &dyn Verifier = &signer.public();
+                assert_eq!(Verifier::scheme(verifier), signer.scheme());
+                assert!(verifier.verify(b"entry", &signer.sign(b"entry")).is_ok());
+            }
+            assert_eq!(external.1.get(), 1);
+        }
+    }
+}
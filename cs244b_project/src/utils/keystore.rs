@@ -0,0 +1,256 @@
+/* Encrypted keystore, so a node keeps its signing key across restarts.
+   A keyfile holds the node's secret key encrypted with ChaCha20-Poly1305 under a key
+   derived from the operator's passphrase (PBKDF2-HMAC-SHA256 with a random salt). The
+   scheme, salt and iteration count are stored in the clear and authenticated along
+   with the ciphertext, so a wrong passphrase or an edited file is detected rather than
+   yielding a different key.
+   During a key rotation the new key waits in a second file next to the keyfile (its
+   name with ".next" appended) until the rotation is in the log; then it is renamed over
+   the keyfile. A node that stops in between finds it there when it restarts. */
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::utils::crypto::*;
+
+// Environment variable a node reads its keyfile passphrase from
+pub const PASSPHRASE_ENV: &str = "STREAMLET_KEY_PASSPHRASE";
+// PBKDF2 iterations for new keyfiles
+const KDF_ITERATIONS: u32 = 100_000;
+// Most iterations we accept from a keyfile
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+const KEYFILE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct KeyFile {
+    version: u8,
+    scheme: SignatureScheme,
+    salt: [u8; 16],
+    iterations: u32,
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl KeyFile {
+    // Everything but the ciphertext, authenticated with it
+    fn header(&self) -> Vec<u8> {
+        bincode::serialize(&(self.version, self.scheme, self.salt, self.iterations, self.nonce))
+            .expect("Failed serialization.")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeystoreError {
+    Io(String),
+    Malformed,
+    EmptyPassphrase,
+    WrongPassphrase,
+    SchemeMismatch { stored: SignatureScheme, expected: SignatureScheme },
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keyfile I/O failed: {}", e),
+            KeystoreError::Malformed => write!(f, "not a keyfile"),
+            KeystoreError::EmptyPassphrase => write!(f, "keyfile passphrase is empty"),
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase (or the keyfile was modified)"),
+            KeystoreError::SchemeMismatch { stored, expected } => {
+                write!(f, "keyfile holds a {} key, but the deployment uses {}", stored, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+// PBKDF2-HMAC-SHA256 (RFC 8018), one block: exactly the 32 bytes a ChaCha20 key needs
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> Key {
+    let prf = Hmac::<Sha256>::new_from_slice(passphrase).expect("HMAC takes keys of any length");
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block = mac.finalize().into_bytes();
+    let mut key = block;
+    for _ in 1..iterations {
+        let mut mac = prf.clone();
+        mac.update(&block);
+        block = mac.finalize().into_bytes();
+        for (k, b) in key.iter_mut().zip(block.iter()) {
+            *k ^= b;
+        }
+    }
+    *Key::from_slice(&key)
+}
+
+fn seal_with(keypair: &Keypair, passphrase: &[u8], iterations: u32) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut file = KeyFile {
+        version: KEYFILE_VERSION,
+        scheme: keypair.scheme(),
+        salt: rng.gen(),
+        iterations,
+        nonce: rng.gen(),
+        ciphertext: Vec::new(),
+    };
+    let key = derive_key(passphrase, &file.salt, iterations);
+    let header = file.header();
+    file.ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(Nonce::from_slice(&file.nonce), Payload { msg: &keypair.secret_bytes(), aad: &header })
+        .expect("encrypting a 32-byte key can't fail");
+    bincode::serialize(&file).expect("Failed serialization.")
+}
+
+/* Encrypts a keypair into keyfile contents.
+@param keypair: the node's keypair
+@param passphrase: what the key will be encrypted under */
+pub fn seal(keypair: &Keypair, passphrase: &[u8]) -> Vec<u8> {
+    seal_with(keypair, passphrase, KDF_ITERATIONS)
+}
+
+/* Decrypts keyfile contents.
+@param bytes: the keyfile's contents
+@param passphrase: what the key was encrypted under */
+pub fn unseal(bytes: &[u8], passphrase: &[u8]) -> Result<Keypair, KeystoreError> {
+    let file: KeyFile = bincode::deserialize(bytes).map_err(|_| KeystoreError::Malformed)?;
+    if file.version != KEYFILE_VERSION || file.iterations == 0 || file.iterations > MAX_KDF_ITERATIONS {
+        return Err(KeystoreError::Malformed);
+    }
+    let key = derive_key(passphrase, &file.salt, file.iterations);
+    let secret = ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(&file.nonce), Payload { msg: &file.ciphertext, aad: &file.header() })
+        .map_err(|_| KeystoreError::WrongPassphrase)?;
+    Keypair::from_secret_bytes(file.scheme, &secret).map_err(|_| KeystoreError::Malformed)
+}
+
+// Creates the file readable by its owner only; fails if it already exists
+fn write_new(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/* Loads the node's keypair from a keyfile, or generates one and saves it there if the
+file doesn't exist yet.
+@param path: the keyfile
+@param passphrase: what the key is (to be) encrypted under
+@param scheme: the deployment's signature scheme */
+pub fn load_or_create(path: &Path, passphrase: &[u8], scheme: SignatureScheme) -> Result<Keypair, KeystoreError> {
+    if passphrase.is_empty() {
+        return Err(KeystoreError::EmptyPassphrase);
+    }
+    match fs::read(path) {
+        Ok(bytes) => {
+            let keypair = unseal(&bytes, passphrase)?;
+            if keypair.scheme() != scheme {
+                return Err(KeystoreError::SchemeMismatch { stored: keypair.scheme(), expected: scheme });
+            }
+            Ok(keypair)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate(scheme);
+            write_new(path, &seal(&keypair, passphrase)).map_err(|e| KeystoreError::Io(e.to_string()))?;
+            Ok(keypair)
+        }
+        Err(e) => Err(KeystoreError::Io(e.to_string())),
+    }
+}
+
+fn next_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".next");
+    PathBuf::from(name)
+}
+
+/* Saves the key a rotation will switch to, next to the keyfile (replacing one saved
+before, whose rotation never made it into the log).
+@param path: the keyfile
+@param keypair: the new keypair
+@param passphrase: what it is to be encrypted under */
+pub fn stage_next(path: &Path, keypair: &Keypair, passphrase: &[u8]) -> Result<(), KeystoreError> {
+    let next = next_path(path);
+    match fs::remove_file(&next) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(KeystoreError::Io(e.to_string())),
+        _ => {}
+    }
+    write_new(&next, &seal(keypair, passphrase)).map_err(|e| KeystoreError::Io(e.to_string()))
+}
+
+/* The key saved by stage_next, if there is one.
+@param path: the keyfile
+@param passphrase: what the key is encrypted under */
+pub fn load_next(path: &Path, passphrase: &[u8]) -> Result<Option<Keypair>, KeystoreError> {
+    match fs::read(next_path(path)) {
+        Ok(bytes) => unseal(&bytes, passphrase).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(KeystoreError::Io(e.to_string())),
+    }
+}
+
+/* Makes the key saved by stage_next the keyfile's, once the rotation is in the log.
+@param path: the keyfile */
+pub fn promote_next(path: &Path) -> Result<(), KeystoreError> {
+    fs::rename(next_path(path), path).map_err(|e| KeystoreError::Io(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyfile_round_trip() {
+        // PBKDF2-HMAC-SHA256 test vector (RFC 7914, section 11)
+        let key = derive_key(b"passwd", b"salt", 1);
+        assert_eq!(hex::encode(key), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+
+        let keypair = Keypair::generate(SignatureScheme::Ed25519);
+        let sealed = seal_with(&keypair, b"hunter2", 10);
+        assert_eq!(unseal(&sealed, b"hunter2").unwrap().public(), keypair.public());
+        assert_eq!(unseal(&sealed, b"hunter3").err(), Some(KeystoreError::WrongPassphrase));
+        // The iteration count is authenticated too
+        let mut file: KeyFile = bincode::deserialize(&sealed).unwrap();
+        file.iterations = 11;
+        assert_eq!(
+            unseal(&bincode::serialize(&file).unwrap(), b"hunter2").err(),
+            Some(KeystoreError::WrongPassphrase)
+        );
+        assert_eq!(unseal(b"junk", b"hunter2").err(), Some(KeystoreError::Malformed));
+    }
+
+    #[test]
+    fn test_load_or_create_keeps_the_key() {
+        let path = std::env::temp_dir().join(format!("streamlet-keyfile-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let scheme = SignatureScheme::Secp256k1;
+        let created = load_or_create(&path, b"pass", scheme).unwrap();
+        let loaded = load_or_create(&path, b"pass", scheme).unwrap();
+        assert_eq!(created.public(), loaded.public());
+        assert!(matches!(
+            load_or_create(&path, b"pass", SignatureScheme::Ed25519).err(),
+            Some(KeystoreError::SchemeMismatch { .. })
+        ));
+        assert_eq!(load_or_create(&path, b"", scheme).err(), Some(KeystoreError::EmptyPassphrase));
+
+        // A rotation's key waits beside the keyfile until it replaces it
+        assert!(matches!(load_next(&path, b"pass"), Ok(None)));
+        let next = Keypair::generate(scheme);
+        stage_next(&path, &Keypair::generate(scheme), b"pass").unwrap();
+        stage_next(&path, &next, b"pass").unwrap();
+        assert_eq!(load_next(&path, b"pass").unwrap().map(|keypair| keypair.public()), Some(next.public()));
+        promote_next(&path).unwrap();
+        assert_eq!(load_or_create(&path, b"pass", scheme).unwrap().public(), next.public());
+        assert!(matches!(load_next(&path, b"pass"), Ok(None)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+/* Verification budget: bounds how many signatures the node checks for each peer in an epoch.
+   Checking a consensus message's signatures is the most expensive thing a node does
+   with it, and a message full of well-formed but garbage signatures costs as much to
+   reject as a valid one costs to accept. Each peer gets a budget of signatures per
+   epoch. Peers are told apart by the validator that sealed their messages (see
+   envelope), not by the sender name in them, which nobody vouches for and which an
+   echo keeps from the original: otherwise validators echoing a leader's proposal would
+   spend the leader's budget, and a flooder could spend anyone's, or take a fresh
+   budget with every name it made up. Messages over budget aren't dropped, since an
+   honest peer can exceed it in a busy epoch, e.g. echoing many votes; they are deferred
+   and only checked once the node has nothing else to do. A flooding peer therefore
+   delays only its own messages, and the deferred queue is bounded. */
+
+use crate::messages::Message;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Signatures checked per peer per epoch before its messages are deferred. An honest peer
+// sends a proposal and echoes its votes (each carrying every vote it knows of), so this
+// covers a few dozen validators with room to spare.
+pub const DEFAULT_VERIFICATIONS_PER_PEER: usize = 1024;
+// Most messages deferred at once; the oldest is dropped to make room
+pub const DEFERRED_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+pub struct VerificationBudget {
+    per_peer: usize, // 0: unlimited
+    spent: HashMap<String, usize>,
+    over_budget: HashSet<String>, // peers whose messages were deferred this epoch
+    deferred: VecDeque<Message>,
+}
+
+impl Default for VerificationBudget {
+    fn default() -> Self {
+        VerificationBudget::new(DEFAULT_VERIFICATIONS_PER_PEER)
+    }
+}
+
+impl VerificationBudget {
+    /* @param per_peer: signatures checked per peer per epoch (0: unlimited) */
+    pub fn new(per_peer: usize) -> Self {
+        Self { per_peer, spent: HashMap::new(), over_budget: HashSet::new(), deferred: VecDeque::new() }
+    }
+
+    /* A new epoch started: every peer's budget is full again. */
+    pub fn epoch_started(&mut self) {
+        self.spent.clear();
+        self.over_budget.clear();
+    }
+
+    /* Charges a message's signatures to the peer that sealed it. Returns false (and
+    charges nothing) if they don't fit in what is left of its budget this epoch.
+    @param sender: the validator that sealed the message (see envelope)
+    @param message: the message about to be checked */
+    pub fn admit(&mut self, sender: &str, message: &Message) -> bool {
+        if self.per_peer == 0 {
+            return true;
+        }
+        let cost = message.signatures.len().max(1);
+        let spent = self.spent.entry(sender.to_string()).or_insert(0);
+        if *spent + cost > self.per_peer {
+            return false;
+        }
+        *spent += cost;
+        true
+    }
+
+    /* Sets an over-budget message aside until the node is idle. Returns true for the
+    first message of a peer deferred this epoch.
+    @param sender: the validator that sealed the message
+    @param message: the message */
+    pub fn defer(&mut self, sender: &str, message: Message) -> bool {
+        let first = self.over_budget.insert(sender.to_string());
+        if self.deferred.len() == DEFERRED_CAPACITY {
+            self.deferred.pop_front();
+        }
+        self.deferred.push_back(message);
+        first
+    }
+
+    pub fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
+    /* The oldest deferred message, to check now that the node is idle. */
+    pub fn next_deferred(&mut self) -> Option<Message> {
+        self.deferred.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::messages::{MessageKind, MessagePayload};
+    use crate::utils::crypto::*;
+
+    fn vote(sender: &str, signatures: usize) -> Message {
+        let block = MessagePayload::Block(Block::new(1, [0; 32], b"entry".to_vec(), 1, 0));
+        let mut message = Message::new(block, MessageKind::Vote, 1, String::from(sender));
+        let keypair = Keypair::generate(SignatureScheme::default());
+        for _ in 0..signatures {
+            message.sign_message(keypair.sign(b"garbage"));
+        }
+        message
+    }
+
+    #[test]
+    fn test_budget_is_per_peer_and_per_epoch() {
+        let mut budget = VerificationBudget::new(10);
+        assert!(budget.admit("h1", &vote("h1", 6)));
+        assert!(!budget.admit("h1", &vote("h1", 6)));
+        assert!(budget.admit("h1", &vote("h1", 4)));
+        assert!(!budget.admit("h1", &vote("h1", 0)));
+        // Other peers aren't affected by h1's flood
+        assert!(budget.admit("h2", &vote("h2", 10)));
+        budget.epoch_started();
+        assert!(budget.admit("h1", &vote("h1", 10)));
+        assert!(VerificationBudget::new(0).admit("h1", &vote("h1", 1000)));
+    }
+
+    #[test]
+    fn test_messages_are_charged_to_whoever_sealed_them() {
+        let mut budget = VerificationBudget::new(10);
+        // h1 echoes h3's proposal, and floods messages claiming to be from h2
+        assert!(budget.admit("h1", &vote("h3", 4)));
+        assert!(budget.admit("h1", &vote("h2", 6)));
+        assert!(!budget.admit("h1", &vote("h2", 1)));
+        // Neither h2 nor h3 paid for it
+        assert!(budget.admit("h2", &vote("h2", 10)));
+        assert!(budget.admit("h3", &vote("h3", 10)));
+
+        for nonce in 0..DEFERRED_CAPACITY + 1 {
+            let mut message = vote("h1", 1);
+            message.nonce = nonce as u32;
+            assert_eq!(budget.defer("h1", message), nonce == 0);
+        }
+        assert_eq!(budget.next_deferred().map(|message| message.nonce), Some(1));
+        assert!(budget.has_deferred());
+    }
+}
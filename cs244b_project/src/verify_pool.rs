@@ -0,0 +1,207 @@
+/* Signature checks off the event loop. Matching a consensus message's signatures to
+   validators' keys (see identify_signers) is the most expensive thing the node does
+   with it, and a burst of votes checked on the event loop holds up everything behind
+   them: epoch timers, commands, the API. So once a consensus message has passed the
+   cheap checks (its envelope, replay protection, its sender's verification budget),
+   the event loop hands it to tokio's blocking pool and moves on. The result comes back
+   with the message, and the message is handled then, as if it had just arrived, with
+   the signers already matched. Results are taken in the order the messages arrived,
+   so the pool delays consensus messages without reordering them. A result
+   matched against keys that have changed since is ignored, and the signatures checked
+   again. At most MAX_IN_FLIGHT messages are checked at once; beyond that, the event
+   loop checks messages itself, which slows intake down the way it always did. Both
+   share a cache of signatures already found valid (see signature_cache). */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::blockchain::ChainId;
+use crate::messages::Message;
+use crate::signature_cache::SignatureCache;
+use crate::utils::crypto::*;
+
+// Messages checked on the pool at once
+pub const MAX_IN_FLIGHT: usize = 256;
+
+/* A message to check, with what to check it against. */
+pub struct VerifyJob {
+    pub bytes: Vec<u8>,                          // the message as received
+    pub message: Message,
+    pub sealed_by: Option<String>,               // whoever sealed it (see envelope)
+    pub public_keys: HashMap<String, PublicKey>, // validators' keys when it was handed over
+    pub scheme: SignatureScheme,
+    pub chain_id: ChainId,
+    pub cache: Arc<Mutex<SignatureCache>>,
+}
+
+/* A checked message, and the validators whose signatures it carries. */
+#[derive(Debug, Clone)]
+pub struct Verified {
+    pub bytes: Vec<u8>,
+    pub message: Message,
+    pub sealed_by: Option<String>,
+    pub public_keys: HashMap<String, PublicKey>, // the keys it was checked against
+    pub signers: Vec<(String, Signature)>,
+}
+
+impl VerifyJob {
+    pub fn run(self) -> Verified {
+        let signers = identify_signers(&self.message, &self.public_keys, self.scheme, &self.chain_id, &self.cache);
+        Verified { bytes: self.bytes, message: self.message, sealed_by: self.sealed_by, public_keys: self.public_keys, signers }
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyPool {
+    in_flight: VecDeque<JoinHandle<Verified>>, // in the order the messages arrived
+}
+
+impl VerifyPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Whether the pool takes another message (fewer than MAX_IN_FLIGHT are in it). */
+    pub fn has_room(&self) -> bool {
+        self.in_flight.len() < MAX_IN_FLIGHT
+    }
+
+    /* Starts checking a message on the blocking pool (see has_room).
+    @param job: the message */
+    pub fn submit(&mut self, job: VerifyJob) {
+        self.in_flight.push_back(tokio::task::spawn_blocking(move || job.run()));
+    }
+
+    /* The oldest message handed over, once it is checked; None (at once) if there is
+    none. Cancel safe: a result that is ready stays until it is taken. */
+    pub async fn next_verified(&mut self) -> Option<Verified> {
+        let oldest = self.in_flight.front_mut()?;
+        let result = oldest.await;
+        self.in_flight.pop_front();
+        result.ok()
+    }
+}
+
+/* Verifies a (message, signature) pair against a public key. Only material from the
+deployment's scheme counts.
+@param message: the message
+@param signature: a signature on it
+@param pk: the key to verify against
+@param scheme: the deployment's signature scheme
+@param chain_id: the deployment's chain id
+@param cache: signatures found valid before */
+pub fn verify_signature(message: &Message, signature: &Signature, pk: &PublicKey, scheme: SignatureScheme, chain_id: &ChainId, cache: &Mutex<SignatureCache>) -> bool {
+    let signed = message.signed_bytes(chain_id);
+    let digest: Sha256Hash = Sha256::digest(&signed).into();
+    cache.lock().expect("Signature cache lock poisoned").contains(&SignatureCache::key(&digest, signature, pk)) || check_and_cache(&signed, &digest, signature, pk, scheme, cache)
+}
+
+/* Matches each valid signature on the message to the known signer whose key verifies
+it. Each key counts once, however many of the signatures it verifies (a repeated
+signature, or a second one from the same validator). A signature found valid before
+is matched from the cache, without checking it again. Returns (signer name, signature)
+pairs, one per distinct signer.
+@param message: the message
+@param public_keys: validators' keys, by name
+@param scheme: the deployment's signature scheme
+@param chain_id: the deployment's chain id
+@param cache: signatures found valid before */
+pub fn identify_signers(
+    message: &Message,
+    public_keys: &HashMap<String, PublicKey>,
+    scheme: SignatureScheme,
+    chain_id: &ChainId,
+    cache: &Mutex<SignatureCache>,
+) -> Vec<(String, Signature)> {
+    let signed = message.signed_bytes(chain_id);
+    let digest: Sha256Hash = Sha256::digest(&signed).into();
+    let mut ret = Vec::new();
+    let mut matched_keys: Vec<PublicKey> = Vec::new();
+    for signature in &message.signatures {
+        // The key a cached signature goes with, if there is one, before checking any
+        let cached = {
+            let mut cache = cache.lock().expect("Signature cache lock poisoned");
+            public_keys.iter().find(|(_, pk)| !matched_keys.contains(pk) && cache.contains(&SignatureCache::key(&digest, signature, pk)))
+        };
+        let signer = cached.or_else(|| {
+            public_keys
+                .iter()
+                .find(|(_, pk)| !matched_keys.contains(pk) && check_and_cache(&signed, &digest, signature, pk, scheme, cache))
+        });
+        if let Some((name, pk)) = signer {
+            matched_keys.push(*pk);
+            ret.push((name.clone(), *signature));
+        }
+    }
+    ret
+}
+
+// Checks a signature (only material from the deployment's scheme counts), and caches it if it is valid
+fn check_and_cache(signed: &[u8], digest: &Sha256Hash, signature: &Signature, pk: &PublicKey, scheme: SignatureScheme, cache: &Mutex<SignatureCache>) -> bool {
+    if signature.scheme() != scheme || pk.scheme() != scheme {
+        return false;
+    }
+    let valid = pk.verify(signed, signature).is_ok();
+    if valid {
+        cache.lock().expect("Signature cache lock poisoned").insert(SignatureCache::key(digest, signature, pk));
+    }
+    valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::messages::{MessageKind, MessagePayload};
+
+    #[tokio::test]
+    async fn test_pool_matches_signers_in_arrival_order() {
+        let chain_id = ChainId::default();
+        let scheme = SignatureScheme::Ed25519;
+        let validators: Vec<Keypair> = (0..3).map(|_| Keypair::generate(scheme)).collect();
+        let public_keys: HashMap<String, PublicKey> = validators.iter().enumerate().map(|(i, key)| (format!("v{}", i), key.public())).collect();
+        let vote = |epoch, signers: &[&Keypair]| {
+            let block = Block::new(epoch, [0; 32], Vec::new(), 1, 0);
+            let mut message = Message::new(MessagePayload::Block(block), MessageKind::Vote, 0, String::from("v0"));
+            for signer in signers {
+                message.sign_message(signer.sign(&message.signed_bytes(&chain_id)));
+            }
+            message
+        };
+        let cache = Arc::new(Mutex::new(SignatureCache::default()));
+        let job = |message: Message| VerifyJob {
+            bytes: message.serialize(),
+            message,
+            sealed_by: None,
+            public_keys: public_keys.clone(),
+            scheme,
+            chain_id,
+            cache: cache.clone(),
+        };
+
+        let mut pool = VerifyPool::new();
+        assert!(pool.next_verified().await.is_none());
+        let outsider = Keypair::generate(scheme);
+        let messages = [
+            vote(1, &[&validators[0], &validators[1], &validators[2]]),
+            vote(2, &[&validators[1], &validators[1], &outsider]),
+            vote(3, &[]),
+        ];
+        for message in &messages {
+            assert!(pool.has_room());
+            pool.submit(job(message.clone()));
+        }
+        let mut counts = Vec::new();
+        while let Some(done) = pool.next_verified().await {
+            counts.push((done.message, done.signers.len()));
+        }
+        assert_eq!(counts, vec![(messages[0].clone(), 3), (messages[1].clone(), 1), (messages[2].clone(), 0)]);
+        assert!(pool.next_verified().await.is_none());
+
+        // A re-gossiped copy is matched from the cache, without checking its signatures again
+        let hits = cache.lock().expect("Signature cache lock poisoned").hits();
+        assert_eq!(identify_signers(&messages[0], &public_keys, scheme, &chain_id, &cache).len(), 3);
+        assert_eq!(cache.lock().expect("Signature cache lock poisoned").hits(), hits + 3);
+    }
+}
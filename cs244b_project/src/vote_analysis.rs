@@ -0,0 +1,275 @@
+/* Statistical detection of suspicious voting patterns.
+   Equivocation (two signed votes in one epoch) is hard evidence; this looks for softer
+   signals that a validator may be faulty or adversarial:
+   - ConflictingForks: its votes land on blocks that end up abandoned more than others' do
+   - LateVoter: its votes arrive after the block's epoch more often than others' do
+   - ProposerBoycott: it votes for one proposer's blocks much less than for everyone else's
+   Each check compares a validator's rate against the pooled rate of the other validators
+   (a one-sided binomial z-score), so a network-wide slowdown doesn't flag anyone. Scores
+   are for operators to look into, not grounds for any automatic action. */
+
+use crate::blockchain::{Block, LocalChain, SignedBlock};
+use crate::Sha256Hash;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Scores at or above this are reported
+pub const ANOMALY_THRESHOLD: f64 = 3.0;
+// Observations needed before a validator (or validator/proposer pair) is scored
+const MIN_SAMPLES: u64 = 10;
+// Keeps z-scores finite when the other validators' rate is exactly 0 or 1
+const MIN_BASE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    ConflictingForks,
+    LateVoter,
+    ProposerBoycott(String), // the proposer being avoided
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub validator: String,
+    pub kind: AnomalyKind,
+    pub score: f64,    // z-score against the other validators
+    pub rate: f64,     // the validator's own rate
+    pub baseline: f64, // the other validators' pooled rate
+    pub samples: u64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match &self.kind {
+            AnomalyKind::ConflictingForks => String::from("votes on abandoned forks"),
+            AnomalyKind::LateVoter => String::from("votes late"),
+            AnomalyKind::ProposerBoycott(proposer) => format!("skips blocks proposed by {}", proposer),
+        };
+        write!(
+            f,
+            "{} {}: {:.0}% vs {:.0}% for others over {} samples (score {:.1})",
+            self.validator,
+            what,
+            self.rate * 100.0,
+            self.baseline * 100.0,
+            self.samples,
+            self.score
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Count {
+    hits: u64,
+    total: u64,
+}
+
+// A block we've seen votes for whose fate (finalized or abandoned) isn't known yet
+struct ObservedBlock {
+    height: u64,
+    voters: HashSet<String>,
+}
+
+#[derive(Default)]
+pub struct VoteAnalyzer {
+    unresolved: HashMap<Sha256Hash, ObservedBlock>,
+    // Per validator: votes on blocks that were abandoned / votes on resolved blocks
+    fork_votes: HashMap<String, Count>,
+    // Per validator: votes received after the block's epoch / all votes
+    late_votes: HashMap<String, Count>,
+    // Per proposer: blocks seen; per (voter, proposer): votes cast
+    proposals: HashMap<String, u64>,
+    votes_by_proposer: HashMap<(String, String), u64>,
+    seen_votes: HashSet<(String, Sha256Hash)>,
+}
+
+/* One-sided z-score of observing `rate` over `samples` trials if the true rate were `baseline`. */
+fn z_score(rate: f64, baseline: f64, samples: u64) -> f64 {
+    let p = baseline.clamp(MIN_BASE_RATE, 1.0 - MIN_BASE_RATE);
+    (rate - p) / (p * (1.0 - p) / samples as f64).sqrt()
+}
+
+impl VoteAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Records a vote (or a proposal, which doubles as the proposer's vote).
+    Repeated sightings of the same vote (e.g. echoes) are ignored.
+    @param voter: name of the signer
+    @param block: the block voted for
+    @param proposer: leader of the block's epoch
+    @param current_epoch: epoch in which the vote was received */
+    pub fn record_vote(&mut self, voter: &str, block: &Block, proposer: &str, current_epoch: u64) {
+        if !self.seen_votes.insert((voter.to_string(), block.hash)) {
+            return;
+        }
+        let late = self.late_votes.entry(voter.to_string()).or_default();
+        late.total += 1;
+        if current_epoch > block.epoch {
+            late.hits += 1;
+        }
+        if !self.unresolved.contains_key(&block.hash) {
+            *self.proposals.entry(proposer.to_string()).or_default() += 1;
+        }
+        if voter != proposer {
+            *self
+                .votes_by_proposer
+                .entry((voter.to_string(), proposer.to_string()))
+                .or_default() += 1;
+        }
+        self.unresolved
+            .entry(block.hash)
+            .or_insert_with(|| ObservedBlock { height: block.height, voters: HashSet::new() })
+            .voters
+            .insert(voter.to_string());
+    }
+
+    /* Settles every observed block at or below the finalized tip: blocks on the finalized
+    chain were the right ones to vote for, any other block at those heights was abandoned.
+    @param finalized_chain: this node's finalized chain */
+    pub fn record_finalized(&mut self, finalized_chain: &LocalChain) {
+        let finalized_height = finalized_chain.blocks.len() as u64 - 1;
+        let finalized: HashSet<Sha256Hash> = finalized_chain
+            .blocks
+            .iter()
+            .map(|SignedBlock { block, .. }| block.hash)
+            .collect();
+        let settled: Vec<Sha256Hash> = self
+            .unresolved
+            .iter()
+            .filter(|(_, observed)| observed.height <= finalized_height)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in settled {
+            let observed = self.unresolved.remove(&hash).expect("hash was just found");
+            let abandoned = !finalized.contains(&hash);
+            for voter in observed.voters {
+                let count = self.fork_votes.entry(voter).or_default();
+                count.total += 1;
+                if abandoned {
+                    count.hits += 1;
+                }
+            }
+        }
+        self.seen_votes.retain(|(_, hash)| self.unresolved.contains_key(hash));
+    }
+
+    /* Scores every validator with enough observations; returns those at or above
+    ANOMALY_THRESHOLD, highest score first. */
+    pub fn anomalies(&self) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        anomalies.extend(VoteAnalyzer::excess_rate(&self.fork_votes, AnomalyKind::ConflictingForks));
+        anomalies.extend(VoteAnalyzer::excess_rate(&self.late_votes, AnomalyKind::LateVoter));
+        anomalies.extend(self.boycotts());
+        anomalies.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+        anomalies
+    }
+
+    // Validators whose rate is well above everyone else's pooled rate
+    fn excess_rate(counts: &HashMap<String, Count>, kind: AnomalyKind) -> Vec<Anomaly> {
+        let hits: u64 = counts.values().map(|c| c.hits).sum();
+        let total: u64 = counts.values().map(|c| c.total).sum();
+        counts
+            .iter()
+            .filter(|(_, count)| count.total >= MIN_SAMPLES && total > count.total)
+            .filter_map(|(validator, count)| {
+                let rate = count.hits as f64 / count.total as f64;
+                let baseline = (hits - count.hits) as f64 / (total - count.total) as f64;
+                let score = z_score(rate, baseline, count.total);
+                (score >= ANOMALY_THRESHOLD).then(|| Anomaly {
+                    validator: validator.clone(),
+                    kind: kind.clone(),
+                    score,
+                    rate,
+                    baseline,
+                    samples: count.total,
+                })
+            })
+            .collect()
+    }
+
+    // (voter, proposer) pairs where the voter backs that proposer's blocks far less
+    // often than it backs everyone else's
+    fn boycotts(&self) -> Vec<Anomaly> {
+        let voters: HashSet<&String> = self.late_votes.keys().collect();
+        let mut anomalies = Vec::new();
+        for voter in voters {
+            let opportunities = |proposer: &String| self.proposals[proposer];
+            let votes = |proposer: &String| {
+                self.votes_by_proposer
+                    .get(&(voter.clone(), proposer.clone()))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            let others: Vec<&String> = self.proposals.keys().filter(|p| *p != voter).collect();
+            for proposer in &others {
+                let samples = opportunities(proposer);
+                let rest_total: u64 = others.iter().filter(|p| *p != proposer).map(|p| opportunities(p)).sum();
+                if samples < MIN_SAMPLES || rest_total < MIN_SAMPLES {
+                    continue;
+                }
+                let rest_votes: u64 = others.iter().filter(|p| *p != proposer).map(|p| votes(p)).sum();
+                // Skip rates, so that "higher is worse" like the other checks
+                let rate = 1.0 - votes(proposer) as f64 / samples as f64;
+                let baseline = 1.0 - rest_votes as f64 / rest_total as f64;
+                let score = z_score(rate, baseline, samples);
+                if score >= ANOMALY_THRESHOLD {
+                    anomalies.push(Anomaly {
+                        validator: voter.clone(),
+                        kind: AnomalyKind::ProposerBoycott((*proposer).clone()),
+                        score,
+                        rate,
+                        baseline,
+                        samples,
+                    });
+                }
+            }
+        }
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockchainManager, Chain};
+
+    const VALIDATORS: [&str; 4] = ["h1", "h2", "h3", "h4"];
+
+    #[test]
+    fn test_flags_only_the_odd_validator() {
+        let mut manager = BlockchainManager::new();
+        let mut analyzer = VoteAnalyzer::new();
+        for epoch in 1..=60u64 {
+            let parent = manager.head().0.clone();
+            let proposer = VALIDATORS[epoch as usize % 4];
+            let block = Block::new(epoch, parent.hash, Vec::new(), parent.height + 1, 0);
+            for voter in VALIDATORS {
+                match voter {
+                    // Never votes for h2's blocks, and is late otherwise
+                    "h3" if proposer == "h2" => {}
+                    "h3" => analyzer.record_vote(voter, &block, proposer, epoch + 1),
+                    _ => analyzer.record_vote(voter, &block, proposer, epoch),
+                }
+            }
+            // h4 also backs a competing block that never gets finalized
+            let fork = Block::new(epoch, parent.hash, vec![1], parent.height + 1, 1);
+            analyzer.record_vote("h4", &fork, proposer, epoch);
+            manager.add_notarized_block(block, Vec::new());
+            analyzer.record_finalized(manager.finalized_chain());
+        }
+        assert!(manager.finalized_chain().length() > 50);
+
+        let anomalies = analyzer.anomalies();
+        let found: HashSet<(String, AnomalyKind)> =
+            anomalies.iter().map(|a| (a.validator.clone(), a.kind.clone())).collect();
+        assert_eq!(
+            found,
+            HashSet::from([
+                (String::from("h3"), AnomalyKind::LateVoter),
+                (String::from("h3"), AnomalyKind::ProposerBoycott(String::from("h2"))),
+                (String::from("h4"), AnomalyKind::ConflictingForks),
+            ])
+        );
+    }
+}
@@ -0,0 +1,124 @@
+/* WebSocket stream of finalized blocks (needs the "websocket" feature), so dashboards
+   and indexers can follow the log without polling get-entries. Connect to any path on
+   the node's --websocket address; from then on, each block the node finalizes arrives
+   as one text message, in height order, with the block's JSON shape (see json_schema):
+   its entry, if it holds one, is under "entry", and empty blocks have "entry": null.
+   Blocks finalized before the client connected aren't replayed; fetch them with
+   get-entries first. The stream is one way: anything clients send is ignored, except a
+   close. */
+
+use crate::blockchain::SignedBlock;
+use crate::json_schema;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+/* Pushes queued blocks to one client until either side closes.
+@param stream: the client's connection
+@param peer: its address, for logging
+@param queue: the client's blocks, as JSON */
+async fn push_to_client(stream: TcpStream, peer: SocketAddr, mut queue: mpsc::UnboundedReceiver<String>) {
+    let socket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    let (mut sink, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            message = queue.recv() => match message {
+                Some(text) => {
+                    if sink.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                None => {
+                    let _ = sink.close().await; // the node is stopping
+                    return;
+                }
+            },
+            // Read only to answer pings and notice the client leaving
+            received = incoming.next() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/* Accepts clients and hands each finalized block to all of them.
+@param listener: where clients connect
+@param finalized: the node's finalized blocks (see StreamletInstance::subscribe_finalized) */
+async fn broadcast(listener: TcpListener, mut finalized: mpsc::UnboundedReceiver<SignedBlock>) {
+    let mut clients: Vec<mpsc::UnboundedSender<String>> = Vec::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let (client, queue) = mpsc::unbounded_channel();
+                    clients.push(client);
+                    tokio::spawn(push_to_client(stream, peer, queue));
+                }
+                Err(e) => warn!("Couldn't accept a WebSocket client: {}", e),
+            },
+            signed = finalized.recv() => match signed {
+                Some(signed) => {
+                    let message = json_schema::block_json(&signed.block, &signed.cert.signatures).to_string();
+                    clients.retain(|client| client.send(message.clone()).is_ok());
+                }
+                None => return,
+            },
+        }
+    }
+}
+
+/* Serves the stream until the node exits.
+@param addr: address to listen on
+@param finalized: the node's finalized blocks */
+pub async fn serve(addr: SocketAddr, finalized: mpsc::UnboundedReceiver<SignedBlock>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Couldn't start the WebSocket stream on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Streaming finalized blocks over WebSocket on {}", addr);
+    broadcast(listener, finalized).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn test_clients_receive_finalized_blocks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (finalized, blocks) = mpsc::unbounded_channel();
+        tokio::spawn(broadcast(listener, blocks));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await.unwrap();
+        for height in 1..=2 {
+            let block = Block::new(height, [0; 32], vec![height as u8], height, 0);
+            finalized.send(SignedBlock::new(block, Vec::new())).unwrap();
+        }
+        for height in 1..=2 {
+            let message = client.next().await.unwrap().unwrap();
+            let json: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(json["height"], height);
+        }
+
+        // The stream closes once the node stops
+        drop(finalized);
+        assert!(matches!(client.next().await, Some(Ok(Message::Close(_))) | None));
+    }
+}